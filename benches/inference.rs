@@ -0,0 +1,132 @@
+//! Compares the model's fp32 weights with its int8 export on this CPU:
+//! how long embedding takes at each, and how far the int8 embeddings
+//! drift from the fp32 ones. Downloads both exports of the configured
+//! model on first run.
+//!
+//! ```bash
+//! cargo bench --bench inference
+//! SYSTEMATICS_MODEL_NAME=BAAI/bge-small-en-v1.5 cargo bench --bench inference
+//! ```
+
+use std::time::{Duration, Instant};
+
+use systematics_embeddings::config::{InferencePrecision, ModelConfig};
+use systematics_embeddings::{simd, EmbeddingService};
+
+/// Note-like texts of a few lengths, embedded as one batch and ranked
+/// against each other.
+const TEXTS: &[&str] = &[
+    "The triad is the first system to show relatedness.",
+    "A monad is wholeness without distinction of parts.",
+    "The dyad introduces difference: two terms and the tension between them.",
+    "In the tetrad, two polarities cross to give a field of activity.",
+    "The pentad adds significance, the sense of a purpose to the activity.",
+    "Bennett's systematics describes a hierarchy of systems by number of terms.",
+    "Meeting notes: ship the reindex job before the plugin release.",
+    "Grocery list: oats, lentils, coffee, lemons, and olive oil.",
+    "Weekly review: too many open loops, close the reading backlog first.",
+    "Sourdough starter needs feeding twice a day at room temperature.",
+    "The enneagram arranges nine points on a circle connected by two figures.",
+    "Attention is a limited resource; protect the mornings for deep work.",
+    "Octaves describe processes with intervals where outside help is needed.",
+    "Hiking route: ridge trail to the saddle, then down through the beech woods.",
+    "Quantized weights trade a little accuracy for faster inference on CPUs.",
+    "The hexad describes events as the interplay of three pairs of terms.",
+];
+
+/// Timed runs per measurement, of which the fastest is reported.
+const ROUNDS: usize = 10;
+
+async fn load(precision: InferencePrecision) -> EmbeddingService {
+    let mut config = ModelConfig {
+        inference_precision: precision,
+        // Measure the model, not the cache
+        cache_entries: 0,
+        persist_cache: false,
+        ..Default::default()
+    };
+    if let Ok(name) = std::env::var("SYSTEMATICS_MODEL_NAME") {
+        config.name = name;
+    }
+    EmbeddingService::new(&config)
+        .await
+        .unwrap_or_else(|e| panic!("Failed to load the {:?} model: {:#}", precision, e))
+}
+
+/// Fastest of `ROUNDS` embeddings of `texts` as one batch.
+async fn fastest(service: &EmbeddingService, texts: &[&str]) -> Duration {
+    let mut best = Duration::MAX;
+    for _ in 0..ROUNDS {
+        let start = Instant::now();
+        service.embed_batch(texts).await.expect("embedding failed");
+        best = best.min(start.elapsed());
+    }
+    best
+}
+
+/// The `k` texts most similar to text `i`, most similar first.
+fn neighbours(embeddings: &[Vec<f32>], i: usize, k: usize) -> Vec<usize> {
+    let mut scored: Vec<(f32, usize)> = embeddings
+        .iter()
+        .enumerate()
+        .filter(|&(j, _)| j != i)
+        .map(|(j, other)| (simd::dot(&embeddings[i], other), j))
+        .collect();
+    scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+    scored.into_iter().take(k).map(|(_, j)| j).collect()
+}
+
+#[tokio::main]
+async fn main() {
+    let fp32 = load(InferencePrecision::Fp32).await;
+    let int8 = load(InferencePrecision::Int8).await;
+
+    println!("{:<6}  {:>12}  {:>12}", "", "1 text", "16 texts");
+    let mut timings = Vec::new();
+    for (name, service) in [("fp32", &fp32), ("int8", &int8)] {
+        let single = fastest(service, &TEXTS[..1]).await;
+        let batch = fastest(service, TEXTS).await;
+        println!(
+            "{:<6}  {:>10.2}ms  {:>10.2}ms",
+            name,
+            single.as_secs_f64() * 1000.0,
+            batch.as_secs_f64() * 1000.0
+        );
+        timings.push(batch);
+    }
+    println!(
+        "int8 embeds a batch {:.1}x as fast",
+        timings[0].as_secs_f64() / timings[1].as_secs_f64()
+    );
+
+    let expected = fp32.embed_batch(TEXTS).await.expect("embedding failed");
+    let actual = int8.embed_batch(TEXTS).await.expect("embedding failed");
+    let similarities: Vec<f32> = expected
+        .iter()
+        .zip(&actual)
+        .map(|(a, b)| simd::dot(a, b))
+        .collect();
+    let mean = similarities.iter().sum::<f32>() / similarities.len() as f32;
+    let worst = similarities.iter().copied().fold(f32::MAX, f32::min);
+    println!(
+        "Cosine similarity to fp32: mean {:.4}, worst {:.4}",
+        mean, worst
+    );
+
+    // How often each text's 3 nearest neighbours come out the same
+    let k = 3;
+    let agreed: usize = (0..TEXTS.len())
+        .map(|i| {
+            let expected = neighbours(&expected, i, k);
+            neighbours(&actual, i, k)
+                .iter()
+                .filter(|j| expected.contains(j))
+                .count()
+        })
+        .sum();
+    println!(
+        "Top-{} neighbours shared with fp32: {:.1}%",
+        k,
+        100.0 * agreed as f64 / (TEXTS.len() * k) as f64
+    );
+}
@@ -0,0 +1,85 @@
+//! Times each similarity kernel this CPU supports against the portable
+//! one, scoring a query against a corpus of embedding-sized vectors the
+//! way an exact search does.
+//!
+//! ```bash
+//! cargo bench --bench similarity
+//! ```
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use systematics_embeddings::simd;
+
+/// Vectors in the scanned corpus, around the exact-search threshold.
+const CORPUS: usize = 5000;
+
+/// Scans timed per kernel, of which the fastest is reported.
+const ROUNDS: usize = 20;
+
+fn random_vectors(count: usize, dims: usize) -> Vec<Vec<f32>> {
+    let mut state = 0x2545_f491_u32;
+    (0..count)
+        .map(|_| {
+            let mut vector: Vec<f32> = (0..dims)
+                .map(|_| {
+                    state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                    (state >> 8) as f32 / (1u32 << 24) as f32 - 0.5
+                })
+                .collect();
+            simd::normalize(&mut vector);
+            vector
+        })
+        .collect()
+}
+
+/// Fastest of `ROUNDS` scans of the corpus with `score`.
+fn fastest_scan(
+    corpus: &[Vec<f32>],
+    query: &[f32],
+    score: impl Fn(&[f32], &[f32]) -> f32,
+) -> Duration {
+    (0..ROUNDS)
+        .map(|_| {
+            let start = Instant::now();
+            let mut best = f32::MIN;
+            for vector in corpus {
+                best = best.max(score(black_box(query), black_box(vector)));
+            }
+            black_box(best);
+            start.elapsed()
+        })
+        .min()
+        .expect("at least one round")
+}
+
+fn main() {
+    println!("Detected kernel: {:?}", simd::kernel());
+    println!(
+        "{:>6}  {:<8}  {:>14}  {:>14}  {:>8}",
+        "dims", "kernel", "cosine/vector", "dot/vector", "speedup"
+    );
+
+    for dims in [384, 768, 1024] {
+        let corpus = random_vectors(CORPUS, dims);
+        let query = &corpus[0];
+
+        let mut baseline = None;
+        for kernel in simd::available() {
+            let cosine = fastest_scan(&corpus, query, |a, b| simd::cosine_with(kernel, a, b));
+            let dot = fastest_scan(&corpus, query, |a, b| simd::dot_with(kernel, a, b));
+            // Unit vectors turn the scalar cosine the index used to compute
+            // into the dot product it computes now
+            let baseline = *baseline.get_or_insert(cosine);
+            let name = format!("{:?}", kernel);
+            println!(
+                "{:>6}  {:<8}  {:>12.1}ns  {:>12.1}ns  {:>7.1}x",
+                dims,
+                name,
+                cosine.as_nanos() as f64 / CORPUS as f64,
+                dot.as_nanos() as f64 / CORPUS as f64,
+                baseline.as_secs_f64() / dot.as_secs_f64(),
+            );
+        }
+    }
+}
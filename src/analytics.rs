@@ -0,0 +1,211 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tracing::warn;
+
+use crate::feedback::FeedbackEvent;
+
+const LOG_FILE: &str = "analytics.jsonl";
+/// The previous log, kept after rotation until the next one
+const ROTATED_FILE: &str = "analytics.1.jsonl";
+
+/// A search as it was run, without the result texts.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SearchEvent {
+    /// SHA-256 of the query, so repeated queries can be counted without
+    /// keeping what was searched for
+    pub query_hash: String,
+    /// The query itself, kept only when `SYSTEMATICS_ANALYTICS_QUERY_TEXT`
+    /// is set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub query: Option<String>,
+    /// Collections searched; empty for the default collection
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub collections: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filter: Option<Value>,
+    pub result_ids: Vec<String>,
+    pub latency_ms: u64,
+    /// Retrieval config in effect, as listed by `/config/history`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub config_hash: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum AnalyticsEvent {
+    Search(SearchEvent),
+    Feedback(FeedbackEvent),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AnalyticsRecord {
+    pub timestamp: DateTime<Utc>,
+    #[serde(flatten)]
+    pub event: AnalyticsEvent,
+}
+
+struct LogWriter {
+    file: File,
+    len: u64,
+}
+
+/// Append-only log of searches and feedback, stored as JSON lines in the
+/// data directory. When the log reaches half its size cap it is rotated:
+/// the previous log is replaced by the current one and a new one started,
+/// so the two together never take much more than the cap.
+pub struct AnalyticsLog {
+    dir: PathBuf,
+    max_bytes: u64,
+    writer: Mutex<LogWriter>,
+}
+
+impl AnalyticsLog {
+    /// Open the log in `dir`, returning it with the records it already
+    /// holds, oldest first. A line torn by a crash mid-write is dropped.
+    pub fn open(dir: &Path, max_bytes: u64) -> Result<(Self, Vec<AnalyticsRecord>)> {
+        fs::create_dir_all(dir)?;
+        let mut records = read_records(&dir.join(ROTATED_FILE))?;
+
+        let path = dir.join(LOG_FILE);
+        records.extend(read_records(&path)?);
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let len = truncate_torn_line(&path, &file)?;
+
+        let log = Self {
+            dir: dir.to_path_buf(),
+            max_bytes,
+            writer: Mutex::new(LogWriter { file, len }),
+        };
+        Ok((log, records))
+    }
+
+    /// Append an event, timestamped now. Each record is written with a
+    /// single write, so a crash can at worst leave one partial line.
+    pub fn record(&self, event: AnalyticsEvent) -> Result<()> {
+        let record = AnalyticsRecord {
+            timestamp: Utc::now(),
+            event,
+        };
+        let mut line = serde_json::to_vec(&record)?;
+        line.push(b'\n');
+
+        let mut writer = self.writer.lock().unwrap();
+        if writer.len > 0 && writer.len + line.len() as u64 > self.max_bytes / 2 {
+            fs::rename(self.dir.join(LOG_FILE), self.dir.join(ROTATED_FILE))?;
+            writer.file = File::create(self.dir.join(LOG_FILE))?;
+            writer.len = 0;
+        }
+        writer.file.write_all(&line)?;
+        writer.len += line.len() as u64;
+        Ok(())
+    }
+
+    /// Records at or after `since`, oldest first.
+    pub fn export(&self, since: Option<DateTime<Utc>>) -> Result<Vec<AnalyticsRecord>> {
+        // Hold the writer so a rotation can't move records between reads
+        let _writer = self.writer.lock().unwrap();
+        let mut records = read_records(&self.dir.join(ROTATED_FILE))?;
+        records.extend(read_records(&self.dir.join(LOG_FILE))?);
+        records.retain(|record| since.is_none_or(|since| record.timestamp >= since));
+        Ok(records)
+    }
+}
+
+/// Every parseable record in the file at `path`. Lines that don't parse,
+/// such as a torn last line, are skipped.
+fn read_records(path: &Path) -> Result<Vec<AnalyticsRecord>> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let contents = fs::read_to_string(path)?;
+    let mut records = Vec::new();
+    for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+        match serde_json::from_str(line) {
+            Ok(record) => records.push(record),
+            Err(e) => warn!("Skipping unreadable analytics record in {:?}: {}", path, e),
+        }
+    }
+    Ok(records)
+}
+
+/// Cut the log back to its last complete line, so the next record starts
+/// on a line of its own. Returns the resulting length.
+fn truncate_torn_line(path: &Path, file: &File) -> Result<u64> {
+    let contents = fs::read(path)?;
+    let complete = contents
+        .iter()
+        .rposition(|&b| b == b'\n')
+        .map_or(0, |last| last + 1);
+    if complete < contents.len() {
+        file.set_len(complete as u64)?;
+    }
+    Ok(complete as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn search(id: &str) -> AnalyticsEvent {
+        AnalyticsEvent::Search(SearchEvent {
+            query_hash: "abc".to_string(),
+            query: None,
+            collections: Vec::new(),
+            filter: None,
+            result_ids: vec![id.to_string()],
+            latency_ms: 3,
+            config_hash: None,
+        })
+    }
+
+    #[test]
+    fn test_analytics_log_survives_torn_writes_and_rotates() {
+        let dir =
+            std::env::temp_dir().join(format!("systematics-analytics-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let (log, records) = AnalyticsLog::open(&dir, 1024).unwrap();
+        assert!(records.is_empty());
+        log.record(search("a")).unwrap();
+        log.record(AnalyticsEvent::Feedback(FeedbackEvent {
+            query: "triads".to_string(),
+            id: "a".to_string(),
+            relevant: true,
+        }))
+        .unwrap();
+        drop(log);
+
+        // A crash mid-write leaves half a line behind
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(dir.join(LOG_FILE))
+            .unwrap();
+        file.write_all(b"{\"timestamp\":\"20").unwrap();
+
+        let (log, records) = AnalyticsLog::open(&dir, 1024).unwrap();
+        assert_eq!(records.len(), 2);
+        assert!(matches!(records[1].event, AnalyticsEvent::Feedback(_)));
+        log.record(search("b")).unwrap();
+        assert_eq!(log.export(None).unwrap().len(), 3);
+
+        // Past the cap, the oldest records are dropped a log at a time
+        for i in 0..50 {
+            log.record(search(&i.to_string())).unwrap();
+        }
+        let size = |name| fs::metadata(dir.join(name)).unwrap().len();
+        assert!(size(LOG_FILE) + size(ROTATED_FILE) <= 1024);
+        let exported = log.export(None).unwrap();
+        assert!(exported.len() < 53);
+        assert_eq!(
+            exported.last().unwrap().event,
+            search("49"),
+            "the newest record is always kept"
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,571 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tracing::info;
+
+use crate::config::Config;
+use crate::index::{self, CollectionSettings, Collections, IndexedDocument, VectorIndex};
+use crate::migrations::{self, BACKUP_DIR};
+use crate::storage::{self, Snapshot};
+use crate::vector::ProductQuantizer;
+
+/// Version of the backup file layout. Backups written by a newer build are
+/// refused rather than misread.
+pub const BACKUP_FORMAT: u32 = 1;
+
+const EXTENSION: &str = "snapshot";
+
+/// Summary of one backup, stored at the end of its file.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BackupInfo {
+    pub format: u32,
+    pub id: String,
+    /// Backup this one holds the changes since; unset for a full backup
+    pub base: Option<String>,
+    pub created_at: DateTime<Utc>,
+    /// Documents across every collection when the backup was taken
+    pub documents: usize,
+    /// Documents written to the backup: every one for a full backup, those
+    /// added or changed since the base for an incremental one
+    pub changed: usize,
+    /// Documents deleted since the base
+    pub deleted: usize,
+    pub bytes: u64,
+}
+
+/// Every document each collection held when a backup was taken, by
+/// fingerprint, so the next incremental backup can tell what changed and
+/// a restore can check it got everything back.
+#[derive(Serialize, Deserialize, Default)]
+struct Manifest {
+    collections: BTreeMap<String, CollectionManifest>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CollectionManifest {
+    settings: CollectionSettings,
+    documents: HashMap<String, u64>,
+}
+
+/// One collection's part of a backup. Aliases and centroids are small, so
+/// they are always stored whole.
+#[derive(Serialize)]
+struct CollectionDeltaRef<'a> {
+    name: &'a str,
+    aliases: &'a HashMap<String, String>,
+    quantizer: Option<&'a ProductQuantizer>,
+    documents: Vec<&'a IndexedDocument>,
+    deleted: Vec<&'a str>,
+}
+
+#[derive(Deserialize)]
+struct CollectionDelta {
+    name: String,
+    aliases: HashMap<String, String>,
+    quantizer: Option<ProductQuantizer>,
+    documents: Vec<IndexedDocument>,
+    deleted: Vec<String>,
+}
+
+/// A collection as restored from a backup chain.
+pub struct RestoredCollection {
+    pub settings: CollectionSettings,
+    pub snapshot: Snapshot,
+}
+
+/// The state a backup chain restores to.
+pub struct Restored {
+    /// The backups applied, full backup first
+    pub chain: Vec<BackupInfo>,
+    pub collections: BTreeMap<String, RestoredCollection>,
+}
+
+/// Where backups of `data_dir` are written.
+pub fn backup_dir(data_dir: &Path) -> PathBuf {
+    data_dir.join(BACKUP_DIR)
+}
+
+/// Back up every collection to a new file under `backups/`. An
+/// incremental backup holds only what changed since the latest backup,
+/// which must exist. Files are written whole and renamed into place, so an
+/// interrupted backup leaves no partial file behind.
+///
+/// A backup file is a sequence of length-prefixed MessagePack records, one
+/// per collection, followed by the manifest, the [`BackupInfo`], and the
+/// lengths of those two, so either can be read without the rest.
+pub async fn create(
+    data_dir: &Path,
+    collections: &Collections,
+    incremental: bool,
+) -> Result<BackupInfo> {
+    let mut targets = Vec::new();
+    for (name, index) in collections.writable() {
+        let settings = collections.settings(Some(&name)).await?;
+        targets.push((name, index, settings));
+    }
+    let data_dir = data_dir.to_path_buf();
+    // Serializing and writing every collection takes a while, so it runs
+    // off the async runtime
+    tokio::task::spawn_blocking(move || write(&data_dir, targets, incremental)).await?
+}
+
+/// Write a backup of `targets`, given as `(name, index, settings)`.
+fn write(
+    data_dir: &Path,
+    targets: Vec<(String, Arc<VectorIndex>, CollectionSettings)>,
+    incremental: bool,
+) -> Result<BackupInfo> {
+    let dir = backup_dir(data_dir);
+    fs::create_dir_all(&dir)?;
+    let (base, base_manifest) = if incremental {
+        let base = list(data_dir)?.pop().ok_or_else(|| {
+            anyhow::anyhow!("No backup to base an incremental backup on; take a full backup first")
+        })?;
+        let manifest = read_manifest(&path_of(&dir, &base.id))?;
+        (Some(base.id), manifest)
+    } else {
+        (None, Manifest::default())
+    };
+
+    let timestamp = Utc::now().format("%Y%m%dT%H%M%S%3fZ").to_string();
+    let mut id = timestamp.clone();
+    for n in 1.. {
+        if !path_of(&dir, &id).exists() {
+            break;
+        }
+        id = format!("{}-{}", timestamp, n);
+    }
+    let path = path_of(&dir, &id);
+    let tmp_path = path.with_extension(format!("{}.tmp", EXTENSION));
+    let mut file = BufWriter::new(File::create(&tmp_path)?);
+
+    let mut manifest = Manifest::default();
+    let (mut documents, mut changed, mut deleted) = (0, 0, 0);
+    for (name, index, settings) in targets {
+        let previous = base_manifest.collections.get(&name);
+        let fingerprints = index.read_contents(|docs, aliases, quantizer| -> Result<_> {
+            let fingerprints: HashMap<String, u64> = docs
+                .iter()
+                .map(|(id, doc)| Ok((id.clone(), fingerprint(doc)?)))
+                .collect::<Result<_>>()?;
+            let delta = CollectionDeltaRef {
+                name: &name,
+                aliases,
+                quantizer,
+                documents: docs
+                    .values()
+                    .filter(|doc| {
+                        previous.and_then(|previous| previous.documents.get(&doc.id))
+                            != fingerprints.get(&doc.id)
+                    })
+                    .collect(),
+                deleted: previous
+                    .map(|previous| {
+                        previous
+                            .documents
+                            .keys()
+                            .filter(|id| !docs.contains_key(*id))
+                            .map(String::as_str)
+                            .collect()
+                    })
+                    .unwrap_or_default(),
+            };
+            changed += delta.documents.len();
+            deleted += delta.deleted.len();
+            write_record(&mut file, &delta)?;
+            Ok(fingerprints)
+        })?;
+        documents += fingerprints.len();
+        manifest.collections.insert(
+            name,
+            CollectionManifest {
+                settings,
+                documents: fingerprints,
+            },
+        );
+    }
+
+    let manifest_len = write_record(&mut file, &manifest)?;
+    let mut info = BackupInfo {
+        format: BACKUP_FORMAT,
+        id,
+        base,
+        created_at: Utc::now(),
+        documents,
+        changed,
+        deleted,
+        bytes: 0,
+    };
+    let info_len = write_record(&mut file, &info)?;
+    file.write_all(&manifest_len.to_le_bytes())?;
+    file.write_all(&info_len.to_le_bytes())?;
+    let file = file.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()?;
+    info.bytes = file.metadata()?.len();
+    fs::rename(&tmp_path, &path)?;
+
+    info!(
+        "Wrote {} backup {} with {} of {} documents",
+        if info.base.is_some() {
+            "incremental"
+        } else {
+            "full"
+        },
+        info.id,
+        info.changed,
+        info.documents
+    );
+    Ok(info)
+}
+
+/// Every backup of `data_dir`, oldest first.
+pub fn list(data_dir: &Path) -> Result<Vec<BackupInfo>> {
+    let dir = backup_dir(data_dir);
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut backups = Vec::new();
+    for entry in fs::read_dir(&dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == EXTENSION) {
+            backups.push(read_info(&path)?);
+        }
+    }
+    backups.sort_by(|a, b| a.id.cmp(&b.id));
+    Ok(backups)
+}
+
+/// The backups `id` is restored from: the full backup it builds on, then
+/// each incremental backup up to and including `id`.
+pub fn chain(backup_dir: &Path, id: &str) -> Result<Vec<BackupInfo>> {
+    let mut chain = Vec::new();
+    let mut next = Some(id.to_string());
+    while let Some(id) = next {
+        let path = path_of(backup_dir, &id);
+        if !path.exists() {
+            anyhow::bail!("Backup {} not found in {:?}", id, backup_dir);
+        }
+        let info = read_info(&path)?;
+        if chain.iter().any(|later: &BackupInfo| later.id == info.id) {
+            anyhow::bail!("Backup {} is its own base", info.id);
+        }
+        next = info.base.clone();
+        chain.push(info);
+    }
+    chain.reverse();
+    Ok(chain)
+}
+
+/// Apply the chain of backups ending at `id` in order, and check the
+/// result holds exactly the documents `id` recorded.
+pub fn restore(backup_dir: &Path, id: &str) -> Result<Restored> {
+    let chain = chain(backup_dir, id)?;
+
+    type State = (
+        HashMap<String, IndexedDocument>,
+        HashMap<String, String>,
+        Option<ProductQuantizer>,
+    );
+    let mut state: BTreeMap<String, State> = BTreeMap::new();
+    let mut manifest = Manifest::default();
+    for info in &chain {
+        let path = path_of(backup_dir, &info.id);
+        let file = File::open(&path)?;
+        let file_len = file.metadata()?.len();
+        let mut reader = BufReader::new(file);
+        manifest = read_manifest(&path)?;
+        for _ in 0..manifest.collections.len() {
+            let delta: CollectionDelta = read_record(&mut reader, file_len)
+                .with_context(|| format!("Backup {} is truncated or corrupt", info.id))?;
+            let (documents, aliases, quantizer) = state.entry(delta.name).or_default();
+            for id in delta.deleted {
+                documents.remove(&id);
+            }
+            documents.extend(delta.documents.into_iter().map(|doc| (doc.id.clone(), doc)));
+            *aliases = delta.aliases;
+            *quantizer = delta.quantizer;
+        }
+        // Collections deleted since the base
+        state.retain(|name, _| manifest.collections.contains_key(name));
+    }
+
+    let mut collections = BTreeMap::new();
+    for (name, (documents, aliases, quantizer)) in state {
+        let expected = &manifest.collections[&name];
+        if documents.len() != expected.documents.len() {
+            anyhow::bail!(
+                "Restoring {} gives collection {} {} documents, but it had {}",
+                id,
+                name,
+                documents.len(),
+                expected.documents.len()
+            );
+        }
+        for doc in documents.values() {
+            if expected.documents.get(&doc.id) != Some(&fingerprint(doc)?) {
+                anyhow::bail!(
+                    "Restoring {} gives a different version of {} in collection {} than was backed up",
+                    id,
+                    doc.id,
+                    name
+                );
+            }
+        }
+        collections.insert(
+            name,
+            RestoredCollection {
+                settings: expected.settings.clone(),
+                snapshot: Snapshot {
+                    documents: documents.into_values().collect(),
+                    aliases,
+                    quantizer,
+                },
+            },
+        );
+    }
+    Ok(Restored { chain, collections })
+}
+
+/// Restore the chain of backups ending at `id` into `data_dir`, which must
+/// be empty or not exist yet, for the server to be started on afterwards.
+/// Each collection is written as a single compacted snapshot, in the
+/// configured storage backend. The restored directory is then opened and
+/// verified: every collection must load with the documents the backup
+/// recorded and no integrity issues.
+pub async fn restore_to(
+    backup_dir: &Path,
+    id: &str,
+    data_dir: &Path,
+    config: &Config,
+) -> Result<Restored> {
+    if data_dir.exists() && fs::read_dir(data_dir)?.next().is_some() {
+        anyhow::bail!(
+            "{:?} isn't empty; restore into a new data directory",
+            data_dir
+        );
+    }
+    let restored = restore(backup_dir, id)?;
+    migrations::run(data_dir)?;
+
+    for (name, collection) in &restored.collections {
+        let dir = data_dir.join("collections").join(name);
+        let (store, _, _) = storage::open(&dir, config.storage)?;
+        let snapshot = &collection.snapshot;
+        store.snapshot(
+            &snapshot.documents.iter().collect::<Vec<_>>(),
+            &snapshot.aliases,
+            snapshot.quantizer.as_ref(),
+        )?;
+        index::write_settings(&dir, &collection.settings)?;
+    }
+
+    let collections = Collections::open(
+        data_dir,
+        config.model.precision,
+        config.quantization,
+        config.hnsw,
+        config.storage,
+    )?;
+    for (name, collection) in &restored.collections {
+        let index = collections.get(Some(name)).await?;
+        let expected = collection.snapshot.documents.len();
+        let documents = index.count().await;
+        if documents != expected {
+            anyhow::bail!(
+                "Restored collection {} loads with {} documents, expected {}",
+                name,
+                documents,
+                expected
+            );
+        }
+        let report = index.verify(false).await?;
+        if !report.issues.is_empty() {
+            anyhow::bail!(
+                "Restored collection {} has {} integrity issues",
+                name,
+                report.issues.len()
+            );
+        }
+    }
+    info!("Restored backup {} into {:?}", id, data_dir);
+    Ok(restored)
+}
+
+fn path_of(backup_dir: &Path, id: &str) -> PathBuf {
+    backup_dir.join(format!("{}.{}", id, EXTENSION))
+}
+
+/// Identifies a document's exact stored state: text, metadata, vectors,
+/// and version.
+fn fingerprint(doc: &IndexedDocument) -> Result<u64> {
+    let digest = Sha256::digest(rmp_serde::to_vec_named(doc)?);
+    Ok(u64::from_le_bytes(digest[..8].try_into().unwrap()))
+}
+
+/// Write `value` prefixed with its length, returning the bytes written.
+fn write_record(file: &mut impl Write, value: &impl Serialize) -> Result<u64> {
+    let bytes = rmp_serde::to_vec_named(value)?;
+    file.write_all(&(bytes.len() as u64).to_le_bytes())?;
+    file.write_all(&bytes)?;
+    Ok(8 + bytes.len() as u64)
+}
+
+/// Read a record written by [`write_record`] from a file `file_len` bytes
+/// long.
+fn read_record<T: DeserializeOwned>(reader: &mut (impl Read + Seek), file_len: u64) -> Result<T> {
+    let mut len = [0u8; 8];
+    reader.read_exact(&mut len)?;
+    let len = u64::from_le_bytes(len);
+    // A corrupt length mustn't allocate more than the file could hold
+    if len > file_len.saturating_sub(reader.stream_position()?) {
+        anyhow::bail!("record of {} bytes runs past the end of the file", len);
+    }
+    let mut bytes = vec![0u8; len as usize];
+    reader.read_exact(&mut bytes)?;
+    Ok(rmp_serde::from_slice(&bytes)?)
+}
+
+/// Offsets from the end of a backup file of its manifest and info records.
+fn trailer(file: &mut File) -> Result<(u64, u64)> {
+    file.seek(SeekFrom::End(-16)).map_err(|e| match e.kind() {
+        ErrorKind::InvalidInput => anyhow::anyhow!("not a backup file"),
+        _ => e.into(),
+    })?;
+    let mut lens = [0u8; 16];
+    file.read_exact(&mut lens)?;
+    let manifest_len = u64::from_le_bytes(lens[..8].try_into().unwrap());
+    let info_len = u64::from_le_bytes(lens[8..].try_into().unwrap());
+    Ok((16 + info_len + manifest_len, 16 + info_len))
+}
+
+fn read_info(path: &Path) -> Result<BackupInfo> {
+    let read = || -> Result<BackupInfo> {
+        let mut file = File::open(path)?;
+        let (_, info_at) = trailer(&mut file)?;
+        file.seek(SeekFrom::End(-(info_at as i64)))?;
+        let file_len = file.metadata()?.len();
+        let mut info: BackupInfo = read_record(&mut file, file_len)?;
+        info.bytes = file_len;
+        Ok(info)
+    };
+    let info = read().with_context(|| format!("Failed to read backup {:?}", path))?;
+    if info.format > BACKUP_FORMAT {
+        anyhow::bail!(
+            "Backup {} uses format {}, but this build only reads up to {}",
+            info.id,
+            info.format,
+            BACKUP_FORMAT
+        );
+    }
+    Ok(info)
+}
+
+fn read_manifest(path: &Path) -> Result<Manifest> {
+    let mut file = File::open(path)?;
+    let (manifest_at, _) = trailer(&mut file)?;
+    file.seek(SeekFrom::End(-(manifest_at as i64)))?;
+    let file_len = file.metadata()?.len();
+    read_record(&mut BufReader::new(file), file_len)
+        .with_context(|| format!("Failed to read the manifest of backup {:?}", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{HnswConfig, Precision, QuantizationConfig, StorageConfig};
+
+    #[tokio::test]
+    async fn test_incremental_backup_chain_restores() {
+        let dir = std::env::temp_dir().join(format!("systematics-backup-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let collections = Collections::open(
+            &dir,
+            Precision::F32,
+            QuantizationConfig::default(),
+            HnswConfig::default(),
+            StorageConfig::default(),
+        )
+        .unwrap();
+        let index = collections.get(None).await.unwrap();
+        for id in ["a", "b", "c"] {
+            index
+                .add(id, vec![1.0, 0.0], id.to_string(), None)
+                .await
+                .unwrap();
+        }
+        assert!(create(&dir, &collections, true).await.is_err());
+        let full = create(&dir, &collections, false).await.unwrap();
+        assert_eq!((full.changed, full.documents), (3, 3));
+
+        index
+            .add("b", vec![0.0, 1.0], "b2".to_string(), None)
+            .await
+            .unwrap();
+        index.delete("c").await.unwrap();
+        index.add_alias("old-a", "a").await.unwrap();
+        let incremental = create(&dir, &collections, true).await.unwrap();
+        assert_eq!(incremental.base.as_deref(), Some(full.id.as_str()));
+        assert_eq!((incremental.changed, incremental.deleted), (1, 1));
+        assert!(incremental.bytes < full.bytes);
+
+        let restored = restore(&backup_dir(&dir), &incremental.id).unwrap();
+        let ids: Vec<&str> = restored.chain.iter().map(|info| info.id.as_str()).collect();
+        assert_eq!(ids, [full.id.as_str(), incremental.id.as_str()]);
+        let default = &restored.collections["default"].snapshot;
+        let mut texts: Vec<&str> = default
+            .documents
+            .iter()
+            .map(|doc| doc.text.as_str())
+            .collect();
+        texts.sort();
+        assert_eq!(texts, ["a", "b2"]);
+        assert_eq!(default.aliases.get("old-a").map(String::as_str), Some("a"));
+
+        // The full backup alone still restores the state it was taken in
+        let restored = restore(&backup_dir(&dir), &full.id).unwrap();
+        assert_eq!(restored.collections["default"].snapshot.documents.len(), 3);
+
+        // The chain restores into a fresh data directory the server can open
+        let target = dir.join("restored");
+        let config = Config::default();
+        restore_to(&backup_dir(&dir), &incremental.id, &target, &config)
+            .await
+            .unwrap();
+        let reopened = Collections::open(
+            &target,
+            Precision::F32,
+            QuantizationConfig::default(),
+            HnswConfig::default(),
+            StorageConfig::default(),
+        )
+        .unwrap();
+        let default = reopened.get(None).await.unwrap();
+        assert_eq!(default.get("b").await.unwrap().unwrap().text, "b2");
+        assert_eq!(default.count().await, 2);
+        assert!(
+            restore_to(&backup_dir(&dir), &incremental.id, &target, &config)
+                .await
+                .is_err()
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_read_record_rejects_length_past_end() {
+        let mut bytes = Vec::new();
+        write_record(&mut bytes, &"record").unwrap();
+        let len = bytes.len() as u64;
+        let value: String = read_record(&mut std::io::Cursor::new(&bytes), len).unwrap();
+        assert_eq!(value, "record");
+
+        bytes[..8].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(read_record::<String>(&mut std::io::Cursor::new(&bytes), len).is_err());
+    }
+}
@@ -0,0 +1,445 @@
+use anyhow::{Context, Result};
+use clap::Parser;
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Soak test for a running server: writers keep syncing notes while
+/// searchers type queries, and latency percentiles and error rates are
+/// reported as it runs.
+#[derive(Parser, Debug, Clone)]
+#[command(about)]
+struct Args {
+    /// Server to load, including the route prefix
+    #[arg(long, default_value = "http://127.0.0.1:8765/v1")]
+    url: String,
+    /// Clients syncing notes, as the Obsidian plugin does on save
+    #[arg(long, default_value_t = 2)]
+    writers: usize,
+    /// Clients searching interactively
+    #[arg(long, default_value_t = 8)]
+    searchers: usize,
+    /// How long to run, e.g. `90s`, `30m`, or `4h`
+    #[arg(long, default_value = "10m", value_parser = parse_duration)]
+    duration: Duration,
+    /// How often to print the latest window's figures
+    #[arg(long, default_value = "30s", value_parser = parse_duration)]
+    report_every: Duration,
+    /// Collection written and searched, created if missing
+    #[arg(long, default_value = "loadgen")]
+    collection: String,
+    /// Distinct notes writers cycle through
+    #[arg(long, default_value_t = 5000)]
+    notes: usize,
+    /// Notes per bulk sync
+    #[arg(long, default_value_t = 50)]
+    sync_batch: usize,
+    /// Pause between a writer's syncs
+    #[arg(long, default_value = "2s", value_parser = parse_duration)]
+    sync_interval: Duration,
+    /// Pause between a searcher's queries, like keystrokes
+    #[arg(long, default_value = "300ms", value_parser = parse_duration)]
+    think_time: Duration,
+    /// Origin header to send, for servers in hardened mode
+    #[arg(long)]
+    origin: Option<String>,
+    /// Seed for the generated notes and queries
+    #[arg(long, default_value_t = 1)]
+    seed: u64,
+}
+
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let (number, unit) = s
+        .find(|c: char| !c.is_ascii_digit())
+        .map_or((s, "s"), |i| s.split_at(i));
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("Invalid duration {:?}", s))?;
+    match unit {
+        "ms" => Ok(Duration::from_millis(number)),
+        "s" => Ok(Duration::from_secs(number)),
+        "m" => Ok(Duration::from_secs(number * 60)),
+        "h" => Ok(Duration::from_secs(number * 3600)),
+        _ => Err(format!("Unknown unit in {:?}, expected ms, s, m, or h", s)),
+    }
+}
+
+/// Latencies in logarithmic buckets 2% wide, so memory stays fixed however
+/// long the run and percentiles are within 2%.
+#[derive(Clone)]
+struct Histogram {
+    buckets: Vec<u64>,
+    count: u64,
+    errors: u64,
+    max: Duration,
+}
+
+const BUCKET_GROWTH: f64 = 1.02;
+/// Buckets from a microsecond to well past the client timeout
+const BUCKETS: usize = 1100;
+
+impl Histogram {
+    fn new() -> Self {
+        Self {
+            buckets: vec![0; BUCKETS],
+            count: 0,
+            errors: 0,
+            max: Duration::ZERO,
+        }
+    }
+
+    fn record(&mut self, latency: Duration, ok: bool) {
+        let micros = latency.as_micros().max(1) as f64;
+        let bucket = (micros.ln() / BUCKET_GROWTH.ln()) as usize;
+        self.buckets[bucket.min(BUCKETS - 1)] += 1;
+        self.count += 1;
+        self.errors += u64::from(!ok);
+        self.max = self.max.max(latency);
+    }
+
+    fn merge(&mut self, other: &Histogram) {
+        for (a, b) in self.buckets.iter_mut().zip(&other.buckets) {
+            *a += b;
+        }
+        self.count += other.count;
+        self.errors += other.errors;
+        self.max = self.max.max(other.max);
+    }
+
+    /// Upper bound of the bucket holding the `p`th percentile.
+    fn percentile(&self, p: f64) -> Duration {
+        let target = ((p / 100.0) * self.count as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (bucket, &count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= target {
+                let micros = BUCKET_GROWTH.powi(bucket as i32 + 1);
+                return Duration::from_micros(micros as u64).min(self.max);
+            }
+        }
+        self.max
+    }
+}
+
+/// Histograms per operation for the current window and the whole run.
+#[derive(Default)]
+struct Stats {
+    window: BTreeMap<&'static str, Histogram>,
+    total: BTreeMap<&'static str, Histogram>,
+}
+
+impl Stats {
+    fn record(&mut self, operation: &'static str, latency: Duration, ok: bool) {
+        self.window
+            .entry(operation)
+            .or_insert_with(Histogram::new)
+            .record(latency, ok);
+    }
+
+    /// Print the window's figures and fold them into the totals.
+    fn report(&mut self, label: &str, elapsed: Duration) {
+        println!("--- {} ---", label);
+        print_table(&self.window, elapsed);
+        for (operation, histogram) in std::mem::take(&mut self.window) {
+            self.total
+                .entry(operation)
+                .or_insert_with(Histogram::new)
+                .merge(&histogram);
+        }
+    }
+}
+
+fn print_table(histograms: &BTreeMap<&'static str, Histogram>, elapsed: Duration) {
+    println!(
+        "{:<8} {:>9} {:>8} {:>8} {:>9} {:>9} {:>9} {:>9}",
+        "op", "requests", "req/s", "errors", "p50", "p90", "p99", "max"
+    );
+    let ms = |d: Duration| format!("{:.1}ms", d.as_secs_f64() * 1000.0);
+    for (operation, h) in histograms {
+        println!(
+            "{:<8} {:>9} {:>8.1} {:>7.2}% {:>9} {:>9} {:>9} {:>9}",
+            operation,
+            h.count,
+            h.count as f64 / elapsed.as_secs_f64().max(1e-9),
+            100.0 * h.errors as f64 / h.count.max(1) as f64,
+            ms(h.percentile(50.0)),
+            ms(h.percentile(90.0)),
+            ms(h.percentile(99.0)),
+            ms(h.max),
+        );
+    }
+}
+
+/// Small deterministic generator (xorshift64*), so runs with the same seed
+/// send the same notes and queries.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Self(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    fn chance(&mut self, p: f64) -> bool {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64 <= p
+    }
+}
+
+/// Vocabulary notes and queries are drawn from.
+const WORDS: &str = "monad dyad triad tetrad pentad hexad heptad octad system term relation \
+    structure energy will function being time eternity hazard order meaning value quality \
+    process integration purpose impulse pattern coalescence identity polarity complexity note \
+    project meeting idea research draft review journal";
+const TAGS: &[&str] = &["systematics", "journal", "project", "reading", "idea"];
+
+fn sentence(rng: &mut Rng, words: usize) -> String {
+    let vocabulary: Vec<&str> = WORDS.split_whitespace().collect();
+    (0..words)
+        .map(|_| vocabulary[rng.below(vocabulary.len())])
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn note(rng: &mut Rng, id: usize, revision: u64) -> Value {
+    let paragraphs = 1 + rng.below(6);
+    let text = (0..paragraphs)
+        .map(|_| {
+            let len = 10 + rng.below(60);
+            sentence(rng, len)
+        })
+        .collect::<Vec<_>>()
+        .join("\n\n");
+    json!({
+        "id": format!("notes/{}.md", id),
+        "text": format!("# Note {} (revision {})\n\n{}", id, revision, text),
+        "metadata": { "tags": [TAGS[rng.below(TAGS.len())]], "revision": revision },
+    })
+}
+
+struct Client {
+    http: reqwest::Client,
+    args: Args,
+    stats: Arc<Mutex<Stats>>,
+}
+
+impl Client {
+    /// Send a request and record its latency. Success means a 2xx status
+    /// and, for bulk syncs, no document failing. Deleting a note that isn't
+    /// indexed also succeeds, since the id picked may never have been
+    /// synced or may already be gone.
+    async fn send(&self, operation: &'static str, request: reqwest::RequestBuilder) {
+        let mut request = request;
+        if let Some(origin) = &self.args.origin {
+            request = request.header("Origin", origin);
+        }
+        let started = Instant::now();
+        let ok = match request.send().await {
+            Ok(response) if response.status().is_success() => match operation {
+                "sync" => response
+                    .json::<Value>()
+                    .await
+                    .is_ok_and(|body| body["failed"].as_u64() == Some(0)),
+                _ => response.bytes().await.is_ok(),
+            },
+            Ok(response) if operation == "delete" && response.status() == StatusCode::NOT_FOUND => {
+                response
+                    .json::<Value>()
+                    .await
+                    .is_ok_and(|body| body["code"] == "document_not_found")
+            }
+            _ => false,
+        };
+        self.stats
+            .lock()
+            .unwrap()
+            .record(operation, started.elapsed(), ok);
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.args.url.trim_end_matches('/'), path)
+    }
+
+    async fn writer(&self, seed: u64, running: &AtomicBool) {
+        let mut rng = Rng::new(seed);
+        let mut revision = 0;
+        while running.load(Ordering::Relaxed) {
+            revision += 1;
+            let body = (0..self.args.sync_batch)
+                .map(|_| {
+                    let id = rng.below(self.args.notes);
+                    note(&mut rng, id, revision).to_string()
+                })
+                .collect::<Vec<_>>()
+                .join("\n");
+            self.send(
+                "sync",
+                self.http
+                    .post(self.url("/index/bulk"))
+                    .query(&[("collection", &self.args.collection)])
+                    .header("Content-Type", "application/x-ndjson")
+                    .body(body),
+            )
+            .await;
+
+            // Notes are occasionally deleted, as in a real vault
+            if rng.chance(0.1) {
+                let id = format!("notes/{}.md", rng.below(self.args.notes));
+                self.send(
+                    "delete",
+                    self.http
+                        .delete(self.url(&format!("/index/{}", id)))
+                        .query(&[("collection", &self.args.collection)]),
+                )
+                .await;
+            }
+            tokio::time::sleep(self.args.sync_interval).await;
+        }
+    }
+
+    async fn searcher(&self, seed: u64, running: &AtomicBool) {
+        let mut rng = Rng::new(seed);
+        while running.load(Ordering::Relaxed) {
+            // Search as the user types each word of a query
+            let words = 1 + rng.below(5);
+            let query = sentence(&mut rng, words);
+            let mut typed = String::new();
+            for word in query.split(' ') {
+                if !typed.is_empty() {
+                    typed.push(' ');
+                }
+                typed.push_str(word);
+
+                let mut body = json!({
+                    "query": typed,
+                    "collection": self.args.collection,
+                    "limit": 10,
+                });
+                if rng.chance(0.3) {
+                    body["hybrid"] = json!({ "alpha": 0.5 });
+                }
+                if rng.chance(0.2) {
+                    body["filter"] = json!({ "tags": TAGS[rng.below(TAGS.len())] });
+                }
+                self.send("search", self.http.post(self.url("/search")).json(&body))
+                    .await;
+                tokio::time::sleep(self.args.think_time).await;
+            }
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    let http = reqwest::Client::builder()
+        .timeout(Duration::from_secs(60))
+        .build()?;
+
+    // The collection may already exist from an earlier run
+    let mut create = http
+        .post(format!("{}/collections", args.url.trim_end_matches('/')))
+        .json(&json!({ "name": args.collection }));
+    if let Some(origin) = &args.origin {
+        create = create.header("Origin", origin);
+    }
+    create
+        .send()
+        .await
+        .with_context(|| format!("Server not reachable at {}", args.url))?;
+
+    println!(
+        "Running {} writers and {} searchers against {} for {:?}",
+        args.writers, args.searchers, args.url, args.duration
+    );
+    let client = Arc::new(Client {
+        http,
+        args: args.clone(),
+        stats: Arc::new(Mutex::new(Stats::default())),
+    });
+    let running = Arc::new(AtomicBool::new(true));
+
+    let mut tasks = Vec::new();
+    for i in 0..args.writers + args.searchers {
+        let (client, running) = (client.clone(), running.clone());
+        let seed = args.seed.wrapping_add(i as u64);
+        let writer = i < args.writers;
+        tasks.push(tokio::spawn(async move {
+            if writer {
+                client.writer(seed, &running).await
+            } else {
+                client.searcher(seed, &running).await
+            }
+        }));
+    }
+
+    let started = Instant::now();
+    let mut window_started = started;
+    while started.elapsed() < args.duration {
+        let remaining = args.duration.saturating_sub(started.elapsed());
+        tokio::time::sleep(args.report_every.min(remaining)).await;
+        let label = format!("{:.0}s", started.elapsed().as_secs_f64());
+        client
+            .stats
+            .lock()
+            .unwrap()
+            .report(&label, window_started.elapsed());
+        window_started = Instant::now();
+    }
+
+    running.store(false, Ordering::Relaxed);
+    for task in tasks {
+        task.await?;
+    }
+    let mut stats = client.stats.lock().unwrap();
+    stats.report("final window", window_started.elapsed());
+    println!("=== total over {:.0}s ===", started.elapsed().as_secs_f64());
+    print_table(&stats.total, started.elapsed());
+
+    let failed = stats.total.values().any(|h| h.errors > 0);
+    if failed {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram_percentiles() {
+        let mut histogram = Histogram::new();
+        for ms in 1..=100 {
+            histogram.record(Duration::from_millis(ms), ms != 100);
+        }
+
+        let within = |actual: Duration, expected_ms: f64| {
+            let actual = actual.as_secs_f64() * 1000.0;
+            assert!(
+                (actual - expected_ms).abs() <= expected_ms * 0.03,
+                "{}ms, expected about {}ms",
+                actual,
+                expected_ms
+            );
+        };
+        within(histogram.percentile(50.0), 50.0);
+        within(histogram.percentile(99.0), 99.0);
+        assert_eq!(histogram.percentile(100.0), Duration::from_millis(100));
+        assert_eq!((histogram.count, histogram.errors), (100, 1));
+        assert_eq!(parse_duration("4h"), Ok(Duration::from_secs(4 * 3600)));
+        assert_eq!(parse_duration("250ms"), Ok(Duration::from_millis(250)));
+    }
+}
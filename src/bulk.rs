@@ -0,0 +1,577 @@
+use axum::extract::ConnectInfo;
+use axum::http::HeaderMap;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::fs::File;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter, Lines};
+use tokio::sync::Notify;
+
+use crate::chunking::{self, Document, DocumentEmbedding};
+use crate::codec::NdjsonLines;
+use crate::config::{ChunkingConfig, Config};
+use crate::embedding::EmbeddingService;
+use crate::error::AppError;
+use crate::ids::IdRule;
+use crate::index::{Upsert, UpsertStatus, VectorIndex};
+use crate::markdown;
+use crate::metadata;
+
+#[derive(Serialize, Deserialize)]
+pub struct BulkDocument {
+    /// Required unless the collection's id policy generates ids
+    #[serde(default)]
+    pub id: Option<String>,
+    pub text: String,
+    pub metadata: Option<Value>,
+}
+
+/// Outcome for one document of a bulk upload.
+#[derive(Serialize)]
+pub struct BulkItemResult {
+    /// Position of the document in the upload, counting from 0
+    pub index: usize,
+    /// Canonical id the document was indexed under, or the id it was sent
+    /// with if it failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    pub success: bool,
+    /// Whether the document was new or replaced one, if it was indexed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<UpsertStatus>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct BulkIndexResponse {
+    pub indexed: usize,
+    pub failed: usize,
+    /// How long the upload waited for other bulk jobs before starting
+    pub queued_ms: u64,
+    pub results: Vec<BulkItemResult>,
+}
+
+/// Documents of a bulk upload, read as NDJSON lines stream in or from an
+/// array decoded up front.
+pub enum BulkSource {
+    Lines(NdjsonLines),
+    Items(std::vec::IntoIter<BulkDocument>),
+    Spooled(Lines<BufReader<File>>),
+}
+
+impl BulkSource {
+    /// The next document, or why it couldn't be parsed. Fails only if the
+    /// body itself can't be read any further.
+    pub async fn next(&mut self) -> Result<Option<Result<BulkDocument, String>>, AppError> {
+        match self {
+            BulkSource::Lines(lines) => match lines.next_line().await {
+                Some(line) => Ok(Some(
+                    serde_json::from_slice(&line?).map_err(|e| format!("Invalid JSON: {}", e)),
+                )),
+                None => Ok(None),
+            },
+            BulkSource::Items(items) => Ok(items.next().map(Ok)),
+            BulkSource::Spooled(lines) => match lines.next_line().await {
+                Ok(Some(line)) => serde_json::from_str(&line)
+                    .map(Some)
+                    .map_err(|e| AppError::Internal(format!("Spooled upload is corrupt: {}", e))),
+                Ok(None) => Ok(None),
+                Err(e) => Err(spool_error(e)),
+            },
+        }
+    }
+}
+
+/// Directory under the data directory that uploads are spooled to.
+const SPOOL_DIR: &str = "spool";
+
+/// An upload written to disk while its job waits its turn, so it isn't
+/// held in memory. The file is removed when the spool is dropped.
+pub struct Spool {
+    path: PathBuf,
+    /// Documents in the upload, including any that couldn't be parsed
+    pub total: usize,
+}
+
+impl Spool {
+    /// Write every document of `source` to a new file, one per line,
+    /// keeping documents that couldn't be parsed as the error they failed
+    /// with.
+    pub async fn write(data_dir: &Path, source: &mut BulkSource) -> Result<Self, AppError> {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        let dir = data_dir.join(SPOOL_DIR);
+        tokio::fs::create_dir_all(&dir).await.map_err(spool_error)?;
+        let spool = Self {
+            path: dir.join(format!(
+                "bulk-{}-{}.ndjson",
+                std::process::id(),
+                NEXT.fetch_add(1, Ordering::Relaxed)
+            )),
+            total: 0,
+        };
+        spool.fill(source).await
+    }
+
+    async fn fill(mut self, source: &mut BulkSource) -> Result<Self, AppError> {
+        let mut file = BufWriter::new(File::create(&self.path).await.map_err(spool_error)?);
+        while let Some(item) = source.next().await? {
+            let mut line = serde_json::to_vec(&item)
+                .map_err(|e| AppError::Internal(format!("Can't spool the upload: {}", e)))?;
+            line.push(b'\n');
+            file.write_all(&line).await.map_err(spool_error)?;
+            self.total += 1;
+        }
+        file.flush().await.map_err(spool_error)?;
+        Ok(self)
+    }
+
+    /// Read the documents back, in the order they were uploaded.
+    pub async fn read(&self) -> Result<BulkSource, AppError> {
+        let file = File::open(&self.path).await.map_err(spool_error)?;
+        Ok(BulkSource::Spooled(BufReader::new(file).lines()))
+    }
+
+    /// Remove uploads left spooled by a previous run, whose jobs were lost
+    /// with it.
+    pub fn clear(data_dir: &Path) {
+        let dir = data_dir.join(SPOOL_DIR);
+        if let Err(e) = std::fs::remove_dir_all(&dir) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                tracing::warn!("Couldn't clear {}: {}", dir.display(), e);
+            }
+        }
+    }
+}
+
+fn spool_error(e: std::io::Error) -> AppError {
+    AppError::Internal(format!("Can't spool the upload: {}", e))
+}
+
+impl Drop for Spool {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Embeds and indexes a bulk upload batch by batch, recording the outcome
+/// of every document so one bad document doesn't abort the rest.
+pub struct BulkIndexer<'a> {
+    service: &'a EmbeddingService,
+    index: &'a VectorIndex,
+    config: &'a Config,
+    chunking: ChunkingConfig,
+    ids: IdRule,
+    template: Option<String>,
+    results: Vec<BulkItemResult>,
+}
+
+impl<'a> BulkIndexer<'a> {
+    pub fn new(service: &'a EmbeddingService, index: &'a VectorIndex, config: &'a Config) -> Self {
+        Self {
+            service,
+            index,
+            config,
+            chunking: config.chunking,
+            ids: IdRule::default(),
+            template: None,
+            results: Vec::new(),
+        }
+    }
+
+    /// Chunk documents with `chunking` rather than as configured.
+    pub fn with_chunking(mut self, chunking: ChunkingConfig) -> Self {
+        self.chunking = chunking;
+        self
+    }
+
+    /// Give documents ids by the collection's policy rather than require
+    /// them.
+    pub fn with_ids(mut self, ids: IdRule) -> Self {
+        self.ids = ids;
+        self
+    }
+
+    /// Embed documents' metadata with their text, rendered with the
+    /// collection's embedding template.
+    pub fn with_template(mut self, template: Option<String>) -> Self {
+        self.template = template;
+        self
+    }
+
+    /// Embed and index the documents in `batch`, leaving it empty.
+    pub async fn flush(&mut self, batch: &mut Vec<Result<BulkDocument, String>>) {
+        let mut sections = Vec::new();
+        let mut headers = Vec::new();
+        for item in batch.iter_mut() {
+            let Ok(doc) = item else {
+                continue;
+            };
+            let mut doc_sections = Vec::new();
+            if self.config.markdown.enabled {
+                let prepared =
+                    markdown::prepare(&doc.text, doc.metadata.take(), &self.config.markdown);
+                doc.text = prepared.text;
+                doc.metadata = prepared.metadata;
+                doc_sections = prepared.sections;
+            }
+            doc.metadata = doc
+                .metadata
+                .take()
+                .map(|metadata| metadata::normalize(&self.config.metadata, metadata));
+            // Content-derived ids hash the text as indexed
+            match self.ids.resolve(doc.id.take(), &doc.text) {
+                Ok(id) => {
+                    doc.id = Some(id);
+                    sections.push(doc_sections);
+                    headers.push(self.template.as_deref().and_then(|template| {
+                        metadata::render_template(template, doc.metadata.as_ref())
+                    }));
+                }
+                Err(e) => *item = Err(e.to_string()),
+            }
+        }
+        let documents: Vec<Document> = batch
+            .iter()
+            .filter_map(|item| item.as_ref().ok())
+            .zip(sections.iter().zip(&headers))
+            .map(|(doc, (sections, header))| {
+                (doc.text.as_str(), sections.as_slice(), header.as_deref())
+            })
+            .collect();
+        let mut embeddings = self.embed(&documents).await.into_iter();
+
+        for item in batch.drain(..) {
+            let index = self.results.len();
+            let result = match item {
+                Ok(doc) => {
+                    let embedding = embeddings.next().expect("one embedding per document");
+                    self.add(doc, embedding).await
+                }
+                Err(error) => Err((None, error)),
+            };
+
+            self.results.push(match result {
+                Ok(upsert) => BulkItemResult {
+                    index,
+                    id: Some(upsert.id),
+                    success: true,
+                    status: Some(upsert.status),
+                    version: Some(upsert.version),
+                    error: None,
+                },
+                Err((id, error)) => BulkItemResult {
+                    index,
+                    id,
+                    success: false,
+                    status: None,
+                    version: None,
+                    error: Some(error),
+                },
+            });
+        }
+    }
+
+    pub fn finish(self) -> BulkIndexResponse {
+        let indexed = self.results.iter().filter(|result| result.success).count();
+        BulkIndexResponse {
+            indexed,
+            failed: self.results.len() - indexed,
+            queued_ms: 0,
+            results: self.results,
+        }
+    }
+
+    async fn embed(&self, documents: &[Document<'_>]) -> Vec<Result<DocumentEmbedding, String>> {
+        let chunking = self.chunking;
+        match chunking::embed_sections(self.service, documents, chunking).await {
+            Ok(embeddings) => embeddings.into_iter().map(Ok).collect(),
+            Err(_) => {
+                // One bad document fails the whole batch, so embed them
+                // one at a time to find out which
+                let mut embeddings = Vec::with_capacity(documents.len());
+                for document in documents {
+                    embeddings.push(
+                        chunking::embed_sections(
+                            self.service,
+                            std::slice::from_ref(document),
+                            chunking,
+                        )
+                        .await
+                        .map(|mut embedded| embedded.remove(0))
+                        .map_err(|e| e.to_string()),
+                    );
+                }
+                embeddings
+            }
+        }
+    }
+
+    async fn add(
+        &self,
+        doc: BulkDocument,
+        embedding: Result<DocumentEmbedding, String>,
+    ) -> Result<Upsert, (Option<String>, String)> {
+        let id = doc.id.expect("ids are resolved before embedding");
+        let embedding = embedding.map_err(|error| (Some(id.clone()), error))?;
+        self.index
+            .add(&id, embedding, doc.text, doc.metadata)
+            .await
+            .map_err(|e| (Some(id), e.to_string()))
+    }
+}
+
+/// Who a bulk job belongs to, for per-client limits: the `X-Client-Id`
+/// header if sent, otherwise the address the request came from.
+pub fn client_id(headers: &HeaderMap, peer: Option<&ConnectInfo<SocketAddr>>) -> String {
+    headers
+        .get("x-client-id")
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .map(String::from)
+        .or_else(|| peer.map(|ConnectInfo(addr)| addr.ip().to_string()))
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+#[derive(Clone)]
+struct QueuedJob {
+    ticket: u64,
+    client: String,
+    since: DateTime<Utc>,
+}
+
+#[derive(Default)]
+struct QueueState {
+    next_ticket: u64,
+    running: Vec<QueuedJob>,
+    /// In arrival order
+    waiting: VecDeque<QueuedJob>,
+}
+
+impl QueueState {
+    /// Start waiting jobs, oldest first, while there is room overall and
+    /// for their client.
+    fn admit(&mut self, limit: usize, per_client: usize) -> bool {
+        let mut admitted = false;
+        let mut i = 0;
+        while i < self.waiting.len() && self.running.len() < limit {
+            let client = &self.waiting[i].client;
+            let running = self
+                .running
+                .iter()
+                .filter(|job| job.client == *client)
+                .count();
+            if running < per_client {
+                let mut job = self.waiting.remove(i).expect("index in range");
+                job.since = Utc::now();
+                self.running.push(job);
+                admitted = true;
+            } else {
+                i += 1;
+            }
+        }
+        admitted
+    }
+}
+
+/// Bulk jobs running or waiting, as reported by the queue endpoint.
+#[derive(Serialize)]
+pub struct QueueStatus {
+    pub limit: usize,
+    pub per_client_limit: usize,
+    pub running: Vec<JobStatus>,
+    pub waiting: Vec<JobStatus>,
+}
+
+#[derive(Serialize)]
+pub struct JobStatus {
+    pub client: String,
+    /// Place in the queue, counting from 1, for waiting jobs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub position: Option<usize>,
+    /// When the job started running, or joined the queue if waiting
+    pub since: DateTime<Utc>,
+}
+
+/// Admits bulk jobs in arrival order, at most `limit` at once and at most
+/// `per_client` for any one client, so two clients syncing whole vaults at
+/// once take turns instead of thrashing memory and the model. A job held
+/// back only by its own client's limit doesn't hold up other clients' jobs
+/// queued behind it.
+pub struct BulkQueue {
+    limit: usize,
+    per_client: usize,
+    state: Mutex<QueueState>,
+    admitted: Notify,
+}
+
+/// A place in the [`BulkQueue`], given up when dropped, whether the job
+/// ran or its client gave up waiting.
+pub struct BulkPermit {
+    queue: Arc<BulkQueue>,
+    ticket: u64,
+}
+
+impl BulkQueue {
+    pub fn new(limit: usize, per_client: usize) -> Self {
+        Self {
+            limit,
+            per_client,
+            state: Mutex::new(QueueState::default()),
+            admitted: Notify::new(),
+        }
+    }
+
+    /// Wait for a turn to run a bulk job for `client`.
+    pub async fn acquire(self: &Arc<Self>, client: String) -> BulkPermit {
+        let permit = {
+            let mut state = self.state.lock().unwrap();
+            let ticket = state.next_ticket;
+            state.next_ticket += 1;
+            state.waiting.push_back(QueuedJob {
+                ticket,
+                client,
+                since: Utc::now(),
+            });
+            state.admit(self.limit, self.per_client);
+            BulkPermit {
+                queue: self.clone(),
+                ticket,
+            }
+        };
+
+        loop {
+            // Register before checking, so a release in between isn't missed
+            let notified = self.admitted.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            if self.is_running(permit.ticket) {
+                return permit;
+            }
+            notified.await;
+        }
+    }
+
+    fn is_running(&self, ticket: u64) -> bool {
+        let state = self.state.lock().unwrap();
+        state.running.iter().any(|job| job.ticket == ticket)
+    }
+
+    fn release(&self, ticket: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.running.retain(|job| job.ticket != ticket);
+        state.waiting.retain(|job| job.ticket != ticket);
+        if state.admit(self.limit, self.per_client) {
+            self.admitted.notify_waiters();
+        }
+    }
+
+    pub fn status(&self) -> QueueStatus {
+        let state = self.state.lock().unwrap();
+        let status = |job: &QueuedJob, position: Option<usize>| JobStatus {
+            client: job.client.clone(),
+            position,
+            since: job.since,
+        };
+        QueueStatus {
+            limit: self.limit,
+            per_client_limit: self.per_client,
+            running: state.running.iter().map(|job| status(job, None)).collect(),
+            waiting: state
+                .waiting
+                .iter()
+                .enumerate()
+                .map(|(i, job)| status(job, Some(i + 1)))
+                .collect(),
+        }
+    }
+}
+
+impl Drop for BulkPermit {
+    fn drop(&mut self) {
+        self.queue.release(self.ticket);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_bulk_queue_is_fair_across_clients() {
+        let queue = Arc::new(BulkQueue::new(2, 1));
+        let a1 = queue.acquire("a".to_string()).await;
+
+        // a's second job waits on a's limit; b's queued behind it still runs
+        let waiting = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.acquire("a".to_string()).await }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let b1 = queue.acquire("b".to_string()).await;
+
+        let status = queue.status();
+        assert_eq!(status.running.len(), 2);
+        assert_eq!(status.waiting.len(), 1);
+        assert_eq!(status.waiting[0].position, Some(1));
+        assert_eq!(status.waiting[0].client, "a");
+
+        drop(b1);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!waiting.is_finished());
+        drop(a1);
+        let a2 = tokio::time::timeout(Duration::from_secs(1), waiting)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(queue.status().running.len(), 1);
+        drop(a2);
+        assert!(queue.status().running.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_spooled_upload_reads_back_in_order() {
+        let dir = std::env::temp_dir().join(format!("systematics-spool-{}", std::process::id()));
+        let documents = vec![
+            BulkDocument {
+                id: Some("a".into()),
+                text: "First\nline".into(),
+                metadata: Some(serde_json::json!({ "tags": ["x"] })),
+            },
+            BulkDocument {
+                id: None,
+                text: "Second".into(),
+                metadata: None,
+            },
+        ];
+        let spool = Spool::write(&dir, &mut BulkSource::Items(documents.into_iter()))
+            .await
+            .unwrap();
+        assert_eq!(spool.total, 2);
+
+        let mut source = spool.read().await.unwrap();
+        let first = source.next().await.unwrap().unwrap().unwrap();
+        assert_eq!(
+            (first.id.as_deref(), first.text.as_str()),
+            (Some("a"), "First\nline")
+        );
+        let second = source.next().await.unwrap().unwrap().unwrap();
+        assert_eq!((second.id, second.text.as_str()), (None, "Second"));
+        assert!(source.next().await.unwrap().is_none());
+
+        let path = spool.path.clone();
+        drop(spool);
+        assert!(!path.exists());
+        Spool::clear(&dir);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,548 @@
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use memmap2::Mmap;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::hnsw::Hnsw;
+use crate::index::{Chunk, CollectionSettings, Collections, IndexedDocument, VectorIndex};
+use crate::vector::StoredVector;
+
+/// Version of the bundle layout. Bundles written by a newer build are
+/// refused rather than misread.
+pub const BUNDLE_FORMAT: u32 = 1;
+
+pub const EXTENSION: &str = "bundle";
+
+/// Directory under the data directory whose bundles are mounted at startup.
+pub const BUNDLES_DIR: &str = "bundles";
+
+const MAGIC: &[u8; 8] = b"SYSBNDL\n";
+
+/// Magic, then the offset and length of the manifest, which comes last
+/// since the section offsets aren't known until they are written.
+const HEADER_LEN: u64 = 24;
+
+/// Sections start on a multiple of this, so the words of the vectors and
+/// graph are aligned within the file.
+const ALIGN: u64 = 8;
+
+/// A byte range of the bundle file.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+pub struct Section {
+    pub offset: u64,
+    pub length: u64,
+}
+
+/// What a bundle holds and where. The file is laid out as
+///
+/// - `vectors`: little-endian f32s, `dimensions` per vector, every
+///   document's own vector followed by its chunks'
+/// - `graph`: little-endian u32s, per node its layer count and then per
+///   layer a neighbour count and the neighbours; node `i` is document `i`
+/// - `records`: each document's id, text, and metadata as JSON
+/// - `offsets`: little-endian u64s, where each record starts within
+///   `records`, plus where the last one ends
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Manifest {
+    pub format: u32,
+    /// Collection the bundle was packed from
+    pub name: String,
+    pub created_at: DateTime<Utc>,
+    /// Fingerprint of the model the vectors were made by
+    pub model_fingerprint: String,
+    pub settings: CollectionSettings,
+    pub dimensions: usize,
+    pub documents: usize,
+    pub aliases: HashMap<String, String>,
+    /// Node the graph is searched from, `None` if it is empty
+    pub entry: Option<u32>,
+    pub vectors: Section,
+    pub graph: Section,
+    pub records: Section,
+    pub offsets: Section,
+}
+
+/// A document in the `records` section, its vectors referred to by their
+/// position in the `vectors` section.
+#[derive(Serialize, Deserialize)]
+struct Record {
+    id: String,
+    text: String,
+    #[serde(default)]
+    metadata: Option<Value>,
+    version: u64,
+    #[serde(default)]
+    updated_at: Option<DateTime<Utc>>,
+    vector: u32,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    chunks: Vec<RecordChunk>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RecordChunk {
+    start: usize,
+    end: usize,
+    vector: u32,
+}
+
+/// Writes sections one after the other, keeping track of where they land.
+struct SectionWriter {
+    file: BufWriter<File>,
+    position: u64,
+}
+
+impl SectionWriter {
+    fn write(&mut self, bytes: &[u8]) -> Result<()> {
+        self.file.write_all(bytes)?;
+        self.position += bytes.len() as u64;
+        Ok(())
+    }
+
+    /// Pad to the next section boundary and return where it starts.
+    fn start(&mut self) -> Result<u64> {
+        let padding = (ALIGN - self.position % ALIGN) % ALIGN;
+        self.write(&vec![0; padding as usize])?;
+        Ok(self.position)
+    }
+
+    fn section(&self, offset: u64) -> Section {
+        Section {
+            offset,
+            length: self.position - offset,
+        }
+    }
+}
+
+/// Pack every document of `index`, with a freshly built graph over them,
+/// into a bundle at `out`. The bundle is written to a temporary file and
+/// renamed into place, so a mounted bundle is never seen half written.
+pub fn pack(
+    index: &VectorIndex,
+    name: &str,
+    settings: CollectionSettings,
+    model_fingerprint: &str,
+    out: &Path,
+) -> Result<Manifest> {
+    let tmp_path = out.with_extension(format!("{}.tmp", EXTENSION));
+    let manifest = index.read_contents(|docs, aliases, _| -> Result<Manifest> {
+        let mut ids: Vec<&String> = docs.keys().collect();
+        ids.sort();
+
+        let mut graph = Hnsw::new(index.hnsw());
+        for id in &ids {
+            graph.insert(id, docs);
+        }
+
+        let mut writer = SectionWriter {
+            file: BufWriter::new(File::create(&tmp_path)?),
+            position: 0,
+        };
+        writer.write(&[0; HEADER_LEN as usize])?;
+
+        let vectors_start = writer.start()?;
+        let mut dimensions = None;
+        let mut vectors = 0u32;
+        let mut records = Vec::with_capacity(ids.len());
+        for id in &ids {
+            let doc = &docs[*id];
+            let mut write_vector = |vector: &StoredVector| -> Result<u32> {
+                let values = vector.to_f32();
+                if *dimensions.get_or_insert(values.len()) != values.len() {
+                    bail!("Document {} has vectors of mixed lengths", doc.id);
+                }
+                for value in values.iter() {
+                    writer.write(&value.to_le_bytes())?;
+                }
+                vectors += 1;
+                Ok(vectors - 1)
+            };
+            let vector = write_vector(&doc.embedding)?;
+            let chunks = doc
+                .chunks
+                .iter()
+                .map(|chunk| {
+                    Ok(RecordChunk {
+                        start: chunk.start,
+                        end: chunk.end,
+                        vector: write_vector(&chunk.embedding)?,
+                    })
+                })
+                .collect::<Result<_>>()?;
+            records.push(Record {
+                id: doc.id.clone(),
+                text: doc.text.clone(),
+                metadata: doc.metadata.clone(),
+                version: doc.version,
+                updated_at: doc.updated_at,
+                vector,
+                chunks,
+            });
+        }
+        let vectors = writer.section(vectors_start);
+
+        let graph_start = writer.start()?;
+        let (nodes, entry) = graph.export();
+        for layers in nodes {
+            writer.write(&(layers.len() as u32).to_le_bytes())?;
+            for links in layers {
+                writer.write(&(links.len() as u32).to_le_bytes())?;
+                for link in links {
+                    writer.write(&link.to_le_bytes())?;
+                }
+            }
+        }
+        let graph_section = writer.section(graph_start);
+
+        let records_start = writer.start()?;
+        let mut offsets = Vec::with_capacity(records.len() + 1);
+        for record in &records {
+            offsets.push(writer.position - records_start);
+            writer.write(&serde_json::to_vec(record)?)?;
+        }
+        offsets.push(writer.position - records_start);
+        let records_section = writer.section(records_start);
+
+        let offsets_start = writer.start()?;
+        for offset in offsets {
+            writer.write(&offset.to_le_bytes())?;
+        }
+        let offsets_section = writer.section(offsets_start);
+
+        let manifest = Manifest {
+            format: BUNDLE_FORMAT,
+            name: name.to_string(),
+            created_at: Utc::now(),
+            model_fingerprint: model_fingerprint.to_string(),
+            settings,
+            dimensions: dimensions.unwrap_or(0),
+            documents: records.len(),
+            aliases: aliases.clone(),
+            entry,
+            vectors,
+            graph: graph_section,
+            records: records_section,
+            offsets: offsets_section,
+        };
+        let manifest_start = writer.start()?;
+        writer.write(&serde_json::to_vec(&manifest)?)?;
+        let manifest_section = writer.section(manifest_start);
+
+        let mut file = writer.file.into_inner()?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(MAGIC)?;
+        file.write_all(&manifest_section.offset.to_le_bytes())?;
+        file.write_all(&manifest_section.length.to_le_bytes())?;
+        file.sync_all()?;
+        Ok(manifest)
+    });
+    let manifest = match manifest {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(e);
+        }
+    };
+    fs::rename(&tmp_path, out)?;
+    Ok(manifest)
+}
+
+/// A bundle file, memory-mapped while it is read.
+pub struct Bundle {
+    map: Mmap,
+    manifest: Manifest,
+    path: PathBuf,
+}
+
+impl Bundle {
+    /// Map the bundle at `path` and check its manifest against the file.
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+        // Safety: bundles are written to a temporary file and renamed into
+        // place, and never modified afterwards
+        let map = unsafe { Mmap::map(&file)? };
+
+        if map.len() < HEADER_LEN as usize || &map[..MAGIC.len()] != MAGIC {
+            bail!("{:?} is not an index bundle", path);
+        }
+        let word = |at: usize| u64::from_le_bytes(map[at..at + 8].try_into().unwrap());
+        let manifest_section = Section {
+            offset: word(8),
+            length: word(16),
+        };
+        let manifest: Manifest = serde_json::from_slice(slice(&map, manifest_section, path)?)
+            .with_context(|| format!("Bundle {:?} has an unreadable manifest", path))?;
+        if manifest.format > BUNDLE_FORMAT {
+            bail!(
+                "Bundle {:?} has format {}, but this build reads up to {}; upgrade to mount it",
+                path,
+                manifest.format,
+                BUNDLE_FORMAT
+            );
+        }
+        for section in [
+            manifest.vectors,
+            manifest.graph,
+            manifest.records,
+            manifest.offsets,
+        ] {
+            slice(&map, section, path)?;
+        }
+        if manifest.offsets.length != (manifest.documents as u64 + 1) * 8 {
+            bail!(
+                "Bundle {:?} is corrupt: its record offsets don't add up",
+                path
+            );
+        }
+
+        Ok(Self {
+            map,
+            manifest,
+            path: path.to_path_buf(),
+        })
+    }
+
+    pub fn manifest(&self) -> &Manifest {
+        &self.manifest
+    }
+
+    /// Collection name the bundle is mounted under: its file name without
+    /// the extension.
+    pub fn collection_name(&self) -> String {
+        self.path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_default()
+    }
+
+    /// Load the bundle as a read-only index stored the way `collections`
+    /// stores vectors, searched through the packed graph rather than one
+    /// built afresh. Everything is copied out of the file, so the index
+    /// takes as much memory as the collection it was packed from.
+    pub fn load(&self, collections: &Collections) -> Result<VectorIndex> {
+        let documents = self.documents()?;
+        let keys = documents.iter().map(|doc| doc.id.clone()).collect();
+        let graph = Hnsw::import(collections.hnsw(), keys, self.links()?, self.manifest.entry);
+        Ok(VectorIndex::read_only(
+            documents,
+            self.manifest.aliases.clone(),
+            graph,
+            collections.precision(),
+            collections.quantization(),
+            collections.hnsw(),
+        ))
+    }
+
+    /// Every document, in node order.
+    pub fn documents(&self) -> Result<Vec<IndexedDocument>> {
+        let records = slice(&self.map, self.manifest.records, &self.path)?;
+        let offsets: Vec<usize> = words::<8>(slice(&self.map, self.manifest.offsets, &self.path)?)
+            .map(|offset| u64::from_le_bytes(offset) as usize)
+            .collect();
+
+        offsets
+            .windows(2)
+            .map(|range| {
+                let bytes = records
+                    .get(range[0]..range[1])
+                    .context("record offset past the end of the records")?;
+                let record: Record = serde_json::from_slice(bytes)?;
+                Ok(IndexedDocument {
+                    embedding: self.vector(record.vector)?,
+                    chunks: record
+                        .chunks
+                        .into_iter()
+                        .map(|chunk| {
+                            Ok(Chunk {
+                                start: chunk.start,
+                                end: chunk.end,
+                                embedding: self.vector(chunk.vector)?,
+                            })
+                        })
+                        .collect::<Result<_>>()?,
+                    id: record.id,
+                    text: record.text,
+                    metadata: record.metadata,
+                    version: record.version,
+                    updated_at: record.updated_at,
+                })
+            })
+            .collect::<Result<_>>()
+            .with_context(|| format!("Bundle {:?} is corrupt", self.path))
+    }
+
+    /// Vector `i` of the `vectors` section.
+    fn vector(&self, i: u32) -> Result<StoredVector> {
+        let size = (self.manifest.dimensions * 4) as u64;
+        let section = Section {
+            offset: self.manifest.vectors.offset + i as u64 * size,
+            length: size,
+        };
+        if section.offset + size > self.manifest.vectors.offset + self.manifest.vectors.length {
+            bail!("vector {} is past the end of the vectors", i);
+        }
+        let values = words::<4>(slice(&self.map, section, &self.path)?)
+            .map(f32::from_le_bytes)
+            .collect();
+        Ok(StoredVector::F32(values))
+    }
+
+    /// Each node's links per layer, checked to stay within the graph.
+    fn links(&self) -> Result<Vec<Vec<Vec<u32>>>> {
+        let mut words =
+            words::<4>(slice(&self.map, self.manifest.graph, &self.path)?).map(u32::from_le_bytes);
+        let nodes = self.manifest.documents;
+        let mut next = || {
+            words
+                .next()
+                .with_context(|| format!("Bundle {:?} has a truncated graph", self.path))
+        };
+
+        let mut links = Vec::with_capacity(nodes);
+        for _ in 0..nodes {
+            let layers = next()? as usize;
+            let mut node = Vec::with_capacity(layers);
+            for _ in 0..layers {
+                let count = next()? as usize;
+                let neighbours = (0..count).map(|_| next()).collect::<Result<Vec<_>>>()?;
+                if neighbours.iter().any(|&n| n as usize >= nodes) {
+                    bail!("Bundle {:?} has a graph link to a missing node", self.path);
+                }
+                node.push(neighbours);
+            }
+            links.push(node);
+        }
+        if self
+            .manifest
+            .entry
+            .is_some_and(|entry| entry as usize >= nodes)
+            || links.is_empty() != self.manifest.entry.is_none()
+        {
+            bail!("Bundle {:?} has a bad graph entry point", self.path);
+        }
+        Ok(links)
+    }
+}
+
+/// The bytes of `section`, or an error if it runs past the end of the file.
+fn slice<'a>(map: &'a [u8], section: Section, path: &Path) -> Result<&'a [u8]> {
+    section
+        .offset
+        .checked_add(section.length)
+        .filter(|&end| end <= map.len() as u64)
+        .map(|end| &map[section.offset as usize..end as usize])
+        .with_context(|| format!("Bundle {:?} is truncated", path))
+}
+
+fn words<const N: usize>(bytes: &[u8]) -> impl Iterator<Item = [u8; N]> + '_ {
+    bytes
+        .chunks_exact(N)
+        .map(|word| word.try_into().expect("chunks are N bytes"))
+}
+
+/// Bundle files in the data directory's `bundles/`, in name order.
+pub fn discover(data_dir: &Path) -> Result<Vec<PathBuf>> {
+    let dir = data_dir.join(BUNDLES_DIR);
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(&dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == EXTENSION) {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{HnswConfig, Precision, QuantizationConfig, StorageConfig};
+    use crate::index::SearchOptions;
+
+    #[tokio::test]
+    async fn test_pack_and_serve_read_only() {
+        let dir = std::env::temp_dir().join(format!("systematics-bundle-{}", std::process::id()));
+        let collections = Collections::open(
+            &dir,
+            Precision::F32,
+            QuantizationConfig::default(),
+            HnswConfig::default(),
+            StorageConfig::default(),
+        )
+        .unwrap();
+        let index = collections.get(None).await.unwrap();
+        for (id, embedding) in [("a", [1.0, 0.0]), ("b", [0.0, 1.0]), ("c", [0.7, 0.7])] {
+            let metadata = Some(serde_json::json!({ "id": id }));
+            index
+                .add(id, embedding.to_vec(), format!("Text {}", id), metadata)
+                .await
+                .unwrap();
+        }
+        index.add_alias("old-a", "a").await.unwrap();
+
+        fs::create_dir_all(dir.join(BUNDLES_DIR)).unwrap();
+        let path = dir.join(BUNDLES_DIR).join("corpus.bundle");
+        let settings = CollectionSettings::default();
+        pack(&index, "default", settings.clone(), "test:0:mean:2", &path).unwrap();
+
+        let bundle = Bundle::open(&path).unwrap();
+        assert_eq!(bundle.manifest().documents, 3);
+        assert_eq!(bundle.manifest().dimensions, 2);
+        assert_eq!(discover(&dir).unwrap(), vec![path.clone()]);
+
+        let name = bundle.collection_name();
+        collections
+            .mount(&name, bundle.load(&collections).unwrap(), settings, &path)
+            .unwrap();
+        let mounted = collections.get(Some("corpus")).await.unwrap();
+        let results = mounted
+            .search(&[1.0, 0.1], 1, &SearchOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(results[0].id, "a");
+        let doc = mounted.get("old-a").await.unwrap().unwrap();
+        assert_eq!(doc.metadata, Some(serde_json::json!({ "id": "a" })));
+
+        assert!(mounted.delete("a").await.is_err());
+        assert!(mounted
+            .add("d", vec![1.0, 1.0], "Text d".to_string(), None)
+            .await
+            .is_err());
+        assert_eq!(collections.writable().len(), 1);
+
+        // Deleting sets the file aside, since it is the user's, where it
+        // isn't mounted again on restart
+        collections.delete("corpus").await.unwrap();
+        assert!(collections.get(Some("corpus")).await.is_err());
+        assert!(!path.exists());
+        assert!(path.with_extension("bundle.unmounted").exists());
+        drop(collections);
+
+        let collections = Collections::open(
+            &dir,
+            Precision::F32,
+            QuantizationConfig::default(),
+            HnswConfig::default(),
+            StorageConfig::default(),
+        )
+        .unwrap();
+        for path in discover(&dir).unwrap() {
+            let bundle = Bundle::open(&path).unwrap();
+            let index = bundle.load(&collections).unwrap();
+            collections
+                .mount(&bundle.collection_name(), index, settings.clone(), &path)
+                .unwrap();
+        }
+        assert!(collections.get(Some("corpus")).await.is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
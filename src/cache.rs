@@ -0,0 +1,427 @@
+use anyhow::{Context, Result};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use tracing::warn;
+
+pub type CacheKey = [u8; 32];
+
+/// Embeddings of texts the model has already seen, so re-indexing an
+/// unchanged note skips inference. Recent entries are kept in memory and
+/// evicted least recently used first; with persistence every entry is
+/// also appended to a file, so the cache survives restarts. The file is
+/// kept under a size cap by dropping its oldest entries.
+pub struct EmbeddingCache {
+    /// Identifies the model and every setting that changes its output, so
+    /// a different model never gets another's embeddings
+    namespace: String,
+    capacity: usize,
+    memory: Mutex<Lru>,
+    disk: Option<Mutex<DiskCache>>,
+    memory_hits: AtomicU64,
+    disk_hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Serialize)]
+pub struct CacheMetrics {
+    /// Entries held in memory
+    pub entries: usize,
+    pub capacity: usize,
+    /// Entries in the persistent cache, if enabled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub persisted: Option<usize>,
+    pub memory_hits: u64,
+    pub disk_hits: u64,
+    pub misses: u64,
+    pub hit_rate: f64,
+}
+
+impl EmbeddingCache {
+    /// A cache holding up to `capacity` embeddings in memory, persisted
+    /// under `dir` if given in a file of at most `max_bytes`. A capacity
+    /// of 0 disables caching.
+    pub fn new(
+        namespace: String,
+        capacity: usize,
+        dir: Option<&Path>,
+        max_bytes: u64,
+    ) -> Result<Self> {
+        let disk = match dir {
+            Some(dir) if capacity > 0 => {
+                // One file per namespace, so switching models doesn't mix
+                // or discard their entries
+                let name = format!("{:x}", Sha256::digest(namespace.as_bytes()));
+                let path = dir.join(format!("{}.bin", &name[..16]));
+                Some(Mutex::new(DiskCache::open(&path, max_bytes).with_context(
+                    || format!("Failed to open embedding cache {:?}", path),
+                )?))
+            }
+            _ => None,
+        };
+
+        Ok(Self {
+            namespace,
+            capacity,
+            memory: Mutex::new(Lru::default()),
+            disk,
+            memory_hits: AtomicU64::new(0),
+            disk_hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        })
+    }
+
+    pub fn key(&self, text: &str) -> CacheKey {
+        let mut hasher = Sha256::new();
+        hasher.update(self.namespace.as_bytes());
+        hasher.update([0]);
+        hasher.update(text.as_bytes());
+        hasher.finalize().into()
+    }
+
+    pub fn get(&self, key: &CacheKey) -> Option<Vec<f32>> {
+        if self.capacity == 0 {
+            return None;
+        }
+
+        if let Some(embedding) = self.memory.lock().unwrap().get(key) {
+            self.memory_hits.fetch_add(1, Ordering::Relaxed);
+            return Some(embedding);
+        }
+
+        let from_disk = self.disk.as_ref().and_then(|disk| {
+            disk.lock().unwrap().get(key).unwrap_or_else(|e| {
+                warn!("Failed to read the embedding cache: {}", e);
+                None
+            })
+        });
+        match from_disk {
+            Some(embedding) => {
+                self.disk_hits.fetch_add(1, Ordering::Relaxed);
+                self.memory
+                    .lock()
+                    .unwrap()
+                    .insert(*key, embedding.clone(), self.capacity);
+                Some(embedding)
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    pub fn insert(&self, key: CacheKey, embedding: &[f32]) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        if let Some(disk) = &self.disk {
+            // The cache is only an optimization, so a full disk shouldn't
+            // fail the embed
+            if let Err(e) = disk.lock().unwrap().insert(key, embedding) {
+                warn!("Failed to persist an embedding: {}", e);
+            }
+        }
+        self.memory
+            .lock()
+            .unwrap()
+            .insert(key, embedding.to_vec(), self.capacity);
+    }
+
+    pub fn metrics(&self) -> CacheMetrics {
+        let memory_hits = self.memory_hits.load(Ordering::Relaxed);
+        let disk_hits = self.disk_hits.load(Ordering::Relaxed);
+        let misses = self.misses.load(Ordering::Relaxed);
+        let lookups = memory_hits + disk_hits + misses;
+
+        CacheMetrics {
+            entries: self.memory.lock().unwrap().entries.len(),
+            capacity: self.capacity,
+            persisted: self
+                .disk
+                .as_ref()
+                .map(|disk| disk.lock().unwrap().offsets.len()),
+            memory_hits,
+            disk_hits,
+            misses,
+            hit_rate: if lookups > 0 {
+                (memory_hits + disk_hits) as f64 / lookups as f64
+            } else {
+                0.0
+            },
+        }
+    }
+}
+
+/// Least-recently-used map: every access stamps an entry with the next
+/// tick, and the entry with the oldest tick is evicted first.
+#[derive(Default)]
+struct Lru {
+    entries: HashMap<CacheKey, (Vec<f32>, u64)>,
+    by_tick: BTreeMap<u64, CacheKey>,
+    tick: u64,
+}
+
+impl Lru {
+    fn get(&mut self, key: &CacheKey) -> Option<Vec<f32>> {
+        let (embedding, tick) = self.entries.get_mut(key)?;
+        self.by_tick.remove(tick);
+        self.tick += 1;
+        *tick = self.tick;
+        self.by_tick.insert(self.tick, *key);
+        Some(embedding.clone())
+    }
+
+    fn insert(&mut self, key: CacheKey, embedding: Vec<f32>, capacity: usize) {
+        self.tick += 1;
+        if let Some((_, old_tick)) = self.entries.insert(key, (embedding, self.tick)) {
+            self.by_tick.remove(&old_tick);
+        }
+        self.by_tick.insert(self.tick, key);
+
+        while self.entries.len() > capacity {
+            let Some((_, oldest)) = self.by_tick.pop_first() else {
+                break;
+            };
+            self.entries.remove(&oldest);
+        }
+    }
+}
+
+/// Append-only file of `key, length (u32), values (f32...)` records, all
+/// little-endian, with an in-memory index of where each one starts.
+struct DiskCache {
+    path: PathBuf,
+    file: File,
+    offsets: HashMap<CacheKey, (u64, u32)>,
+    /// Current length of the file
+    len: u64,
+    max_bytes: u64,
+}
+
+const HEADER: u64 = 36;
+
+impl DiskCache {
+    fn open(path: &Path, max_bytes: u64) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+        let file_len = file.metadata()?.len();
+
+        // Index every complete record; a record cut short by a crash, or
+        // whose length runs past the end of the file, ends the index there
+        let mut offsets = HashMap::new();
+        let mut reader = BufReader::new(&mut file);
+        let mut offset = 0u64;
+        loop {
+            let mut header = [0u8; HEADER as usize];
+            if reader.read_exact(&mut header).is_err() {
+                break;
+            }
+            let key: CacheKey = header[..32].try_into().unwrap();
+            let len = u32::from_le_bytes(header[32..].try_into().unwrap());
+            let end = offset + HEADER + len as u64 * 4;
+            if end > file_len {
+                break;
+            }
+            if reader.seek_relative(len as i64 * 4).is_err() {
+                break;
+            }
+            offsets.insert(key, (offset + HEADER, len));
+            offset = end;
+        }
+        drop(reader);
+        file.set_len(offset)?;
+
+        let mut cache = Self {
+            path: path.to_path_buf(),
+            file,
+            offsets,
+            len: offset,
+            max_bytes,
+        };
+        if cache.len > max_bytes {
+            cache.compact(0)?;
+        }
+        Ok(cache)
+    }
+
+    fn get(&mut self, key: &CacheKey) -> Result<Option<Vec<f32>>> {
+        let Some(&(offset, len)) = self.offsets.get(key) else {
+            return Ok(None);
+        };
+
+        let mut bytes = vec![0u8; len as usize * 4];
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(&mut bytes)?;
+        Ok(Some(
+            bytes
+                .chunks_exact(4)
+                .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+                .collect(),
+        ))
+    }
+
+    fn insert(&mut self, key: CacheKey, embedding: &[f32]) -> Result<()> {
+        if self.offsets.contains_key(&key) {
+            return Ok(());
+        }
+
+        let mut record = Vec::with_capacity(HEADER as usize + embedding.len() * 4);
+        record.extend_from_slice(&key);
+        record.extend_from_slice(&(embedding.len() as u32).to_le_bytes());
+        for value in embedding {
+            record.extend_from_slice(&value.to_le_bytes());
+        }
+        if record.len() as u64 > self.max_bytes {
+            return Ok(());
+        }
+        if self.len + record.len() as u64 > self.max_bytes {
+            self.compact(record.len() as u64)?;
+        }
+
+        let offset = self.file.seek(SeekFrom::End(0))?;
+        self.file.write_all(&record)?;
+        self.offsets
+            .insert(key, (offset + HEADER, embedding.len() as u32));
+        self.len = offset + record.len() as u64;
+        Ok(())
+    }
+
+    /// Rewrites the file with only its newest records, keeping at most half
+    /// of the cap less `reserve` bytes, so compacting stays rare.
+    fn compact(&mut self, reserve: u64) -> Result<()> {
+        let budget = (self.max_bytes / 2).saturating_sub(reserve);
+        let mut records: Vec<(CacheKey, u64, u32)> = self
+            .offsets
+            .iter()
+            .map(|(key, &(offset, len))| (*key, offset, len))
+            .collect();
+        records.sort_unstable_by_key(|&(_, offset, _)| std::cmp::Reverse(offset));
+
+        let mut kept = Vec::new();
+        let mut size = 0u64;
+        for record in records {
+            let record_size = HEADER + record.2 as u64 * 4;
+            if size + record_size > budget {
+                break;
+            }
+            size += record_size;
+            kept.push(record);
+        }
+        kept.reverse();
+
+        let tmp = self.path.with_extension("bin.tmp");
+        let mut out = BufWriter::new(File::create(&tmp)?);
+        let mut offsets = HashMap::with_capacity(kept.len());
+        let mut offset = 0u64;
+        for (key, old_offset, len) in kept {
+            let mut values = vec![0u8; len as usize * 4];
+            self.file.seek(SeekFrom::Start(old_offset))?;
+            self.file.read_exact(&mut values)?;
+            out.write_all(&key)?;
+            out.write_all(&len.to_le_bytes())?;
+            out.write_all(&values)?;
+            offsets.insert(key, (offset + HEADER, len));
+            offset += HEADER + values.len() as u64;
+        }
+        out.into_inner()?.sync_all()?;
+        fs::rename(&tmp, &self.path)?;
+
+        self.file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .open(&self.path)?;
+        self.offsets = offsets;
+        self.len = offset;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache_evicts_and_persists() {
+        let dir = std::env::temp_dir().join(format!("systematics-cache-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let cache = EmbeddingCache::new("model".to_string(), 2, Some(&dir), u64::MAX).unwrap();
+        let (a, b, c) = (cache.key("a"), cache.key("b"), cache.key("c"));
+        assert_eq!(cache.get(&a), None);
+        cache.insert(a, &[1.0, 0.0]);
+        cache.insert(b, &[0.0, 1.0]);
+        assert_eq!(cache.get(&a), Some(vec![1.0, 0.0]));
+
+        // b is now the least recently used, so it goes first
+        cache.insert(c, &[0.6, 0.8]);
+        let memory = cache.memory.lock().unwrap();
+        assert!(memory.entries.contains_key(&a) && !memory.entries.contains_key(&b));
+        drop(memory);
+        // ...but is still on disk
+        assert_eq!(cache.get(&b), Some(vec![0.0, 1.0]));
+
+        // A fresh cache over the same directory finds everything
+        let reopened = EmbeddingCache::new("model".to_string(), 2, Some(&dir), u64::MAX).unwrap();
+        assert_eq!(reopened.get(&c), Some(vec![0.6, 0.8]));
+        let metrics = reopened.metrics();
+        assert_eq!((metrics.persisted, metrics.disk_hits), (Some(3), 1));
+
+        // Another model's entries are kept apart
+        let other = EmbeddingCache::new("other".to_string(), 2, Some(&dir), u64::MAX).unwrap();
+        assert_eq!(other.get(&other.key("a")), None);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_disk_cache_drops_bad_records_and_stays_under_its_cap() {
+        let dir =
+            std::env::temp_dir().join(format!("systematics-cache-cap-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let path = dir.join("cache.bin");
+
+        // A record claiming more values than the file holds ends the index
+        // without allocating for them
+        let mut disk = DiskCache::open(&path, u64::MAX).unwrap();
+        disk.insert([1; 32], &[1.0, 2.0]).unwrap();
+        drop(disk);
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[2; 32]).unwrap();
+        file.write_all(&u32::MAX.to_le_bytes()).unwrap();
+        file.write_all(&[0; 16]).unwrap();
+        drop(file);
+        let mut disk = DiskCache::open(&path, u64::MAX).unwrap();
+        assert_eq!(disk.offsets.len(), 1);
+        assert_eq!(fs::metadata(&path).unwrap().len(), HEADER + 8);
+        assert_eq!(disk.get(&[1; 32]).unwrap(), Some(vec![1.0, 2.0]));
+        drop(disk);
+
+        // Each record is 36 + 8 bytes; a 200-byte cap drops the oldest
+        let mut disk = DiskCache::open(&path, 200).unwrap();
+        for i in 2..10u8 {
+            disk.insert([i; 32], &[i as f32, 0.0]).unwrap();
+            assert!(fs::metadata(&path).unwrap().len() <= 200);
+        }
+        assert_eq!(disk.get(&[1; 32]).unwrap(), None);
+        assert_eq!(disk.get(&[9; 32]).unwrap(), Some(vec![9.0, 0.0]));
+        drop(disk);
+        let reopened = DiskCache::open(&path, 200).unwrap();
+        assert!(reopened.offsets.contains_key(&[9; 32]));
+        assert!(!dir.join("cache.bin.tmp").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,186 @@
+use anyhow::Result;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::Duration;
+use tracing::warn;
+
+use crate::config::ChaosConfig;
+
+/// The faults to inject, installed once at startup. A global rather than
+/// state threaded through every layer, since it only exists in builds with
+/// the `chaos` feature.
+static CHAOS: OnceLock<Chaos> = OnceLock::new();
+
+#[derive(Debug, thiserror::Error)]
+#[error("Injected {layer} fault: {kind}")]
+pub struct InjectedFault {
+    layer: &'static str,
+    kind: &'static str,
+}
+
+#[derive(Clone, Copy)]
+enum Layer {
+    Embedding,
+    Storage,
+}
+
+impl Layer {
+    fn name(self) -> &'static str {
+        match self {
+            Layer::Embedding => "embedding",
+            Layer::Storage => "storage",
+        }
+    }
+}
+
+/// Decides which calls fail. Every decision is a pure function of the seed
+/// and either the call's position or the item involved, so the same
+/// requests in the same order meet the same faults on every run.
+struct Chaos {
+    config: ChaosConfig,
+    embedding_calls: AtomicU64,
+    storage_calls: AtomicU64,
+}
+
+impl Chaos {
+    fn new(config: ChaosConfig) -> Self {
+        Self {
+            config,
+            embedding_calls: AtomicU64::new(0),
+            storage_calls: AtomicU64::new(0),
+        }
+    }
+
+    fn latency(&self, layer: Layer) -> Duration {
+        Duration::from_millis(match layer {
+            Layer::Embedding => self.config.embedding_latency_ms,
+            Layer::Storage => self.config.storage_latency_ms,
+        })
+    }
+
+    /// Whether this call fails transiently. Retrying gets a fresh roll.
+    fn transient(&self, layer: Layer) -> bool {
+        let (calls, rate) = match layer {
+            Layer::Embedding => (&self.embedding_calls, self.config.embedding_error_rate),
+            Layer::Storage => (&self.storage_calls, self.config.storage_error_rate),
+        };
+        let call = calls.fetch_add(1, Ordering::Relaxed);
+        roll(self.config.seed ^ ((layer as u64 + 1) << 32) ^ call) < rate
+    }
+
+    /// Whether `item` (a text or document id) always fails. Keyed by
+    /// content, so retrying doesn't help, like a genuinely bad document.
+    fn poisoned(&self, item: &str) -> bool {
+        roll(self.config.seed ^ fnv1a(item.as_bytes())) < self.config.partial_failure_rate
+    }
+
+    fn check(&self, layer: Layer, items: &[&str]) -> Result<(), InjectedFault> {
+        if self.transient(layer) {
+            return Err(InjectedFault {
+                layer: layer.name(),
+                kind: "transient error",
+            });
+        }
+        if items.iter().any(|item| self.poisoned(item)) {
+            return Err(InjectedFault {
+                layer: layer.name(),
+                kind: "item rejected",
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Start injecting faults as configured. Only the first call has effect.
+pub fn install(config: ChaosConfig) {
+    warn!(
+        "Chaos mode: injecting faults (seed {}, embedding {}ms/{}, storage {}ms/{}, partial {})",
+        config.seed,
+        config.embedding_latency_ms,
+        config.embedding_error_rate,
+        config.storage_latency_ms,
+        config.storage_error_rate,
+        config.partial_failure_rate
+    );
+    let _ = CHAOS.set(Chaos::new(config));
+}
+
+/// Hook before the model embeds `texts`: delays, then fails the whole
+/// batch transiently or because one of the texts is poisoned.
+pub async fn before_embedding(texts: &[&str]) -> Result<()> {
+    let Some(chaos) = CHAOS.get() else {
+        return Ok(());
+    };
+    tokio::time::sleep(chaos.latency(Layer::Embedding)).await;
+    Ok(chaos.check(Layer::Embedding, texts)?)
+}
+
+/// Hook before a write reaches the index log, for the document `id` it
+/// concerns if any. Storage is written under the index lock, so the delay
+/// blocks like a slow disk would.
+pub fn before_storage_write(id: Option<&str>) -> Result<()> {
+    let Some(chaos) = CHAOS.get() else {
+        return Ok(());
+    };
+    std::thread::sleep(chaos.latency(Layer::Storage));
+    Ok(chaos.check(Layer::Storage, id.as_slice())?)
+}
+
+/// Uniform value in [0, 1) derived from `seed` (splitmix64).
+fn roll(seed: u64) -> f64 {
+    let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^= z >> 31;
+    (z >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Stable across builds, unlike the standard library's hasher.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_faults_are_deterministic() {
+        let config = ChaosConfig {
+            seed: 7,
+            embedding_error_rate: 0.3,
+            partial_failure_rate: 0.2,
+            ..ChaosConfig::default()
+        };
+        let outcomes = |chaos: &Chaos| -> Vec<bool> {
+            (0..100)
+                .map(|i| {
+                    chaos
+                        .check(Layer::Embedding, &[&format!("note {}", i)])
+                        .is_ok()
+                })
+                .collect()
+        };
+
+        let first = outcomes(&Chaos::new(config.clone()));
+        assert_eq!(first, outcomes(&Chaos::new(config.clone())));
+        let failures = first.iter().filter(|ok| !**ok).count();
+        assert!((30..=60).contains(&failures), "{} failures", failures);
+
+        // Poisoned items fail however often they are retried
+        let chaos = Chaos::new(ChaosConfig {
+            embedding_error_rate: 0.0,
+            ..config
+        });
+        let poisoned: Vec<String> = (0..100)
+            .map(|i| format!("note {}", i))
+            .filter(|text| chaos.poisoned(text))
+            .collect();
+        assert!(!poisoned.is_empty());
+        for _ in 0..3 {
+            assert!(chaos.check(Layer::Storage, &[&poisoned[0]]).is_err());
+        }
+    }
+}
@@ -0,0 +1,346 @@
+//! Splitting documents longer than the model's input into overlapping
+//! chunks, and embedding them for indexing.
+
+use anyhow::Result;
+use std::borrow::Cow;
+use std::ops::Range;
+
+use crate::config::ChunkingConfig;
+use crate::embedding::{self, EmbeddingService};
+
+/// Embeddings for one document, ready to index.
+pub struct DocumentEmbedding {
+    /// The document's embedding, or for a chunked document the normalized
+    /// mean of its chunks weighted by their length
+    pub embedding: Vec<f32>,
+    /// Per-chunk embeddings, empty when the document fit in one window
+    pub chunks: Vec<ChunkEmbedding>,
+}
+
+impl From<Vec<f32>> for DocumentEmbedding {
+    fn from(embedding: Vec<f32>) -> Self {
+        Self {
+            embedding,
+            chunks: Vec::new(),
+        }
+    }
+}
+
+pub struct ChunkEmbedding {
+    /// Byte range of the chunk within the document text
+    pub span: Range<usize>,
+    pub embedding: Vec<f32>,
+}
+
+/// Group token offsets into windows of `size` tokens, each starting
+/// `size - overlap` tokens after the previous one, and return the byte
+/// range each window covers. A text that fits in one window gets none.
+/// Windows overlap by at most half their size, however small the room
+/// left for text, so a long text never gets a window per token.
+pub fn windows(offsets: &[(usize, usize)], size: usize, overlap: usize) -> Vec<Range<usize>> {
+    if size == 0 || offsets.len() <= size {
+        return Vec::new();
+    }
+
+    let step = (size - overlap.min(size / 2)).max(1);
+    let mut windows = Vec::new();
+    let mut start = 0;
+    loop {
+        let end = (start + size).min(offsets.len());
+        windows.push(offsets[start].0..offsets[end - 1].1);
+        if end == offsets.len() {
+            return windows;
+        }
+        start += step;
+    }
+}
+
+/// As [`windows`], but a window that doesn't reach the end of the text
+/// ends instead at the last token in its second half that ends a
+/// sentence, if any does, so chunks hold whole sentences. The overlap is
+/// at most half of each window as cut. `offsets` are byte ranges of
+/// tokens in `text`.
+pub fn sentence_windows(
+    text: &str,
+    offsets: &[(usize, usize)],
+    size: usize,
+    overlap: usize,
+) -> Vec<Range<usize>> {
+    if size == 0 || offsets.len() <= size {
+        return Vec::new();
+    }
+    let ends_sentence = |token: usize| {
+        let (start, end) = offsets[token];
+        text[start..end].ends_with(['.', '!', '?'])
+    };
+
+    let mut windows = Vec::new();
+    let mut start = 0;
+    loop {
+        let mut end = (start + size).min(offsets.len());
+        if end == offsets.len() {
+            windows.push(offsets[start].0..offsets[end - 1].1);
+            return windows;
+        }
+        if let Some(last) = (start + size / 2..end)
+            .rev()
+            .find(|&token| ends_sentence(token))
+        {
+            end = last + 1;
+        }
+        windows.push(offsets[start].0..offsets[end - 1].1);
+        start = (end - overlap.min((end - start) / 2)).max(start + 1);
+    }
+}
+
+/// Embed documents for indexing. Documents longer than the chunk size (or
+/// than the model's own input limit, if smaller) are split into
+/// overlapping windows so text past the limit isn't silently dropped.
+/// Every window of every document goes through the model in one batch.
+pub async fn embed_documents(
+    service: &EmbeddingService,
+    texts: &[&str],
+    config: ChunkingConfig,
+) -> Result<Vec<DocumentEmbedding>> {
+    let documents: Vec<Document> = texts.iter().map(|&text| (text, &[][..], None)).collect();
+    embed_sections(service, &documents, config).await
+}
+
+/// A document to embed: its text, the byte ranges of its sections, and a
+/// header embedded ahead of the text and of each of its chunks.
+pub type Document<'a> = (&'a str, &'a [Range<usize>], Option<&'a str>);
+
+/// As [`embed_documents`], for documents already divided into sections,
+/// e.g. by heading: each section is a chunk of its own, and those longer
+/// than the chunk size are windowed in turn. A document with no sections
+/// is chunked as a whole. Every chunk is embedded with the model's
+/// document instruction, which takes room in the windows too. A document's
+/// header, such as its title, takes up to half of the room left in each of
+/// its windows, and is cut short to fit; chunk spans still refer to its
+/// text.
+pub async fn embed_sections(
+    service: &EmbeddingService,
+    documents: &[Document<'_>],
+    config: ChunkingConfig,
+) -> Result<Vec<DocumentEmbedding>> {
+    let size = match service.max_content_tokens() {
+        Some(max) => config.size.min(max),
+        None => config.size,
+    };
+    let instruction = service.document_instruction();
+    let instruction_tokens = match instruction {
+        "" => 0,
+        instruction => service
+            .token_offsets(&instruction.replace(embedding::TEXT_PLACEHOLDER, ""))?
+            .len(),
+    };
+
+    let split = |text: &str, offsets: &[(usize, usize)], size: usize| {
+        if config.sentence_boundaries {
+            sentence_windows(text, offsets, size, config.overlap)
+        } else {
+            windows(offsets, size, config.overlap)
+        }
+    };
+
+    let mut splits = Vec::with_capacity(documents.len());
+    let mut inputs: Vec<Cow<str>> = Vec::new();
+    for &(text, sections, header) in documents {
+        let (header, size) = match header {
+            Some(header) if size > 0 => fit_header(
+                header,
+                &service.token_offsets(header)?,
+                size,
+                instruction_tokens,
+            ),
+            header => (header, size.saturating_sub(instruction_tokens)),
+        };
+        let mut spans = Vec::new();
+        for section in sections {
+            let windows = if size == 0 {
+                Vec::new()
+            } else {
+                let section = &text[section.clone()];
+                split(section, &service.token_offsets(section)?, size)
+            };
+            if windows.is_empty() {
+                spans.push(section.clone());
+            } else {
+                spans.extend(
+                    windows
+                        .into_iter()
+                        .map(|window| section.start + window.start..section.start + window.end),
+                );
+            }
+        }
+        if sections.is_empty() && size > 0 {
+            spans = split(text, &service.token_offsets(text)?, size);
+        }
+
+        if spans.is_empty() {
+            inputs.push(input(instruction, header, text));
+        } else {
+            inputs.extend(
+                spans
+                    .iter()
+                    .map(|span| input(instruction, header, &text[span.clone()])),
+            );
+        }
+        splits.push(spans);
+    }
+
+    let inputs: Vec<&str> = inputs.iter().map(AsRef::as_ref).collect();
+    let mut embeddings = service.embed_batch(&inputs).await?.into_iter();
+    let mut next = || embeddings.next().expect("one embedding per input");
+
+    Ok(splits
+        .into_iter()
+        .map(|spans| {
+            if spans.is_empty() {
+                return next().into();
+            }
+
+            let chunks: Vec<ChunkEmbedding> = spans
+                .into_iter()
+                .map(|span| ChunkEmbedding {
+                    span,
+                    embedding: next(),
+                })
+                .collect();
+            DocumentEmbedding {
+                embedding: weighted_mean(&chunks),
+                chunks,
+            }
+        })
+        .collect())
+}
+
+/// Fit a header, whose tokens are at `offsets`, into windows of `size`
+/// tokens of which the instruction takes `instruction_tokens`: it gets up
+/// to half of the rest, and is cut short if longer. Returns what is left
+/// of the header, if anything, and the tokens left for the text.
+fn fit_header<'a>(
+    header: &'a str,
+    offsets: &[(usize, usize)],
+    size: usize,
+    instruction_tokens: usize,
+) -> (Option<&'a str>, usize) {
+    let available = size.saturating_sub(instruction_tokens);
+    let kept = offsets.len().min(available / 2);
+    let header = match kept {
+        0 => None,
+        kept => Some(&header[..offsets[kept - 1].1]),
+    };
+    (header, available - kept)
+}
+
+/// What the model is given for `text`: the header ahead of it, and both
+/// put into the instruction.
+fn input<'a>(instruction: &str, header: Option<&str>, text: &'a str) -> Cow<'a, str> {
+    match header {
+        Some(header) => Cow::Owned(
+            embedding::instruct(instruction, &format!("{}\n\n{}", header, text)).into_owned(),
+        ),
+        None => embedding::instruct(instruction, text),
+    }
+}
+
+fn weighted_mean(chunks: &[ChunkEmbedding]) -> Vec<f32> {
+    let mut mean = vec![0.0f32; chunks[0].embedding.len()];
+    for chunk in chunks {
+        let weight = chunk.span.len() as f32;
+        for (m, x) in mean.iter_mut().zip(&chunk.embedding) {
+            *m += weight * x;
+        }
+    }
+
+    let norm = mean.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        for m in &mut mean {
+            *m /= norm;
+        }
+    }
+    mean
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_windows_overlap() {
+        // Ten two-byte tokens separated by spaces
+        let offsets: Vec<(usize, usize)> = (0..10).map(|i| (i * 3, i * 3 + 2)).collect();
+
+        assert!(windows(&offsets, 10, 2).is_empty());
+        assert!(windows(&offsets, 0, 0).is_empty());
+
+        // Windows of 4 tokens stepping by 3 share one token
+        assert_eq!(windows(&offsets, 4, 1), vec![0..11, 9..20, 18..29]);
+
+        // The last window is cut short rather than running past the text
+        assert_eq!(windows(&offsets, 6, 2), vec![0..17, 12..29]);
+    }
+
+    #[test]
+    fn test_header_is_cut_to_fit() {
+        let header = "Title: Law of Three\nTags: triad";
+        let mut offsets = Vec::new();
+        let mut start = 0;
+        for word in header.split([' ', '\n']) {
+            offsets.push((start, start + word.len()));
+            start += word.len() + 1;
+        }
+
+        // A short header keeps all its tokens
+        assert_eq!(fit_header(header, &offsets, 64, 4), (Some(header), 54));
+        // A long one gets half of what the instruction leaves
+        assert_eq!(
+            fit_header(header, &offsets, 10, 4),
+            (Some("Title: Law of"), 3)
+        );
+        assert_eq!(fit_header(header, &offsets, 5, 4), (None, 1));
+        // Header, instruction, and text never take more than the window
+        for size in 0..20 {
+            for instruction in 0..10 {
+                let (header, left) = fit_header(header, &offsets, size, instruction);
+                let header = header.map_or(0, |h| h.split([' ', '\n']).count());
+                assert!(size == 0 || header + instruction.min(size) + left <= size);
+            }
+        }
+    }
+
+    #[test]
+    fn test_sentence_windows_end_at_sentences() {
+        let text = "One two three. Four five six seven. Eight nine";
+        let mut offsets = Vec::new();
+        let mut start = 0;
+        for word in text.split(' ') {
+            offsets.push((start, start + word.len()));
+            start += word.len() + 1;
+        }
+
+        // The first window of 5 would end after "five"; it ends after
+        // "three." instead, and the next starts one token of overlap back
+        assert_eq!(
+            sentence_windows(text, &offsets, 5, 1),
+            vec![0..14, 8..35, 29..46]
+        );
+        // Without a sentence end in the second half, windows are cut as usual
+        assert_eq!(
+            sentence_windows(text, &offsets, 2, 0),
+            windows(&offsets, 2, 0)
+        );
+        assert!(sentence_windows(text, &offsets, 10, 1).is_empty());
+
+        // An overlap as large as the window still moves on by half of it,
+        // rather than a token at a time
+        let text = "A. ".repeat(1000);
+        let mut offsets = Vec::new();
+        for start in (0..text.len()).step_by(3) {
+            offsets.push((start, start + 2));
+        }
+        assert!(sentence_windows(&text, &offsets, 64, 64).len() < 100);
+        assert!(windows(&offsets, 64, 64).len() < 100);
+    }
+}
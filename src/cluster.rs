@@ -0,0 +1,274 @@
+/// Lloyd iterations at most. Embedding clusters settle within a handful.
+const ITERATIONS: usize = 20;
+
+/// Vectors a silhouette is measured over. Scoring compares every pair,
+/// so larger sets are sampled down to this many.
+const SILHOUETTE_SAMPLE: usize = 1000;
+
+/// Vectors grouped by k-means.
+pub struct Clustering {
+    /// The cluster of each vector, by index into `centroids`
+    pub assignments: Vec<usize>,
+    /// Unit-length mean direction of each cluster
+    pub centroids: Vec<Vec<f32>>,
+}
+
+impl Clustering {
+    /// Indexes of the vectors in `cluster`, the closest to its centroid
+    /// first.
+    pub fn members(&self, vectors: &[Vec<f32>], cluster: usize) -> Vec<usize> {
+        let centroid = &self.centroids[cluster];
+        let mut members: Vec<(usize, f32)> = self
+            .assignments
+            .iter()
+            .enumerate()
+            .filter(|&(_, &assigned)| assigned == cluster)
+            .map(|(i, _)| (i, similarity(&vectors[i], centroid)))
+            .collect();
+        members.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+        members.into_iter().map(|(i, _)| i).collect()
+    }
+}
+
+/// Group `vectors`, which must all have the same length, into at most `k`
+/// clusters by spherical k-means, comparing them by cosine similarity.
+/// Seeds are chosen farthest-first from the most central vector rather
+/// than at random, so the same vectors always cluster the same way.
+pub fn kmeans(vectors: &[Vec<f32>], k: usize) -> Clustering {
+    if vectors.is_empty() {
+        return Clustering {
+            assignments: Vec::new(),
+            centroids: Vec::new(),
+        };
+    }
+    let k = k.clamp(1, vectors.len());
+
+    let central = nearest(&mean_direction(vectors.iter()), vectors.iter());
+    let mut centroids = vec![unit(&vectors[central])];
+    // Each next seed is the vector least like any seed so far
+    let mut closest: Vec<f32> = vectors
+        .iter()
+        .map(|v| similarity(v, &centroids[0]))
+        .collect();
+    while centroids.len() < k {
+        let (seed, _) = closest
+            .iter()
+            .enumerate()
+            .min_by(|a, b| a.1.total_cmp(b.1))
+            .expect("vectors is not empty");
+        let centroid = unit(&vectors[seed]);
+        for (c, v) in closest.iter_mut().zip(vectors) {
+            *c = c.max(similarity(v, &centroid));
+        }
+        centroids.push(centroid);
+    }
+
+    let mut assignments = vec![usize::MAX; vectors.len()];
+    for _ in 0..ITERATIONS {
+        let mut changed = false;
+        for (assignment, vector) in assignments.iter_mut().zip(vectors) {
+            let cluster = nearest(vector, centroids.iter());
+            changed |= *assignment != cluster;
+            *assignment = cluster;
+        }
+        if !changed {
+            break;
+        }
+        for (cluster, centroid) in centroids.iter_mut().enumerate() {
+            let members = vectors
+                .iter()
+                .zip(&assignments)
+                .filter(|&(_, &assigned)| assigned == cluster)
+                .map(|(vector, _)| vector);
+            // A centroid nothing was assigned to keeps its position
+            let mean = mean_direction(members);
+            if mean.iter().any(|&x| x != 0.0) {
+                *centroid = mean;
+            }
+        }
+    }
+
+    Clustering {
+        assignments,
+        centroids,
+    }
+}
+
+/// Mean silhouette of the clustering of `vectors` into `assignments`,
+/// by cosine distance: towards 1 when each vector is much closer to its
+/// own cluster than to the next nearest, 0 when clusters overlap, and
+/// negative when vectors sit in the wrong one. Measured over an evenly
+/// spread sample of large sets.
+pub fn silhouette(vectors: &[Vec<f32>], assignments: &[usize]) -> f32 {
+    let sample = sample(vectors.len());
+    let distances = distances(vectors, &sample);
+    let assignments: Vec<usize> = sample.iter().map(|&i| assignments[i]).collect();
+    sample_silhouette(&distances, &assignments)
+}
+
+/// The `k` from 2 to `max_k` whose k-means clustering of `vectors` has
+/// the best silhouette, each tried on a sample of large sets. 1 if there
+/// are too few vectors to compare two clusters.
+pub fn choose_k(vectors: &[Vec<f32>], max_k: usize) -> usize {
+    let sample = sample(vectors.len());
+    let distances = distances(vectors, &sample);
+    let sampled: Vec<Vec<f32>> = sample.iter().map(|&i| vectors[i].clone()).collect();
+
+    let mut best = (1, f32::MIN);
+    for k in 2..=max_k.min(sampled.len().saturating_sub(1)) {
+        let clustering = kmeans(&sampled, k);
+        let score = sample_silhouette(&distances, &clustering.assignments);
+        // Ties go to the fewer clusters
+        if score > best.1 {
+            best = (k, score);
+        }
+    }
+    best.0
+}
+
+/// Indexes of up to `SILHOUETTE_SAMPLE` of `len` vectors, evenly spread.
+fn sample(len: usize) -> Vec<usize> {
+    let step = len.div_ceil(SILHOUETTE_SAMPLE).max(1);
+    (0..len).step_by(step).collect()
+}
+
+/// Cosine distance between every pair of the `sample` of `vectors`, row
+/// by row.
+fn distances(vectors: &[Vec<f32>], sample: &[usize]) -> Vec<f32> {
+    let mut distances = vec![0.0; sample.len() * sample.len()];
+    for (a, &i) in sample.iter().enumerate() {
+        for (b, &j) in sample.iter().enumerate().skip(a + 1) {
+            let distance = 1.0 - similarity(&vectors[i], &vectors[j]);
+            distances[a * sample.len() + b] = distance;
+            distances[b * sample.len() + a] = distance;
+        }
+    }
+    distances
+}
+
+/// Mean silhouette of sampled vectors from their pairwise `distances`.
+/// Vectors alone in their cluster count as 0.
+fn sample_silhouette(distances: &[f32], assignments: &[usize]) -> f32 {
+    let len = assignments.len();
+    let clusters = assignments.iter().max().map_or(0, |&c| c + 1);
+    if len == 0 {
+        return 0.0;
+    }
+
+    let mut total = 0.0;
+    for (i, &own) in assignments.iter().enumerate() {
+        let mut sums = vec![0.0f32; clusters];
+        let mut counts = vec![0usize; clusters];
+        for (j, &cluster) in assignments.iter().enumerate() {
+            if j != i {
+                sums[cluster] += distances[i * len + j];
+                counts[cluster] += 1;
+            }
+        }
+        if counts[own] == 0 {
+            continue;
+        }
+        let within = sums[own] / counts[own] as f32;
+        let Some(nearest) = (0..clusters)
+            .filter(|&c| c != own && counts[c] > 0)
+            .map(|c| sums[c] / counts[c] as f32)
+            .min_by(|a, b| a.total_cmp(b))
+        else {
+            continue;
+        };
+        let spread = within.max(nearest);
+        if spread > 0.0 {
+            total += (nearest - within) / spread;
+        }
+    }
+    total / len as f32
+}
+
+/// Index of the vector in `candidates` most similar to `vector`.
+fn nearest<'a>(vector: &[f32], candidates: impl Iterator<Item = &'a Vec<f32>>) -> usize {
+    candidates
+        .map(|candidate| similarity(vector, candidate))
+        .enumerate()
+        .max_by(|a, b| a.1.total_cmp(&b.1).then(b.0.cmp(&a.0)))
+        .map_or(0, |(i, _)| i)
+}
+
+/// Normalized sum of the vectors, each normalized first so long ones
+/// don't dominate. All zeros if there are none.
+fn mean_direction<'a>(vectors: impl Iterator<Item = &'a Vec<f32>>) -> Vec<f32> {
+    let mut sum: Vec<f32> = Vec::new();
+    for vector in vectors {
+        sum.resize(vector.len(), 0.0);
+        for (s, x) in sum.iter_mut().zip(unit(vector)) {
+            *s += x;
+        }
+    }
+    unit(&sum)
+}
+
+fn unit(vector: &[f32]) -> Vec<f32> {
+    let norm = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm == 0.0 {
+        return vector.to_vec();
+    }
+    vector.iter().map(|x| x / norm).collect()
+}
+
+fn similarity(a: &[f32], b: &[f32]) -> f32 {
+    crate::index::cosine_similarity(a, b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kmeans_finds_clusters() {
+        let mut vectors = Vec::new();
+        for i in 0..10 {
+            let jitter = i as f32 * 0.02;
+            vectors.push(vec![1.0, jitter, 0.0]);
+            vectors.push(vec![0.0, 1.0, jitter]);
+            vectors.push(vec![jitter, 0.0, 1.0]);
+        }
+        let clustering = kmeans(&vectors, 3);
+
+        assert_eq!(clustering.centroids.len(), 3);
+        // Vectors built along the same axis share a cluster, and the three
+        // axes get different ones
+        for axis in 0..3 {
+            let cluster = clustering.assignments[axis];
+            assert!((axis..vectors.len())
+                .step_by(3)
+                .all(|i| clustering.assignments[i] == cluster));
+        }
+        let mut clusters = clustering.assignments[..3].to_vec();
+        clusters.sort();
+        clusters.dedup();
+        assert_eq!(clusters.len(), 3);
+
+        let members = clustering.members(&vectors, clustering.assignments[0]);
+        assert_eq!(members.len(), 10);
+        assert!(members.iter().all(|i| i % 3 == 0));
+        assert_eq!(kmeans(&vectors, 3).assignments, clustering.assignments);
+        assert!(kmeans(&[], 3).centroids.is_empty());
+    }
+
+    #[test]
+    fn test_choose_k_by_silhouette() {
+        let mut vectors = Vec::new();
+        for i in 0..10 {
+            let jitter = i as f32 * 0.02;
+            vectors.push(vec![1.0, jitter, 0.0, 0.0]);
+            vectors.push(vec![0.0, 1.0, jitter, 0.0]);
+            vectors.push(vec![jitter, 0.0, 1.0, 0.0]);
+        }
+
+        assert_eq!(choose_k(&vectors, 8), 3);
+        let three = kmeans(&vectors, 3);
+        let two = kmeans(&vectors, 2);
+        assert!(silhouette(&vectors, &three.assignments) > 0.8);
+        assert!(silhouette(&vectors, &two.assignments) < silhouette(&vectors, &three.assignments));
+        assert_eq!(choose_k(&vectors[..2], 8), 1);
+    }
+}
@@ -0,0 +1,304 @@
+use axum::{
+    async_trait,
+    body::{BodyDataStream, Bytes},
+    extract::{FromRequest, FromRequestParts, Request},
+    http::{header, request::Parts, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use futures::StreamExt;
+use serde::{de::DeserializeOwned, Serialize};
+use std::convert::Infallible;
+
+use crate::error::AppError;
+
+const MSGPACK: &str = "application/msgpack";
+const MSGPACK_LEGACY: &str = "application/x-msgpack";
+const NDJSON: &str = "application/x-ndjson";
+
+/// Wire format of a request or response body.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    MessagePack,
+}
+
+impl Format {
+    pub fn from_content_type(headers: &HeaderMap) -> Result<Self, AppError> {
+        let Some(content_type) = headers.get(header::CONTENT_TYPE) else {
+            return Ok(Format::Json);
+        };
+        let mime = content_type.to_str().unwrap_or_default();
+        let mime = mime.split(';').next().unwrap_or_default().trim();
+
+        match mime {
+            MSGPACK | MSGPACK_LEGACY => Ok(Format::MessagePack),
+            "application/json" | "" => Ok(Format::Json),
+            other => Err(AppError::UnsupportedMediaType(format!(
+                "Unsupported content type {:?}, expected application/json or {}",
+                other, MSGPACK
+            ))),
+        }
+    }
+
+    /// Pick the response format from an Accept header, defaulting to JSON.
+    fn from_accept(headers: &HeaderMap) -> Self {
+        let accept = headers
+            .get(header::ACCEPT)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+
+        let wants_msgpack = accept.split(',').any(|mime| {
+            let mime = mime.split(';').next().unwrap_or_default().trim();
+            mime == MSGPACK || mime == MSGPACK_LEGACY
+        });
+
+        if wants_msgpack {
+            Format::MessagePack
+        } else {
+            Format::Json
+        }
+    }
+
+    pub fn encode<T: Serialize>(self, value: T) -> Encoded<T> {
+        Encoded(self, value)
+    }
+}
+
+/// The response format requested by the client's Accept header.
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Format {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(Format::from_accept(&parts.headers))
+    }
+}
+
+/// Request body decoded from JSON or MessagePack according to its
+/// Content-Type.
+pub struct Body<T>(pub T);
+
+#[async_trait]
+impl<S, T> FromRequest<S> for Body<T>
+where
+    S: Send + Sync,
+    T: DeserializeOwned,
+{
+    type Rejection = AppError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let format = Format::from_content_type(req.headers())?;
+        let bytes = Bytes::from_request(req, state)
+            .await
+            .map_err(|e| AppError::BadRequest(e.to_string()))?;
+
+        let value = match format {
+            Format::Json => serde_json::from_slice(&bytes)
+                .map_err(|e| AppError::BadRequest(format!("Invalid JSON body: {}", e)))?,
+            Format::MessagePack => rmp_serde::from_slice(&bytes)
+                .map_err(|e| AppError::BadRequest(format!("Invalid MessagePack body: {}", e)))?,
+        };
+
+        Ok(Body(value))
+    }
+}
+
+/// Whether a request body is newline-delimited JSON.
+pub fn is_ndjson(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|mime| mime.split(';').next())
+        .is_some_and(|mime| mime.trim() == NDJSON)
+}
+
+/// Longest line an NDJSON body may have by default.
+pub const MAX_LINE_BYTES: usize = 16 * 1024 * 1024;
+
+/// Largest NDJSON body accepted by default.
+pub const MAX_NDJSON_BYTES: u64 = 1024 * 1024 * 1024;
+
+/// Newline-delimited JSON request body, split into lines as it streams in
+/// so the whole upload never has to be held in memory. Lines and the body
+/// as a whole are capped, so one endless line or upload can't exhaust
+/// memory or tie up the server.
+pub struct NdjsonLines {
+    stream: BodyDataStream,
+    buffer: Vec<u8>,
+    done: bool,
+    /// Bytes of the body read so far
+    read: u64,
+    max_line: usize,
+    max_total: u64,
+}
+
+impl NdjsonLines {
+    pub fn new(body: axum::body::Body) -> Self {
+        Self {
+            stream: body.into_data_stream(),
+            buffer: Vec::new(),
+            done: false,
+            read: 0,
+            max_line: MAX_LINE_BYTES,
+            max_total: MAX_NDJSON_BYTES,
+        }
+    }
+
+    /// Cap lines at `max_line` bytes and the body at `max_total`.
+    pub fn with_limits(mut self, max_line: usize, max_total: u64) -> Self {
+        self.max_line = max_line;
+        self.max_total = max_total;
+        self
+    }
+
+    /// The next non-blank line, or `None` once the body is exhausted. A
+    /// line or body over its limit is a `413 Payload Too Large`, after
+    /// which no more lines are read.
+    pub async fn next_line(&mut self) -> Option<Result<Vec<u8>, AppError>> {
+        loop {
+            if let Some(end) = self.buffer.iter().position(|&b| b == b'\n') {
+                if end > self.max_line {
+                    return Some(Err(self.line_too_long()));
+                }
+                let line: Vec<u8> = self.buffer.drain(..=end).collect();
+                if line.iter().all(u8::is_ascii_whitespace) {
+                    continue;
+                }
+                return Some(Ok(line));
+            }
+            if self.buffer.len() > self.max_line {
+                return Some(Err(self.line_too_long()));
+            }
+
+            if self.done {
+                // A final line without a trailing newline
+                let line = std::mem::take(&mut self.buffer);
+                return (!line.iter().all(u8::is_ascii_whitespace)).then_some(Ok(line));
+            }
+
+            match self.stream.next().await {
+                Some(Ok(chunk)) => {
+                    self.read += chunk.len() as u64;
+                    if self.read > self.max_total {
+                        self.stop();
+                        return Some(Err(AppError::PayloadTooLarge(format!(
+                            "Body exceeds the limit of {} bytes",
+                            self.max_total
+                        ))));
+                    }
+                    self.buffer.extend_from_slice(&chunk);
+                }
+                Some(Err(e)) => return Some(Err(AppError::BadRequest(e.to_string()))),
+                None => self.done = true,
+            }
+        }
+    }
+
+    fn line_too_long(&mut self) -> AppError {
+        self.stop();
+        AppError::PayloadTooLarge(format!("Line exceeds the limit of {} bytes", self.max_line))
+    }
+
+    /// Read no further.
+    fn stop(&mut self) {
+        self.buffer = Vec::new();
+        self.done = true;
+    }
+}
+
+/// Response body serialized in the format the client asked for.
+pub struct Encoded<T>(Format, T);
+
+impl<T> Encoded<T> {
+    /// The value without its wire format, for callers other than HTTP.
+    pub fn into_inner(self) -> T {
+        self.1
+    }
+}
+
+impl<T: Serialize> IntoResponse for Encoded<T> {
+    fn into_response(self) -> Response {
+        let Encoded(format, value) = self;
+
+        match format {
+            Format::Json => Json(value).into_response(),
+            // Named fields keep maps keyed by field name, matching the JSON shape
+            Format::MessagePack => match rmp_serde::to_vec_named(&value) {
+                Ok(bytes) => ([(header::CONTENT_TYPE, MSGPACK)], bytes).into_response(),
+                Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    #[test]
+    fn test_format_negotiation() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(Format::from_accept(&headers), Format::Json);
+        assert_eq!(Format::from_content_type(&headers).unwrap(), Format::Json);
+
+        headers.insert(
+            header::ACCEPT,
+            HeaderValue::from_static("application/json;q=0.5, application/msgpack"),
+        );
+        assert_eq!(Format::from_accept(&headers), Format::MessagePack);
+
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/x-msgpack"),
+        );
+        assert_eq!(
+            Format::from_content_type(&headers).unwrap(),
+            Format::MessagePack
+        );
+
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+        assert!(Format::from_content_type(&headers).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_ndjson_lines() {
+        let body = axum::body::Body::from("{\"id\": 1}\n\n  \n{\"id\": 2}\r\n{\"id\": 3}");
+        let mut lines = NdjsonLines::new(body);
+
+        let mut ids = Vec::new();
+        while let Some(line) = lines.next_line().await {
+            let value: serde_json::Value = serde_json::from_slice(&line.unwrap()).unwrap();
+            ids.push(value["id"].as_u64().unwrap());
+        }
+        assert_eq!(ids, vec![1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn test_ndjson_limits() {
+        let body = axum::body::Body::from(format!("{{}}\n{}\n{{}}\n", "x".repeat(100)));
+        let mut lines = NdjsonLines::new(body).with_limits(64, 1024);
+        assert!(lines.next_line().await.unwrap().is_ok());
+        assert!(matches!(
+            lines.next_line().await,
+            Some(Err(AppError::PayloadTooLarge(_)))
+        ));
+        assert!(lines.next_line().await.is_none());
+
+        // One endless line never ending in a newline
+        let body = axum::body::Body::from("x".repeat(100));
+        let mut lines = NdjsonLines::new(body).with_limits(64, 1024);
+        assert!(matches!(
+            lines.next_line().await,
+            Some(Err(AppError::PayloadTooLarge(_)))
+        ));
+
+        let body = axum::body::Body::from("{}\n".repeat(20));
+        let mut lines = NdjsonLines::new(body).with_limits(64, 16);
+        assert!(matches!(
+            lines.next_line().await,
+            Some(Err(AppError::PayloadTooLarge(_)))
+        ));
+    }
+}
@@ -0,0 +1,169 @@
+//! Per-endpoint concurrency limits. Requests beyond an endpoint's limit
+//! are turned away at once with `503 Service Unavailable` and a
+//! `Retry-After` header rather than queued, so a client stuck in a retry
+//! loop can't pile up embedding work in memory. The same limits cover the
+//! gRPC calls and live search queries that do an endpoint's work.
+
+use axum::{
+    error_handling::HandleErrorLayer,
+    http::{header, HeaderValue},
+    response::{IntoResponse, Response},
+    routing::MethodRouter,
+    BoxError,
+};
+use std::sync::Arc;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tower::limit::GlobalConcurrencyLimitLayer;
+use tower::load_shed::error::Overloaded;
+use tower::ServiceBuilder;
+use tracing::debug;
+
+use crate::config::ConcurrencyConfig;
+use crate::error::AppError;
+
+/// The limit of each group of heavy endpoints.
+#[derive(Clone)]
+pub struct Limits {
+    pub embed: EndpointLimit,
+    pub index: EndpointLimit,
+    pub search: EndpointLimit,
+    pub analysis: EndpointLimit,
+}
+
+impl Limits {
+    pub fn new(config: &ConcurrencyConfig) -> Self {
+        let retry_after = config.retry_after_secs;
+        Self {
+            embed: EndpointLimit::new("embedding", config.embed, retry_after),
+            index: EndpointLimit::new("indexing", config.index, retry_after),
+            search: EndpointLimit::new("search", config.search, retry_after),
+            analysis: EndpointLimit::new("analysis", config.analysis, retry_after),
+        }
+    }
+}
+
+/// A limit shared by every route it is applied to.
+#[derive(Clone)]
+pub struct EndpointLimit {
+    name: &'static str,
+    /// `None` when unlimited
+    permits: Option<Arc<Semaphore>>,
+    retry_after_secs: u64,
+}
+
+impl EndpointLimit {
+    /// Allow `max` requests at once to the routes of `name`, or any number
+    /// if `max` is 0.
+    pub fn new(name: &'static str, max: usize, retry_after_secs: u64) -> Self {
+        Self {
+            name,
+            permits: (max > 0).then(|| Arc::new(Semaphore::new(max))),
+            retry_after_secs,
+        }
+    }
+
+    /// `route`, shedding requests while the limit is reached.
+    pub fn apply<S>(&self, route: MethodRouter<S>) -> MethodRouter<S>
+    where
+        S: Clone + Send + Sync + 'static,
+    {
+        let Some(permits) = &self.permits else {
+            return route;
+        };
+        let (name, retry_after_secs) = (self.name, self.retry_after_secs);
+        route.layer(
+            ServiceBuilder::new()
+                .layer(HandleErrorLayer::new(move |error: BoxError| async move {
+                    shed(error, name, retry_after_secs)
+                }))
+                .load_shed()
+                .layer(GlobalConcurrencyLimitLayer::with_semaphore(permits.clone())),
+        )
+    }
+
+    /// A permit for work done outside the routes the limit is applied to,
+    /// held until dropped; `None` when unlimited. Fails while the limit is
+    /// reached.
+    pub fn try_acquire(&self) -> Result<Option<OwnedSemaphorePermit>, AppError> {
+        let Some(permits) = &self.permits else {
+            return Ok(None);
+        };
+        permits.clone().try_acquire_owned().map(Some).map_err(|_| {
+            debug!("Shed a {} request", self.name);
+            overloaded(self.name, self.retry_after_secs)
+        })
+    }
+}
+
+fn overloaded(name: &str, retry_after_secs: u64) -> AppError {
+    AppError::ServiceUnavailable(format!(
+        "Too many {} requests in progress; retry in {} s",
+        name, retry_after_secs
+    ))
+}
+
+fn shed(error: BoxError, name: &str, retry_after_secs: u64) -> Response {
+    if !error.is::<Overloaded>() {
+        return AppError::Internal(error.to_string()).into_response();
+    }
+    debug!("Shed a {} request", name);
+    let mut response = overloaded(name, retry_after_secs).into_response();
+    response
+        .headers_mut()
+        .insert(header::RETRY_AFTER, HeaderValue::from(retry_after_secs));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, extract::Request, http::StatusCode, routing::get, Router};
+    use tokio::sync::Notify;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn test_requests_beyond_the_limit_are_shed() {
+        let release = Arc::new(Notify::new());
+        let limit = EndpointLimit::new("search", 1, 2);
+        let handler = {
+            let release = release.clone();
+            get(move || async move {
+                release.notified().await;
+                "done"
+            })
+        };
+        // Both routes share the one permit
+        let app = Router::new()
+            .route("/a", limit.apply(handler.clone()))
+            .route("/b", limit.apply(handler));
+        let request = |uri| Request::builder().uri(uri).body(Body::empty()).unwrap();
+
+        let first = tokio::spawn(app.clone().oneshot(request("/a")));
+        let permits = limit.permits.clone().unwrap();
+        while permits.available_permits() > 0 {
+            tokio::task::yield_now().await;
+        }
+        let shed = app.clone().oneshot(request("/b")).await.unwrap();
+        assert_eq!(shed.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(shed.headers()[header::RETRY_AFTER], "2");
+
+        release.notify_one();
+        assert_eq!(first.await.unwrap().unwrap().status(), StatusCode::OK);
+        release.notify_one();
+        let response = app.clone().oneshot(request("/b")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Work outside the routes takes from the same permit
+        let held = limit.try_acquire().unwrap();
+        assert!(held.is_some());
+        assert!(limit.try_acquire().is_err());
+        let shed = app.oneshot(request("/a")).await.unwrap();
+        assert_eq!(shed.status(), StatusCode::SERVICE_UNAVAILABLE);
+        drop(held);
+        assert!(limit.try_acquire().unwrap().is_some());
+
+        let unlimited = EndpointLimit::new("search", 0, 2);
+        assert!(unlimited.permits.is_none());
+        assert!(unlimited.try_acquire().unwrap().is_none());
+    }
+}
@@ -0,0 +1,1817 @@
+use anyhow::{Context, Result};
+use axum::http::HeaderValue;
+use clap::{Parser, Subcommand};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::embedding;
+use crate::flags::Flag;
+use crate::metadata;
+use crate::preflight::Problems;
+use crate::signature::TrustRoot;
+use crate::vector::PQ_CENTROIDS;
+
+/// Numeric precision embeddings are stored and returned at. Pooling and
+/// normalization always accumulate in f32.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Precision {
+    #[default]
+    F32,
+    /// Half the memory per vector at a small similarity error
+    F16,
+}
+
+impl Precision {
+    pub fn is_f32(&self) -> bool {
+        *self == Precision::F32
+    }
+}
+
+impl FromStr for Precision {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "f32" => Ok(Precision::F32),
+            "f16" => Ok(Precision::F16),
+            _ => anyhow::bail!("Unknown precision {:?}, expected f32 or f16", s),
+        }
+    }
+}
+
+/// Numeric format of the model weights inference runs with, choosing which
+/// ONNX export of a model is downloaded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InferencePrecision {
+    #[default]
+    Fp32,
+    /// Weights quantized to 8-bit integers: a quarter of the size and
+    /// faster on CPUs, with slightly different embeddings
+    Int8,
+}
+
+impl InferencePrecision {
+    pub fn is_fp32(&self) -> bool {
+        *self == InferencePrecision::Fp32
+    }
+}
+
+impl FromStr for InferencePrecision {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "fp32" | "f32" => Ok(InferencePrecision::Fp32),
+            "int8" => Ok(InferencePrecision::Int8),
+            _ => anyhow::bail!("Unknown inference precision {:?}, expected fp32 or int8", s),
+        }
+    }
+}
+
+/// Lossy compression applied to vectors as they are indexed, trading
+/// recall for memory. Queries stay at full precision and are compared
+/// against the compressed vectors directly.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Quantization {
+    /// Store vectors at the model precision
+    #[default]
+    None,
+    /// One signed byte per dimension plus a per-vector scale, about a
+    /// quarter of f32
+    Int8,
+    /// Product quantization: each group of dimensions is replaced by the
+    /// index of its nearest learned centroid, one byte per group
+    Pq,
+}
+
+impl Quantization {
+    pub fn is_none(&self) -> bool {
+        *self == Quantization::None
+    }
+}
+
+impl FromStr for Quantization {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" => Ok(Quantization::None),
+            "int8" => Ok(Quantization::Int8),
+            "pq" => Ok(Quantization::Pq),
+            _ => anyhow::bail!("Unknown quantization {:?}, expected none, int8 or pq", s),
+        }
+    }
+}
+
+/// Where each collection's documents are persisted. Either way the index
+/// is searched in memory; this only decides what is on disk.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    /// A snapshot of the in-memory index plus a log of changes since
+    #[default]
+    Memory,
+    /// One SQLite database per collection, written on every change
+    Sqlite,
+}
+
+impl FromStr for StorageBackend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "memory" => Ok(StorageBackend::Memory),
+            "sqlite" => Ok(StorageBackend::Sqlite),
+            _ => anyhow::bail!("Unknown storage backend {:?}, expected memory or sqlite", s),
+        }
+    }
+}
+
+/// When writes to the index log are forced to disk. Until then a write
+/// survives the server crashing but not the machine losing power.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FsyncPolicy {
+    /// Before every mutation is acknowledged
+    Always,
+    /// Every `fsync_interval_ms`, so at most that much is lost
+    #[default]
+    Interval,
+    /// Whenever the OS flushes its cache
+    Never,
+}
+
+impl FromStr for FsyncPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "always" => Ok(FsyncPolicy::Always),
+            "interval" => Ok(FsyncPolicy::Interval),
+            "never" => Ok(FsyncPolicy::Never),
+            _ => anyhow::bail!(
+                "Unknown fsync policy {:?}, expected always, interval, or never",
+                s
+            ),
+        }
+    }
+}
+
+/// How the model's per-token outputs are reduced to one embedding.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Pooling {
+    /// Average over the non-padding tokens, as sentence-transformers models
+    /// such as MiniLM and E5 expect
+    #[default]
+    Mean,
+    /// The first ([CLS]) token's output, as BGE models expect
+    Cls,
+    /// Largest value of each dimension over the non-padding tokens
+    Max,
+    /// Average over the non-padding tokens weighted by position, later
+    /// tokens counting more, as SGPT-style decoder models expect
+    WeightedMean,
+}
+
+impl FromStr for Pooling {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "mean" => Ok(Pooling::Mean),
+            "cls" => Ok(Pooling::Cls),
+            "max" => Ok(Pooling::Max),
+            "weighted-mean" => Ok(Pooling::WeightedMean),
+            _ => anyhow::bail!(
+                "Unknown pooling {:?}, expected mean, cls, max or weighted-mean",
+                s
+            ),
+        }
+    }
+}
+
+/// Hardware backends ONNX Runtime can run the model on. All but the CPU
+/// need the server built with the matching Cargo feature.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ExecutionProvider {
+    /// NVIDIA GPUs (`cuda` feature)
+    Cuda,
+    /// Any DirectX 12 GPU on Windows (`directml` feature)
+    #[value(name = "directml")]
+    DirectMl,
+    /// Apple Neural Engine and GPU on macOS (`coreml` feature)
+    #[value(name = "coreml")]
+    CoreMl,
+    Cpu,
+}
+
+impl FromStr for ExecutionProvider {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "cuda" => Ok(ExecutionProvider::Cuda),
+            "directml" => Ok(ExecutionProvider::DirectMl),
+            "coreml" => Ok(ExecutionProvider::CoreMl),
+            "cpu" => Ok(ExecutionProvider::Cpu),
+            _ => anyhow::bail!(
+                "Unknown execution provider {:?}, expected cuda, directml, coreml, or cpu",
+                s
+            ),
+        }
+    }
+}
+
+/// Settings for the embedding model.
+#[derive(Debug, Clone)]
+pub struct ModelConfig {
+    /// Model name reported to clients, and the HuggingFace repo the model
+    /// is downloaded from (bare names resolve under `sentence-transformers/`).
+    pub name: String,
+    /// Local ONNX model file to load instead of downloading `name`.
+    pub path: Option<PathBuf>,
+    /// Local tokenizer.json, by default `tokenizer.json` next to `path`.
+    pub tokenizer_path: Option<PathBuf>,
+    pub pooling: Pooling,
+    /// Threads ONNX Runtime uses within one inference.
+    pub threads: usize,
+    /// Execution providers to try, in order of preference. The first one
+    /// that loads is used, and the CPU is always the last resort.
+    pub execution_providers: Vec<ExecutionProvider>,
+    /// Where downloaded models are cached.
+    pub cache_dir: PathBuf,
+    /// Minisign public keys downloaded model files must be signed by, as
+    /// must mounted bundles. Empty skips signature checks.
+    pub trusted_keys: Vec<String>,
+    /// Embeddings kept in memory so unchanged texts skip the model. 0
+    /// disables the cache.
+    pub cache_entries: usize,
+    /// Also keep every cached embedding on disk under `cache_dir`, so the
+    /// cache survives restarts.
+    pub persist_cache: bool,
+    /// Size cap of the persisted cache file; the oldest entries are dropped
+    /// past it.
+    pub cache_max_mb: u64,
+    /// Whether the tokenizer adds special tokens ([CLS]/[SEP]) when encoding.
+    /// Sentence-transformers models exported with pooling expect them.
+    pub add_special_tokens: bool,
+    /// Texts that tokenize to more tokens than this are rejected outright
+    /// rather than tying up the model.
+    pub max_request_tokens: usize,
+    /// Tokens the model sees of each text, longer texts being truncated.
+    /// 0 keeps the tokenizer's own limit, which this can only lower.
+    pub max_length: usize,
+    /// Reject texts longer than `max_length` from `/embed` instead of
+    /// silently embedding only their start
+    pub strict_truncation: bool,
+    pub precision: Precision,
+    /// Weights the model runs with. Only picks the file downloaded; a
+    /// local `path` is loaded as it is.
+    pub inference_precision: InferencePrecision,
+    /// Instruction search queries are embedded with, e.g. `query: {{text}}`
+    /// for e5 models; empty embeds them as given.
+    pub query_instruction: String,
+    /// Instruction documents are embedded with for indexing, e.g.
+    /// `passage: {{text}}`; empty embeds them as given.
+    pub document_instruction: String,
+    /// How long the first of several concurrent embedding requests waits
+    /// for others to share its forward pass.
+    pub batch_window_ms: u64,
+    /// Texts after which a shared forward pass runs without waiting out
+    /// the window.
+    pub batch_max_texts: usize,
+}
+
+impl Default for ModelConfig {
+    fn default() -> Self {
+        Self {
+            name: "all-MiniLM-L6-v2".to_string(),
+            path: None,
+            tokenizer_path: None,
+            pooling: Pooling::Mean,
+            threads: 4,
+            execution_providers: Vec::new(),
+            cache_dir: dirs::home_dir()
+                .unwrap_or_default()
+                .join(".cache")
+                .join("systematics-embeddings"),
+            trusted_keys: Vec::new(),
+            cache_entries: 10_000,
+            persist_cache: true,
+            cache_max_mb: 1024,
+            add_special_tokens: true,
+            max_request_tokens: 8192,
+            max_length: 0,
+            strict_truncation: false,
+            precision: Precision::F32,
+            inference_precision: InferencePrecision::Fp32,
+            query_instruction: String::new(),
+            document_instruction: String::new(),
+            batch_window_ms: 2,
+            batch_max_texts: 64,
+        }
+    }
+}
+
+impl ModelConfig {
+    /// These settings for another model, served as `name` and pooled with
+    /// `pooling` if given. `model` is a local `.onnx` file or a HuggingFace
+    /// repo, as for the base model. Instructions are particular to a model,
+    /// so aren't carried over.
+    pub fn for_model(&self, name: &str, model: &str, pooling: Option<Pooling>) -> Self {
+        let mut config = self.clone();
+        config.pooling = pooling.unwrap_or(self.pooling);
+        config.tokenizer_path = None;
+        config.query_instruction.clear();
+        config.document_instruction.clear();
+        if model.ends_with(".onnx") {
+            config.name = name.to_string();
+            config.path = Some(PathBuf::from(model));
+        } else {
+            config.name = model.to_string();
+            config.path = None;
+        }
+        config
+    }
+}
+
+/// Settings for persisting collections.
+#[derive(Debug, Clone, Copy)]
+pub struct StorageConfig {
+    pub backend: StorageBackend,
+    /// Number of index log records after which a fresh snapshot is written
+    /// and the log truncated. Only the memory backend keeps a log.
+    pub snapshot_every: usize,
+    pub fsync: FsyncPolicy,
+    /// Time between syncs under [`FsyncPolicy::Interval`].
+    pub fsync_interval_ms: u64,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            backend: StorageBackend::Memory,
+            snapshot_every: 1000,
+            fsync: FsyncPolicy::Interval,
+            fsync_interval_ms: 1000,
+        }
+    }
+}
+
+/// Parameters for the HNSW approximate nearest-neighbour graph.
+#[derive(Debug, Clone, Copy)]
+pub struct HnswConfig {
+    /// Links per node on the upper layers, doubled on the bottom layer.
+    /// Higher improves recall at the cost of memory and insert time.
+    pub m: usize,
+    /// Candidate list size while inserting. Higher builds a better graph,
+    /// more slowly.
+    pub ef_construction: usize,
+    /// Candidate list size while searching. Higher improves recall at the
+    /// cost of latency.
+    pub ef_search: usize,
+    /// Time a graph search may take, in milliseconds. When set, each
+    /// collection's `ef_search` is tuned periodically to the best recall
+    /// that fits, trying values from 16 up, and the value above only
+    /// applies until a collection is first tuned.
+    pub target_latency_ms: Option<f64>,
+    /// Documents sampled as queries, with exact results as ground truth,
+    /// when measuring recall for tuning.
+    pub tune_queries: usize,
+    /// Lock each collection's entry layers, the upper graph layers every
+    /// search descends through, into RAM when it is warmed.
+    pub pin_entry_layers: bool,
+}
+
+impl Default for HnswConfig {
+    fn default() -> Self {
+        Self {
+            m: 16,
+            ef_construction: 200,
+            ef_search: 64,
+            target_latency_ms: None,
+            tune_queries: 50,
+            pin_entry_layers: false,
+        }
+    }
+}
+
+/// Vector compression for the index.
+#[derive(Debug, Clone, Copy)]
+pub struct QuantizationConfig {
+    pub mode: Quantization,
+    /// Bytes per vector under product quantization. More keeps more
+    /// detail; rounded down to a divisor of the model's dimensions.
+    pub pq_subvectors: usize,
+    /// Vectors a collection needs before its product quantization
+    /// centroids are learned. Until then vectors are stored unquantized.
+    pub pq_train_size: usize,
+}
+
+impl Default for QuantizationConfig {
+    fn default() -> Self {
+        Self {
+            mode: Quantization::None,
+            pq_subvectors: 96,
+            pq_train_size: 1000,
+        }
+    }
+}
+
+/// How long documents are split before embedding.
+#[derive(Debug, Clone, Copy)]
+pub struct ChunkingConfig {
+    /// Tokens per chunk. Documents longer than this, or than the model's
+    /// input limit, are embedded as several windows. 0 disables chunking,
+    /// leaving the model to truncate long documents.
+    pub size: usize,
+    /// Tokens consecutive windows share, so a passage that straddles a
+    /// boundary is still seen whole by one of them.
+    pub overlap: usize,
+    /// End windows at a sentence boundary where one falls in their second
+    /// half. Set per request by the `sentence_chunks` feature flag.
+    pub sentence_boundaries: bool,
+}
+
+impl Default for ChunkingConfig {
+    fn default() -> Self {
+        Self {
+            size: 256,
+            overlap: 32,
+            sentence_boundaries: false,
+        }
+    }
+}
+
+/// How metadata keys are normalized at ingest. Filter paths go through the
+/// same rules, so queries match regardless of how a note spelled a key.
+#[derive(Debug, Clone, Default)]
+pub struct MetadataConfig {
+    /// Lowercase every key, so `Tags` and `tags` are the same field
+    pub lowercase_keys: bool,
+    /// Trim keys and replace spaces and punctuation with `_`
+    pub sanitize_keys: bool,
+    /// Top-level keys renamed to a canonical field after the rules above,
+    /// e.g. Obsidian's older `tag` property to `tags`
+    pub key_aliases: HashMap<String, String>,
+}
+
+impl MetadataConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.lowercase_keys || self.sanitize_keys || !self.key_aliases.is_empty()
+    }
+}
+
+/// The log of searches and feedback kept for analysis.
+#[derive(Debug, Clone, Copy)]
+pub struct AnalyticsConfig {
+    /// Size cap of the log in MB; 0 disables it, and feedback is then
+    /// kept in memory only
+    pub max_mb: u64,
+    /// Keep query texts, not just their hashes
+    pub query_text: bool,
+}
+
+impl Default for AnalyticsConfig {
+    fn default() -> Self {
+        Self {
+            max_mb: 64,
+            query_text: false,
+        }
+    }
+}
+
+/// How markdown is cleaned up before indexing.
+#[derive(Debug, Clone, Copy)]
+pub struct MarkdownConfig {
+    /// Strip markdown syntax from documents and vault notes, and merge
+    /// their frontmatter and `#tags` into metadata
+    pub enabled: bool,
+    /// Embed each heading's section as a chunk of its own
+    pub split_headings: bool,
+    /// Keep the contents of code blocks, rather than dropping them
+    pub keep_code: bool,
+}
+
+impl Default for MarkdownConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            split_headings: false,
+            keep_code: true,
+        }
+    }
+}
+
+/// An Obsidian vault whose markdown files are indexed automatically.
+#[derive(Debug, Clone)]
+pub struct VaultConfig {
+    /// Vault root; unset disables watching
+    pub path: Option<PathBuf>,
+    /// Collection the notes are indexed into
+    pub collection: String,
+    /// Quiet period after a change before the batch of changes is indexed
+    pub debounce_ms: u64,
+}
+
+impl Default for VaultConfig {
+    fn default() -> Self {
+        Self {
+            path: None,
+            collection: "default".to_string(),
+            debounce_ms: 500,
+        }
+    }
+}
+
+/// Optional cross-encoder that rescores search results on request.
+#[derive(Debug, Clone)]
+pub struct RerankerConfig {
+    /// HuggingFace repo of the cross-encoder; unset (with no `path`)
+    /// disables reranking
+    pub model: Option<String>,
+    /// Local ONNX model file to load instead of downloading `model`
+    pub path: Option<PathBuf>,
+    /// Local tokenizer.json, by default `tokenizer.json` next to `path`
+    pub tokenizer_path: Option<PathBuf>,
+    /// Candidates rescored per search; the best `limit` of them are returned
+    pub candidates: usize,
+}
+
+impl RerankerConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.model.is_some() || self.path.is_some()
+    }
+}
+
+impl Default for RerankerConfig {
+    fn default() -> Self {
+        Self {
+            model: None,
+            path: None,
+            tokenizer_path: None,
+            candidates: 50,
+        }
+    }
+}
+
+/// Links from search results back into Obsidian.
+#[derive(Debug, Clone)]
+pub struct ObsidianConfig {
+    /// Vault name used in `obsidian://` links; unset leaves links out
+    pub vault: Option<String>,
+    /// Metadata field holding a note's vault path, used instead of its id
+    /// when present
+    pub path_field: String,
+}
+
+impl Default for ObsidianConfig {
+    fn default() -> Self {
+        Self {
+            vault: None,
+            path_field: "path".to_string(),
+        }
+    }
+}
+
+/// Opt-in recording of request and response bodies, for diagnosing
+/// client/server contract mismatches.
+#[derive(Debug, Clone)]
+pub struct DebugCaptureConfig {
+    pub enabled: bool,
+    /// Fraction of requests captured, spread evenly
+    pub sample_rate: f64,
+    /// Captures kept; the oldest is dropped first
+    pub capacity: usize,
+    /// Bodies larger than this are noted but not recorded
+    pub max_body_bytes: usize,
+    /// JSON fields whose values are replaced by a placeholder, at any depth
+    pub redact_fields: Vec<String>,
+}
+
+impl Default for DebugCaptureConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            sample_rate: 1.0,
+            capacity: 100,
+            max_body_bytes: 64 * 1024,
+            redact_fields: ["text", "texts", "query", "passage"]
+                .map(String::from)
+                .to_vec(),
+        }
+    }
+}
+
+/// Token-bucket limit on how fast each client address can make requests.
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimitConfig {
+    /// Requests a client can make per second, sustained; 0 turns rate
+    /// limiting off
+    pub per_second: f64,
+    /// Requests a client can make in a burst after being idle
+    pub burst: u32,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            per_second: 0.0,
+            burst: 20,
+        }
+    }
+}
+
+impl RateLimitConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.per_second > 0.0
+    }
+}
+
+/// Requests each group of heavy endpoints runs at once. Beyond that they
+/// are turned away with `503 Service Unavailable` rather than queued.
+/// 0 leaves a group unlimited.
+#[derive(Debug, Clone, Copy)]
+pub struct ConcurrencyConfig {
+    /// `/embed` and `/embed/batch`
+    pub embed: usize,
+    /// `/index`
+    pub index: usize,
+    /// `/search`, query templates, and `/similar`
+    pub search: usize,
+    /// `/cluster`, `/dedupe`, `/digest`, and `/projection`, which each
+    /// read a whole collection
+    pub analysis: usize,
+    /// Seconds a turned-away client is told to wait before retrying
+    pub retry_after_secs: u64,
+}
+
+impl Default for ConcurrencyConfig {
+    fn default() -> Self {
+        Self {
+            embed: 32,
+            index: 32,
+            search: 64,
+            analysis: 2,
+            retry_after_secs: 1,
+        }
+    }
+}
+
+/// Faults injected into the embedding and storage layers, so clients can
+/// test their retry and degradation handling. Needs the `chaos` feature.
+#[cfg(feature = "chaos")]
+#[derive(Debug, Clone, Default)]
+pub struct ChaosConfig {
+    /// Same seed, same requests in the same order: same faults
+    pub seed: u64,
+    /// Delay before every embedding batch
+    pub embedding_latency_ms: u64,
+    /// Fraction of embedding batches that fail, independently of content
+    pub embedding_error_rate: f64,
+    /// Delay before every index log write
+    pub storage_latency_ms: u64,
+    /// Fraction of index writes that fail, independently of content
+    pub storage_error_rate: f64,
+    /// Fraction of texts and document ids that always fail, failing any
+    /// batch containing one
+    pub partial_failure_rate: f64,
+}
+
+#[cfg(feature = "chaos")]
+impl ChaosConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.embedding_latency_ms > 0
+            || self.embedding_error_rate > 0.0
+            || self.storage_latency_ms > 0
+            || self.storage_error_rate > 0.0
+            || self.partial_failure_rate > 0.0
+    }
+}
+
+/// PEM files for serving over TLS.
+#[derive(Debug, Clone, Default)]
+pub struct TlsConfig {
+    /// Certificate chain the server presents, leaf first
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    /// CA that client certificates must be signed by. When set, clients
+    /// without such a certificate can't connect at all.
+    pub client_ca: Option<PathBuf>,
+    /// Port to also listen on for plain HTTP, answering every request with
+    /// a redirect to HTTPS
+    pub redirect_port: Option<u16>,
+}
+
+impl TlsConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.cert.is_some() || self.key.is_some()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub model: ModelConfig,
+    /// Further models served next to the base one, by the name clients
+    /// and collections refer to them with: a HuggingFace repo or a local
+    /// `.onnx` file each, loaded with the base model's settings.
+    pub models: BTreeMap<String, String>,
+    /// Pooling of the models in `models` that don't pool like the base
+    /// model, by name.
+    pub model_pooling: BTreeMap<String, Pooling>,
+    /// Query instructions of the models in `models`, by name.
+    pub model_query_instructions: BTreeMap<String, String>,
+    /// Document instructions of the models in `models`, by name.
+    pub model_document_instructions: BTreeMap<String, String>,
+    pub hnsw: HnswConfig,
+    pub reranker: RerankerConfig,
+    pub quantization: QuantizationConfig,
+    pub chunking: ChunkingConfig,
+    pub metadata: MetadataConfig,
+    pub markdown: MarkdownConfig,
+    pub analytics: AnalyticsConfig,
+    pub tls: TlsConfig,
+    pub vault: VaultConfig,
+    pub obsidian: ObsidianConfig,
+    pub debug_capture: DebugCaptureConfig,
+    pub rate_limit: RateLimitConfig,
+    pub concurrency: ConcurrencyConfig,
+    #[cfg(feature = "chaos")]
+    pub chaos: ChaosConfig,
+    /// Address the server listens on.
+    pub host: String,
+    pub port: u16,
+    /// Port of the gRPC API on the same host; unset to not serve it.
+    pub grpc_port: Option<u16>,
+    /// Directory for persisted server state.
+    pub data_dir: PathBuf,
+    pub storage: StorageConfig,
+    /// Most texts accepted by a single batch embedding request.
+    pub max_batch_size: usize,
+    /// Bulk uploads run at once; more wait their turn in a queue.
+    pub max_bulk_jobs: usize,
+    /// Bulk uploads one client can run at once.
+    pub max_bulk_jobs_per_client: usize,
+    /// Background jobs run at once; more wait queued.
+    pub max_jobs: usize,
+    /// Background jobs waiting their turn; more are refused.
+    pub max_queued_jobs: usize,
+    /// Default length search result texts are truncated to, in characters.
+    /// `None` returns full texts unless a request asks otherwise.
+    pub max_text_length: Option<usize>,
+    /// Quiet period after a live search query before it is run, so only
+    /// the last of a burst of keystrokes is searched.
+    pub live_search_debounce_ms: u64,
+    /// How long federated search waits for a peer before giving up on it.
+    pub federation_timeout_ms: u64,
+    /// Path all API routes are mounted under, e.g. `/v1`. Empty mounts
+    /// them at the root.
+    pub route_prefix: String,
+    /// Also serve the original unprefixed routes for older clients.
+    pub legacy_routes: bool,
+    /// Read every collection into memory and run the model once right
+    /// after startup, so the first searches don't pay for it.
+    pub warm_on_start: bool,
+    /// Feature flags on everywhere until changed at runtime through
+    /// `PATCH /admin/flags`.
+    pub flags: Vec<Flag>,
+    /// Reject clients that aren't on this machine or don't send one of
+    /// `allowed_origins`, and add security headers to every response.
+    pub hardened: bool,
+    /// Origins allowed by CORS and, in hardened mode, required of every
+    /// request. Empty allows any origin outside hardened mode.
+    pub allowed_origins: Vec<String>,
+    /// Hosts bundles may be mounted from by URL. Empty refuses mounting
+    /// from URLs.
+    pub bundle_hosts: Vec<String>,
+    /// Largest bundle a mount downloads, in megabytes.
+    pub max_bundle_mb: u64,
+    /// Where each setting not left at its default was last given, by its
+    /// key, so a problem with it names the config file line, variable, or
+    /// flag to fix.
+    pub(crate) sources: HashMap<String, String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            model: ModelConfig::default(),
+            models: BTreeMap::new(),
+            model_pooling: BTreeMap::new(),
+            model_query_instructions: BTreeMap::new(),
+            model_document_instructions: BTreeMap::new(),
+            hnsw: HnswConfig::default(),
+            reranker: RerankerConfig::default(),
+            quantization: QuantizationConfig::default(),
+            chunking: ChunkingConfig::default(),
+            metadata: MetadataConfig::default(),
+            markdown: MarkdownConfig::default(),
+            analytics: AnalyticsConfig::default(),
+            tls: TlsConfig::default(),
+            vault: VaultConfig::default(),
+            obsidian: ObsidianConfig::default(),
+            debug_capture: DebugCaptureConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+            concurrency: ConcurrencyConfig::default(),
+            #[cfg(feature = "chaos")]
+            chaos: ChaosConfig::default(),
+            host: "127.0.0.1".to_string(),
+            port: 8765,
+            grpc_port: None,
+            data_dir: PathBuf::from("data"),
+            storage: StorageConfig::default(),
+            max_batch_size: 256,
+            max_bulk_jobs: 2,
+            max_bulk_jobs_per_client: 1,
+            max_jobs: 2,
+            max_queued_jobs: 100,
+            max_text_length: None,
+            live_search_debounce_ms: 150,
+            federation_timeout_ms: 2000,
+            route_prefix: "/v1".to_string(),
+            legacy_routes: true,
+            warm_on_start: true,
+            flags: Vec::new(),
+            hardened: false,
+            allowed_origins: Vec::new(),
+            bundle_hosts: Vec::new(),
+            max_bundle_mb: 4096,
+            sources: HashMap::new(),
+        }
+    }
+}
+
+impl Config {
+    /// Build the configuration from defaults, overridden in turn by the
+    /// config file (if one is given), `SYSTEMATICS_*` environment variables,
+    /// and command-line flags.
+    pub fn load(cli: &Cli) -> Result<Self> {
+        let mut config = Self::default();
+        // Every bad setting is reported at once, so they can all be fixed
+        // before the next try
+        let mut problems = Problems::default();
+
+        if let Some(path) = &cli.config {
+            let settings = fs::read_to_string(path)
+                .with_context(|| format!("Failed to read config file {:?}", path))
+                .and_then(|contents| parse_config_file(&contents));
+            match settings {
+                Ok(settings) => {
+                    for (key, value) in settings {
+                        let setting = format!("{} in {:?}", key.to_lowercase(), path);
+                        match config.set(&key, &value) {
+                            Ok(true) => config.given(&key, setting),
+                            Ok(false) => problems.add(
+                                &setting,
+                                "Unknown setting",
+                                "Check its spelling against the settings in the README",
+                            ),
+                            Err(e) => problems.add(
+                                &setting,
+                                format!("Invalid value {:?}: {:#}", value, e),
+                                "Correct the value",
+                            ),
+                        }
+                    }
+                }
+                Err(e) => problems.add(
+                    "--config",
+                    format!("Invalid config file {:?}: {:#}", path, e),
+                    "Fix the file's syntax; it takes tables, strings, numbers, and booleans",
+                ),
+            }
+        }
+
+        // `env::vars` panics on a variable that isn't UTF-8, and any
+        // process can have one; only ours are worth reporting
+        for (name, value) in env::vars_os() {
+            let Some(name) = name.to_str() else {
+                continue;
+            };
+            if let Some(key) = name.strip_prefix(ENV_PREFIX) {
+                let Some(value) = value.to_str() else {
+                    problems.add(
+                        name,
+                        format!("Invalid value {:?}: not valid UTF-8", value),
+                        "Correct the value, or unset the variable for the default",
+                    );
+                    continue;
+                };
+                match config.set(key, value) {
+                    Ok(true) => config.given(key, name),
+                    Ok(false) => {}
+                    Err(e) => problems.add(
+                        name,
+                        format!("Invalid value {:?}: {:#}", value, e),
+                        "Correct the value, or unset the variable for the default",
+                    ),
+                }
+            }
+        }
+
+        cli.apply(&mut config);
+        config.validate(&mut problems);
+        problems.into_result()?;
+        Ok(config)
+    }
+
+    /// How to name the setting `key` (its environment variable without the
+    /// `SYSTEMATICS_` prefix) in a problem with it: where it was given, or
+    /// its environment variable if it wasn't.
+    pub fn setting(&self, key: &str) -> String {
+        self.sources
+            .get(key)
+            .cloned()
+            .unwrap_or_else(|| format!("{}{}", ENV_PREFIX, key))
+    }
+
+    /// Record that the setting `key` was last given by `source`.
+    fn given(&mut self, key: &str, source: impl Into<String>) {
+        self.sources.insert(key.to_string(), source.into());
+    }
+
+    /// Apply one setting, named as its environment variable without the
+    /// `SYSTEMATICS_` prefix. Returns false if there is no such setting.
+    fn set(&mut self, key: &str, value: &str) -> Result<bool> {
+        match key {
+            "MODEL_NAME" => self.model.name = value.to_string(),
+            "MODEL_PATH" => self.model.path = Some(PathBuf::from(value)),
+            "MODELS" => {
+                self.models = value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|entry| !entry.is_empty())
+                    .map(|entry| match entry.split_once('=') {
+                        Some((name, model))
+                            if !name.trim().is_empty() && !model.trim().is_empty() =>
+                        {
+                            Ok((name.trim().to_string(), model.trim().to_string()))
+                        }
+                        _ => anyhow::bail!("Expected name=model, got {:?}", entry),
+                    })
+                    .collect::<Result<_>>()?
+            }
+            "MODELS_POOLING" => {
+                self.model_pooling = value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|entry| !entry.is_empty())
+                    .map(|entry| match entry.split_once('=') {
+                        Some((name, pooling)) if !name.trim().is_empty() => {
+                            Ok((name.trim().to_string(), pooling.trim().parse()?))
+                        }
+                        _ => anyhow::bail!("Expected name=pooling, got {:?}", entry),
+                    })
+                    .collect::<Result<_>>()?
+            }
+            "TOKENIZER_PATH" => self.model.tokenizer_path = Some(PathBuf::from(value)),
+            "POOLING" => self.model.pooling = value.parse()?,
+            "THREADS" => self.model.threads = value.parse()?,
+            "EXECUTION_PROVIDERS" => {
+                self.model.execution_providers = value
+                    .split(',')
+                    .filter(|provider| !provider.trim().is_empty())
+                    .map(str::parse)
+                    .collect::<Result<_>>()?
+            }
+            "CACHE_DIR" => self.model.cache_dir = PathBuf::from(value),
+            "TRUSTED_KEYS" => {
+                self.model.trusted_keys = value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|key| !key.is_empty())
+                    .map(String::from)
+                    .collect()
+            }
+            "EMBEDDING_CACHE_SIZE" => self.model.cache_entries = value.parse()?,
+            "EMBEDDING_CACHE_PERSIST" => self.model.persist_cache = value.parse()?,
+            "EMBEDDING_CACHE_MAX_MB" => self.model.cache_max_mb = value.parse()?,
+            "ADD_SPECIAL_TOKENS" => self.model.add_special_tokens = value.parse()?,
+            "MAX_REQUEST_TOKENS" => self.model.max_request_tokens = value.parse()?,
+            "MAX_LENGTH" => self.model.max_length = value.parse()?,
+            "STRICT_TRUNCATION" => self.model.strict_truncation = value.parse()?,
+            "PRECISION" => self.model.precision = value.parse()?,
+            "INFERENCE_PRECISION" => self.model.inference_precision = value.parse()?,
+            "QUERY_INSTRUCTION" => self.model.query_instruction = value.to_string(),
+            "DOCUMENT_INSTRUCTION" => self.model.document_instruction = value.to_string(),
+            "MODEL_QUERY_INSTRUCTIONS" => self.model_query_instructions = instructions(value)?,
+            "MODEL_DOCUMENT_INSTRUCTIONS" => {
+                self.model_document_instructions = instructions(value)?
+            }
+            "BATCH_WINDOW_MS" => self.model.batch_window_ms = value.parse()?,
+            "BATCH_MAX_TEXTS" => self.model.batch_max_texts = value.parse()?,
+            "HNSW_M" => self.hnsw.m = value.parse()?,
+            "HNSW_EF_CONSTRUCTION" => self.hnsw.ef_construction = value.parse()?,
+            "HNSW_EF_SEARCH" => self.hnsw.ef_search = value.parse()?,
+            "HNSW_TARGET_LATENCY_MS" => {
+                self.hnsw.target_latency_ms = Some(value.parse()?).filter(|&ms: &f64| ms > 0.0)
+            }
+            "HNSW_TUNE_QUERIES" => self.hnsw.tune_queries = value.parse()?,
+            "HNSW_PIN_ENTRY_LAYERS" => self.hnsw.pin_entry_layers = value.parse()?,
+            "RERANK_MODEL" => self.reranker.model = Some(value.to_string()),
+            "RERANK_MODEL_PATH" => self.reranker.path = Some(PathBuf::from(value)),
+            "RERANK_TOKENIZER_PATH" => self.reranker.tokenizer_path = Some(PathBuf::from(value)),
+            "RERANK_CANDIDATES" => self.reranker.candidates = value.parse()?,
+            "QUANTIZATION" => self.quantization.mode = value.parse()?,
+            "PQ_SUBVECTORS" => self.quantization.pq_subvectors = value.parse()?,
+            "PQ_TRAIN_SIZE" => self.quantization.pq_train_size = value.parse()?,
+            "CHUNK_SIZE" => self.chunking.size = value.parse()?,
+            "CHUNK_OVERLAP" => self.chunking.overlap = value.parse()?,
+            "METADATA_LOWERCASE_KEYS" => self.metadata.lowercase_keys = value.parse()?,
+            "METADATA_SANITIZE_KEYS" => self.metadata.sanitize_keys = value.parse()?,
+            "METADATA_KEY_ALIASES" => {
+                self.metadata.key_aliases.clear();
+                for pair in value.split(',').filter(|pair| !pair.trim().is_empty()) {
+                    let Some((from, to)) = pair.split_once('=') else {
+                        anyhow::bail!("Key aliases must look like from=to, got {:?}", pair);
+                    };
+                    self.metadata
+                        .key_aliases
+                        .insert(from.trim().to_string(), to.trim().to_string());
+                }
+            }
+            "TLS_CERT" => self.tls.cert = Some(PathBuf::from(value)),
+            "TLS_KEY" => self.tls.key = Some(PathBuf::from(value)),
+            "TLS_CLIENT_CA" => self.tls.client_ca = Some(PathBuf::from(value)),
+            "TLS_REDIRECT_PORT" => self.tls.redirect_port = Some(value.parse()?),
+            "MARKDOWN_STRIP" => self.markdown.enabled = value.parse()?,
+            "MARKDOWN_SPLIT_HEADINGS" => self.markdown.split_headings = value.parse()?,
+            "MARKDOWN_KEEP_CODE" => self.markdown.keep_code = value.parse()?,
+            "ANALYTICS_MAX_MB" => self.analytics.max_mb = value.parse()?,
+            "ANALYTICS_QUERY_TEXT" => self.analytics.query_text = value.parse()?,
+            "VAULT_PATH" => self.vault.path = Some(PathBuf::from(value)),
+            "VAULT_COLLECTION" => self.vault.collection = value.to_string(),
+            "VAULT_DEBOUNCE_MS" => self.vault.debounce_ms = value.parse()?,
+            "OBSIDIAN_VAULT" => {
+                self.obsidian.vault = Some(value.trim().to_string()).filter(|v| !v.is_empty())
+            }
+            "OBSIDIAN_PATH_FIELD" => self.obsidian.path_field = value.to_string(),
+            "DEBUG_CAPTURE" => self.debug_capture.enabled = value.parse()?,
+            "DEBUG_CAPTURE_SAMPLE_RATE" => self.debug_capture.sample_rate = value.parse()?,
+            "DEBUG_CAPTURE_SIZE" => self.debug_capture.capacity = value.parse()?,
+            "DEBUG_CAPTURE_MAX_BODY_BYTES" => self.debug_capture.max_body_bytes = value.parse()?,
+            "DEBUG_REDACT_FIELDS" => {
+                self.debug_capture.redact_fields = value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|field| !field.is_empty())
+                    .map(String::from)
+                    .collect()
+            }
+            "RATE_LIMIT_PER_SECOND" => self.rate_limit.per_second = value.parse()?,
+            "RATE_LIMIT_BURST" => self.rate_limit.burst = value.parse()?,
+            "CONCURRENCY_EMBED" => self.concurrency.embed = value.parse()?,
+            "CONCURRENCY_INDEX" => self.concurrency.index = value.parse()?,
+            "CONCURRENCY_SEARCH" => self.concurrency.search = value.parse()?,
+            "CONCURRENCY_ANALYSIS" => self.concurrency.analysis = value.parse()?,
+            "CONCURRENCY_RETRY_AFTER_SECS" => self.concurrency.retry_after_secs = value.parse()?,
+            #[cfg(feature = "chaos")]
+            "CHAOS_SEED" => self.chaos.seed = value.parse()?,
+            #[cfg(feature = "chaos")]
+            "CHAOS_EMBEDDING_LATENCY_MS" => self.chaos.embedding_latency_ms = value.parse()?,
+            #[cfg(feature = "chaos")]
+            "CHAOS_EMBEDDING_ERROR_RATE" => self.chaos.embedding_error_rate = value.parse()?,
+            #[cfg(feature = "chaos")]
+            "CHAOS_STORAGE_LATENCY_MS" => self.chaos.storage_latency_ms = value.parse()?,
+            #[cfg(feature = "chaos")]
+            "CHAOS_STORAGE_ERROR_RATE" => self.chaos.storage_error_rate = value.parse()?,
+            #[cfg(feature = "chaos")]
+            "CHAOS_PARTIAL_FAILURE_RATE" => self.chaos.partial_failure_rate = value.parse()?,
+            "HOST" => self.host = value.to_string(),
+            "PORT" => self.port = value.parse()?,
+            "GRPC_PORT" => self.grpc_port = Some(value.parse()?),
+            "DATA_DIR" => self.data_dir = PathBuf::from(value),
+            "STORAGE_BACKEND" => self.storage.backend = value.parse()?,
+            "SNAPSHOT_EVERY" => self.storage.snapshot_every = value.parse()?,
+            "STORAGE_FSYNC" => self.storage.fsync = value.parse()?,
+            "STORAGE_FSYNC_INTERVAL_MS" => self.storage.fsync_interval_ms = value.parse()?,
+            "MAX_BATCH_SIZE" => self.max_batch_size = value.parse()?,
+            "MAX_BULK_JOBS" => self.max_bulk_jobs = value.parse()?,
+            "MAX_BULK_JOBS_PER_CLIENT" => self.max_bulk_jobs_per_client = value.parse()?,
+            "MAX_JOBS" => self.max_jobs = value.parse()?,
+            "MAX_QUEUED_JOBS" => self.max_queued_jobs = value.parse()?,
+            "MAX_TEXT_LENGTH" => {
+                self.max_text_length = Some(value.parse()?).filter(|&max: &usize| max > 0)
+            }
+            "LIVE_SEARCH_DEBOUNCE_MS" => self.live_search_debounce_ms = value.parse()?,
+            "FEDERATION_TIMEOUT_MS" => self.federation_timeout_ms = value.parse()?,
+            "ROUTE_PREFIX" => self.route_prefix = value.trim_end_matches('/').to_string(),
+            "LEGACY_ROUTES" => self.legacy_routes = value.parse()?,
+            "WARM_ON_START" => self.warm_on_start = value.parse()?,
+            "FLAGS" => {
+                self.flags = value
+                    .split(',')
+                    .filter(|flag| !flag.trim().is_empty())
+                    .map(str::parse)
+                    .collect::<Result<_>>()?
+            }
+            "HARDENED" => self.hardened = value.parse()?,
+            "BUNDLE_HOSTS" => {
+                self.bundle_hosts = value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|host| !host.is_empty())
+                    .map(str::to_ascii_lowercase)
+                    .collect()
+            }
+            "MAX_BUNDLE_MB" => self.max_bundle_mb = value.parse()?,
+            "ALLOWED_ORIGINS" => {
+                self.allowed_origins = value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|origin| !origin.is_empty())
+                    .map(String::from)
+                    .collect()
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Check settings that are only invalid in combination or once every
+    /// source has been applied, recording every problem.
+    fn validate(&mut self, problems: &mut Problems) {
+        if self.quantization.pq_subvectors == 0 {
+            problems.add(
+                &self.setting("PQ_SUBVECTORS"),
+                "PQ subvectors must be at least 1",
+                "Use a divisor of the model's dimensions, such as 48 for 384",
+            );
+        }
+        if self.quantization.pq_train_size < PQ_CENTROIDS {
+            problems.add(
+                &self.setting("PQ_TRAIN_SIZE"),
+                format!(
+                    "PQ train size must be at least {}, got {}",
+                    PQ_CENTROIDS, self.quantization.pq_train_size
+                ),
+                format!("Raise it to {} or more", PQ_CENTROIDS),
+            );
+        }
+        if self.hnsw.m < 2 {
+            problems.add(
+                &self.setting("HNSW_M"),
+                format!("HNSW m must be at least 2, got {}", self.hnsw.m),
+                "Raise it to 2 or more, or unset it for the default of 16",
+            );
+        }
+        if self
+            .hnsw
+            .target_latency_ms
+            .is_some_and(|ms| !ms.is_finite())
+        {
+            problems.add(
+                &self.setting("HNSW_TARGET_LATENCY_MS"),
+                "HNSW target latency must be a number of milliseconds",
+                "Set it to a number such as 10",
+            );
+        }
+        if self.hnsw.target_latency_ms.is_some() && self.hnsw.tune_queries == 0 {
+            problems.add(
+                &self.setting("HNSW_TUNE_QUERIES"),
+                "HNSW tuning needs at least 1 query",
+                "Raise it, or unset SYSTEMATICS_HNSW_TARGET_LATENCY_MS to stop tuning",
+            );
+        }
+        // Chunking caps the overlap at half a window anyway, so a larger
+        // one would silently not be what was asked for
+        if self.chunking.size > 0 && self.chunking.overlap > self.chunking.size / 2 {
+            problems.add(
+                &self.setting("CHUNK_OVERLAP"),
+                format!(
+                    "Chunk overlap ({}) must be at most half the chunk size ({})",
+                    self.chunking.overlap, self.chunking.size
+                ),
+                "Lower the overlap, or raise SYSTEMATICS_CHUNK_SIZE",
+            );
+        }
+        if self.models.contains_key(&self.model.name) {
+            problems.add(
+                &self.setting("MODELS"),
+                format!(
+                    "Model name {:?} is already the base model's",
+                    self.model.name
+                ),
+                "Serve the extra model under another name",
+            );
+        }
+        if let Some(name) = self
+            .model_pooling
+            .keys()
+            .find(|name| !self.models.contains_key(*name))
+        {
+            problems.add(
+                &self.setting("MODELS_POOLING"),
+                format!(
+                    "Pooling is set for {:?}, which isn't in SYSTEMATICS_MODELS",
+                    name
+                ),
+                "Add the model to SYSTEMATICS_MODELS, or set the base model's pooling \
+                 with SYSTEMATICS_POOLING",
+            );
+        }
+        for (setting, instruction) in [
+            (
+                self.setting("QUERY_INSTRUCTION"),
+                &self.model.query_instruction,
+            ),
+            (
+                self.setting("DOCUMENT_INSTRUCTION"),
+                &self.model.document_instruction,
+            ),
+        ] {
+            if let Err(e) = embedding::check_instruction(instruction) {
+                problems.add(
+                    &setting,
+                    e,
+                    format!(
+                        "Mark where the text goes, e.g. {:?}",
+                        format!("query: {}", embedding::TEXT_PLACEHOLDER)
+                    ),
+                );
+            }
+        }
+        for (setting, instructions) in [
+            (
+                self.setting("MODEL_QUERY_INSTRUCTIONS"),
+                &self.model_query_instructions,
+            ),
+            (
+                self.setting("MODEL_DOCUMENT_INSTRUCTIONS"),
+                &self.model_document_instructions,
+            ),
+        ] {
+            for (name, instruction) in instructions {
+                if !self.models.contains_key(name) {
+                    problems.add(
+                        &setting,
+                        format!(
+                            "An instruction is set for {:?}, which isn't in SYSTEMATICS_MODELS",
+                            name
+                        ),
+                        "Add the model to SYSTEMATICS_MODELS, or set the base model's with \
+                         SYSTEMATICS_QUERY_INSTRUCTION and SYSTEMATICS_DOCUMENT_INSTRUCTION",
+                    );
+                } else if let Err(e) = embedding::check_instruction(instruction) {
+                    problems.add(
+                        &setting,
+                        format!("{}: {}", name, e),
+                        format!(
+                            "Mark where the text goes, e.g. {{\"{}\": \"query: {}\"}}",
+                            name,
+                            embedding::TEXT_PLACEHOLDER
+                        ),
+                    );
+                }
+            }
+        }
+        if self.grpc_port == Some(self.port) {
+            problems.add(
+                &self.setting("GRPC_PORT"),
+                format!("The gRPC port must differ from the HTTP port {}", self.port),
+                "Pick another port for gRPC",
+            );
+        }
+        if let Err(e) = TrustRoot::new(&self.model.trusted_keys) {
+            problems.add(
+                &self.setting("TRUSTED_KEYS"),
+                format!("{:#}", e),
+                "Use the public keys minisign printed, the base64 line of each .pub file",
+            );
+        }
+        if self.model.threads == 0 {
+            problems.add(
+                &self.setting("THREADS"),
+                "Thread count must be at least 1",
+                "Set it to the number of cores to give the model, e.g. 4",
+            );
+        }
+        if self.storage.fsync == FsyncPolicy::Interval && self.storage.fsync_interval_ms == 0 {
+            problems.add(
+                &self.setting("STORAGE_FSYNC_INTERVAL_MS"),
+                "Fsync interval must be at least 1 ms",
+                "Raise it, or set SYSTEMATICS_STORAGE_FSYNC=always to sync every change",
+            );
+        }
+        if self.max_bulk_jobs == 0 || self.max_bulk_jobs_per_client == 0 {
+            problems.add(
+                &self.setting("MAX_BULK_JOBS"),
+                "Bulk job limits must be at least 1",
+                "Raise SYSTEMATICS_MAX_BULK_JOBS and SYSTEMATICS_MAX_BULK_JOBS_PER_CLIENT \
+                 to 1 or more",
+            );
+        }
+        if self.max_jobs == 0 {
+            problems.add(
+                &self.setting("MAX_JOBS"),
+                "Max jobs must be at least 1",
+                "Raise it to 1 or more",
+            );
+        }
+        if self.model.batch_max_texts == 0 {
+            problems.add(
+                &self.setting("BATCH_MAX_TEXTS"),
+                "Batch max texts must be at least 1",
+                "Raise it to 1 or more",
+            );
+        }
+        if !self.route_prefix.is_empty() && !self.route_prefix.starts_with('/') {
+            problems.add(
+                &self.setting("ROUTE_PREFIX"),
+                format!("Route prefix must start with '/': {:?}", self.route_prefix),
+                format!("Use {:?}", format!("/{}", self.route_prefix)),
+            );
+        }
+        if !(0.0..=1.0).contains(&self.debug_capture.sample_rate) {
+            problems.add(
+                &self.setting("DEBUG_CAPTURE_SAMPLE_RATE"),
+                format!(
+                    "Debug capture sample rate must be between 0 and 1, got {}",
+                    self.debug_capture.sample_rate
+                ),
+                "Use a fraction, e.g. 0.1 to capture one request in ten",
+            );
+        }
+        if !(self.rate_limit.per_second >= 0.0 && self.rate_limit.per_second.is_finite()) {
+            problems.add(
+                &self.setting("RATE_LIMIT_PER_SECOND"),
+                format!(
+                    "Rate limit must be a number of requests per second, got {}",
+                    self.rate_limit.per_second
+                ),
+                "Use a number such as 20, or 0 for no limit",
+            );
+        }
+        if self.rate_limit.is_enabled() && self.rate_limit.burst == 0 {
+            problems.add(
+                &self.setting("RATE_LIMIT_BURST"),
+                "Rate limit burst must be at least 1",
+                "Raise it to 1 or more",
+            );
+        }
+        #[cfg(feature = "chaos")]
+        for (name, rate) in [
+            ("embedding error", self.chaos.embedding_error_rate),
+            ("storage error", self.chaos.storage_error_rate),
+            ("partial failure", self.chaos.partial_failure_rate),
+        ] {
+            if !(0.0..=1.0).contains(&rate) {
+                problems.add_general(
+                    format!("Chaos {} rate must be between 0 and 1, got {}", name, rate),
+                    "Use a fraction, e.g. 0.05",
+                );
+            }
+        }
+        if self.tls.is_enabled() && (self.tls.cert.is_none() || self.tls.key.is_none()) {
+            problems.add(
+                &self.setting("TLS_CERT"),
+                "TLS needs both a certificate and a key",
+                "Set both SYSTEMATICS_TLS_CERT and SYSTEMATICS_TLS_KEY, or neither",
+            );
+        }
+        if self.tls.client_ca.is_some() && !self.tls.is_enabled() {
+            problems.add(
+                &self.setting("TLS_CLIENT_CA"),
+                "Client certificate authentication needs a TLS certificate and key",
+                "Set SYSTEMATICS_TLS_CERT and SYSTEMATICS_TLS_KEY too",
+            );
+        }
+        if let Some(port) = self.tls.redirect_port {
+            if !self.tls.is_enabled() {
+                problems.add(
+                    &self.setting("TLS_REDIRECT_PORT"),
+                    "Redirecting to HTTPS needs a TLS certificate and key",
+                    "Set SYSTEMATICS_TLS_CERT and SYSTEMATICS_TLS_KEY too",
+                );
+            }
+            if port == self.port || self.grpc_port == Some(port) {
+                problems.add(
+                    &self.setting("TLS_REDIRECT_PORT"),
+                    format!(
+                        "The HTTPS redirect port {} must differ from the HTTP and gRPC ports",
+                        port
+                    ),
+                    "Pick another port for plain HTTP",
+                );
+            }
+        }
+        if self.hardened && self.allowed_origins.is_empty() {
+            self.allowed_origins = vec![OBSIDIAN_ORIGIN.to_string()];
+        }
+        for origin in &self.allowed_origins {
+            if HeaderValue::from_str(origin).is_err() {
+                problems.add(
+                    &self.setting("ALLOWED_ORIGINS"),
+                    format!("Invalid allowed origin {:?}", origin),
+                    "List origins such as app://obsidian.md, separated by commas",
+                );
+            }
+        }
+
+        // Aliases match keys after lowercasing and sanitizing
+        let aliases = std::mem::take(&mut self.metadata.key_aliases);
+        self.metadata.key_aliases = aliases
+            .into_iter()
+            .map(|(from, to)| (metadata::normalize_key(&self.metadata, &from, false), to))
+            .collect();
+    }
+}
+
+const ENV_PREFIX: &str = "SYSTEMATICS_";
+
+/// Origin the Obsidian desktop app sends with its requests.
+const OBSIDIAN_ORIGIN: &str = "app://obsidian.md";
+
+/// Local embedding and semantic search server for Obsidian vaults.
+#[derive(Parser, Debug, Default)]
+#[command(version, about)]
+pub struct Cli {
+    /// TOML config file. Environment variables override its settings, and
+    /// flags override both.
+    #[arg(long, short)]
+    pub config: Option<PathBuf>,
+    /// Model to load, as a HuggingFace repo
+    #[arg(long)]
+    pub model: Option<String>,
+    /// Local ONNX model file, instead of downloading `--model`
+    #[arg(long)]
+    pub model_path: Option<PathBuf>,
+    /// Local tokenizer.json to use with `--model-path`
+    #[arg(long)]
+    pub tokenizer_path: Option<PathBuf>,
+    /// How token embeddings are pooled into one vector
+    #[arg(long)]
+    pub pooling: Option<Pooling>,
+    /// Threads ONNX Runtime uses per inference
+    #[arg(long)]
+    pub threads: Option<usize>,
+    /// Execution providers to try in order, e.g. `cuda,cpu`
+    #[arg(long, value_delimiter = ',')]
+    pub execution_providers: Option<Vec<ExecutionProvider>>,
+    /// Address to listen on
+    #[arg(long)]
+    pub host: Option<String>,
+    /// Port to listen on
+    #[arg(long, short)]
+    pub port: Option<u16>,
+    /// Directory for persisted server state
+    #[arg(long)]
+    pub data_dir: Option<PathBuf>,
+    /// Obsidian vault to index and keep in sync
+    #[arg(long)]
+    pub vault: Option<PathBuf>,
+    /// PEM certificate chain to serve HTTPS with
+    #[arg(long)]
+    pub tls_cert: Option<PathBuf>,
+    /// PEM private key for `--tls-cert`
+    #[arg(long)]
+    pub tls_key: Option<PathBuf>,
+    /// PEM CA certificates; only clients with a certificate signed by one
+    /// of them can connect
+    #[arg(long)]
+    pub tls_client_ca: Option<PathBuf>,
+    /// Also listen on this port for plain HTTP, redirecting to HTTPS
+    #[arg(long)]
+    pub tls_redirect_port: Option<u16>,
+    /// Only accept local requests from allowed origins, and send security
+    /// headers
+    #[arg(long)]
+    pub hardened: bool,
+    /// List pending data directory migrations and exit without running them
+    #[arg(long)]
+    pub check_migrations: bool,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Tasks run instead of the server.
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Restore a backup, with the incremental backups before it, into a
+    /// new data directory. Run it while the server is stopped.
+    Restore {
+        /// Backup to restore, as listed by `GET /admin/backups`
+        id: String,
+        /// Empty or new data directory to restore into
+        #[arg(long)]
+        to: PathBuf,
+        /// Directory holding the backups, by default `backups/` in the
+        /// configured data directory
+        #[arg(long)]
+        from: Option<PathBuf>,
+    },
+    /// Pack a collection into a read-only bundle another server can mount
+    /// by copying it into `bundles/` in its data directory.
+    Pack {
+        /// Collection to pack, by default the default collection
+        #[arg(long)]
+        collection: Option<String>,
+        /// Bundle file to write; its name without the extension is the
+        /// collection name it is mounted under
+        #[arg(long)]
+        out: PathBuf,
+    },
+}
+
+impl Cli {
+    fn apply(&self, config: &mut Config) {
+        if let Some(model) = &self.model {
+            config.model.name = model.clone();
+            config.given("MODEL_NAME", "--model");
+        }
+        if let Some(path) = &self.model_path {
+            config.model.path = Some(path.clone());
+            config.given("MODEL_PATH", "--model-path");
+        }
+        if let Some(path) = &self.tokenizer_path {
+            config.model.tokenizer_path = Some(path.clone());
+            config.given("TOKENIZER_PATH", "--tokenizer-path");
+        }
+        if let Some(pooling) = self.pooling {
+            config.model.pooling = pooling;
+            config.given("POOLING", "--pooling");
+        }
+        if let Some(threads) = self.threads {
+            config.model.threads = threads;
+            config.given("THREADS", "--threads");
+        }
+        if let Some(providers) = &self.execution_providers {
+            config.model.execution_providers = providers.clone();
+            config.given("EXECUTION_PROVIDERS", "--execution-providers");
+        }
+        if let Some(host) = &self.host {
+            config.host = host.clone();
+            config.given("HOST", "--host");
+        }
+        if let Some(port) = self.port {
+            config.port = port;
+            config.given("PORT", "--port");
+        }
+        if let Some(dir) = &self.data_dir {
+            config.data_dir = dir.clone();
+            config.given("DATA_DIR", "--data-dir");
+        }
+        if let Some(path) = &self.vault {
+            config.vault.path = Some(path.clone());
+            config.given("VAULT_PATH", "--vault");
+        }
+        if let Some(path) = &self.tls_cert {
+            config.tls.cert = Some(path.clone());
+            config.given("TLS_CERT", "--tls-cert");
+        }
+        if let Some(path) = &self.tls_key {
+            config.tls.key = Some(path.clone());
+            config.given("TLS_KEY", "--tls-key");
+        }
+        if let Some(path) = &self.tls_client_ca {
+            config.tls.client_ca = Some(path.clone());
+            config.given("TLS_CLIENT_CA", "--tls-client-ca");
+        }
+        if let Some(port) = self.tls_redirect_port {
+            config.tls.redirect_port = Some(port);
+            config.given("TLS_REDIRECT_PORT", "--tls-redirect-port");
+        }
+        if self.hardened {
+            config.hardened = true;
+            config.given("HARDENED", "--hardened");
+        }
+    }
+}
+
+/// Instructions by model, from a JSON object of instructions by model
+/// name, so they can hold any text; an empty value sets none.
+fn instructions(value: &str) -> Result<BTreeMap<String, String>> {
+    if value.trim().is_empty() {
+        return Ok(BTreeMap::new());
+    }
+    serde_json::from_str(value).with_context(|| {
+        format!(
+            "Expected a JSON object of instructions by model name, e.g. \
+             {{\"e5\": \"query: {{{{text}}}}\"}}, got {:?}",
+            value
+        )
+    })
+}
+
+/// Read `key = value` settings from a config file. Keys are the
+/// environment variable names without the `SYSTEMATICS_` prefix, in any
+/// case, and keys under a `[table]` are prefixed with the table name, so
+/// `[hnsw]` followed by `m = 32` sets `HNSW_M`.
+///
+/// Only the part of TOML these settings need is supported: tables,
+/// strings, integers, floats, booleans, and comments.
+fn parse_config_file(contents: &str) -> Result<Vec<(String, String)>> {
+    let mut settings = Vec::new();
+    let mut table = String::new();
+
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        if let Some(rest) = line.strip_prefix('[') {
+            let Some((name, after)) = rest.split_once(']') else {
+                anyhow::bail!("line {}: unterminated table header", number + 1);
+            };
+            if !after.trim().is_empty() && !after.trim().starts_with('#') {
+                anyhow::bail!("line {}: unexpected text after table header", number + 1);
+            }
+            table = name.trim().to_string();
+            continue;
+        }
+
+        let Some((key, value)) = line.split_once('=') else {
+            anyhow::bail!("line {}: expected `key = value`", number + 1);
+        };
+        let value = parse_toml_value(value.trim())
+            .with_context(|| format!("line {}: invalid value for {}", number + 1, key.trim()))?;
+        let key = match table.as_str() {
+            "" => key.trim().to_string(),
+            table => format!("{}_{}", table, key.trim()),
+        };
+        settings.push((key.to_uppercase().replace(['-', '.'], "_"), value));
+    }
+
+    Ok(settings)
+}
+
+/// A scalar TOML value, possibly followed by a comment, as a plain string.
+fn parse_toml_value(value: &str) -> Result<String> {
+    let (parsed, rest) = if let Some(rest) = value.strip_prefix('"') {
+        let mut parsed = String::new();
+        let mut chars = rest.char_indices();
+        let end = loop {
+            match chars.next() {
+                Some((i, '"')) => break i + 1,
+                Some((_, '\\')) => match chars.next() {
+                    Some((_, 'n')) => parsed.push('\n'),
+                    Some((_, 't')) => parsed.push('\t'),
+                    Some((_, c @ ('"' | '\\'))) => parsed.push(c),
+                    _ => anyhow::bail!("unsupported escape sequence"),
+                },
+                Some((_, c)) => parsed.push(c),
+                None => anyhow::bail!("unterminated string"),
+            }
+        };
+        (parsed, &rest[end..])
+    } else if let Some(rest) = value.strip_prefix('\'') {
+        let Some((literal, rest)) = rest.split_once('\'') else {
+            anyhow::bail!("unterminated string");
+        };
+        (literal.to_string(), rest)
+    } else {
+        // Anything after a bare value's `#` is a comment
+        let bare = value.split('#').next().unwrap_or_default().trim();
+        let number = bare.replace('_', "");
+        if !(bare == "true" || bare == "false" || number.parse::<f64>().is_ok()) {
+            anyhow::bail!("expected a string, number, or boolean, got {:?}", bare);
+        }
+        (number, "")
+    };
+
+    let rest = rest.trim();
+    if !rest.is_empty() && !rest.starts_with('#') {
+        anyhow::bail!("unexpected text after value: {:?}", rest);
+    }
+    Ok(parsed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_file() {
+        let settings = parse_config_file(
+            r#"
+            # Run a multilingual model on another port
+            port = 9000
+            data_dir = 'C:\vaults\data'
+
+            [model]
+            name = "paraphrase-multilingual-MiniLM-L12-v2"  # HuggingFace repo
+            [hnsw]
+            ef_search = 1_000
+            "#,
+        )
+        .unwrap();
+
+        let mut config = Config::default();
+        for (key, value) in &settings {
+            assert!(config.set(key, value).unwrap(), "unknown key {}", key);
+        }
+        assert_eq!(config.port, 9000);
+        assert_eq!(config.data_dir, PathBuf::from("C:\\vaults\\data"));
+        assert_eq!(config.model.name, "paraphrase-multilingual-MiniLM-L12-v2");
+        assert_eq!(config.hnsw.ef_search, 1000);
+
+        // `[model] pooling` would read as MODEL_POOLING, so the pooling of
+        // the extra models lives under MODELS
+        assert!(!config.set("MODEL_POOLING", "bge=cls").unwrap());
+        assert!(config.set("MODELS_POOLING", "bge=cls").unwrap());
+        assert_eq!(config.model_pooling["bge"], Pooling::Cls);
+
+        assert!(parse_config_file("port = ").is_err());
+        assert!(parse_config_file("name = \"unterminated").is_err());
+        assert!(parse_config_file("tags = [1, 2]").is_err());
+    }
+
+    #[test]
+    fn test_validate_reports_every_problem() {
+        let mut config = Config::default();
+        config.hnsw.m = 1;
+        config.chunking.overlap = config.chunking.size / 2 + 1;
+        config.grpc_port = Some(config.port);
+        config.model.threads = 0;
+
+        let mut problems = Problems::default();
+        config.validate(&mut problems);
+        let settings: Vec<_> = problems
+            .problems()
+            .iter()
+            .map(|problem| problem.setting.as_deref().unwrap())
+            .collect();
+        assert_eq!(
+            settings,
+            [
+                "SYSTEMATICS_HNSW_M",
+                "SYSTEMATICS_CHUNK_OVERLAP",
+                "SYSTEMATICS_GRPC_PORT",
+                "SYSTEMATICS_THREADS"
+            ]
+        );
+        assert!(problems.into_result().is_err());
+    }
+
+    #[test]
+    fn test_problems_name_where_the_setting_was_given() {
+        let path = env::temp_dir().join(format!("systematics-sources-{}.toml", std::process::id()));
+        fs::write(&path, "[hnsw]\nm = 1\n").unwrap();
+        let cli = Cli {
+            config: Some(path.clone()),
+            threads: Some(0),
+            ..Cli::default()
+        };
+
+        let message = Config::load(&cli).unwrap_err().to_string();
+        fs::remove_file(&path).unwrap();
+        assert!(message.contains(&format!("hnsw_m in {:?}: HNSW m must be at least 2", path)));
+        assert!(message.contains("--threads: Thread count must be at least 1"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_non_utf8_variable_is_reported_not_panicked_on() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        env::set_var("SYSTEMATICS_WARM_ON_START", OsStr::from_bytes(b"tr\xffue"));
+        let err = Config::load(&Cli::default()).unwrap_err();
+        env::remove_var("SYSTEMATICS_WARM_ON_START");
+        let message = err.to_string();
+        assert!(message.contains("SYSTEMATICS_WARM_ON_START: Invalid value"));
+        assert!(message.contains("not valid UTF-8"));
+    }
+
+    #[test]
+    fn test_model_instructions() {
+        let mut config = Config::default();
+        config.set("MODELS", "e5=intfloat/e5-small-v2").unwrap();
+        config
+            .set(
+                "MODEL_QUERY_INSTRUCTIONS",
+                r#"{"e5": "query: {{text}}", "bge": "Represent this sentence, briefly: {{text}}"}"#,
+            )
+            .unwrap();
+        config
+            .set("MODEL_DOCUMENT_INSTRUCTIONS", r#"{"e5": "passage: "}"#)
+            .unwrap();
+        assert_eq!(config.model_query_instructions["e5"], "query: {{text}}");
+        // Commas and surrounding whitespace are kept
+        assert_eq!(config.model_document_instructions["e5"], "passage: ");
+        assert!(config
+            .set("MODEL_QUERY_INSTRUCTIONS", "e5=query: {{text}}")
+            .is_err());
+
+        let mut problems = Problems::default();
+        config.validate(&mut problems);
+        let messages: Vec<_> = problems
+            .problems()
+            .iter()
+            .map(|problem| problem.message.as_str())
+            .collect();
+        assert_eq!(messages.len(), 2);
+        assert!(messages[0].contains("\"bge\", which isn't in SYSTEMATICS_MODELS"));
+        assert!(messages[1].starts_with("e5: Instruction \"passage: \" has no {{text}}"));
+    }
+}
@@ -0,0 +1,259 @@
+use axum::{
+    body::{to_bytes, Body, Bytes},
+    extract::{Request, State},
+    http::{header, HeaderMap},
+    middleware::Next,
+    response::Response,
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{HashSet, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::codec::Format;
+use crate::config::DebugCaptureConfig;
+
+/// One captured request and the response it got.
+#[derive(Serialize, Clone)]
+pub struct Capture {
+    /// Position among all requests seen, sampled or not
+    pub seq: u64,
+    pub timestamp: DateTime<Utc>,
+    pub method: String,
+    pub uri: String,
+    pub status: u16,
+    pub duration_ms: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_content_type: Option<String>,
+    pub request_body: CapturedBody,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_content_type: Option<String>,
+    pub response_body: CapturedBody,
+}
+
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "snake_case", tag = "kind", content = "content")]
+pub enum CapturedBody {
+    Empty,
+    /// JSON or MessagePack, decoded and redacted
+    Structured(Value),
+    /// Anything else, as (lossy) UTF-8
+    Text(String),
+    /// Too large or streamed, so left alone
+    Omitted(String),
+}
+
+/// Recent requests and responses, kept in a fixed-size ring for
+/// diagnosing client/server contract mismatches.
+pub struct DebugCapture {
+    config: DebugCaptureConfig,
+    redact: HashSet<String>,
+    seen: AtomicU64,
+    captures: Mutex<VecDeque<Capture>>,
+}
+
+impl DebugCapture {
+    pub fn new(config: DebugCaptureConfig) -> Self {
+        Self {
+            redact: config.redact_fields.iter().cloned().collect(),
+            captures: Mutex::new(VecDeque::with_capacity(config.capacity)),
+            seen: AtomicU64::new(0),
+            config,
+        }
+    }
+
+    /// Captures in the order the requests arrived.
+    pub fn list(&self) -> Vec<Capture> {
+        self.captures.lock().unwrap().iter().cloned().collect()
+    }
+
+    pub fn clear(&self) {
+        self.captures.lock().unwrap().clear();
+    }
+
+    /// Whether request `seq` is sampled. Spreads captures evenly, so a
+    /// rate of 0.25 captures every fourth request.
+    fn sampled(&self, seq: u64) -> bool {
+        let rate = self.config.sample_rate.clamp(0.0, 1.0);
+        ((seq + 1) as f64 * rate).floor() > (seq as f64 * rate).floor()
+    }
+
+    fn push(&self, capture: Capture) {
+        let mut captures = self.captures.lock().unwrap();
+        if captures.len() >= self.config.capacity {
+            captures.pop_front();
+        }
+        captures.push_back(capture);
+    }
+
+    /// Decode a body for display, with the configured fields redacted.
+    fn decode(&self, headers: &HeaderMap, bytes: &[u8]) -> CapturedBody {
+        if bytes.is_empty() {
+            return CapturedBody::Empty;
+        }
+
+        let structured = if matches!(Format::from_content_type(headers), Ok(Format::MessagePack)) {
+            rmp_serde::from_slice::<Value>(bytes).ok()
+        } else {
+            serde_json::from_slice::<Value>(bytes).ok()
+        };
+        match structured {
+            Some(mut value) => {
+                self.redact_value(&mut value);
+                CapturedBody::Structured(value)
+            }
+            None => CapturedBody::Text(String::from_utf8_lossy(bytes).into_owned()),
+        }
+    }
+
+    fn redact_value(&self, value: &mut Value) {
+        match value {
+            Value::Object(fields) => {
+                for (key, value) in fields.iter_mut() {
+                    if self.redact.contains(key) {
+                        *value = redacted(value);
+                    } else {
+                        self.redact_value(value);
+                    }
+                }
+            }
+            Value::Array(values) => values.iter_mut().for_each(|v| self.redact_value(v)),
+            _ => {}
+        }
+    }
+}
+
+/// A placeholder keeping a redacted value's shape and size, which is
+/// usually what a contract mismatch is about, but not its content.
+fn redacted(value: &Value) -> Value {
+    match value {
+        Value::String(s) => Value::String(format!("[redacted: {} chars]", s.chars().count())),
+        Value::Array(values) => Value::Array(values.iter().map(redacted).collect()),
+        Value::Null => Value::Null,
+        _ => Value::String("[redacted]".to_string()),
+    }
+}
+
+fn content_type(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(String::from)
+}
+
+/// Buffer `body` if it is known to fit within `limit` bytes, returning it
+/// along with what to pass on. Bodies that don't, such as streamed bulk
+/// uploads, pass through untouched.
+async fn buffer(body: Body, limit: usize) -> (Body, Option<Bytes>) {
+    let fits = axum::body::HttpBody::size_hint(&body)
+        .upper()
+        .is_some_and(|upper| upper <= limit as u64);
+    if !fits {
+        return (body, None);
+    }
+
+    match to_bytes(body, limit).await {
+        Ok(bytes) => (Body::from(bytes.clone()), Some(bytes)),
+        Err(e) => (
+            Body::empty(),
+            Some(Bytes::from(format!("[unreadable body: {}]", e))),
+        ),
+    }
+}
+
+/// Middleware recording sampled requests and responses.
+pub async fn capture(
+    State(capture): State<Arc<DebugCapture>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let seq = capture.seen.fetch_add(1, Ordering::Relaxed);
+    // Don't capture reads of the captures themselves
+    if !capture.sampled(seq) || request.uri().path().ends_with("/debug/requests") {
+        return next.run(request).await;
+    }
+
+    let started = Instant::now();
+    let timestamp = Utc::now();
+    let method = request.method().to_string();
+    let uri = request.uri().to_string();
+    let limit = capture.config.max_body_bytes;
+
+    let (parts, body) = request.into_parts();
+    let (body, request_bytes) = buffer(body, limit).await;
+    let request_body = match &request_bytes {
+        Some(bytes) => capture.decode(&parts.headers, bytes),
+        None => CapturedBody::Omitted(format!("larger than {} bytes or streamed", limit)),
+    };
+    let request_content_type = content_type(&parts.headers);
+
+    let response = next.run(Request::from_parts(parts, body)).await;
+
+    let (parts, body) = response.into_parts();
+    let (body, response_bytes) = buffer(body, limit).await;
+    let response_body = match &response_bytes {
+        Some(bytes) => capture.decode(&parts.headers, bytes),
+        None => CapturedBody::Omitted(format!("larger than {} bytes or streamed", limit)),
+    };
+
+    capture.push(Capture {
+        seq,
+        timestamp,
+        method,
+        uri,
+        status: parts.status.as_u16(),
+        duration_ms: started.elapsed().as_secs_f64() * 1000.0,
+        request_content_type,
+        request_body,
+        response_content_type: content_type(&parts.headers),
+        response_body,
+    });
+
+    Response::from_parts(parts, body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_sampling_and_redaction() {
+        let capture = DebugCapture::new(DebugCaptureConfig {
+            sample_rate: 0.25,
+            ..DebugCaptureConfig::default()
+        });
+        let sampled = (0..8).filter(|&seq| capture.sampled(seq)).count();
+        assert_eq!(sampled, 2);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_TYPE, "application/json".parse().unwrap());
+        let body = json!({
+            "query": "private thoughts",
+            "limit": 5,
+            "documents": [{ "id": "a", "text": "secret", "metadata": { "tags": ["x"] } }],
+            "texts": ["one", "three"]
+        });
+        let CapturedBody::Structured(decoded) =
+            capture.decode(&headers, body.to_string().as_bytes())
+        else {
+            panic!("expected a structured body");
+        };
+        assert_eq!(
+            decoded,
+            json!({
+                "query": "[redacted: 16 chars]",
+                "limit": 5,
+                "documents": [{
+                    "id": "a",
+                    "text": "[redacted: 6 chars]",
+                    "metadata": { "tags": ["x"] }
+                }],
+                "texts": ["[redacted: 3 chars]", "[redacted: 5 chars]"]
+            })
+        );
+    }
+}
@@ -0,0 +1,287 @@
+//! Near-duplicate detection over stored embeddings.
+//!
+//! Comparing every pair of documents goes quadratic on a large vault, so
+//! pairs are only compared within blocks. Random-hyperplane hashing puts
+//! vectors that point the same way in the same bucket of a table, and
+//! with several independent tables a near-duplicate pair shares a bucket
+//! in at least one of them with high probability. How many tables, and
+//! how many hyperplanes each, follows from the threshold, so that a pair
+//! right at it is found at least [`RECALL`] of the time.
+
+use std::collections::HashMap;
+use std::f64::consts::PI;
+
+use crate::simd;
+
+/// Chance a pair right at the threshold shares a bucket in some table.
+const RECALL: f64 = 0.99;
+
+/// Most hash tables a pair gets a chance to share a bucket in.
+const MAX_TABLES: usize = 32;
+
+/// Most hyperplanes per table. Each splits a bucket in two, so a table
+/// has up to 2^MAX_BITS buckets.
+const MAX_BITS: usize = 10;
+
+/// Lowest threshold accepted. Below it the buckets that keep [`RECALL`]
+/// are so coarse that nearly every pair gets compared anyway.
+pub const MIN_THRESHOLD: f32 = 0.8;
+
+/// Documents that are near-duplicates of one another, directly or
+/// through a chain of near-duplicates.
+pub struct DuplicateGroup {
+    /// Indexes of the vectors in the group, in ascending order
+    pub members: Vec<usize>,
+    /// Every compared pair above the threshold, as indexes and their
+    /// cosine similarity, the most similar first
+    pub pairs: Vec<(usize, usize, f32)>,
+}
+
+/// Group `vectors`, which must all have the same length, wherever a pair
+/// has cosine similarity of at least `threshold`. The largest group comes
+/// first. Hashing is seeded the same way every time, so the same vectors
+/// always give the same groups.
+pub fn near_duplicates(vectors: &[Vec<f32>], threshold: f32) -> Vec<DuplicateGroup> {
+    let Some(dims) = vectors.first().map(Vec::len) else {
+        return Vec::new();
+    };
+    let (tables, bits) = blocking(threshold);
+    let mut random = Gaussian::new(0x9e37_79b9_7f4a_7c15);
+    let planes: Vec<Vec<f32>> = (0..tables * bits)
+        .map(|_| (0..dims).map(|_| random.next()).collect())
+        .collect();
+    let signatures: Vec<Vec<u32>> = vectors
+        .iter()
+        .map(|vector| {
+            planes
+                .chunks(bits)
+                .map(|table| signature(vector, table))
+                .collect()
+        })
+        .collect();
+
+    let mut pairs = Vec::new();
+    for table in 0..tables {
+        let mut buckets: HashMap<u32, Vec<usize>> = HashMap::new();
+        for (i, signature) in signatures.iter().enumerate() {
+            buckets.entry(signature[table]).or_default().push(i);
+        }
+        for bucket in buckets.values() {
+            for (n, &a) in bucket.iter().enumerate() {
+                for &b in &bucket[n + 1..] {
+                    // Pairs that shared a bucket in an earlier table were
+                    // compared there
+                    let (a_seen, b_seen) = (&signatures[a][..table], &signatures[b][..table]);
+                    if a_seen.iter().zip(b_seen).any(|(x, y)| x == y) {
+                        continue;
+                    }
+                    let score = simd::cosine(&vectors[a], &vectors[b]);
+                    if score >= threshold {
+                        pairs.push((a, b, score));
+                    }
+                }
+            }
+        }
+    }
+
+    let mut groups = DisjointSets::new(vectors.len());
+    for &(a, b, _) in &pairs {
+        groups.union(a, b);
+    }
+    let mut by_root: HashMap<usize, DuplicateGroup> = HashMap::new();
+    for (a, b, score) in pairs {
+        let group = by_root
+            .entry(groups.find(a))
+            .or_insert_with(|| DuplicateGroup {
+                members: Vec::new(),
+                pairs: Vec::new(),
+            });
+        group.members.extend([a, b]);
+        group.pairs.push((a, b, score));
+    }
+
+    let mut groups: Vec<DuplicateGroup> = by_root.into_values().collect();
+    for group in &mut groups {
+        group.members.sort_unstable();
+        group.members.dedup();
+        group
+            .pairs
+            .sort_by(|x, y| y.2.total_cmp(&x.2).then((x.0, x.1).cmp(&(y.0, y.1))));
+    }
+    groups.sort_by(|a, b| {
+        b.members
+            .len()
+            .cmp(&a.members.len())
+            .then(a.members[0].cmp(&b.members[0]))
+    });
+    groups
+}
+
+/// Hash tables and hyperplanes per table for `threshold`: as many
+/// hyperplanes as still let a pair right at the threshold share a bucket
+/// in one of at most [`MAX_TABLES`] tables [`RECALL`] of the time, since
+/// more hyperplanes mean smaller buckets and fewer comparisons.
+fn blocking(threshold: f32) -> (usize, usize) {
+    // A random hyperplane separates two vectors with probability equal
+    // to the angle between them over pi
+    let same_side = 1.0 - f64::from(threshold.clamp(-1.0, 1.0)).acos() / PI;
+    for bits in (1..=MAX_BITS).rev() {
+        let shared = same_side.powi(bits as i32);
+        let tables = ((1.0 - RECALL).ln() / (1.0 - shared).ln()).ceil();
+        if tables <= MAX_TABLES as f64 {
+            return ((tables as usize).max(1), bits);
+        }
+    }
+    (MAX_TABLES, 1)
+}
+
+/// Which side of each hyperplane `vector` lies on, one bit per plane.
+fn signature(vector: &[f32], planes: &[Vec<f32>]) -> u32 {
+    planes
+        .iter()
+        .enumerate()
+        .filter(|(_, plane)| simd::dot(vector, plane) >= 0.0)
+        .fold(0, |bits, (i, _)| bits | 1 << i)
+}
+
+/// Standard normal samples from a fixed seed. Hyperplanes drawn from a
+/// normal distribution are uniformly oriented, which the hashing's
+/// guarantees rely on.
+struct Gaussian {
+    state: u64,
+}
+
+impl Gaussian {
+    fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    /// Uniform in (0, 1], by xorshift.
+    fn uniform(&mut self) -> f64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        ((self.state >> 11) + 1) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Box-Muller transform of two uniform samples.
+    fn next(&mut self) -> f32 {
+        let radius = (-2.0 * self.uniform().ln()).sqrt();
+        let angle = std::f64::consts::TAU * self.uniform();
+        (radius * angle.cos()) as f32
+    }
+}
+
+/// Union-find over vector indexes, with path halving.
+struct DisjointSets {
+    parents: Vec<usize>,
+}
+
+impl DisjointSets {
+    fn new(len: usize) -> Self {
+        Self {
+            parents: (0..len).collect(),
+        }
+    }
+
+    fn find(&mut self, mut i: usize) -> usize {
+        while self.parents[i] != i {
+            self.parents[i] = self.parents[self.parents[i]];
+            i = self.parents[i];
+        }
+        i
+    }
+
+    fn union(&mut self, a: usize, b: usize) {
+        let (a, b) = (self.find(a), self.find(b));
+        self.parents[a.max(b)] = a.min(b);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn random_vectors(count: usize, dims: usize, random: &mut Gaussian) -> Vec<Vec<f32>> {
+        (0..count)
+            .map(|_| (0..dims).map(|_| random.next()).collect())
+            .collect()
+    }
+
+    #[test]
+    fn test_near_duplicates_found_within_blocks() {
+        let mut random = Gaussian::new(42);
+        let mut vectors = random_vectors(500, 64, &mut random);
+        // Copies of the first ten vectors with a little noise, and a
+        // second copy of the first
+        for i in 0..10 {
+            let copy: Vec<f32> = vectors[i]
+                .iter()
+                .map(|&x| x + 0.05 * random.next())
+                .collect();
+            vectors.push(copy);
+        }
+        vectors.push(vectors[0].clone());
+
+        let groups = near_duplicates(&vectors, 0.95);
+
+        assert_eq!(groups.len(), 10);
+        assert_eq!(groups[0].members, vec![0, 500, 510]);
+        assert_eq!(groups[0].pairs.len(), 3);
+        assert!(groups[0].pairs[0].2 > 0.999);
+        for (i, group) in groups[1..].iter().enumerate() {
+            assert_eq!(group.members, vec![i + 1, i + 501]);
+            assert!(group.pairs[0].2 >= 0.95);
+        }
+        // Independent random vectors are nowhere near each other, so
+        // nothing else was grouped
+        assert!(near_duplicates(&vectors[..500], 0.95).is_empty());
+        assert!(near_duplicates(&[], 0.95).is_empty());
+    }
+
+    #[test]
+    fn test_blocking_keeps_recall_at_the_threshold() {
+        for threshold in [MIN_THRESHOLD, 0.9, 0.95, 0.99, 1.0] {
+            let (tables, bits) = blocking(threshold);
+            assert!(tables <= MAX_TABLES && bits <= MAX_BITS);
+            let same_side = 1.0 - f64::from(threshold).acos() / PI;
+            let missed = (1.0 - same_side.powi(bits as i32)).powi(tables as i32);
+            assert!(1.0 - missed >= RECALL, "threshold {}", threshold);
+        }
+        // Stricter thresholds get finer buckets
+        assert!(blocking(0.99).1 >= blocking(MIN_THRESHOLD).1);
+    }
+
+    #[test]
+    fn test_near_duplicates_match_all_pairs() {
+        let mut random = Gaussian::new(7);
+        let mut vectors = random_vectors(200, 32, &mut random);
+        for i in 0..40 {
+            let copy: Vec<f32> = vectors[i]
+                .iter()
+                .map(|&x| x + 0.2 * random.next())
+                .collect();
+            vectors.push(copy);
+        }
+
+        for threshold in [MIN_THRESHOLD, 0.95] {
+            let mut expected = Vec::new();
+            for a in 0..vectors.len() {
+                for b in a + 1..vectors.len() {
+                    if simd::cosine(&vectors[a], &vectors[b]) >= threshold {
+                        expected.push((a, b));
+                    }
+                }
+            }
+            let mut found: Vec<(usize, usize)> = near_duplicates(&vectors, threshold)
+                .into_iter()
+                .flat_map(|group| group.pairs)
+                .map(|(a, b, _)| (a, b))
+                .collect();
+            found.sort_unstable();
+
+            assert!(!expected.is_empty());
+            assert_eq!(found, expected, "threshold {}", threshold);
+        }
+    }
+}
@@ -0,0 +1,79 @@
+use crate::index::cosine_similarity;
+
+/// Pick `k` of the candidates by maximal marginal relevance: each pick is
+/// the candidate maximizing `lambda * score - (1 - lambda) * s`, where `s`
+/// is its similarity to the closest candidate already picked. A `lambda`
+/// of 1 keeps the ranking by score; lower values trade relevance for
+/// covering more ground.
+///
+/// Embeddings are given with the model that made them. Candidates without
+/// one count as unlike every other, as do candidates embedded by different
+/// models. Returns the picks in order, each with the penalty subtracted
+/// from its score when it was picked.
+pub fn mmr(
+    scores: &[f32],
+    embeddings: &[Option<(&str, &[f32])>],
+    lambda: f32,
+    k: usize,
+) -> Vec<(usize, f32)> {
+    let mut closest = vec![0.0f32; scores.len()];
+    let mut remaining: Vec<usize> = (0..scores.len()).collect();
+    let mut picks = Vec::with_capacity(k.min(scores.len()));
+
+    while picks.len() < k && !remaining.is_empty() {
+        let (position, &pick) = remaining
+            .iter()
+            .enumerate()
+            .max_by(|(_, &a), (_, &b)| {
+                let marginal = |i: usize| lambda * scores[i] - (1.0 - lambda) * closest[i];
+                marginal(a).total_cmp(&marginal(b)).then(b.cmp(&a))
+            })
+            .expect("remaining is not empty");
+        remaining.swap_remove(position);
+        picks.push((pick, (1.0 - lambda) * closest[pick]));
+
+        let Some((model, picked)) = embeddings[pick] else {
+            continue;
+        };
+        for &i in &remaining {
+            if let Some((_, embedding)) = embeddings[i].filter(|(other, _)| *other == model) {
+                closest[i] = closest[i].max(cosine_similarity(picked, embedding));
+            }
+        }
+    }
+    picks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mmr_skips_near_duplicates() {
+        let embeddings = [
+            vec![1.0, 0.0],
+            vec![0.99, 0.14],
+            vec![0.6, 0.8],
+            vec![0.0, 1.0],
+        ];
+        let embeddings: Vec<Option<(&str, &[f32])>> = embeddings
+            .iter()
+            .map(|e| Some(("minilm", e.as_slice())))
+            .chain([None])
+            .collect();
+        let scores = [0.9, 0.89, 0.75, 0.5, 0.4];
+
+        // Relevance alone keeps the ranking
+        let picks: Vec<usize> = mmr(&scores, &embeddings, 1.0, 3)
+            .into_iter()
+            .map(|(i, _)| i)
+            .collect();
+        assert_eq!(picks, [0, 1, 2]);
+
+        let picks = mmr(&scores, &embeddings, 0.5, 5);
+        let order: Vec<usize> = picks.iter().map(|&(i, _)| i).collect();
+        assert_eq!(order, [0, 3, 4, 2, 1], "the near-duplicate drops to last");
+        assert_eq!(picks[0].1, 0.0);
+        assert!(picks[4].1 > 0.45);
+    }
+}
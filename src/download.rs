@@ -0,0 +1,337 @@
+use anyhow::{Context, Result};
+use reqwest::header::{HeaderMap, RANGE};
+use reqwest::{redirect, StatusCode};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tracing::{info, warn};
+
+use crate::signature::TrustRoot;
+
+const HUGGINGFACE_URL: &str = "https://huggingface.co";
+
+/// HuggingFace repo for a model name. Bare names are assumed to be
+/// sentence-transformers models.
+pub fn huggingface_repo(model_name: &str) -> String {
+    if model_name.contains('/') {
+        model_name.to_string()
+    } else {
+        format!("sentence-transformers/{}", model_name)
+    }
+}
+
+/// Directory a model's files are cached in under `cache_dir`.
+pub fn model_cache_dir(cache_dir: &Path, model_name: &str) -> PathBuf {
+    cache_dir.join(huggingface_repo(model_name).replace('/', "--"))
+}
+
+/// Download `file` from the main branch of a HuggingFace repo to `dest`.
+///
+/// Bytes land in `<dest>.part` first, so an interrupted download resumes
+/// where it left off on the next attempt. Files stored in git LFS (such as
+/// ONNX weights) are verified against the SHA-256 HuggingFace publishes
+/// for them before being moved into place, as is every file against its
+/// minisign signature when `trust` has keys. A file failing either check
+/// is removed rather than resumed.
+pub async fn fetch_from_huggingface(
+    repo: &str,
+    file: &str,
+    dest: &Path,
+    trust: &TrustRoot,
+) -> Result<()> {
+    let url = format!("{}/{}/resolve/main/{}", HUGGINGFACE_URL, repo, file);
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    // The resolve endpoint redirects to a CDN that drops HuggingFace's
+    // headers, so read the checksum from the redirect itself
+    let probe = reqwest::Client::builder()
+        .redirect(redirect::Policy::none())
+        .build()?
+        .head(&url)
+        .send()
+        .await
+        .with_context(|| format!("Failed to reach {}", url))?;
+    if probe.status().is_client_error() {
+        anyhow::bail!("{} returned {}", url, probe.status());
+    }
+    let expected = linked_sha256(probe.headers());
+
+    let part = part_path(dest);
+    download_part(&reqwest::Client::new(), &url, &part, None).await?;
+
+    let actual = sha256_file(&part)?;
+    match expected {
+        Some(expected) if expected != actual => {
+            // A corrupt partial file would otherwise be resumed forever
+            fs::remove_file(&part)?;
+            anyhow::bail!(
+                "Checksum mismatch for {}: expected {}, got {}",
+                url,
+                expected,
+                actual
+            );
+        }
+        Some(_) => {}
+        None => warn!("No checksum published for {}, skipping verification", url),
+    }
+    if let Err(e) = trust.verify_download(&url, &part, dest).await {
+        // Resuming would only append to bytes that failed their signature
+        let _ = fs::remove_file(&part);
+        return Err(e);
+    }
+
+    fs::rename(&part, dest)?;
+    info!("Saved {} ({})", dest.display(), actual);
+    Ok(())
+}
+
+/// Where `dest` is downloaded to before it is verified.
+fn part_path(dest: &Path) -> PathBuf {
+    dest.with_extension(match dest.extension() {
+        Some(ext) => format!("{}.part", ext.to_string_lossy()),
+        None => "part".to_string(),
+    })
+}
+
+/// Download `url` into `part`, resuming from the bytes already there,
+/// failing once the file would grow past `max_bytes`.
+async fn download_part(
+    client: &reqwest::Client,
+    url: &str,
+    part: &Path,
+    max_bytes: Option<u64>,
+) -> Result<()> {
+    let resume_from = fs::metadata(part).map(|m| m.len()).unwrap_or(0);
+    let too_big = |bytes: u64| max_bytes.is_some_and(|max| bytes > max);
+
+    let mut request = client.get(url);
+    if resume_from > 0 {
+        request = request.header(RANGE, format!("bytes={}-", resume_from));
+    }
+    let response = request
+        .send()
+        .await
+        .with_context(|| format!("Failed to reach {}", url))?;
+
+    // 416 means the partial file already holds every byte, e.g. after a
+    // crash between finishing the download and renaming it
+    if response.status() != StatusCode::RANGE_NOT_SATISFIABLE {
+        let mut response = response.error_for_status()?;
+        let (mut file, mut bytes) = if response.status() == StatusCode::PARTIAL_CONTENT {
+            info!("Resuming download of {} at {} bytes", url, resume_from);
+            (OpenOptions::new().append(true).open(part)?, resume_from)
+        } else {
+            info!("Downloading {}", url);
+            (File::create(part)?, 0)
+        };
+
+        let limit = || anyhow::anyhow!("{} is larger than {} bytes", url, max_bytes.unwrap_or(0));
+        if too_big(bytes + response.content_length().unwrap_or(0)) {
+            return Err(limit());
+        }
+        while let Some(chunk) = response.chunk().await? {
+            bytes += chunk.len() as u64;
+            if too_big(bytes) {
+                return Err(limit());
+            }
+            file.write_all(&chunk)?;
+        }
+        file.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Numbers the partial files of [`fetch_verified`].
+static DOWNLOADS: AtomicU64 = AtomicU64::new(0);
+
+/// Hosts a client may have the server download from, and how much, so it
+/// can't be pointed at services on the server's own network or made to
+/// fill its disk.
+pub struct Allowlist<'a> {
+    pub hosts: &'a [String],
+    pub max_bytes: u64,
+}
+
+impl Allowlist<'_> {
+    /// Fail unless `url` is HTTPS on one of the allowed hosts.
+    pub fn check(&self, url: &reqwest::Url) -> Result<()> {
+        if self.hosts.is_empty() {
+            anyhow::bail!(
+                "Downloads from URLs are disabled; allow hosts with SYSTEMATICS_BUNDLE_HOSTS"
+            );
+        }
+        if !allowed(self.hosts, url) {
+            anyhow::bail!(
+                "{} isn't an HTTPS URL on an allowed host ({})",
+                url,
+                self.hosts.join(", ")
+            );
+        }
+        Ok(())
+    }
+
+    /// A client that only follows redirects to allowed URLs.
+    pub fn client(&self) -> Result<reqwest::Client> {
+        let hosts = self.hosts.to_vec();
+        Ok(reqwest::Client::builder()
+            .redirect(redirect::Policy::custom(move |attempt| {
+                if attempt.previous().len() >= 10 {
+                    attempt.error("too many redirects")
+                } else if allowed(&hosts, attempt.url()) {
+                    attempt.follow()
+                } else {
+                    let error = format!("redirected to {}, which isn't allowed", attempt.url());
+                    attempt.error(error)
+                }
+            }))
+            .build()?)
+    }
+}
+
+fn allowed(hosts: &[String], url: &reqwest::Url) -> bool {
+    url.scheme() == "https"
+        && url.host_str().is_some_and(|host| {
+            hosts
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(host))
+        })
+}
+
+/// Download `url` to `dest` through `client`, checking it against
+/// `expected_sha256` before moving it into place. Bytes land in a partial
+/// file of this download's own first, removed if the download fails or
+/// grows past `max_bytes`, so concurrent downloads to one `dest` can't mix.
+pub async fn fetch_verified(
+    client: &reqwest::Client,
+    url: &str,
+    dest: &Path,
+    expected_sha256: &str,
+    max_bytes: u64,
+) -> Result<()> {
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let file_name = dest.file_name().unwrap_or_default().to_string_lossy();
+    let part = dest.with_file_name(format!(
+        ".{}.{}-{}.part",
+        file_name,
+        std::process::id(),
+        DOWNLOADS.fetch_add(1, Ordering::Relaxed)
+    ));
+    let verified = async {
+        download_part(client, url, &part, Some(max_bytes)).await?;
+        let actual = sha256_file(&part)?;
+        if !actual.eq_ignore_ascii_case(expected_sha256) {
+            anyhow::bail!(
+                "Checksum mismatch for {}: expected {}, got {}",
+                url,
+                expected_sha256,
+                actual
+            );
+        }
+        Ok(actual)
+    }
+    .await;
+    let actual = match verified {
+        Ok(actual) => actual,
+        Err(e) => {
+            let _ = fs::remove_file(&part);
+            return Err(e);
+        }
+    };
+
+    fs::rename(&part, dest)?;
+    info!("Saved {} ({})", dest.display(), actual);
+    Ok(())
+}
+
+/// The SHA-256 published for `url` in a `<url>.sha256` file next to it, in
+/// the format `sha256sum` writes. `None` if there is no such file.
+pub async fn published_sha256(client: &reqwest::Client, url: &str) -> Result<Option<String>> {
+    let checksum_url = format!("{}.sha256", url);
+    let response = client
+        .get(&checksum_url)
+        .send()
+        .await
+        .with_context(|| format!("Failed to reach {}", checksum_url))?;
+    if response.status() == StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    let text = response.error_for_status()?.text().await?;
+    parse_sha256(&text)
+        .map(Some)
+        .with_context(|| format!("{} doesn't hold a SHA-256", checksum_url))
+}
+
+/// The hex digest leading a `sha256sum` line, lowercased.
+fn parse_sha256(text: &str) -> Option<String> {
+    let digest = text.split_whitespace().next()?;
+    (digest.len() == 64 && digest.chars().all(|c| c.is_ascii_hexdigit()))
+        .then(|| digest.to_ascii_lowercase())
+}
+
+/// SHA-256 HuggingFace reports for LFS files in `X-Linked-Etag`.
+fn linked_sha256(headers: &HeaderMap) -> Option<String> {
+    let etag = headers.get("x-linked-etag")?.to_str().ok()?;
+    let etag = etag.trim_start_matches("W/").trim_matches('"');
+    (etag.len() == 64 && etag.chars().all(|c| c.is_ascii_hexdigit()))
+        .then(|| etag.to_ascii_lowercase())
+}
+
+pub fn sha256_file(path: &Path) -> Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_linked_sha256() {
+        let sha = "6fd5d72fe4589f189f8ebc006442dbb529bb7ce38f8082112682524616046452";
+        let mut headers = HeaderMap::new();
+        headers.insert("x-linked-etag", format!("\"{}\"", sha).parse().unwrap());
+        assert_eq!(linked_sha256(&headers).as_deref(), Some(sha));
+
+        // Git blob hashes of regular files are not SHA-256
+        headers.insert(
+            "x-linked-etag",
+            "\"cb202bfe2e3c98645018a6d12f182a434c9d3e02\""
+                .parse()
+                .unwrap(),
+        );
+        assert_eq!(linked_sha256(&headers), None);
+
+        let line = format!("{}  corpus.bundle\n", sha.to_ascii_uppercase());
+        assert_eq!(parse_sha256(&line).as_deref(), Some(sha));
+        assert_eq!(parse_sha256("not a checksum"), None);
+    }
+
+    #[test]
+    fn test_allowlist() {
+        let hosts = ["corpora.example.org".to_string()];
+        let allowlist = Allowlist {
+            hosts: &hosts,
+            max_bytes: 1024,
+        };
+        let check = |url: &str| allowlist.check(&reqwest::Url::parse(url).unwrap());
+        assert!(check("https://corpora.example.org/bennett.bundle").is_ok());
+        assert!(check("https://CORPORA.example.org/bennett.bundle").is_ok());
+        assert!(check("http://corpora.example.org/bennett.bundle").is_err());
+        assert!(check("https://169.254.169.254/latest/meta-data").is_err());
+        assert!(check("https://corpora.example.org.evil.test/bennett.bundle").is_err());
+
+        let none = Allowlist {
+            hosts: &[],
+            max_bytes: 1024,
+        };
+        let url = reqwest::Url::parse("https://corpora.example.org/bennett.bundle").unwrap();
+        assert!(none.check(&url).is_err());
+    }
+}
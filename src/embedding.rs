@@ -1,148 +1,994 @@
-use anyhow::Result;
+//! Running the embedding model: tokenizing, batching, pooling, and
+//! caching embeddings.
+
+use anyhow::{Context, Result};
 use ndarray::ArrayView;
 use ort::{
-    session::{builder::GraphOptimizationLevel, Session, SessionOutputs},
-    value::Value,
+    execution_providers::{
+        CUDAExecutionProvider, CoreMLExecutionProvider, DirectMLExecutionProvider,
+        ExecutionProvider as _,
+    },
+    io_binding::IoBinding,
+    session::{
+        builder::{GraphOptimizationLevel, SessionBuilder},
+        Session, SessionOutputs,
+    },
+    value::Tensor,
 };
-use std::path::PathBuf;
-use std::sync::Mutex;
-use tokenizers::Tokenizer;
-use tracing::info;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tokenizers::{Encoding, Tokenizer};
+use tokio::sync::oneshot;
+use tracing::{info, warn};
+
+use crate::cache::{CacheMetrics, EmbeddingCache};
+use crate::config::{ExecutionProvider, ModelConfig, Pooling, Precision};
+use crate::download;
+use crate::signature::TrustRoot;
+use crate::vector::round_to_precision;
+
+#[derive(Debug, thiserror::Error)]
+pub enum EmbeddingError {
+    #[error("Text is {tokens} tokens long, exceeding the limit of {limit}")]
+    TooManyTokens { tokens: usize, limit: usize },
+    #[error("Text is {tokens} tokens long, exceeding the model's maximum length of {limit}")]
+    Truncated { tokens: usize, limit: usize },
+    #[error("Failed to tokenize: {0}")]
+    Tokenization(String),
+    #[error("Inference failed: {0}")]
+    Inference(String),
+}
+
+/// Length of a text in tokens, from [`EmbeddingService::count_tokens`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TokenCount {
+    pub tokens: usize,
+    /// Most tokens the model sees, if the tokenizer truncates at all
+    pub max_length: Option<usize>,
+    /// Only the first `max_length` tokens are embedded
+    pub truncated: bool,
+}
+
+/// Cumulative tokenizer counters, updated on every embed call.
+#[derive(Default)]
+pub struct TokenizerStats {
+    texts: AtomicU64,
+    tokens: AtomicU64,
+    truncated: AtomicU64,
+    rejected: AtomicU64,
+    nanos: AtomicU64,
+}
+
+#[derive(Serialize)]
+pub struct TokenizerMetrics {
+    pub texts: u64,
+    pub tokens: u64,
+    pub rejected: u64,
+    pub tokens_per_second: f64,
+    pub average_tokens: f64,
+    pub truncation_rate: f64,
+}
+
+impl TokenizerStats {
+    fn record(&self, texts: usize, tokens: usize, truncated: usize, elapsed: Duration) {
+        self.texts.fetch_add(texts as u64, Ordering::Relaxed);
+        self.tokens.fetch_add(tokens as u64, Ordering::Relaxed);
+        self.truncated
+            .fetch_add(truncated as u64, Ordering::Relaxed);
+        self.nanos
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
 
+    pub fn snapshot(&self) -> TokenizerMetrics {
+        let texts = self.texts.load(Ordering::Relaxed);
+        let tokens = self.tokens.load(Ordering::Relaxed);
+        let truncated = self.truncated.load(Ordering::Relaxed);
+        let seconds = self.nanos.load(Ordering::Relaxed) as f64 / 1e9;
+        let per_text = |value: u64| {
+            if texts == 0 {
+                0.0
+            } else {
+                value as f64 / texts as f64
+            }
+        };
+
+        TokenizerMetrics {
+            texts,
+            tokens,
+            rejected: self.rejected.load(Ordering::Relaxed),
+            tokens_per_second: if seconds > 0.0 {
+                tokens as f64 / seconds
+            } else {
+                0.0
+            },
+            average_tokens: per_text(tokens),
+            truncation_rate: per_text(truncated),
+        }
+    }
+}
+
+/// Model name, a prefix of the model file's SHA-256, pooling and
+/// dimensions, e.g. `all-MiniLM-L6-v2:53aa51172d142c89:mean:384`, then a
+/// prefix of the document instruction's SHA-256 if there is one. Vectors
+/// produced under different fingerprints aren't comparable.
+fn fingerprint(
+    name: &str,
+    model_sha256: &str,
+    pooling: Pooling,
+    dimensions: usize,
+    document_instruction: &str,
+) -> String {
+    let pooling = match pooling {
+        Pooling::Mean => "mean",
+        Pooling::Cls => "cls",
+        Pooling::Max => "max",
+        Pooling::WeightedMean => "weighted-mean",
+    };
+    let hash = &model_sha256[..model_sha256.len().min(16)];
+    let fingerprint = format!("{}:{}:{}:{}", name, hash, pooling, dimensions);
+    if document_instruction.is_empty() {
+        return fingerprint;
+    }
+    let instruction = Sha256::digest(document_instruction.as_bytes());
+    let instruction: String = instruction[..8]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    format!("{}:{}", fingerprint, instruction)
+}
+
+/// Where an instruction puts the text it is given, e.g. `query: {{text}}`.
+pub const TEXT_PLACEHOLDER: &str = "{{text}}";
+
+/// `text` put into `instruction`'s placeholder. An empty instruction
+/// leaves the text as it is.
+pub fn instruct<'a>(instruction: &str, text: &'a str) -> Cow<'a, str> {
+    if instruction.is_empty() {
+        Cow::Borrowed(text)
+    } else {
+        Cow::Owned(instruction.replacen(TEXT_PLACEHOLDER, text, 1))
+    }
+}
+
+/// Check a non-empty `instruction` has a placeholder for the text.
+pub fn check_instruction(instruction: &str) -> Result<(), String> {
+    if instruction.is_empty() || instruction.contains(TEXT_PLACEHOLDER) {
+        Ok(())
+    } else {
+        Err(format!(
+            "Instruction {:?} has no {} for the text to go in",
+            instruction, TEXT_PLACEHOLDER
+        ))
+    }
+}
+
+/// Reduce the token outputs of sequence `batch_index` to one embedding,
+/// ignoring padding.
+fn pool(
+    pooling: Pooling,
+    embeddings: &ArrayView<f32, ndarray::IxDyn>,
+    batch_index: usize,
+    attention_mask: &[u32],
+) -> Vec<f32> {
+    let hidden_size = embeddings.shape()[2];
+    let token = |i: usize| embeddings.slice(ndarray::s![batch_index, i, ..]);
+    let tokens = attention_mask
+        .iter()
+        .enumerate()
+        .filter(|(_, &mask)| mask == 1)
+        .map(|(i, _)| i);
+
+    match pooling {
+        Pooling::Cls => token(0).to_vec(),
+        Pooling::Max => {
+            let mut pooled = vec![f32::NEG_INFINITY; hidden_size];
+            for i in tokens {
+                for (max, &value) in pooled.iter_mut().zip(token(i)) {
+                    *max = max.max(value);
+                }
+            }
+            pooled
+        }
+        Pooling::Mean | Pooling::WeightedMean => {
+            let mut pooled = vec![0.0f32; hidden_size];
+            let mut weight_sum = 0.0f32;
+            for i in tokens {
+                // Positions count from 1 so the first token isn't ignored
+                let weight = match pooling {
+                    Pooling::WeightedMean => (i + 1) as f32,
+                    _ => 1.0,
+                };
+                for (sum, &value) in pooled.iter_mut().zip(token(i)) {
+                    *sum += weight * value;
+                }
+                weight_sum += weight;
+            }
+            for value in &mut pooled {
+                *value /= weight_sum;
+            }
+            pooled
+        }
+    }
+}
+
+/// Sequences up to this many tokens share the smallest length bucket.
+const MIN_BUCKET_LENGTH: usize = 16;
+
+/// Group sequence indices into buckets whose lengths round up to the same
+/// power of two, so padding within a bucket is at most 2x. Buckets are
+/// returned shortest first.
+fn length_buckets(lengths: &[usize]) -> Vec<Vec<usize>> {
+    let mut buckets: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+    for (i, &len) in lengths.iter().enumerate() {
+        let bucket = len.max(MIN_BUCKET_LENGTH).next_power_of_two();
+        buckets.entry(bucket).or_default().push(i);
+    }
+
+    buckets.into_values().collect()
+}
+
+/// Padded sequence lengths are rounded up to a multiple of this so input
+/// tensors of the same shape can be reused across calls.
+const PAD_TO_MULTIPLE: usize = 8;
+
+/// Upper bound on distinct input shapes kept allocated at once.
+const MAX_CACHED_SHAPES: usize = 64;
+
+/// An ONNX session with its IO binding and input tensors reused across
+/// runs, so bulk indexing doesn't allocate fresh tensors per batch. Owned
+/// by the inference thread once the service is running.
+struct Inference {
+    session: Session,
+    binding: IoBinding,
+    /// Input tensors keyed by (batch size, sequence length)
+    inputs: HashMap<(usize, usize), InputTensors>,
+    /// Whether the model takes `token_type_ids`, as BERT exports such as
+    /// BGE do
+    token_type_ids: bool,
+    pooling: Pooling,
+    precision: Precision,
+}
+
+struct InputTensors {
+    input_ids: Tensor<i64>,
+    attention_mask: Tensor<i64>,
+    token_type_ids: Tensor<i64>,
+}
+
+/// Tokenized texts from one caller, waiting for the inference thread.
+struct Job {
+    encodings: Vec<Encoding>,
+    reply: oneshot::Sender<Result<Vec<Vec<f32>>>>,
+}
+
+impl Inference {
+    /// Run jobs until the service is dropped. Jobs that arrive within
+    /// `window` of the first one waiting are run together, up to
+    /// `max_texts` texts, so concurrent requests share forward passes
+    /// instead of queueing for the session one after another.
+    fn serve(mut self, queue: mpsc::Receiver<Job>, window: Duration, max_texts: usize) {
+        while let Ok(first) = queue.recv() {
+            let deadline = Instant::now() + window;
+            let mut texts = first.encodings.len();
+            let mut jobs = vec![first];
+            while texts < max_texts {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                match queue.recv_timeout(remaining) {
+                    Ok(job) => {
+                        texts += job.encodings.len();
+                        jobs.push(job);
+                    }
+                    Err(_) => break,
+                }
+            }
+            self.run_jobs(jobs);
+        }
+    }
+
+    /// Embed every job's texts and reply to each with its own embeddings.
+    /// A failed batch fails only the jobs with texts in it.
+    fn run_jobs(&mut self, jobs: Vec<Job>) {
+        let texts: Vec<(usize, usize)> = jobs
+            .iter()
+            .enumerate()
+            .flat_map(|(j, job)| (0..job.encodings.len()).map(move |i| (j, i)))
+            .collect();
+        let lengths: Vec<usize> = texts
+            .iter()
+            .map(|&(j, i)| jobs[j].encodings[i].len())
+            .collect();
+        let mut results: Vec<Vec<Option<Vec<f32>>>> = jobs
+            .iter()
+            .map(|job| vec![None; job.encodings.len()])
+            .collect();
+        let mut errors: Vec<Option<String>> = vec![None; jobs.len()];
+
+        // Run each length bucket as its own batch so short texts aren't
+        // padded out to the longest text in the batch
+        for bucket in length_buckets(&lengths) {
+            let batch: Vec<&Encoding> = bucket
+                .iter()
+                .map(|&t| &jobs[texts[t].0].encodings[texts[t].1])
+                .collect();
+            match self.run_batch(&batch) {
+                Ok(embeddings) => {
+                    for (t, embedding) in bucket.into_iter().zip(embeddings) {
+                        let (j, i) = texts[t];
+                        results[j][i] = Some(embedding);
+                    }
+                }
+                Err(e) => {
+                    for t in bucket {
+                        errors[texts[t].0] = Some(format!("{:#}", e));
+                    }
+                }
+            }
+        }
+
+        for ((job, embeddings), error) in jobs.into_iter().zip(results).zip(errors) {
+            let result = match error {
+                Some(error) => Err(EmbeddingError::Inference(error).into()),
+                None => Ok(embeddings.into_iter().flatten().collect()),
+            };
+            // The caller may have given up waiting
+            let _ = job.reply.send(result);
+        }
+    }
+
+    fn run_batch(&mut self, encodings: &[&Encoding]) -> Result<Vec<Vec<f32>>> {
+        let batch_size = encodings.len();
+        let longest = encodings.iter().map(|e| e.len()).max().unwrap_or(0);
+        let seq_len = longest.div_ceil(PAD_TO_MULTIPLE) * PAD_TO_MULTIPLE;
+
+        let shape = (batch_size, seq_len);
+        if !self.inputs.contains_key(&shape) && self.inputs.len() >= MAX_CACHED_SHAPES {
+            self.inputs.clear();
+        }
+        let inputs = match self.inputs.entry(shape) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let allocator = self.session.allocator();
+                entry.insert(InputTensors {
+                    input_ids: Tensor::new(allocator, [batch_size, seq_len])?,
+                    attention_mask: Tensor::new(allocator, [batch_size, seq_len])?,
+                    token_type_ids: Tensor::new(allocator, [batch_size, seq_len])?,
+                })
+            }
+        };
+
+        // Pad every sequence to the same length; padding is masked out.
+        // ONNX expects i64 inputs (common requirement)
+        let (_, ids) = inputs.input_ids.extract_tensor_mut();
+        let (_, mask) = inputs.attention_mask.extract_tensor_mut();
+        let (_, types) = inputs.token_type_ids.extract_tensor_mut();
+        ids.fill(0);
+        mask.fill(0);
+        types.fill(0);
+        for (b, encoding) in encodings.iter().enumerate() {
+            let offset = b * seq_len;
+            let tokens = encoding
+                .get_ids()
+                .iter()
+                .zip(encoding.get_attention_mask())
+                .zip(encoding.get_type_ids());
+            for (i, ((&id, &m), &t)) in tokens.enumerate() {
+                ids[offset + i] = id as i64;
+                mask[offset + i] = m as i64;
+                types[offset + i] = t as i64;
+            }
+        }
+
+        // Run inference
+        self.binding.bind_input("input_ids", &inputs.input_ids)?;
+        self.binding
+            .bind_input("attention_mask", &inputs.attention_mask)?;
+        if self.token_type_ids {
+            self.binding
+                .bind_input("token_type_ids", &inputs.token_type_ids)?;
+        }
+        let outputs: SessionOutputs = self.session.run_binding(&self.binding)?;
+
+        // Extract embeddings (last_hidden_state)
+        let (shape, data) = outputs[0].try_extract_tensor::<f32>()?;
+
+        // Convert shape to Vec<usize> for ArrayView
+        let shape_vec: Vec<usize> = shape.iter().map(|&x| x as usize).collect();
+        let embeddings = ArrayView::from_shape(&shape_vec[..], data)?;
+
+        let mut results = Vec::with_capacity(batch_size);
+        for (b, encoding) in encodings.iter().enumerate() {
+            let pooled = pool(self.pooling, &embeddings, b, encoding.get_attention_mask());
+
+            // Normalize, then round to the storage precision
+            let mut normalized = Self::normalize(&pooled);
+            round_to_precision(&mut normalized, self.precision);
+            results.push(normalized);
+        }
+
+        Ok(results)
+    }
+
+    fn normalize(vec: &[f32]) -> Vec<f32> {
+        let norm: f32 = vec.iter().map(|x| x * x).sum::<f32>().sqrt();
+        vec.iter().map(|x| x / norm).collect()
+    }
+}
+
+/// A sentence-embedding model loaded into ONNX Runtime, turning texts into
+/// normalized vectors. Concurrent calls share forward passes, and repeated
+/// texts are served from a cache.
 pub struct EmbeddingService {
-    session: Mutex<Session>,
+    /// Queue of the inference thread, which batches concurrent callers'
+    /// texts into shared forward passes
+    jobs: mpsc::Sender<Job>,
     tokenizer: Tokenizer,
+    /// The tokenizer without truncation, for measuring whole documents
+    splitter: Tokenizer,
+    add_special_tokens: bool,
+    max_request_tokens: usize,
+    /// Where the model is actually running
+    execution_provider: ExecutionProvider,
+    /// Length of the embeddings the model produces
+    dimensions: usize,
+    /// Identifies the model's output space; see [`fingerprint`]
+    fingerprint: String,
+    /// Instructions queries and documents are put into before embedding,
+    /// for models trained with prefixes like `query: `; empty for none
+    query_instruction: String,
+    document_instruction: String,
+    stats: TokenizerStats,
+    cache: EmbeddingCache,
 }
 
 impl EmbeddingService {
-    pub async fn new() -> Result<Self> {
-        // Download and load model
-        let model_path = Self::download_model().await?;
+    /// Load the model `config` names, downloading it into its cache
+    /// directory first if it isn't there yet.
+    pub async fn new(config: &ModelConfig) -> Result<Self> {
+        // Load a local model if one is configured, otherwise download it
+        let model_path = match &config.path {
+            Some(path) => path.clone(),
+            None => Self::download_model(config).await?,
+        };
+        let tokenizer_path = match (&config.tokenizer_path, &config.path) {
+            (Some(path), _) => path.clone(),
+            (None, Some(model)) => model.with_file_name("tokenizer.json"),
+            (None, None) => Self::download_tokenizer(config).await?,
+        };
+
+        Self::from_files(&model_path, &tokenizer_path, config)
+    }
 
+    /// Load a model and tokenizer from explicit paths, e.g. a fine-tuned
+    /// variant exported alongside the base model.
+    pub fn from_files(
+        model_path: &Path,
+        tokenizer_path: &Path,
+        config: &ModelConfig,
+    ) -> Result<Self> {
         info!("Loading ONNX model from {:?}", model_path);
-        let session = Session::builder()?
-            .with_optimization_level(GraphOptimizationLevel::Level3)?
-            .with_intra_threads(4)?
-            .commit_from_file(&model_path)?;
+        let (session, execution_provider) =
+            match Self::load_session(model_path, config, &config.execution_providers) {
+                Ok(loaded) => loaded,
+                // An accelerator can register fine and still fail to load
+                // the model, e.g. when its runtime libraries are missing
+                Err(e) if !config.execution_providers.is_empty() => {
+                    warn!(
+                        "Failed to load the model on an accelerator, using the CPU: {}",
+                        e
+                    );
+                    Self::load_session(model_path, config, &[])?
+                }
+                Err(e) => return Err(e),
+            };
 
-        info!("Loading tokenizer");
-        let tokenizer_path = Self::download_tokenizer().await?;
-        let tokenizer = Tokenizer::from_file(&tokenizer_path)
+        // Let ONNX Runtime place outputs in the session's own memory
+        let mut binding = session.create_binding()?;
+        binding
+            .bind_output_to_device(&session.outputs[0].name, &session.allocator().memory_info())?;
+        let token_type_ids = session
+            .inputs
+            .iter()
+            .any(|input| input.name == "token_type_ids");
+        let dimensions = session.outputs[0]
+            .output_type
+            .tensor_shape()
+            .and_then(|shape| shape.last().copied())
+            .filter(|&dim| dim > 0)
+            .map(|dim| dim as usize);
+
+        info!("Loading tokenizer from {:?}", tokenizer_path);
+        let mut tokenizer = Tokenizer::from_file(tokenizer_path)
             .map_err(|e| anyhow::anyhow!("Failed to load tokenizer: {}", e))?;
+        if config.max_length > 0 {
+            match tokenizer.get_truncation().cloned() {
+                // Past its own limit the model has no positions left
+                Some(own) if own.max_length < config.max_length => warn!(
+                    "Max length {} exceeds the tokenizer's limit of {}, keeping that",
+                    config.max_length, own.max_length
+                ),
+                own => {
+                    let mut truncation = own.unwrap_or_default();
+                    truncation.max_length = config.max_length;
+                    tokenizer
+                        .with_truncation(Some(truncation))
+                        .map_err(|e| anyhow::anyhow!("Failed to configure tokenizer: {}", e))?;
+                }
+            }
+        }
+        let mut splitter = tokenizer.clone();
+        splitter
+            .with_truncation(None)
+            .map_err(|e| anyhow::anyhow!("Failed to configure tokenizer: {}", e))?;
+        splitter.with_padding(None);
+
+        // Everything that changes the output goes into the cache key
+        let cache = EmbeddingCache::new(
+            format!(
+                "{}|{:?}|{:?}|{}|{:?}",
+                model_path.display(),
+                config.pooling,
+                config.precision,
+                config.add_special_tokens,
+                tokenizer.get_truncation().map(|t| t.max_length)
+            ),
+            config.cache_entries,
+            config
+                .persist_cache
+                .then(|| config.cache_dir.join("embeddings"))
+                .as_deref(),
+            config.cache_max_mb * 1024 * 1024,
+        )?;
+
+        let mut inference = Inference {
+            session,
+            binding,
+            inputs: HashMap::new(),
+            token_type_ids,
+            pooling: config.pooling,
+            precision: config.precision,
+        };
+
+        // Models exported with a dynamic hidden size only reveal it by
+        // running one
+        let dimensions = match dimensions {
+            Some(dimensions) => dimensions,
+            None => {
+                let encoding = tokenizer
+                    .encode("dimensions", config.add_special_tokens)
+                    .map_err(|e| EmbeddingError::Tokenization(e.to_string()))?;
+                inference.run_batch(&[&encoding])?.remove(0).len()
+            }
+        };
+        info!("Model produces {}-dimensional embeddings", dimensions);
+
+        let (jobs, queue) = mpsc::channel();
+        let window = Duration::from_millis(config.batch_window_ms);
+        let max_texts = config.batch_max_texts;
+        thread::Builder::new()
+            .name("inference".to_string())
+            .spawn(move || inference.serve(queue, window, max_texts))?;
 
         Ok(Self {
-            session: Mutex::new(session),
+            jobs,
             tokenizer,
+            splitter,
+            add_special_tokens: config.add_special_tokens,
+            max_request_tokens: config.max_request_tokens,
+            execution_provider,
+            dimensions,
+            fingerprint: fingerprint(
+                &config.name,
+                &download::sha256_file(model_path)?,
+                config.pooling,
+                dimensions,
+                &config.document_instruction,
+            ),
+            query_instruction: config.query_instruction.clone(),
+            document_instruction: config.document_instruction.clone(),
+            stats: TokenizerStats::default(),
+            cache,
         })
     }
 
-    pub async fn embed(&self, text: &str) -> Result<Vec<f32>> {
-        // Tokenize
+    pub fn dimensions(&self) -> usize {
+        self.dimensions
+    }
+
+    /// Changes whenever the vectors this service produces would, so
+    /// clients caching them can tell when to throw them away.
+    pub fn fingerprint(&self) -> &str {
+        &self.fingerprint
+    }
+
+    pub fn execution_provider(&self) -> ExecutionProvider {
+        self.execution_provider
+    }
+
+    /// Instruction search queries are embedded with, unless a request
+    /// gives its own.
+    pub fn query_instruction(&self) -> &str {
+        &self.query_instruction
+    }
+
+    /// Instruction documents are embedded with for indexing, unless a
+    /// request gives its own.
+    pub fn document_instruction(&self) -> &str {
+        &self.document_instruction
+    }
+
+    fn load_session(
+        model_path: &Path,
+        config: &ModelConfig,
+        providers: &[ExecutionProvider],
+    ) -> Result<(Session, ExecutionProvider)> {
+        let builder = Session::builder()?
+            .with_optimization_level(GraphOptimizationLevel::Level3)?
+            .with_intra_threads(config.threads)?;
+        let (builder, provider) = Self::register_execution_provider(builder, providers)?;
+        Ok((builder.commit_from_file(model_path)?, provider))
+    }
+
+    /// Register the first of `providers` that this build and machine
+    /// support, falling back to the CPU if none do.
+    fn register_execution_provider(
+        mut builder: SessionBuilder,
+        providers: &[ExecutionProvider],
+    ) -> Result<(SessionBuilder, ExecutionProvider)> {
+        for &provider in providers {
+            let registered = match provider {
+                ExecutionProvider::Cuda => CUDAExecutionProvider::default().register(&mut builder),
+                ExecutionProvider::DirectMl => {
+                    DirectMLExecutionProvider::default().register(&mut builder)
+                }
+                ExecutionProvider::CoreMl => {
+                    CoreMLExecutionProvider::default().register(&mut builder)
+                }
+                ExecutionProvider::Cpu => break,
+            };
+
+            match registered {
+                Ok(()) => {
+                    info!(
+                        "Running the model with the {:?} execution provider",
+                        provider
+                    );
+                    if provider == ExecutionProvider::DirectMl {
+                        // DirectML supports neither
+                        builder = builder
+                            .with_memory_pattern(false)?
+                            .with_parallel_execution(false)?;
+                    }
+                    return Ok((builder, provider));
+                }
+                Err(e) => warn!("{:?} execution provider unavailable: {}", provider, e),
+            }
+        }
+
+        info!("Running the model on the CPU");
+        Ok((builder, ExecutionProvider::Cpu))
+    }
+
+    pub fn tokenizer_stats(&self) -> &TokenizerStats {
+        &self.stats
+    }
+
+    pub fn cache_metrics(&self) -> CacheMetrics {
+        self.cache.metrics()
+    }
+
+    /// Most tokens of a text the model sees, after room for special tokens,
+    /// if the tokenizer truncates at all.
+    pub fn max_content_tokens(&self) -> Option<usize> {
+        let limit = self.tokenizer.get_truncation()?.max_length;
+        Some(if self.add_special_tokens {
+            limit.saturating_sub(2)
+        } else {
+            limit
+        })
+    }
+
+    /// How many tokens `text` encodes to, special tokens included, and
+    /// whether that is more than the model sees.
+    pub fn count_tokens(&self, text: &str) -> Result<TokenCount> {
+        let tokens = self
+            .splitter
+            .encode(text, self.add_special_tokens)
+            .map_err(|e| EmbeddingError::Tokenization(e.to_string()))?
+            .len();
+        let max_length = self.tokenizer.get_truncation().map(|t| t.max_length);
+        Ok(TokenCount {
+            tokens,
+            max_length,
+            truncated: max_length.is_some_and(|limit| tokens > limit),
+        })
+    }
+
+    /// Byte offsets of every token in `text`, ignoring truncation, for
+    /// splitting long documents into chunks.
+    pub fn token_offsets(&self, text: &str) -> Result<Vec<(usize, usize)>> {
         let encoding = self
-            .tokenizer
+            .splitter
             .encode(text, false)
-            .map_err(|e| anyhow::anyhow!("Failed to tokenize: {}", e))?;
+            .map_err(|e| EmbeddingError::Tokenization(e.to_string()))?;
+        if encoding.len() > self.max_request_tokens {
+            self.stats.rejected.fetch_add(1, Ordering::Relaxed);
+            return Err(EmbeddingError::TooManyTokens {
+                tokens: encoding.len(),
+                limit: self.max_request_tokens,
+            }
+            .into());
+        }
 
-        let input_ids = encoding.get_ids();
-        let attention_mask = encoding.get_attention_mask();
+        Ok(encoding.get_offsets().to_vec())
+    }
 
-        // Convert to i64 for ONNX (common requirement)
-        let input_ids_i64: Vec<i64> = input_ids.iter().map(|&x| x as i64).collect();
-        let attention_mask_i64: Vec<i64> = attention_mask.iter().map(|&x| x as i64).collect();
+    /// Embed one text.
+    pub async fn embed(&self, text: &str) -> Result<Vec<f32>> {
+        let mut embeddings = self.embed_batch(&[text]).await?;
+        Ok(embeddings.remove(0))
+    }
 
-        // Run inference (lock the mutex to get mutable access)
-        let mut session = self
-            .session
-            .lock()
-            .map_err(|e| anyhow::anyhow!("Failed to lock session: {}", e))?;
-        let outputs: SessionOutputs = session.run(ort::inputs![
-            "input_ids" => Value::from_array(([1, input_ids_i64.len()], input_ids_i64))?,
-            "attention_mask" => Value::from_array(([1, attention_mask_i64.len()], attention_mask_i64))?,
-        ])?;
+    /// Embed a search query with `instruction`, or with the model's query
+    /// instruction.
+    pub async fn embed_query(&self, query: &str, instruction: Option<&str>) -> Result<Vec<f32>> {
+        let instruction = instruction.unwrap_or(&self.query_instruction);
+        self.embed(&instruct(instruction, query)).await
+    }
 
-        // Extract embeddings (last_hidden_state)
-        let (shape, data) = outputs[0].try_extract_tensor::<f32>()?;
+    /// Embed several texts with a single padded forward pass, returning the
+    /// embeddings in input order. Texts embedded before come from the cache.
+    pub async fn embed_batch(&self, texts: &[&str]) -> Result<Vec<Vec<f32>>> {
+        if texts.is_empty() {
+            return Ok(Vec::new());
+        }
 
-        // Convert shape to Vec<usize> for ArrayView
-        let shape_vec: Vec<usize> = shape.iter().map(|&x| x as usize).collect();
-        let embeddings = ArrayView::from_shape(&shape_vec[..], data)?;
+        let keys: Vec<_> = texts.iter().map(|text| self.cache.key(text)).collect();
+        let mut results: Vec<Option<Vec<f32>>> =
+            keys.iter().map(|key| self.cache.get(key)).collect();
+        let missing: Vec<usize> = (0..texts.len()).filter(|&i| results[i].is_none()).collect();
+        if missing.is_empty() {
+            return Ok(results.into_iter().flatten().collect());
+        }
 
-        // Mean pooling
-        let pooled = self.mean_pooling(&embeddings, attention_mask);
+        let uncached: Vec<&str> = missing.iter().map(|&i| texts[i]).collect();
+        let embeddings = self.infer(&uncached).await?;
+        for (i, embedding) in missing.into_iter().zip(embeddings) {
+            self.cache.insert(keys[i], &embedding);
+            results[i] = Some(embedding);
+        }
+
+        Ok(results
+            .into_iter()
+            .map(|embedding| embedding.expect("every text embedded"))
+            .collect())
+    }
 
-        // Normalize
-        let normalized = self.normalize(&pooled);
+    /// Embed one text by running the model, neither reading nor filling
+    /// the cache, e.g. to time or check the model itself.
+    pub async fn embed_uncached(&self, text: &str) -> Result<Vec<f32>> {
+        let mut embeddings = self.infer(&[text]).await?;
+        Ok(embeddings.remove(0))
+    }
+
+    /// Run the model on `texts` in one forward pass.
+    async fn infer(&self, texts: &[&str]) -> Result<Vec<Vec<f32>>> {
+        #[cfg(feature = "chaos")]
+        crate::chaos::before_embedding(texts).await?;
+        let encodings = self.tokenize(texts)?;
 
-        Ok(normalized)
+        let (reply, embeddings) = oneshot::channel();
+        self.jobs
+            .send(Job { encodings, reply })
+            .map_err(|_| EmbeddingError::Inference("the inference thread stopped".to_string()))?;
+        embeddings
+            .await
+            .map_err(|_| EmbeddingError::Inference("the inference thread stopped".to_string()))?
     }
 
-    fn mean_pooling(&self, embeddings: &ArrayView<f32, ndarray::IxDyn>, attention_mask: &[u32]) -> Vec<f32> {
-        let shape = embeddings.shape();
-        let seq_len = shape[1];
-        let hidden_size = shape[2];
+    fn tokenize(&self, texts: &[&str]) -> Result<Vec<Encoding>> {
+        let started = Instant::now();
 
-        let mut pooled = vec![0.0f32; hidden_size];
-        let mut mask_sum = 0.0f32;
+        // encode_batch spreads the texts across the tokenizers' rayon pool
+        let encodings = self
+            .tokenizer
+            .encode_batch(texts.to_vec(), self.add_special_tokens)
+            .map_err(|e| EmbeddingError::Tokenization(e.to_string()))?;
 
-        for i in 0..seq_len {
-            if attention_mask[i] == 1 {
-                for j in 0..hidden_size {
-                    pooled[j] += embeddings[[0, i, j]];
+        let mut tokens = 0;
+        let mut truncated = 0;
+        for encoding in &encodings {
+            // Count tokens the tokenizer's own truncation cut off too
+            let overflow: usize = encoding.get_overflowing().iter().map(|o| o.len()).sum();
+            let count = encoding.len() + overflow;
+            if count > self.max_request_tokens {
+                self.stats.rejected.fetch_add(1, Ordering::Relaxed);
+                return Err(EmbeddingError::TooManyTokens {
+                    tokens: count,
+                    limit: self.max_request_tokens,
                 }
-                mask_sum += 1.0;
+                .into());
+            }
+
+            tokens += count;
+            if overflow > 0 {
+                truncated += 1;
             }
         }
+        self.stats
+            .record(encodings.len(), tokens, truncated, started.elapsed());
 
-        // Average
-        for val in &mut pooled {
-            *val /= mask_sum;
+        Ok(encodings)
+    }
+
+    /// Path to the model's ONNX weights, downloading them on first use.
+    /// Int8 weights come from the first of the repo's quantized exports
+    /// suited to this CPU that it publishes.
+    async fn download_model(config: &ModelConfig) -> Result<PathBuf> {
+        if config.inference_precision.is_fp32() {
+            return Self::locate_or_fetch(config, "onnx/model.onnx", "model.onnx").await;
         }
 
-        pooled
+        let mut errors = Vec::new();
+        for remote in int8_exports() {
+            let local = remote.trim_start_matches("onnx/");
+            match Self::locate_or_fetch(config, remote, local).await {
+                Ok(path) => {
+                    info!("Running {} with int8 weights from {}", config.name, remote);
+                    return Ok(path);
+                }
+                Err(e) => errors.push(format!("{:#}", e)),
+            }
+        }
+        anyhow::bail!(
+            "No int8 export of {} found ({}); set SYSTEMATICS_INFERENCE_PRECISION=fp32, \
+             or quantize the model yourself and load it with SYSTEMATICS_MODEL_PATH",
+            config.name,
+            errors.join("; ")
+        )
     }
 
-    fn normalize(&self, vec: &[f32]) -> Vec<f32> {
-        let norm: f32 = vec.iter().map(|x| x * x).sum::<f32>().sqrt();
-        vec.iter().map(|x| x / norm).collect()
+    /// Path to the model's tokenizer, downloading it on first use.
+    pub async fn download_tokenizer(config: &ModelConfig) -> Result<PathBuf> {
+        Self::locate_or_fetch(config, "tokenizer.json", "tokenizer.json").await
     }
 
-    async fn download_model() -> Result<PathBuf> {
-        // For now, assume model is in models/ directory
-        // In production, download from HuggingFace
-        let model_dir = PathBuf::from("models");
-        std::fs::create_dir_all(&model_dir)?;
-
-        let model_path = model_dir.join("model.onnx");
-
-        if !model_path.exists() {
-            info!("Downloading model from HuggingFace...");
-            // TODO: Download model
-            // For now, return error with instructions
-            anyhow::bail!(
-                "Model not found. Please download model manually:\n\
-                 1. Download from: https://huggingface.co/sentence-transformers/all-MiniLM-L6-v2\n\
-                 2. Convert to ONNX format\n\
-                 3. Place in: {:?}",
-                model_path
+    /// A file exported into `models/` by download-model.py takes
+    /// precedence; otherwise the file is fetched from HuggingFace into the
+    /// cache directory once and reused from there. With trusted keys
+    /// configured, cached files are checked against their signatures on
+    /// every load; exported files are trusted as they are.
+    async fn locate_or_fetch(config: &ModelConfig, remote: &str, local: &str) -> Result<PathBuf> {
+        let exported = PathBuf::from("models").join(local);
+        if exported.exists() {
+            return Ok(exported);
+        }
+
+        let trust = TrustRoot::new(&config.trusted_keys)?;
+        let cached = download::model_cache_dir(&config.cache_dir, &config.name).join(local);
+        if cached.exists() {
+            trust.verify_cached(&cached)?;
+        } else {
+            info!(
+                "Downloading {} for {} from HuggingFace...",
+                local, config.name
             );
+            let repo = download::huggingface_repo(&config.name);
+            download::fetch_from_huggingface(&repo, remote, &cached, &trust)
+                .await
+                .with_context(|| format!("Failed to download {} for {}", local, config.name))?;
         }
 
-        Ok(model_path)
+        Ok(cached)
     }
+}
 
-    async fn download_tokenizer() -> Result<PathBuf> {
-        let model_dir = PathBuf::from("models");
-        let tokenizer_path = model_dir.join("tokenizer.json");
-
-        if !tokenizer_path.exists() {
-            anyhow::bail!(
-                "Tokenizer not found. Please download:\n\
-                 1. Download tokenizer.json from HuggingFace\n\
-                 2. Place in: {:?}",
-                tokenizer_path
-            );
+/// Quantized ONNX files a HuggingFace repo may publish, best first for
+/// this CPU. Sentence-transformers repos ship one per instruction set;
+/// others follow Optimum's single `model_quantized.onnx`.
+fn int8_exports() -> Vec<&'static str> {
+    let mut exports = Vec::new();
+    #[cfg(target_arch = "x86_64")]
+    {
+        if is_x86_feature_detected!("avx512vnni") {
+            exports.push("onnx/model_qint8_avx512_vnni.onnx");
         }
+        if is_x86_feature_detected!("avx512f") {
+            exports.push("onnx/model_qint8_avx512.onnx");
+        }
+        exports.push("onnx/model_quint8_avx2.onnx");
+    }
+    #[cfg(target_arch = "aarch64")]
+    exports.push("onnx/model_qint8_arm64.onnx");
+    exports.push("onnx/model_quantized.onnx");
+    exports
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fingerprint() {
+        let sha = "53aa51172d142c896c37a1d8b7d2a6b3c6f3e6b1a2c3d4e5f60718293a4b5c6d";
+        assert_eq!(
+            fingerprint("all-MiniLM-L6-v2", sha, Pooling::Mean, 384, ""),
+            "all-MiniLM-L6-v2:53aa51172d142c89:mean:384"
+        );
+        assert_ne!(
+            fingerprint("all-MiniLM-L6-v2", sha, Pooling::Cls, 384, ""),
+            fingerprint("all-MiniLM-L6-v2", sha, Pooling::Mean, 384, "")
+        );
+        // Documents embedded with another instruction aren't comparable
+        let instructed = fingerprint("e5-small-v2", sha, Pooling::Mean, 384, "passage: {{text}}");
+        assert!(instructed.starts_with("e5-small-v2:53aa51172d142c89:mean:384:"));
+        assert_eq!(
+            instructed.len(),
+            "e5-small-v2:53aa51172d142c89:mean:384:".len() + 16
+        );
+        assert_ne!(
+            instructed,
+            fingerprint("e5-small-v2", sha, Pooling::Mean, 384, "passage:{{text}}")
+        );
+    }
+
+    #[test]
+    fn test_instruct() {
+        assert_eq!(
+            instruct("query: {{text}}", "what is a triad?"),
+            "query: what is a triad?"
+        );
+        assert_eq!(instruct("", "{{text}}"), "{{text}}");
+        // Only the instruction's placeholder is filled, not one in the text
+        assert_eq!(instruct("{{text}}!", "{{text}}"), "{{text}}!");
+        assert!(check_instruction("passage: {{text}}").is_ok());
+        assert!(check_instruction("").is_ok());
+        assert!(check_instruction("query: ").is_err());
+    }
+
+    #[test]
+    fn test_tokenizer_stats_snapshot() {
+        let stats = TokenizerStats::default();
+        stats.record(1, 100, 0, Duration::from_millis(10));
+        stats.record(1, 300, 1, Duration::from_millis(30));
+
+        let metrics = stats.snapshot();
+        assert_eq!(metrics.texts, 2);
+        assert_eq!(metrics.tokens, 400);
+        assert!((metrics.average_tokens - 200.0).abs() < 1e-9);
+        assert!((metrics.truncation_rate - 0.5).abs() < 1e-9);
+        assert!((metrics.tokens_per_second - 10_000.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_pooling_strategies() {
+        // Three tokens of two dimensions, the last one padding
+        let data = [1.0, 4.0, 3.0, 2.0, 100.0, 100.0];
+        let embeddings = ArrayView::from_shape(&[1, 3, 2][..], &data[..]).unwrap();
+        let mask = [1, 1, 0];
+
+        assert_eq!(pool(Pooling::Cls, &embeddings, 0, &mask), vec![1.0, 4.0]);
+        assert_eq!(pool(Pooling::Mean, &embeddings, 0, &mask), vec![2.0, 3.0]);
+        assert_eq!(pool(Pooling::Max, &embeddings, 0, &mask), vec![3.0, 4.0]);
+        // Weights 1 and 2
+        let weighted = pool(Pooling::WeightedMean, &embeddings, 0, &mask);
+        assert!((weighted[0] - 7.0 / 3.0).abs() < 1e-6);
+        assert!((weighted[1] - 8.0 / 3.0).abs() < 1e-6);
+    }
 
-        Ok(tokenizer_path)
+    #[test]
+    fn test_length_buckets() {
+        let lengths = [5, 300, 12, 20, 16, 31, 33];
+        assert_eq!(
+            length_buckets(&lengths),
+            vec![vec![0, 2, 4], vec![3, 5], vec![6], vec![1]]
+        );
     }
 }
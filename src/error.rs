@@ -0,0 +1,227 @@
+//! Errors as the API reports them: an HTTP status, a stable code clients
+//! can branch on, and a message for people.
+
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+
+use crate::embedding::EmbeddingError;
+use crate::index::IndexError;
+use crate::storage;
+
+/// Stable, machine-readable identifier of what went wrong. Codes are
+/// never renamed; new ones may be added, so clients should treat an
+/// unknown code by its HTTP status.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    // Codes for failures without a more specific one, by status
+    Internal,
+    NotFound,
+    BadRequest,
+    PayloadTooLarge,
+    UnsupportedMediaType,
+    Conflict,
+    Forbidden,
+    RateLimited,
+    Overloaded,
+
+    /// A request named a model that isn't served
+    ModelNotFound,
+    /// A collection is bound to a model the server no longer loads
+    ModelNotLoaded,
+    /// The model failed while embedding
+    InferenceFailed,
+    /// The tokenizer couldn't encode a text
+    TokenizationFailed,
+    /// A text has more tokens than allowed
+    TextTooLong,
+    /// A vector doesn't have the collection's number of dimensions
+    DimensionMismatch,
+    /// The disk holding the index is full
+    IndexFull,
+    DocumentNotFound,
+    CollectionNotFound,
+    CollectionExists,
+    InvalidCollectionName,
+    /// The default collection can't be deleted
+    DefaultCollection,
+    /// An alias would shadow a document id
+    AliasConflict,
+    /// A document id breaks the collection's id policy
+    InvalidId,
+    /// The collection is a read-only bundle
+    ReadOnly,
+}
+
+impl ErrorCode {
+    pub fn status(self) -> StatusCode {
+        match self {
+            ErrorCode::Internal | ErrorCode::InferenceFailed => StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorCode::NotFound
+            | ErrorCode::ModelNotFound
+            | ErrorCode::DocumentNotFound
+            | ErrorCode::CollectionNotFound => StatusCode::NOT_FOUND,
+            ErrorCode::BadRequest
+            | ErrorCode::InvalidCollectionName
+            | ErrorCode::DefaultCollection
+            | ErrorCode::InvalidId => StatusCode::BAD_REQUEST,
+            ErrorCode::PayloadTooLarge | ErrorCode::TextTooLong => StatusCode::PAYLOAD_TOO_LARGE,
+            ErrorCode::UnsupportedMediaType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ErrorCode::TokenizationFailed => StatusCode::UNPROCESSABLE_ENTITY,
+            ErrorCode::Conflict
+            | ErrorCode::CollectionExists
+            | ErrorCode::AliasConflict
+            | ErrorCode::DimensionMismatch => StatusCode::CONFLICT,
+            ErrorCode::Forbidden | ErrorCode::ReadOnly => StatusCode::FORBIDDEN,
+            ErrorCode::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            ErrorCode::Overloaded | ErrorCode::ModelNotLoaded => StatusCode::SERVICE_UNAVAILABLE,
+            ErrorCode::IndexFull => StatusCode::INSUFFICIENT_STORAGE,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorResponse {
+    error: String,
+    code: ErrorCode,
+}
+
+#[derive(Debug)]
+pub enum AppError {
+    Internal(String),
+    NotFound(String),
+    BadRequest(String),
+    PayloadTooLarge(String),
+    UnsupportedMediaType(String),
+    Conflict(String),
+    Forbidden(String),
+    TooManyRequests(String),
+    ServiceUnavailable(String),
+    /// A failure with a code more specific than its status
+    Coded(ErrorCode, String),
+}
+
+impl AppError {
+    pub(crate) fn code(&self) -> ErrorCode {
+        match self {
+            AppError::Internal(_) => ErrorCode::Internal,
+            AppError::NotFound(_) => ErrorCode::NotFound,
+            AppError::BadRequest(_) => ErrorCode::BadRequest,
+            AppError::PayloadTooLarge(_) => ErrorCode::PayloadTooLarge,
+            AppError::UnsupportedMediaType(_) => ErrorCode::UnsupportedMediaType,
+            AppError::Conflict(_) => ErrorCode::Conflict,
+            AppError::Forbidden(_) => ErrorCode::Forbidden,
+            AppError::TooManyRequests(_) => ErrorCode::RateLimited,
+            AppError::ServiceUnavailable(_) => ErrorCode::Overloaded,
+            AppError::Coded(code, _) => *code,
+        }
+    }
+
+    /// The error's message, without its status.
+    pub(crate) fn message(&self) -> &str {
+        match self {
+            AppError::Internal(msg)
+            | AppError::NotFound(msg)
+            | AppError::BadRequest(msg)
+            | AppError::PayloadTooLarge(msg)
+            | AppError::UnsupportedMediaType(msg)
+            | AppError::Conflict(msg)
+            | AppError::Forbidden(msg)
+            | AppError::TooManyRequests(msg)
+            | AppError::ServiceUnavailable(msg)
+            | AppError::Coded(_, msg) => msg,
+        }
+    }
+}
+
+impl std::fmt::Display for AppError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.message())
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let code = self.code();
+        let error = self.message().to_string();
+        (code.status(), Json(ErrorResponse { error, code })).into_response()
+    }
+}
+
+impl From<IndexError> for AppError {
+    fn from(err: IndexError) -> Self {
+        let code = match err {
+            IndexError::NotFound(_) => ErrorCode::DocumentNotFound,
+            IndexError::AliasIsDocument(_) => ErrorCode::AliasConflict,
+            IndexError::CollectionNotFound(_) => ErrorCode::CollectionNotFound,
+            IndexError::CollectionExists(_) => ErrorCode::CollectionExists,
+            IndexError::InvalidCollectionName(_) => ErrorCode::InvalidCollectionName,
+            IndexError::DeleteDefaultCollection => ErrorCode::DefaultCollection,
+            IndexError::DimensionMismatch { .. } => ErrorCode::DimensionMismatch,
+            IndexError::ReadOnly => ErrorCode::ReadOnly,
+            IndexError::InvalidId(_) => ErrorCode::InvalidId,
+            IndexError::Full => ErrorCode::IndexFull,
+        };
+        AppError::Coded(code, err.to_string())
+    }
+}
+
+impl From<anyhow::Error> for AppError {
+    fn from(err: anyhow::Error) -> Self {
+        if let Some(err) = err.downcast_ref::<EmbeddingError>() {
+            let code = match err {
+                EmbeddingError::TooManyTokens { .. } | EmbeddingError::Truncated { .. } => {
+                    ErrorCode::TextTooLong
+                }
+                EmbeddingError::Tokenization(_) => ErrorCode::TokenizationFailed,
+                EmbeddingError::Inference(_) => ErrorCode::InferenceFailed,
+            };
+            return AppError::Coded(code, err.to_string());
+        }
+        if storage::is_full(&err) {
+            return IndexError::Full.into();
+        }
+
+        match err.downcast::<IndexError>() {
+            Ok(err) => err.into(),
+            Err(err) => AppError::Internal(err.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_typed_errors_keep_their_codes() {
+        let err = AppError::from(anyhow::Error::from(EmbeddingError::TooManyTokens {
+            tokens: 9000,
+            limit: 8192,
+        }));
+        assert_eq!(err.code(), ErrorCode::TextTooLong);
+        assert_eq!(err.code().status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let err = AppError::from(
+            anyhow::Error::from(IndexError::NotFound("a".to_string())).context("Reading a"),
+        );
+        assert_eq!(err.code(), ErrorCode::DocumentNotFound);
+        assert_eq!(err.message(), "Document not found: a");
+
+        let disk_full = std::io::Error::from(std::io::ErrorKind::StorageFull);
+        let err = AppError::from(anyhow::Error::from(disk_full).context("Appending to the log"));
+        assert_eq!(err.code(), ErrorCode::IndexFull);
+        assert_eq!(err.code().status(), StatusCode::INSUFFICIENT_STORAGE);
+
+        let err = AppError::from(anyhow::anyhow!("Inference thread stopped"));
+        assert_eq!(err.code(), ErrorCode::Internal);
+        assert_eq!(
+            serde_json::to_value(ErrorCode::ModelNotLoaded).unwrap(),
+            "model_not_loaded"
+        );
+    }
+}
@@ -0,0 +1,75 @@
+use axum::http::{header, HeaderMap};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+/// Weak ETag for a document's content. Weak because the same content may be
+/// served as JSON or MessagePack.
+pub fn document_etag(text: &str, metadata: Option<&Value>) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(text.as_bytes());
+    hasher.update([0]);
+    if let Some(metadata) = metadata {
+        hasher.update(metadata.to_string().as_bytes());
+    }
+
+    format!("W/\"{:x}\"", hasher.finalize())
+}
+
+/// Weak ETag for a list of documents, derived from their ids and ETags in
+/// order, so adding, removing, or editing any document changes it.
+pub fn list_etag<'a>(entries: impl IntoIterator<Item = (&'a str, &'a str)>) -> String {
+    let mut hasher = Sha256::new();
+    for (id, etag) in entries {
+        hasher.update(id.as_bytes());
+        hasher.update([0]);
+        hasher.update(etag.as_bytes());
+        hasher.update([0]);
+    }
+
+    format!("W/\"{:x}\"", hasher.finalize())
+}
+
+/// Whether the request's If-None-Match header matches `etag`, using the weak
+/// comparison HTTP requires for If-None-Match.
+pub fn if_none_match(headers: &HeaderMap, etag: &str) -> bool {
+    let Some(value) = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+    else {
+        return false;
+    };
+
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    let etag = opaque(etag);
+
+    value
+        .split(',')
+        .any(|candidate| candidate.trim() == "*" || opaque(candidate) == etag)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    #[test]
+    fn test_if_none_match() {
+        let etag = document_etag("text", None);
+        let strong = etag.trim_start_matches("W/").to_string();
+
+        let mut headers = HeaderMap::new();
+        assert!(!if_none_match(&headers, &etag));
+
+        headers.insert(
+            header::IF_NONE_MATCH,
+            HeaderValue::from_str(&format!("\"other\", {}", strong)).unwrap(),
+        );
+        assert!(if_none_match(&headers, &etag));
+
+        headers.insert(header::IF_NONE_MATCH, HeaderValue::from_static("\"other\""));
+        assert!(!if_none_match(&headers, &etag));
+
+        headers.insert(header::IF_NONE_MATCH, HeaderValue::from_static("*"));
+        assert!(if_none_match(&headers, &etag));
+    }
+}
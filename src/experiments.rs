@@ -0,0 +1,240 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::RwLock;
+
+use crate::config::{Config, HnswConfig, InferencePrecision, Pooling, Precision, Quantization};
+
+/// The subset of the configuration that affects which documents a search
+/// returns and in what order.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct RetrievalConfig {
+    pub model: String,
+    pub add_special_tokens: bool,
+    #[serde(default)]
+    pub pooling: Pooling,
+    #[serde(default)]
+    pub chunk_size: usize,
+    #[serde(default)]
+    pub chunk_overlap: usize,
+    /// Cross-encoder available for reranking. Left out when unset so
+    /// hashes recorded before reranking existed still match.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reranker: Option<String>,
+    /// Left out at fp32, for the same reason
+    #[serde(default, skip_serializing_if = "InferencePrecision::is_fp32")]
+    pub inference_precision: InferencePrecision,
+    /// Base model instructions, left out when empty
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub query_instruction: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub document_instruction: String,
+    /// Storage precision, left out at f32
+    #[serde(default, skip_serializing_if = "Precision::is_f32")]
+    pub precision: Precision,
+    /// Left out when vectors aren't compressed
+    #[serde(default, skip_serializing_if = "Quantization::is_none")]
+    pub quantization: Quantization,
+    /// Only recorded under product quantization
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pq_subvectors: Option<usize>,
+    /// Graph parameters, each left out at its default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hnsw_m: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hnsw_ef_construction: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hnsw_ef_search: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target_latency_ms: Option<f64>,
+    /// Models served alongside the base model, by name, and their pooling
+    /// and instructions where set
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub models: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub model_pooling: BTreeMap<String, Pooling>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub model_query_instructions: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub model_document_instructions: BTreeMap<String, String>,
+}
+
+impl RetrievalConfig {
+    pub fn from_config(config: &Config) -> Self {
+        let hnsw = HnswConfig::default();
+        let unless_default = |value: usize, default: usize| (value != default).then_some(value);
+        Self {
+            model: config.model.name.clone(),
+            add_special_tokens: config.model.add_special_tokens,
+            pooling: config.model.pooling,
+            chunk_size: config.chunking.size,
+            chunk_overlap: config.chunking.overlap,
+            reranker: config.reranker.model.clone().or_else(|| {
+                config
+                    .reranker
+                    .path
+                    .as_ref()
+                    .map(|path| path.display().to_string())
+            }),
+            inference_precision: config.model.inference_precision,
+            query_instruction: config.model.query_instruction.clone(),
+            document_instruction: config.model.document_instruction.clone(),
+            precision: config.model.precision,
+            quantization: config.quantization.mode,
+            pq_subvectors: (config.quantization.mode == Quantization::Pq)
+                .then_some(config.quantization.pq_subvectors),
+            hnsw_m: unless_default(config.hnsw.m, hnsw.m),
+            hnsw_ef_construction: unless_default(config.hnsw.ef_construction, hnsw.ef_construction),
+            hnsw_ef_search: unless_default(config.hnsw.ef_search, hnsw.ef_search),
+            target_latency_ms: config.hnsw.target_latency_ms,
+            models: config.models.clone(),
+            model_pooling: config.model_pooling.clone(),
+            model_query_instructions: config.model_query_instructions.clone(),
+            model_document_instructions: config.model_document_instructions.clone(),
+        }
+    }
+
+    /// Short, stable hash identifying this exact configuration.
+    pub fn hash(&self) -> String {
+        let json = serde_json::to_vec(self).expect("RetrievalConfig is always serializable");
+        let digest = format!("{:x}", Sha256::digest(&json));
+        digest[..16].to_string()
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ConfigChange {
+    pub timestamp: DateTime<Utc>,
+    pub hash: String,
+    pub config: RetrievalConfig,
+}
+
+/// Append-only history of retrieval config changes, stored as JSON lines.
+pub struct ConfigChangelog {
+    path: PathBuf,
+    changes: RwLock<Vec<ConfigChange>>,
+}
+
+impl ConfigChangelog {
+    pub fn open(path: PathBuf) -> Result<Self> {
+        let changes = if path.exists() {
+            fs::read_to_string(&path)?
+                .lines()
+                .filter(|line| !line.trim().is_empty())
+                .map(serde_json::from_str)
+                .collect::<Result<Vec<ConfigChange>, _>>()?
+        } else {
+            Vec::new()
+        };
+
+        Ok(Self {
+            path,
+            changes: RwLock::new(changes),
+        })
+    }
+
+    /// Record `config` as the active configuration, appending a changelog
+    /// entry only when it differs from the last one. Returns its hash.
+    pub async fn record(&self, config: RetrievalConfig) -> Result<String> {
+        let hash = config.hash();
+
+        let mut changes = self.changes.write().unwrap();
+        if changes.last().map(|last| &last.hash) == Some(&hash) {
+            return Ok(hash);
+        }
+
+        let change = ConfigChange {
+            timestamp: Utc::now(),
+            hash: hash.clone(),
+            config,
+        };
+
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(file, "{}", serde_json::to_string(&change)?)?;
+
+        changes.push(change);
+        Ok(hash)
+    }
+
+    pub async fn changes(&self) -> Vec<ConfigChange> {
+        let changes = self.changes.read().unwrap();
+        changes.clone()
+    }
+
+    /// Hash of the configuration currently in effect.
+    pub async fn active_hash(&self) -> Option<String> {
+        let changes = self.changes.read().unwrap();
+        changes.last().map(|change| change.hash.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_record_only_appends_changes() {
+        let path = std::env::temp_dir().join(format!(
+            "systematics-changelog-{}.jsonl",
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&path);
+
+        let config = RetrievalConfig::from_config(&Config::default());
+        let changed = RetrievalConfig {
+            add_special_tokens: false,
+            ..config.clone()
+        };
+
+        let changelog = ConfigChangelog::open(path.clone()).unwrap();
+        let first = changelog.record(config.clone()).await.unwrap();
+        changelog.record(config.clone()).await.unwrap();
+        let second = changelog.record(changed).await.unwrap();
+        assert_ne!(first, second);
+
+        // Reopening reads the persisted history back
+        let reopened = ConfigChangelog::open(path.clone()).unwrap();
+        assert_eq!(reopened.changes().await.len(), 2);
+        assert_eq!(reopened.active_hash().await, Some(second));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_settings_at_their_defaults_leave_the_hash_alone() {
+        let config = Config::default();
+        let recorded = serde_json::to_value(RetrievalConfig::from_config(&config)).unwrap();
+        for key in [
+            "precision",
+            "quantization",
+            "pq_subvectors",
+            "hnsw_m",
+            "hnsw_ef_construction",
+            "hnsw_ef_search",
+            "target_latency_ms",
+            "models",
+            "model_pooling",
+        ] {
+            assert!(recorded.get(key).is_none(), "{} was recorded", key);
+        }
+
+        let mut tuned = config.clone();
+        tuned.hnsw.m = 32;
+        tuned.quantization.mode = Quantization::Pq;
+        let tuned = RetrievalConfig::from_config(&tuned);
+        assert_eq!(tuned.hnsw_m, Some(32));
+        assert_eq!(tuned.pq_subvectors, Some(96));
+        assert_ne!(tuned.hash(), RetrievalConfig::from_config(&config).hash());
+    }
+}
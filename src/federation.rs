@@ -0,0 +1,133 @@
+use anyhow::Result;
+use futures::future::join_all;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::RwLock;
+use std::time::Duration;
+use tracing::warn;
+
+use crate::filter::Filter;
+use crate::index::SearchResult;
+use crate::index::{Boost, HybridConfig};
+
+/// A remote systematics-embeddings instance queried during federated search.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Peer {
+    pub name: String,
+    pub url: String,
+}
+
+#[derive(Deserialize)]
+struct RemoteSearchResponse {
+    results: Vec<SearchResult>,
+}
+
+/// Registered peers, persisted as JSON so they survive restarts.
+pub struct FederationRegistry {
+    path: PathBuf,
+    peers: RwLock<BTreeMap<String, Peer>>,
+    client: reqwest::Client,
+}
+
+impl FederationRegistry {
+    pub fn open(path: PathBuf, timeout: Duration) -> Result<Self> {
+        let peers = if path.exists() {
+            serde_json::from_str(&fs::read_to_string(&path)?)?
+        } else {
+            BTreeMap::new()
+        };
+
+        Ok(Self {
+            path,
+            peers: RwLock::new(peers),
+            client: reqwest::Client::builder().timeout(timeout).build()?,
+        })
+    }
+
+    pub async fn add(&self, peer: Peer) -> Result<()> {
+        let mut peers = self.peers.write().unwrap();
+        peers.insert(peer.name.clone(), peer);
+        self.save(&peers)
+    }
+
+    pub async fn remove(&self, name: &str) -> Result<bool> {
+        let mut peers = self.peers.write().unwrap();
+        let removed = peers.remove(name).is_some();
+        if removed {
+            self.save(&peers)?;
+        }
+        Ok(removed)
+    }
+
+    pub async fn list(&self) -> Vec<Peer> {
+        let peers = self.peers.read().unwrap();
+        peers.values().cloned().collect()
+    }
+
+    /// Run a search against every peer concurrently, attributing each result
+    /// to the peer it came from. Peers that are down or exceed the timeout
+    /// are returned by name instead of failing the whole search.
+    pub async fn search(
+        &self,
+        query: &str,
+        limit: usize,
+        filter: Option<&Filter>,
+        hybrid: Option<&HybridConfig>,
+        boosts: &[Boost],
+        min_score: Option<f32>,
+    ) -> (Vec<SearchResult>, Vec<String>) {
+        let peers = self.list().await;
+
+        let requests = peers.iter().map(|peer| async move {
+            let url = format!("{}/search", peer.url.trim_end_matches('/'));
+            let response = self
+                .client
+                .post(&url)
+                .json(&serde_json::json!({
+                    "query": query,
+                    "limit": limit,
+                    "filter": filter,
+                    "hybrid": hybrid,
+                    "boosts": boosts,
+                    "min_score": min_score,
+                }))
+                .send()
+                .await
+                .and_then(|response| response.error_for_status());
+
+            match response {
+                Ok(response) => response.json::<RemoteSearchResponse>().await,
+                Err(e) => Err(e),
+            }
+        });
+
+        let mut results = Vec::new();
+        let mut failed = Vec::new();
+        for (peer, response) in peers.iter().zip(join_all(requests).await) {
+            match response {
+                Ok(response) => {
+                    results.extend(response.results.into_iter().map(|mut result| {
+                        result.source = Some(peer.name.clone());
+                        result
+                    }));
+                }
+                Err(e) => {
+                    warn!("Federated search to {} failed: {}", peer.name, e);
+                    failed.push(peer.name.clone());
+                }
+            }
+        }
+
+        (results, failed)
+    }
+
+    fn save(&self, peers: &BTreeMap<String, Peer>) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&self.path, serde_json::to_string_pretty(peers)?)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,133 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+
+/// A relevance judgement recorded by a client for one search result.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct FeedbackEvent {
+    pub query: String,
+    pub id: String,
+    pub relevant: bool,
+}
+
+/// A training example in the (anchor, positive, negative) layout used by
+/// sentence-transformers' `MultipleNegativesRankingLoss` and `TripletLoss`.
+#[derive(Serialize)]
+pub struct Triplet {
+    pub anchor: String,
+    pub positive: String,
+    pub negative: String,
+}
+
+pub struct FeedbackLog {
+    events: RwLock<Vec<FeedbackEvent>>,
+}
+
+impl FeedbackLog {
+    /// A log holding judgements recorded earlier, e.g. by a previous run.
+    pub fn with_events(events: Vec<FeedbackEvent>) -> Self {
+        Self {
+            events: RwLock::new(events),
+        }
+    }
+
+    pub async fn record(&self, event: FeedbackEvent) -> Result<()> {
+        let mut events = self.events.write().unwrap();
+        events.push(event);
+        Ok(())
+    }
+
+    /// Pair every relevant result with every irrelevant result for the same
+    /// query, returning `(query, positive_id, negative_id)` tuples.
+    pub async fn triplet_ids(&self) -> Vec<(String, String, String)> {
+        let mut triplets = Vec::new();
+        for (query, results) in self.judgements() {
+            let positives = results.iter().filter(|(_, &relevant)| relevant);
+            for (positive, _) in positives {
+                let negatives = results.iter().filter(|(_, &relevant)| !relevant);
+                for (negative, _) in negatives {
+                    triplets.push((query.clone(), positive.clone(), negative.clone()));
+                }
+            }
+        }
+
+        triplets
+    }
+
+    /// Queries with at least one relevant result, mapped to those result ids.
+    /// This is the test set used to evaluate retrieval quality.
+    pub async fn relevant_ids(&self) -> Vec<(String, Vec<String>)> {
+        self.judgements()
+            .into_iter()
+            .map(|(query, results)| {
+                let relevant = results
+                    .into_iter()
+                    .filter(|(_, relevant)| *relevant)
+                    .map(|(id, _)| id)
+                    .collect::<Vec<_>>();
+                (query, relevant)
+            })
+            .filter(|(_, relevant)| !relevant.is_empty())
+            .collect()
+    }
+
+    /// Judgements grouped by query then result id. When a result was judged
+    /// more than once, the latest judgement wins.
+    fn judgements(&self) -> BTreeMap<String, BTreeMap<String, bool>> {
+        let events = self.events.read().unwrap();
+
+        let mut judgements: BTreeMap<String, BTreeMap<String, bool>> = BTreeMap::new();
+        for event in events.iter() {
+            judgements
+                .entry(event.query.clone())
+                .or_default()
+                .insert(event.id.clone(), event.relevant);
+        }
+
+        judgements
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(query: &str, id: &str, relevant: bool) -> FeedbackEvent {
+        FeedbackEvent {
+            query: query.to_string(),
+            id: id.to_string(),
+            relevant,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_triplet_ids() {
+        let log = FeedbackLog::with_events(Vec::new());
+        log.record(event("q", "a", true)).await.unwrap();
+        log.record(event("q", "b", false)).await.unwrap();
+        log.record(event("q", "c", true)).await.unwrap();
+        // Later judgement overrides the earlier one
+        log.record(event("q", "c", false)).await.unwrap();
+        // No negatives for this query, so no triplets
+        log.record(event("other", "a", true)).await.unwrap();
+
+        let triplets = log.triplet_ids().await;
+        assert_eq!(
+            triplets,
+            vec![
+                ("q".to_string(), "a".to_string(), "b".to_string()),
+                ("q".to_string(), "a".to_string(), "c".to_string()),
+            ]
+        );
+
+        let relevant = log.relevant_ids().await;
+        assert_eq!(
+            relevant,
+            vec![
+                ("other".to_string(), vec!["a".to_string()]),
+                ("q".to_string(), vec!["a".to_string()]),
+            ]
+        );
+    }
+}
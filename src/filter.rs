@@ -0,0 +1,267 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::cmp::Ordering;
+
+/// A condition on document metadata, written as a JSON object mapping
+/// field paths to values, e.g. `{"tag": "systematics", "author.name": "Jo"}`.
+///
+/// Every field must match. Dotted paths reach into nested objects, and a
+/// field holding an array matches if any element does, so `{"tags": "x"}`
+/// finds documents tagged `x`. A field may map to an operator object
+/// instead of a value (`{"year": {"$gte": 2020}}`), and `$and`, `$or`, and
+/// `$not` combine filters.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "Value", into = "Value")]
+pub enum Filter {
+    And(Vec<Filter>),
+    Or(Vec<Filter>),
+    Not(Box<Filter>),
+    Field { path: String, condition: Condition },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Condition {
+    Eq(Value),
+    In(Vec<Value>),
+    Gt(Value),
+    Gte(Value),
+    Lt(Value),
+    Lte(Value),
+}
+
+impl TryFrom<Value> for Filter {
+    type Error = String;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        match value {
+            Value::Object(fields) => parse_object(fields),
+            other => Err(format!("Filter must be an object, got {}", other)),
+        }
+    }
+}
+
+fn parse_object(fields: Map<String, Value>) -> Result<Filter, String> {
+    let mut filters = Vec::new();
+    for (key, value) in fields {
+        filters.push(match key.as_str() {
+            "$and" => Filter::And(parse_list(&key, value)?),
+            "$or" => Filter::Or(parse_list(&key, value)?),
+            "$not" => Filter::Not(Box::new(Filter::try_from(value)?)),
+            op if op.starts_with('$') => return Err(format!("Unknown filter operator {}", op)),
+            _ => parse_field(key, value)?,
+        });
+    }
+
+    Ok(match filters.len() {
+        1 => filters.remove(0),
+        _ => Filter::And(filters),
+    })
+}
+
+fn parse_list(op: &str, value: Value) -> Result<Vec<Filter>, String> {
+    match value {
+        Value::Array(filters) => filters.into_iter().map(Filter::try_from).collect(),
+        other => Err(format!("{} expects an array of filters, got {}", op, other)),
+    }
+}
+
+/// Parse `path: value` or `path: {"$op": operand, ...}`.
+fn parse_field(path: String, value: Value) -> Result<Filter, String> {
+    let operators = match value {
+        Value::Object(ops) if ops.keys().any(|key| key.starts_with('$')) => ops,
+        value => {
+            return Ok(Filter::Field {
+                path,
+                condition: Condition::Eq(value),
+            })
+        }
+    };
+
+    let mut filters = Vec::new();
+    for (op, operand) in operators {
+        let condition = match op.as_str() {
+            "$eq" => Condition::Eq(operand),
+            "$ne" => {
+                // Negating equality means a missing field also matches
+                filters.push(Filter::Not(Box::new(Filter::Field {
+                    path: path.clone(),
+                    condition: Condition::Eq(operand),
+                })));
+                continue;
+            }
+            "$in" => match operand {
+                Value::Array(values) => Condition::In(values),
+                other => return Err(format!("$in expects an array, got {}", other)),
+            },
+            "$gt" => Condition::Gt(operand),
+            "$gte" => Condition::Gte(operand),
+            "$lt" => Condition::Lt(operand),
+            "$lte" => Condition::Lte(operand),
+            _ => {
+                return Err(format!(
+                    "Unknown operator {} for field {:?} (to match an object literally, use $eq)",
+                    op, path
+                ))
+            }
+        };
+        filters.push(Filter::Field {
+            path: path.clone(),
+            condition,
+        });
+    }
+
+    Ok(match filters.len() {
+        1 => filters.remove(0),
+        _ => Filter::And(filters),
+    })
+}
+
+impl From<Filter> for Value {
+    fn from(filter: Filter) -> Self {
+        match filter {
+            Filter::And(filters) => json!({ "$and": filters }),
+            Filter::Or(filters) => json!({ "$or": filters }),
+            Filter::Not(filter) => json!({ "$not": *filter }),
+            Filter::Field { path, condition } => {
+                let (op, operand) = match condition {
+                    Condition::Eq(value) => ("$eq", value),
+                    Condition::In(values) => ("$in", Value::Array(values)),
+                    Condition::Gt(value) => ("$gt", value),
+                    Condition::Gte(value) => ("$gte", value),
+                    Condition::Lt(value) => ("$lt", value),
+                    Condition::Lte(value) => ("$lte", value),
+                };
+                json!({ path: { op: operand } })
+            }
+        }
+    }
+}
+
+impl Filter {
+    /// Whether a document with this metadata passes the filter. Documents
+    /// without metadata only pass filters with no conditions.
+    pub fn matches(&self, metadata: Option<&Value>) -> bool {
+        match self {
+            Filter::And(filters) => filters.iter().all(|filter| filter.matches(metadata)),
+            Filter::Or(filters) => filters.iter().any(|filter| filter.matches(metadata)),
+            Filter::Not(filter) => !filter.matches(metadata),
+            Filter::Field { path, condition } => {
+                let Some(value) = metadata.and_then(|metadata| lookup(metadata, path)) else {
+                    return false;
+                };
+
+                condition.matches(value)
+                    || value
+                        .as_array()
+                        .is_some_and(|values| values.iter().any(|value| condition.matches(value)))
+            }
+        }
+    }
+}
+
+impl Condition {
+    fn matches(&self, value: &Value) -> bool {
+        match self {
+            Condition::Eq(expected) => values_equal(value, expected),
+            Condition::In(options) => options.iter().any(|option| values_equal(value, option)),
+            Condition::Gt(bound) => compare(value, bound) == Some(Ordering::Greater),
+            Condition::Gte(bound) => {
+                matches!(
+                    compare(value, bound),
+                    Some(Ordering::Greater | Ordering::Equal)
+                )
+            }
+            Condition::Lt(bound) => compare(value, bound) == Some(Ordering::Less),
+            Condition::Lte(bound) => {
+                matches!(
+                    compare(value, bound),
+                    Some(Ordering::Less | Ordering::Equal)
+                )
+            }
+        }
+    }
+}
+
+/// Follow a dotted path through nested objects.
+pub(crate) fn lookup<'a>(metadata: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('.')
+        .try_fold(metadata, |value, key| value.as_object()?.get(key))
+}
+
+/// JSON equality that treats `1` and `1.0` as the same number.
+fn values_equal(a: &Value, b: &Value) -> bool {
+    match (a.as_f64(), b.as_f64()) {
+        (Some(a), Some(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+/// Order numbers numerically and strings lexically, which also orders ISO
+/// 8601 dates correctly. Other combinations don't compare.
+fn compare(a: &Value, b: &Value) -> Option<Ordering> {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => a.as_f64()?.partial_cmp(&b.as_f64()?),
+        (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(value: Value) -> Filter {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn test_equality_filter() {
+        let metadata = json!({
+            "tags": ["systematics", "notes"],
+            "author": { "name": "Jo" },
+            "year": 2024
+        });
+
+        assert!(filter(json!({})).matches(Some(&metadata)));
+        assert!(filter(json!({ "tags": "systematics" })).matches(Some(&metadata)));
+        assert!(filter(json!({ "author.name": "Jo", "year": 2024.0 })).matches(Some(&metadata)));
+        assert!(!filter(json!({ "tags": "other" })).matches(Some(&metadata)));
+        assert!(!filter(json!({ "missing": 1 })).matches(Some(&metadata)));
+        assert!(!filter(json!({ "tags": "systematics" })).matches(None));
+
+        assert!(serde_json::from_value::<Filter>(json!(["tags"])).is_err());
+    }
+
+    #[test]
+    fn test_operators_and_composition() {
+        let metadata = json!({
+            "tags": ["systematics"],
+            "created": "2024-03-01",
+            "words": 850
+        });
+        let matches = |value: Value| filter(value).matches(Some(&metadata));
+
+        assert!(matches(
+            json!({ "created": { "$gte": "2024-01-01", "$lt": "2025-01-01" } })
+        ));
+        assert!(!matches(json!({ "words": { "$gt": 1000 } })));
+        assert!(matches(
+            json!({ "tags": { "$in": ["draft", "systematics"] } })
+        ));
+        assert!(matches(json!({ "status": { "$ne": "archived" } })));
+        assert!(!matches(json!({ "tags": { "$ne": "systematics" } })));
+        assert!(matches(json!({
+            "$or": [{ "words": { "$lt": 100 } }, { "tags": "systematics" }]
+        })));
+        assert!(!matches(json!({
+            "$and": [{ "words": { "$lt": 100 } }, { "tags": "systematics" }]
+        })));
+
+        assert!(serde_json::from_value::<Filter>(json!({ "year": { "$near": 1 } })).is_err());
+
+        // Filters survive a round trip, e.g. when forwarded to federation peers
+        let original = filter(json!({ "$or": [{ "a": 1 }, { "b": { "$in": [2, 3] } }] }));
+        let round_tripped = filter(serde_json::to_value(&original).unwrap());
+        assert_eq!(original, round_tripped);
+    }
+}
@@ -0,0 +1,260 @@
+//! Runtime feature flags gating experimental behaviours, so a change to
+//! ranking or chunking can be tried on one collection or client before
+//! everyone gets it.
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::RwLock;
+
+/// An experimental behaviour that can be switched on at runtime.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Flag {
+    /// Chunked documents score by their best chunks together rather than
+    /// the best one alone
+    ChunkCoverage,
+    /// Chunk windows end at a sentence boundary where one is near
+    SentenceChunks,
+}
+
+impl Flag {
+    pub const ALL: [Flag; 2] = [Flag::ChunkCoverage, Flag::SentenceChunks];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Flag::ChunkCoverage => "chunk_coverage",
+            Flag::SentenceChunks => "sentence_chunks",
+        }
+    }
+
+    pub fn description(self) -> &'static str {
+        match self {
+            Flag::ChunkCoverage => {
+                "Search scores a chunked document by blending its best chunk with its next \
+                 best, so documents that match throughout outrank a passing mention"
+            }
+            Flag::SentenceChunks => {
+                "Indexing ends each chunk at the last sentence boundary in its second half, \
+                 so chunks hold whole sentences"
+            }
+        }
+    }
+}
+
+impl FromStr for Flag {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Flag::ALL
+            .into_iter()
+            .find(|flag| flag.name() == s.trim().to_ascii_lowercase())
+            .ok_or_else(|| {
+                let known: Vec<&str> = Flag::ALL.iter().map(|flag| flag.name()).collect();
+                anyhow::anyhow!(
+                    "Unknown feature flag {:?}, expected one of {}",
+                    s,
+                    known.join(", ")
+                )
+            })
+    }
+}
+
+/// Where a flag is on: everywhere or nowhere by default, overridden for
+/// particular collections, and those overridden in turn for particular
+/// clients.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct FlagRule {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub collections: BTreeMap<String, bool>,
+    /// By the address requests come from
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub clients: BTreeMap<String, bool>,
+}
+
+/// A flag's rule as saved. The default is only saved once changed at
+/// runtime, so until then it follows `SYSTEMATICS_FLAGS`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct SavedRule {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    enabled: Option<bool>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    collections: BTreeMap<String, bool>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    clients: BTreeMap<String, bool>,
+}
+
+impl SavedRule {
+    fn is_enabled(&self, default: bool, collection: &str, client: Option<&str>) -> bool {
+        client
+            .and_then(|client| self.clients.get(client))
+            .or_else(|| self.collections.get(collection))
+            .copied()
+            .or(self.enabled)
+            .unwrap_or(default)
+    }
+
+    fn rule(&self, default: bool) -> FlagRule {
+        FlagRule {
+            enabled: self.enabled.unwrap_or(default),
+            collections: self.collections.clone(),
+            clients: self.clients.clone(),
+        }
+    }
+}
+
+/// A change to one flag's rule.
+#[derive(Deserialize)]
+pub struct FlagUpdate {
+    pub flag: Flag,
+    /// On or off for the scope below. `null` removes a collection's or
+    /// client's override, so it follows the default again.
+    pub enabled: Option<bool>,
+    /// Change the flag for this collection only
+    pub collection: Option<String>,
+    /// Change the flag for requests from this address only
+    pub client: Option<String>,
+}
+
+/// Every flag's rule, persisted as JSON so changes survive restarts.
+pub struct FeatureFlags {
+    path: PathBuf,
+    defaults: Vec<Flag>,
+    rules: RwLock<BTreeMap<Flag, SavedRule>>,
+}
+
+impl FeatureFlags {
+    /// Load the rules saved at `path`. Flags whose default was never
+    /// changed at runtime are on by default if listed in `defaults`, else
+    /// off, whatever overrides were saved for them.
+    pub fn open(path: PathBuf, defaults: &[Flag]) -> Result<Self> {
+        let rules = if path.exists() {
+            serde_json::from_str(&fs::read_to_string(&path)?)?
+        } else {
+            BTreeMap::new()
+        };
+
+        Ok(Self {
+            path,
+            defaults: defaults.to_vec(),
+            rules: RwLock::new(rules),
+        })
+    }
+
+    /// Whether `flag` is on for a request to `collection` from `client`.
+    pub fn is_enabled(&self, flag: Flag, collection: &str, client: Option<&str>) -> bool {
+        let default = self.defaults.contains(&flag);
+        match self.rules.read().unwrap().get(&flag) {
+            Some(rule) => rule.is_enabled(default, collection, client),
+            None => default,
+        }
+    }
+
+    pub fn rules(&self) -> BTreeMap<Flag, FlagRule> {
+        let rules = self.rules.read().unwrap();
+        Flag::ALL
+            .into_iter()
+            .map(|flag| {
+                let rule = rules.get(&flag).cloned().unwrap_or_default();
+                (flag, rule.rule(self.defaults.contains(&flag)))
+            })
+            .collect()
+    }
+
+    /// Apply `update` and save, returning the flag's new rule.
+    pub fn update(&self, update: FlagUpdate) -> Result<FlagRule> {
+        let mut rules = self.rules.write().unwrap();
+        let rule = rules.entry(update.flag).or_default();
+        let overrides = match (update.collection, update.client) {
+            (Some(_), Some(_)) => anyhow::bail!("Set either collection or client, not both"),
+            (Some(collection), None) => Some((&mut rule.collections, collection)),
+            (None, Some(client)) => Some((&mut rule.clients, client)),
+            (None, None) => None,
+        };
+        match (overrides, update.enabled) {
+            (Some((overrides, key)), Some(enabled)) => {
+                overrides.insert(key, enabled);
+            }
+            (Some((overrides, key)), None) => {
+                overrides.remove(&key);
+            }
+            (None, Some(enabled)) => rule.enabled = Some(enabled),
+            (None, None) => {
+                anyhow::bail!("enabled is required unless a collection or client is given")
+            }
+        }
+        let rule = rule.rule(self.defaults.contains(&update.flag));
+
+        // Written aside and renamed over, so a crash mid-write can't leave
+        // a truncated file that fails the next start
+        let tmp = self.path.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_string_pretty(&*rules)?)?;
+        fs::rename(&tmp, &self.path)?;
+        Ok(rule)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_client_overrides_collection_overrides_default() {
+        let dir = std::env::temp_dir().join(format!("flags-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("flags.json");
+        let _ = fs::remove_file(&path);
+        let flags = FeatureFlags::open(path.clone(), &[Flag::SentenceChunks]).unwrap();
+        let update = |enabled, collection: Option<&str>, client: Option<&str>| FlagUpdate {
+            flag: Flag::ChunkCoverage,
+            enabled,
+            collection: collection.map(String::from),
+            client: client.map(String::from),
+        };
+
+        assert!(flags.is_enabled(Flag::SentenceChunks, "default", None));
+        assert!(!flags.is_enabled(Flag::ChunkCoverage, "default", None));
+
+        flags
+            .update(update(Some(true), Some("work"), None))
+            .unwrap();
+        flags
+            .update(update(Some(false), None, Some("10.0.0.7")))
+            .unwrap();
+        assert!(flags.is_enabled(Flag::ChunkCoverage, "work", None));
+        assert!(flags.is_enabled(Flag::ChunkCoverage, "work", Some("10.0.0.8")));
+        assert!(!flags.is_enabled(Flag::ChunkCoverage, "work", Some("10.0.0.7")));
+        assert!(!flags.is_enabled(Flag::ChunkCoverage, "default", None));
+
+        // Changes survive a restart, and removing an override falls back.
+        // Defaults never changed at runtime follow the new ones
+        let flags = FeatureFlags::open(path.clone(), &[Flag::ChunkCoverage]).unwrap();
+        assert!(flags.is_enabled(Flag::ChunkCoverage, "default", None));
+        assert!(!flags.is_enabled(Flag::ChunkCoverage, "work", Some("10.0.0.7")));
+        assert!(!flags.is_enabled(Flag::SentenceChunks, "default", None));
+        flags.update(update(Some(false), None, None)).unwrap();
+        let flags = FeatureFlags::open(path.clone(), &[Flag::ChunkCoverage]).unwrap();
+        assert!(!flags.is_enabled(Flag::ChunkCoverage, "default", None));
+        assert!(!flags.rules()[&Flag::ChunkCoverage].enabled);
+        assert!(!path.with_extension("json.tmp").exists());
+        assert!(flags.is_enabled(Flag::ChunkCoverage, "work", None));
+        flags.update(update(None, Some("work"), None)).unwrap();
+        assert!(!flags.is_enabled(Flag::ChunkCoverage, "work", None));
+        assert!(flags.update(update(None, None, None)).is_err());
+        assert!(flags
+            .update(update(Some(true), Some("work"), Some("10.0.0.7")))
+            .is_err());
+
+        assert_eq!(
+            "sentence_chunks".parse::<Flag>().unwrap(),
+            Flag::SentenceChunks
+        );
+        assert!("new_ranking".parse::<Flag>().is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,307 @@
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tonic::service::Interceptor;
+use tonic::transport::server::TcpIncoming;
+use tonic::transport::Server;
+use tonic::{Request, Response, Status};
+use tracing::{error, info, warn};
+
+use crate::codec::{Body, Format};
+use crate::error::AppError;
+use crate::index::UpsertStatus;
+use crate::ratelimit::{self, RateLimiter};
+use crate::server::{client_address, AppState, CollectionParams};
+
+pub mod proto {
+    tonic::include_proto!("systematics.v1");
+}
+
+use proto::embeddings_server::{Embeddings, EmbeddingsServer};
+
+/// The Embed, Index, Search, and Delete RPCs of `proto/systematics.proto`,
+/// each running the HTTP handler it mirrors against the same state.
+struct EmbeddingsService {
+    state: AppState,
+}
+
+#[tonic::async_trait]
+impl Embeddings for EmbeddingsService {
+    async fn embed(
+        &self,
+        request: Request<proto::EmbedRequest>,
+    ) -> Result<Response<proto::EmbedResponse>, Status> {
+        let _permit = self.state.limits.embed.try_acquire()?;
+        let request = request.into_inner();
+        let response = crate::server::embed(
+            Format::Json,
+            State(self.state.clone()),
+            Body(crate::server::EmbedRequest {
+                text: request.text,
+                model: request.model,
+                strict: request.strict,
+            }),
+        )
+        .await?
+        .into_inner();
+
+        Ok(Response::new(proto::EmbedResponse {
+            embedding: response.embedding,
+            dimensions: response.dimensions as u32,
+            model_fingerprint: response.model_fingerprint,
+            token_count: response.token_count as u32,
+            truncated: response.truncated,
+        }))
+    }
+
+    async fn index(
+        &self,
+        request: Request<proto::IndexRequest>,
+    ) -> Result<Response<proto::IndexResponse>, Status> {
+        let _permit = self.state.limits.index.try_acquire()?;
+        let client = client_address(request.remote_addr());
+        let request = request.into_inner();
+        let response = crate::server::index_document(
+            Format::Json,
+            State(self.state.clone()),
+            None,
+            Body(crate::server::IndexRequest {
+                id: request.id,
+                collection: request.collection,
+                text: request.text,
+                metadata: parse_json("metadata_json", request.metadata_json)?,
+                document_instruction: request.document_instruction,
+                client,
+            }),
+        )
+        .await?
+        .into_inner();
+
+        Ok(Response::new(proto::IndexResponse {
+            id: response.id,
+            status: match response.status {
+                UpsertStatus::Created => "created",
+                UpsertStatus::Updated => "updated",
+            }
+            .to_string(),
+            version: response.version,
+        }))
+    }
+
+    async fn search(
+        &self,
+        request: Request<proto::SearchRequest>,
+    ) -> Result<Response<proto::SearchResponse>, Status> {
+        let _permit = self.state.limits.search.try_acquire()?;
+        let client = client_address(request.remote_addr());
+        let request = request.into_inner();
+        let payload = crate::server::SearchRequest {
+            query: request.query,
+            collection: request.collection,
+            collections: request.collections,
+            weights: Default::default(),
+            limit: request.limit.map(|limit| limit as usize),
+            offset: request.offset as usize,
+            explain: false,
+            filter: parse_json("filter_json", request.filter_json)?,
+            hybrid: parse_json("hybrid_json", request.hybrid_json)?,
+            boosts: Vec::new(),
+            rerank: request.rerank,
+            max_text_length: request.max_text_length.map(|max| max as usize),
+            mmr: request.mmr,
+            lambda: request.lambda,
+            min_score: request.min_score,
+            timeout_ms: request.timeout_ms.map(u64::from),
+            query_instruction: request.query_instruction,
+            client,
+        };
+        let response = crate::server::run_logged_search(&self.state, payload, "", false).await?;
+
+        Ok(Response::new(proto::SearchResponse {
+            results: response
+                .results
+                .into_iter()
+                .map(|result| proto::SearchResult {
+                    id: result.id,
+                    score: result.score,
+                    text: result.text,
+                    passage: result.passage,
+                    truncated: result.truncated,
+                    collection: result.collection,
+                    obsidian_uri: result.obsidian_uri,
+                })
+                .collect(),
+            model_fingerprint: response.model_fingerprint,
+            total_candidates: response.total_candidates as u64,
+            next_offset: response.next_offset.map(|offset| offset as u64),
+            partial: response.partial,
+        }))
+    }
+
+    async fn delete(
+        &self,
+        request: Request<proto::DeleteRequest>,
+    ) -> Result<Response<proto::DeleteResponse>, Status> {
+        let request = request.into_inner();
+        let response = crate::server::delete_index_entry(
+            Format::Json,
+            State(self.state.clone()),
+            Path(request.id),
+            Query(CollectionParams {
+                collection: request.collection,
+            }),
+        )
+        .await?
+        .into_inner();
+
+        Ok(Response::new(proto::DeleteResponse { id: response.id }))
+    }
+}
+
+/// Parse an optional field carrying JSON in the HTTP API's syntax.
+fn parse_json<T: serde::de::DeserializeOwned>(
+    field: &str,
+    json: Option<String>,
+) -> Result<Option<T>, AppError> {
+    json.map(|json| serde_json::from_str(&json))
+        .transpose()
+        .map_err(|e| AppError::BadRequest(format!("Invalid {}: {}", field, e)))
+}
+
+/// Lets only loopback clients through in hardened mode, as over HTTP.
+#[derive(Clone)]
+struct LoopbackOnly {
+    hardened: bool,
+}
+
+impl Interceptor for LoopbackOnly {
+    fn call(&mut self, request: Request<()>) -> Result<Request<()>, Status> {
+        let peer = request.remote_addr();
+        if self.hardened && !peer.is_some_and(|peer| peer.ip().to_canonical().is_loopback()) {
+            warn!("Rejected gRPC request from non-loopback address {:?}", peer);
+            return Err(Status::permission_denied(
+                "Only local connections are accepted",
+            ));
+        }
+        Ok(request)
+    }
+}
+
+/// Takes a token for every call from its peer's rate limit bucket, as
+/// over HTTP.
+#[derive(Clone)]
+struct RateLimited {
+    limiter: Option<Arc<RateLimiter>>,
+}
+
+impl Interceptor for RateLimited {
+    fn call(&mut self, request: Request<()>) -> Result<Request<()>, Status> {
+        let Some(limiter) = &self.limiter else {
+            return Ok(request);
+        };
+        let peer = request.remote_addr().map(|peer| peer.ip());
+        match limiter.check(peer) {
+            Ok(()) => Ok(request),
+            Err(wait) => {
+                let retry_after = ratelimit::retry_after(wait);
+                let mut status = Status::from(AppError::TooManyRequests(format!(
+                    "Too many requests; retry in {} s",
+                    retry_after
+                )));
+                status
+                    .metadata_mut()
+                    .insert("retry-after", retry_after.into());
+                Err(status)
+            }
+        }
+    }
+}
+
+/// The gRPC status matching an error's HTTP status, with the error's code
+/// in the `error-code` metadata.
+impl From<AppError> for Status {
+    fn from(err: AppError) -> Self {
+        let code = err.code();
+        let message = err.message().to_string();
+        let mut status = match code.status() {
+            StatusCode::NOT_FOUND => Status::not_found(message),
+            StatusCode::BAD_REQUEST
+            | StatusCode::PAYLOAD_TOO_LARGE
+            | StatusCode::UNSUPPORTED_MEDIA_TYPE
+            | StatusCode::UNPROCESSABLE_ENTITY => Status::invalid_argument(message),
+            StatusCode::CONFLICT => Status::failed_precondition(message),
+            StatusCode::FORBIDDEN => Status::permission_denied(message),
+            StatusCode::TOO_MANY_REQUESTS | StatusCode::INSUFFICIENT_STORAGE => {
+                Status::resource_exhausted(message)
+            }
+            StatusCode::SERVICE_UNAVAILABLE => Status::unavailable(message),
+            _ => Status::internal(message),
+        };
+        let code = serde_json::to_value(code).expect("error codes serialize");
+        if let Some(value) = code.as_str().and_then(|code| code.parse().ok()) {
+            status.metadata_mut().insert("error-code", value);
+        }
+        status
+    }
+}
+
+/// Bind the gRPC API to `addr` and serve it in the background.
+pub fn spawn(state: AppState, addr: SocketAddr) -> anyhow::Result<()> {
+    let incoming = TcpIncoming::new(addr, true, None)
+        .map_err(|e| anyhow::anyhow!("Failed to bind the gRPC port {}: {}", addr, e))?;
+    let mut loopback = LoopbackOnly {
+        hardened: state.config.hardened,
+    };
+    let mut limited = RateLimited {
+        limiter: state.rate_limiter.clone(),
+    };
+    let service = EmbeddingsServer::with_interceptor(
+        EmbeddingsService { state },
+        move |request: Request<()>| {
+            loopback
+                .call(request)
+                .and_then(|request| limited.call(request))
+        },
+    );
+
+    info!("gRPC server listening on {}", addr);
+    tokio::spawn(async move {
+        if let Err(e) = Server::builder()
+            .add_service(service)
+            .serve_with_incoming(incoming)
+            .await
+        {
+            error!("gRPC server stopped: {}", e);
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::IndexError;
+
+    #[test]
+    fn test_hardened_mode_requires_a_loopback_peer() {
+        assert!(LoopbackOnly { hardened: false }
+            .call(Request::new(()))
+            .is_ok());
+        // Without a known peer address there's nothing to vouch for it
+        let err = LoopbackOnly { hardened: true }
+            .call(Request::new(()))
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::PermissionDenied);
+
+        let status = Status::from(AppError::NotFound("Document not found: a".to_string()));
+        assert_eq!(status.code(), tonic::Code::NotFound);
+        assert_eq!(status.message(), "Document not found: a");
+        let status = Status::from(AppError::from(IndexError::DimensionMismatch {
+            expected: 384,
+            found: 768,
+        }));
+        assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+        assert_eq!(status.metadata()["error-code"], "dimension_mismatch");
+    }
+}
@@ -0,0 +1,958 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::io;
+use std::time::{Duration, Instant};
+
+use crate::config::HnswConfig;
+use crate::index::{IndexedDocument, Issue, IssueKind};
+use crate::simd;
+use crate::vector::{Query, StoredVector};
+use crate::warm::{self, Pinned};
+
+type Documents = HashMap<String, IndexedDocument>;
+
+/// Bounds of the `ef_search` values [`tune`] tries.
+const MIN_TUNED_EF: usize = 16;
+const MAX_TUNED_EF: usize = 1024;
+
+/// Recall and latency of graph searches at one `ef_search`.
+#[derive(Serialize, Clone, Debug)]
+pub struct EfTrial {
+    pub ef_search: usize,
+    /// Share of the exact top results the graph found
+    pub recall: f32,
+    /// Mean time per search
+    pub latency_ms: f64,
+}
+
+/// Outcome of [`tune`]: the `ef_search` chosen and every value tried.
+#[derive(Serialize, Clone, Debug)]
+pub struct EfTuning {
+    pub ef_search: usize,
+    pub recall: f32,
+    pub latency_ms: f64,
+    /// Sampled queries recall was measured on
+    pub queries: usize,
+    pub tuned_at: DateTime<Utc>,
+    pub trials: Vec<EfTrial>,
+}
+
+impl EfTuning {
+    /// The `ef_search` to use when a search has only `time` left: the tuned
+    /// value if it fits, else the largest smaller value tried that does, or
+    /// the smallest tried.
+    pub fn ef_within(&self, time: Duration) -> usize {
+        let time_ms = time.as_secs_f64() * 1000.0;
+        if self.latency_ms <= time_ms {
+            return self.ef_search;
+        }
+        self.trials
+            .iter()
+            .filter(|trial| trial.ef_search < self.ef_search && trial.latency_ms <= time_ms)
+            .map(|trial| trial.ef_search)
+            .max()
+            .unwrap_or(self.trials[0].ef_search)
+    }
+}
+
+/// A stored document used as a query for tuning, with the ids of its exact
+/// top results as ground truth. The document itself is left out of them,
+/// since it would always be found.
+pub struct TuneQuery {
+    id: String,
+    vector: Vec<f32>,
+    truth: HashSet<String>,
+}
+
+impl TuneQuery {
+    /// Take document `id` of `docs` as a query, scanning every other
+    /// document for its exact top `k`. `None` if there is no such document.
+    pub fn new(id: &str, docs: &Documents, k: usize) -> Option<Self> {
+        let vector = docs.get(id)?.embedding.to_f32().into_owned();
+        let mut scored: Vec<(f32, &str)> = docs
+            .values()
+            .filter(|doc| doc.id != id)
+            .map(|doc| (doc.embedding.cosine_similarity(&vector), doc.id.as_str()))
+            .collect();
+        scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+        let truth = scored
+            .into_iter()
+            .take(k)
+            .map(|(_, id)| id.to_string())
+            .collect();
+        Some(Self {
+            id: id.to_string(),
+            vector,
+            truth,
+        })
+    }
+}
+
+/// Find the `ef_search` giving the best recall@`k` while a search takes no
+/// longer than `budget` on average, measuring each value with `trial`, e.g.
+/// [`Hnsw::trial`] over `queries` sampled queries. Doubles `ef_search` from
+/// the smallest value until the budget is exceeded or recall is perfect;
+/// if even the smallest is too slow, that is chosen.
+pub fn tune(
+    k: usize,
+    budget: Duration,
+    queries: usize,
+    mut trial: impl FnMut(usize) -> EfTrial,
+) -> EfTuning {
+    let mut trials: Vec<EfTrial> = Vec::new();
+    let mut ef = MIN_TUNED_EF.max(k);
+    let budget_ms = budget.as_secs_f64() * 1000.0;
+    loop {
+        let trial = trial(ef);
+        let done = trial.latency_ms > budget_ms || trial.recall >= 1.0 || ef >= MAX_TUNED_EF;
+        trials.push(trial);
+        if done {
+            break;
+        }
+        ef *= 2;
+    }
+
+    // Trials are in increasing ef, so the first with the best recall is
+    // the fastest of them
+    let chosen = trials
+        .iter()
+        .filter(|trial| trial.latency_ms <= budget_ms)
+        .fold(None, |best: Option<&EfTrial>, trial| match best {
+            Some(best) if best.recall >= trial.recall => Some(best),
+            _ => Some(trial),
+        })
+        .unwrap_or(&trials[0])
+        .clone();
+
+    EfTuning {
+        ef_search: chosen.ef_search,
+        recall: chosen.recall,
+        latency_ms: chosen.latency_ms,
+        queries,
+        tuned_at: Utc::now(),
+        trials,
+    }
+}
+
+/// A node paired with its similarity to the current query, ordered by
+/// similarity.
+#[derive(Clone, Copy, PartialEq)]
+struct Scored {
+    similarity: f32,
+    node: u32,
+}
+
+impl Eq for Scored {}
+
+impl Ord for Scored {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.similarity
+            .total_cmp(&other.similarity)
+            .then(self.node.cmp(&other.node))
+    }
+}
+
+impl PartialOrd for Scored {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+struct Node {
+    key: String,
+    /// Neighbours on each layer, from the bottom layer up to the node's level
+    links: Vec<Vec<u32>>,
+    /// Vector of a deleted or replaced document. The node stays in the graph
+    /// as a waypoint until the next rebuild but is never returned.
+    retired: Option<StoredVector>,
+}
+
+/// The entry layers, the layers above the bottom one that every search
+/// descends through, copied into memory locked into RAM so they can't be
+/// swapped out. Nodes are never removed from a graph, only retired, so a
+/// copy the graph has grown past still routes searches correctly, if less
+/// directly, until it is pinned again.
+struct EntryLayers {
+    /// Graph node of each entry node: every node above the bottom layer
+    nodes: Pinned<u32>,
+    /// Each entry node's vector, `dimensions` values apiece
+    vectors: Pinned<f32>,
+    dimensions: usize,
+    /// Where each entry node's first layer starts in `starts`
+    layers: Pinned<u32>,
+    /// Where the links of each entry node's layers start in `links`, from
+    /// layer 1 up, followed by where the last one ends
+    starts: Pinned<u32>,
+    /// Links, as positions in `nodes`
+    links: Pinned<u32>,
+    /// Position of the graph's entry point when copied, and its level
+    entry: u32,
+    top: usize,
+}
+
+impl EntryLayers {
+    fn bytes(&self) -> usize {
+        self.nodes.bytes()
+            + self.vectors.bytes()
+            + self.layers.bytes()
+            + self.starts.bytes()
+            + self.links.bytes()
+    }
+
+    fn similarity(&self, query: &[f32], node: u32) -> f32 {
+        let start = node as usize * self.dimensions;
+        simd::dot(query, &self.vectors[start..start + self.dimensions])
+    }
+
+    fn links(&self, node: u32, layer: usize) -> &[u32] {
+        let at = self.layers[node as usize] as usize + layer - 1;
+        &self.links[self.starts[at] as usize..self.starts[at + 1] as usize]
+    }
+
+    /// Greedily descend to the bottom layer, returning the graph node to
+    /// search it from.
+    fn descend(&self, query: &[f32]) -> u32 {
+        let mut node = self.entry;
+        let mut best = self.similarity(query, node);
+        for layer in (1..=self.top).rev() {
+            loop {
+                let closer = self
+                    .links(node, layer)
+                    .iter()
+                    .map(|&neighbour| (neighbour, self.similarity(query, neighbour)))
+                    .filter(|&(_, similarity)| similarity > best)
+                    .max_by(|a, b| a.1.total_cmp(&b.1));
+                let Some((neighbour, similarity)) = closer else {
+                    break;
+                };
+                node = neighbour;
+                best = similarity;
+            }
+        }
+        self.nodes[node as usize]
+    }
+}
+
+/// Hierarchical navigable small world graph over the documents of a
+/// [`VectorIndex`](crate::index::VectorIndex).
+///
+/// Nodes refer to documents by id rather than copying their vectors, so
+/// every call takes the document map the graph was built over. Vectors and
+/// queries are unit length, as the index stores them, and compared by dot
+/// product.
+pub struct Hnsw {
+    config: HnswConfig,
+    nodes: Vec<Node>,
+    live: HashMap<String, u32>,
+    entry: Option<u32>,
+    retired: usize,
+    rng: u64,
+    /// Nodes above the bottom layer
+    upper_nodes: usize,
+    /// The entry layers, locked into RAM by [`pin`](Self::pin), and how
+    /// many nodes they covered
+    pinned: Option<(EntryLayers, usize)>,
+}
+
+impl Hnsw {
+    pub fn new(config: HnswConfig) -> Self {
+        Self {
+            config,
+            nodes: Vec::new(),
+            live: HashMap::new(),
+            entry: None,
+            retired: 0,
+            rng: 0x9e37_79b9_7f4a_7c15,
+            upper_nodes: 0,
+            pinned: None,
+        }
+    }
+
+    /// Rough bytes held by the graph: node ids, links, and the vectors of
+    /// retired nodes, without allocator or hash table overhead.
+    pub fn size_bytes(&self) -> usize {
+        let nodes: usize = self
+            .nodes
+            .iter()
+            .map(|node| {
+                node.key.len()
+                    + node
+                        .links
+                        .iter()
+                        .map(|links| links.len() * 4)
+                        .sum::<usize>()
+                    + node.retired.as_ref().map_or(0, StoredVector::size_bytes)
+            })
+            .sum();
+        nodes + self.live.keys().map(|key| key.len() + 4).sum::<usize>()
+    }
+
+    /// Build a graph over every document in `docs`.
+    pub fn build(config: HnswConfig, docs: &Documents) -> Self {
+        let mut graph = Self::new(config);
+        for key in docs.keys() {
+            graph.insert(key, docs);
+        }
+        graph
+    }
+
+    /// Rebuild a graph from the links of [`export`](Self::export)ed nodes,
+    /// node `i` standing for `keys[i]`.
+    pub fn import(
+        config: HnswConfig,
+        keys: Vec<String>,
+        links: Vec<Vec<Vec<u32>>>,
+        entry: Option<u32>,
+    ) -> Self {
+        let mut graph = Self::new(config);
+        graph.live = keys
+            .iter()
+            .enumerate()
+            .map(|(node, key)| (key.clone(), node as u32))
+            .collect();
+        graph.nodes = keys
+            .into_iter()
+            .zip(links)
+            .map(|(key, links)| Node {
+                key,
+                links,
+                retired: None,
+            })
+            .collect();
+        graph.entry = entry;
+        graph.upper_nodes = graph
+            .nodes
+            .iter()
+            .filter(|node| node.links.len() > 1)
+            .count();
+        graph
+    }
+
+    /// Every node's links, in the order the nodes were inserted, and the
+    /// entry point. Retired nodes are included, so export a freshly built
+    /// graph.
+    pub fn export(&self) -> (Vec<&[Vec<u32>]>, Option<u32>) {
+        let links = self
+            .nodes
+            .iter()
+            .map(|node| node.links.as_slice())
+            .collect();
+        (links, self.entry)
+    }
+
+    /// Link the document `key`, which must already be in `docs`. Any
+    /// previous version must have been [`remove`](Self::remove)d first.
+    pub fn insert(&mut self, key: &str, docs: &Documents) {
+        let vector = docs[key].embedding.to_f32();
+        let query = Query::new(&vector);
+        let level = self.random_level();
+        let node = self.nodes.len() as u32;
+        self.nodes.push(Node {
+            key: key.to_string(),
+            links: vec![Vec::new(); level + 1],
+            retired: None,
+        });
+        self.live.insert(key.to_string(), node);
+        if level > 0 {
+            self.upper_nodes += 1;
+        }
+
+        let Some(mut entry) = self.entry else {
+            self.entry = Some(node);
+            return;
+        };
+
+        // Descend greedily through the layers above the new node
+        let top = self.level_of(entry);
+        for layer in (level + 1..=top).rev() {
+            entry = self.search_layer(&query, &[entry], 1, layer, docs)[0].node;
+        }
+
+        let mut entry_points = vec![entry];
+        for layer in (0..=level.min(top)).rev() {
+            let candidates = self.search_layer(
+                &query,
+                &entry_points,
+                self.config.ef_construction,
+                layer,
+                docs,
+            );
+            let neighbours = self.select_neighbours(&candidates, self.config.m, docs);
+            for &neighbour in &neighbours {
+                self.connect(neighbour, node, layer, docs);
+            }
+            self.nodes[node as usize].links[layer] = neighbours;
+            entry_points = candidates.iter().map(|c| c.node).collect();
+        }
+
+        if level > top {
+            self.entry = Some(node);
+        }
+    }
+
+    /// Retire the node for `key`, keeping `vector` so searches can still
+    /// route through it.
+    pub fn remove(&mut self, key: &str, vector: StoredVector) {
+        if let Some(node) = self.live.remove(key) {
+            self.nodes[node as usize].retired = Some(vector);
+            self.retired += 1;
+        }
+    }
+
+    /// Whether retired nodes outnumber live ones, making a rebuild worthwhile.
+    pub fn needs_rebuild(&self) -> bool {
+        self.retired > self.live.len()
+    }
+
+    /// Approximate `limit` most similar documents, best first, keeping at
+    /// least `ef` candidates while searching the bottom layer.
+    pub fn search(
+        &self,
+        query: &[f32],
+        limit: usize,
+        ef: usize,
+        docs: &Documents,
+    ) -> Vec<(&str, f32)> {
+        self.search_until(&Query::new(query), limit, ef, None, docs)
+            .0
+    }
+
+    /// [`search`](Self::search) that stops exploring the graph at
+    /// `deadline`, once it has `ef` candidates, returning the best found so
+    /// far. Also returns whether it stopped early.
+    pub fn search_until(
+        &self,
+        query: &Query,
+        limit: usize,
+        ef: usize,
+        deadline: Option<Instant>,
+        docs: &Documents,
+    ) -> (Vec<(&str, f32)>, bool) {
+        let Some(mut entry) = self.entry else {
+            return (Vec::new(), false);
+        };
+
+        match &self.pinned {
+            Some((layers, _)) if layers.dimensions == query.values().len() => {
+                entry = layers.descend(query.values());
+            }
+            _ => {
+                for layer in (1..=self.level_of(entry)).rev() {
+                    entry = self.search_layer(query, &[entry], 1, layer, docs)[0].node;
+                }
+            }
+        }
+
+        let (candidates, cut_short) =
+            self.search_layer_until(query, &[entry], ef.max(limit), 0, deadline, docs);
+        let found = candidates
+            .into_iter()
+            .map(|scored| (&self.nodes[scored.node as usize], scored.similarity))
+            .filter(|(node, _)| node.retired.is_none())
+            .take(limit)
+            .map(|(node, similarity)| (node.key.as_str(), similarity))
+            .collect();
+        (found, cut_short)
+    }
+
+    /// Recall@`k` and mean latency of graph searches at `ef` for
+    /// `queries`, against their exact top results. Each query's own
+    /// document is left out of what the graph finds, as it is of the truth.
+    pub fn trial(&self, queries: &[TuneQuery], k: usize, ef: usize, docs: &Documents) -> EfTrial {
+        let expected: usize = queries.iter().map(|query| query.truth.len()).sum();
+        let start = Instant::now();
+        let mut hits = 0;
+        for query in queries {
+            hits += self
+                .search(&query.vector, k + 1, ef, docs)
+                .into_iter()
+                .filter(|(id, _)| *id != query.id)
+                .take(k)
+                .filter(|(id, _)| query.truth.contains(*id))
+                .count();
+        }
+        let latency = start.elapsed() / queries.len().max(1) as u32;
+        EfTrial {
+            ef_search: ef,
+            recall: if expected == 0 {
+                1.0
+            } else {
+                hits as f32 / expected as f32
+            },
+            latency_ms: latency.as_secs_f64() * 1000.0,
+        }
+    }
+
+    /// The memory a graph search reads: every node's links on every layer
+    /// and its vector.
+    pub fn regions<'a>(&'a self, docs: &'a Documents) -> Vec<&'a [u8]> {
+        let mut regions = Vec::new();
+        for (i, node) in self.nodes.iter().enumerate() {
+            regions.extend(node.links.iter().map(|links| warm::bytes_of(links)));
+            regions.push(self.vector(i as u32, docs).as_bytes());
+        }
+        regions
+    }
+
+    /// Copy the entry layers into memory locked into RAM, which searches
+    /// then descend through, replacing any earlier copy. Returns the bytes
+    /// locked.
+    pub fn pin(&mut self, docs: &Documents) -> io::Result<usize> {
+        // Unlocked first, so the old copy doesn't count against the limit
+        self.pinned = None;
+        let Some(entry) = self.entry.filter(|&entry| self.level_of(entry) > 0) else {
+            return Ok(0);
+        };
+
+        let nodes: Vec<u32> = (0..self.nodes.len() as u32)
+            .filter(|&node| self.level_of(node) > 0)
+            .collect();
+        let position: HashMap<u32, u32> = nodes
+            .iter()
+            .enumerate()
+            .map(|(i, &node)| (node, i as u32))
+            .collect();
+        let mut vectors = Vec::new();
+        let mut layers = Vec::with_capacity(nodes.len());
+        let mut starts = Vec::new();
+        let mut links = Vec::new();
+        for &node in &nodes {
+            vectors.extend_from_slice(&self.vector(node, docs).to_f32());
+            layers.push(starts.len() as u32);
+            for layer_links in &self.nodes[node as usize].links[1..] {
+                starts.push(links.len() as u32);
+                // Links above the bottom layer only lead to entry nodes,
+                // unless an imported graph is corrupt
+                links.extend(
+                    layer_links
+                        .iter()
+                        .filter_map(|link| position.get(link).copied()),
+                );
+            }
+        }
+        starts.push(links.len() as u32);
+
+        let layers = EntryLayers {
+            dimensions: vectors.len() / nodes.len(),
+            nodes: Pinned::lock(&nodes)?,
+            vectors: Pinned::lock(&vectors)?,
+            layers: Pinned::lock(&layers)?,
+            starts: Pinned::lock(&starts)?,
+            links: Pinned::lock(&links)?,
+            entry: position[&entry],
+            top: self.level_of(entry),
+        };
+        let bytes = layers.bytes();
+        self.pinned = Some((layers, nodes.len()));
+        Ok(bytes)
+    }
+
+    /// Unlock the entry layers, if pinned.
+    pub fn unpin(&mut self) {
+        self.pinned = None;
+    }
+
+    /// Whether the entry layers should be pinned again: they aren't but
+    /// the graph has any, or it has grown since by a tenth of its entry
+    /// nodes or past the copied entry point. Pinning copies every entry
+    /// node, so this keeps its cost per insert constant.
+    pub fn pin_is_due(&self) -> bool {
+        let Some(entry) = self.entry.filter(|&entry| self.level_of(entry) > 0) else {
+            return false;
+        };
+        match &self.pinned {
+            None => true,
+            Some((layers, covered)) => {
+                self.upper_nodes > covered + covered / 10 || self.level_of(entry) > layers.top
+            }
+        }
+    }
+
+    /// Cross-check the graph against the documents it was built over.
+    pub fn verify(&self, docs: &Documents) -> Vec<Issue> {
+        let mut issues = Vec::new();
+
+        for key in docs.keys() {
+            if !self.live.contains_key(key) {
+                issues.push(Issue::new(
+                    IssueKind::GraphMissingDocument,
+                    key,
+                    "document is not linked into the graph",
+                ));
+            }
+        }
+
+        for (key, &node) in &self.live {
+            match self.nodes.get(node as usize) {
+                Some(n) if n.key == *key && n.retired.is_none() && docs.contains_key(key) => {}
+                Some(n) if n.key != *key => issues.push(Issue::new(
+                    IssueKind::GraphDanglingNode,
+                    key,
+                    format!("graph entry points at the node for {:?}", n.key),
+                )),
+                _ => issues.push(Issue::new(
+                    IssueKind::GraphDanglingNode,
+                    key,
+                    "graph node has no matching document",
+                )),
+            }
+        }
+
+        let retired = self.nodes.iter().filter(|n| n.retired.is_some()).count();
+        for (i, node) in self.nodes.iter().enumerate() {
+            let invalid = node
+                .links
+                .iter()
+                .flatten()
+                .filter(|&&link| link as usize >= self.nodes.len() || link as usize == i)
+                .count();
+            if invalid > 0 {
+                issues.push(Issue::new(
+                    IssueKind::GraphInvalidLink,
+                    &node.key,
+                    format!(
+                        "{} links point outside the graph or back at the node",
+                        invalid
+                    ),
+                ));
+            }
+        }
+
+        if retired != self.retired || (self.entry.is_none() && !self.nodes.is_empty()) {
+            issues.push(Issue {
+                kind: IssueKind::GraphCounts,
+                id: None,
+                detail: format!(
+                    "{} retired nodes recorded as {}, entry point {}",
+                    retired,
+                    self.retired,
+                    if self.entry.is_some() {
+                        "set"
+                    } else {
+                        "missing"
+                    }
+                ),
+                repaired: false,
+            });
+        }
+
+        issues
+    }
+
+    /// Best-first search of one layer, returning up to `ef` nodes sorted by
+    /// descending similarity.
+    fn search_layer(
+        &self,
+        query: &Query,
+        entry_points: &[u32],
+        ef: usize,
+        layer: usize,
+        docs: &Documents,
+    ) -> Vec<Scored> {
+        self.search_layer_until(query, entry_points, ef, layer, None, docs)
+            .0
+    }
+
+    /// [`search_layer`](Self::search_layer) that stops at `deadline` once it
+    /// has `ef` nodes, returning whether it did.
+    fn search_layer_until(
+        &self,
+        query: &Query,
+        entry_points: &[u32],
+        ef: usize,
+        layer: usize,
+        deadline: Option<Instant>,
+        docs: &Documents,
+    ) -> (Vec<Scored>, bool) {
+        let mut cut_short = false;
+        let mut visited: HashSet<u32> = entry_points.iter().copied().collect();
+        let mut candidates = BinaryHeap::new();
+        let mut results = BinaryHeap::new();
+
+        for &node in entry_points {
+            let scored = Scored {
+                similarity: self.vector(node, docs).similarity(query),
+                node,
+            };
+            candidates.push(scored);
+            results.push(Reverse(scored));
+            if results.len() > ef {
+                results.pop();
+            }
+        }
+
+        while let Some(candidate) = candidates.pop() {
+            let worst = results.peek().map_or(f32::MIN, |Reverse(s)| s.similarity);
+            if candidate.similarity < worst && results.len() >= ef {
+                break;
+            }
+            if results.len() >= ef && deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                cut_short = true;
+                break;
+            }
+
+            for &neighbour in &self.nodes[candidate.node as usize].links[layer] {
+                if !visited.insert(neighbour) {
+                    continue;
+                }
+
+                let similarity = self.vector(neighbour, docs).similarity(query);
+                let worst = results.peek().map_or(f32::MIN, |Reverse(s)| s.similarity);
+                if results.len() < ef || similarity > worst {
+                    let scored = Scored {
+                        similarity,
+                        node: neighbour,
+                    };
+                    candidates.push(scored);
+                    results.push(Reverse(scored));
+                    if results.len() > ef {
+                        results.pop();
+                    }
+                }
+            }
+        }
+
+        let mut results: Vec<Scored> = results.into_iter().map(|Reverse(s)| s).collect();
+        results.sort_by(|a, b| b.cmp(a));
+        (results, cut_short)
+    }
+
+    /// Pick up to `m` neighbours from `candidates` (sorted best first),
+    /// preferring ones that are closer to the query than to any neighbour
+    /// already picked so links spread in different directions.
+    fn select_neighbours(&self, candidates: &[Scored], m: usize, docs: &Documents) -> Vec<u32> {
+        let mut selected: Vec<u32> = Vec::with_capacity(m);
+        for candidate in candidates {
+            if selected.len() >= m {
+                break;
+            }
+
+            let vector = self.vector(candidate.node, docs).to_f32();
+            let diverse = selected.iter().all(|&picked| {
+                self.vector(picked, docs).unit_similarity(&vector) < candidate.similarity
+            });
+            if diverse {
+                selected.push(candidate.node);
+            }
+        }
+
+        // Fill any remaining slots with the closest pruned candidates so
+        // sparse regions stay connected
+        for candidate in candidates {
+            if selected.len() >= m {
+                break;
+            }
+            if !selected.contains(&candidate.node) {
+                selected.push(candidate.node);
+            }
+        }
+
+        selected
+    }
+
+    /// Add a link from `from` to `to`, pruning `from`'s links on that layer
+    /// if it now has too many.
+    fn connect(&mut self, from: u32, to: u32, layer: usize, docs: &Documents) {
+        let max_links = if layer == 0 {
+            self.config.m * 2
+        } else {
+            self.config.m
+        };
+
+        let mut links = std::mem::take(&mut self.nodes[from as usize].links[layer]);
+        links.push(to);
+
+        if links.len() > max_links {
+            let base = self.vector(from, docs).to_f32();
+            let mut candidates: Vec<Scored> = links
+                .iter()
+                .map(|&node| Scored {
+                    similarity: self.vector(node, docs).unit_similarity(&base),
+                    node,
+                })
+                .collect();
+            candidates.sort_by(|a, b| b.cmp(a));
+            links = self.select_neighbours(&candidates, max_links, docs);
+        }
+
+        self.nodes[from as usize].links[layer] = links;
+    }
+
+    fn vector<'a>(&'a self, node: u32, docs: &'a Documents) -> &'a StoredVector {
+        let node = &self.nodes[node as usize];
+        match &node.retired {
+            Some(vector) => vector,
+            None => &docs[&node.key].embedding,
+        }
+    }
+
+    fn level_of(&self, node: u32) -> usize {
+        self.nodes[node as usize].links.len() - 1
+    }
+
+    /// Draw a level from the exponentially decaying distribution the HNSW
+    /// paper uses, with normalization factor 1/ln(M).
+    fn random_level(&mut self) -> usize {
+        // xorshift64*
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        let bits = self.rng.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 11;
+        let uniform = (bits as f64 + 1.0) / (1u64 << 53) as f64;
+
+        (-uniform.ln() / (self.config.m as f64).ln()) as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Precision;
+
+    fn random_docs(count: usize, dims: usize) -> Documents {
+        let mut state = 0x2545_f491_u32;
+        (0..count)
+            .map(|i| {
+                let mut embedding: Vec<f32> = (0..dims)
+                    .map(|_| {
+                        state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                        (state >> 8) as f32 / (1u32 << 24) as f32 - 0.5
+                    })
+                    .collect();
+                // The index stores unit vectors
+                crate::simd::normalize(&mut embedding);
+                let doc = IndexedDocument {
+                    id: i.to_string(),
+                    embedding: StoredVector::new(embedding, Precision::F32),
+                    text: String::new(),
+                    metadata: None,
+                    version: 1,
+                    updated_at: None,
+                    chunks: Vec::new(),
+                };
+                (doc.id.clone(), doc)
+            })
+            .collect()
+    }
+
+    fn exact_top_k(query: &[f32], k: usize, docs: &Documents) -> Vec<String> {
+        let mut scored: Vec<(f32, &String)> = docs
+            .values()
+            .map(|doc| (doc.embedding.cosine_similarity(query), &doc.id))
+            .collect();
+        scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+        scored
+            .into_iter()
+            .take(k)
+            .map(|(_, id)| id.clone())
+            .collect()
+    }
+
+    #[test]
+    fn test_recall_against_exact_search() {
+        let mut docs = random_docs(1000, 32);
+        let config = HnswConfig {
+            m: 8,
+            ef_construction: 64,
+            ef_search: 64,
+            ..HnswConfig::default()
+        };
+        let mut graph = Hnsw::build(config, &docs);
+
+        let queries: Vec<Vec<f32>> = (0..20)
+            .map(|i| docs[&i.to_string()].embedding.to_f32().into_owned())
+            .collect();
+        let mut hits = 0;
+        for query in &queries {
+            let expected = exact_top_k(query, 10, &docs);
+            let found: Vec<&str> = graph
+                .search(query, 10, 64, &docs)
+                .into_iter()
+                .map(|(id, _)| id)
+                .collect();
+            hits += expected
+                .iter()
+                .filter(|id| found.contains(&id.as_str()))
+                .count();
+        }
+        let recall = hits as f32 / (queries.len() * 10) as f32;
+        assert!(recall > 0.9, "recall@10 was {}", recall);
+
+        // Deleted documents are routed through but never returned
+        for i in 0..500 {
+            let doc = docs.remove(&i.to_string()).unwrap();
+            graph.remove(&doc.id, doc.embedding);
+        }
+        for query in &queries {
+            let found = graph.search(query, 10, 64, &docs);
+            assert_eq!(found.len(), 10);
+            assert!(found.iter().all(|(id, _)| docs.contains_key(*id)));
+        }
+    }
+
+    #[test]
+    fn test_tune_fits_the_latency_budget() {
+        let docs = random_docs(1000, 32);
+        let graph = Hnsw::build(HnswConfig::default(), &docs);
+        let queries: Vec<TuneQuery> = (0..10)
+            .filter_map(|i| TuneQuery::new(&i.to_string(), &docs, 10))
+            .collect();
+        assert_eq!(queries.len(), 10);
+        // A query's own document isn't among the results it should find
+        assert!(queries
+            .iter()
+            .all(|query| query.truth.len() == 10 && !query.truth.contains(&query.id)));
+        let trial = |ef| graph.trial(&queries, 10, ef, &docs);
+
+        // With time to spare, ef doubles until recall is perfect or capped
+        let tuning = tune(10, Duration::from_secs(10), queries.len(), trial);
+        assert!(tuning.recall > 0.9, "recall@10 was {}", tuning.recall);
+        let best = tuning.trials.iter().map(|t| t.recall).fold(0.0, f32::max);
+        assert_eq!(tuning.recall, best);
+        assert!(tuning
+            .trials
+            .windows(2)
+            .all(|w| w[1].ef_search == w[0].ef_search * 2));
+
+        // No search fits a zero budget, so the smallest ef is used
+        let tuning = tune(10, Duration::ZERO, queries.len(), trial);
+        assert_eq!(tuning.trials.len(), 1);
+        assert_eq!(tuning.ef_search, MIN_TUNED_EF);
+    }
+
+    #[test]
+    fn test_pinned_entry_layers_route_searches() {
+        let config = HnswConfig {
+            m: 8,
+            ..HnswConfig::default()
+        };
+        let grown = random_docs(2000, 32);
+        let docs: Documents = grown
+            .iter()
+            .filter(|(id, _)| id.parse::<usize>().unwrap() < 1000)
+            .map(|(id, doc)| (id.clone(), doc.clone()))
+            .collect();
+        let mut graph = Hnsw::build(config, &docs);
+        assert!(graph.pin_is_due());
+        // The sandbox's lock limit may refuse
+        if graph.pin(&docs).is_err() {
+            return;
+        }
+        assert!(!graph.pin_is_due());
+        let query = docs["3"].embedding.to_f32().into_owned();
+        assert_eq!(graph.search(&query, 1, 64, &docs)[0].0, "3");
+
+        // A copy the graph has outgrown still finds the new nodes
+        for i in 1000..2000 {
+            graph.insert(&i.to_string(), &grown);
+        }
+        assert!(graph.pin_is_due());
+        let query = grown["1500"].embedding.to_f32().into_owned();
+        assert_eq!(graph.search(&query, 1, 64, &grown)[0].0, "1500");
+        graph.pin(&grown).unwrap();
+        assert!(!graph.pin_is_due());
+        assert_eq!(graph.search(&query, 1, 64, &grown)[0].0, "1500");
+    }
+}
@@ -0,0 +1,170 @@
+//! How a collection's document ids are chosen: named by the client and
+//! checked against a pattern, generated by the server, or derived from
+//! each document's content so the same text is only stored once.
+
+use anyhow::Result;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::index::IndexError;
+use crate::text;
+
+/// A collection's id policy, stored in its settings.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum IdPolicy {
+    /// Every document is sent with an id, which must match `pattern`
+    /// (anchored at both ends) if one is set
+    Client {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pattern: Option<String>,
+    },
+    /// The server names new documents with a UUIDv7, so ids sort by when
+    /// they were created. Sending an id updates that document.
+    Uuid7,
+    /// A document's id is the hash of its text, so the same text indexed
+    /// by two clients is one document
+    ContentHash,
+}
+
+impl Default for IdPolicy {
+    fn default() -> Self {
+        IdPolicy::Client { pattern: None }
+    }
+}
+
+impl IdPolicy {
+    pub fn is_default(&self) -> bool {
+        *self == IdPolicy::default()
+    }
+
+    /// Compile the policy's pattern, failing if it isn't a valid regular
+    /// expression.
+    pub fn compile(&self) -> Result<IdRule, String> {
+        let pattern = match self {
+            IdPolicy::Client {
+                pattern: Some(pattern),
+            } => Some(
+                Regex::new(&format!("^(?:{})$", pattern))
+                    .map_err(|e| format!("Invalid id pattern {:?}: {}", pattern, e))?,
+            ),
+            _ => None,
+        };
+        Ok(IdRule {
+            policy: self.clone(),
+            pattern,
+        })
+    }
+}
+
+/// An [`IdPolicy`] ready to apply.
+#[derive(Debug, Clone, Default)]
+pub struct IdRule {
+    policy: IdPolicy,
+    pattern: Option<Regex>,
+}
+
+impl IdRule {
+    /// The id to index `text` under, given the `id` it was sent with.
+    pub fn resolve(&self, id: Option<String>, text: &str) -> Result<String> {
+        let id = match (&self.policy, id) {
+            (IdPolicy::Client { pattern }, Some(id)) => match (&self.pattern, pattern) {
+                (Some(regex), Some(pattern)) if !regex.is_match(&id) => Err(IndexError::InvalidId(
+                    format!("{:?} doesn't match {:?}", id, pattern),
+                )),
+                _ if id.is_empty() => Err(IndexError::InvalidId("ids can't be empty".into())),
+                _ => Ok(id),
+            },
+            (IdPolicy::Client { .. }, None) => Err(IndexError::InvalidId(
+                "the collection's documents need an id".into(),
+            )),
+            (IdPolicy::Uuid7, Some(id)) if !is_uuid(&id) => Err(IndexError::InvalidId(format!(
+                "{:?} isn't a UUID; leave the id out to have one generated",
+                id
+            ))),
+            (IdPolicy::Uuid7, id) => Ok(id.unwrap_or_else(uuid7)),
+            (IdPolicy::ContentHash, id) => {
+                let hash = text::content_hash(text);
+                match id {
+                    Some(id) if id != hash => Err(IndexError::InvalidId(format!(
+                        "{:?} isn't the hash of the text; the collection derives ids from \
+                         content, so leave the id out",
+                        id
+                    ))),
+                    _ => Ok(hash),
+                }
+            }
+        };
+        Ok(id?)
+    }
+}
+
+/// A new UUIDv7: the Unix time in milliseconds, then random bits.
+fn uuid7() -> String {
+    let mut bytes = [0u8; 16];
+    getrandom::getrandom(&mut bytes[6..]).expect("the OS provides random numbers");
+    let millis = chrono::Utc::now().timestamp_millis() as u64;
+    bytes[..6].copy_from_slice(&millis.to_be_bytes()[2..]);
+    bytes[6] = 0x70 | (bytes[6] & 0x0f);
+    bytes[8] = 0x80 | (bytes[8] & 0x3f);
+
+    let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+/// Whether `id` is a UUID in its hyphenated form, of any version.
+fn is_uuid(id: &str) -> bool {
+    id.len() == 36
+        && id.char_indices().all(|(i, c)| match i {
+            8 | 13 | 18 | 23 => c == '-',
+            _ => c.is_ascii_hexdigit(),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_id_policies() {
+        let client = IdPolicy::Client {
+            pattern: Some("[a-z]+/[a-z0-9-]+".into()),
+        }
+        .compile()
+        .unwrap();
+        assert_eq!(
+            client.resolve(Some("notes/a-1".into()), "").unwrap(),
+            "notes/a-1"
+        );
+        assert!(client.resolve(Some("notes/A 1".into()), "").is_err());
+        assert!(client.resolve(Some("x/notes/a".into()), "").is_err());
+        assert!(client.resolve(None, "text").is_err());
+        assert!(IdPolicy::Client {
+            pattern: Some("(".into())
+        }
+        .compile()
+        .is_err());
+
+        let uuid = IdPolicy::Uuid7.compile().unwrap();
+        let first = uuid.resolve(None, "").unwrap();
+        let second = uuid.resolve(None, "").unwrap();
+        assert!(is_uuid(&first) && first != second);
+        assert_eq!(&first[14..15], "7");
+        assert!(matches!(&first[19..20], "8" | "9" | "a" | "b"));
+        assert_eq!(uuid.resolve(Some(first.clone()), "").unwrap(), first);
+        assert!(uuid.resolve(Some("note".into()), "").is_err());
+
+        let hash = IdPolicy::ContentHash.compile().unwrap();
+        let id = hash.resolve(None, "Same text").unwrap();
+        assert_eq!(hash.resolve(None, "Same  text\n").unwrap(), id);
+        assert_eq!(hash.resolve(Some(id.clone()), "Same text").unwrap(), id);
+        assert!(hash.resolve(Some("note".into()), "Same text").is_err());
+    }
+}
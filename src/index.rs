@@ -1,107 +1,2099 @@
+//! Storing and searching embedded documents, alone or in named
+//! collections.
+
 use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::collections::HashMap;
-use std::sync::RwLock;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::iter;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+use crate::chunking::DocumentEmbedding;
+use crate::config::{HnswConfig, Precision, Quantization, QuantizationConfig, StorageConfig};
+use crate::filter::Filter;
+use crate::hnsw::{self, EfTuning, Hnsw, TuneQuery};
+use crate::ids::IdPolicy;
+use crate::lexical::{self, Analyzer, AnalyzerSettings, Bm25Index};
+use crate::simd;
+use crate::storage::{self, LogRecord, ReplayRecord, VectorStore};
+use crate::vector::{ProductQuantizer, Query, StoredVector};
+use crate::warm;
+
+#[derive(Debug, thiserror::Error)]
+pub enum IndexError {
+    #[error("Document not found: {0}")]
+    NotFound(String),
+    #[error("{0:?} is already a document id and can't also be an alias")]
+    AliasIsDocument(String),
+    #[error("Collection not found: {0}")]
+    CollectionNotFound(String),
+    #[error("Collection already exists: {0}")]
+    CollectionExists(String),
+    #[error("Invalid collection name {0:?}: use 1-64 letters, digits, '-' or '_'")]
+    InvalidCollectionName(String),
+    #[error("The default collection can't be deleted")]
+    DeleteDefaultCollection,
+    #[error("Embedding has {found} dimensions but the collection's have {expected}; was it made by another model?")]
+    DimensionMismatch { expected: usize, found: usize },
+    #[error("The collection is served from a read-only bundle and can't be changed")]
+    ReadOnly,
+    #[error("Invalid document id: {0}")]
+    InvalidId(String),
+    #[error("The disk holding the index is full; free some space and retry")]
+    Full,
+}
+
+/// Whether [`VectorIndex::add`] stored a new document or replaced one.
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum UpsertStatus {
+    Created,
+    Updated,
+}
+
+/// What [`VectorIndex::add`] did.
+#[derive(Debug)]
+pub struct Upsert {
+    /// Canonical id, which differs from the id given if that was an alias
+    pub id: String,
+    pub status: UpsertStatus,
+    pub version: u64,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum IssueKind {
+    /// A document is stored under a key other than its own id
+    IdMismatch,
+    /// An alias points at a document that no longer exists
+    DanglingAlias,
+    /// An alias has the same id as a document, hiding it from lookups
+    AliasShadowsDocument,
+    /// A vector's length differs from the rest of the collection
+    DimensionMismatch,
+    /// A vector contains NaN or infinite values
+    NonFiniteVector,
+    GraphMissingDocument,
+    GraphDanglingNode,
+    GraphInvalidLink,
+    GraphCounts,
+    /// The keyword index covers a different number of documents
+    LexicalCounts,
+}
+
+/// An inconsistency found by [`VectorIndex::verify`].
+#[derive(Serialize, Debug)]
+pub struct Issue {
+    pub kind: IssueKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    pub detail: String,
+    pub repaired: bool,
+}
+
+impl Issue {
+    pub fn new(kind: IssueKind, id: &str, detail: impl Into<String>) -> Self {
+        Self {
+            kind,
+            id: Some(id.to_string()),
+            detail: detail.into(),
+            repaired: false,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct VerifyReport {
+    pub documents: usize,
+    pub aliases: usize,
+    pub issues: Vec<Issue>,
+}
+
+/// Bytes a collection holds in memory, estimated from what it stores
+/// without allocator or hash table overhead.
+#[derive(Debug, Default, Serialize)]
+pub struct MemoryUsage {
+    /// Vectors, including chunks
+    pub vectors: usize,
+    /// Ids, texts, and metadata
+    pub documents: usize,
+    pub graph: usize,
+    pub keyword_index: usize,
+    pub total: usize,
+}
+
+/// Outcome of [`VectorIndex::warm`].
+#[derive(Serialize)]
+pub struct WarmReport {
+    pub documents: usize,
+    /// Memory read through: vectors, texts, and graph links
+    pub bytes_touched: usize,
+    /// Memory of the graph's entry layers locked into RAM
+    pub pinned_bytes: usize,
+    /// Why the entry layers couldn't be pinned, if they couldn't
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pin_error: Option<String>,
+    pub took_ms: f64,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct IndexedDocument {
+    pub id: String,
+    pub embedding: StoredVector,
+    pub text: String,
+    pub metadata: Option<Value>,
+    /// Starts at 1 and goes up by one every time the document is replaced.
+    /// 0 for documents indexed before versions were tracked.
+    #[serde(default)]
+    pub version: u64,
+    #[serde(default)]
+    pub updated_at: Option<DateTime<Utc>>,
+    /// Separately embedded windows of a document too long to embed whole
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub chunks: Vec<Chunk>,
+}
+
+/// One window of a long document.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Chunk {
+    /// Byte offsets of the chunk within the document text
+    pub start: usize,
+    pub end: usize,
+    pub embedding: StoredVector,
+}
+
+impl IndexedDocument {
+    /// The document's own vector followed by its chunks'.
+    fn vectors(&self) -> impl Iterator<Item = &StoredVector> {
+        iter::once(&self.embedding).chain(self.chunks.iter().map(|chunk| &chunk.embedding))
+    }
+
+    /// As [`vectors`](Self::vectors), mutably.
+    fn vectors_mut(&mut self) -> impl Iterator<Item = &mut StoredVector> {
+        iter::once(&mut self.embedding)
+            .chain(self.chunks.iter_mut().map(|chunk| &mut chunk.embedding))
+    }
+
+    /// Similarity to a unit-length query. A chunked document scores as its
+    /// best matching chunk, whose index is returned alongside.
+    pub fn similarity(&self, query: &Query) -> (f32, Option<usize>) {
+        self.chunks
+            .iter()
+            .map(|chunk| chunk.embedding.similarity(query))
+            .enumerate()
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map_or_else(
+                || (self.embedding.similarity(query), None),
+                |(i, score)| (score, Some(i)),
+            )
+    }
+
+    /// As [`similarity`](Self::similarity), but a chunked document's score
+    /// blends its best chunk with its next best, so one that matches
+    /// throughout outranks a single passing mention. The best chunk is
+    /// still the one returned.
+    pub fn coverage_similarity(&self, query: &Query) -> (f32, Option<usize>) {
+        let (best, chunk) = self.similarity(query);
+        let Some(chunk) = chunk else {
+            return (best, None);
+        };
+        let next = self
+            .chunks
+            .iter()
+            .enumerate()
+            .filter(|&(i, _)| i != chunk)
+            .map(|(_, other)| other.embedding.similarity(query))
+            .fold(f32::MIN, f32::max)
+            .max(0.0);
+        // Never above the best chunk alone, so scores stay comparable with
+        // unchunked documents
+        let score = (1.0 - COVERAGE_WEIGHT) * best + COVERAGE_WEIGHT * next.min(best);
+        (score, Some(chunk))
+    }
+}
+
+/// Share of a chunked document's coverage score that comes from its
+/// second best chunk.
+const COVERAGE_WEIGHT: f32 = 0.25;
+
+/// Re-index every document for keyword search, keeping the analyzer.
+fn rebuild_lexical(state: &mut IndexState) {
+    let IndexState {
+        documents, lexical, ..
+    } = state;
+    lexical.rebuild(
+        documents
+            .iter()
+            .map(|(key, doc)| (key.as_str(), doc.text.as_str())),
+    );
+}
+
+/// Up to this many documents, search scans every vector. The scan is exact
+/// and still fast at this size; the HNSW graph takes over beyond it.
+const EXACT_SEARCH_THRESHOLD: usize = 5000;
+
+/// Candidates scored between checks of a search's deadline. At least this
+/// many are scored however late the search starts.
+const DEADLINE_CHECK_INTERVAL: usize = 256;
+
+/// Results per query whose recall `ef_search` is tuned for.
+const TUNE_K: usize = 10;
+
+/// How many times `limit` candidates are fetched from the graph when a
+/// metadata filter will discard some of them.
+const FILTER_OVERFETCH: usize = 8;
+
+/// One document found by a search.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SearchResult {
+    pub id: String,
+    pub score: f32,
+    pub text: String,
+    /// The best-matching chunk of a document long enough to be chunked
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub passage: Option<String>,
+    /// Whether `text` was shortened to fit `max_text_length`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub truncated: bool,
+    /// Instance the result came from, set for federated searches
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    /// Collection the result came from, set when searching several
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub collection: Option<String>,
+    /// Other searched collections holding the same text, which were left
+    /// out of the results in favour of this one
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub duplicates: Vec<String>,
+    /// Link that opens the note in Obsidian, when a vault name is configured
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub obsidian_uri: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub explanation: Option<ScoreExplanation>,
+}
+
+/// Component scores behind a result's final score. Components are only
+/// present when the corresponding ranking stage ran.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct ScoreExplanation {
+    /// Cosine similarity between the query and document embeddings
+    pub dense: f32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lexical: Option<f32>,
+    /// Added for containing the query's phrases in hybrid search
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub phrase: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub boost: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rerank_delta: Option<f32>,
+    /// Weight of the result's collection in a multi-collection search
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub collection_weight: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mmr_penalty: Option<f32>,
+}
+
+/// Everything besides the query embedding and limit that shapes a search.
+#[derive(Default)]
+pub struct SearchOptions<'a> {
+    /// Only return documents whose metadata passes this filter
+    pub filter: Option<&'a Filter>,
+    /// Blend BM25 scores for this query text into the ranking
+    pub hybrid: Option<(&'a str, HybridConfig)>,
+    pub boosts: &'a [Boost],
+    /// Leave out results scoring below this, so a search may return fewer
+    /// than `limit` results, or none
+    pub min_score: Option<f32>,
+    /// Return the best results found by this time rather than finish
+    pub deadline: Option<Instant>,
+    /// Score chunked documents by
+    /// [`coverage_similarity`](IndexedDocument::coverage_similarity)
+    pub chunk_coverage: bool,
+}
+
+/// Raises the score of results whose metadata passes `filter`, e.g. to
+/// favour notes from the current project without excluding the rest.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Boost {
+    pub filter: Filter,
+    /// Added to the score of matching results; negative to demote them
+    pub weight: f32,
+}
+
+/// How hybrid search combines vector and keyword relevance.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct HybridConfig {
+    /// Weight of the vector score under weighted fusion; the keyword
+    /// score, scaled so the best match is 1, gets the rest
+    #[serde(default = "default_alpha")]
+    pub alpha: f32,
+    #[serde(default)]
+    pub fusion: Fusion,
+    /// Added to the fused score of documents containing the query's quoted
+    /// phrases word for word, in proportion to how many of them they
+    /// contain. Only quoted phrases count, as the boost outweighs the
+    /// fused scores themselves, reciprocal rank fusion's especially
+    #[serde(default = "default_phrase_boost")]
+    pub phrase_boost: f32,
+}
+
+fn default_alpha() -> f32 {
+    0.5
+}
+
+fn default_phrase_boost() -> f32 {
+    0.2
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Fusion {
+    /// `alpha * vector + (1 - alpha) * keyword`
+    #[default]
+    Weighted,
+    /// Reciprocal rank fusion, which ignores score scales entirely
+    Rrf,
+}
+
+/// Damping constant for reciprocal rank fusion, from the original paper.
+const RRF_K: f32 = 60.0;
 
-use crate::SearchResult;
+/// Replace each result's score with its hybrid score, using the dense and
+/// lexical components recorded in its explanation.
+fn fuse(results: &mut [SearchResult], hybrid: HybridConfig) {
+    let components = |result: &SearchResult| {
+        let explanation = result.explanation.as_ref().expect("set by search");
+        (explanation.dense, explanation.lexical.unwrap_or(0.0))
+    };
 
-#[derive(Clone)]
-struct IndexedDocument {
-    id: String,
-    embedding: Vec<f32>,
-    text: String,
-    metadata: Option<Value>,
+    match hybrid.fusion {
+        Fusion::Weighted => {
+            let best = results
+                .iter()
+                .map(|result| components(result).1)
+                .fold(0.0f32, f32::max);
+            for result in results.iter_mut() {
+                let (dense, lexical) = components(result);
+                let lexical = if best > 0.0 { lexical / best } else { 0.0 };
+                result.score = hybrid.alpha * dense + (1.0 - hybrid.alpha) * lexical;
+            }
+        }
+        Fusion::Rrf => {
+            let scores: Vec<(f32, f32)> = results.iter().map(components).collect();
+            let mut by_dense: Vec<usize> = (0..scores.len()).collect();
+            by_dense.sort_by(|&a, &b| scores[b].0.total_cmp(&scores[a].0));
+            // Documents without the query's terms get no keyword rank
+            let mut by_lexical: Vec<usize> =
+                (0..scores.len()).filter(|&i| scores[i].1 > 0.0).collect();
+            by_lexical.sort_by(|&a, &b| scores[b].1.total_cmp(&scores[a].1));
+
+            for result in results.iter_mut() {
+                result.score = 0.0;
+            }
+            for ranking in [by_dense, by_lexical] {
+                for (rank, i) in ranking.into_iter().enumerate() {
+                    results[i].score += 1.0 / (RRF_K + rank as f32 + 1.0);
+                }
+            }
+        }
+    }
+}
+
+/// Documents, their aliases, and the graph and keyword index over them,
+/// locked together so they never disagree.
+struct IndexState {
+    documents: HashMap<String, IndexedDocument>,
+    /// Alternative ids (e.g. a note's path before a rename) mapped to the
+    /// canonical id they stand for
+    aliases: HashMap<String, String>,
+    graph: Hnsw,
+    lexical: Bm25Index,
+    /// Centroids for product quantization, once learned
+    quantizer: Option<Arc<ProductQuantizer>>,
+}
+
+impl IndexState {
+    /// Length of the stored vectors, read from any document but `except`.
+    fn dimensions(&self, except: Option<&str>) -> Option<usize> {
+        self.documents
+            .values()
+            .find(|doc| Some(doc.id.as_str()) != except)
+            .map(|doc| doc.embedding.to_f32().len())
+    }
+
+    /// The canonical id for `id`, which may be an alias.
+    fn resolve<'a>(&'a self, id: &'a str) -> &'a str {
+        self.aliases.get(id).map_or(id, String::as_str)
+    }
+
+    /// Remove a document along with every alias pointing at it.
+    fn remove_document(&mut self, id: &str) -> Option<IndexedDocument> {
+        let doc = self.documents.remove(id)?;
+        self.aliases.retain(|_, target| target != id);
+        Some(doc)
+    }
+
+    /// Apply a logged mutation during recovery. The graph and keyword index
+    /// are built once recovery is complete rather than kept up to date here.
+    fn replay(&mut self, record: ReplayRecord) {
+        match record {
+            ReplayRecord::Put(doc) => {
+                self.documents.insert(doc.id.clone(), doc);
+            }
+            ReplayRecord::Delete(id) => {
+                self.remove_document(&id);
+            }
+            ReplayRecord::Clear => {
+                self.documents.clear();
+                self.aliases.clear();
+            }
+            ReplayRecord::Alias { alias, id } => {
+                self.aliases.insert(alias, id);
+            }
+            ReplayRecord::Unalias(alias) => {
+                self.aliases.remove(&alias);
+            }
+            ReplayRecord::Quantizer(quantizer) => {
+                self.quantizer = Some(Arc::new(quantizer));
+            }
+        }
+    }
+
+    /// Scale full and half precision vectors to unit length, as they are
+    /// now stored, if they were stored before that.
+    fn normalize_all(&mut self) {
+        for doc in self.documents.values_mut() {
+            for vector in doc.vectors_mut() {
+                let precision = match vector {
+                    StoredVector::F32(_) => Precision::F32,
+                    StoredVector::F16(_) => Precision::F16,
+                    _ => continue,
+                };
+                let original = vector.to_f32().into_owned();
+                let mut values = original.clone();
+                simd::normalize(&mut values);
+                if values != original {
+                    *vector = StoredVector::new(values, precision);
+                }
+            }
+        }
+    }
+
+    /// Bring every vector in line with `mode`: vectors coded against the
+    /// quantizer get it attached, and vectors stored before it was learned,
+    /// or before quantization was switched on, are quantized.
+    fn quantize_all(&mut self, mode: Quantization) {
+        let quantizer = self.quantizer.clone();
+        for doc in self.documents.values_mut() {
+            for vector in doc.vectors_mut() {
+                quantize(vector, mode, quantizer.as_ref());
+            }
+        }
+    }
 }
 
+fn quantize(
+    vector: &mut StoredVector,
+    mode: Quantization,
+    quantizer: Option<&Arc<ProductQuantizer>>,
+) {
+    let quantized = match (&mut *vector, mode, quantizer) {
+        (StoredVector::Pq(pq), _, _) => {
+            pq.quantizer = quantizer.cloned();
+            return;
+        }
+        (StoredVector::Int8 { .. }, _, _) => return,
+        (_, Quantization::Int8, _) => StoredVector::int8(&vector.to_f32()),
+        (_, Quantization::Pq, Some(quantizer)) => quantizer.encode(vector.to_f32().into_owned()),
+        _ => return,
+    };
+    *vector = quantized;
+}
+
+/// Documents with their embeddings, text, and metadata, searchable by
+/// similarity to a query embedding and persisted under one directory.
 pub struct VectorIndex {
-    documents: RwLock<HashMap<String, IndexedDocument>>,
+    state: RwLock<IndexState>,
+    precision: Precision,
+    quantization: QuantizationConfig,
+    hnsw: HnswConfig,
+    /// Graph search candidate list size, tuned at runtime if a latency
+    /// target is set
+    ef_search: AtomicUsize,
+    ef_tuning: RwLock<Option<EfTuning>>,
+    /// Where mutations are persisted; `None` for a purely in-memory index
+    storage: Option<Box<dyn VectorStore>>,
+    /// Set for an index served from a bundle, which refuses every mutation
+    read_only: bool,
+    /// Shared by writers from choosing the model to embed with until their
+    /// vectors are stored, and held alone by a rebuild switching models
+    binding: tokio::sync::RwLock<()>,
+    /// Held while product quantization centroids are learned, so only one
+    /// training runs at a time
+    training: Mutex<()>,
+    /// Bumped whenever vectors change without a new document version, so
+    /// a training can tell the vectors it copied went stale
+    replacements: AtomicU64,
+    /// Whether the graph's entry layers are kept locked into RAM, pinned
+    /// again as the graph grows or is rebuilt
+    pin_entry_layers: AtomicBool,
 }
 
 impl VectorIndex {
-    pub fn new() -> Self {
+    /// Create an empty in-memory index.
+    pub fn new(precision: Precision, quantization: QuantizationConfig, hnsw: HnswConfig) -> Self {
         Self {
-            documents: RwLock::new(HashMap::new()),
+            state: RwLock::new(IndexState {
+                documents: HashMap::new(),
+                aliases: HashMap::new(),
+                graph: Hnsw::new(hnsw),
+                lexical: Bm25Index::default(),
+                quantizer: None,
+            }),
+            precision,
+            quantization,
+            hnsw,
+            ef_search: AtomicUsize::new(hnsw.ef_search),
+            ef_tuning: RwLock::new(None),
+            storage: None,
+            read_only: false,
+            binding: tokio::sync::RwLock::new(()),
+            training: Mutex::new(()),
+            replacements: AtomicU64::new(0),
+            pin_entry_layers: AtomicBool::new(false),
+        }
+    }
+
+    /// An index over `documents` that refuses to change, searched through
+    /// a `graph` already built over them.
+    pub fn read_only(
+        documents: Vec<IndexedDocument>,
+        aliases: HashMap<String, String>,
+        graph: Hnsw,
+        precision: Precision,
+        quantization: QuantizationConfig,
+        hnsw: HnswConfig,
+    ) -> Self {
+        let mut index = Self::new(precision, quantization, hnsw);
+        let mut state = IndexState {
+            documents: HashMap::with_capacity(documents.len()),
+            aliases,
+            graph,
+            lexical: Bm25Index::default(),
+            quantizer: None,
+        };
+        for mut doc in documents {
+            for vector in doc.vectors_mut() {
+                *vector = index.encode(vector.to_f32().into_owned(), None);
+            }
+            state.documents.insert(doc.id.clone(), doc);
         }
+        rebuild_lexical(&mut state);
+        index.state = RwLock::new(state);
+        index.read_only = true;
+        index
+    }
+
+    /// Open a persistent index in `dir`, recovering every document from the
+    /// last snapshot and the mutation log written since.
+    pub fn open(
+        dir: &Path,
+        precision: Precision,
+        quantization: QuantizationConfig,
+        hnsw: HnswConfig,
+        storage: StorageConfig,
+    ) -> Result<Self> {
+        let (storage, snapshot, records) = storage::open(dir, storage)?;
+        let mut state = IndexState {
+            documents: snapshot
+                .documents
+                .into_iter()
+                .map(|doc| (doc.id.clone(), doc))
+                .collect(),
+            aliases: snapshot.aliases,
+            graph: Hnsw::new(hnsw),
+            lexical: Bm25Index::default(),
+            quantizer: snapshot.quantizer.map(Arc::new),
+        };
+        for record in records {
+            state.replay(record);
+        }
+        state.normalize_all();
+        state.quantize_all(quantization.mode);
+        info!("Loaded {} documents from {:?}", state.documents.len(), dir);
+        state.graph = Hnsw::build(hnsw, &state.documents);
+        rebuild_lexical(&mut state);
+
+        let index = Self {
+            state: RwLock::new(state),
+            precision,
+            quantization,
+            hnsw,
+            ef_search: AtomicUsize::new(hnsw.ef_search),
+            ef_tuning: RwLock::new(None),
+            storage: Some(storage),
+            read_only: false,
+            binding: tokio::sync::RwLock::new(()),
+            training: Mutex::new(()),
+            replacements: AtomicU64::new(0),
+            pin_entry_layers: AtomicBool::new(false),
+        };
+        // Product quantization switched on for a collection that is
+        // already big enough
+        index.train_quantizer_if_due(true)?;
+
+        Ok(index)
+    }
+
+    pub fn precision(&self) -> Precision {
+        self.precision
+    }
+
+    pub fn quantization(&self) -> QuantizationConfig {
+        self.quantization
+    }
+
+    pub fn hnsw(&self) -> HnswConfig {
+        self.hnsw
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Keep the collection bound to its current model until the guard is
+    /// dropped. Held by writers while they embed and store documents, so a
+    /// rebuild can't switch models between the two.
+    pub async fn writing(&self) -> tokio::sync::RwLockReadGuard<'_, ()> {
+        self.binding.read().await
+    }
+
+    /// Wait for every writer holding [`writing`](Self::writing) to finish,
+    /// and keep new ones out until the guard is dropped.
+    pub async fn switching_model(&self) -> tokio::sync::RwLockWriteGuard<'_, ()> {
+        self.binding.write().await
+    }
+
+    /// Length of the stored vectors, or `None` while the index is empty.
+    pub fn dimensions(&self) -> Option<usize> {
+        self.state.read().unwrap().dimensions(None)
     }
 
+    /// Candidate list size graph searches use, as tuned or configured.
+    pub fn ef_search(&self) -> usize {
+        self.ef_search.load(Ordering::Relaxed)
+    }
+
+    /// Result of the last [`tune_ef_search`](Self::tune_ef_search).
+    pub fn ef_tuning(&self) -> Option<EfTuning> {
+        self.ef_tuning.read().unwrap().clone()
+    }
+
+    /// Set `ef_search` to the value giving graph searches the best recall
+    /// within `budget`, measured on up to `queries` stored documents used
+    /// as queries. Returns `None`, changing nothing, while the collection is
+    /// small enough to be searched exactly. Scans the collection once per
+    /// query, so run it off the async runtime; the lock is taken afresh for
+    /// each scan and each value tried, so writers aren't held up meanwhile.
+    pub fn tune_ef_search(&self, budget: Duration, queries: usize) -> Option<EfTuning> {
+        let ids: Vec<String> = {
+            let state = self.state.read().unwrap();
+            let docs = &state.documents;
+            if docs.len() <= EXACT_SEARCH_THRESHOLD {
+                return None;
+            }
+            let step = (docs.len() / queries.max(1)).max(1);
+            docs.keys().step_by(step).take(queries).cloned().collect()
+        };
+        let sample: Vec<TuneQuery> = ids
+            .iter()
+            .filter_map(|id| {
+                let state = self.state.read().unwrap();
+                TuneQuery::new(id, &state.documents, TUNE_K)
+            })
+            .collect();
+        let tuning = hnsw::tune(TUNE_K, budget, sample.len(), |ef| {
+            let state = self.state.read().unwrap();
+            state.graph.trial(&sample, TUNE_K, ef, &state.documents)
+        });
+
+        self.ef_search.store(tuning.ef_search, Ordering::Relaxed);
+        *self.ef_tuning.write().unwrap() = Some(tuning.clone());
+        Some(tuning)
+    }
+
+    /// Read through every document's vectors and text and the whole
+    /// graph, so pages swapped out or not yet faulted in are loaded before
+    /// a search needs them. With `pin`, the graph's entry layers, which
+    /// every graph search descends through, are then copied into memory
+    /// locked into RAM, and kept there as the graph grows or is rebuilt
+    /// until a warm without `pin`. Reads the whole index, so run it off the
+    /// async runtime.
+    pub fn warm(&self, pin: bool) -> WarmReport {
+        let start = Instant::now();
+        let state = self.state.read().unwrap();
+        let docs = &state.documents;
+
+        let mut bytes_touched = 0;
+        for doc in docs.values() {
+            bytes_touched += warm::touch(doc.text.as_bytes());
+            bytes_touched += warm::touch(doc.embedding.as_bytes());
+            for chunk in &doc.chunks {
+                bytes_touched += warm::touch(chunk.embedding.as_bytes());
+            }
+        }
+        for region in state.graph.regions(docs) {
+            bytes_touched += warm::touch(region);
+        }
+        let documents = docs.len();
+        drop(state);
+
+        let mut state = self.state.write().unwrap();
+        let mut pinned_bytes = 0;
+        let mut pin_error = None;
+        if pin {
+            match state.graph.pin(&state.documents) {
+                Ok(bytes) => pinned_bytes = bytes,
+                Err(e) => {
+                    pin_error = Some(format!(
+                        "The OS refused to lock the graph's entry layers: {} \
+                         (raise the memory lock limit, ulimit -l)",
+                        e
+                    ))
+                }
+            }
+        } else {
+            state.graph.unpin();
+        }
+        self.pin_entry_layers
+            .store(pin && pin_error.is_none(), Ordering::Relaxed);
+
+        WarmReport {
+            documents,
+            bytes_touched,
+            pinned_bytes,
+            pin_error,
+            took_ms: start.elapsed().as_secs_f64() * 1000.0,
+        }
+    }
+
+    /// Fail if the index is [`read_only`](Self::read_only), before any
+    /// mutation starts.
+    fn writable(&self) -> Result<()> {
+        if self.read_only {
+            return Err(IndexError::ReadOnly.into());
+        }
+        Ok(())
+    }
+
+    /// Store `values` as configured: quantized if the mode calls for it
+    /// (and, for product quantization, once centroids are learned),
+    /// otherwise at the model precision. Vectors are scaled to unit length
+    /// first, so scoring them against a query is a dot product.
+    fn encode(
+        &self,
+        mut values: Vec<f32>,
+        quantizer: Option<&Arc<ProductQuantizer>>,
+    ) -> StoredVector {
+        simd::normalize(&mut values);
+        match (self.quantization.mode, quantizer) {
+            (Quantization::Int8, _) => StoredVector::int8(&values),
+            (Quantization::Pq, Some(quantizer)) => quantizer.encode(values),
+            _ => StoredVector::new(values, self.precision),
+        }
+    }
+
+    /// Index a document, replacing any previous version. An `id` that is an
+    /// alias updates the document it points at.
     pub async fn add(
         &self,
         id: &str,
-        embedding: Vec<f32>,
+        embedding: impl Into<DocumentEmbedding>,
         text: String,
         metadata: Option<Value>,
-    ) -> Result<()> {
+    ) -> Result<Upsert> {
+        self.writable()?;
+        let embedded = embedding.into();
+        let mut state = self.state.write().unwrap();
+        let id = state.resolve(id).to_string();
+        if let Some(expected) = state.dimensions(Some(&id)) {
+            if expected != embedded.embedding.len() {
+                return Err(IndexError::DimensionMismatch {
+                    expected,
+                    found: embedded.embedding.len(),
+                }
+                .into());
+            }
+        }
+        let quantizer = state.quantizer.clone();
+        let (status, version) = match state.documents.get(&id) {
+            Some(old) => (UpsertStatus::Updated, old.version + 1),
+            None => (UpsertStatus::Created, 1),
+        };
         let doc = IndexedDocument {
-            id: id.to_string(),
-            embedding,
+            id: id.clone(),
+            embedding: self.encode(embedded.embedding, quantizer.as_ref()),
             text,
             metadata,
+            version,
+            updated_at: Some(Utc::now()),
+            chunks: embedded
+                .chunks
+                .into_iter()
+                .map(|chunk| Chunk {
+                    start: chunk.span.start,
+                    end: chunk.span.end,
+                    embedding: self.encode(chunk.embedding, quantizer.as_ref()),
+                })
+                .collect(),
         };
 
-        let mut docs = self.documents.write().unwrap();
-        docs.insert(id.to_string(), doc);
+        self.put(&mut state, doc)?;
+        drop(state);
+        self.train_quantizer_if_due(false)?;
 
-        Ok(())
+        Ok(Upsert {
+            id,
+            status,
+            version,
+        })
+    }
+
+    /// Insert a document exported from another index as it was, keeping
+    /// its version and timestamp, with its vectors stored the way this
+    /// index stores them.
+    pub async fn restore(&self, mut doc: IndexedDocument) -> Result<()> {
+        self.writable()?;
+        let mut state = self.state.write().unwrap();
+        let found = doc.embedding.to_f32().len();
+        if let Some(expected) = state.dimensions(Some(&doc.id)).filter(|&d| d != found) {
+            return Err(IndexError::DimensionMismatch { expected, found }.into());
+        }
+        let quantizer = state.quantizer.clone();
+        for vector in doc.vectors_mut() {
+            *vector = self.encode(vector.to_f32().into_owned(), quantizer.as_ref());
+        }
+        // The restored version may be the one already here
+        self.replacements.fetch_add(1, Ordering::Relaxed);
+        self.put(&mut state, doc)?;
+        drop(state);
+        self.train_quantizer_if_due(false)
     }
 
-    pub async fn search(&self, query_embedding: &[f32], limit: usize) -> Result<Vec<SearchResult>> {
-        let docs = self.documents.read().unwrap();
+    /// Log `doc` and put it in place of any document with its id.
+    fn put(&self, state: &mut IndexState, doc: IndexedDocument) -> Result<()> {
+        let id = doc.id.clone();
+        let snapshot_due = self.log(&LogRecord::Put(&doc))?;
+        if let Some(old) = state.documents.remove(&id) {
+            state.lexical.remove(&id, &old.text);
+            state.graph.remove(&id, old.embedding);
+        }
+        state.lexical.insert(&id, &doc.text);
+        state.documents.insert(id.clone(), doc);
+        let IndexState {
+            documents, graph, ..
+        } = &mut *state;
+        graph.insert(&id, documents);
+        self.after_mutation(state, snapshot_due)
+    }
 
-        let mut results: Vec<SearchResult> = docs
-            .values()
-            .map(|doc| {
-                let score = cosine_similarity(query_embedding, &doc.embedding);
-                SearchResult {
-                    id: doc.id.clone(),
-                    score,
-                    text: doc.text.clone(),
-                }
+    /// Most similar documents to the query, restricted to those whose
+    /// metadata passes the filter if one is given, and blended with keyword
+    /// scores in hybrid mode.
+    pub async fn search(
+        &self,
+        query_embedding: &[f32],
+        limit: usize,
+        options: &SearchOptions<'_>,
+    ) -> Result<Vec<SearchResult>> {
+        Ok(self
+            .search_partial(query_embedding, limit, options)
+            .await?
+            .0)
+    }
+
+    /// [`search`](Self::search), also returning whether the results are
+    /// partial because the deadline passed first: an exact scan stops
+    /// scoring documents, and a graph search uses a smaller `ef_search` when
+    /// the tuned one wouldn't fit the time left and stops exploring at the
+    /// deadline.
+    pub async fn search_partial(
+        &self,
+        query_embedding: &[f32],
+        limit: usize,
+        options: &SearchOptions<'_>,
+    ) -> Result<(Vec<SearchResult>, bool)> {
+        let mut partial = false;
+        // Stored vectors are unit length, so with a unit query every
+        // similarity is a dot product
+        let mut query = query_embedding.to_vec();
+        simd::normalize(&mut query);
+        let query_embedding = Query::new(&query);
+
+        let state = self.state.read().unwrap();
+        // A query embedded just before the collection switched models
+        if let Some(expected) = state.dimensions(None).filter(|&d| d != query.len()) {
+            return Err(IndexError::DimensionMismatch {
+                expected,
+                found: query.len(),
+            }
+            .into());
+        }
+        let docs = &state.documents;
+        let filter = options.filter;
+        let passes =
+            |doc: &IndexedDocument| filter.is_none_or(|f| f.matches(doc.metadata.as_ref()));
+        let exact =
+            || -> Vec<&IndexedDocument> { docs.values().filter(|doc| passes(doc)).collect() };
+
+        // The graph ranks chunked documents by their mean embedding; every
+        // candidate is then scored by its best chunk
+        let mut candidates: Vec<&IndexedDocument> = if docs.len() <= EXACT_SEARCH_THRESHOLD {
+            exact()
+        } else {
+            // Over-fetch from the graph so a filter, keyword scores, or
+            // boosts can still reorder enough results, and fall back to a scan if the
+            // filter is too selective
+            let reranked = options.hybrid.is_some() || !options.boosts.is_empty();
+            let fetch = if filter.is_some() || reranked {
+                limit * FILTER_OVERFETCH
+            } else {
+                limit
+            };
+            let mut ef = self.ef_search();
+            if let (Some(deadline), Some(tuning)) = (options.deadline, self.ef_tuning()) {
+                let reduced = tuning.ef_within(deadline.saturating_duration_since(Instant::now()));
+                partial |= reduced < ef;
+                ef = ef.min(reduced);
+            }
+            let (found, cut_short) =
+                state
+                    .graph
+                    .search_until(&query_embedding, fetch, ef, options.deadline, docs);
+            partial |= cut_short;
+            let candidates: Vec<&IndexedDocument> = found
+                .into_iter()
+                .map(|(id, _)| &docs[id])
+                .filter(|doc| passes(doc))
+                .collect();
+
+            if candidates.len() < limit && filter.is_some() {
+                exact()
+            } else {
+                candidates
+            }
+        };
+
+        let lexical = options
+            .hybrid
+            .map(|(text, _)| state.lexical.scores(text))
+            .unwrap_or_default();
+        let phrases = options
+            .hybrid
+            .filter(|(_, hybrid)| hybrid.phrase_boost != 0.0)
+            .map(|(text, _)| lexical::query_phrases(text))
+            .unwrap_or_default();
+        let phrase_boost = |doc: &IndexedDocument| {
+            let (_, hybrid) = options.hybrid?;
+            if phrases.is_empty() {
+                return Some(0.0);
+            }
+            let words = lexical::words(&doc.text);
+            let matched = phrases
+                .iter()
+                .filter(|phrase| lexical::contains_phrase(&words, phrase))
+                .count();
+            Some(hybrid.phrase_boost * matched as f32 / phrases.len() as f32)
+        };
+        if candidates.len() < docs.len() {
+            // Strong keyword matches compete even if the graph missed them
+            let seen: HashSet<&str> = candidates.iter().map(|doc| doc.id.as_str()).collect();
+            let mut keyword_only: Vec<(&str, f32)> = lexical
+                .iter()
+                .filter(|(id, _)| !seen.contains(*id))
+                .map(|(&id, &score)| (id, score))
+                .collect();
+            keyword_only.sort_by(|a, b| b.1.total_cmp(&a.1));
+            candidates.extend(
+                keyword_only
+                    .into_iter()
+                    .map(|(id, _)| &docs[id])
+                    .filter(|doc| passes(doc))
+                    .take(limit * FILTER_OVERFETCH),
+            );
+        }
+
+        // Past the deadline, only the candidates scored so far are ranked
+        let mut scored = Vec::with_capacity(candidates.len());
+        for (i, doc) in candidates.into_iter().enumerate() {
+            if i > 0
+                && i % DEADLINE_CHECK_INTERVAL == 0
+                && options
+                    .deadline
+                    .is_some_and(|deadline| Instant::now() >= deadline)
+            {
+                partial = true;
+                break;
+            }
+            let similarity = if options.chunk_coverage {
+                doc.coverage_similarity(&query_embedding)
+            } else {
+                doc.similarity(&query_embedding)
+            };
+            scored.push((doc, similarity));
+        }
+
+        let mut results: Vec<SearchResult> = scored
+            .into_iter()
+            .map(|(doc, (score, chunk))| SearchResult {
+                id: doc.id.clone(),
+                score,
+                text: doc.text.clone(),
+                passage: chunk.map(|i| {
+                    let chunk = &doc.chunks[i];
+                    doc.text[chunk.start..chunk.end].to_string()
+                }),
+                truncated: false,
+                source: None,
+                collection: None,
+                duplicates: Vec::new(),
+                obsidian_uri: None,
+                explanation: Some(ScoreExplanation {
+                    dense: score,
+                    lexical: options
+                        .hybrid
+                        .map(|_| lexical.get(doc.id.as_str()).copied().unwrap_or(0.0)),
+                    phrase: phrase_boost(doc),
+                    boost: (!options.boosts.is_empty()).then(|| {
+                        options
+                            .boosts
+                            .iter()
+                            .filter(|boost| boost.filter.matches(doc.metadata.as_ref()))
+                            .map(|boost| boost.weight)
+                            .sum()
+                    }),
+                    ..Default::default()
+                }),
             })
             .collect();
 
+        if let Some((_, hybrid)) = options.hybrid {
+            fuse(&mut results, hybrid);
+        }
+        for result in &mut results {
+            let explanation = result.explanation.as_ref();
+            result.score += explanation.and_then(|e| e.phrase).unwrap_or(0.0)
+                + explanation.and_then(|e| e.boost).unwrap_or(0.0);
+        }
+
         // Sort by score descending
         results.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap());
+        if let Some(min_score) = options.min_score {
+            results.retain(|result| result.score >= min_score);
+        }
 
         // Return top k
         results.truncate(limit);
 
-        Ok(results)
+        Ok((results, partial))
+    }
+
+    /// Documents most similar to the stored document `id` (or alias), which
+    /// is left out of them, along with its canonical id. `None` if there is
+    /// no such document.
+    pub async fn similar(
+        &self,
+        id: &str,
+        limit: usize,
+    ) -> Result<Option<(String, Vec<SearchResult>)>> {
+        let (id, embedding) = {
+            let state = self.state.read().unwrap();
+            let id = state.resolve(id);
+            let Some(doc) = state.documents.get(id) else {
+                return Ok(None);
+            };
+            (id.to_string(), doc.embedding.to_f32().into_owned())
+        };
+
+        let mut results = self
+            .search(&embedding, limit + 1, &SearchOptions::default())
+            .await?;
+        results.retain(|result| result.id != id);
+        results.truncate(limit);
+        Ok(Some((id, results)))
     }
 
+    /// Look up a document by id or alias.
     pub async fn get(&self, id: &str) -> Result<Option<IndexedDocument>> {
-        let docs = self.documents.read().unwrap();
-        Ok(docs.get(id).cloned())
+        let state = self.state.read().unwrap();
+        Ok(state.documents.get(state.resolve(id)).cloned())
+    }
+
+    /// Look up several documents under one lock, in the order requested.
+    pub async fn get_many(&self, ids: &[String]) -> Result<Vec<Option<IndexedDocument>>> {
+        let state = self.state.read().unwrap();
+        Ok(ids
+            .iter()
+            .map(|id| state.documents.get(state.resolve(id)).cloned())
+            .collect())
+    }
+
+    pub async fn list(&self) -> Result<Vec<IndexedDocument>> {
+        let state = self.state.read().unwrap();
+        Ok(state.documents.values().cloned().collect())
     }
 
+    /// Ids of every document, without copying the documents.
+    pub async fn ids(&self) -> Vec<String> {
+        let state = self.state.read().unwrap();
+        state.documents.keys().cloned().collect()
+    }
+
+    /// Delete a document by id or alias, along with all its aliases.
     pub async fn delete(&self, id: &str) -> Result<bool> {
-        let mut docs = self.documents.write().unwrap();
-        Ok(docs.remove(id).is_some())
+        self.delete_where(id, None)
+    }
+
+    /// Delete a document by id or alias if it is still at `version`.
+    /// Returns false if there is no such document, or it has changed since.
+    pub async fn delete_version(&self, id: &str, version: u64) -> Result<bool> {
+        self.delete_where(id, Some(version))
+    }
+
+    fn delete_where(&self, id: &str, version: Option<u64>) -> Result<bool> {
+        self.writable()?;
+        let mut state = self.state.write().unwrap();
+        let id = state.resolve(id).to_string();
+        match (state.documents.get(&id), version) {
+            (None, _) => return Ok(false),
+            (Some(doc), Some(version)) if doc.version != version => return Ok(false),
+            _ => {}
+        }
+
+        let snapshot_due = self.log(&LogRecord::Delete(&id))?;
+        if let Some(old) = state.remove_document(&id) {
+            state.lexical.remove(&id, &old.text);
+            state.graph.remove(&id, old.embedding);
+        }
+        self.after_mutation(&mut state, snapshot_due)?;
+        Ok(true)
+    }
+
+    /// Run `f` over the stored state: documents by id, aliases, and
+    /// product quantization centroids. Writers wait until it returns.
+    pub fn read_contents<R>(
+        &self,
+        f: impl FnOnce(
+            &HashMap<String, IndexedDocument>,
+            &HashMap<String, String>,
+            Option<&ProductQuantizer>,
+        ) -> R,
+    ) -> R {
+        let state = self.state.read().unwrap();
+        f(&state.documents, &state.aliases, state.quantizer.as_deref())
+    }
+
+    /// Re-index every document for keyword search with `analyzer`.
+    pub fn set_analyzer(&self, analyzer: Analyzer) {
+        let mut state = self.state.write().unwrap();
+        state.lexical = Bm25Index::build(analyzer, std::iter::empty());
+        rebuild_lexical(&mut state);
     }
 
     pub async fn clear(&self) -> Result<()> {
-        let mut docs = self.documents.write().unwrap();
-        docs.clear();
+        self.writable()?;
+        let mut state = self.state.write().unwrap();
+        let snapshot_due = self.log(&LogRecord::Clear)?;
+        state.documents.clear();
+        state.aliases.clear();
+        state.graph = Hnsw::new(self.hnsw);
+        state.lexical.rebuild(std::iter::empty());
+        self.after_mutation(&mut state, snapshot_due)?;
         Ok(())
     }
 
+    /// Make `alias` resolve to the document `id` (itself possibly an alias).
+    /// Returns the canonical id the alias points at.
+    pub async fn add_alias(&self, alias: &str, id: &str) -> Result<String> {
+        self.writable()?;
+        let mut state = self.state.write().unwrap();
+        let id = state.resolve(id).to_string();
+        if !state.documents.contains_key(&id) {
+            return Err(IndexError::NotFound(id).into());
+        }
+        if state.documents.contains_key(alias) {
+            return Err(IndexError::AliasIsDocument(alias.to_string()).into());
+        }
+
+        let snapshot_due = self.log(&LogRecord::Alias { alias, id: &id })?;
+        state.aliases.insert(alias.to_string(), id.clone());
+        self.after_mutation(&mut state, snapshot_due)?;
+        Ok(id)
+    }
+
+    /// Remove an alias, leaving the document it pointed at untouched.
+    pub async fn remove_alias(&self, alias: &str) -> Result<bool> {
+        self.writable()?;
+        let mut state = self.state.write().unwrap();
+        if !state.aliases.contains_key(alias) {
+            return Ok(false);
+        }
+
+        let snapshot_due = self.log(&LogRecord::Unalias(alias))?;
+        state.aliases.remove(alias);
+        self.after_mutation(&mut state, snapshot_due)?;
+        Ok(true)
+    }
+
+    /// Every alias and the canonical id it resolves to.
+    pub async fn aliases(&self) -> HashMap<String, String> {
+        let state = self.state.read().unwrap();
+        state.aliases.clone()
+    }
+
+    /// Replace the vectors of documents re-embedded by a rebuild, given as
+    /// `(id, version, embedding)`, keeping their text, metadata, and
+    /// version. Documents deleted or replaced since they were read for the
+    /// rebuild are left alone. Returns how many were replaced. The graph is
+    /// only updated by [`finish_rebuild`](Self::finish_rebuild).
+    pub async fn replace_vectors(
+        &self,
+        embedded: Vec<(String, u64, DocumentEmbedding)>,
+    ) -> Result<usize> {
+        self.replace_vectors_with(embedded, || Ok(())).await
+    }
+
+    /// [`replace_vectors`](Self::replace_vectors), calling `switch` first
+    /// with the index locked, so no search or write sees the new vectors
+    /// without whatever it changes, e.g. the model they were embedded by.
+    /// Nothing is replaced if it fails.
+    pub async fn replace_vectors_with(
+        &self,
+        embedded: Vec<(String, u64, DocumentEmbedding)>,
+        switch: impl FnOnce() -> Result<()>,
+    ) -> Result<usize> {
+        self.writable()?;
+        let mut state = self.state.write().unwrap();
+        switch()?;
+        let mut snapshot_due = false;
+        let mut replaced = 0;
+        self.replacements.fetch_add(1, Ordering::Relaxed);
+        for (id, version, embedding) in embedded {
+            let Some(doc) = state.documents.get_mut(&id) else {
+                continue;
+            };
+            if doc.version != version {
+                continue;
+            }
+            // Product quantization centroids belong to the old vectors, so
+            // the new ones are kept whole until they are learned again
+            doc.embedding = self.encode(embedding.embedding, None);
+            doc.chunks = embedding
+                .chunks
+                .into_iter()
+                .map(|chunk| Chunk {
+                    start: chunk.span.start,
+                    end: chunk.span.end,
+                    embedding: self.encode(chunk.embedding, None),
+                })
+                .collect();
+            snapshot_due |= self.log(&LogRecord::Put(doc))?;
+            replaced += 1;
+        }
+        if snapshot_due {
+            self.after_mutation(&mut state, true)?;
+        }
+        Ok(replaced)
+    }
+
+    /// Replace the metadata of a document still at `version`, keeping its
+    /// text and vectors, as a new version. Returns false if there is no
+    /// such document, or it has changed since.
+    pub async fn set_metadata(
+        &self,
+        id: &str,
+        version: u64,
+        metadata: Option<Value>,
+    ) -> Result<bool> {
+        self.writable()?;
+        let mut state = self.state.write().unwrap();
+        let Some(doc) = state
+            .documents
+            .get_mut(id)
+            .filter(|doc| doc.version == version)
+        else {
+            return Ok(false);
+        };
+        doc.metadata = metadata;
+        doc.version += 1;
+        doc.updated_at = Some(Utc::now());
+        let snapshot_due = self.log(&LogRecord::Put(doc))?;
+        self.after_mutation(&mut state, snapshot_due)?;
+        Ok(true)
+    }
+
+    /// Finish a rebuild: learn product quantization centroids afresh from
+    /// the new vectors, rebuild the graph and keyword index with the
+    /// current parameters, and write a snapshot.
+    pub async fn finish_rebuild(&self) -> Result<()> {
+        self.writable()?;
+        let mut state = self.state.write().unwrap();
+        let retrain = self.quantization.mode == Quantization::Pq;
+        if retrain {
+            state.quantizer = None;
+            self.replacements.fetch_add(1, Ordering::Relaxed);
+        }
+        state.graph = Hnsw::build(self.hnsw, &state.documents);
+        rebuild_lexical(&mut state);
+        // Training writes the snapshot once the vectors are coded again
+        self.after_mutation(&mut state, !retrain)?;
+        drop(state);
+        self.train_quantizer_if_due(true)
+    }
+
+    /// Cross-check documents, aliases, vectors, and search indexes for
+    /// inconsistencies. With `repair`, fixable issues are fixed: dangling
+    /// and shadowing aliases are dropped, misfiled documents re-keyed, and
+    /// the graph and keyword index rebuilt. Bad vectors are only reported, as fixing them
+    /// needs the document re-embedded.
+    pub async fn verify(&self, repair: bool) -> Result<VerifyReport> {
+        if repair {
+            self.writable()?;
+        }
+        let mut state = self.state.write().unwrap();
+        let mut issues = Vec::new();
+
+        for (key, doc) in &state.documents {
+            if *key != doc.id {
+                issues.push(Issue::new(
+                    IssueKind::IdMismatch,
+                    key,
+                    format!("stored under {:?} but has id {:?}", key, doc.id),
+                ));
+            }
+        }
+
+        for (alias, target) in &state.aliases {
+            if state.documents.contains_key(alias) {
+                issues.push(Issue::new(
+                    IssueKind::AliasShadowsDocument,
+                    alias,
+                    format!("alias for {:?} has the same id as a document", target),
+                ));
+            } else if !state.documents.contains_key(target) {
+                issues.push(Issue::new(
+                    IssueKind::DanglingAlias,
+                    alias,
+                    format!("points at missing document {:?}", target),
+                ));
+            }
+        }
+
+        // The most common length is taken as the collection's dimensions
+        let mut lengths: HashMap<usize, usize> = HashMap::new();
+        for doc in state.documents.values() {
+            *lengths.entry(doc.embedding.to_f32().len()).or_default() += 1;
+        }
+        let dimensions = lengths
+            .into_iter()
+            .max_by_key(|&(len, count)| (count, len))
+            .map(|(len, _)| len);
+        for doc in state.documents.values() {
+            let vector = doc.embedding.to_f32();
+            if Some(vector.len()) != dimensions {
+                issues.push(Issue::new(
+                    IssueKind::DimensionMismatch,
+                    &doc.id,
+                    format!(
+                        "{} dimensions, expected {}",
+                        vector.len(),
+                        dimensions.unwrap_or(0)
+                    ),
+                ));
+            }
+            if vector.iter().any(|x| !x.is_finite()) {
+                issues.push(Issue::new(
+                    IssueKind::NonFiniteVector,
+                    &doc.id,
+                    "vector contains NaN or infinite values",
+                ));
+            }
+        }
+
+        issues.extend(state.graph.verify(&state.documents));
+        if state.lexical.len() != state.documents.len() {
+            issues.push(Issue {
+                kind: IssueKind::LexicalCounts,
+                id: None,
+                detail: format!(
+                    "keyword index covers {} of {} documents",
+                    state.lexical.len(),
+                    state.documents.len()
+                ),
+                repaired: false,
+            });
+        }
+
+        if repair && !issues.is_empty() {
+            self.repair(&mut state, &mut issues)?;
+        }
+
+        Ok(VerifyReport {
+            documents: state.documents.len(),
+            aliases: state.aliases.len(),
+            issues,
+        })
+    }
+
+    fn repair(&self, state: &mut IndexState, issues: &mut [Issue]) -> Result<()> {
+        let mut snapshot_due = false;
+        let mut rebuild = false;
+
+        for issue in issues.iter_mut() {
+            match issue.kind {
+                IssueKind::IdMismatch => {
+                    let key = issue.id.as_deref().unwrap_or_default();
+                    if let Some(doc) = state.documents.remove(key) {
+                        snapshot_due |= self.log(&LogRecord::Delete(key))?;
+                        snapshot_due |= self.log(&LogRecord::Put(&doc))?;
+                        state.documents.insert(doc.id.clone(), doc);
+                    }
+                    rebuild = true;
+                }
+                IssueKind::DanglingAlias | IssueKind::AliasShadowsDocument => {
+                    let alias = issue.id.as_deref().unwrap_or_default();
+                    snapshot_due |= self.log(&LogRecord::Unalias(alias))?;
+                    state.aliases.remove(alias);
+                }
+                IssueKind::GraphMissingDocument
+                | IssueKind::GraphDanglingNode
+                | IssueKind::GraphInvalidLink
+                | IssueKind::GraphCounts
+                | IssueKind::LexicalCounts => rebuild = true,
+                IssueKind::DimensionMismatch | IssueKind::NonFiniteVector => continue,
+            }
+            issue.repaired = true;
+        }
+
+        if rebuild {
+            info!("Rebuilding search indexes after verification");
+            state.graph = Hnsw::build(self.hnsw, &state.documents);
+            rebuild_lexical(state);
+        }
+        self.after_mutation(state, snapshot_due)
+    }
+
+    /// Number of documents whose metadata passes `filter`.
+    pub async fn count_matching(&self, filter: &Filter) -> usize {
+        let state = self.state.read().unwrap();
+        state
+            .documents
+            .values()
+            .filter(|doc| filter.matches(doc.metadata.as_ref()))
+            .count()
+    }
+
     pub async fn count(&self) -> usize {
-        let docs = self.documents.read().unwrap();
-        docs.len()
+        let state = self.state.read().unwrap();
+        state.documents.len()
+    }
+
+    pub async fn vector_bytes(&self) -> usize {
+        let state = self.state.read().unwrap();
+        state
+            .documents
+            .values()
+            .flat_map(|doc| {
+                iter::once(&doc.embedding).chain(doc.chunks.iter().map(|c| &c.embedding))
+            })
+            .map(StoredVector::size_bytes)
+            .sum()
+    }
+
+    /// Estimate of the memory the collection holds, by what holds it.
+    pub async fn memory(&self) -> MemoryUsage {
+        let state = self.state.read().unwrap();
+        let mut usage = MemoryUsage {
+            graph: state.graph.size_bytes(),
+            keyword_index: state.lexical.size_bytes(),
+            ..Default::default()
+        };
+        for doc in state.documents.values() {
+            usage.vectors += iter::once(&doc.embedding)
+                .chain(doc.chunks.iter().map(|c| &c.embedding))
+                .map(StoredVector::size_bytes)
+                .sum::<usize>();
+            usage.documents += doc.id.len()
+                + doc.text.len()
+                + doc
+                    .metadata
+                    .as_ref()
+                    .map_or(0, |metadata| metadata.to_string().len());
+        }
+        usage.total = usage.vectors + usage.documents + usage.graph + usage.keyword_index;
+        usage
+    }
+}
+
+impl VectorIndex {
+    /// Persist a mutation before it is applied. Returns whether a snapshot
+    /// is due afterwards.
+    fn log(&self, record: &LogRecord) -> Result<bool> {
+        #[cfg(feature = "chaos")]
+        if self.storage.is_some() {
+            crate::chaos::before_storage_write(match record {
+                LogRecord::Put(doc) => Some(doc.id.as_str()),
+                LogRecord::Delete(id) => Some(*id),
+                _ => None,
+            })?;
+        }
+
+        match &self.storage {
+            Some(storage) => storage.append(record),
+            None => Ok(false),
+        }
+    }
+
+    fn quantizer_due(&self, state: &IndexState) -> bool {
+        self.quantization.mode == Quantization::Pq
+            && state.quantizer.is_none()
+            && state.documents.len() >= self.quantization.pq_train_size
+    }
+
+    /// Learn product quantization centroids once enough vectors are
+    /// indexed, then code every vector against them. The vectors are
+    /// copied out under a read lock, and k-means and coding run with no
+    /// lock held, so searches and writes carry on meanwhile; only swapping
+    /// the codes in takes the write lock, coding there just the documents
+    /// written in the meantime. If another training is running, `wait`
+    /// decides whether to wait for it or leave the work to it.
+    fn train_quantizer_if_due(&self, wait: bool) -> Result<()> {
+        let _training = match self.training.try_lock() {
+            Ok(training) => training,
+            Err(_) if wait => self.training.lock().unwrap(),
+            Err(_) => return Ok(()),
+        };
+        // Copies replaced while training are learned from again
+        loop {
+            let (copied, replacements) = {
+                let state = self.state.read().unwrap();
+                if !self.quantizer_due(&state) {
+                    return Ok(());
+                }
+                let copied: Vec<(String, u64, Vec<Vec<f32>>)> = state
+                    .documents
+                    .values()
+                    .map(|doc| {
+                        let vectors = doc.vectors().map(|v| v.to_f32().into_owned()).collect();
+                        (doc.id.clone(), doc.version, vectors)
+                    })
+                    .collect();
+                (copied, self.replacements.load(Ordering::Relaxed))
+            };
+
+            let vectors: Vec<Vec<f32>> = copied
+                .iter()
+                .flat_map(|(_, _, vectors)| vectors.iter().cloned())
+                .collect();
+            let quantizer = match ProductQuantizer::train(&vectors, self.quantization.pq_subvectors)
+            {
+                Ok(quantizer) => Arc::new(quantizer),
+                Err(e) => {
+                    warn!("Failed to learn product quantization centroids: {}", e);
+                    return Ok(());
+                }
+            };
+            let trained = vectors.len();
+            drop(vectors);
+            let coded: Vec<(String, u64, Vec<StoredVector>)> = copied
+                .into_iter()
+                .map(|(id, version, vectors)| {
+                    let codes = vectors
+                        .into_iter()
+                        .map(|vector| quantizer.encode(vector))
+                        .collect();
+                    (id, version, codes)
+                })
+                .collect();
+
+            let mut state = self.state.write().unwrap();
+            if !self.quantizer_due(&state) {
+                return Ok(());
+            }
+            if self.replacements.load(Ordering::Relaxed) != replacements {
+                continue;
+            }
+            self.log(&LogRecord::Quantizer(&quantizer))?;
+            // Vectors written since were stored whole, so a document still
+            // holding codes of an earlier quantizer only had its metadata
+            // changed
+            for (id, version, codes) in coded {
+                if let Some(doc) = state.documents.get_mut(&id).filter(|doc| {
+                    doc.version == version || matches!(doc.embedding, StoredVector::Pq(_))
+                }) {
+                    for (vector, code) in doc.vectors_mut().zip(codes) {
+                        *vector = code;
+                    }
+                }
+            }
+            info!(
+                "Learned product quantization centroids from {} vectors ({} bytes per vector)",
+                trained,
+                quantizer.subvectors()
+            );
+            state.quantizer = Some(quantizer);
+            state.quantize_all(self.quantization.mode);
+            // Compacts the full-precision vectors in the log away
+            return self.after_mutation(&mut state, self.storage.is_some());
+        }
+    }
+
+    /// Housekeeping after a mutation: rebuild the graph once deleted nodes
+    /// outnumber live ones, pin its entry layers again if they are pinned
+    /// and it has grown or been rebuilt, and write a snapshot if one is due.
+    fn after_mutation(&self, state: &mut IndexState, snapshot_due: bool) -> Result<()> {
+        if state.graph.needs_rebuild() {
+            state.graph = Hnsw::build(self.hnsw, &state.documents);
+        }
+        if self.pin_entry_layers.load(Ordering::Relaxed) && state.graph.pin_is_due() {
+            if let Err(e) = state.graph.pin(&state.documents) {
+                warn!("Stopped pinning the graph's entry layers: {}", e);
+                self.pin_entry_layers.store(false, Ordering::Relaxed);
+            }
+        }
+        if let (Some(storage), true) = (&self.storage, snapshot_due) {
+            storage.snapshot(
+                &state.documents.values().collect::<Vec<_>>(),
+                &state.aliases,
+                state.quantizer.as_deref(),
+            )?;
+        }
+        Ok(())
     }
 }
 
-fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
-    assert_eq!(a.len(), b.len(), "Vectors must have same length");
+/// Collection used when a request doesn't name one.
+pub const DEFAULT_COLLECTION: &str = "default";
+
+const SETTINGS_FILE: &str = "settings.json";
+
+/// Settings of one collection, stored in its directory.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct CollectionSettings {
+    /// Multiplies the collection's scores when it is searched together with
+    /// others, so e.g. your own notes outrank clippings at equal similarity
+    #[serde(default = "default_weight")]
+    pub weight: f32,
+    /// Model the collection's documents and queries are embedded with,
+    /// `None` for the base model
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    /// How text is split into terms for keyword search
+    #[serde(default, skip_serializing_if = "AnalyzerSettings::is_default")]
+    pub analyzer: AnalyzerSettings,
+    /// How documents added through the API get their ids
+    #[serde(default, skip_serializing_if = "IdPolicy::is_default")]
+    pub id_policy: IdPolicy,
+    /// Template of metadata fields embedded with each document's text, see
+    /// [`metadata::render_template`](crate::metadata::render_template)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub embed_template: Option<String>,
+}
 
-    let dot_product: f32 = a.iter().zip(b.iter()).map(|(x, y)| x * y).sum();
-    let norm_a: f32 = a.iter().map(|x| x * x).sum::<f32>().sqrt();
-    let norm_b: f32 = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+fn default_weight() -> f32 {
+    1.0
+}
 
-    if norm_a == 0.0 || norm_b == 0.0 {
-        return 0.0;
+impl Default for CollectionSettings {
+    fn default() -> Self {
+        Self {
+            weight: default_weight(),
+            model: None,
+            analyzer: AnalyzerSettings::default(),
+            id_policy: IdPolicy::default(),
+            embed_template: None,
+        }
     }
+}
+
+fn read_settings(dir: &Path) -> Result<CollectionSettings> {
+    let path = dir.join(SETTINGS_FILE);
+    if !path.exists() {
+        return Ok(CollectionSettings::default());
+    }
+    Ok(serde_json::from_str(&fs::read_to_string(&path)?)?)
+}
+
+/// Store a collection's settings in its directory `dir`.
+pub fn write_settings(dir: &Path, settings: &CollectionSettings) -> Result<()> {
+    fs::write(
+        dir.join(SETTINGS_FILE),
+        serde_json::to_string_pretty(settings)?,
+    )?;
+    Ok(())
+}
+
+#[derive(Serialize)]
+pub struct CollectionInfo {
+    pub name: String,
+    pub documents: usize,
+    pub weight: f32,
+    /// Memory held by the stored vectors, including chunks
+    pub vector_bytes: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(skip_serializing_if = "AnalyzerSettings::is_default")]
+    pub analyzer: AnalyzerSettings,
+    #[serde(skip_serializing_if = "IdPolicy::is_default")]
+    pub id_policy: IdPolicy,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub embed_template: Option<String>,
+    /// Served from a bundle, so searchable but not writable
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub read_only: bool,
+    /// How `ef_search` was last tuned to the latency target, if it was
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ef_tuning: Option<EfTuning>,
+}
 
-    dot_product / (norm_a * norm_b)
+/// Named, isolated indexes, e.g. one per vault. Each collection is a
+/// separate [`VectorIndex`] persisted in its own directory under
+/// `<data dir>/collections`.
+pub struct Collections {
+    data_dir: PathBuf,
+    precision: Precision,
+    quantization: QuantizationConfig,
+    hnsw: HnswConfig,
+    storage: StorageConfig,
+    // Taken in this order by whatever holds more than one. A collection's
+    // own index may be locked first, but is never locked while they are held
+    collections: RwLock<BTreeMap<String, Arc<VectorIndex>>>,
+    settings: RwLock<HashMap<String, CollectionSettings>>,
+    /// Bundle file of each collection mounted from one
+    bundles: RwLock<HashMap<String, PathBuf>>,
+    /// Names of collections on their way in, e.g. bundles being downloaded
+    claimed: Mutex<HashSet<String>>,
+}
+
+/// A collection name held for a collection that is being set up, released
+/// when dropped.
+pub struct Claim<'a> {
+    collections: &'a Collections,
+    name: String,
+}
+
+impl Drop for Claim<'_> {
+    fn drop(&mut self) {
+        self.collections.claimed.lock().unwrap().remove(&self.name);
+    }
+}
+
+impl Collections {
+    /// Open every collection previously created under `data_dir`, creating
+    /// the default collection if it doesn't exist yet.
+    pub fn open(
+        data_dir: &Path,
+        precision: Precision,
+        quantization: QuantizationConfig,
+        hnsw: HnswConfig,
+        storage: StorageConfig,
+    ) -> Result<Self> {
+        let dir = data_dir.join("collections");
+        let mut collections = BTreeMap::new();
+        let mut settings = HashMap::new();
+        if dir.exists() {
+            for entry in fs::read_dir(&dir)? {
+                let entry = entry?;
+                let name = entry.file_name().to_string_lossy().to_string();
+                if entry.file_type()?.is_dir() && valid_collection_name(&name) {
+                    let index =
+                        VectorIndex::open(&entry.path(), precision, quantization, hnsw, storage)?;
+                    let collection_settings = read_settings(&entry.path())?;
+                    if !collection_settings.analyzer.is_default() {
+                        index.set_analyzer(Analyzer::new(&collection_settings.analyzer));
+                    }
+                    settings.insert(name.clone(), collection_settings);
+                    collections.insert(name, Arc::new(index));
+                }
+            }
+        }
+
+        if !collections.contains_key(DEFAULT_COLLECTION) {
+            let index = VectorIndex::open(
+                &dir.join(DEFAULT_COLLECTION),
+                precision,
+                quantization,
+                hnsw,
+                storage,
+            )?;
+            collections.insert(DEFAULT_COLLECTION.to_string(), Arc::new(index));
+            settings.insert(DEFAULT_COLLECTION.to_string(), Default::default());
+        }
+
+        Ok(Self {
+            data_dir: data_dir.to_path_buf(),
+            precision,
+            quantization,
+            hnsw,
+            storage,
+            collections: RwLock::new(collections),
+            settings: RwLock::new(settings),
+            bundles: RwLock::new(HashMap::new()),
+            claimed: Mutex::new(HashSet::new()),
+        })
+    }
+
+    pub fn precision(&self) -> Precision {
+        self.precision
+    }
+
+    pub fn quantization(&self) -> QuantizationConfig {
+        self.quantization
+    }
+
+    pub fn hnsw(&self) -> HnswConfig {
+        self.hnsw
+    }
+
+    /// Serve the read-only `index` loaded from the bundle at `path` as the
+    /// collection `name`.
+    pub fn mount(
+        &self,
+        name: &str,
+        index: VectorIndex,
+        settings: CollectionSettings,
+        path: &Path,
+    ) -> Result<()> {
+        if !valid_collection_name(name) {
+            return Err(IndexError::InvalidCollectionName(name.to_string()).into());
+        }
+
+        // Indexing every text takes a while, so isn't done under the lock
+        if !settings.analyzer.is_default() {
+            index.set_analyzer(Analyzer::new(&settings.analyzer));
+        }
+
+        let mut collections = self.collections.write().unwrap();
+        if collections.contains_key(name) {
+            return Err(IndexError::CollectionExists(name.to_string()).into());
+        }
+        collections.insert(name.to_string(), Arc::new(index));
+        self.settings
+            .write()
+            .unwrap()
+            .insert(name.to_string(), settings);
+        self.bundles
+            .write()
+            .unwrap()
+            .insert(name.to_string(), path.to_path_buf());
+        info!("Mounted bundle {:?} as collection {}", path, name);
+        Ok(())
+    }
+
+    /// Hold `name` for a collection that takes a while to set up, so no
+    /// other can be created or claimed under it meanwhile.
+    pub fn claim(&self, name: &str) -> Result<Claim<'_>> {
+        if !valid_collection_name(name) {
+            return Err(IndexError::InvalidCollectionName(name.to_string()).into());
+        }
+        let collections = self.collections.read().unwrap();
+        let mut claimed = self.claimed.lock().unwrap();
+        if collections.contains_key(name) || !claimed.insert(name.to_string()) {
+            return Err(IndexError::CollectionExists(name.to_string()).into());
+        }
+        Ok(Claim {
+            collections: self,
+            name: name.to_string(),
+        })
+    }
+
+    pub async fn create(&self, name: &str) -> Result<Arc<VectorIndex>> {
+        if !valid_collection_name(name) {
+            return Err(IndexError::InvalidCollectionName(name.to_string()).into());
+        }
+
+        let mut collections = self.collections.write().unwrap();
+        if collections.contains_key(name) || self.claimed.lock().unwrap().contains(name) {
+            return Err(IndexError::CollectionExists(name.to_string()).into());
+        }
+
+        let index = Arc::new(VectorIndex::open(
+            &self.data_dir.join("collections").join(name),
+            self.precision,
+            self.quantization,
+            self.hnsw,
+            self.storage,
+        )?);
+        collections.insert(name.to_string(), index.clone());
+        self.settings
+            .write()
+            .unwrap()
+            .insert(name.to_string(), CollectionSettings::default());
+        info!("Created collection {}", name);
+        Ok(index)
+    }
+
+    /// Look up a collection, falling back to the default one when `name`
+    /// is `None`.
+    pub async fn get(&self, name: Option<&str>) -> Result<Arc<VectorIndex>> {
+        let name = name.unwrap_or(DEFAULT_COLLECTION);
+        let collections = self.collections.read().unwrap();
+        collections
+            .get(name)
+            .cloned()
+            .ok_or_else(|| IndexError::CollectionNotFound(name.to_string()).into())
+    }
+
+    /// Every collection by name, in name order.
+    pub fn all(&self) -> Vec<(String, Arc<VectorIndex>)> {
+        let collections = self.collections.read().unwrap();
+        collections
+            .iter()
+            .map(|(name, index)| (name.clone(), index.clone()))
+            .collect()
+    }
+
+    /// Every collection but those mounted from bundles, which are
+    /// distributed as bundles rather than backed up or rebuilt.
+    pub fn writable(&self) -> Vec<(String, Arc<VectorIndex>)> {
+        let mut all = self.all();
+        all.retain(|(_, index)| !index.is_read_only());
+        all
+    }
+
+    /// Settings of the collection `name`, or of the default collection.
+    pub async fn settings(&self, name: Option<&str>) -> Result<CollectionSettings> {
+        let name = name.unwrap_or(DEFAULT_COLLECTION);
+        let settings = self.settings.read().unwrap();
+        settings
+            .get(name)
+            .cloned()
+            .ok_or_else(|| IndexError::CollectionNotFound(name.to_string()).into())
+    }
+
+    /// Directory a collection's files are stored in.
+    pub fn dir(&self, name: &str) -> PathBuf {
+        self.data_dir.join("collections").join(name)
+    }
+
+    /// Replace a collection's settings, persisting them.
+    pub async fn update_settings(&self, name: &str, settings: CollectionSettings) -> Result<()> {
+        let index = self.get(Some(name)).await?;
+        let analyzer_changed = self.store_settings(name, &settings)?;
+        // Re-indexing every text locks only this collection, not them all
+        if analyzer_changed {
+            index.set_analyzer(Analyzer::new(&settings.analyzer));
+        }
+        Ok(())
+    }
+
+    /// Bind a collection to `model` in the same step as its vectors are
+    /// replaced by the ones `model` embedded, given as for
+    /// [`VectorIndex::replace_vectors`]. Returns how many were replaced.
+    pub async fn rebind(
+        &self,
+        name: &str,
+        model: Option<String>,
+        embedded: Vec<(String, u64, DocumentEmbedding)>,
+    ) -> Result<usize> {
+        let index = self.get(Some(name)).await?;
+        let mut settings = self.settings(Some(name)).await?;
+        settings.model = model;
+        index
+            .replace_vectors_with(embedded, || self.store_settings(name, &settings).map(drop))
+            .await
+    }
+
+    /// Persist and publish a collection's settings, returning whether its
+    /// analyzer changed.
+    fn store_settings(&self, name: &str, settings: &CollectionSettings) -> Result<bool> {
+        if self.bundles.read().unwrap().contains_key(name) {
+            return Err(IndexError::ReadOnly.into());
+        }
+        let mut all = self.settings.write().unwrap();
+        let Some(current) = all.get_mut(name) else {
+            return Err(IndexError::CollectionNotFound(name.to_string()).into());
+        };
+        write_settings(&self.dir(name), settings)?;
+        let changed = settings.analyzer != current.analyzer;
+        *current = settings.clone();
+        Ok(changed)
+    }
+
+    pub async fn list(&self) -> Vec<CollectionInfo> {
+        let collections = self.all();
+
+        let mut infos = Vec::with_capacity(collections.len());
+        for (name, index) in collections {
+            let settings = self.settings(Some(&name)).await.unwrap_or_default();
+            infos.push(CollectionInfo {
+                name,
+                documents: index.count().await,
+                weight: settings.weight,
+                vector_bytes: index.vector_bytes().await,
+                model: settings.model,
+                analyzer: settings.analyzer,
+                id_policy: settings.id_policy,
+                embed_template: settings.embed_template,
+                read_only: index.is_read_only(),
+                ef_tuning: index.ef_tuning(),
+            });
+        }
+        infos
+    }
+
+    /// Delete a collection and everything stored for it, or unmount one
+    /// mounted from a bundle. The bundle file is the user's, so rather than
+    /// being removed it is renamed to `<name>.bundle.unmounted`, which
+    /// isn't mounted again on the next start.
+    pub async fn delete(&self, name: &str) -> Result<()> {
+        if name == DEFAULT_COLLECTION {
+            return Err(IndexError::DeleteDefaultCollection.into());
+        }
+
+        let mut collections = self.collections.write().unwrap();
+        if !collections.contains_key(name) {
+            return Err(IndexError::CollectionNotFound(name.to_string()).into());
+        }
+
+        let mut settings = self.settings.write().unwrap();
+        let mut bundles = self.bundles.write().unwrap();
+        if let Some(bundle) = bundles.get(name) {
+            // Renamed first, so a failure leaves it mounted, as on disk
+            let unmounted = bundle.with_extension("bundle.unmounted");
+            fs::rename(bundle, &unmounted)?;
+            info!("Unmounted bundle {:?} to {:?}", bundle, unmounted);
+            bundles.remove(name);
+            collections.remove(name);
+            settings.remove(name);
+            return Ok(());
+        }
+        collections.remove(name);
+        settings.remove(name);
+        fs::remove_dir_all(self.data_dir.join("collections").join(name))?;
+        info!("Deleted collection {}", name);
+        Ok(())
+    }
+}
+
+/// Collection names double as directory names, so keep them simple.
+pub fn valid_collection_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    simd::cosine(a, b)
 }
 
 #[cfg(test)]
@@ -118,4 +2110,574 @@ mod tests {
         let b = vec![0.0, 1.0, 0.0];
         assert!((cosine_similarity(&a, &b) - 0.0).abs() < 0.001);
     }
+
+    #[test]
+    fn test_chunk_coverage_rewards_documents_matching_throughout() {
+        let chunked = |vectors: [[f32; 2]; 2]| IndexedDocument {
+            id: String::new(),
+            embedding: StoredVector::F32(vec![1.0, 0.0]),
+            text: String::new(),
+            metadata: None,
+            version: 1,
+            updated_at: None,
+            chunks: vectors
+                .into_iter()
+                .map(|vector| Chunk {
+                    start: 0,
+                    end: 0,
+                    embedding: StoredVector::F32(vector.to_vec()),
+                })
+                .collect(),
+        };
+        let passing_mention = chunked([[0.0, 1.0], [1.0, 0.0]]);
+        let throughout = chunked([[0.96, 0.28], [0.96, 0.28]]);
+        let query = Query::new(&[1.0, 0.0]);
+
+        assert_eq!(passing_mention.similarity(&query), (1.0, Some(1)));
+        let (score, chunk) = passing_mention.coverage_similarity(&query);
+        assert!((score - 0.75).abs() < 1e-6);
+        assert_eq!(chunk, Some(1));
+        assert!(throughout.coverage_similarity(&query).0 > score);
+        assert!((throughout.coverage_similarity(&query).0 - 0.96).abs() < 1e-6);
+    }
+
+    #[tokio::test]
+    async fn test_aliases_resolve_to_canonical_document() {
+        let index = VectorIndex::new(
+            Precision::F32,
+            QuantizationConfig::default(),
+            HnswConfig::default(),
+        );
+        let created = index
+            .add("new.md", vec![1.0, 0.0], "v1".to_string(), None)
+            .await
+            .unwrap();
+        assert_eq!(
+            (created.status, created.version),
+            (UpsertStatus::Created, 1)
+        );
+        index.add_alias("old.md", "new.md").await.unwrap();
+
+        assert_eq!(index.get("old.md").await.unwrap().unwrap().id, "new.md");
+        assert!(index.add_alias("new.md", "old.md").await.is_err());
+
+        // Re-indexing under the alias updates the canonical entry in place
+        let updated = index
+            .add("old.md", vec![0.0, 1.0], "v2".to_string(), None)
+            .await
+            .unwrap();
+        assert_eq!(updated.id, "new.md");
+        assert_eq!(
+            (updated.status, updated.version),
+            (UpsertStatus::Updated, 2)
+        );
+        assert_eq!(index.count().await, 1);
+        assert_eq!(index.get("new.md").await.unwrap().unwrap().text, "v2");
+
+        // Deleting through the alias removes the document and its aliases
+        assert!(index.delete("old.md").await.unwrap());
+        assert_eq!(index.count().await, 0);
+        assert!(index.aliases().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_search_rejects_query_of_other_dimensions() {
+        let index = VectorIndex::new(
+            Precision::F32,
+            QuantizationConfig::default(),
+            HnswConfig::default(),
+        );
+        index
+            .add("a", vec![1.0, 0.0], "a".to_string(), None)
+            .await
+            .unwrap();
+
+        let error = index
+            .search(&[1.0, 0.0, 0.0], 1, &SearchOptions::default())
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<IndexError>(),
+            Some(IndexError::DimensionMismatch {
+                expected: 2,
+                found: 3
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_hybrid_search_boosts_keyword_matches() {
+        let index = VectorIndex::new(
+            Precision::F32,
+            QuantizationConfig::default(),
+            HnswConfig::default(),
+        );
+        index
+            .add("a", vec![1.0, 0.0], "An unrelated note".to_string(), None)
+            .await
+            .unwrap();
+        index
+            .add(
+                "b",
+                vec![0.8, 0.6],
+                "Bennett's systematics".to_string(),
+                None,
+            )
+            .await
+            .unwrap();
+
+        let results = index
+            .search(&[1.0, 0.0], 2, &SearchOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(results[0].id, "a");
+
+        for fusion in [Fusion::Weighted, Fusion::Rrf] {
+            let options = SearchOptions {
+                hybrid: Some((
+                    "systematics",
+                    HybridConfig {
+                        alpha: 0.5,
+                        fusion,
+                        phrase_boost: 0.0,
+                    },
+                )),
+                ..Default::default()
+            };
+            let results = index.search(&[1.0, 0.0], 2, &options).await.unwrap();
+            assert_eq!(results[0].id, "b");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_min_score_drops_weak_results() {
+        let index = VectorIndex::new(
+            Precision::F32,
+            QuantizationConfig::default(),
+            HnswConfig::default(),
+        );
+        index
+            .add("close", vec![1.0, 0.0], String::new(), None)
+            .await
+            .unwrap();
+        index
+            .add("far", vec![0.1, 1.0], String::new(), None)
+            .await
+            .unwrap();
+
+        // Results below the minimum are left out rather than padded back in
+        let options = SearchOptions {
+            min_score: Some(0.5),
+            ..Default::default()
+        };
+        let results = index.search(&[1.0, 0.0], 2, &options).await.unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].id, "close");
+
+        let options = SearchOptions {
+            min_score: Some(1.5),
+            ..Default::default()
+        };
+        assert!(index
+            .search(&[1.0, 0.0], 2, &options)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_deadline_returns_partial_results() {
+        let index = VectorIndex::new(
+            Precision::F32,
+            QuantizationConfig::default(),
+            HnswConfig::default(),
+        );
+        for i in 0..DEADLINE_CHECK_INTERVAL * 2 {
+            let angle = i as f32 / 100.0;
+            index
+                .add(
+                    &i.to_string(),
+                    vec![angle.cos(), angle.sin()],
+                    String::new(),
+                    None,
+                )
+                .await
+                .unwrap();
+        }
+
+        let (results, partial) = index
+            .search_partial(&[1.0, 0.0], 5, &SearchOptions::default())
+            .await
+            .unwrap();
+        assert!(!partial);
+        assert_eq!(results[0].id, "0");
+
+        // A search that starts late still ranks the first batch it scores
+        let options = SearchOptions {
+            deadline: Some(Instant::now()),
+            ..Default::default()
+        };
+        let (results, partial) = index
+            .search_partial(&[1.0, 0.0], 5, &options)
+            .await
+            .unwrap();
+        assert!(partial);
+        assert_eq!(results.len(), 5);
+    }
+
+    #[tokio::test]
+    async fn test_warm_touches_every_document() {
+        let index = VectorIndex::new(
+            Precision::F32,
+            QuantizationConfig::default(),
+            HnswConfig::default(),
+        );
+        for i in 0..200 {
+            let angle = i as f32 / 100.0;
+            index
+                .add(
+                    &i.to_string(),
+                    vec![angle.cos(), angle.sin()],
+                    "text".to_string(),
+                    None,
+                )
+                .await
+                .unwrap();
+        }
+
+        let report = index.warm(false);
+        assert_eq!(report.documents, 200);
+        // Each document's text and vector, plus the graph
+        assert!(report.bytes_touched > 200 * (4 + 8));
+        assert_eq!(report.pinned_bytes, 0);
+
+        // Pinning may be refused by the sandbox's lock limit, but then
+        // says why
+        let report = index.warm(true);
+        assert!(report.pinned_bytes > 0 || report.pin_error.is_some());
+        if report.pin_error.is_none() {
+            // The pin follows the graph as it grows
+            for i in 200..400 {
+                let angle = i as f32 / 100.0;
+                index
+                    .add(
+                        &i.to_string(),
+                        vec![angle.cos(), angle.sin()],
+                        "text".to_string(),
+                        None,
+                    )
+                    .await
+                    .unwrap();
+            }
+            assert!(!index.state.read().unwrap().graph.pin_is_due());
+        }
+        assert_eq!(index.warm(false).pinned_bytes, 0);
+        assert!(!index.pin_entry_layers.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn test_similar_excludes_the_document() {
+        let index = VectorIndex::new(
+            Precision::F32,
+            QuantizationConfig::default(),
+            HnswConfig::default(),
+        );
+        for (id, vector) in [("a", [1.0, 0.0]), ("b", [0.8, 0.6]), ("c", [0.0, 1.0])] {
+            index
+                .add(id, vector.to_vec(), id.to_string(), None)
+                .await
+                .unwrap();
+        }
+        index.add_alias("old-a", "a").await.unwrap();
+
+        let (id, results) = index.similar("old-a", 2).await.unwrap().unwrap();
+        assert_eq!(id, "a");
+        let ids: Vec<&str> = results.iter().map(|result| result.id.as_str()).collect();
+        assert_eq!(ids, ["b", "c"]);
+        assert!(index.similar("missing", 2).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_verify_reports_and_repairs() {
+        let index = VectorIndex::new(
+            Precision::F32,
+            QuantizationConfig::default(),
+            HnswConfig::default(),
+        );
+        for id in ["a", "b"] {
+            index
+                .add(id, vec![1.0, 0.0], id.to_string(), None)
+                .await
+                .unwrap();
+        }
+        assert!(index.verify(false).await.unwrap().issues.is_empty());
+
+        {
+            let mut state = index.state.write().unwrap();
+            state.aliases.insert("old".to_string(), "gone".to_string());
+            state.graph = Hnsw::new(HnswConfig::default());
+        }
+
+        let report = index.verify(false).await.unwrap();
+        let kinds: Vec<IssueKind> = report.issues.iter().map(|issue| issue.kind).collect();
+        assert_eq!(kinds.len(), 3);
+        assert!(kinds.contains(&IssueKind::DanglingAlias));
+        assert!(kinds.contains(&IssueKind::GraphMissingDocument));
+
+        let report = index.verify(true).await.unwrap();
+        assert!(report.issues.iter().all(|issue| issue.repaired));
+        assert!(index.verify(false).await.unwrap().issues.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_rebuild_replaces_vectors_of_unchanged_documents() {
+        let index = VectorIndex::new(
+            Precision::F32,
+            QuantizationConfig::default(),
+            HnswConfig::default(),
+        );
+        for id in ["a", "b"] {
+            index
+                .add(id, vec![1.0, 0.0], id.to_string(), None)
+                .await
+                .unwrap();
+        }
+        // "b" is re-indexed after the rebuild read it, at version 1
+        index
+            .add("b", vec![0.0, 1.0], "b2".to_string(), None)
+            .await
+            .unwrap();
+
+        let replaced = index
+            .replace_vectors(vec![
+                ("a".to_string(), 1, vec![0.6, 0.8].into()),
+                ("b".to_string(), 1, vec![0.8, 0.6].into()),
+                ("gone".to_string(), 1, vec![0.8, 0.6].into()),
+            ])
+            .await
+            .unwrap();
+        assert_eq!(replaced, 1);
+        index.finish_rebuild().await.unwrap();
+
+        let a = index.get("a").await.unwrap().unwrap();
+        assert_eq!(
+            (a.embedding.to_f32().into_owned(), a.version),
+            (vec![0.6, 0.8], 1)
+        );
+        let b = index.get("b").await.unwrap().unwrap();
+        assert_eq!(b.embedding.to_f32().into_owned(), vec![0.0, 1.0]);
+    }
+
+    #[tokio::test]
+    async fn test_collections_are_isolated() {
+        let dir =
+            std::env::temp_dir().join(format!("systematics-collections-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        {
+            let collections = Collections::open(
+                &dir,
+                Precision::F32,
+                QuantizationConfig::default(),
+                HnswConfig::default(),
+                StorageConfig::default(),
+            )
+            .unwrap();
+            let work = collections.create("work").await.unwrap();
+            work.add("a", vec![1.0, 0.0], "work note".to_string(), None)
+                .await
+                .unwrap();
+            // Another model's vectors don't mix with the collection's
+            let err = work
+                .add("b", vec![1.0, 0.0, 0.0], "other model".to_string(), None)
+                .await
+                .unwrap_err();
+            assert!(matches!(
+                err.downcast_ref(),
+                Some(IndexError::DimensionMismatch {
+                    expected: 2,
+                    found: 3
+                })
+            ));
+
+            let default = collections.get(None).await.unwrap();
+            assert_eq!(default.count().await, 0);
+            assert!(collections.create("work").await.is_err());
+            assert!(collections.create("../escape").await.is_err());
+            assert!(collections.delete(DEFAULT_COLLECTION).await.is_err());
+            collections
+                .update_settings(
+                    "work",
+                    CollectionSettings {
+                        weight: 1.5,
+                        model: Some("multilingual".to_string()),
+                        analyzer: AnalyzerSettings {
+                            cjk_bigrams: true,
+                            ..Default::default()
+                        },
+                        id_policy: IdPolicy::Uuid7,
+                        embed_template: Some("{title}".to_string()),
+                    },
+                )
+                .await
+                .unwrap();
+        }
+
+        // Collections are rediscovered on startup
+        let collections = Collections::open(
+            &dir,
+            Precision::F32,
+            QuantizationConfig::default(),
+            HnswConfig::default(),
+            StorageConfig::default(),
+        )
+        .unwrap();
+        let work = collections.get(Some("work")).await.unwrap();
+        assert_eq!(work.count().await, 1);
+        let settings = collections.settings(Some("work")).await.unwrap();
+        assert_eq!(settings.weight, 1.5);
+        assert_eq!(settings.model.as_deref(), Some("multilingual"));
+        assert!(settings.analyzer.cjk_bigrams);
+        assert_eq!(settings.id_policy, IdPolicy::Uuid7);
+        assert_eq!(settings.embed_template.as_deref(), Some("{title}"));
+        assert_eq!(collections.settings(None).await.unwrap().weight, 1.0);
+
+        collections.delete("work").await.unwrap();
+        assert!(collections.get(Some("work")).await.is_err());
+        assert!(!dir.join("collections").join("work").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_persistence_recovers_documents() {
+        let dir = std::env::temp_dir().join(format!("systematics-index-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        {
+            // Snapshot after every 3 records so both paths are exercised
+            let index = VectorIndex::open(
+                &dir,
+                Precision::F32,
+                QuantizationConfig::default(),
+                HnswConfig::default(),
+                StorageConfig {
+                    snapshot_every: 3,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+            for id in ["a", "b", "c", "d"] {
+                index
+                    .add(id, vec![1.0, 0.0], format!("text {}", id), None)
+                    .await
+                    .unwrap();
+            }
+            index.delete("b").await.unwrap();
+            index.add_alias("old-d", "d").await.unwrap();
+        }
+
+        // Simulate a crash mid-append leaving a torn record behind
+        let mut log = std::fs::OpenOptions::new()
+            .append(true)
+            .open(dir.join("index.log"))
+            .unwrap();
+        std::io::Write::write_all(&mut log, &[200, 0, 0, 0, 1, 2]).unwrap();
+
+        let index = VectorIndex::open(
+            &dir,
+            Precision::F32,
+            QuantizationConfig::default(),
+            HnswConfig::default(),
+            StorageConfig {
+                snapshot_every: 3,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(index.count().await, 3);
+        assert!(index.get("b").await.unwrap().is_none());
+        assert_eq!(index.get("d").await.unwrap().unwrap().text, "text d");
+        assert_eq!(index.get("old-d").await.unwrap().unwrap().id, "d");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_product_quantizer_is_learned_and_persisted() {
+        let dir = std::env::temp_dir().join(format!("systematics-pq-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let quantization = QuantizationConfig {
+            mode: Quantization::Pq,
+            pq_subvectors: 4,
+            pq_train_size: 256,
+        };
+        let vector = |i: usize| -> Vec<f32> {
+            let angle = i as f32 * 0.05;
+            vec![
+                angle.cos(),
+                angle.sin(),
+                (angle * 3.0).cos(),
+                (angle * 3.0).sin(),
+            ]
+        };
+
+        {
+            let index = VectorIndex::open(
+                &dir,
+                Precision::F32,
+                quantization,
+                HnswConfig::default(),
+                StorageConfig {
+                    snapshot_every: 1000,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+            for i in 0..300 {
+                index
+                    .add(&i.to_string(), vector(i), String::new(), None)
+                    .await
+                    .unwrap();
+            }
+            let doc = index.get("0").await.unwrap().unwrap();
+            assert!(matches!(doc.embedding, StoredVector::Pq(_)));
+        }
+
+        let index = VectorIndex::open(
+            &dir,
+            Precision::F32,
+            quantization,
+            HnswConfig::default(),
+            StorageConfig {
+                snapshot_every: 1000,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        for id in ["0", "299"] {
+            let doc = index.get(id).await.unwrap().unwrap();
+            assert!(matches!(doc.embedding, StoredVector::Pq(_)));
+        }
+        let results = index
+            .search(&vector(120), 3, &SearchOptions::default())
+            .await
+            .unwrap();
+        assert!(results.iter().any(|result| result.id == "120"));
+
+        // A rebuild learns the centroids again and codes every vector
+        // against them, including those coded against the old ones
+        index.finish_rebuild().await.unwrap();
+        for id in ["0", "299"] {
+            let doc = index.get(id).await.unwrap().unwrap();
+            assert!(matches!(doc.embedding, StoredVector::Pq(_)));
+        }
+        let results = index
+            .search(&vector(120), 3, &SearchOptions::default())
+            .await
+            .unwrap();
+        assert!(results.iter().any(|result| result.id == "120"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,447 @@
+//! Long-running operations run as background jobs: queued behind a limit
+//! on how many run at once, with their progress and outcome kept for
+//! clients to poll by job id, and cancellable while they run.
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Display;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::sync::Semaphore;
+use tracing::{info, warn};
+
+use crate::error::AppError;
+
+/// Finished jobs kept for polling; older ones are forgotten.
+const RETAINED_JOBS: usize = 100;
+
+/// Largest result kept for one job, serialized. A larger one is dropped,
+/// with an error saying so.
+const MAX_RESULT_BYTES: usize = 1024 * 1024;
+
+/// Results kept across all finished jobs, serialized; the oldest jobs'
+/// results are dropped first to stay within it.
+const RETAINED_RESULT_BYTES: usize = 16 * 1024 * 1024;
+
+/// Errors kept per job, so a job failing on every document doesn't hold
+/// them all.
+const MAX_ERRORS: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobState {
+    /// Waiting for a running job to finish
+    Queued,
+    Running,
+    /// Finished, possibly with errors for some documents
+    Succeeded,
+    /// Stopped by an error before finishing
+    Failed,
+    /// Stopped on request
+    Cancelled,
+}
+
+impl JobState {
+    pub fn is_finished(self) -> bool {
+        !matches!(self, JobState::Queued | JobState::Running)
+    }
+}
+
+/// A job as reported by `/jobs`.
+#[derive(Debug, Clone, Serialize)]
+pub struct JobInfo {
+    pub id: String,
+    pub kind: &'static str,
+    pub state: JobState,
+    pub created_at: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub started_at: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub finished_at: Option<DateTime<Utc>>,
+    /// Items processed so far, and in all, e.g. documents
+    pub done: usize,
+    pub total: usize,
+    /// Items processed per second since the job started
+    pub throughput: f64,
+    /// Time spent running, not counting the queue
+    pub elapsed_ms: u64,
+    /// What failed, most recent last, at most 100 of them
+    pub errors: Vec<String>,
+    /// What the job produced, once it succeeded: the response the same
+    /// request would have got without `async`. Dropped if it's over 1 MiB,
+    /// or to keep the results of more recent jobs.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+}
+
+/// A queued, running, or finished job, updated by whatever runs it.
+pub struct Job {
+    started: Mutex<Option<Instant>>,
+    cancelled: AtomicBool,
+    info: Mutex<JobInfo>,
+    /// Serialized size of the result kept, if any
+    result_bytes: AtomicUsize,
+    /// Progress of each part of the job, e.g. each collection, summed
+    /// into the job's
+    parts: Mutex<BTreeMap<String, (usize, usize)>>,
+}
+
+impl Job {
+    /// Record the progress of one part of the job.
+    pub fn progress(&self, part: &str, done: usize, total: usize) {
+        let mut parts = self.parts.lock().unwrap();
+        parts.insert(part.to_string(), (done, total));
+        let (done, total) = parts.values().fold((0, 0), |(done, total), part| {
+            (done + part.0, total + part.1)
+        });
+        let mut info = self.info.lock().unwrap();
+        info.done = done;
+        info.total = total;
+    }
+
+    pub fn error(&self, error: String) {
+        let mut info = self.info.lock().unwrap();
+        if info.errors.len() == MAX_ERRORS {
+            info.errors.remove(0);
+        }
+        info.errors.push(error);
+    }
+
+    /// Whether the job was asked to stop. Jobs check between steps, and
+    /// stop early by returning an error.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    fn start(&self) {
+        *self.started.lock().unwrap() = Some(Instant::now());
+        let mut info = self.info.lock().unwrap();
+        info.state = JobState::Running;
+        info.started_at = Some(Utc::now());
+    }
+
+    fn finish(&self, state: JobState, result: Option<Value>) {
+        let bytes = result
+            .as_ref()
+            .and_then(|result| serde_json::to_vec(result).ok())
+            .map_or(0, |result| result.len());
+        let mut info = self.info.lock().unwrap();
+        if bytes > MAX_RESULT_BYTES {
+            if info.errors.len() == MAX_ERRORS {
+                info.errors.remove(0);
+            }
+            info.errors.push(format!(
+                "Result of {} bytes is over the {} kept; run the request without async to get it",
+                bytes, MAX_RESULT_BYTES
+            ));
+        } else {
+            info.result = result;
+            self.result_bytes.store(bytes, Ordering::Relaxed);
+        }
+        info.state = state;
+        info.finished_at = Some(Utc::now());
+    }
+
+    fn drop_result(&self) {
+        self.info.lock().unwrap().result = None;
+        self.result_bytes.store(0, Ordering::Relaxed);
+    }
+
+    pub fn info(&self) -> JobInfo {
+        let mut info = self.info.lock().unwrap().clone();
+        let elapsed = match (info.started_at, info.finished_at) {
+            (Some(started_at), Some(finished_at)) => {
+                (finished_at - started_at).to_std().unwrap_or_default()
+            }
+            _ => self
+                .started
+                .lock()
+                .unwrap()
+                .map(|started| started.elapsed())
+                .unwrap_or_default(),
+        };
+        info.elapsed_ms = elapsed.as_millis() as u64;
+        if elapsed.as_secs_f64() > 0.0 {
+            info.throughput = info.done as f64 / elapsed.as_secs_f64();
+        }
+        info
+    }
+}
+
+/// Jobs by id, numbered from 1 since the server started. At most
+/// `max_running` run at once; up to `max_queued` more wait in order of
+/// submission, and any beyond that are refused.
+pub struct Jobs {
+    next: AtomicU64,
+    running: Arc<Semaphore>,
+    max_unfinished: usize,
+    jobs: Arc<Mutex<HashMap<String, Arc<Job>>>>,
+}
+
+impl Jobs {
+    pub fn new(max_running: usize, max_queued: usize) -> Self {
+        Self {
+            next: AtomicU64::new(0),
+            running: Arc::new(Semaphore::new(max_running)),
+            max_unfinished: max_running + max_queued,
+            jobs: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Queue `run` as a job of `kind`, and return it as it stands. Its
+    /// output is kept as the job's result; an error fails the job, or
+    /// marks it cancelled if it was asked to stop. Refused with `503
+    /// Service Unavailable` if the queue is full.
+    pub fn spawn<F, Fut, T, E>(&self, kind: &'static str, run: F) -> Result<JobInfo, AppError>
+    where
+        F: FnOnce(Arc<Job>) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<T, E>> + Send,
+        T: Serialize,
+        E: Display,
+    {
+        let job = self.register(kind)?;
+        let running = self.running.clone();
+        let jobs = self.jobs.clone();
+        let queued = job.clone();
+        tokio::spawn(async move {
+            let _permit = running.acquire_owned().await;
+            if job.is_cancelled() {
+                job.finish(JobState::Cancelled, None);
+                return;
+            }
+            job.start();
+            let id = job.info().id;
+            info!("Job {} ({}) started", id, kind);
+
+            match run(job.clone()).await {
+                Ok(output) => {
+                    let result = serde_json::to_value(output)
+                        .map_err(|e| warn!("Job {} result can't be serialized: {}", id, e))
+                        .ok();
+                    job.finish(JobState::Succeeded, result);
+                    info!("Job {} ({}) succeeded", id, kind);
+                }
+                Err(e) if job.is_cancelled() => {
+                    job.finish(JobState::Cancelled, None);
+                    info!("Job {} ({}) cancelled: {}", id, kind, e);
+                }
+                Err(e) => {
+                    job.error(e.to_string());
+                    job.finish(JobState::Failed, None);
+                    warn!("Job {} ({}) failed: {}", id, kind, e);
+                }
+            }
+            trim(&mut jobs.lock().unwrap());
+        });
+        Ok(queued.info())
+    }
+
+    fn register(&self, kind: &'static str) -> Result<Arc<Job>, AppError> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let unfinished = jobs
+            .values()
+            .filter(|job| !job.info.lock().unwrap().state.is_finished())
+            .count();
+        if unfinished >= self.max_unfinished {
+            return Err(AppError::ServiceUnavailable(format!(
+                "Too many jobs queued ({}); retry once some finish",
+                unfinished
+            )));
+        }
+
+        let id = (self.next.fetch_add(1, Ordering::Relaxed) + 1).to_string();
+        let job = Arc::new(Job {
+            started: Mutex::new(None),
+            cancelled: AtomicBool::new(false),
+            info: Mutex::new(JobInfo {
+                id: id.clone(),
+                kind,
+                state: JobState::Queued,
+                created_at: Utc::now(),
+                started_at: None,
+                finished_at: None,
+                done: 0,
+                total: 0,
+                throughput: 0.0,
+                elapsed_ms: 0,
+                errors: Vec::new(),
+                result: None,
+            }),
+            result_bytes: AtomicUsize::new(0),
+            parts: Mutex::new(BTreeMap::new()),
+        });
+        jobs.insert(id, job.clone());
+        Ok(job)
+    }
+
+    pub fn get(&self, id: &str) -> Option<JobInfo> {
+        let job = self.jobs.lock().unwrap().get(id).cloned();
+        job.map(|job| job.info())
+    }
+
+    /// Every job kept, the most recent first.
+    pub fn list(&self) -> Vec<JobInfo> {
+        let jobs: Vec<Arc<Job>> = self.jobs.lock().unwrap().values().cloned().collect();
+        let mut infos: Vec<JobInfo> = jobs.iter().map(|job| job.info()).collect();
+        infos.sort_by_key(|info| std::cmp::Reverse(info.id.parse::<u64>().unwrap_or_default()));
+        infos
+    }
+
+    /// Ask a job to stop. A queued job never starts; a running one stops
+    /// at its next check. Finished jobs are left as they are.
+    pub fn cancel(&self, id: &str) -> Option<JobInfo> {
+        let job = self.jobs.lock().unwrap().get(id).cloned()?;
+        if !job.info().state.is_finished() {
+            job.cancelled.store(true, Ordering::Relaxed);
+        }
+        Some(job.info())
+    }
+}
+
+/// Forget the oldest finished jobs beyond the number kept, then drop the
+/// oldest results beyond the bytes kept.
+fn trim(jobs: &mut HashMap<String, Arc<Job>>) {
+    let mut finished: Vec<(DateTime<Utc>, String)> = jobs
+        .iter()
+        .filter_map(|(id, job)| {
+            let info = job.info.lock().unwrap();
+            info.finished_at.map(|at| (at, id.clone()))
+        })
+        .collect();
+    finished.sort();
+    let excess = finished.len().saturating_sub(RETAINED_JOBS);
+    for (_, id) in finished.drain(..excess) {
+        jobs.remove(&id);
+    }
+
+    let mut kept: usize = finished
+        .iter()
+        .map(|(_, id)| jobs[id].result_bytes.load(Ordering::Relaxed))
+        .sum();
+    for (_, id) in &finished {
+        if kept <= RETAINED_RESULT_BYTES {
+            break;
+        }
+        let job = &jobs[id];
+        kept -= job.result_bytes.load(Ordering::Relaxed);
+        job.drop_result();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    async fn wait_until_finished(jobs: &Jobs, id: &str) -> JobInfo {
+        loop {
+            let info = jobs.get(id).unwrap();
+            if info.state.is_finished() {
+                return info;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_jobs_queue_and_cancel() {
+        let jobs = Jobs::new(1, 1);
+        let (release, released) = tokio::sync::oneshot::channel::<()>();
+        let first = jobs
+            .spawn("reindex", |job| async move {
+                job.progress("notes", 10, 40);
+                job.progress("work", 0, 60);
+                job.progress("notes", 40, 40);
+                job.error("a.md: Text is too long".into());
+                let _ = released.await;
+                Ok::<_, String>(serde_json::json!({ "rebuilt": 39 }))
+            })
+            .unwrap();
+        // The second waits for the first to finish, and is cancelled
+        // before it starts
+        let second = jobs
+            .spawn("cluster", |_| async { Ok::<_, String>(()) })
+            .unwrap();
+        assert_eq!(second.state, JobState::Queued);
+        // The queue is full
+        assert!(matches!(
+            jobs.spawn("cluster", |_| async { Ok::<_, String>(()) }),
+            Err(AppError::ServiceUnavailable(_))
+        ));
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        let info = jobs.get(&first.id).unwrap();
+        assert_eq!(info.state, JobState::Running);
+        assert_eq!((info.done, info.total), (40, 100));
+        assert_eq!(jobs.cancel(&second.id).unwrap().state, JobState::Queued);
+        release.send(()).unwrap();
+
+        let info = wait_until_finished(&jobs, &first.id).await;
+        assert_eq!(info.state, JobState::Succeeded);
+        assert_eq!(info.errors, ["a.md: Text is too long"]);
+        assert_eq!(info.result, Some(serde_json::json!({ "rebuilt": 39 })));
+        let info = wait_until_finished(&jobs, &second.id).await;
+        assert_eq!(info.state, JobState::Cancelled);
+        assert_eq!(info.started_at, None);
+
+        // A running job stops at its next check
+        let third = jobs
+            .spawn("bulk_index", |job| async move {
+                while !job.is_cancelled() {
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                }
+                Err::<(), _>("Cancelled")
+            })
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        jobs.cancel(&third.id);
+        let info = wait_until_finished(&jobs, &third.id).await;
+        assert_eq!(info.state, JobState::Cancelled);
+
+        let failed = jobs
+            .spawn("backup", |_| async { Err::<(), _>("Disk full") })
+            .unwrap();
+        let info = wait_until_finished(&jobs, &failed.id).await;
+        assert_eq!(
+            (info.state, info.errors),
+            (JobState::Failed, vec!["Disk full".into()])
+        );
+
+        let ids: Vec<String> = jobs.list().into_iter().map(|info| info.id).collect();
+        assert_eq!(ids, ["4", "3", "2", "1"]);
+        assert!(jobs.get("5").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_large_results_are_not_kept() {
+        let jobs = Jobs::new(1, 10);
+        let large = jobs
+            .spawn("cluster", |_| async {
+                Ok::<_, String>("x".repeat(MAX_RESULT_BYTES))
+            })
+            .unwrap();
+        let info = wait_until_finished(&jobs, &large.id).await;
+        assert_eq!(info.state, JobState::Succeeded);
+        assert_eq!(info.result, None);
+        assert_eq!(info.errors.len(), 1);
+
+        // The oldest results go first once they add up to too much
+        let mut ids = Vec::new();
+        for _ in 0..(RETAINED_RESULT_BYTES / MAX_RESULT_BYTES + 1) {
+            let job = jobs
+                .spawn("cluster", |_| async {
+                    Ok::<_, String>("x".repeat(MAX_RESULT_BYTES - 2))
+                })
+                .unwrap();
+            wait_until_finished(&jobs, &job.id).await;
+            ids.push(job.id);
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(jobs.get(&ids[0]).unwrap().result, None);
+        assert!(jobs.get(ids.last().unwrap()).unwrap().result.is_some());
+    }
+}
@@ -0,0 +1,412 @@
+use rust_stemmers::{Algorithm, Stemmer};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+/// Term frequency saturation: how quickly repeats of a term stop adding
+/// to a document's score.
+const K1: f32 = 1.2;
+/// How strongly scores are normalized by document length.
+const B: f32 = 0.75;
+
+/// Languages terms can be stemmed in, each with its stopword list.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Language {
+    Arabic,
+    Danish,
+    Dutch,
+    English,
+    Finnish,
+    French,
+    German,
+    Greek,
+    Hungarian,
+    Italian,
+    Norwegian,
+    Portuguese,
+    Romanian,
+    Russian,
+    Spanish,
+    Swedish,
+    Turkish,
+}
+
+impl Language {
+    fn algorithm(self) -> Algorithm {
+        match self {
+            Language::Arabic => Algorithm::Arabic,
+            Language::Danish => Algorithm::Danish,
+            Language::Dutch => Algorithm::Dutch,
+            Language::English => Algorithm::English,
+            Language::Finnish => Algorithm::Finnish,
+            Language::French => Algorithm::French,
+            Language::German => Algorithm::German,
+            Language::Greek => Algorithm::Greek,
+            Language::Hungarian => Algorithm::Hungarian,
+            Language::Italian => Algorithm::Italian,
+            Language::Norwegian => Algorithm::Norwegian,
+            Language::Portuguese => Algorithm::Portuguese,
+            Language::Romanian => Algorithm::Romanian,
+            Language::Russian => Algorithm::Russian,
+            Language::Spanish => Algorithm::Spanish,
+            Language::Swedish => Algorithm::Swedish,
+            Language::Turkish => Algorithm::Turkish,
+        }
+    }
+
+    /// ISO 639-1 code, which the stopword lists are keyed by
+    fn code(self) -> &'static str {
+        match self {
+            Language::Arabic => "ar",
+            Language::Danish => "da",
+            Language::Dutch => "nl",
+            Language::English => "en",
+            Language::Finnish => "fi",
+            Language::French => "fr",
+            Language::German => "de",
+            Language::Greek => "el",
+            Language::Hungarian => "hu",
+            Language::Italian => "it",
+            Language::Norwegian => "no",
+            Language::Portuguese => "pt",
+            Language::Romanian => "ro",
+            Language::Russian => "ru",
+            Language::Spanish => "es",
+            Language::Swedish => "sv",
+            Language::Turkish => "tr",
+        }
+    }
+}
+
+/// How a collection's text is split into terms for keyword search, kept
+/// in its settings.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct AnalyzerSettings {
+    /// Stem terms and drop stopwords in this language
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub language: Option<Language>,
+    /// Further terms to ignore, e.g. words every note repeats
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stopwords: Vec<String>,
+    /// Split Chinese, Japanese, and Korean text, which has no spaces
+    /// between words, into overlapping pairs of characters
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub cjk_bigrams: bool,
+}
+
+impl AnalyzerSettings {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Turns text into the terms [`Bm25Index`] indexes, for documents and
+/// queries alike.
+#[derive(Default)]
+pub struct Analyzer {
+    stemmer: Option<Stemmer>,
+    stopwords: HashSet<String>,
+    cjk_bigrams: bool,
+}
+
+impl Analyzer {
+    pub fn new(settings: &AnalyzerSettings) -> Self {
+        let language_stopwords = settings
+            .language
+            .map_or(&[][..], |language| stop_words::get(language.code()));
+        Self {
+            stemmer: settings
+                .language
+                .map(|language| Stemmer::create(language.algorithm())),
+            stopwords: language_stopwords
+                .iter()
+                .map(|word| word.to_string())
+                .chain(settings.stopwords.iter().map(|word| word.to_lowercase()))
+                .collect(),
+            cjk_bigrams: settings.cjk_bigrams,
+        }
+    }
+
+    /// Lowercased runs of letters, digits, and underscores, so identifiers
+    /// like `snake_case_name` stay whole, less stopwords and stemmed.
+    fn terms(&self, text: &str) -> Vec<String> {
+        let mut terms = Vec::new();
+        for word in text
+            .split(|c: char| !(c.is_alphanumeric() || c == '_'))
+            .filter(|word| !word.is_empty())
+        {
+            if self.cjk_bigrams && word.chars().any(is_cjk) {
+                split_cjk(word, &mut terms);
+                continue;
+            }
+            let word = word.to_lowercase();
+            if self.stopwords.contains(&word) {
+                continue;
+            }
+            terms.push(match &self.stemmer {
+                Some(stemmer) => stemmer.stem(&word).into_owned(),
+                None => word,
+            });
+        }
+        terms
+    }
+}
+
+/// Push the runs of CJK characters in `word` as overlapping bigrams (or a
+/// lone character), and the runs in between as whole lowercased terms.
+fn split_cjk(word: &str, terms: &mut Vec<String>) {
+    let chars: Vec<char> = word.chars().collect();
+    let mut start = 0;
+    while start < chars.len() {
+        let cjk = is_cjk(chars[start]);
+        let end = chars[start..]
+            .iter()
+            .position(|&c| is_cjk(c) != cjk)
+            .map_or(chars.len(), |len| start + len);
+        let run = &chars[start..end];
+        if !cjk {
+            terms.push(run.iter().collect::<String>().to_lowercase());
+        } else if run.len() == 1 {
+            terms.push(run[0].to_string());
+        } else {
+            terms.extend(run.windows(2).map(|pair| pair.iter().collect::<String>()));
+        }
+        start = end;
+    }
+}
+
+/// Phrases a query asks for literally, as lowercased words: those in
+/// double quotes.
+pub fn query_phrases(query: &str) -> Vec<Vec<String>> {
+    query
+        .split(['"', '\u{201c}', '\u{201d}'])
+        .skip(1)
+        .step_by(2)
+        .map(words)
+        .filter(|phrase| !phrase.is_empty())
+        .collect()
+}
+
+/// Whether `phrase` occurs in `text` word for word, as split by [`words`].
+pub fn contains_phrase(text: &[String], phrase: &[String]) -> bool {
+    !phrase.is_empty() && text.windows(phrase.len()).any(|window| window == phrase)
+}
+
+/// Lowercased runs of letters, digits, and underscores, unstemmed.
+pub fn words(text: &str) -> Vec<String> {
+    text.split(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+/// Han, kana, and Hangul characters.
+fn is_cjk(c: char) -> bool {
+    matches!(
+        c,
+        '\u{3040}'..='\u{30ff}'
+            | '\u{3400}'..='\u{4dbf}'
+            | '\u{4e00}'..='\u{9fff}'
+            | '\u{f900}'..='\u{faff}'
+            | '\u{ff66}'..='\u{ff9f}'
+            | '\u{ac00}'..='\u{d7af}'
+            | '\u{1100}'..='\u{11ff}'
+            | '\u{20000}'..='\u{2fa1f}'
+    )
+}
+
+/// Inverted index for BM25 keyword scoring, catching exact terms such as
+/// names and code identifiers that embeddings blur. Like the graph, it is
+/// rebuilt from the stored documents on startup rather than persisted.
+#[derive(Default)]
+pub struct Bm25Index {
+    /// Documents containing each term, with the term's count in each
+    postings: HashMap<String, HashMap<String, u32>>,
+    /// Number of terms in each document
+    lengths: HashMap<String, u32>,
+    total_length: u64,
+    analyzer: Analyzer,
+}
+
+impl Bm25Index {
+    pub fn build<'a>(analyzer: Analyzer, docs: impl Iterator<Item = (&'a str, &'a str)>) -> Self {
+        let mut index = Self {
+            analyzer,
+            ..Self::default()
+        };
+        index.rebuild(docs);
+        index
+    }
+
+    /// Replace every indexed document with `docs`, keeping the analyzer.
+    pub fn rebuild<'a>(&mut self, docs: impl Iterator<Item = (&'a str, &'a str)>) {
+        self.postings.clear();
+        self.lengths.clear();
+        self.total_length = 0;
+        for (key, text) in docs {
+            self.insert(key, text);
+        }
+    }
+
+    /// Number of documents indexed.
+    pub fn len(&self) -> usize {
+        self.lengths.len()
+    }
+
+    /// Rough bytes held by the postings and document lengths, without
+    /// allocator or hash table overhead.
+    pub fn size_bytes(&self) -> usize {
+        let postings: usize = self
+            .postings
+            .iter()
+            .map(|(term, docs)| term.len() + docs.keys().map(|key| key.len() + 4).sum::<usize>())
+            .sum();
+        postings + self.lengths.keys().map(|key| key.len() + 4).sum::<usize>()
+    }
+
+    /// Index `text` under `key`. A previous version must have been removed
+    /// first.
+    pub fn insert(&mut self, key: &str, text: &str) {
+        let mut length = 0;
+        for term in self.analyzer.terms(text) {
+            *self
+                .postings
+                .entry(term)
+                .or_default()
+                .entry(key.to_string())
+                .or_default() += 1;
+            length += 1;
+        }
+        self.lengths.insert(key.to_string(), length);
+        self.total_length += length as u64;
+    }
+
+    /// Remove the document indexed under `key` with this `text`.
+    pub fn remove(&mut self, key: &str, text: &str) {
+        let Some(length) = self.lengths.remove(key) else {
+            return;
+        };
+        self.total_length -= length as u64;
+
+        for term in self
+            .analyzer
+            .terms(text)
+            .into_iter()
+            .collect::<HashSet<_>>()
+        {
+            if let Some(docs) = self.postings.get_mut(&term) {
+                docs.remove(key);
+                if docs.is_empty() {
+                    self.postings.remove(&term);
+                }
+            }
+        }
+    }
+
+    /// BM25 score of every document containing at least one query term.
+    pub fn scores(&self, query: &str) -> HashMap<&str, f32> {
+        let mut scores: HashMap<&str, f32> = HashMap::new();
+        if self.lengths.is_empty() {
+            return scores;
+        }
+
+        let count = self.lengths.len() as f32;
+        let average_length = self.total_length as f32 / count;
+        for term in self
+            .analyzer
+            .terms(query)
+            .into_iter()
+            .collect::<HashSet<_>>()
+        {
+            let Some(docs) = self.postings.get(&term) else {
+                continue;
+            };
+
+            let df = docs.len() as f32;
+            let idf = (1.0 + (count - df + 0.5) / (df + 0.5)).ln();
+            for (key, &tf) in docs {
+                let tf = tf as f32;
+                let length = self.lengths[key] as f32;
+                let norm = K1 * (1.0 - B + B * length / average_length.max(1.0));
+                *scores.entry(key.as_str()).or_default() += idf * tf * (K1 + 1.0) / (tf + norm);
+            }
+        }
+        scores
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bm25_prefers_rare_exact_terms() {
+        let mut index = Bm25Index::build(
+            Analyzer::default(),
+            [
+                ("a", "Notes on systems thinking and feedback loops"),
+                ("b", "Bennett's systematics: the triad and the tetrad"),
+                ("c", "Thinking about thinking, and notes about notes"),
+            ]
+            .into_iter(),
+        );
+
+        let scores = index.scores("Bennett systematics");
+        assert_eq!(scores.len(), 1);
+        assert!(scores["b"] > 0.0);
+
+        // A term in fewer documents outweighs a common one
+        let scores = index.scores("notes triad");
+        assert!(scores["b"] > scores["a"]);
+
+        index.remove("b", "Bennett's systematics: the triad and the tetrad");
+        assert!(index.scores("systematics").is_empty());
+        assert_eq!(index.len(), 2);
+    }
+
+    #[test]
+    fn test_phrases_match_word_for_word() {
+        assert_eq!(
+            query_phrases(r#"triads "law of three" and "octave""#),
+            [vec!["law", "of", "three"], vec!["octave"]]
+        );
+        // Only quoted phrases count
+        assert!(query_phrases("Law of Three").is_empty());
+        assert!(query_phrases("enneagram").is_empty());
+
+        let text = words("Bennett's law, of three forces: the Law of Three.");
+        assert!(contains_phrase(&text, &words("law of three")));
+        assert!(!contains_phrase(&text, &words("three laws")));
+    }
+
+    #[test]
+    fn test_analyzer_stems_and_splits_cjk() {
+        let analyzer = Analyzer::new(&AnalyzerSettings {
+            language: Some(Language::English),
+            stopwords: vec!["Note".to_string()],
+            cjk_bigrams: true,
+        });
+        assert_eq!(
+            analyzer.terms("The note on running systems"),
+            ["run", "system"]
+        );
+        assert_eq!(
+            analyzer.terms("日本語の勉強"),
+            ["日本", "本語", "語の", "の勉", "勉強"]
+        );
+        assert_eq!(analyzer.terms("Rust入門"), ["rust", "入門"]);
+
+        let index = Bm25Index::build(
+            analyzer,
+            [("a", "日本語の勉強ノート"), ("b", "中国語の文法")].into_iter(),
+        );
+        let scores = index.scores("日本語");
+        assert_eq!(scores.len(), 1);
+        assert!(scores["a"] > 0.0);
+        assert!(
+            Bm25Index::build(Analyzer::default(), [("a", "日本語の勉強")].into_iter())
+                .scores("日本語")
+                .is_empty()
+        );
+    }
+}
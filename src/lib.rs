@@ -0,0 +1,106 @@
+//! Local semantic search over notes: an embedding model run with ONNX
+//! Runtime, and vector collections to index and search what it embeds.
+//!
+//! The `systematics-embeddings` binary serves all of this over HTTP (see
+//! [`server`]), but the pieces work on their own inside another program:
+//!
+//! - [`embedding`]: [`EmbeddingService`] turns text into normalized
+//!   vectors, downloading its model on first use.
+//! - [`index`]: [`VectorIndex`] stores documents and their vectors on
+//!   disk and searches them, and [`Collections`] manages several of them.
+//! - [`chunking`]: splits long documents into overlapping chunks so each
+//!   is embedded within the model's token limit.
+//! - [`simd`]: the dot product and cosine similarity kernels search runs
+//!   on, vectorized for the CPU.
+//!
+//! ```no_run
+//! use systematics_embeddings::config::{Config, ModelConfig};
+//! use systematics_embeddings::index::{SearchOptions, VectorIndex};
+//! use systematics_embeddings::EmbeddingService;
+//!
+//! # async fn example() -> anyhow::Result<()> {
+//! let config = Config::default();
+//! let model = EmbeddingService::new(&ModelConfig::default()).await?;
+//! let index = VectorIndex::open(
+//!     &config.data_dir,
+//!     config.model.precision,
+//!     config.quantization,
+//!     config.hnsw,
+//!     config.storage,
+//! )?;
+//!
+//! let note = "The triad is the first system to show relatedness.";
+//! index
+//!     .add("Triads.md", model.embed(note).await?, note.to_string(), None)
+//!     .await?;
+//!
+//! let query = model.embed("what is a triad?").await?;
+//! for result in index.search(&query, 5, &SearchOptions::default()).await? {
+//!     println!("{} {:.3}", result.id, result.score);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+mod analytics;
+mod backup;
+mod bulk;
+mod bundle;
+mod cache;
+#[cfg(feature = "chaos")]
+mod chaos;
+pub mod chunking;
+mod cluster;
+mod codec;
+mod concurrency;
+pub mod config;
+mod debug;
+mod dedupe;
+mod diversity;
+mod download;
+pub mod embedding;
+mod error;
+mod etag;
+mod experiments;
+mod federation;
+mod feedback;
+pub mod filter;
+mod flags;
+mod grpc;
+mod hnsw;
+mod ids;
+pub mod index;
+mod jobs;
+mod lexical;
+mod live;
+mod lock;
+mod markdown;
+mod metadata;
+mod migrations;
+mod models;
+mod obsidian;
+mod preflight;
+mod projection;
+mod ratelimit;
+mod rebuild;
+mod reconcile;
+mod reranker;
+mod security;
+mod selftest;
+pub mod server;
+mod signature;
+pub mod simd;
+mod snapshot;
+mod sqlite;
+mod stats;
+mod storage;
+mod templates;
+mod text;
+mod tls;
+mod ui;
+mod vault;
+mod vector;
+mod warm;
+
+pub use embedding::EmbeddingService;
+pub use index::{Collections, VectorIndex};
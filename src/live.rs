@@ -0,0 +1,158 @@
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::State;
+use axum::response::Response;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tracing::debug;
+
+use crate::error::ErrorCode;
+use crate::index::SearchResult;
+use crate::server::{run_search, AppState, SearchRequest};
+
+/// A query as typed so far: any search request, plus an id the client
+/// chooses (typically increasing per keystroke) that is echoed with the
+/// results so it can tell which query they answer.
+#[derive(Deserialize)]
+struct LiveQuery {
+    #[serde(default)]
+    id: u64,
+    #[serde(flatten)]
+    search: SearchRequest,
+}
+
+#[derive(Serialize)]
+struct LiveResults {
+    id: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    results: Option<Vec<SearchResult>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code: Option<ErrorCode>,
+}
+
+impl LiveResults {
+    fn error(id: u64, error: String, code: ErrorCode) -> Self {
+        Self {
+            id,
+            results: None,
+            error: Some(error),
+            code: Some(code),
+        }
+    }
+}
+
+/// Upgrade to a WebSocket that answers search-as-you-type queries.
+pub async fn live_search(ws: WebSocketUpgrade, State(state): State<AppState>) -> Response {
+    ws.on_upgrade(move |socket| serve(socket, state))
+}
+
+/// Queries are debounced: one is only run once no newer query has arrived
+/// for the configured interval, so a burst of keystrokes costs a single
+/// search for the last of them. Queries that arrive while a search runs
+/// are coalesced the same way once it finishes. Text messages are JSON and
+/// binary messages MessagePack, and each is answered in its own format.
+async fn serve(mut socket: WebSocket, state: AppState) {
+    let debounce = Duration::from_millis(state.config.live_search_debounce_ms);
+    let mut pending: Option<(LiveQuery, bool)> = None;
+
+    loop {
+        let message = if pending.is_some() {
+            match tokio::time::timeout(debounce, socket.recv()).await {
+                Ok(message) => message,
+                Err(_) => {
+                    let (query, binary) = pending.take().unwrap();
+                    let reply = answer(&state, query).await;
+                    if socket.send(encode(&reply, binary)).await.is_err() {
+                        break;
+                    }
+                    continue;
+                }
+            }
+        } else {
+            socket.recv().await
+        };
+
+        let (query, binary) = match message {
+            Some(Ok(Message::Text(text))) => (
+                serde_json::from_str::<LiveQuery>(&text).map_err(|e| e.to_string()),
+                false,
+            ),
+            Some(Ok(Message::Binary(bytes))) => (
+                rmp_serde::from_slice::<LiveQuery>(&bytes).map_err(|e| e.to_string()),
+                true,
+            ),
+            // Pings are answered by axum
+            Some(Ok(Message::Ping(_) | Message::Pong(_))) => continue,
+            Some(Ok(Message::Close(_))) | None => break,
+            Some(Err(e)) => {
+                debug!("Live search connection failed: {}", e);
+                break;
+            }
+        };
+
+        match query {
+            Ok(query) => pending = Some((query, binary)),
+            Err(e) => {
+                let reply =
+                    LiveResults::error(0, format!("Invalid query: {}", e), ErrorCode::BadRequest);
+                if socket.send(encode(&reply, binary)).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+async fn answer(state: &AppState, query: LiveQuery) -> LiveResults {
+    // Clearing the search box clears the results, without a round trip
+    // through the model
+    if query.search.query.trim().is_empty() {
+        return LiveResults {
+            id: query.id,
+            results: Some(Vec::new()),
+            error: None,
+            code: None,
+        };
+    }
+
+    let _permit = match state.limits.search.try_acquire() {
+        Ok(permit) => permit,
+        Err(e) => return LiveResults::error(query.id, e.message().to_string(), e.code()),
+    };
+    match run_search(state, query.search, "", false).await {
+        Ok(response) => LiveResults {
+            id: query.id,
+            results: Some(response.results),
+            error: None,
+            code: None,
+        },
+        Err(e) => LiveResults::error(query.id, e.message().to_string(), e.code()),
+    }
+}
+
+fn encode(reply: &LiveResults, binary: bool) -> Message {
+    if binary {
+        Message::Binary(rmp_serde::to_vec_named(reply).expect("results serialize"))
+    } else {
+        Message::Text(serde_json::to_string(reply).expect("results serialize"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_live_query_carries_search_fields() {
+        let json = r#"{"id": 3, "query": "tri", "limit": 5, "filter": {"tags": "x"}, "hybrid": {"alpha": 0.7}}"#;
+        let query: LiveQuery = serde_json::from_str(json).unwrap();
+        assert_eq!((query.id, query.search.query.as_str()), (3, "tri"));
+        assert_eq!(query.search.limit, Some(5));
+        assert!(query.search.filter.is_some() && query.search.hybrid.is_some());
+
+        let bytes = rmp_serde::to_vec_named(&serde_json::json!({ "query": "tri" })).unwrap();
+        let query: LiveQuery = rmp_serde::from_slice(&bytes).unwrap();
+        assert_eq!((query.id, query.search.query.as_str()), (0, "tri"));
+    }
+}
@@ -0,0 +1,129 @@
+//! The data directory's lock, held for as long as the server runs so a
+//! second server started against the same directory stops at startup
+//! rather than interleaving writes with the first into the same logs and
+//! snapshots.
+//!
+//! The lock is taken by the operating system on an open file, so it is
+//! released when the process exits, however it exits; the file left behind
+//! only records who held it last.
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::Write;
+use std::path::Path;
+use tracing::warn;
+
+/// Name of the lock file in the data directory.
+pub const LOCK_FILE: &str = "server.lock";
+
+/// Who holds the lock, written into the lock file for a second server to
+/// report.
+#[derive(Debug, Serialize, Deserialize)]
+struct Holder {
+    pid: u32,
+    /// Address the holder serves on
+    address: String,
+    started_at: DateTime<Utc>,
+}
+
+/// Exclusive use of a data directory, until dropped.
+pub struct DataDirLock {
+    _file: Option<File>,
+}
+
+impl DataDirLock {
+    /// Lock `data_dir` for a server listening on `address`, failing with
+    /// who holds it if another process does. Filesystems without locks,
+    /// such as some network shares, are used unlocked with a warning.
+    pub fn acquire(data_dir: &Path, address: &str) -> Result<Self> {
+        fs::create_dir_all(data_dir)
+            .with_context(|| format!("Failed to create data directory {:?}", data_dir))?;
+        let path = data_dir.join(LOCK_FILE);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .with_context(|| format!("Failed to open lock file {:?}", path))?;
+
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => return Err(in_use(data_dir, &path)),
+            Err(TryLockError::Error(e)) if e.kind() == std::io::ErrorKind::Unsupported => {
+                warn!(
+                    "Can't lock data directory {:?} ({}); make sure no other server uses it",
+                    data_dir, e
+                );
+                return Ok(Self { _file: None });
+            }
+            Err(TryLockError::Error(e)) => {
+                return Err(e).with_context(|| format!("Failed to lock {:?}", path));
+            }
+        }
+
+        let holder = Holder {
+            pid: std::process::id(),
+            address: address.to_string(),
+            started_at: Utc::now(),
+        };
+        file.set_len(0)?;
+        file.write_all(&serde_json::to_vec(&holder)?)?;
+        file.flush()?;
+        Ok(Self { _file: Some(file) })
+    }
+}
+
+/// The error for a data directory locked by another process, naming it
+/// when the lock file says who it is.
+fn in_use(data_dir: &Path, path: &Path) -> anyhow::Error {
+    // On Windows the holder's lock also keeps the file from being read
+    let holder = fs::read(path)
+        .ok()
+        .and_then(|bytes| serde_json::from_slice::<Holder>(&bytes).ok());
+    let by = match holder {
+        Some(holder) => format!(
+            "another server (pid {}, serving {} since {})",
+            holder.pid,
+            holder.address,
+            holder.started_at.format("%Y-%m-%d %H:%M:%S UTC")
+        ),
+        None => "another process".to_string(),
+    };
+    anyhow::anyhow!(
+        "Data directory {:?} is in use by {}. Two servers writing the same index would \
+         corrupt it: stop the other one, or start this one with a different --data-dir \
+         (or SYSTEMATICS_DATA_DIR).",
+        data_dir,
+        by
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_second_lock_fails_until_first_is_dropped() {
+        let dir = std::env::temp_dir().join(format!("systematics-lock-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let first = DataDirLock::acquire(&dir, "127.0.0.1:8765").unwrap();
+        let err = DataDirLock::acquire(&dir, "127.0.0.1:8766")
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("is in use by"), "{}", err);
+        if cfg!(unix) {
+            let expected = format!("pid {}, serving 127.0.0.1:8765", std::process::id());
+            assert!(err.contains(&expected), "{}", err);
+        }
+
+        drop(first);
+        DataDirLock::acquire(&dir, "127.0.0.1:8766").unwrap();
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
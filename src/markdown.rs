@@ -0,0 +1,304 @@
+use pulldown_cmark::{Event, LinkType, Options, Parser, Tag, TagEnd};
+use serde_json::{Map, Value};
+use std::ops::Range;
+
+use crate::config::MarkdownConfig;
+
+/// A note ready to embed: its text with the markdown syntax stripped, its
+/// metadata with the frontmatter and tags merged in, and where each
+/// heading's section starts and ends in the text.
+#[derive(Debug)]
+pub struct Prepared {
+    pub text: String,
+    pub metadata: Option<Value>,
+    /// Byte ranges of the sections, empty unless splitting by heading
+    /// found more than one
+    pub sections: Vec<Range<usize>>,
+}
+
+/// Split a note into its YAML frontmatter, if it has a valid mapping as
+/// one, and the body after it.
+pub fn split_frontmatter(contents: &str) -> (Option<Value>, &str) {
+    let contents = contents.strip_prefix('\u{feff}').unwrap_or(contents);
+    let frontmatter = contents
+        .strip_prefix("---\n")
+        .or_else(|| contents.strip_prefix("---\r\n"))
+        .and_then(|rest| {
+            let end = rest.find("\n---")?;
+            let after = &rest[end + 4..];
+            let body = after
+                .strip_prefix("\r\n")
+                .or_else(|| after.strip_prefix('\n'))
+                .or_else(|| after.is_empty().then_some(after))?;
+            let metadata: Value = serde_yaml::from_str(&rest[..end]).ok()?;
+            metadata.is_object().then_some((metadata, body))
+        });
+
+    match frontmatter {
+        Some((metadata, body)) => (Some(metadata), body),
+        None => (None, contents),
+    }
+}
+
+/// Turn a markdown note into the text worth embedding. Heading markers,
+/// emphasis, link targets, code fences, HTML, and Obsidian comments and
+/// embeds go; the words stay, with wikilinks reduced to their display
+/// text. Frontmatter fields are merged into `metadata` (fields sent with
+/// the request win), and `#tags` in the text are added to its `tags`.
+pub fn prepare(text: &str, metadata: Option<Value>, config: &MarkdownConfig) -> Prepared {
+    let (frontmatter, body) = split_frontmatter(text);
+    let body = strip_comments(body);
+
+    let mut out = String::with_capacity(body.len());
+    let mut tags = Vec::new();
+    let mut headings = Vec::new();
+    // Images and embeds nest, and their alt text is mostly file names
+    let mut in_image = 0usize;
+    let mut in_wikilink = false;
+    let mut in_code_block = false;
+
+    let options = Options::ENABLE_TABLES
+        | Options::ENABLE_STRIKETHROUGH
+        | Options::ENABLE_TASKLISTS
+        | Options::ENABLE_GFM
+        | Options::ENABLE_MATH
+        | Options::ENABLE_WIKILINKS;
+    for event in Parser::new_ext(&body, options) {
+        match event {
+            Event::Start(Tag::Heading { .. }) => {
+                end_block(&mut out);
+                headings.push(out.len());
+            }
+            Event::Start(Tag::Image { .. }) => in_image += 1,
+            Event::End(TagEnd::Image) => in_image -= 1,
+            Event::Start(Tag::CodeBlock(_)) => {
+                end_block(&mut out);
+                in_code_block = true;
+            }
+            Event::End(TagEnd::CodeBlock) => {
+                in_code_block = false;
+                end_block(&mut out);
+            }
+            Event::Start(Tag::Link {
+                link_type: LinkType::WikiLink { has_pothole: false },
+                ..
+            }) => in_wikilink = true,
+            Event::End(TagEnd::Link) => in_wikilink = false,
+            Event::Text(text) if in_wikilink && in_image == 0 => {
+                // An unaliased link to a heading reads as the note's name
+                // followed by the heading's
+                out.push_str(&text.replace('#', " "));
+            }
+            Event::Text(text) if in_code_block && config.keep_code => out.push_str(&text),
+            Event::Text(_) if in_code_block => {}
+            Event::Text(text) | Event::Code(text) if in_image == 0 => {
+                push_text(&mut out, &text, &mut tags);
+            }
+            Event::InlineMath(math) | Event::DisplayMath(math) if in_image == 0 => {
+                out.push_str(&math);
+            }
+            Event::SoftBreak => out.push(' '),
+            Event::HardBreak => out.push('\n'),
+            Event::End(TagEnd::TableCell) => out.push(' '),
+            Event::End(
+                TagEnd::Paragraph
+                | TagEnd::Heading(_)
+                | TagEnd::Item
+                | TagEnd::BlockQuote(_)
+                | TagEnd::TableHead
+                | TagEnd::TableRow,
+            ) => end_block(&mut out),
+            _ => {}
+        }
+    }
+
+    let text = out.trim_end().to_string();
+    let sections = if config.split_headings {
+        sections(&text, &headings)
+    } else {
+        Vec::new()
+    };
+    Prepared {
+        metadata: merge_metadata(metadata, frontmatter, tags),
+        text,
+        sections,
+    }
+}
+
+/// Start a new line unless the text is empty or already on one.
+fn end_block(out: &mut String) {
+    out.truncate(out.trim_end_matches(' ').len());
+    if !out.is_empty() && !out.ends_with('\n') {
+        out.push('\n');
+    }
+}
+
+/// Append text, collecting `#tags` and dropping the `#` from them.
+fn push_text(out: &mut String, text: &str, tags: &mut Vec<String>) {
+    let mut rest = text;
+    while let Some(hash) = rest.find('#') {
+        let tag: String = rest[hash + 1..]
+            .chars()
+            .take_while(|&c| c.is_alphanumeric() || matches!(c, '_' | '-' | '/'))
+            .collect();
+        // A tag starts a word and isn't all digits, as in Obsidian
+        let starts_word = rest[..hash]
+            .chars()
+            .next_back()
+            .is_none_or(char::is_whitespace)
+            && (hash > 0 || !out.ends_with(|c: char| !c.is_whitespace()));
+        if !tag.is_empty() && starts_word && !tag.chars().all(|c| c.is_ascii_digit()) {
+            out.push_str(&rest[..hash]);
+            out.push_str(&tag);
+            if !tags.contains(&tag) {
+                tags.push(tag.clone());
+            }
+            rest = &rest[hash + 1 + tag.len()..];
+        } else {
+            out.push_str(&rest[..=hash]);
+            rest = &rest[hash + 1..];
+        }
+    }
+    out.push_str(rest);
+}
+
+/// Remove Obsidian `%%comments%%` and callout markers like `[!note]`,
+/// neither of which markdown knows about.
+fn strip_comments(body: &str) -> String {
+    let mut stripped = String::with_capacity(body.len());
+    let mut parts = body.split("%%");
+    while let Some(visible) = parts.next() {
+        stripped.push_str(visible);
+        // Skip the comment; an unclosed one runs to the end
+        parts.next();
+    }
+
+    stripped
+        .lines()
+        .map(|line| {
+            let quoted = line.trim_start_matches(['>', ' ']);
+            match quoted
+                .strip_prefix("[!")
+                .and_then(|rest| rest.split_once(']'))
+            {
+                Some((_, title)) => {
+                    let prefix = &line[..line.len() - quoted.len()];
+                    format!("{}{}", prefix, title.trim_start_matches(['+', '-']))
+                }
+                None => line.to_string(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Ranges from each heading to the next, with any text before the first
+/// heading as a section of its own. Empty sections are dropped.
+fn sections(text: &str, headings: &[usize]) -> Vec<Range<usize>> {
+    let mut bounds: Vec<usize> = headings
+        .iter()
+        .copied()
+        .filter(|&start| start > 0 && start < text.len())
+        .collect();
+    bounds.insert(0, 0);
+    bounds.push(text.len());
+    bounds.dedup();
+
+    let sections: Vec<Range<usize>> = bounds
+        .windows(2)
+        .map(|pair| pair[0]..pair[1])
+        .filter(|range| !text[range.clone()].trim().is_empty())
+        .collect();
+    if sections.len() > 1 {
+        sections
+    } else {
+        Vec::new()
+    }
+}
+
+/// Frontmatter fields under the request's own, with inline tags appended
+/// to `tags`.
+fn merge_metadata(
+    metadata: Option<Value>,
+    frontmatter: Option<Value>,
+    tags: Vec<String>,
+) -> Option<Value> {
+    let mut merged = match frontmatter {
+        Some(Value::Object(fields)) => fields,
+        _ => Map::new(),
+    };
+    match metadata {
+        Some(Value::Object(fields)) => merged.extend(fields),
+        Some(other) if merged.is_empty() && tags.is_empty() => return Some(other),
+        _ => {}
+    }
+
+    if !tags.is_empty() {
+        let mut all: Vec<Value> = match merged.remove("tags") {
+            Some(Value::Array(existing)) => existing,
+            Some(Value::String(existing)) => existing
+                .split([',', ' '])
+                .filter(|tag| !tag.is_empty())
+                .map(|tag| Value::String(tag.to_string()))
+                .collect(),
+            Some(other) => vec![other],
+            None => Vec::new(),
+        };
+        for tag in tags {
+            let tag = Value::String(tag);
+            if !all.contains(&tag) {
+                all.push(tag);
+            }
+        }
+        merged.insert("tags".to_string(), Value::Array(all));
+    }
+
+    (!merged.is_empty()).then_some(Value::Object(merged))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_prepare_strips_markdown() {
+        let config = MarkdownConfig {
+            enabled: true,
+            split_headings: true,
+            keep_code: false,
+        };
+        let note = "---\ntags: [systematics]\n---\n\
+            # Triads\n\nThe **three** forces, see [[Bennett#Triad|the triad]] and [[Dyad]].\n\
+            ![[diagram.png]]\n\n\
+            ```rust\nlet x = 1;\n```\n\n\
+            ## Tetrads %%todo%%\n\n- [ ] Read [the book](https://example.com) #reading\n";
+        let prepared = prepare(note, Some(json!({ "status": "draft" })), &config);
+
+        assert_eq!(
+            prepared.text,
+            "Triads\nThe three forces, see the triad and Dyad.\nTetrads\nRead the book reading"
+        );
+        assert_eq!(
+            prepared.metadata,
+            Some(json!({ "status": "draft", "tags": ["systematics", "reading"] }))
+        );
+        let sections: Vec<&str> = prepared
+            .sections
+            .iter()
+            .map(|range| prepared.text[range.clone()].trim())
+            .collect();
+        assert_eq!(
+            sections,
+            [
+                "Triads\nThe three forces, see the triad and Dyad.",
+                "Tetrads\nRead the book reading"
+            ]
+        );
+
+        // Neither headings in code nor issue numbers are tags
+        let prepared = prepare("Fixes #12 in C#\n", None, &config);
+        assert_eq!(prepared.text, "Fixes #12 in C#");
+        assert_eq!(prepared.metadata, None);
+    }
+}
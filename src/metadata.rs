@@ -0,0 +1,285 @@
+use serde_json::{Map, Value};
+
+use crate::config::MetadataConfig;
+use crate::filter::Filter;
+
+/// Canonical form of a single metadata key: lowercased and sanitized as
+/// configured, then mapped through the top-level key aliases if `top_level`.
+pub fn normalize_key(config: &MetadataConfig, key: &str, top_level: bool) -> String {
+    let mut key = if config.lowercase_keys {
+        key.to_lowercase()
+    } else {
+        key.to_string()
+    };
+
+    if config.sanitize_keys {
+        let mut sanitized = String::with_capacity(key.len());
+        for c in key.trim().chars() {
+            let c = if c.is_alphanumeric() || c == '-' {
+                c
+            } else {
+                '_'
+            };
+            if !(c == '_' && sanitized.ends_with('_')) {
+                sanitized.push(c);
+            }
+        }
+        key = sanitized;
+    }
+
+    if top_level {
+        if let Some(canonical) = config.key_aliases.get(&key) {
+            return canonical.clone();
+        }
+    }
+    key
+}
+
+/// Normalize every key in a document's metadata at ingest. Keys that end
+/// up the same, such as `tag` and `tags` when one aliases the other, have
+/// their values merged into one list.
+pub fn normalize(config: &MetadataConfig, metadata: Value) -> Value {
+    if !config.is_enabled() {
+        return metadata;
+    }
+    normalize_value(config, metadata, true)
+}
+
+fn normalize_value(config: &MetadataConfig, value: Value, top_level: bool) -> Value {
+    match value {
+        Value::Object(fields) => {
+            let mut normalized = Map::new();
+            for (key, value) in fields {
+                let key = normalize_key(config, &key, top_level);
+                let value = normalize_value(config, value, false);
+                match normalized.remove(&key) {
+                    Some(existing) => {
+                        normalized.insert(key, merge(existing, value));
+                    }
+                    None => {
+                        normalized.insert(key, value);
+                    }
+                }
+            }
+            Value::Object(normalized)
+        }
+        Value::Array(values) => Value::Array(
+            values
+                .into_iter()
+                .map(|value| normalize_value(config, value, false))
+                .collect(),
+        ),
+        other => other,
+    }
+}
+
+/// Combine two values for the same key into one list without duplicates.
+fn merge(a: Value, b: Value) -> Value {
+    let mut merged = Vec::new();
+    for value in [a, b] {
+        let values = match value {
+            Value::Array(values) => values,
+            value => vec![value],
+        };
+        for value in values {
+            if !merged.contains(&value) {
+                merged.push(value);
+            }
+        }
+    }
+    Value::Array(merged)
+}
+
+/// Rewrite a filter's field paths with the rules applied at ingest, so a
+/// query for `Tags` finds documents whose `tags` were normalized.
+pub fn normalize_filter(config: &MetadataConfig, filter: Filter) -> Filter {
+    if !config.is_enabled() {
+        return filter;
+    }
+
+    match filter {
+        Filter::And(filters) => Filter::And(
+            filters
+                .into_iter()
+                .map(|filter| normalize_filter(config, filter))
+                .collect(),
+        ),
+        Filter::Or(filters) => Filter::Or(
+            filters
+                .into_iter()
+                .map(|filter| normalize_filter(config, filter))
+                .collect(),
+        ),
+        Filter::Not(filter) => Filter::Not(Box::new(normalize_filter(config, *filter))),
+        Filter::Field { path, condition } => {
+            let path = path
+                .split('.')
+                .enumerate()
+                .map(|(i, segment)| normalize_key(config, segment, i == 0))
+                .collect::<Vec<_>>()
+                .join(".");
+            Filter::Field { path, condition }
+        }
+    }
+}
+
+/// Render a collection's embedding template with a document's metadata,
+/// giving the header embedded along with its text. `{field}` is replaced
+/// by the field's value, found by a dotted path as in filters but ignoring
+/// case, since keys may have been lowercased at ingest, with lists joined
+/// by commas; a line whose fields are all missing or empty is left out.
+/// `None` if nothing is left.
+pub fn render_template(template: &str, metadata: Option<&Value>) -> Option<String> {
+    let mut lines = Vec::new();
+    for line in template.lines() {
+        let mut rendered = String::new();
+        let mut fields = 0;
+        let mut found = 0;
+        let mut rest = line;
+        while let Some((field, start, end)) = next_field(rest) {
+            rendered.push_str(&rest[..start]);
+            let value = metadata
+                .and_then(|metadata| lookup_any_case(metadata, field))
+                .map(field_text)
+                .unwrap_or_default();
+            fields += 1;
+            if !value.is_empty() {
+                found += 1;
+            }
+            rendered.push_str(&value);
+            rest = &rest[end..];
+        }
+        rendered.push_str(rest);
+        if fields == 0 || found > 0 {
+            lines.push(rendered.trim_end().to_string());
+        }
+    }
+
+    let header = lines.join("\n").trim().to_string();
+    (!header.is_empty()).then_some(header)
+}
+
+/// Follow a dotted path through nested objects, preferring a key that
+/// matches exactly and otherwise taking one that differs only in case.
+fn lookup_any_case<'a>(metadata: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('.').try_fold(metadata, |value, key| {
+        let fields = value.as_object()?;
+        fields.get(key).or_else(|| {
+            let key = key.to_lowercase();
+            fields
+                .iter()
+                .find(|(field, _)| field.to_lowercase() == key)
+                .map(|(_, value)| value)
+        })
+    })
+}
+
+/// Check an embedding template names at least one field.
+pub fn check_template(template: &str) -> Result<(), String> {
+    if !template.lines().any(|line| next_field(line).is_some()) {
+        return Err(format!(
+            "Embedding template {:?} names no {{field}} of the metadata",
+            template
+        ));
+    }
+    Ok(())
+}
+
+/// The first `{field}` placeholder in `s`: its path, and the byte range
+/// of the placeholder with its braces.
+fn next_field(s: &str) -> Option<(&str, usize, usize)> {
+    let mut offset = 0;
+    while let Some(start) = s[offset..].find('{').map(|i| offset + i) {
+        let end = start + s[start..].find('}')?;
+        let field = s[start + 1..end].trim();
+        if !field.is_empty() && !field.contains('{') {
+            return Some((field, start, end + 1));
+        }
+        offset = start + 1;
+    }
+    None
+}
+
+fn field_text(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.trim().to_string(),
+        Value::Number(n) => n.to_string(),
+        Value::Bool(b) => b.to_string(),
+        Value::Array(values) => values
+            .iter()
+            .map(field_text)
+            .filter(|text| !text.is_empty())
+            .collect::<Vec<_>>()
+            .join(", "),
+        Value::Null | Value::Object(_) => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_normalize_obsidian_properties() {
+        let config = MetadataConfig {
+            lowercase_keys: true,
+            sanitize_keys: true,
+            key_aliases: [("tag".to_string(), "tags".to_string())].into(),
+        };
+
+        let metadata = normalize(
+            &config,
+            json!({
+                "Tags": ["systematics"],
+                "tag": "notes",
+                "Created Date": "2024-03-01",
+                "Author": { "Full Name": "Jo" }
+            }),
+        );
+        assert_eq!(
+            metadata,
+            json!({
+                "tags": ["systematics", "notes"],
+                "created_date": "2024-03-01",
+                "author": { "full_name": "Jo" }
+            })
+        );
+
+        let filter: Filter =
+            serde_json::from_value(json!({ "TAG": "notes", "Author.Full Name": "Jo" })).unwrap();
+        assert!(normalize_filter(&config, filter).matches(Some(&metadata)));
+    }
+
+    #[test]
+    fn test_render_template() {
+        let template = "{title}\nTags: {tags}\nBy {author.name} ({year})";
+        let metadata = json!({
+            "title": "Triads",
+            "tags": ["systematics", "", "three"],
+            "author": { "name": "Jo" }
+        });
+        assert_eq!(
+            render_template(template, Some(&metadata)).as_deref(),
+            Some("Triads\nTags: systematics, three\nBy Jo ()")
+        );
+
+        // Lines whose fields are all missing are left out
+        let metadata = json!({ "title": "Dyads", "tags": [] });
+        assert_eq!(
+            render_template(template, Some(&metadata)).as_deref(),
+            Some("Dyads")
+        );
+        assert_eq!(render_template(template, None), None);
+
+        // Fields match keys lowercased at ingest
+        let metadata = json!({ "title": "Triads", "author": { "name": "Jo" } });
+        assert_eq!(
+            render_template("{Title} by {Author.Name}", Some(&metadata)).as_deref(),
+            Some("Triads by Jo")
+        );
+
+        assert!(check_template(template).is_ok());
+        assert!(check_template("Title: { }").is_err());
+    }
+}
@@ -0,0 +1,205 @@
+use anyhow::{Context, Result};
+use chrono::Utc;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+use crate::lock;
+
+/// Version of the on-disk layout this build reads and writes. Bump it
+/// whenever the layout changes, and add a migration from the previous
+/// version to [`MIGRATIONS`].
+pub const FORMAT_VERSION: u32 = 2;
+
+const VERSION_FILE: &str = "format_version";
+
+/// Where copies of the data directory are kept before migrating it.
+pub const BACKUP_DIR: &str = "backups";
+
+/// Upgrades a data directory from `from` to `from + 1`.
+pub struct Migration {
+    pub from: u32,
+    pub description: &'static str,
+    run: fn(&Path) -> Result<()>,
+}
+
+/// Every migration, in version order.
+const MIGRATIONS: &[Migration] = &[Migration {
+    from: 1,
+    description: "Move the default collection from index/ to collections/default/",
+    run: move_default_collection,
+}];
+
+/// Migrations needed to bring `data_dir` up to [`FORMAT_VERSION`], in the
+/// order they would run. Fails if the directory was written by a newer
+/// build, which this one can't safely read.
+pub fn pending(data_dir: &Path) -> Result<Vec<&'static Migration>> {
+    let version = match read_version(data_dir)? {
+        Some(version) => version,
+        None if is_unversioned(data_dir) => 1,
+        // Nothing stored yet, so nothing to migrate
+        None => FORMAT_VERSION,
+    };
+
+    if version > FORMAT_VERSION {
+        anyhow::bail!(
+            "{:?} uses on-disk format {}, but this build only supports up to {}; \
+             upgrade the server or restore a backup",
+            data_dir,
+            version,
+            FORMAT_VERSION
+        );
+    }
+
+    Ok(MIGRATIONS
+        .iter()
+        .filter(|migration| migration.from >= version)
+        .collect())
+}
+
+/// Bring `data_dir` up to the current format. The version is recorded
+/// after each step, so an interrupted upgrade resumes where it stopped.
+///
+/// Before anything changes, the data directory is copied as-is into
+/// `backups/`, so a botched upgrade can be rolled back by restoring the
+/// copy and running the previous release.
+pub fn run(data_dir: &Path) -> Result<()> {
+    fs::create_dir_all(data_dir)?;
+
+    let pending = pending(data_dir)?;
+    if let Some(first) = pending.first() {
+        let backup = backup(data_dir, first.from)?;
+        info!(
+            "Backed up {:?} to {:?} before migrating; restore its contents to roll back",
+            data_dir, backup
+        );
+    }
+
+    for migration in pending {
+        info!(
+            "Migrating {:?} from format {}: {}",
+            data_dir, migration.from, migration.description
+        );
+        (migration.run)(data_dir).with_context(|| {
+            format!(
+                "Migration from format {} failed: {}",
+                migration.from, migration.description
+            )
+        })?;
+        write_version(data_dir, migration.from + 1)?;
+    }
+
+    if read_version(data_dir)? != Some(FORMAT_VERSION) {
+        write_version(data_dir, FORMAT_VERSION)?;
+    }
+    Ok(())
+}
+
+/// Copy everything in `data_dir` except earlier backups and the server's
+/// lock file to a fresh directory under `backups/` named after the format
+/// it holds.
+fn backup(data_dir: &Path, version: u32) -> Result<PathBuf> {
+    let dest = data_dir.join(BACKUP_DIR).join(format!(
+        "format-{}-{}",
+        version,
+        Utc::now().format("%Y%m%dT%H%M%S")
+    ));
+    fs::create_dir_all(&dest)?;
+
+    for entry in fs::read_dir(data_dir)? {
+        let entry = entry?;
+        if entry.file_name() != BACKUP_DIR && entry.file_name() != lock::LOCK_FILE {
+            copy_recursive(&entry.path(), &dest.join(entry.file_name()))
+                .with_context(|| format!("Failed to back up {:?}", entry.path()))?;
+        }
+    }
+    Ok(dest)
+}
+
+fn copy_recursive(from: &Path, to: &Path) -> Result<()> {
+    if from.is_dir() {
+        fs::create_dir_all(to)?;
+        for entry in fs::read_dir(from)? {
+            let entry = entry?;
+            copy_recursive(&entry.path(), &to.join(entry.file_name()))?;
+        }
+    } else {
+        fs::copy(from, to)?;
+    }
+    Ok(())
+}
+
+fn read_version(data_dir: &Path) -> Result<Option<u32>> {
+    let path = data_dir.join(VERSION_FILE);
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let contents = fs::read_to_string(&path)?;
+    let version = contents
+        .trim()
+        .parse()
+        .with_context(|| format!("Invalid format version in {:?}: {:?}", path, contents))?;
+    Ok(Some(version))
+}
+
+fn write_version(data_dir: &Path, version: u32) -> Result<()> {
+    let path = data_dir.join(VERSION_FILE);
+    let tmp_path = data_dir.join(format!("{}.tmp", VERSION_FILE));
+    fs::write(&tmp_path, format!("{}\n", version))?;
+    fs::rename(&tmp_path, &path)?;
+    Ok(())
+}
+
+/// Data directories from before versioning hold the default collection
+/// at `index/`.
+fn is_unversioned(data_dir: &Path) -> bool {
+    data_dir.join("index").exists()
+}
+
+fn move_default_collection(data_dir: &Path) -> Result<()> {
+    let old = data_dir.join("index");
+    let new = data_dir.join("collections").join("default");
+    if !old.exists() {
+        return Ok(());
+    }
+    if new.exists() {
+        anyhow::bail!("Both {:?} and {:?} exist; remove one and restart", old, new);
+    }
+
+    fs::create_dir_all(data_dir.join("collections"))?;
+    fs::rename(&old, &new)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_migrates_unversioned_data_dir() {
+        let dir = std::env::temp_dir().join(format!("systematics-migrate-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("index")).unwrap();
+        fs::write(dir.join("index").join("index.log"), b"").unwrap();
+
+        assert_eq!(pending(&dir).unwrap().len(), 1);
+        run(&dir).unwrap();
+        assert!(pending(&dir).unwrap().is_empty());
+        assert!(dir.join("collections/default/index.log").exists());
+        assert!(!dir.join("index").exists());
+        assert_eq!(read_version(&dir).unwrap(), Some(FORMAT_VERSION));
+
+        // The pre-migration layout was kept for rollback
+        let backups: Vec<_> = fs::read_dir(dir.join(BACKUP_DIR)).unwrap().collect();
+        assert_eq!(backups.len(), 1);
+        let backup = backups[0].as_ref().unwrap().path();
+        assert!(backup.join("index/index.log").exists());
+
+        // A newer format is refused rather than misread
+        write_version(&dir, FORMAT_VERSION + 1).unwrap();
+        assert!(pending(&dir).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
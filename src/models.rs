@@ -0,0 +1,339 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use tracing::info;
+
+use crate::chunking;
+use crate::config::{ChunkingConfig, Config};
+use crate::embedding::EmbeddingService;
+use crate::index::{SearchOptions, VectorIndex};
+
+/// Documents re-embedded per forward pass when evaluating a variant.
+const EVALUATION_BATCH_SIZE: usize = 32;
+
+#[derive(Serialize, Clone)]
+pub struct ModelInfo {
+    pub name: String,
+    pub dimensions: usize,
+    pub fingerprint: String,
+}
+
+/// The models served at once: the base model, which collections use
+/// unless bound to another, and those configured by name alongside it,
+/// e.g. a multilingual model for a collection of notes in other languages.
+pub struct ModelRegistry {
+    base: String,
+    models: BTreeMap<String, Arc<EmbeddingService>>,
+}
+
+impl ModelRegistry {
+    /// Load the base model and every model in `config.models`.
+    pub async fn load(config: &Config) -> Result<Self> {
+        let mut models = BTreeMap::new();
+        let base = Arc::new(EmbeddingService::new(&config.model).await?);
+        models.insert(config.model.name.clone(), base);
+        for (name, model) in &config.models {
+            info!("Loading model {} from {}", name, model);
+            let mut model_config =
+                config
+                    .model
+                    .for_model(name, model, config.model_pooling.get(name).copied());
+            if let Some(instruction) = config.model_query_instructions.get(name) {
+                model_config.query_instruction = instruction.clone();
+            }
+            if let Some(instruction) = config.model_document_instructions.get(name) {
+                model_config.document_instruction = instruction.clone();
+            }
+            let service = EmbeddingService::new(&model_config)
+                .await
+                .with_context(|| format!("Failed to load model {}", name))?;
+            models.insert(name.clone(), Arc::new(service));
+        }
+
+        Ok(Self {
+            base: config.model.name.clone(),
+            models,
+        })
+    }
+
+    pub fn base(&self) -> &Arc<EmbeddingService> {
+        &self.models[&self.base]
+    }
+
+    /// The model called `name`, or the base model when `name` is `None`.
+    pub fn get(&self, name: Option<&str>) -> Option<&Arc<EmbeddingService>> {
+        self.models.get(name.unwrap_or(&self.base))
+    }
+
+    /// Every model by name, the base model included.
+    pub fn list(&self) -> Vec<ModelInfo> {
+        self.models
+            .iter()
+            .map(|(name, service)| ModelInfo {
+                name: name.clone(),
+                dimensions: service.dimensions(),
+                fingerprint: service.fingerprint().to_string(),
+            })
+            .collect()
+    }
+}
+
+/// Recall@k of the base model and a variant over the feedback test set.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Evaluation {
+    /// Hash of the retrieval config active when the evaluation ran
+    pub config_hash: Option<String>,
+    pub k: usize,
+    pub queries: usize,
+    pub base_recall: f32,
+    pub variant_recall: f32,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum EvaluationStatus {
+    Pending,
+    Complete(Evaluation),
+    Failed { error: String },
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct VariantInfo {
+    pub name: String,
+    pub model_path: PathBuf,
+    /// Unset for a variant using the base model's tokenizer
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tokenizer_path: Option<PathBuf>,
+    pub evaluation: EvaluationStatus,
+}
+
+struct ModelVariant {
+    info: VariantInfo,
+    service: Arc<EmbeddingService>,
+}
+
+/// Fine-tuned model variants loaded alongside the base model, persisted
+/// as JSON so they are loaded again after a restart.
+pub struct VariantRegistry {
+    path: PathBuf,
+    variants: RwLock<BTreeMap<String, ModelVariant>>,
+}
+
+impl VariantRegistry {
+    /// An empty registry saving to `path`, and the variants saved there
+    /// before, to load and [`register`](Self::register) again. Evaluations
+    /// a restart cut short are marked failed.
+    pub fn open(path: PathBuf) -> Result<(Self, Vec<VariantInfo>)> {
+        let mut saved: Vec<VariantInfo> = if path.exists() {
+            serde_json::from_str(&fs::read_to_string(&path)?)?
+        } else {
+            Vec::new()
+        };
+        for info in &mut saved {
+            if matches!(info.evaluation, EvaluationStatus::Pending) {
+                info.evaluation = EvaluationStatus::Failed {
+                    error: "Interrupted by a restart".to_string(),
+                };
+            }
+        }
+
+        let registry = Self {
+            path,
+            variants: RwLock::new(BTreeMap::new()),
+        };
+        Ok((registry, saved))
+    }
+
+    /// Register a loaded variant, replacing any previous variant with the
+    /// same name.
+    pub async fn register(&self, info: VariantInfo, service: Arc<EmbeddingService>) -> Result<()> {
+        let mut variants = self.variants.write().unwrap();
+        variants.insert(info.name.clone(), ModelVariant { info, service });
+        self.save(&variants)
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<EmbeddingService>> {
+        let variants = self.variants.read().unwrap();
+        variants.get(name).map(|variant| variant.service.clone())
+    }
+
+    pub async fn list(&self) -> Vec<VariantInfo> {
+        let variants = self.variants.read().unwrap();
+        variants
+            .values()
+            .map(|variant| variant.info.clone())
+            .collect()
+    }
+
+    pub async fn set_evaluation(&self, name: &str, evaluation: EvaluationStatus) -> Result<()> {
+        let mut variants = self.variants.write().unwrap();
+        if let Some(variant) = variants.get_mut(name) {
+            variant.info.evaluation = evaluation;
+            self.save(&variants)?;
+        }
+        Ok(())
+    }
+
+    fn save(&self, variants: &BTreeMap<String, ModelVariant>) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let infos: Vec<&VariantInfo> = variants.values().map(|variant| &variant.info).collect();
+        let tmp = self.path.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_string_pretty(&infos)?)?;
+        fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+/// The model a collection bound to `name` is embedded with: the served
+/// model or registered variant of that name, or the base model for `None`.
+pub fn resolve(
+    models: &ModelRegistry,
+    variants: &VariantRegistry,
+    name: Option<&str>,
+) -> Option<Arc<EmbeddingService>> {
+    models
+        .get(name)
+        .cloned()
+        .or_else(|| name.and_then(|name| variants.get(name)))
+}
+
+/// Compare recall@k of the base model against a variant. The base model is
+/// scored against the stored vectors; the variant re-embeds every indexed
+/// document into a scratch index first, chunked the same way.
+pub async fn evaluate(
+    index: &VectorIndex,
+    base: &EmbeddingService,
+    variant: &EmbeddingService,
+    chunking: ChunkingConfig,
+    test_set: &[(String, Vec<String>)],
+    k: usize,
+    config_hash: Option<String>,
+) -> Result<Evaluation> {
+    let scratch = VectorIndex::new(index.precision(), index.quantization(), index.hnsw());
+    for docs in index.list().await?.chunks(EVALUATION_BATCH_SIZE) {
+        let texts: Vec<&str> = docs.iter().map(|doc| doc.text.as_str()).collect();
+        let embeddings = chunking::embed_documents(variant, &texts, chunking).await?;
+        for (doc, embedded) in docs.iter().zip(embeddings) {
+            scratch
+                .add(&doc.id, embedded, doc.text.clone(), None)
+                .await?;
+        }
+    }
+
+    let mut base_total = 0.0;
+    let mut variant_total = 0.0;
+    for (query, relevant) in test_set {
+        let base_results = index
+            .search(
+                &base.embed_query(query, None).await?,
+                k,
+                &SearchOptions::default(),
+            )
+            .await?;
+        let base_ids: Vec<String> = base_results.into_iter().map(|r| r.id).collect();
+        base_total += recall_at_k(&base_ids, relevant);
+
+        let variant_results = scratch
+            .search(
+                &variant.embed_query(query, None).await?,
+                k,
+                &SearchOptions::default(),
+            )
+            .await?;
+        let variant_ids: Vec<String> = variant_results.into_iter().map(|r| r.id).collect();
+        variant_total += recall_at_k(&variant_ids, relevant);
+    }
+
+    let queries = test_set.len();
+    let mean = |total: f32| {
+        if queries == 0 {
+            0.0
+        } else {
+            total / queries as f32
+        }
+    };
+
+    Ok(Evaluation {
+        config_hash,
+        k,
+        queries,
+        base_recall: mean(base_total),
+        variant_recall: mean(variant_total),
+    })
+}
+
+/// Fraction of the relevant ids that appear in the retrieved list.
+fn recall_at_k(retrieved: &[String], relevant: &[String]) -> f32 {
+    if relevant.is_empty() {
+        return 0.0;
+    }
+
+    let retrieved: HashSet<&String> = retrieved.iter().collect();
+    let hits = relevant.iter().filter(|id| retrieved.contains(id)).count();
+
+    hits as f32 / relevant.len() as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recall_at_k() {
+        let ids = |ids: &[&str]| ids.iter().map(|id| id.to_string()).collect::<Vec<_>>();
+
+        assert_eq!(recall_at_k(&ids(&["a", "b"]), &ids(&["a"])), 1.0);
+        assert_eq!(recall_at_k(&ids(&["a", "b"]), &ids(&["a", "c"])), 0.5);
+        assert_eq!(recall_at_k(&ids(&["b"]), &ids(&["a"])), 0.0);
+        assert_eq!(recall_at_k(&ids(&["a"]), &[]), 0.0);
+    }
+
+    #[test]
+    fn test_saved_variants_are_read_back_with_pending_evaluations_failed() {
+        let path =
+            std::env::temp_dir().join(format!("systematics-variants-{}.json", std::process::id()));
+        let saved = serde_json::json!([
+            {
+                "name": "finetune",
+                "model_path": "models/finetune/model.onnx",
+                "evaluation": { "status": "pending" }
+            },
+            {
+                "name": "tuned",
+                "model_path": "models/tuned/model.onnx",
+                "tokenizer_path": "models/tuned/tokenizer.json",
+                "evaluation": {
+                    "status": "complete",
+                    "config_hash": null,
+                    "k": 10,
+                    "queries": 4,
+                    "base_recall": 0.5,
+                    "variant_recall": 0.75
+                }
+            }
+        ]);
+        fs::write(&path, saved.to_string()).unwrap();
+
+        let (registry, saved) = VariantRegistry::open(path.clone()).unwrap();
+        assert!(registry.get("finetune").is_none());
+        assert!(matches!(
+            saved[0].evaluation,
+            EvaluationStatus::Failed { .. }
+        ));
+        assert_eq!(
+            saved[1].tokenizer_path,
+            Some(PathBuf::from("models/tuned/tokenizer.json"))
+        );
+        assert!(matches!(
+            saved[1].evaluation,
+            EvaluationStatus::Complete(Evaluation { k: 10, .. })
+        ));
+
+        fs::remove_file(&path).unwrap();
+    }
+}
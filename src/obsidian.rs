@@ -0,0 +1,53 @@
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use serde_json::Value;
+
+use crate::config::ObsidianConfig;
+
+/// Everything but unreserved URI characters is escaped, including `/`,
+/// which Obsidian expects encoded in the `file` parameter.
+const COMPONENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// An `obsidian://open` link to a note, or `None` if no vault name is
+/// configured. The note's path is taken from its metadata if it has the
+/// configured path field, and is otherwise its id, which is the vault
+/// path for notes indexed by the plugin or the vault watcher.
+pub fn open_uri(config: &ObsidianConfig, id: &str, metadata: Option<&Value>) -> Option<String> {
+    let vault = config.vault.as_deref()?;
+    let path = metadata
+        .and_then(|metadata| metadata.get(&config.path_field))
+        .and_then(Value::as_str)
+        .unwrap_or(id);
+    Some(format!(
+        "obsidian://open?vault={}&file={}",
+        utf8_percent_encode(vault, COMPONENT),
+        utf8_percent_encode(path, COMPONENT)
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_open_uri() {
+        let config = ObsidianConfig {
+            vault: Some("My Vault".to_string()),
+            ..ObsidianConfig::default()
+        };
+        assert_eq!(
+            open_uri(&config, "Projects/Triads & Tetrads.md", None).unwrap(),
+            "obsidian://open?vault=My%20Vault&file=Projects%2FTriads%20%26%20Tetrads.md"
+        );
+        let metadata = json!({ "path": "Notes/Real.md" });
+        assert_eq!(
+            open_uri(&config, "note-1", Some(&metadata)).unwrap(),
+            "obsidian://open?vault=My%20Vault&file=Notes%2FReal.md"
+        );
+        assert_eq!(open_uri(&ObsidianConfig::default(), "a.md", None), None);
+    }
+}
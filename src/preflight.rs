@@ -0,0 +1,299 @@
+//! Startup checks of the configuration and of what it points at: files,
+//! ports, and the collections already on disk. Every problem found is
+//! reported at once, each with a suggested fix, instead of the server
+//! stopping at the first one somewhere deep in loading the model.
+
+use anyhow::Result;
+use std::fmt;
+use std::fs;
+use std::net::TcpListener;
+use std::path::Path;
+use tracing::warn;
+
+use crate::config::Config;
+use crate::index::Collections;
+use crate::models::{self, ModelRegistry, VariantRegistry};
+use crate::tls;
+
+/// One thing wrong, and what to do about it.
+#[derive(Debug)]
+pub struct Problem {
+    /// The setting at fault, as it was given, when it comes down to one
+    pub setting: Option<String>,
+    pub message: String,
+    pub fix: String,
+}
+
+/// Problems found so far, reported together.
+#[derive(Debug, Default)]
+pub struct Problems(Vec<Problem>);
+
+impl Problems {
+    /// Record a problem with `setting`.
+    pub fn add(&mut self, setting: &str, message: impl Into<String>, fix: impl Into<String>) {
+        self.0.push(Problem {
+            setting: Some(setting.to_string()),
+            message: message.into(),
+            fix: fix.into(),
+        });
+    }
+
+    /// Record a problem that isn't down to one setting.
+    pub fn add_general(&mut self, message: impl Into<String>, fix: impl Into<String>) {
+        self.0.push(Problem {
+            setting: None,
+            message: message.into(),
+            fix: fix.into(),
+        });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn problems(&self) -> &[Problem] {
+        &self.0
+    }
+
+    /// Fail with every problem if there are any.
+    pub fn into_result(self) -> Result<()> {
+        if self.is_empty() {
+            return Ok(());
+        }
+        anyhow::bail!("{}", self)
+    }
+}
+
+impl fmt::Display for Problems {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let count = self.0.len();
+        write!(
+            f,
+            "Found {} problem{} with the configuration:",
+            count,
+            if count == 1 { "" } else { "s" }
+        )?;
+        for (i, problem) in self.0.iter().enumerate() {
+            write!(f, "\n\n  {}. ", i + 1)?;
+            if let Some(setting) = &problem.setting {
+                write!(f, "{}: ", setting)?;
+            }
+            write!(f, "{}\n     Fix: {}", problem.message, problem.fix)?;
+        }
+        Ok(())
+    }
+}
+
+/// Check that what `config` points at is usable: model, tokenizer, and
+/// vault paths exist, the model cache is writable, the TLS files parse,
+/// and, when `serving`, the ports are free to listen on.
+pub fn check_environment(config: &Config, serving: bool) -> Problems {
+    let mut problems = Problems::default();
+
+    let model = &config.model;
+    if let Some(path) = &model.path {
+        check_file(&mut problems, &config.setting("MODEL_PATH"), path);
+        if model.tokenizer_path.is_none() {
+            let tokenizer = path.with_file_name("tokenizer.json");
+            if !tokenizer.is_file() {
+                problems.add(
+                    &config.setting("TOKENIZER_PATH"),
+                    format!("No tokenizer.json next to the model at {:?}", tokenizer),
+                    "Export the tokenizer next to the model, or set \
+                     SYSTEMATICS_TOKENIZER_PATH to where it is",
+                );
+            }
+        }
+    } else if let Err(e) = writable(&model.cache_dir) {
+        problems.add(
+            &config.setting("CACHE_DIR"),
+            format!(
+                "Models are downloaded into {:?}, which isn't writable: {}",
+                model.cache_dir, e
+            ),
+            "Point SYSTEMATICS_CACHE_DIR at a writable directory, or load a local model \
+             with SYSTEMATICS_MODEL_PATH",
+        );
+    }
+    if let Some(path) = &model.tokenizer_path {
+        check_file(&mut problems, &config.setting("TOKENIZER_PATH"), path);
+    }
+    for (name, source) in &config.models {
+        if source.ends_with(".onnx") {
+            check_file(
+                &mut problems,
+                &format!("{} ({})", config.setting("MODELS"), name),
+                Path::new(source),
+            );
+        }
+    }
+    if let Some(path) = &config.reranker.path {
+        check_file(&mut problems, &config.setting("RERANK_MODEL_PATH"), path);
+    }
+    if let Some(path) = &config.vault.path {
+        if !path.is_dir() {
+            problems.add(
+                &config.setting("VAULT_PATH"),
+                format!("The vault {:?} isn't a directory", path),
+                "Point it at the vault's root folder, the one holding .obsidian",
+            );
+        }
+    }
+
+    if config.tls.is_enabled() {
+        if let Err(e) = tls::server_config(&config.tls) {
+            problems.add(
+                &config.setting("TLS_CERT"),
+                format!("{:#}", e),
+                "Check the certificate, key, and client CA are PEM files that belong \
+                 together, e.g. with `openssl x509 -in cert.pem -noout -text`",
+            );
+        }
+    }
+
+    if serving {
+        let mut ports = vec![("PORT", config.port)];
+        ports.extend(config.grpc_port.map(|port| ("GRPC_PORT", port)));
+        ports.extend(
+            config
+                .tls
+                .redirect_port
+                .map(|port| ("TLS_REDIRECT_PORT", port)),
+        );
+        for (key, port) in ports {
+            if let Err(e) = TcpListener::bind((config.host.as_str(), port)) {
+                problems.add(
+                    &config.setting(key),
+                    format!("Can't listen on {}:{}: {}", config.host, port, e),
+                    if e.kind() == std::io::ErrorKind::AddrInUse {
+                        "Stop whatever is using the port, or pick another one"
+                    } else {
+                        "Check SYSTEMATICS_HOST is an address of this machine, \
+                         such as 127.0.0.1 or 0.0.0.0"
+                    },
+                );
+            }
+        }
+    }
+
+    problems
+}
+
+/// Check every collection on disk can be served by the model it is bound
+/// to, with vectors of that model's length. Returns the problems that stop
+/// the server, then those it only warns about.
+///
+/// Vectors of another length fail every search and write to the
+/// collection, so they are an error. A collection bound to a model that
+/// isn't served only warns: the rest keep working, and moving it to a
+/// served model with a reindex needs the server running.
+pub async fn check_collections(
+    collections: &Collections,
+    models: &ModelRegistry,
+    variants: &VariantRegistry,
+) -> (Problems, Problems) {
+    let mut errors = Problems::default();
+    let mut warnings = Problems::default();
+    for (name, index) in collections.all() {
+        let Ok(settings) = collections.settings(Some(&name)).await else {
+            continue;
+        };
+        let Some(service) = models::resolve(models, variants, settings.model.as_deref()) else {
+            let model = settings.model.unwrap_or_default();
+            warnings.add_general(
+                format!(
+                    "Collection {} is bound to the model {:?}, which isn't loaded",
+                    name, model
+                ),
+                format!(
+                    "Serve {:?} again through SYSTEMATICS_MODELS or as a variant, or move \
+                     the collection to a served model with POST /admin/reindex",
+                    model
+                ),
+            );
+            continue;
+        };
+        if let Some(stored) = index.dimensions() {
+            if stored != service.dimensions() {
+                errors.add_general(
+                    format!(
+                        "Collection {} holds {}-dimensional vectors, but its model makes \
+                         {}-dimensional ones",
+                        name,
+                        stored,
+                        service.dimensions()
+                    ),
+                    format!(
+                        "Start with the model it was indexed with and move it to the new \
+                         one with POST /admin/reindex, or delete {:?} to index it afresh",
+                        collections.dir(&name)
+                    ),
+                );
+            }
+        }
+    }
+    (errors, warnings)
+}
+
+/// Log each problem as a warning.
+pub fn warn_all(problems: &Problems) {
+    for problem in problems.problems() {
+        warn!("{} Fix: {}", problem.message, problem.fix);
+    }
+}
+
+fn check_file(problems: &mut Problems, setting: &str, path: &Path) {
+    if !path.is_file() {
+        problems.add(
+            setting,
+            format!("{:?} doesn't exist or isn't a file", path),
+            "Fix the path, relative to the directory the server starts in",
+        );
+    }
+}
+
+/// Whether files can be created in `dir`, creating it if need be.
+fn writable(dir: &Path) -> std::io::Result<()> {
+    fs::create_dir_all(dir)?;
+    let probe = dir.join(format!(".write-check-{}", std::process::id()));
+    fs::write(&probe, b"")?;
+    fs::remove_file(&probe)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_environment_problems_are_reported_together() {
+        let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut config = Config::default();
+        config.host = "127.0.0.1".to_string();
+        config.port = taken.local_addr().unwrap().port();
+        config.model.path = Some(PathBuf::from("missing/model.onnx"));
+        config.vault.path = Some(PathBuf::from("missing/vault"));
+
+        let problems = check_environment(&config, true);
+        let settings: Vec<_> = problems
+            .problems()
+            .iter()
+            .map(|problem| problem.setting.as_deref().unwrap())
+            .collect();
+        assert_eq!(
+            settings,
+            [
+                "SYSTEMATICS_MODEL_PATH",
+                "SYSTEMATICS_TOKENIZER_PATH",
+                "SYSTEMATICS_VAULT_PATH",
+                "SYSTEMATICS_PORT"
+            ]
+        );
+        let report = problems.to_string();
+        assert!(report.starts_with("Found 4 problems with the configuration:"));
+        assert!(report.contains("\n\n  4. SYSTEMATICS_PORT: Can't listen on"));
+
+        // Nothing is bound when only checking files
+        assert_eq!(check_environment(&config, false).problems().len(), 3);
+    }
+}
@@ -0,0 +1,119 @@
+/// Power iterations per component. The UI only needs a stable picture, not
+/// exact components, and this converges well before then for embeddings.
+const ITERATIONS: usize = 50;
+
+/// Project `vectors` onto their first two principal components, giving a
+/// 2D map in which similar vectors land near each other. Found by power
+/// iteration on the centered data, so the covariance matrix is never
+/// formed. Deterministic: the same vectors always give the same map.
+pub fn project_2d(vectors: &[Vec<f32>]) -> Vec<[f32; 2]> {
+    let Some(dimensions) = vectors.first().map(Vec::len) else {
+        return Vec::new();
+    };
+
+    let mut mean = vec![0.0f64; dimensions];
+    for vector in vectors {
+        for (m, &x) in mean.iter_mut().zip(vector) {
+            *m += x as f64;
+        }
+    }
+    for m in &mut mean {
+        *m /= vectors.len() as f64;
+    }
+    let centered: Vec<Vec<f64>> = vectors
+        .iter()
+        .map(|vector| {
+            vector
+                .iter()
+                .zip(&mean)
+                .map(|(&x, m)| x as f64 - m)
+                .collect()
+        })
+        .collect();
+
+    let first = principal_component(&centered, dimensions, None);
+    let second = principal_component(&centered, dimensions, Some(&first));
+    centered
+        .iter()
+        .map(|row| [dot(row, &first) as f32, dot(row, &second) as f32])
+        .collect()
+}
+
+/// The direction of greatest variance, orthogonal to `exclude` if given.
+fn principal_component(rows: &[Vec<f64>], dimensions: usize, exclude: Option<&[f64]>) -> Vec<f64> {
+    // A fixed, uneven start that is unlikely to be orthogonal to the answer
+    let mut v: Vec<f64> = (0..dimensions)
+        .map(|i| 1.0 + (i % 7) as f64 / 7.0)
+        .collect();
+    for _ in 0..ITERATIONS {
+        if let Some(exclude) = exclude {
+            orthogonalize(&mut v, exclude);
+        }
+        if !normalize(&mut v) {
+            break;
+        }
+        // v <- Xᵀ(Xv), the covariance applied to v up to scale
+        let mut next = vec![0.0; dimensions];
+        for row in rows {
+            let projection = dot(row, &v);
+            for (n, &x) in next.iter_mut().zip(row) {
+                *n += projection * x;
+            }
+        }
+        v = next;
+    }
+    if let Some(exclude) = exclude {
+        orthogonalize(&mut v, exclude);
+    }
+    normalize(&mut v);
+    v
+}
+
+fn dot(a: &[f64], b: &[f64]) -> f64 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+fn orthogonalize(v: &mut [f64], unit: &[f64]) {
+    let projection = dot(v, unit);
+    for (x, u) in v.iter_mut().zip(unit) {
+        *x -= projection * u;
+    }
+}
+
+/// Scale `v` to unit length. False if it has none, e.g. when every vector
+/// is the same and there is no variance to find.
+fn normalize(v: &mut [f64]) -> bool {
+    let norm = dot(v, v).sqrt();
+    if norm < 1e-12 {
+        v.iter_mut().for_each(|x| *x = 0.0);
+        return false;
+    }
+    v.iter_mut().for_each(|x| *x /= norm);
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_projection_separates_clusters() {
+        // Two tight clusters far apart along one axis, spread along another
+        let mut vectors = Vec::new();
+        for i in 0..20 {
+            let jitter = (i % 5) as f32 * 0.01;
+            vectors.push(vec![1.0 + jitter, 0.1 * i as f32, 0.0]);
+            vectors.push(vec![-1.0 - jitter, 0.1 * i as f32, 0.0]);
+        }
+        let points = project_2d(&vectors);
+
+        assert_eq!(points.len(), vectors.len());
+        // The first component splits the clusters
+        for pair in points.chunks(2) {
+            assert!(pair[0][0].signum() != pair[1][0].signum());
+            assert!((pair[0][0] - pair[1][0]).abs() > 1.5);
+        }
+        assert_eq!(project_2d(&vectors), points);
+        assert!(project_2d(&[]).is_empty());
+    }
+}
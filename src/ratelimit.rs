@@ -0,0 +1,188 @@
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{header, HeaderValue},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+use crate::config::RateLimitConfig;
+use crate::error::AppError;
+
+/// Clients tracked at once. Past this the least recently seen is
+/// forgotten, bounding memory when many addresses come and go.
+const MAX_BUCKETS: usize = 10_000;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    /// When the client was last seen, in [`Buckets::tick`]s
+    tick: u64,
+}
+
+/// Buckets by client, least recently seen first in `by_tick`.
+#[derive(Default)]
+struct Buckets {
+    buckets: HashMap<String, Bucket>,
+    by_tick: BTreeMap<u64, String>,
+    tick: u64,
+}
+
+/// A token bucket per client address: each request takes a token, and
+/// tokens come back at the configured rate up to the burst size.
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            config,
+            buckets: Mutex::new(Buckets::default()),
+        }
+    }
+
+    /// Take a token from the bucket of the client at `peer`, or say how
+    /// long until there is one.
+    pub fn check(&self, peer: Option<IpAddr>) -> Result<(), Duration> {
+        let client = peer.map_or_else(|| "unknown".to_string(), |ip| ip.to_canonical().to_string());
+        self.acquire(&client, Instant::now()).inspect_err(|wait| {
+            debug!("Rate limited {} for {:.1}s", client, wait.as_secs_f64());
+        })
+    }
+
+    /// Take a token from `client`'s bucket, or say how long until there
+    /// is one.
+    fn acquire(&self, client: &str, now: Instant) -> Result<(), Duration> {
+        let burst = self.config.burst as f64;
+        let per_second = self.config.per_second;
+
+        let mut state = self.buckets.lock().unwrap();
+        let Buckets {
+            buckets,
+            by_tick,
+            tick,
+        } = &mut *state;
+        *tick += 1;
+        let bucket = match buckets.get_mut(client) {
+            Some(bucket) => {
+                by_tick.remove(&bucket.tick);
+                bucket
+            }
+            None => {
+                if buckets.len() >= MAX_BUCKETS {
+                    if let Some((_, oldest)) = by_tick.pop_first() {
+                        buckets.remove(&oldest);
+                    }
+                }
+                buckets.entry(client.to_string()).or_insert(Bucket {
+                    tokens: burst,
+                    updated: now,
+                    tick: 0,
+                })
+            }
+        };
+        bucket.tick = *tick;
+        by_tick.insert(*tick, client.to_string());
+
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * per_second).min(burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / per_second))
+        }
+    }
+}
+
+/// Whole seconds to tell a client to wait, never 0.
+pub fn retry_after(wait: Duration) -> u64 {
+    wait.as_secs_f64().ceil().max(1.0) as u64
+}
+
+/// Middleware turning away clients that have used up their requests with
+/// `429 Too Many Requests` and a `Retry-After` header.
+pub async fn limit(
+    State(limiter): State<Arc<RateLimiter>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let peer = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+    if let Err(wait) = limiter.check(peer) {
+        let retry_after = retry_after(wait);
+        let mut response =
+            AppError::TooManyRequests(format!("Too many requests; retry in {} s", retry_after))
+                .into_response();
+        response
+            .headers_mut()
+            .insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+        return response;
+    }
+
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket_refills_at_the_configured_rate() {
+        let limiter = RateLimiter::new(RateLimitConfig {
+            per_second: 2.0,
+            burst: 3,
+        });
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            assert!(limiter.acquire("a", start).is_ok());
+        }
+        let wait = limiter.acquire("a", start).unwrap_err();
+        assert_eq!(wait, Duration::from_millis(500));
+        // Other clients have buckets of their own
+        assert!(limiter.acquire("b", start).is_ok());
+
+        assert!(limiter
+            .acquire("a", start + Duration::from_millis(500))
+            .is_ok());
+        assert!(limiter
+            .acquire("a", start + Duration::from_millis(500))
+            .is_err());
+        // Idle time refills no further than the burst
+        let later = start + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert!(limiter.acquire("a", later).is_ok());
+        }
+        assert!(limiter.acquire("a", later).is_err());
+    }
+
+    #[test]
+    fn test_least_recently_seen_client_is_forgotten() {
+        let limiter = RateLimiter::new(RateLimitConfig {
+            per_second: 1.0,
+            burst: 1,
+        });
+        let now = Instant::now();
+        assert!(limiter.acquire("first", now).is_ok());
+        for i in 0..MAX_BUCKETS {
+            let _ = limiter.acquire(&format!("client-{}", i), now);
+        }
+        assert!(limiter.buckets.lock().unwrap().buckets.len() <= MAX_BUCKETS);
+        // Forgotten, so it starts again with a full bucket
+        assert!(limiter.acquire("first", now).is_ok());
+        // Still tracked, so still empty
+        assert!(limiter
+            .acquire(&format!("client-{}", MAX_BUCKETS - 1), now)
+            .is_err());
+    }
+}
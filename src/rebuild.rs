@@ -0,0 +1,264 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use tokio::sync::mpsc::UnboundedSender;
+use tracing::{info, warn};
+
+use crate::chunking::{self, Document, DocumentEmbedding};
+use crate::config::Config;
+use crate::embedding::EmbeddingService;
+use crate::index::{Collections, IndexedDocument, VectorIndex};
+use crate::jobs::Job;
+use crate::metadata;
+
+/// One line of the progress streamed by `/admin/rebuild`.
+#[derive(Serialize, Debug)]
+#[serde(tag = "event", rename_all = "lowercase")]
+pub enum RebuildEvent {
+    Started {
+        collection: String,
+        documents: usize,
+    },
+    Progress {
+        collection: String,
+        done: usize,
+        total: usize,
+    },
+    Finished {
+        collection: String,
+        documents: usize,
+        rebuilt: usize,
+        /// Deleted or re-indexed while the rebuild ran, so already
+        /// embedded by the current model
+        skipped: usize,
+        /// Ids whose text the model couldn't embed
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        failed: Vec<String>,
+        /// Integrity issues found by verifying the collection afterwards
+        issues: usize,
+        /// Every document was accounted for and verification found nothing
+        verified: bool,
+    },
+    Error {
+        collection: String,
+        error: String,
+    },
+}
+
+/// A model to bind a collection to, re-embedding its documents with it.
+pub struct Rebind<'a> {
+    pub collections: &'a Collections,
+    /// Served model to bind it to, `None` for the base model
+    pub model: Option<String>,
+}
+
+/// How a rebuild runs, beyond what it rebuilds.
+#[derive(Default)]
+pub struct RebuildOptions<'a> {
+    /// The collection's embedding template
+    pub template: Option<&'a str>,
+    pub rebind: Option<Rebind<'a>>,
+    /// The job running the rebuild, which stops between batches if the job
+    /// is cancelled
+    pub job: Option<&'a Job>,
+}
+
+/// Re-embed every document of a collection from its stored text and
+/// metadata with the current model and embedding template, then rebuild
+/// its search indexes with the current parameters and verify the result.
+/// Progress goes to `events`; a client that stops listening doesn't stop
+/// the rebuild.
+///
+/// With `rebind`, `service` is the model to switch the collection to. The
+/// old vectors keep serving searches until every document is embedded by
+/// the new model; then the collection is bound to it and the vectors are
+/// swapped together.
+pub async fn rebuild(
+    service: &EmbeddingService,
+    name: &str,
+    index: &VectorIndex,
+    options: RebuildOptions<'_>,
+    config: &Config,
+    events: &UnboundedSender<RebuildEvent>,
+) {
+    if let Err(e) = run(service, name, index, options, config, events).await {
+        warn!("Rebuilding collection {} failed: {}", name, e);
+        let _ = events.send(RebuildEvent::Error {
+            collection: name.to_string(),
+            error: e.to_string(),
+        });
+    }
+}
+
+async fn run(
+    service: &EmbeddingService,
+    name: &str,
+    index: &VectorIndex,
+    options: RebuildOptions<'_>,
+    config: &Config,
+    events: &UnboundedSender<RebuildEvent>,
+) -> anyhow::Result<()> {
+    let RebuildOptions {
+        template,
+        rebind,
+        job,
+    } = options;
+    let documents = index.list().await?;
+    let total = documents.len();
+    info!("Rebuilding {} documents in collection {}", total, name);
+    let _ = events.send(RebuildEvent::Started {
+        collection: name.to_string(),
+        documents: total,
+    });
+
+    let mut rebuilt = 0;
+    let mut failed = Vec::new();
+    let mut done = 0;
+    // Vectors from the model being switched to wait until all are ready
+    let mut pending = Vec::new();
+    let mut cancelled = false;
+    for batch in documents.chunks(config.max_batch_size.max(1)) {
+        if job.is_some_and(Job::is_cancelled) {
+            cancelled = true;
+            break;
+        }
+        let replacements = embed(service, batch, template, config, &mut failed).await;
+        if rebind.is_some() {
+            pending.extend(replacements);
+        } else {
+            rebuilt += index.replace_vectors(replacements).await?;
+        }
+        done += batch.len();
+        let _ = events.send(RebuildEvent::Progress {
+            collection: name.to_string(),
+            done,
+            total,
+        });
+    }
+
+    if cancelled {
+        // Vectors replaced so far are kept, so the graph must catch up
+        if rebind.is_none() {
+            index.finish_rebuild().await?;
+        }
+        anyhow::bail!("Cancelled after {} of {} documents", done, total);
+    }
+
+    if let Some(rebind) = rebind {
+        // Switching would leave those documents with the old model's vectors
+        if let Some(id) = failed.first() {
+            anyhow::bail!(
+                "The new model failed on {} of the collection's documents, such as {}, \
+                 so it keeps its model",
+                failed.len(),
+                id
+            );
+        }
+        // With writers kept out, none is still storing the old model's
+        // vectors and none can start until the switch is made
+        let _switching = index.switching_model().await;
+        let read: HashMap<String, u64> = documents
+            .iter()
+            .map(|doc| (doc.id.clone(), doc.version))
+            .collect();
+
+        // Documents written while the job ran hold the old model's vectors,
+        // so they are embedded again before switching
+        let changed: Vec<IndexedDocument> = index
+            .list()
+            .await?
+            .into_iter()
+            .filter(|doc| read.get(&doc.id) != Some(&doc.version))
+            .collect();
+        for batch in changed.chunks(config.max_batch_size.max(1)) {
+            pending.extend(embed(service, batch, template, config, &mut failed).await);
+        }
+        if let Some(id) = failed.first() {
+            anyhow::bail!(
+                "The new model failed on {} documents written during the rebuild, such as {}, \
+                 so the collection keeps its model",
+                failed.len(),
+                id
+            );
+        }
+        // Vectors read before a document changed are skipped in favour of
+        // those embedded again
+        rebuilt += rebind
+            .collections
+            .rebind(name, rebind.model, pending)
+            .await?;
+    }
+
+    index.finish_rebuild().await?;
+    let report = index.verify(false).await?;
+    let skipped = total.saturating_sub(rebuilt + failed.len());
+    info!(
+        "Rebuilt {} of {} documents in collection {} ({} failed, {} integrity issues)",
+        rebuilt,
+        total,
+        name,
+        failed.len(),
+        report.issues.len()
+    );
+    let _ = events.send(RebuildEvent::Finished {
+        collection: name.to_string(),
+        documents: report.documents,
+        rebuilt,
+        skipped,
+        verified: failed.is_empty() && report.issues.is_empty(),
+        failed,
+        issues: report.issues.len(),
+    });
+    Ok(())
+}
+
+/// Embed a batch of stored documents, as `(id, version, embedding)`
+/// replacements, adding the ids of those the model fails on to `failed`.
+async fn embed(
+    service: &EmbeddingService,
+    batch: &[IndexedDocument],
+    template: Option<&str>,
+    config: &Config,
+    failed: &mut Vec<String>,
+) -> Vec<(String, u64, DocumentEmbedding)> {
+    let headers: Vec<Option<String>> = batch
+        .iter()
+        .map(|doc| {
+            template.and_then(|template| metadata::render_template(template, doc.metadata.as_ref()))
+        })
+        .collect();
+    let documents: Vec<Document> = batch
+        .iter()
+        .zip(&headers)
+        .map(|(doc, header)| (doc.text.as_str(), &[][..], header.as_deref()))
+        .collect();
+    let attempt = chunking::embed_sections(service, &documents, config.chunking).await;
+    let embedded = match attempt {
+        Ok(embedded) => embedded.into_iter().map(Some).collect(),
+        // Find the document that fails the batch, as bulk indexing does
+        Err(_) => {
+            let mut embedded = Vec::with_capacity(batch.len());
+            for document in &documents {
+                embedded.push(
+                    chunking::embed_sections(
+                        service,
+                        std::slice::from_ref(document),
+                        config.chunking,
+                    )
+                    .await
+                    .ok()
+                    .map(|mut embedded| embedded.remove(0)),
+                );
+            }
+            embedded
+        }
+    };
+
+    let mut replacements = Vec::with_capacity(batch.len());
+    for (doc, embedding) in batch.iter().zip(embedded) {
+        match embedding {
+            Some(embedding) => replacements.push((doc.id.clone(), doc.version, embedding)),
+            None => failed.push(doc.id.clone()),
+        }
+    }
+    replacements
+}
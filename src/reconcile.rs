@@ -0,0 +1,307 @@
+//! Reconciling a collection with the latest manifest of its vault: the
+//! list of files a client says exist. Notes indexed from files that are
+//! gone, e.g. deleted while the server was offline, are flagged or pruned.
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeSet, HashSet};
+use std::fs;
+use std::path::Path;
+
+use crate::index::VectorIndex;
+use crate::jobs::Job;
+
+/// Name of the manifest file in a collection's directory.
+const MANIFEST_FILE: &str = "manifest.json";
+
+/// Name of the file in a collection's directory listing the notes the
+/// reconciler flagged, so it only ever unflags those.
+const FLAGGED_FILE: &str = "flagged.json";
+
+/// Metadata field set on notes whose file is missing from the manifest.
+pub const STALE_FIELD: &str = "stale";
+
+/// The files of a vault, as last reported for a collection.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    pub received_at: DateTime<Utc>,
+    /// Vault paths, with `/` separators
+    pub files: Vec<String>,
+}
+
+pub fn write_manifest(dir: &Path, manifest: &Manifest) -> Result<()> {
+    write_json(&dir.join(MANIFEST_FILE), manifest)
+}
+
+fn write_json(path: &Path, value: &impl Serialize) -> Result<()> {
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, serde_json::to_vec(value)?)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+fn read_flagged(dir: &Path) -> Result<BTreeSet<String>> {
+    let path = dir.join(FLAGGED_FILE);
+    if !path.exists() {
+        return Ok(BTreeSet::new());
+    }
+    serde_json::from_slice(&fs::read(&path)?)
+        .with_context(|| format!("Unreadable list of flagged notes {:?}", path))
+}
+
+/// The manifest stored in a collection's directory `dir`, if one was sent.
+pub fn read_manifest(dir: &Path) -> Result<Option<Manifest>> {
+    let path = dir.join(MANIFEST_FILE);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let manifest = serde_json::from_slice(&fs::read(&path)?)
+        .with_context(|| format!("Unreadable manifest {:?}", path))?;
+    Ok(Some(manifest))
+}
+
+/// What to do with notes whose file is missing from the manifest.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StaleAction {
+    /// Set `"stale": true` in their metadata, so searches can exclude them
+    /// and they can be reviewed before deleting
+    #[default]
+    Flag,
+    Delete,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReconcileReport {
+    pub collection: String,
+    pub action: StaleAction,
+    /// When the manifest reconciled against was sent
+    pub manifest_received_at: DateTime<Utc>,
+    pub manifest_files: usize,
+    /// Notes compared against the manifest; documents that aren't vault
+    /// notes are left alone
+    pub checked: usize,
+    /// Ids of the notes whose file is missing
+    pub stale: Vec<String>,
+    /// Notes flagged stale earlier whose file is back
+    pub unflagged: Vec<String>,
+    /// Notes changed while being reconciled, left for the next run
+    pub changed: Vec<String>,
+}
+
+/// Flag or delete the notes of `index`, whose directory is `dir`, whose
+/// file isn't in `manifest`. A note's file is its metadata's `path_field`
+/// if set, otherwise its id; only `.md` files are notes. Each note is only
+/// changed if it is still at the version read, and only flags set here are
+/// ever removed. Stops between notes if `job` is cancelled.
+pub async fn reconcile(
+    collection: &str,
+    dir: &Path,
+    index: &VectorIndex,
+    manifest: &Manifest,
+    action: StaleAction,
+    path_field: &str,
+    job: Option<&Job>,
+) -> Result<ReconcileReport> {
+    let files: HashSet<&str> = manifest.files.iter().map(String::as_str).collect();
+    let documents = index.list().await?;
+    let mut flagged = read_flagged(dir)?;
+    {
+        // Forget notes deleted since they were flagged
+        let ids: HashSet<&str> = documents.iter().map(|doc| doc.id.as_str()).collect();
+        flagged.retain(|id| ids.contains(id.as_str()));
+    }
+    let mut notes: Vec<_> = documents
+        .into_iter()
+        .filter(|doc| is_note(source_path(&doc.id, doc.metadata.as_ref(), path_field)))
+        .collect();
+    notes.sort_by(|a, b| a.id.cmp(&b.id));
+
+    let mut report = ReconcileReport {
+        collection: collection.to_string(),
+        action,
+        manifest_received_at: manifest.received_at,
+        manifest_files: manifest.files.len(),
+        checked: notes.len(),
+        stale: Vec::new(),
+        unflagged: Vec::new(),
+        changed: Vec::new(),
+    };
+    let result = async {
+        for (i, doc) in notes.into_iter().enumerate() {
+            if let Some(job) = job {
+                if job.is_cancelled() {
+                    anyhow::bail!("Cancelled after {} of {} notes", i, report.checked);
+                }
+                job.progress(collection, i, report.checked);
+            }
+            let exists = files.contains(source_path(&doc.id, doc.metadata.as_ref(), path_field));
+            let mut metadata = doc.metadata;
+            let marked = metadata
+                .as_ref()
+                .and_then(|metadata| metadata.get(STALE_FIELD))
+                .is_some_and(|stale| stale == true);
+            if !marked {
+                // The flag was removed by someone else
+                flagged.remove(&doc.id);
+            }
+
+            match (exists, action) {
+                (true, _) if marked && flagged.contains(&doc.id) => {
+                    if let Some(Value::Object(fields)) = &mut metadata {
+                        fields.remove(STALE_FIELD);
+                    }
+                    if index.set_metadata(&doc.id, doc.version, metadata).await? {
+                        flagged.remove(&doc.id);
+                        report.unflagged.push(doc.id);
+                    } else {
+                        report.changed.push(doc.id);
+                    }
+                }
+                (true, _) => {}
+                (false, StaleAction::Flag) => {
+                    if !marked {
+                        match &mut metadata {
+                            Some(Value::Object(fields)) => {
+                                fields.insert(STALE_FIELD.to_string(), Value::Bool(true));
+                            }
+                            // Metadata that isn't an object has nowhere to
+                            // hold the flag
+                            Some(_) => {
+                                report.stale.push(doc.id);
+                                continue;
+                            }
+                            None => metadata = Some(serde_json::json!({ STALE_FIELD: true })),
+                        }
+                        if !index.set_metadata(&doc.id, doc.version, metadata).await? {
+                            report.changed.push(doc.id);
+                            continue;
+                        }
+                        flagged.insert(doc.id.clone());
+                    }
+                    report.stale.push(doc.id);
+                }
+                (false, StaleAction::Delete) => {
+                    if index.delete_version(&doc.id, doc.version).await? {
+                        flagged.remove(&doc.id);
+                        report.stale.push(doc.id);
+                    } else {
+                        report.changed.push(doc.id);
+                    }
+                }
+            }
+        }
+        Ok::<_, anyhow::Error>(())
+    }
+    .await;
+    // Kept even if stopped early, so flags already set can be removed
+    write_json(&dir.join(FLAGGED_FILE), &flagged)?;
+    result?;
+    if let Some(job) = job {
+        job.progress(collection, report.checked, report.checked);
+    }
+    Ok(report)
+}
+
+fn source_path<'a>(id: &'a str, metadata: Option<&'a Value>, path_field: &str) -> &'a str {
+    metadata
+        .and_then(|metadata| metadata.get(path_field))
+        .and_then(Value::as_str)
+        .unwrap_or(id)
+}
+
+fn is_note(path: &str) -> bool {
+    path.to_ascii_lowercase().ends_with(".md")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{HnswConfig, Precision, QuantizationConfig};
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_reconcile_flags_then_deletes_missing_notes() {
+        let dir =
+            std::env::temp_dir().join(format!("systematics-reconcile-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let index = VectorIndex::new(
+            Precision::F32,
+            QuantizationConfig::default(),
+            HnswConfig::default(),
+        );
+        let docs = [
+            ("Kept.md", None),
+            ("Gone.md", Some(json!({ "tags": ["triad"] }))),
+            ("moved", Some(json!({ "path": "Old/Moved.md" }))),
+            ("clipping-1", None),
+            ("Own.md", Some(json!({ "stale": true }))),
+        ];
+        for (id, metadata) in docs {
+            index
+                .add(id, vec![1.0, 0.0], id.to_string(), metadata)
+                .await
+                .unwrap();
+        }
+        let manifest = Manifest {
+            received_at: Utc::now(),
+            files: vec!["Kept.md".into(), "Moved.md".into()],
+        };
+
+        let report = reconcile(
+            "notes",
+            &dir,
+            &index,
+            &manifest,
+            StaleAction::Flag,
+            "path",
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.checked, 4);
+        assert_eq!(report.stale, ["Gone.md", "Own.md", "moved"]);
+        let gone = index.get("Gone.md").await.unwrap().unwrap();
+        assert_eq!(
+            gone.metadata,
+            Some(json!({ "tags": ["triad"], "stale": true }))
+        );
+        assert_eq!(gone.version, 2);
+
+        // A flagged note whose file is back is unflagged, but a note
+        // marked stale by its owner keeps its mark
+        let manifest = Manifest {
+            received_at: Utc::now(),
+            files: vec!["Kept.md".into(), "Gone.md".into(), "Own.md".into()],
+        };
+        let report = reconcile(
+            "notes",
+            &dir,
+            &index,
+            &manifest,
+            StaleAction::Delete,
+            "path",
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            (report.stale, report.unflagged),
+            (vec!["moved".to_string()], vec!["Gone.md".to_string()])
+        );
+        let gone = index.get("Gone.md").await.unwrap().unwrap();
+        assert_eq!(gone.metadata, Some(json!({ "tags": ["triad"] })));
+        assert!(index.get("moved").await.unwrap().is_none());
+        assert!(index.get("clipping-1").await.unwrap().is_some());
+        let own = index.get("Own.md").await.unwrap().unwrap();
+        assert_eq!(own.metadata, Some(json!({ "stale": true })));
+
+        // Changes are only made to the version read
+        assert!(!index.set_metadata("Kept.md", 2, None).await.unwrap());
+        assert!(!index.delete_version("Kept.md", 2).await.unwrap());
+        assert!(index.delete_version("Kept.md", 1).await.unwrap());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,178 @@
+use anyhow::{Context, Result};
+use ort::session::{builder::GraphOptimizationLevel, Session};
+use ort::value::Tensor;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use tokenizers::{EncodeInput, Tokenizer, TruncationParams, TruncationStrategy};
+use tracing::info;
+
+use crate::config::{ModelConfig, RerankerConfig};
+use crate::download;
+use crate::signature::TrustRoot;
+
+/// Pairs scored per forward pass, bounding the padded input size.
+const BATCH_SIZE: usize = 16;
+
+/// Most tokens of a query and passage read together, the limit of the
+/// BERT-sized cross-encoders rerankers are, unless the tokenizer's own is
+/// lower.
+const MAX_LENGTH: usize = 512;
+
+/// A cross-encoder: reads the query and a passage together and scores how
+/// well the passage answers the query. Much slower than comparing
+/// embeddings, and much better at nuance, so it only rescores the top
+/// candidates the vector search found.
+pub struct Reranker {
+    session: Mutex<Session>,
+    /// Cuts passages short to fit, keeping the whole query
+    tokenizer: Tokenizer,
+    /// Cuts whichever of the two is longer, for queries too long to keep
+    long_query_tokenizer: Tokenizer,
+    /// Whether the model takes `token_type_ids`, which BERT cross-encoders
+    /// use to tell the query from the passage
+    token_type_ids: bool,
+}
+
+impl Reranker {
+    /// Load the configured cross-encoder, downloading it on first use like
+    /// the embedding model.
+    pub async fn new(config: &RerankerConfig, model: &ModelConfig) -> Result<Self> {
+        let model_path = match (&config.path, &config.model) {
+            (Some(path), _) => path.clone(),
+            (None, Some(name)) => fetch(model, name, "onnx/model.onnx", "model.onnx").await?,
+            (None, None) => anyhow::bail!("No reranker model configured"),
+        };
+        let tokenizer_path = match (&config.tokenizer_path, &config.path, &config.model) {
+            (Some(path), _, _) => path.clone(),
+            (None, Some(path), _) => path.with_file_name("tokenizer.json"),
+            (None, None, Some(name)) => {
+                fetch(model, name, "tokenizer.json", "tokenizer.json").await?
+            }
+            (None, None, None) => unreachable!("checked above"),
+        };
+
+        info!("Loading reranker from {:?}", model_path);
+        let session = Session::builder()?
+            .with_optimization_level(GraphOptimizationLevel::Level3)?
+            .with_intra_threads(model.threads)?
+            .commit_from_file(&model_path)?;
+        let token_type_ids = session
+            .inputs
+            .iter()
+            .any(|input| input.name == "token_type_ids");
+        let mut tokenizer = Tokenizer::from_file(&tokenizer_path)
+            .map_err(|e| anyhow::anyhow!("Failed to load reranker tokenizer: {}", e))?;
+        tokenizer.with_padding(None);
+        let max_length = tokenizer
+            .get_truncation()
+            .map_or(MAX_LENGTH, |own| own.max_length.min(MAX_LENGTH));
+        let truncate = |strategy| {
+            let mut tokenizer = tokenizer.clone();
+            tokenizer
+                .with_truncation(Some(TruncationParams {
+                    max_length,
+                    strategy,
+                    ..TruncationParams::default()
+                }))
+                .map_err(|e| anyhow::anyhow!("Failed to configure reranker tokenizer: {}", e))?;
+            Ok::<_, anyhow::Error>(tokenizer)
+        };
+        let long_query_tokenizer = truncate(TruncationStrategy::LongestFirst)?;
+        let tokenizer = truncate(TruncationStrategy::OnlySecond)?;
+
+        Ok(Self {
+            session: Mutex::new(session),
+            tokenizer,
+            long_query_tokenizer,
+            token_type_ids,
+        })
+    }
+
+    /// Relevance of each passage to the query, between 0 and 1.
+    pub fn score(&self, query: &str, passages: &[&str]) -> Result<Vec<f32>> {
+        let mut scores = Vec::with_capacity(passages.len());
+        for batch in passages.chunks(BATCH_SIZE) {
+            let pairs: Vec<EncodeInput> = batch
+                .iter()
+                .map(|&passage| (query, passage).into())
+                .collect();
+            // Only the passage is cut unless the query alone fills the
+            // window, when there is no passage left to cut
+            let encodings = self
+                .tokenizer
+                .encode_batch(pairs.clone(), true)
+                .or_else(|_| self.long_query_tokenizer.encode_batch(pairs, true))
+                .map_err(|e| anyhow::anyhow!("Failed to tokenize: {}", e))?;
+
+            let seq_len = encodings.iter().map(|e| e.len()).max().unwrap_or(0);
+            let shape = [encodings.len(), seq_len];
+            let mut ids = vec![0i64; encodings.len() * seq_len];
+            let mut mask = vec![0i64; ids.len()];
+            let mut types = vec![0i64; ids.len()];
+            for (b, encoding) in encodings.iter().enumerate() {
+                let offset = b * seq_len;
+                for (i, ((&id, &m), &t)) in encoding
+                    .get_ids()
+                    .iter()
+                    .zip(encoding.get_attention_mask())
+                    .zip(encoding.get_type_ids())
+                    .enumerate()
+                {
+                    ids[offset + i] = id as i64;
+                    mask[offset + i] = m as i64;
+                    types[offset + i] = t as i64;
+                }
+            }
+
+            let mut inputs = ort::inputs![
+                "input_ids" => Tensor::from_array((shape, ids))?,
+                "attention_mask" => Tensor::from_array((shape, mask))?,
+            ];
+            if self.token_type_ids {
+                inputs.push((
+                    "token_type_ids".into(),
+                    Tensor::from_array((shape, types))?.into(),
+                ));
+            }
+
+            let mut session = self
+                .session
+                .lock()
+                .map_err(|e| anyhow::anyhow!("Failed to lock reranker session: {}", e))?;
+            let outputs = session.run(inputs)?;
+            // Logits of shape [batch, labels]; the last label is relevance
+            let (shape, logits) = outputs[0].try_extract_tensor::<f32>()?;
+            let labels = shape.last().copied().unwrap_or(1).max(1) as usize;
+            scores.extend(logits.chunks(labels).map(|row| sigmoid(row[labels - 1])));
+        }
+        Ok(scores)
+    }
+}
+
+fn sigmoid(x: f32) -> f32 {
+    1.0 / (1.0 + (-x).exp())
+}
+
+/// Path to a file of the reranker model `name`, cached next to the
+/// embedding model's files.
+async fn fetch(model: &ModelConfig, name: &str, remote: &str, local: &str) -> Result<PathBuf> {
+    let trust = TrustRoot::new(&model.trusted_keys)?;
+    let cached = download::model_cache_dir(&model.cache_dir, name).join(local);
+    if cached.exists() {
+        trust.verify_cached(&cached)?;
+    } else {
+        info!(
+            "Downloading {} for reranker {} from HuggingFace...",
+            local, name
+        );
+        download::fetch_from_huggingface(
+            &download::huggingface_repo(name),
+            remote,
+            &cached,
+            &trust,
+        )
+        .await
+        .with_context(|| format!("Failed to download {} for {}", local, name))?;
+    }
+    Ok(cached)
+}
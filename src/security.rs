@@ -0,0 +1,126 @@
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{header, HeaderValue},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tracing::warn;
+
+use crate::config::Config;
+use crate::error::AppError;
+
+/// Headers added to every response in hardened mode. The API only serves
+/// data, so nothing it returns should be rendered, framed, or sniffed.
+const SECURITY_HEADERS: &[(header::HeaderName, &str)] = &[
+    (header::X_CONTENT_TYPE_OPTIONS, "nosniff"),
+    (header::X_FRAME_OPTIONS, "DENY"),
+    (header::REFERRER_POLICY, "no-referrer"),
+    (
+        header::CONTENT_SECURITY_POLICY,
+        "default-src 'none'; frame-ancestors 'none'",
+    ),
+    (header::CACHE_CONTROL, "no-store"),
+];
+
+/// Hardened-mode gate in front of every route: only loopback clients
+/// sending an allowed `Origin` get through, and every response carries
+/// the security headers.
+pub async fn harden(
+    State(config): State<Arc<Config>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if !peer.ip().to_canonical().is_loopback() {
+        warn!("Rejected request from non-loopback address {}", peer);
+        return with_security_headers(
+            AppError::Forbidden("Only local connections are accepted".to_string()).into_response(),
+        );
+    }
+
+    let origin = request
+        .headers()
+        .get(header::ORIGIN)
+        .and_then(|origin| origin.to_str().ok());
+    if !origin.is_some_and(|origin| config.allowed_origins.iter().any(|o| o == origin)) {
+        warn!("Rejected request with origin {:?}", origin);
+        return with_security_headers(
+            AppError::Forbidden(format!(
+                "Requests must come from an allowed origin ({})",
+                config.allowed_origins.join(", ")
+            ))
+            .into_response(),
+        );
+    }
+
+    with_security_headers(next.run(request).await)
+}
+
+fn with_security_headers(mut response: Response) -> Response {
+    let headers = response.headers_mut();
+    for (name, value) in SECURITY_HEADERS {
+        headers.insert(name.clone(), HeaderValue::from_static(value));
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        body::Body, extract::connect_info::MockConnectInfo, http::StatusCode, middleware,
+        routing::get, Router,
+    };
+    use tower::ServiceExt;
+
+    async fn status(peer: &str, origin: Option<&str>) -> (StatusCode, bool) {
+        let config = Arc::new(Config {
+            hardened: true,
+            allowed_origins: vec!["app://obsidian.md".to_string()],
+            ..Config::default()
+        });
+        let app = Router::new()
+            .route("/health", get(|| async { "ok" }))
+            .layer(middleware::from_fn_with_state(config, harden))
+            .layer(MockConnectInfo(peer.parse::<SocketAddr>().unwrap()));
+
+        let mut request = Request::builder().uri("/health");
+        if let Some(origin) = origin {
+            request = request.header(header::ORIGIN, origin);
+        }
+        let response = app
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let nosniff = response.headers().get(header::X_CONTENT_TYPE_OPTIONS)
+            == Some(&HeaderValue::from_static("nosniff"));
+        (response.status(), nosniff)
+    }
+
+    #[tokio::test]
+    async fn test_hardened_mode_requires_local_obsidian_requests() {
+        let obsidian = Some("app://obsidian.md");
+        assert_eq!(
+            status("127.0.0.1:5000", obsidian).await,
+            (StatusCode::OK, true)
+        );
+        assert_eq!(
+            status("[::ffff:127.0.0.1]:5000", obsidian).await,
+            (StatusCode::OK, true)
+        );
+        assert_eq!(
+            status("192.168.1.20:5000", obsidian).await,
+            (StatusCode::FORBIDDEN, true)
+        );
+        assert_eq!(
+            status("127.0.0.1:5000", Some("https://evil.example")).await,
+            (StatusCode::FORBIDDEN, true)
+        );
+        assert_eq!(
+            status("127.0.0.1:5000", None).await,
+            (StatusCode::FORBIDDEN, true)
+        );
+    }
+}
@@ -0,0 +1,136 @@
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+use tracing::warn;
+
+use crate::embedding::EmbeddingService;
+use crate::index::{SearchOptions, VectorIndex};
+
+/// Where the self-test index is kept in the data directory. It sits
+/// outside `collections/`, so it is never listed, searched, or backed up.
+pub const SELFTEST_DIR: &str = "selftest";
+
+/// Numbers canaries, so concurrent self-tests don't trip over each other.
+static CANARIES: AtomicU64 = AtomicU64::new(0);
+
+const CANARY_TEXT: &str =
+    "Systematics self-test canary: the triad of affirming, denying, and reconciling forces.";
+
+#[derive(Serialize, Debug)]
+pub struct StageReport {
+    pub stage: &'static str,
+    pub ok: bool,
+    pub latency_ms: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SelfTestReport {
+    /// Every stage ran and succeeded
+    pub ok: bool,
+    pub total_ms: f64,
+    /// Stages in the order they ran. A failed stage skips those that need
+    /// its result, but the canary is always deleted once indexed.
+    pub stages: Vec<StageReport>,
+}
+
+/// Run a canary document through the whole pipeline: embed it, index it
+/// into `index`, search for it, and delete it again, timing each stage.
+pub async fn run(service: &EmbeddingService, index: &VectorIndex) -> SelfTestReport {
+    let started = Instant::now();
+    let id = format!("canary-{}", CANARIES.fetch_add(1, Ordering::Relaxed));
+    let mut stages = Vec::new();
+
+    // Run by the model every time, since an embedding served from the
+    // cache would pass even with the model broken
+    let text = canary_text(&id);
+    let embedding = stage(&mut stages, "embed", service.embed_uncached(&text)).await;
+    if let Some(embedding) = embedding {
+        let indexed = stage(
+            &mut stages,
+            "index",
+            index.add(&id, embedding.clone(), text, None),
+        )
+        .await;
+        if indexed.is_some() {
+            stage(&mut stages, "search", async {
+                let results = index
+                    .search(&embedding, 1, &SearchOptions::default())
+                    .await?;
+                match results.first() {
+                    Some(result) if result.id == id => Ok(()),
+                    _ => anyhow::bail!("the canary wasn't the top result"),
+                }
+            })
+            .await;
+            // Clean up even when the search failed
+            stage(&mut stages, "delete", async {
+                if !index.delete(&id).await? {
+                    anyhow::bail!("the canary was already gone");
+                }
+                Ok(())
+            })
+            .await;
+        }
+    }
+
+    let ok = stages.iter().all(|stage| stage.ok) && stages.len() == 4;
+    if !ok {
+        warn!("Self-test failed: {:?}", stages);
+    }
+    SelfTestReport {
+        ok,
+        total_ms: started.elapsed().as_secs_f64() * 1000.0,
+        stages,
+    }
+}
+
+/// The text of canary `id`, different for every run.
+fn canary_text(id: &str) -> String {
+    format!("{} ({})", CANARY_TEXT, id)
+}
+
+/// Run one stage, recording how long it took and whether it succeeded.
+async fn stage<T>(
+    stages: &mut Vec<StageReport>,
+    name: &'static str,
+    future: impl std::future::Future<Output = anyhow::Result<T>>,
+) -> Option<T> {
+    let started = Instant::now();
+    let result = future.await;
+    let latency_ms = started.elapsed().as_secs_f64() * 1000.0;
+    match result {
+        Ok(value) => {
+            stages.push(StageReport {
+                stage: name,
+                ok: true,
+                latency_ms,
+                error: None,
+            });
+            Some(value)
+        }
+        Err(e) => {
+            stages.push(StageReport {
+                stage: name,
+                ok: false,
+                latency_ms,
+                error: Some(e.to_string()),
+            });
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_canary_text_differs_per_run() {
+        let first = canary_text("canary-0");
+        let second = canary_text("canary-1");
+        assert_ne!(first, second);
+        assert!(first.starts_with(CANARY_TEXT));
+    }
+}
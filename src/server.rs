@@ -0,0 +1,3822 @@
+use axum::{
+    extract::{ConnectInfo, DefaultBodyLimit, FromRequest, Path, Query, Request, State},
+    http::{header, HeaderMap, Method, StatusCode},
+    middleware,
+    response::{IntoResponse, Response},
+    routing::{delete, get, patch, post, put},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use tracing::{info, warn};
+
+use crate::analytics::{AnalyticsEvent, AnalyticsLog, AnalyticsRecord, SearchEvent};
+use crate::backup::BackupInfo;
+use crate::bulk::{
+    BulkDocument, BulkIndexResponse, BulkIndexer, BulkQueue, BulkSource, QueueStatus, Spool,
+};
+use crate::bundle::Bundle;
+use crate::cache::CacheMetrics;
+#[cfg(feature = "chaos")]
+use crate::chaos;
+use crate::codec::{Body, Encoded, Format, NdjsonLines};
+use crate::concurrency::Limits;
+use crate::config::{
+    ChunkingConfig, Cli, Command, Config, ExecutionProvider, InferencePrecision, ModelConfig,
+    Precision, Quantization,
+};
+use crate::debug::{Capture, DebugCapture};
+use crate::embedding::{self, EmbeddingError, EmbeddingService, TokenizerMetrics};
+use crate::error::{AppError, ErrorCode};
+use crate::experiments::{ConfigChange, ConfigChangelog, RetrievalConfig};
+use crate::federation::{FederationRegistry, Peer};
+use crate::feedback::{FeedbackEvent, FeedbackLog, Triplet};
+use crate::filter::Filter;
+use crate::flags::{FeatureFlags, Flag, FlagRule, FlagUpdate};
+use crate::ids::{IdPolicy, IdRule};
+use crate::index::{
+    Boost, CollectionInfo, CollectionSettings, Collections, HybridConfig, IndexError, MemoryUsage,
+    SearchOptions, SearchResult, UpsertStatus, VectorIndex, VerifyReport, WarmReport,
+};
+use crate::jobs::{JobInfo, Jobs};
+use crate::lexical::AnalyzerSettings;
+use crate::lock::DataDirLock;
+use crate::models::{EvaluationStatus, ModelInfo, ModelRegistry, VariantInfo, VariantRegistry};
+use crate::ratelimit::RateLimiter;
+use crate::rebuild::{Rebind, RebuildEvent, RebuildOptions};
+use crate::reconcile::{Manifest, StaleAction};
+use crate::reranker::Reranker;
+use crate::selftest::SelfTestReport;
+use crate::signature::TrustRoot;
+use crate::snapshot::RestoreReport;
+use crate::stats::{RequestCounts, RequestStats};
+use crate::templates::{QueryTemplate, TemplateRegistry};
+use crate::vault::VaultWatcher;
+use crate::{
+    backup, bulk, bundle, chunking, cluster, codec, debug, dedupe, diversity, download, etag, grpc,
+    index, live, markdown, metadata, migrations, models, obsidian, preflight, projection,
+    ratelimit, rebuild, reconcile, security, selftest, signature, snapshot, stats, text, tls, ui,
+};
+
+#[derive(Clone)]
+pub(crate) struct AppState {
+    pub(crate) config: Arc<Config>,
+    pub(crate) models: Arc<ModelRegistry>,
+    pub(crate) collections: Arc<Collections>,
+    pub(crate) feedback_log: Arc<FeedbackLog>,
+    /// Set unless the analytics log is disabled
+    pub(crate) analytics: Option<Arc<AnalyticsLog>>,
+    pub(crate) model_variants: Arc<VariantRegistry>,
+    pub(crate) config_changelog: Arc<ConfigChangelog>,
+    pub(crate) federation: Arc<FederationRegistry>,
+    pub(crate) templates: Arc<TemplateRegistry>,
+    /// Takes turns between bulk uploads
+    pub(crate) bulk_queue: Arc<BulkQueue>,
+    /// Held while `/admin/rebuild` or `/admin/reindex` runs, so only one
+    /// runs at a time
+    pub(crate) rebuild_lock: Arc<tokio::sync::Mutex<()>>,
+    /// Background jobs, listed by `/jobs`
+    pub(crate) jobs: Arc<Jobs>,
+    /// Hidden index the self-test's canary documents go through
+    pub(crate) selftest_index: Arc<VectorIndex>,
+    /// Set when a reranker model is configured
+    pub(crate) reranker: Option<Arc<Reranker>>,
+    /// Set when debug capture is enabled
+    pub(crate) debug_capture: Option<Arc<DebugCapture>>,
+    pub(crate) flags: Arc<FeatureFlags>,
+    /// Uptime and requests served, for `/stats`
+    pub(crate) requests: Arc<RequestStats>,
+    /// Set when rate limiting is on, for HTTP and gRPC alike
+    pub(crate) rate_limiter: Option<Arc<RateLimiter>>,
+    /// Concurrency limits of the heavy endpoints, also taken by the gRPC
+    /// calls and live searches doing their work
+    pub(crate) limits: Limits,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct EmbedRequest {
+    pub(crate) text: String,
+    /// Registered model variant to embed with instead of the base model
+    pub(crate) model: Option<String>,
+    /// Reject the text if the model would only see part of it; defaults
+    /// to SYSTEMATICS_STRICT_TRUNCATION
+    pub(crate) strict: Option<bool>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct EmbedResponse {
+    pub(crate) embedding: Vec<f32>,
+    pub(crate) dimensions: usize,
+    /// Identifies the model that produced the vectors; cached vectors from
+    /// a different fingerprint are stale
+    pub(crate) model_fingerprint: String,
+    /// Tokens the text encodes to, special tokens included
+    pub(crate) token_count: usize,
+    /// The text was longer than the model's maximum length, so only its
+    /// start was embedded
+    pub(crate) truncated: bool,
+}
+
+#[derive(Deserialize)]
+struct EmbedBatchRequest {
+    texts: Vec<String>,
+    /// Registered model variant to embed with instead of the base model
+    model: Option<String>,
+}
+
+#[derive(Serialize)]
+struct EmbedBatchResponse {
+    /// One embedding per input text, in request order
+    embeddings: Vec<Vec<f32>>,
+    dimensions: usize,
+    /// As for a single embedding
+    model_fingerprint: String,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct SearchRequest {
+    pub(crate) query: String,
+    /// Collection to search; the default collection if unset
+    pub(crate) collection: Option<String>,
+    /// Search several collections at once instead, merging their results
+    #[serde(default)]
+    pub(crate) collections: Vec<String>,
+    /// Score weights by collection name for a multi-collection search,
+    /// overriding the collections' own
+    #[serde(default)]
+    pub(crate) weights: HashMap<String, f32>,
+    pub(crate) limit: Option<usize>,
+    /// Results to skip, for fetching pages after the first
+    #[serde(default)]
+    pub(crate) offset: usize,
+    /// Include a per-result breakdown of how the score was computed
+    #[serde(default)]
+    pub(crate) explain: bool,
+    /// Only return documents whose metadata passes this filter
+    pub(crate) filter: Option<Filter>,
+    /// Blend BM25 keyword scores with vector similarity
+    pub(crate) hybrid: Option<HybridConfig>,
+    /// Raise or lower the scores of results whose metadata matches
+    #[serde(default)]
+    pub(crate) boosts: Vec<Boost>,
+    /// Rescore the top candidates with the cross-encoder before returning
+    #[serde(default)]
+    pub(crate) rerank: bool,
+    /// Truncate result texts to this many characters; 0 returns full text.
+    /// Defaults to the server's configured length.
+    pub(crate) max_text_length: Option<usize>,
+    /// Reorder results by maximal marginal relevance, so near-duplicates
+    /// don't crowd out the rest
+    #[serde(default)]
+    pub(crate) mmr: bool,
+    /// Balance of relevance (1) against diversity (0) for `mmr`
+    pub(crate) lambda: Option<f32>,
+    /// Leave out results scoring below this instead of always filling
+    /// `limit`
+    pub(crate) min_score: Option<f32>,
+    /// Respond within this many milliseconds with the best results found
+    /// so far, flagged as partial
+    pub(crate) timeout_ms: Option<u64>,
+    /// Instruction to embed the query with instead of each model's own,
+    /// e.g. `query: {{text}}`; empty embeds it as given
+    pub(crate) query_instruction: Option<String>,
+    /// The client's address, which feature flags can be set for
+    #[serde(skip)]
+    pub(crate) client: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct SearchParams {
+    /// Also query registered federation peers
+    #[serde(default)]
+    federate: bool,
+}
+
+#[derive(Serialize)]
+pub(crate) struct SearchResponse {
+    pub(crate) results: Vec<SearchResult>,
+    /// Identifies the model the query was embedded with
+    pub(crate) model_fingerprint: String,
+    /// Documents in the searched collections that pass the filter, the
+    /// most results that paging could reach. Federated results aren't
+    /// counted.
+    pub(crate) total_candidates: usize,
+    /// Offset of the next page, if there may be one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) next_offset: Option<usize>,
+    /// Federation peers that failed or timed out
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub(crate) failed_sources: Vec<String>,
+    /// The search was cut short by its `timeout_ms`, so better results may
+    /// have been missed
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) partial: bool,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct IndexRequest {
+    /// Required unless the collection's id policy generates ids
+    pub(crate) id: Option<String>,
+    /// Collection to index into; the default collection if unset
+    pub(crate) collection: Option<String>,
+    pub(crate) text: String,
+    pub(crate) metadata: Option<serde_json::Value>,
+    /// Instruction the text is expected to be embedded with, e.g.
+    /// `passage: {{text}}`; refused unless it is the model's own
+    pub(crate) document_instruction: Option<String>,
+    /// The client's address, which feature flags can be set for
+    #[serde(skip)]
+    pub(crate) client: Option<String>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct IndexResponse {
+    pub(crate) success: bool,
+    pub(crate) id: String,
+}
+
+#[derive(Serialize)]
+pub(crate) struct UpsertResponse {
+    pub(crate) success: bool,
+    pub(crate) id: String,
+    pub(crate) status: UpsertStatus,
+    pub(crate) version: u64,
+}
+
+/// Query parameter selecting a collection for GET and DELETE routes.
+#[derive(Deserialize)]
+pub(crate) struct CollectionParams {
+    pub(crate) collection: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct SimilarParams {
+    collection: Option<String>,
+    limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct SimilarResponse {
+    /// The document the results are similar to, resolved from an alias
+    id: String,
+    results: Vec<SearchResult>,
+}
+
+#[derive(Deserialize)]
+struct CreateCollectionRequest {
+    name: String,
+    weight: Option<f32>,
+    /// Served model to embed the collection with instead of the base one
+    model: Option<String>,
+    /// How text is split into terms for keyword search
+    #[serde(default)]
+    analyzer: AnalyzerSettings,
+    /// How documents get their ids
+    #[serde(default)]
+    id_policy: IdPolicy,
+    /// Metadata fields embedded with each document's text
+    embed_template: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct MountRequest {
+    /// Where the bundle is published
+    url: String,
+    /// SHA-256 of the bundle, by default read from `<url>.sha256`
+    sha256: Option<String>,
+    /// Collection to mount it as, by default the bundle's file name
+    name: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct UpdateCollectionRequest {
+    weight: Option<f32>,
+    /// Only while the collection is empty; an empty string binds it back
+    /// to the base model
+    model: Option<String>,
+    /// Replaces the analyzer, re-indexing the collection for keyword search
+    analyzer: Option<AnalyzerSettings>,
+    /// Applies to documents indexed from now on; stored ids are kept
+    id_policy: Option<IdPolicy>,
+    /// Applies to documents indexed or rebuilt from now on; an empty
+    /// string embeds text alone again
+    embed_template: Option<String>,
+}
+
+#[derive(Serialize)]
+struct CollectionsResponse {
+    collections: Vec<CollectionInfo>,
+}
+
+#[derive(Serialize)]
+struct ProjectionResponse {
+    points: Vec<ProjectedPoint>,
+}
+
+#[derive(Serialize)]
+struct ProjectedPoint {
+    id: String,
+    x: f32,
+    y: f32,
+    /// The start of the document's text, for previews
+    preview: String,
+}
+
+#[derive(Deserialize)]
+struct ClusterRequest {
+    collection: Option<String>,
+    /// Clusters to group the documents into; chosen by silhouette score
+    /// if not given
+    k: Option<usize>,
+    /// Most clusters tried when choosing `k`
+    max_k: Option<usize>,
+    /// Documents closest to each centroid to return with snippets
+    representatives: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct ClusterResponse {
+    documents: usize,
+    k: usize,
+    /// Mean silhouette of the clustering, from -1 to 1: higher when
+    /// clusters are tight and well apart
+    silhouette: f32,
+    /// The largest cluster first
+    clusters: Vec<DocumentCluster>,
+}
+
+#[derive(Serialize)]
+struct DocumentCluster {
+    documents: usize,
+    /// Unit-length mean direction of the cluster's embeddings
+    centroid: Vec<f32>,
+    /// The documents closest to the centroid, the closest first
+    representatives: Vec<Representative>,
+    /// Every document in the cluster, the closest to the centroid first
+    ids: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct Representative {
+    id: String,
+    /// Cosine similarity to the centroid
+    score: f32,
+    snippet: String,
+}
+
+#[derive(Deserialize)]
+struct DedupeRequest {
+    collection: Option<String>,
+    /// Cosine similarity at or above which two documents are
+    /// near-duplicates
+    threshold: Option<f32>,
+}
+
+#[derive(Serialize)]
+struct DedupeResponse {
+    /// Documents scanned
+    documents: usize,
+    /// The largest cluster first
+    clusters: Vec<DuplicateCluster>,
+}
+
+#[derive(Serialize)]
+struct DuplicateCluster {
+    ids: Vec<String>,
+    /// The near-duplicate pairs that join the cluster, the most similar
+    /// first
+    pairs: Vec<DuplicatePair>,
+}
+
+#[derive(Serialize)]
+struct DuplicatePair {
+    a: String,
+    b: String,
+    score: f32,
+}
+
+#[derive(Deserialize)]
+struct DigestParams {
+    /// Only documents indexed or replaced at or after this time; a week
+    /// ago if not given
+    since: Option<chrono::DateTime<chrono::Utc>>,
+    collection: Option<String>,
+    /// How many themes to group them into, chosen from their number if
+    /// not given
+    clusters: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct DigestResponse {
+    since: chrono::DateTime<chrono::Utc>,
+    /// Documents indexed since then
+    documents: usize,
+    /// How many of them were clustered, the most recent first
+    clustered: usize,
+    /// The largest cluster first
+    clusters: Vec<DigestCluster>,
+}
+
+#[derive(Serialize)]
+struct DigestCluster {
+    documents: usize,
+    /// The documents most typical of the cluster, with snippets
+    highlights: Vec<DigestEntry>,
+    /// Every document in the cluster, the most typical first
+    ids: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct DigestEntry {
+    id: String,
+    snippet: String,
+}
+
+#[derive(Deserialize)]
+struct VerifyRequest {
+    /// Verify one collection instead of all of them
+    collection: Option<String>,
+    #[serde(default)]
+    repair: bool,
+}
+
+#[derive(Deserialize)]
+struct BackupRequest {
+    /// Only store what changed since the latest backup
+    #[serde(default)]
+    incremental: bool,
+}
+
+#[derive(Serialize)]
+struct BackupVerifyResponse {
+    /// Backups the restore applied, full backup first
+    chain: Vec<String>,
+    collections: Vec<RestoredCollectionInfo>,
+}
+
+#[derive(Serialize)]
+struct RestoredCollectionInfo {
+    name: String,
+    documents: usize,
+    aliases: usize,
+    settings: CollectionSettings,
+}
+
+#[derive(Deserialize)]
+struct RebuildRequest {
+    /// Rebuild one collection instead of all of them
+    collection: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ReindexRequest {
+    /// Re-embed one collection instead of all of them
+    collection: Option<String>,
+    /// Served model to switch the collections to; an empty string switches
+    /// them to the base model
+    model: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ManifestRequest {
+    collection: Option<String>,
+    /// Every file in the vault, by its path with `/` separators
+    files: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct ManifestResponse {
+    collection: String,
+    files: usize,
+    received_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Deserialize)]
+struct ReconcileRequest {
+    collection: Option<String>,
+    #[serde(default)]
+    action: StaleAction,
+}
+
+#[derive(Serialize)]
+struct JobsResponse {
+    /// The most recent first
+    jobs: Vec<JobInfo>,
+}
+
+#[derive(Serialize)]
+struct VerifyResponse {
+    healthy: bool,
+    collections: Vec<CollectionVerifyReport>,
+}
+
+#[derive(Serialize)]
+struct CollectionVerifyReport {
+    name: String,
+    #[serde(flatten)]
+    report: VerifyReport,
+}
+
+#[derive(Deserialize)]
+struct IndexEntryParams {
+    collection: Option<String>,
+    /// Include the stored embedding
+    #[serde(default)]
+    embedding: bool,
+}
+
+#[derive(Serialize)]
+struct IndexEntryResponse {
+    id: String,
+    text: String,
+    metadata: Option<serde_json::Value>,
+    version: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    updated_at: Option<chrono::DateTime<chrono::Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    embedding: Option<Vec<f32>>,
+}
+
+#[derive(Serialize)]
+struct FeedbackResponse {
+    success: bool,
+}
+
+#[derive(Deserialize)]
+struct RegisterVariantRequest {
+    name: String,
+    model_path: PathBuf,
+    tokenizer_path: Option<PathBuf>,
+    k: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct VariantsResponse {
+    variants: Vec<VariantInfo>,
+}
+
+#[derive(Serialize)]
+struct ConfigHistoryResponse {
+    active_hash: Option<String>,
+    changes: Vec<ConfigChange>,
+}
+
+#[derive(Serialize)]
+struct PeersResponse {
+    peers: Vec<Peer>,
+}
+
+#[derive(Serialize)]
+struct FlagsResponse {
+    flags: Vec<FlagInfo>,
+}
+
+#[derive(Serialize)]
+struct FlagInfo {
+    flag: Flag,
+    description: &'static str,
+    #[serde(flatten)]
+    rule: FlagRule,
+}
+
+#[derive(Serialize)]
+struct DebugRequestsResponse {
+    captures: Vec<Capture>,
+}
+
+#[derive(Serialize)]
+struct TemplatesResponse {
+    templates: Vec<QueryTemplate>,
+}
+
+#[derive(Serialize)]
+struct DocumentResponse {
+    id: String,
+    text: String,
+    metadata: Option<serde_json::Value>,
+}
+
+#[derive(Serialize)]
+struct DocumentsResponse {
+    documents: Vec<DocumentResponse>,
+}
+
+#[derive(Serialize)]
+struct Alias {
+    alias: String,
+    /// Canonical id the alias resolves to
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct AddAliasRequest {
+    collection: Option<String>,
+    alias: String,
+    id: String,
+}
+
+#[derive(Serialize)]
+struct AliasesResponse {
+    aliases: Vec<Alias>,
+}
+
+#[derive(Deserialize)]
+struct CountRequest {
+    collection: Option<String>,
+    filter: Option<Filter>,
+}
+
+#[derive(Serialize)]
+struct CountResponse {
+    count: usize,
+}
+
+#[derive(Deserialize)]
+struct GetDocumentsRequest {
+    collection: Option<String>,
+    ids: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct GetDocumentsResponse {
+    /// Found documents, in request order
+    documents: Vec<DocumentResponse>,
+    /// Requested ids that aren't indexed
+    missing: Vec<String>,
+}
+
+/// What this build and configuration supports, so clients can adapt.
+#[derive(Serialize)]
+struct CapabilitiesResponse {
+    version: &'static str,
+    features: Features,
+    /// Body formats accepted and returned
+    formats: Vec<&'static str>,
+    /// Base model followed by registered variants
+    models: Vec<String>,
+    precision: Precision,
+    inference_precision: InferencePrecision,
+    quantization: Quantization,
+    limits: Limits,
+}
+
+#[derive(Serialize)]
+struct Features {
+    hybrid_search: bool,
+    rerank: bool,
+    collections: bool,
+    federation: bool,
+    explain: bool,
+    metadata_filters: bool,
+    feedback: bool,
+    persistence: bool,
+    approximate_search: bool,
+    /// Searches can set the instruction queries are embedded with
+    instructions: bool,
+}
+
+#[derive(Serialize)]
+struct Limits {
+    max_request_tokens: usize,
+    max_batch_size: usize,
+}
+
+#[derive(Serialize)]
+struct StatsResponse {
+    started_at: chrono::DateTime<chrono::Utc>,
+    uptime_secs: u64,
+    /// Every model loaded, the base model included
+    models: Vec<ModelInfo>,
+    execution_provider: ExecutionProvider,
+    collections: Vec<CollectionStats>,
+    /// Estimated memory of every collection together
+    index_bytes: usize,
+    /// Memory the whole process holds in RAM, models included; Linux only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    resident_bytes: Option<u64>,
+    requests: RequestCounts,
+}
+
+#[derive(Serialize)]
+struct CollectionStats {
+    name: String,
+    documents: usize,
+    /// Length of the stored vectors; unset while the collection is empty
+    dimensions: Option<usize>,
+    /// Model the collection is embedded with
+    model: String,
+    memory: MemoryUsage,
+}
+
+#[derive(Serialize)]
+struct HealthResponse {
+    status: String,
+    model: String,
+    dimensions: usize,
+    model_fingerprint: String,
+    execution_provider: ExecutionProvider,
+    /// Every model served, the base model included
+    models: Vec<ModelInfo>,
+}
+
+// Handlers
+async fn health(format: Format, State(state): State<AppState>) -> Encoded<HealthResponse> {
+    format.encode(HealthResponse {
+        status: "ok".to_string(),
+        model: state.config.model.name.clone(),
+        dimensions: state.models.base().dimensions(),
+        model_fingerprint: state.models.base().fingerprint().to_string(),
+        execution_provider: state.models.base().execution_provider(),
+        models: state.models.list(),
+    })
+}
+
+/// What the server is running and has done since it started: each
+/// collection's size, dimensions, and model, memory, and requests served.
+async fn stats(format: Format, State(state): State<AppState>) -> Encoded<StatsResponse> {
+    let mut collections = Vec::new();
+    for (name, index) in state.collections.all() {
+        let settings = state
+            .collections
+            .settings(Some(&name))
+            .await
+            .unwrap_or_default();
+        collections.push(CollectionStats {
+            documents: index.count().await,
+            dimensions: index.dimensions(),
+            model: settings
+                .model
+                .unwrap_or_else(|| state.config.model.name.clone()),
+            memory: index.memory().await,
+            name,
+        });
+    }
+
+    format.encode(StatsResponse {
+        started_at: state.requests.started_at(),
+        uptime_secs: state.requests.uptime().as_secs(),
+        models: state.models.list(),
+        execution_provider: state.models.base().execution_provider(),
+        index_bytes: collections.iter().map(|c| c.memory.total).sum(),
+        collections,
+        resident_bytes: stats::resident_bytes(),
+        requests: state.requests.counts(),
+    })
+}
+
+/// Run a canary document through embedding, indexing, search, and
+/// deletion, so monitoring sees the whole pipeline work rather than just
+/// the process being up. Responds `503 Service Unavailable` if any stage
+/// fails.
+async fn health_selftest(
+    format: Format,
+    State(state): State<AppState>,
+) -> (StatusCode, Encoded<SelfTestReport>) {
+    let report = selftest::run(state.models.base(), &state.selftest_index).await;
+    let status = if report.ok {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, format.encode(report))
+}
+
+async fn capabilities(
+    format: Format,
+    State(state): State<AppState>,
+) -> Encoded<CapabilitiesResponse> {
+    let mut models: Vec<String> = state
+        .models
+        .list()
+        .into_iter()
+        .map(|model| model.name)
+        .collect();
+    models.extend(
+        state
+            .model_variants
+            .list()
+            .await
+            .into_iter()
+            .map(|variant| variant.name),
+    );
+
+    format.encode(CapabilitiesResponse {
+        version: env!("CARGO_PKG_VERSION"),
+        features: Features {
+            hybrid_search: true,
+            rerank: state.reranker.is_some(),
+            collections: true,
+            federation: true,
+            explain: true,
+            metadata_filters: true,
+            feedback: true,
+            persistence: true,
+            approximate_search: true,
+            instructions: true,
+        },
+        formats: vec!["json", "msgpack"],
+        models,
+        precision: state.config.model.precision,
+        inference_precision: state.config.model.inference_precision,
+        quantization: state.config.quantization.mode,
+        limits: Limits {
+            max_request_tokens: state.config.model.max_request_tokens,
+            max_batch_size: state.config.max_batch_size,
+        },
+    })
+}
+
+pub(crate) async fn embed(
+    format: Format,
+    State(state): State<AppState>,
+    Body(payload): Body<EmbedRequest>,
+) -> Result<Encoded<EmbedResponse>, AppError> {
+    let service = embedding_service(&state, payload.model.as_deref()).await?;
+    let count = service.count_tokens(&payload.text)?;
+    let strict = payload
+        .strict
+        .unwrap_or(state.config.model.strict_truncation);
+    if let Some(limit) = count.max_length.filter(|_| strict && count.truncated) {
+        return Err(anyhow::Error::from(EmbeddingError::Truncated {
+            tokens: count.tokens,
+            limit,
+        })
+        .into());
+    }
+    let embedding = service.embed(&payload.text).await?;
+
+    Ok(format.encode(EmbedResponse {
+        dimensions: embedding.len(),
+        embedding,
+        model_fingerprint: service.fingerprint().to_string(),
+        token_count: count.tokens,
+        truncated: count.truncated,
+    }))
+}
+
+/// Embed many texts in one request, batched through the model together.
+async fn embed_batch(
+    format: Format,
+    State(state): State<AppState>,
+    Body(payload): Body<EmbedBatchRequest>,
+) -> Result<Encoded<EmbedBatchResponse>, AppError> {
+    if payload.texts.len() > state.config.max_batch_size {
+        return Err(AppError::PayloadTooLarge(format!(
+            "Batch of {} texts exceeds the limit of {}",
+            payload.texts.len(),
+            state.config.max_batch_size
+        )));
+    }
+
+    let service = embedding_service(&state, payload.model.as_deref()).await?;
+    let texts: Vec<&str> = payload.texts.iter().map(String::as_str).collect();
+    let embeddings = service.embed_batch(&texts).await?;
+
+    Ok(format.encode(EmbedBatchResponse {
+        dimensions: embeddings.first().map_or(0, Vec::len),
+        embeddings,
+        model_fingerprint: service.fingerprint().to_string(),
+    }))
+}
+
+/// The base model, or the served model or registered variant `model`
+/// names.
+async fn embedding_service(
+    state: &AppState,
+    model: Option<&str>,
+) -> Result<Arc<EmbeddingService>, AppError> {
+    if let Some(service) = state.models.get(model) {
+        return Ok(service.clone());
+    }
+    let name = model.unwrap_or_default();
+    state.model_variants.get(name).ok_or_else(|| {
+        AppError::Coded(
+            ErrorCode::ModelNotFound,
+            format!("Model not found: {}", name),
+        )
+    })
+}
+
+/// The model a collection's documents and queries are embedded with.
+async fn collection_model(
+    state: &AppState,
+    collection: Option<&str>,
+) -> Result<Arc<EmbeddingService>, AppError> {
+    let settings = state.collections.settings(collection).await?;
+    bound_model(state, settings.model.as_deref())
+}
+
+fn bound_model(state: &AppState, model: Option<&str>) -> Result<Arc<EmbeddingService>, AppError> {
+    models::resolve(&state.models, &state.model_variants, model).ok_or_else(|| {
+        AppError::Coded(
+            ErrorCode::ModelNotLoaded,
+            format!(
+                "Model {} is not loaded; add it to SYSTEMATICS_MODELS or register it as a \
+                 variant",
+                model.unwrap_or_default()
+            ),
+        )
+    })
+}
+
+/// The address a request came from, which feature flags can be set for.
+/// Unlike an `X-Client-Id` header, a client can't pick another's address
+/// to opt into its flags.
+pub(crate) fn client_address(peer: Option<SocketAddr>) -> Option<String> {
+    peer.map(|peer| peer.ip().to_canonical().to_string())
+}
+
+/// Chunking settings for documents indexed into `collection` by `client`,
+/// with the experimental chunker if its flag is on for them.
+fn flagged_chunking(
+    state: &AppState,
+    collection: Option<&str>,
+    client: Option<&str>,
+) -> ChunkingConfig {
+    let collection = collection.unwrap_or(index::DEFAULT_COLLECTION);
+    ChunkingConfig {
+        sentence_boundaries: state
+            .flags
+            .is_enabled(Flag::SentenceChunks, collection, client),
+        ..state.config.chunking
+    }
+}
+
+pub(crate) async fn index_document(
+    format: Format,
+    State(state): State<AppState>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    Body(mut payload): Body<IndexRequest>,
+) -> Result<Encoded<UpsertResponse>, AppError> {
+    payload.client = payload
+        .client
+        .or_else(|| client_address(peer.map(|ConnectInfo(peer)| peer)));
+    let index = state.collections.get(payload.collection.as_deref()).await?;
+    let mut sections = Vec::new();
+    if state.config.markdown.enabled {
+        let prepared = markdown::prepare(
+            &payload.text,
+            payload.metadata.take(),
+            &state.config.markdown,
+        );
+        payload.text = prepared.text;
+        payload.metadata = prepared.metadata;
+        sections = prepared.sections;
+    }
+    let id = id_rule(&state, payload.collection.as_deref())
+        .await?
+        .resolve(payload.id.take(), &payload.text)?;
+    // Until the document is stored, the collection can't switch models
+    let _writing = index.writing().await;
+    let service = collection_model(&state, payload.collection.as_deref()).await?;
+    // Every vector of a collection is embedded with the same instruction,
+    // so rebuilds and later documents match it
+    if let Some(instruction) = &payload.document_instruction {
+        if instruction != service.document_instruction() {
+            return Err(AppError::BadRequest(format!(
+                "Documents in this collection are embedded with the document instruction {:?}; \
+                 set the model's instruction in the configuration to change it, then rebuild",
+                service.document_instruction()
+            )));
+        }
+    }
+    let chunking = flagged_chunking(
+        &state,
+        payload.collection.as_deref(),
+        payload.client.as_deref(),
+    );
+    let metadata = payload
+        .metadata
+        .map(|metadata| metadata::normalize(&state.config.metadata, metadata));
+    let header = state
+        .collections
+        .settings(payload.collection.as_deref())
+        .await?
+        .embed_template
+        .and_then(|template| metadata::render_template(&template, metadata.as_ref()));
+    let embedded = chunking::embed_sections(
+        &service,
+        &[(&payload.text, &sections, header.as_deref())],
+        chunking,
+    )
+    .await?
+    .remove(0);
+
+    let upsert = index.add(&id, embedded, payload.text, metadata).await?;
+
+    Ok(format.encode(UpsertResponse {
+        success: true,
+        id: upsert.id,
+        status: upsert.status,
+        version: upsert.version,
+    }))
+}
+
+/// Largest body accepted by `/index/bulk` as a JSON or MessagePack array.
+/// NDJSON uploads are streamed and not held in memory, so are only capped
+/// by [`codec::MAX_NDJSON_BYTES`], with each line capped too.
+const BULK_BODY_LIMIT: usize = 256 * 1024 * 1024;
+
+/// Largest snapshot archive `/admin/restore` reads, since each document's
+/// vectors make it far bigger than a bulk upload of the same vault.
+const SNAPSHOT_BODY_LIMIT: u64 = 16 * 1024 * 1024 * 1024;
+
+/// Index many documents in one upload, sent as NDJSON (one document per
+/// line, processed as it streams in) or as a JSON or MessagePack array.
+/// Uploads wait their turn in the bulk queue before the body is read.
+async fn index_bulk(
+    format: Format,
+    State(state): State<AppState>,
+    Query(params): Query<CollectionParams>,
+    Query(run): Query<AsyncParams>,
+    request: Request,
+) -> Result<Response, AppError> {
+    let index = state.collections.get(params.collection.as_deref()).await?;
+    // Checked up front, though documents are embedded by whichever model
+    // the collection is bound to once the upload's turn comes
+    collection_model(&state, params.collection.as_deref()).await?;
+    let client = bulk::client_id(request.headers(), request.extensions().get());
+    let chunking = flagged_chunking(
+        &state,
+        params.collection.as_deref(),
+        client_address(
+            request
+                .extensions()
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(peer)| *peer),
+        )
+        .as_deref(),
+    );
+    let ids = id_rule(&state, params.collection.as_deref()).await?;
+    let template = state
+        .collections
+        .settings(params.collection.as_deref())
+        .await?
+        .embed_template;
+    let ndjson = codec::is_ndjson(request.headers());
+    let source = |request: Request, state: AppState| async move {
+        Ok::<_, AppError>(if ndjson {
+            BulkSource::Lines(NdjsonLines::new(request.into_body()))
+        } else {
+            let Body(documents) = Body::<Vec<BulkDocument>>::from_request(request, &state).await?;
+            BulkSource::Items(documents.into_iter())
+        })
+    };
+
+    if run.run_async {
+        // The body can't outlive the request, so it is written to disk
+        // before the job waits its turn in the bulk queue
+        let mut source = source(request, state.clone()).await?;
+        let spool = Spool::write(&state.config.data_dir, &mut source).await?;
+        let collection = params.collection;
+        let jobs = state.jobs.clone();
+        let job = jobs.spawn("bulk_index", move |job| async move {
+            let queued = Instant::now();
+            let _permit = state.bulk_queue.acquire(client).await;
+            let queued_ms = queued.elapsed().as_millis() as u64;
+            // The collection may have switched models while the job waited
+            let _writing = index.writing().await;
+            let service = collection_model(&state, collection.as_deref())
+                .await
+                .map_err(|e| e.to_string())?;
+            let mut indexer = BulkIndexer::new(&service, &index, &state.config)
+                .with_chunking(chunking)
+                .with_ids(ids)
+                .with_template(template);
+            let total = spool.total;
+            let mut documents = spool.read().await.map_err(|e| e.to_string())?;
+            let mut batch = Vec::new();
+            let mut done = 0;
+            loop {
+                if job.is_cancelled() {
+                    return Err(format!("Cancelled after {} of {} documents", done, total));
+                }
+                while batch.len() < state.config.max_batch_size.max(1) {
+                    match documents.next().await.map_err(|e| e.to_string())? {
+                        Some(item) => batch.push(item),
+                        None => break,
+                    }
+                }
+                if batch.is_empty() {
+                    break;
+                }
+                done += batch.len();
+                indexer.flush(&mut batch).await;
+                job.progress("documents", done, total);
+            }
+
+            let mut response = indexer.finish();
+            response.queued_ms = queued_ms;
+            info!(
+                "Bulk indexed {} documents ({} failed)",
+                response.indexed, response.failed
+            );
+            Ok(response)
+        })?;
+        return Ok(accepted(format, job));
+    }
+
+    let queued = Instant::now();
+    let _permit = state.bulk_queue.acquire(client).await;
+    let queued_ms = queued.elapsed().as_millis() as u64;
+    let _writing = index.writing().await;
+    let service = collection_model(&state, params.collection.as_deref()).await?;
+    let mut source = source(request, state.clone()).await?;
+
+    let mut indexer = BulkIndexer::new(&service, &index, &state.config)
+        .with_chunking(chunking)
+        .with_ids(ids)
+        .with_template(template);
+    let mut batch = Vec::new();
+    while let Some(item) = source.next().await? {
+        batch.push(item);
+        if batch.len() >= state.config.max_batch_size {
+            indexer.flush(&mut batch).await;
+        }
+    }
+    indexer.flush(&mut batch).await;
+
+    let mut response = indexer.finish();
+    response.queued_ms = queued_ms;
+    info!(
+        "Bulk indexed {} documents ({} failed)",
+        response.indexed, response.failed
+    );
+    Ok(format.encode(response).into_response())
+}
+
+/// Bulk uploads running and waiting their turn.
+async fn bulk_queue(format: Format, State(state): State<AppState>) -> Encoded<QueueStatus> {
+    format.encode(state.bulk_queue.status())
+}
+
+async fn get_index_entry(
+    format: Format,
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Query(params): Query<IndexEntryParams>,
+) -> Result<Encoded<IndexEntryResponse>, AppError> {
+    let doc = state
+        .collections
+        .get(params.collection.as_deref())
+        .await?
+        .get(&id)
+        .await?
+        .ok_or_else(|| AppError::from(IndexError::NotFound(id.clone())))?;
+
+    Ok(format.encode(IndexEntryResponse {
+        embedding: params
+            .embedding
+            .then(|| doc.embedding.to_f32().into_owned()),
+        id: doc.id,
+        text: doc.text,
+        metadata: doc.metadata,
+        version: doc.version,
+        updated_at: doc.updated_at,
+    }))
+}
+
+pub(crate) async fn delete_index_entry(
+    format: Format,
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Query(params): Query<CollectionParams>,
+) -> Result<Encoded<IndexResponse>, AppError> {
+    let index = state.collections.get(params.collection.as_deref()).await?;
+    if !index.delete(&id).await? {
+        return Err(IndexError::NotFound(id).into());
+    }
+
+    Ok(format.encode(IndexResponse { success: true, id }))
+}
+
+async fn search(
+    format: Format,
+    State(state): State<AppState>,
+    Query(params): Query<SearchParams>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    Body(mut payload): Body<SearchRequest>,
+) -> Result<Encoded<SearchResponse>, AppError> {
+    payload.client = client_address(peer.map(|ConnectInfo(peer)| peer));
+    let response = run_logged_search(&state, payload, "", params.federate).await?;
+    Ok(format.encode(response))
+}
+
+/// Run a search and record it in the analytics log. Live searches aren't
+/// logged, since every keystroke would be.
+pub(crate) async fn run_logged_search(
+    state: &AppState,
+    payload: SearchRequest,
+    prefix: &str,
+    federate: bool,
+) -> Result<SearchResponse, AppError> {
+    let Some(analytics) = &state.analytics else {
+        return run_search(state, payload, prefix, federate).await;
+    };
+
+    let started = Instant::now();
+    let mut event = SearchEvent {
+        query_hash: text::content_hash(&payload.query),
+        query: state
+            .config
+            .analytics
+            .query_text
+            .then(|| payload.query.clone()),
+        collections: match &payload.collection {
+            Some(collection) => vec![collection.clone()],
+            None => payload.collections.clone(),
+        },
+        filter: payload
+            .filter
+            .as_ref()
+            .and_then(|filter| serde_json::to_value(filter).ok()),
+        result_ids: Vec::new(),
+        latency_ms: 0,
+        config_hash: state.config_changelog.active_hash().await,
+    };
+    let response = run_search(state, payload, prefix, federate).await?;
+
+    event.result_ids = response
+        .results
+        .iter()
+        .map(|result| result.id.clone())
+        .collect();
+    event.latency_ms = started.elapsed().as_millis() as u64;
+    if let Err(e) = analytics.record(AnalyticsEvent::Search(event)) {
+        warn!("Failed to record search in the analytics log: {}", e);
+    }
+    Ok(response)
+}
+
+/// Deepest result `offset + limit` may reach in a search.
+const MAX_SEARCH_DEPTH: usize = 10_000;
+
+/// Deepest result `offset + limit` may reach with MMR, whose cost grows
+/// with the square of the depth.
+const MAX_MMR_DEPTH: usize = 1_000;
+
+/// Candidates MMR picks from, per result it returns.
+const MMR_OVERFETCH: usize = 4;
+
+/// Run a search, embedding the query with `prefix` in front of it.
+pub(crate) async fn run_search(
+    state: &AppState,
+    payload: SearchRequest,
+    prefix: &str,
+    federate: bool,
+) -> Result<SearchResponse, AppError> {
+    if let Some(instruction) = &payload.query_instruction {
+        embedding::check_instruction(instruction).map_err(AppError::BadRequest)?;
+    }
+    // Embedding the query counts against the timeout too
+    let deadline = payload
+        .timeout_ms
+        .map(|ms| Instant::now() + Duration::from_millis(ms));
+    let targets = if payload.collections.is_empty() {
+        let index = state.collections.get(payload.collection.as_deref()).await?;
+        let service = collection_model(state, payload.collection.as_deref()).await?;
+        vec![(None, index, 1.0, service)]
+    } else {
+        if payload.collection.is_some() {
+            return Err(AppError::BadRequest(
+                "Set either collection or collections, not both".to_string(),
+            ));
+        }
+        let mut targets = Vec::with_capacity(payload.collections.len());
+        for name in &payload.collections {
+            let index = state.collections.get(Some(name)).await?;
+            let settings = state.collections.settings(Some(name)).await?;
+            let weight = match payload.weights.get(name) {
+                Some(&weight) => check_weight(weight)?,
+                None => settings.weight,
+            };
+            let service = bound_model(state, settings.model.as_deref())?;
+            targets.push((Some(name.clone()), index, weight, service));
+        }
+        targets
+    };
+    // The query is embedded once by each model the collections are bound
+    // to, with its own instruction unless the request gives one, and the
+    // template's prefix inside the instruction
+    let query = format!("{}{}", prefix, payload.query);
+    let mut query_embeddings: HashMap<&str, Vec<f32>> = HashMap::new();
+    for (_, _, _, service) in &targets {
+        if !query_embeddings.contains_key(service.fingerprint()) {
+            let embedding = service
+                .embed_query(&query, payload.query_instruction.as_deref())
+                .await?;
+            query_embeddings.insert(service.fingerprint(), embedding);
+        }
+    }
+
+    // Peers get the filters as written and normalize them by their own rules
+    let filter = payload
+        .filter
+        .clone()
+        .map(|filter| metadata::normalize_filter(&state.config.metadata, filter));
+    let boosts: Vec<Boost> = payload
+        .boosts
+        .iter()
+        .map(|boost| Boost {
+            filter: metadata::normalize_filter(&state.config.metadata, boost.filter.clone()),
+            weight: boost.weight,
+        })
+        .collect();
+
+    let limit = payload.limit.unwrap_or(10);
+    // Every page is ranked from the top, so a page deep in the results
+    // costs as much as fetching all the ones before it
+    let end = payload.offset.saturating_add(limit);
+    if end > MAX_SEARCH_DEPTH {
+        return Err(AppError::BadRequest(format!(
+            "offset + limit can be at most {}",
+            MAX_SEARCH_DEPTH
+        )));
+    }
+    let lambda = payload.lambda.unwrap_or(0.5);
+    if payload.mmr && end > MAX_MMR_DEPTH {
+        return Err(AppError::BadRequest(format!(
+            "offset + limit can be at most {} with mmr",
+            MAX_MMR_DEPTH
+        )));
+    }
+    if !(0.0..=1.0).contains(&lambda) {
+        return Err(AppError::BadRequest(format!(
+            "lambda must be between 0 and 1, got {}",
+            lambda
+        )));
+    }
+    let reranker = match (payload.rerank, &state.reranker) {
+        (false, _) => None,
+        (true, Some(reranker)) => Some(reranker),
+        (true, None) => {
+            return Err(AppError::BadRequest(
+                "Reranking is not enabled; set SYSTEMATICS_RERANK_MODEL".to_string(),
+            ))
+        }
+    };
+    // The reranker picks the final results from a wider pool
+    let mut fetch = match reranker {
+        Some(_) => end.max(state.config.reranker.candidates),
+        None => end,
+    };
+    // MMR picks the results from a wider pool too
+    if payload.mmr {
+        fetch = fetch.max(end * MMR_OVERFETCH);
+    }
+    let mut options = SearchOptions {
+        filter: filter.as_ref(),
+        hybrid: payload
+            .hybrid
+            .map(|hybrid| (payload.query.as_str(), hybrid)),
+        boosts: &boosts,
+        // Applied to the final scores instead, after collection weights
+        // and reranking
+        min_score: None,
+        deadline,
+        chunk_coverage: false,
+    };
+    let mut results = Vec::new();
+    // Stored embeddings of the results and the model that made them, by
+    // collection and id, for MMR
+    let mut embeddings = HashMap::new();
+    let mut total_candidates = 0;
+    let mut partial = false;
+    for (name, index, weight, service) in &targets {
+        let query_embedding = &query_embeddings[service.fingerprint()];
+        total_candidates += match &filter {
+            Some(filter) => index.count_matching(filter).await,
+            None => index.count().await,
+        };
+        let collection = name
+            .as_deref()
+            .or(payload.collection.as_deref())
+            .unwrap_or(index::DEFAULT_COLLECTION);
+        options.chunk_coverage =
+            state
+                .flags
+                .is_enabled(Flag::ChunkCoverage, collection, payload.client.as_deref());
+        let (mut found, cut_short) = index
+            .search_partial(query_embedding, fetch, &options)
+            .await?;
+        partial |= cut_short;
+
+        if state.config.obsidian.vault.is_some() || payload.mmr {
+            let ids: Vec<String> = found.iter().map(|result| result.id.clone()).collect();
+            let docs = index.get_many(&ids).await?;
+            for (result, doc) in found.iter_mut().zip(docs) {
+                if state.config.obsidian.vault.is_some() {
+                    let metadata = doc.as_ref().and_then(|doc| doc.metadata.as_ref());
+                    result.obsidian_uri =
+                        obsidian::open_uri(&state.config.obsidian, &result.id, metadata);
+                }
+                if let Some(doc) = doc.filter(|_| payload.mmr) {
+                    let embedding = doc.embedding.to_f32().into_owned();
+                    embeddings.insert((name.clone(), doc.id), (service.fingerprint(), embedding));
+                }
+            }
+        }
+        for result in &mut found {
+            result.collection = name.clone();
+            if name.is_some() {
+                result.score *= weight;
+                if let Some(explanation) = &mut result.explanation {
+                    explanation.collection_weight = Some(*weight);
+                }
+            }
+        }
+        results.extend(found);
+    }
+    if targets.len() > 1 {
+        results = dedupe_by_content(results);
+        results.truncate(fetch);
+    }
+
+    let mut failed_sources = Vec::new();
+    if federate {
+        for result in &mut results {
+            result.source = Some("local".to_string());
+        }
+
+        let (remote, failed) = state
+            .federation
+            .search(
+                &payload.query,
+                fetch,
+                payload.filter.as_ref(),
+                payload.hybrid.as_ref(),
+                &payload.boosts,
+                payload.min_score,
+            )
+            .await;
+        merge_remote(&mut results, remote, fetch);
+        failed_sources = failed;
+    }
+
+    // Reranking is skipped rather than run late
+    let reranker = match reranker {
+        Some(_) if deadline.is_some_and(|deadline| Instant::now() >= deadline) => {
+            partial = true;
+            None
+        }
+        reranker => reranker,
+    };
+    if let Some(reranker) = reranker {
+        let passages: Vec<String> = results
+            .iter()
+            .map(|result| {
+                result
+                    .passage
+                    .clone()
+                    .unwrap_or_else(|| result.text.clone())
+            })
+            .collect();
+        // Scoring runs the model, so isn't done on the runtime's threads
+        let scores = tokio::task::spawn_blocking({
+            let reranker = reranker.clone();
+            let query = payload.query.clone();
+            move || {
+                let passages: Vec<&str> = passages.iter().map(String::as_str).collect();
+                reranker.score(&query, &passages)
+            }
+        })
+        .await
+        .map_err(|e| AppError::Internal(e.to_string()))??;
+        for (result, score) in results.iter_mut().zip(scores) {
+            // Collection weights still apply to the reranker's verdict
+            let weight = result
+                .explanation
+                .as_ref()
+                .and_then(|explanation| explanation.collection_weight)
+                .unwrap_or(1.0);
+            let score = score * weight;
+            if let Some(explanation) = &mut result.explanation {
+                explanation.rerank_delta = Some(score - result.score);
+            }
+            result.score = score;
+        }
+        results.sort_by(best_first);
+    }
+    if let Some(min_score) = payload.min_score {
+        results.retain(|result| result.score >= min_score);
+    }
+
+    if payload.mmr {
+        let scores: Vec<f32> = results.iter().map(|result| result.score).collect();
+        // Federated results have no stored embedding here
+        let vectors: Vec<Option<(&str, &[f32])>> = results
+            .iter()
+            .map(|result| {
+                let local = result
+                    .source
+                    .as_deref()
+                    .is_none_or(|source| source == "local");
+                local
+                    .then(|| embeddings.get(&(result.collection.clone(), result.id.clone())))
+                    .flatten()
+                    .map(|(model, embedding)| (*model, embedding.as_slice()))
+            })
+            .collect();
+        let picks = diversity::mmr(&scores, &vectors, lambda, end);
+        let mut pool: Vec<Option<SearchResult>> = results.into_iter().map(Some).collect();
+        results = picks
+            .into_iter()
+            .map(|(i, penalty)| {
+                let mut result = pool[i].take().expect("each candidate is picked once");
+                if let Some(explanation) = &mut result.explanation {
+                    explanation.mmr_penalty = Some(penalty);
+                }
+                result
+            })
+            .collect();
+    }
+
+    let next_offset = (results.len() >= end && end < total_candidates).then_some(end);
+    results.drain(..payload.offset.min(results.len()));
+    results.truncate(limit);
+
+    if !payload.explain {
+        for result in &mut results {
+            result.explanation = None;
+        }
+    }
+
+    let max_text_length = payload
+        .max_text_length
+        .or(state.config.max_text_length)
+        .filter(|&max| max > 0);
+    if let Some(max) = max_text_length {
+        for result in &mut results {
+            if let Some(text) = text::truncate_at_sentence(&result.text, max) {
+                result.text = text;
+                result.truncated = true;
+            }
+        }
+    }
+
+    Ok(SearchResponse {
+        results,
+        model_fingerprint: targets[0].3.fingerprint().to_string(),
+        total_candidates,
+        next_offset,
+        failed_sources,
+        partial,
+    })
+}
+
+/// Orders results best score first. Non-finite scores, e.g. a NaN from a
+/// broken model, come last rather than where `total_cmp` puts them, which
+/// for a positive NaN is ahead of every real score.
+fn best_first(a: &SearchResult, b: &SearchResult) -> std::cmp::Ordering {
+    b.score
+        .is_finite()
+        .cmp(&a.score.is_finite())
+        .then_with(|| b.score.total_cmp(&a.score))
+}
+
+/// Merge peers' results into the local ones as a single ranking of the best
+/// `limit`. Nothing makes a peer's scores finite, and one that isn't can't
+/// be ranked against the rest, so it is dropped.
+fn merge_remote(results: &mut Vec<SearchResult>, remote: Vec<SearchResult>, limit: usize) {
+    results.extend(remote.into_iter().filter(|result| result.score.is_finite()));
+    results.sort_by(best_first);
+    results.truncate(limit);
+}
+
+/// Merge results from several collections into one ranking, keeping only
+/// the best-scoring copy of texts found in more than one, e.g. a clipping
+/// saved both as a note and in a papers collection. The collections of the
+/// dropped copies are listed on the one kept.
+fn dedupe_by_content(mut results: Vec<SearchResult>) -> Vec<SearchResult> {
+    results.sort_by(best_first);
+
+    let mut kept: Vec<SearchResult> = Vec::with_capacity(results.len());
+    let mut by_hash: HashMap<String, usize> = HashMap::new();
+    for result in results {
+        match by_hash.entry(text::content_hash(&result.text)) {
+            Entry::Occupied(entry) => {
+                let best = &mut kept[*entry.get()];
+                if let Some(collection) = result.collection {
+                    if best.collection.as_ref() != Some(&collection)
+                        && !best.duplicates.contains(&collection)
+                    {
+                        best.duplicates.push(collection);
+                    }
+                }
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(kept.len());
+                kept.push(result);
+            }
+        }
+    }
+    kept
+}
+
+/// Run a saved template with the variables in the request body.
+async fn search_template(
+    format: Format,
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    Query(params): Query<SearchParams>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    Body(vars): Body<serde_json::Map<String, serde_json::Value>>,
+) -> Result<Encoded<SearchResponse>, AppError> {
+    let template = state
+        .templates
+        .get(&name)
+        .await
+        .ok_or_else(|| AppError::NotFound(format!("Template not found: {}", name)))?;
+
+    let request = template
+        .render(&vars)
+        .map_err(|e| AppError::BadRequest(e.to_string()))?;
+    let mut payload: SearchRequest = serde_json::from_value(request).map_err(|e| {
+        AppError::BadRequest(format!(
+            "Template {} rendered an invalid search request: {}",
+            name, e
+        ))
+    })?;
+    payload.client = client_address(peer.map(|ConnectInfo(peer)| peer));
+
+    let response = run_logged_search(&state, payload, &template.prefix, params.federate).await?;
+    Ok(format.encode(response))
+}
+
+async fn get_document(
+    format: Format,
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Query(params): Query<CollectionParams>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let doc = state
+        .collections
+        .get(params.collection.as_deref())
+        .await?
+        .get(&id)
+        .await?
+        .ok_or_else(|| AppError::from(IndexError::NotFound(id.clone())))?;
+
+    let etag = etag::document_etag(&doc.text, doc.metadata.as_ref());
+    if etag::if_none_match(&headers, &etag) {
+        return Ok(not_modified(etag));
+    }
+
+    let body = format.encode(DocumentResponse {
+        id: doc.id,
+        text: doc.text,
+        metadata: doc.metadata,
+    });
+    Ok((etag_headers(etag), body).into_response())
+}
+
+/// Documents most similar to an indexed one, found from its stored
+/// embedding so its text needn't be sent again, e.g. for a "related notes"
+/// pane.
+async fn similar_documents(
+    format: Format,
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Query(params): Query<SimilarParams>,
+) -> Result<Encoded<SimilarResponse>, AppError> {
+    let limit = params.limit.unwrap_or(10);
+    if limit > MAX_SEARCH_DEPTH {
+        return Err(AppError::BadRequest(format!(
+            "limit can be at most {}",
+            MAX_SEARCH_DEPTH
+        )));
+    }
+    let index = state.collections.get(params.collection.as_deref()).await?;
+    let (id, mut results) = index
+        .similar(&id, limit)
+        .await?
+        .ok_or_else(|| AppError::from(IndexError::NotFound(id.clone())))?;
+
+    if state.config.obsidian.vault.is_some() {
+        let ids: Vec<String> = results.iter().map(|result| result.id.clone()).collect();
+        for (result, doc) in results.iter_mut().zip(index.get_many(&ids).await?) {
+            let metadata = doc.as_ref().and_then(|doc| doc.metadata.as_ref());
+            result.obsidian_uri = obsidian::open_uri(&state.config.obsidian, &result.id, metadata);
+        }
+    }
+    for result in &mut results {
+        result.explanation = None;
+        if let Some(max) = state.config.max_text_length.filter(|&max| max > 0) {
+            if let Some(text) = text::truncate_at_sentence(&result.text, max) {
+                result.text = text;
+                result.truncated = true;
+            }
+        }
+    }
+
+    Ok(format.encode(SimilarResponse { id, results }))
+}
+
+async fn list_documents(
+    format: Format,
+    State(state): State<AppState>,
+    Query(params): Query<CollectionParams>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let index = state.collections.get(params.collection.as_deref()).await?;
+    let mut docs = index.list().await?;
+    docs.sort_by(|a, b| a.id.cmp(&b.id));
+
+    let etags: Vec<String> = docs
+        .iter()
+        .map(|doc| etag::document_etag(&doc.text, doc.metadata.as_ref()))
+        .collect();
+    let etag = etag::list_etag(
+        docs.iter()
+            .zip(&etags)
+            .map(|(doc, etag)| (doc.id.as_str(), etag.as_str())),
+    );
+    if etag::if_none_match(&headers, &etag) {
+        return Ok(not_modified(etag));
+    }
+
+    let documents = docs
+        .into_iter()
+        .map(|doc| DocumentResponse {
+            id: doc.id,
+            text: doc.text,
+            metadata: doc.metadata,
+        })
+        .collect();
+    let body = format.encode(DocumentsResponse { documents });
+    Ok((etag_headers(etag), body).into_response())
+}
+
+/// Fetch several documents by id in one round trip.
+async fn get_documents(
+    format: Format,
+    State(state): State<AppState>,
+    Body(payload): Body<GetDocumentsRequest>,
+) -> Result<Encoded<GetDocumentsResponse>, AppError> {
+    let index = state.collections.get(payload.collection.as_deref()).await?;
+    let docs = index.get_many(&payload.ids).await?;
+
+    let mut documents = Vec::new();
+    let mut missing = Vec::new();
+    for (id, doc) in payload.ids.into_iter().zip(docs) {
+        match doc {
+            Some(doc) => documents.push(DocumentResponse {
+                id: doc.id,
+                text: doc.text,
+                metadata: doc.metadata,
+            }),
+            None => missing.push(id),
+        }
+    }
+
+    Ok(format.encode(GetDocumentsResponse { documents, missing }))
+}
+
+/// Count indexed documents, optionally only those matching a filter.
+async fn count_documents(
+    format: Format,
+    State(state): State<AppState>,
+    Body(payload): Body<CountRequest>,
+) -> Result<Encoded<CountResponse>, AppError> {
+    let index = state.collections.get(payload.collection.as_deref()).await?;
+    let count = match payload.filter {
+        Some(filter) => {
+            let filter = metadata::normalize_filter(&state.config.metadata, filter);
+            index.count_matching(&filter).await
+        }
+        None => index.count().await,
+    };
+
+    Ok(format.encode(CountResponse { count }))
+}
+
+/// ETag plus `Vary: Accept`, since the same ETag covers JSON and MessagePack.
+fn etag_headers(etag: String) -> [(header::HeaderName, String); 2] {
+    [
+        (header::ETAG, etag),
+        (header::VARY, header::ACCEPT.to_string()),
+    ]
+}
+
+fn not_modified(etag: String) -> Response {
+    (StatusCode::NOT_MODIFIED, etag_headers(etag)).into_response()
+}
+
+async fn list_collections(
+    format: Format,
+    State(state): State<AppState>,
+) -> Encoded<CollectionsResponse> {
+    format.encode(CollectionsResponse {
+        collections: state.collections.list().await,
+    })
+}
+
+/// Characters of each document's text returned with its projected point.
+const PREVIEW_LENGTH: usize = 200;
+
+/// Map a collection's documents onto a 2D plane, similar documents close
+/// together, for the web UI's explorer.
+async fn project_collection(
+    format: Format,
+    State(state): State<AppState>,
+    Query(params): Query<CollectionParams>,
+) -> Result<Encoded<ProjectionResponse>, AppError> {
+    let index = state.collections.get(params.collection.as_deref()).await?;
+    let mut docs = index.list().await?;
+    docs.sort_by(|a, b| a.id.cmp(&b.id));
+
+    let vectors: Vec<Vec<f32>> = docs
+        .iter()
+        .map(|doc| doc.embedding.to_f32().into_owned())
+        .collect();
+    let points = docs
+        .into_iter()
+        .zip(projection::project_2d(&vectors))
+        .map(|(doc, [x, y])| ProjectedPoint {
+            preview: text::truncate_at_sentence(&doc.text, PREVIEW_LENGTH).unwrap_or(doc.text),
+            id: doc.id,
+            x,
+            y,
+        })
+        .collect();
+
+    Ok(format.encode(ProjectionResponse { points }))
+}
+
+/// Most documents a digest clusters. Beyond this the most recently
+/// indexed are kept.
+const MAX_DIGEST_DOCUMENTS: usize = 5_000;
+const MAX_DIGEST_CLUSTERS: usize = 50;
+const DIGEST_HIGHLIGHTS: usize = 3;
+
+/// Group the documents indexed recently into themes, each shown by the
+/// documents closest to its centre.
+async fn digest(
+    format: Format,
+    State(state): State<AppState>,
+    Query(params): Query<DigestParams>,
+) -> Result<Encoded<DigestResponse>, AppError> {
+    if params
+        .clusters
+        .is_some_and(|k| k == 0 || k > MAX_DIGEST_CLUSTERS)
+    {
+        return Err(AppError::BadRequest(format!(
+            "clusters must be between 1 and {}",
+            MAX_DIGEST_CLUSTERS
+        )));
+    }
+    let since = params
+        .since
+        .unwrap_or_else(|| chrono::Utc::now() - chrono::Duration::days(7));
+    let index = state.collections.get(params.collection.as_deref()).await?;
+    let mut docs: Vec<_> = index
+        .list()
+        .await?
+        .into_iter()
+        .filter(|doc| doc.updated_at.is_some_and(|at| at >= since))
+        .collect();
+    let documents = docs.len();
+    docs.sort_by(|a, b| b.updated_at.cmp(&a.updated_at).then(a.id.cmp(&b.id)));
+    docs.truncate(MAX_DIGEST_DOCUMENTS);
+
+    let vectors: Vec<Vec<f32>> = docs
+        .iter()
+        .map(|doc| doc.embedding.to_f32().into_owned())
+        .collect();
+    // Rule of thumb: k ~ sqrt(n / 2), capped so the digest stays readable
+    let k = params
+        .clusters
+        .unwrap_or_else(|| ((docs.len() as f64 / 2.0).sqrt().round() as usize).clamp(1, 8));
+    let clustering = cluster::kmeans(&vectors, k);
+
+    let mut clusters: Vec<DigestCluster> = (0..clustering.centroids.len())
+        .map(|cluster| clustering.members(&vectors, cluster))
+        .filter(|members| !members.is_empty())
+        .map(|members| DigestCluster {
+            documents: members.len(),
+            highlights: members
+                .iter()
+                .take(DIGEST_HIGHLIGHTS)
+                .map(|&i| {
+                    let doc = &docs[i];
+                    DigestEntry {
+                        id: doc.id.clone(),
+                        snippet: text::truncate_at_sentence(&doc.text, PREVIEW_LENGTH)
+                            .unwrap_or_else(|| doc.text.clone()),
+                    }
+                })
+                .collect(),
+            ids: members.iter().map(|&i| docs[i].id.clone()).collect(),
+        })
+        .collect();
+    clusters.sort_by_key(|cluster| std::cmp::Reverse(cluster.documents));
+
+    Ok(format.encode(DigestResponse {
+        since,
+        documents,
+        clustered: docs.len(),
+        clusters,
+    }))
+}
+
+const MAX_CLUSTERS: usize = 100;
+const DEFAULT_MAX_K: usize = 10;
+const DEFAULT_REPRESENTATIVES: usize = 3;
+
+/// Group a collection's documents into thematic clusters by k-means over
+/// their embeddings.
+async fn cluster_documents(
+    format: Format,
+    State(state): State<AppState>,
+    Query(params): Query<AsyncParams>,
+    Body(payload): Body<ClusterRequest>,
+) -> Result<Response, AppError> {
+    for (name, value) in [("k", payload.k), ("max_k", payload.max_k)] {
+        if value.is_some_and(|k| k == 0 || k > MAX_CLUSTERS) {
+            return Err(AppError::BadRequest(format!(
+                "{} must be between 1 and {}",
+                name, MAX_CLUSTERS
+            )));
+        }
+    }
+    let index = state.collections.get(payload.collection.as_deref()).await?;
+    if params.run_async {
+        let job = state.jobs.spawn(
+            "cluster",
+            move |_| async move { cluster(&index, payload).await },
+        )?;
+        return Ok(accepted(format, job));
+    }
+    Ok(format
+        .encode(cluster(&index, payload).await?)
+        .into_response())
+}
+
+async fn cluster(
+    index: &VectorIndex,
+    payload: ClusterRequest,
+) -> Result<ClusterResponse, AppError> {
+    let mut docs = index.list().await?;
+    docs.sort_by(|a, b| a.id.cmp(&b.id));
+
+    let vectors: Vec<Vec<f32>> = docs
+        .iter()
+        .map(|doc| doc.embedding.to_f32().into_owned())
+        .collect();
+    let max_k = payload.max_k.unwrap_or(DEFAULT_MAX_K);
+    let k = payload.k;
+    let (vectors, clustering, silhouette) = tokio::task::spawn_blocking(move || {
+        let k = k.unwrap_or_else(|| cluster::choose_k(&vectors, max_k));
+        let clustering = cluster::kmeans(&vectors, k);
+        let silhouette = cluster::silhouette(&vectors, &clustering.assignments);
+        (vectors, clustering, silhouette)
+    })
+    .await
+    .map_err(anyhow::Error::from)?;
+
+    let representatives = payload.representatives.unwrap_or(DEFAULT_REPRESENTATIVES);
+    let mut clusters: Vec<DocumentCluster> = (0..clustering.centroids.len())
+        .map(|cluster| (cluster, clustering.members(&vectors, cluster)))
+        .filter(|(_, members)| !members.is_empty())
+        .map(|(cluster, members)| {
+            let centroid = &clustering.centroids[cluster];
+            DocumentCluster {
+                documents: members.len(),
+                representatives: members
+                    .iter()
+                    .take(representatives)
+                    .map(|&i| {
+                        let doc = &docs[i];
+                        Representative {
+                            id: doc.id.clone(),
+                            score: index::cosine_similarity(&vectors[i], centroid),
+                            snippet: text::truncate_at_sentence(&doc.text, PREVIEW_LENGTH)
+                                .unwrap_or_else(|| doc.text.clone()),
+                        }
+                    })
+                    .collect(),
+                ids: members.iter().map(|&i| docs[i].id.clone()).collect(),
+                centroid: centroid.clone(),
+            }
+        })
+        .collect();
+    clusters.sort_by_key(|cluster| std::cmp::Reverse(cluster.documents));
+
+    Ok(ClusterResponse {
+        documents: docs.len(),
+        k: clusters.len(),
+        silhouette,
+        clusters,
+    })
+}
+
+const DEFAULT_DEDUPE_THRESHOLD: f32 = 0.95;
+
+/// Find clusters of documents whose embeddings are nearly the same, such
+/// as notes pasted into the vault twice.
+async fn dedupe(
+    format: Format,
+    State(state): State<AppState>,
+    Body(payload): Body<DedupeRequest>,
+) -> Result<Encoded<DedupeResponse>, AppError> {
+    let threshold = payload.threshold.unwrap_or(DEFAULT_DEDUPE_THRESHOLD);
+    if !(dedupe::MIN_THRESHOLD..=1.0).contains(&threshold) {
+        return Err(AppError::BadRequest(format!(
+            "threshold must be between {} and 1, got {}",
+            dedupe::MIN_THRESHOLD,
+            threshold
+        )));
+    }
+    let index = state.collections.get(payload.collection.as_deref()).await?;
+    let mut docs = index.list().await?;
+    docs.sort_by(|a, b| a.id.cmp(&b.id));
+
+    let vectors: Vec<Vec<f32>> = docs
+        .iter()
+        .map(|doc| doc.embedding.to_f32().into_owned())
+        .collect();
+    let groups = tokio::task::spawn_blocking(move || dedupe::near_duplicates(&vectors, threshold))
+        .await
+        .map_err(anyhow::Error::from)?;
+
+    let clusters = groups
+        .into_iter()
+        .map(|group| DuplicateCluster {
+            ids: group.members.iter().map(|&i| docs[i].id.clone()).collect(),
+            pairs: group
+                .pairs
+                .into_iter()
+                .map(|(a, b, score)| DuplicatePair {
+                    a: docs[a].id.clone(),
+                    b: docs[b].id.clone(),
+                    score,
+                })
+                .collect(),
+        })
+        .collect();
+
+    Ok(format.encode(DedupeResponse {
+        documents: docs.len(),
+        clusters,
+    }))
+}
+
+async fn create_collection(
+    format: Format,
+    State(state): State<AppState>,
+    Body(payload): Body<CreateCollectionRequest>,
+) -> Result<(StatusCode, Encoded<CollectionInfo>), AppError> {
+    let settings = CollectionSettings {
+        weight: payload.weight.map(check_weight).transpose()?.unwrap_or(1.0),
+        model: payload
+            .model
+            .map(|model| check_model(&state, model))
+            .transpose()?,
+        analyzer: payload.analyzer,
+        id_policy: check_id_policy(payload.id_policy)?,
+        embed_template: payload
+            .embed_template
+            .map(check_embed_template)
+            .transpose()?,
+    };
+    state.collections.create(&payload.name).await?;
+    if settings != CollectionSettings::default() {
+        state
+            .collections
+            .update_settings(&payload.name, settings.clone())
+            .await?;
+    }
+
+    Ok((
+        StatusCode::CREATED,
+        format.encode(CollectionInfo {
+            name: payload.name,
+            documents: 0,
+            weight: settings.weight,
+            vector_bytes: 0,
+            model: settings.model,
+            analyzer: settings.analyzer,
+            id_policy: settings.id_policy,
+            embed_template: settings.embed_template,
+            read_only: false,
+            ef_tuning: None,
+        }),
+    ))
+}
+
+/// Change a collection's settings, leaving those not given as they are.
+async fn update_collection(
+    format: Format,
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    Body(payload): Body<UpdateCollectionRequest>,
+) -> Result<Encoded<CollectionSettings>, AppError> {
+    let mut settings = state.collections.settings(Some(&name)).await?;
+    if let Some(weight) = payload.weight {
+        settings.weight = check_weight(weight)?;
+    }
+    if let Some(model) = payload.model {
+        let model = (!model.is_empty())
+            .then(|| check_model(&state, model))
+            .transpose()?;
+        // Vectors from two models can't be compared, so a collection keeps
+        // the model it was filled with
+        let index = state.collections.get(Some(&name)).await?;
+        if model != settings.model && index.count().await > 0 {
+            return Err(AppError::Conflict(format!(
+                "Collection {} already holds documents embedded by its model; \
+                 create another collection to use a different one",
+                name
+            )));
+        }
+        settings.model = model;
+    }
+    if let Some(analyzer) = payload.analyzer {
+        settings.analyzer = analyzer;
+    }
+    if let Some(id_policy) = payload.id_policy {
+        settings.id_policy = check_id_policy(id_policy)?;
+    }
+    if let Some(template) = payload.embed_template {
+        settings.embed_template = (!template.is_empty())
+            .then(|| check_embed_template(template))
+            .transpose()?;
+    }
+    state
+        .collections
+        .update_settings(&name, settings.clone())
+        .await?;
+
+    Ok(format.encode(settings))
+}
+
+/// Check that `model` names a served model or registered variant, so a
+/// collection can be bound to it.
+fn check_model(state: &AppState, model: String) -> Result<String, AppError> {
+    if state.models.get(Some(&model)).is_none() && state.model_variants.get(&model).is_none() {
+        return Err(AppError::Coded(
+            ErrorCode::ModelNotFound,
+            format!(
+                "Model {} is not served; add it to SYSTEMATICS_MODELS or register it as \
+                 a variant",
+                model
+            ),
+        ));
+    }
+    Ok(model)
+}
+
+fn check_id_policy(policy: IdPolicy) -> Result<IdPolicy, AppError> {
+    policy.compile().map_err(AppError::BadRequest)?;
+    Ok(policy)
+}
+
+fn check_embed_template(template: String) -> Result<String, AppError> {
+    metadata::check_template(&template).map_err(AppError::BadRequest)?;
+    Ok(template)
+}
+
+/// The id policy of `collection`, ready to apply.
+async fn id_rule(state: &AppState, collection: Option<&str>) -> Result<IdRule, AppError> {
+    let policy = state.collections.settings(collection).await?.id_policy;
+    policy.compile().map_err(AppError::BadRequest)
+}
+
+fn check_weight(weight: f32) -> Result<f32, AppError> {
+    if !weight.is_finite() || weight < 0.0 {
+        return Err(AppError::BadRequest(format!(
+            "Collection weights must be zero or positive, got {}",
+            weight
+        )));
+    }
+    Ok(weight)
+}
+
+/// Download a published bundle, verify it, and serve it as a read-only
+/// collection alongside the others.
+async fn mount_collection(
+    format: Format,
+    State(state): State<AppState>,
+    Body(payload): Body<MountRequest>,
+) -> Result<(StatusCode, Encoded<CollectionInfo>), AppError> {
+    let url = reqwest::Url::parse(&payload.url)
+        .map_err(|_| AppError::BadRequest(format!("Invalid bundle URL {:?}", payload.url)))?;
+    let allowlist = download::Allowlist {
+        hosts: &state.config.bundle_hosts,
+        max_bytes: state.config.max_bundle_mb * 1024 * 1024,
+    };
+    allowlist
+        .check(&url)
+        .map_err(|e| AppError::Forbidden(e.to_string()))?;
+    let client = allowlist
+        .client()
+        .map_err(|e| AppError::Internal(e.to_string()))?;
+    let name = match payload.name {
+        Some(name) => name,
+        None => url
+            .path_segments()
+            .and_then(|mut segments| segments.next_back())
+            .map(|file| file.trim_end_matches(&format!(".{}", bundle::EXTENSION)))
+            .unwrap_or_default()
+            .to_string(),
+    };
+    // Held until the bundle is mounted, so a concurrent mount or create
+    // can't take the name, nor the file it is downloaded to
+    let _claim = state.collections.claim(&name)?;
+
+    let sha256 = match payload.sha256 {
+        Some(sha256) => sha256,
+        None => download::published_sha256(&client, url.as_str())
+            .await
+            .map_err(|e| AppError::BadRequest(e.to_string()))?
+            .ok_or_else(|| {
+                AppError::BadRequest(format!(
+                    "No checksum is published at {}.sha256; pass the bundle's sha256",
+                    url
+                ))
+            })?,
+    };
+    let path = state
+        .config
+        .data_dir
+        .join(bundle::BUNDLES_DIR)
+        .join(format!("{}.{}", name, bundle::EXTENSION));
+    download::fetch_verified(&client, url.as_str(), &path, &sha256, allowlist.max_bytes)
+        .await
+        .map_err(|e| AppError::BadRequest(e.to_string()))?;
+    let signature = signature::signature_path(&path);
+    if !state.config.model.trusted_keys.is_empty() {
+        let fetched = signature::fetch(&client, url.as_str())
+            .await
+            .and_then(|text| {
+                std::fs::write(&signature, text)?;
+                Ok(())
+            });
+        if let Err(e) = fetched {
+            let _ = std::fs::remove_file(&path);
+            return Err(AppError::BadRequest(e.to_string()));
+        }
+    }
+
+    // Reading the bundle in takes a while for a big one
+    let mounted = tokio::task::spawn_blocking({
+        let state = state.clone();
+        let path = path.clone();
+        move || mount_bundle(&state, &path)
+    })
+    .await
+    .map_err(|e| AppError::Internal(e.to_string()))?;
+    if let Err(e) = mounted {
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(&signature);
+        return Err(e);
+    }
+    let info = state
+        .collections
+        .list()
+        .await
+        .into_iter()
+        .find(|info| info.name == name)
+        .ok_or_else(|| AppError::from(IndexError::CollectionNotFound(name)))?;
+
+    Ok((StatusCode::CREATED, format.encode(info)))
+}
+
+async fn delete_collection(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> Result<StatusCode, AppError> {
+    state.collections.delete(&name).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Re-embed every stored text with the current model and rebuild the
+/// search indexes, streaming progress as NDJSON. The rebuild carries on if
+/// the client disconnects.
+async fn rebuild_index(
+    State(state): State<AppState>,
+    Body(payload): Body<RebuildRequest>,
+) -> Result<Response, AppError> {
+    let targets = match payload.collection {
+        Some(name) => {
+            let index = state.collections.get(Some(&name)).await?;
+            vec![(name, index)]
+        }
+        None => state.collections.writable(),
+    };
+    // Each collection is re-embedded by the model it is bound to
+    let mut bound = Vec::with_capacity(targets.len());
+    for (name, index) in targets {
+        let service = collection_model(&state, Some(&name)).await?;
+        let template = state
+            .collections
+            .settings(Some(&name))
+            .await?
+            .embed_template;
+        bound.push((name, index, service, template));
+    }
+    let guard = state
+        .rebuild_lock
+        .clone()
+        .try_lock_owned()
+        .map_err(|_| AppError::Conflict("A rebuild is already running".to_string()))?;
+
+    let (events, receiver) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let _guard = guard;
+        for (name, index, service, template) in bound {
+            let template = template.as_deref();
+            let options = RebuildOptions {
+                template,
+                ..Default::default()
+            };
+            rebuild::rebuild(&service, &name, &index, options, &state.config, &events).await;
+        }
+    });
+
+    let lines = futures::stream::unfold(receiver, |mut receiver| async move {
+        let event = receiver.recv().await?;
+        let mut line = serde_json::to_string(&event).expect("RebuildEvent is always serializable");
+        line.push('\n');
+        Some((Ok::<_, std::convert::Infallible>(line), receiver))
+    });
+    Ok((
+        [(header::CONTENT_TYPE, "application/x-ndjson")],
+        axum::body::Body::from_stream(lines),
+    )
+        .into_response())
+}
+
+/// Re-embed every stored document as a background job, optionally
+/// switching the collections to another model, and respond at once with
+/// the job to poll.
+async fn reindex(
+    format: Format,
+    State(state): State<AppState>,
+    Body(payload): Body<ReindexRequest>,
+) -> Result<(StatusCode, Encoded<JobInfo>), AppError> {
+    let model = payload
+        .model
+        .map(|model| {
+            (!model.is_empty())
+                .then(|| check_model(&state, model))
+                .transpose()
+        })
+        .transpose()?;
+    let targets = match payload.collection {
+        Some(name) => {
+            let index = state.collections.get(Some(&name)).await?;
+            if index.is_read_only() {
+                return Err(IndexError::ReadOnly.into());
+            }
+            vec![(name, index)]
+        }
+        None => state.collections.writable(),
+    };
+    let mut bound = Vec::with_capacity(targets.len());
+    for (name, index) in targets {
+        let settings = state.collections.settings(Some(&name)).await?;
+        let service = match &model {
+            Some(model) => bound_model(&state, model.as_deref())?,
+            None => bound_model(&state, settings.model.as_deref())?,
+        };
+        bound.push((name, index, service, settings.embed_template));
+    }
+    let guard = state
+        .rebuild_lock
+        .clone()
+        .try_lock_owned()
+        .map_err(|_| AppError::Conflict("A rebuild is already running".to_string()))?;
+
+    let job = state.jobs.spawn("reindex", move |job| async move {
+        let _guard = guard;
+        let (events, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        let progress = {
+            let job = job.clone();
+            tokio::spawn(async move {
+                let mut finished = Vec::new();
+                let mut error = None;
+                while let Some(event) = receiver.recv().await {
+                    match &event {
+                        RebuildEvent::Started {
+                            collection,
+                            documents,
+                        } => job.progress(collection, 0, *documents),
+                        RebuildEvent::Progress {
+                            collection,
+                            done,
+                            total,
+                        } => job.progress(collection, *done, *total),
+                        RebuildEvent::Finished {
+                            collection,
+                            failed,
+                            issues,
+                            ..
+                        } => {
+                            for id in failed {
+                                job.error(format!("{}: {} couldn't be embedded", collection, id));
+                            }
+                            if *issues > 0 {
+                                job.error(format!(
+                                    "{}: {} integrity issues found afterwards",
+                                    collection, issues
+                                ));
+                            }
+                            finished.push(event);
+                        }
+                        RebuildEvent::Error {
+                            collection,
+                            error: e,
+                        } => {
+                            error = Some(format!("{}: {}", collection, e));
+                        }
+                    }
+                }
+                (finished, error)
+            })
+        };
+
+        for (name, index, service, template) in bound {
+            if job.is_cancelled() {
+                break;
+            }
+            let options = RebuildOptions {
+                template: template.as_deref(),
+                rebind: model.clone().map(|model| Rebind {
+                    collections: &state.collections,
+                    model,
+                }),
+                job: Some(job.as_ref()),
+            };
+            rebuild::rebuild(&service, &name, &index, options, &state.config, &events).await;
+        }
+        drop(events);
+        match progress.await {
+            Ok((_, Some(error))) => Err(error),
+            Ok((finished, None)) if job.is_cancelled() => {
+                Err(format!("Cancelled after {} collections", finished.len()))
+            }
+            Ok((finished, None)) => Ok(finished),
+            Err(e) => Err(e.to_string()),
+        }
+    })?;
+
+    Ok((StatusCode::ACCEPTED, format.encode(job)))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct AsyncParams {
+    /// Run the request as a background job, responding with the job
+    #[serde(default, rename = "async")]
+    pub(crate) run_async: bool,
+}
+
+/// Respond to a request made with `?async=true`: its job, queued.
+fn accepted(format: Format, job: JobInfo) -> Response {
+    (StatusCode::ACCEPTED, format.encode(job)).into_response()
+}
+
+/// Store the latest list of a vault's files, for reconciling the
+/// collection against it.
+async fn put_manifest(
+    format: Format,
+    State(state): State<AppState>,
+    Body(payload): Body<ManifestRequest>,
+) -> Result<Encoded<ManifestResponse>, AppError> {
+    let name = payload
+        .collection
+        .unwrap_or_else(|| index::DEFAULT_COLLECTION.to_string());
+    if state.collections.get(Some(&name)).await?.is_read_only() {
+        return Err(IndexError::ReadOnly.into());
+    }
+    let manifest = Manifest {
+        received_at: chrono::Utc::now(),
+        files: payload.files,
+    };
+    reconcile::write_manifest(&state.collections.dir(&name), &manifest)?;
+
+    Ok(format.encode(ManifestResponse {
+        collection: name,
+        files: manifest.files.len(),
+        received_at: manifest.received_at,
+    }))
+}
+
+/// Flag or delete the notes of a collection whose files are missing from
+/// its latest manifest, as a background job.
+async fn reconcile_collection(
+    format: Format,
+    State(state): State<AppState>,
+    Body(payload): Body<ReconcileRequest>,
+) -> Result<(StatusCode, Encoded<JobInfo>), AppError> {
+    let name = payload
+        .collection
+        .unwrap_or_else(|| index::DEFAULT_COLLECTION.to_string());
+    let index = state.collections.get(Some(&name)).await?;
+    let manifest = reconcile::read_manifest(&state.collections.dir(&name))?.ok_or_else(|| {
+        AppError::NotFound(format!(
+            "No manifest has been sent for collection {}; PUT /manifest first",
+            name
+        ))
+    })?;
+    if payload.action == StaleAction::Delete && manifest.files.is_empty() {
+        return Err(AppError::BadRequest(
+            "The manifest lists no files, which would delete every note; \
+             send the vault's files first"
+                .to_string(),
+        ));
+    }
+
+    let dir = state.collections.dir(&name);
+    let job = state.jobs.spawn("reconcile", move |job| async move {
+        reconcile::reconcile(
+            &name,
+            &dir,
+            &index,
+            &manifest,
+            payload.action,
+            &state.config.obsidian.path_field,
+            Some(&job),
+        )
+        .await
+    })?;
+    Ok((StatusCode::ACCEPTED, format.encode(job)))
+}
+
+async fn list_jobs(format: Format, State(state): State<AppState>) -> Encoded<JobsResponse> {
+    format.encode(JobsResponse {
+        jobs: state.jobs.list(),
+    })
+}
+
+async fn get_job(
+    format: Format,
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Encoded<JobInfo>, AppError> {
+    let job = state
+        .jobs
+        .get(&id)
+        .ok_or_else(|| AppError::NotFound(format!("Job not found: {}", id)))?;
+    Ok(format.encode(job))
+}
+
+/// Ask a job to stop, responding with it as it stands.
+async fn cancel_job(
+    format: Format,
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Encoded<JobInfo>, AppError> {
+    let job = state
+        .jobs
+        .cancel(&id)
+        .ok_or_else(|| AppError::NotFound(format!("Job not found: {}", id)))?;
+    Ok(format.encode(job))
+}
+
+/// Export every collection as a portable NDJSON archive, streamed as it
+/// is read.
+async fn export_snapshot(State(state): State<AppState>) -> Result<Response, AppError> {
+    let mut fingerprints = std::collections::BTreeMap::new();
+    for (name, _) in state.collections.writable() {
+        let service = collection_model(&state, Some(&name)).await?;
+        fingerprints.insert(name, service.fingerprint().to_string());
+    }
+
+    let receiver = snapshot::export(state.collections.clone(), fingerprints);
+    let lines = futures::stream::unfold(receiver, |mut receiver| async move {
+        let line = receiver.recv().await?;
+        Some((Ok::<_, std::convert::Infallible>(line), receiver))
+    });
+    let filename = format!(
+        "attachment; filename=\"systematics-{}.ndjson\"",
+        chrono::Utc::now().format("%Y%m%dT%H%M%SZ")
+    );
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/x-ndjson".to_string()),
+            (header::CONTENT_DISPOSITION, filename),
+        ],
+        axum::body::Body::from_stream(lines),
+    )
+        .into_response())
+}
+
+#[derive(Deserialize)]
+struct RestoreParams {
+    /// Delete the documents already in the archive's collections first
+    #[serde(default)]
+    replace: bool,
+}
+
+/// Load an archive from [`export_snapshot`], streamed in as it is read.
+async fn import_snapshot(
+    format: Format,
+    State(state): State<AppState>,
+    Query(params): Query<RestoreParams>,
+    request: Request,
+) -> Result<Encoded<RestoreReport>, AppError> {
+    let mut lines = NdjsonLines::new(request.into_body())
+        .with_limits(codec::MAX_LINE_BYTES, SNAPSHOT_BODY_LIMIT);
+    let header = snapshot::read_header(&mut lines).await?;
+
+    // Check everything before changing anything
+    for archived in &header.collections {
+        let service = bound_model(&state, archived.settings.model.as_deref())?;
+        if service.fingerprint() != archived.model_fingerprint {
+            return Err(AppError::BadRequest(format!(
+                "Collection {} was embedded by {}, but this server would use {}; \
+                 vectors from different models can't be compared",
+                archived.name,
+                archived.model_fingerprint,
+                service.fingerprint()
+            )));
+        }
+        if let Ok(index) = state.collections.get(Some(&archived.name)).await {
+            if !params.replace && index.count().await > 0 {
+                return Err(AppError::Conflict(format!(
+                    "Collection {} already holds documents; restore with ?replace=true \
+                     to delete them first",
+                    archived.name
+                )));
+            }
+        }
+    }
+
+    let report = snapshot::restore(&mut lines, header, &state.collections, params.replace).await?;
+
+    // The restored documents haven't been searched yet; warm them in the
+    // background rather than holding up the response
+    let mut restored = Vec::new();
+    for collection in &report.collections {
+        restored.push((
+            collection.name.clone(),
+            state.collections.get(Some(&collection.name)).await?,
+        ));
+    }
+    let pin = state.config.hnsw.pin_entry_layers;
+    tokio::spawn(async move {
+        warm(&state, restored, pin).await;
+    });
+
+    Ok(format.encode(report))
+}
+
+#[derive(Deserialize)]
+struct WarmRequest {
+    /// Only this collection; every collection if not given
+    collection: Option<String>,
+    /// Lock the graph's entry layers into RAM; as configured if not given
+    pin: Option<bool>,
+}
+
+#[derive(Serialize)]
+struct WarmResponse {
+    /// Time the model took to embed a first text
+    model_ms: f64,
+    collections: Vec<WarmedCollection>,
+}
+
+#[derive(Serialize)]
+struct WarmedCollection {
+    name: String,
+    #[serde(flatten)]
+    report: WarmReport,
+}
+
+/// Load a collection's pages and the model's first inference ahead of the
+/// first searches, e.g. after a reboot left them swapped out.
+async fn warm_collections(
+    format: Format,
+    State(state): State<AppState>,
+    Body(payload): Body<WarmRequest>,
+) -> Result<Encoded<WarmResponse>, AppError> {
+    let collections = match &payload.collection {
+        Some(name) => vec![(name.clone(), state.collections.get(Some(name)).await?)],
+        None => state.collections.all(),
+    };
+    let pin = payload.pin.unwrap_or(state.config.hnsw.pin_entry_layers);
+    Ok(format.encode(warm(&state, collections, pin).await))
+}
+
+/// Warm `collections` and the base model, logging what was done.
+async fn warm(
+    state: &AppState,
+    collections: Vec<(String, Arc<VectorIndex>)>,
+    pin: bool,
+) -> WarmResponse {
+    let start = Instant::now();
+    // A cached embedding would leave the model cold
+    if let Err(e) = state.models.base().embed_uncached("warm up").await {
+        warn!("Model warm-up failed: {}", e);
+    }
+    let model_ms = start.elapsed().as_secs_f64() * 1000.0;
+
+    let mut warmed = Vec::with_capacity(collections.len());
+    for (name, index) in collections {
+        let Ok(report) = tokio::task::spawn_blocking(move || index.warm(pin)).await else {
+            continue;
+        };
+        info!(
+            "Warmed collection {}: {} documents, {} bytes in {:.0}ms, {} bytes pinned",
+            name, report.documents, report.bytes_touched, report.took_ms, report.pinned_bytes
+        );
+        if let Some(error) = &report.pin_error {
+            warn!("Collection {} not pinned: {}", name, error);
+        }
+        warmed.push(WarmedCollection { name, report });
+    }
+
+    WarmResponse {
+        model_ms,
+        collections: warmed,
+    }
+}
+
+/// Mount the bundle at `path` as a read-only collection named after the
+/// file, once it is known to hold vectors this server's model would make.
+fn mount_bundle(state: &AppState, path: &std::path::Path) -> Result<String, AppError> {
+    TrustRoot::new(&state.config.model.trusted_keys)?
+        .verify_cached(path)
+        .map_err(|e| AppError::BadRequest(e.to_string()))?;
+    let bundle = Bundle::open(path)?;
+    let manifest = bundle.manifest();
+    let service = bound_model(state, manifest.settings.model.as_deref())?;
+    if service.fingerprint() != manifest.model_fingerprint {
+        return Err(AppError::BadRequest(format!(
+            "Bundle {:?} was embedded by {}, but this server would use {}; \
+             vectors from different models can't be compared",
+            path,
+            manifest.model_fingerprint,
+            service.fingerprint()
+        )));
+    }
+
+    let name = bundle.collection_name();
+    let index = bundle.load(&state.collections)?;
+    state
+        .collections
+        .mount(&name, index, manifest.settings.clone(), path)?;
+    Ok(name)
+}
+
+/// Back up every collection to the data directory's `backups/`.
+async fn create_backup(
+    format: Format,
+    State(state): State<AppState>,
+    Query(params): Query<AsyncParams>,
+    Body(payload): Body<BackupRequest>,
+) -> Result<Response, AppError> {
+    if params.run_async {
+        let job = state.jobs.spawn("backup", move |_| async move {
+            backup::create(
+                &state.config.data_dir,
+                &state.collections,
+                payload.incremental,
+            )
+            .await
+        })?;
+        return Ok(accepted(format, job));
+    }
+    let info = backup::create(
+        &state.config.data_dir,
+        &state.collections,
+        payload.incremental,
+    )
+    .await?;
+    Ok(format.encode(info).into_response())
+}
+
+async fn list_backups(
+    format: Format,
+    State(state): State<AppState>,
+) -> Result<Encoded<Vec<BackupInfo>>, AppError> {
+    Ok(format.encode(backup::list(&state.config.data_dir)?))
+}
+
+/// Restore a backup chain in memory and check it reproduces what was
+/// backed up, without touching the running index.
+async fn verify_backup(
+    format: Format,
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Encoded<BackupVerifyResponse>, AppError> {
+    let dir = backup::backup_dir(&state.config.data_dir);
+    if !backup::list(&state.config.data_dir)?
+        .iter()
+        .any(|info| info.id == id)
+    {
+        return Err(AppError::NotFound(format!("Backup not found: {}", id)));
+    }
+    let restored = tokio::task::spawn_blocking(move || backup::restore(&dir, &id))
+        .await
+        .map_err(anyhow::Error::from)??;
+
+    Ok(format.encode(BackupVerifyResponse {
+        chain: restored.chain.into_iter().map(|info| info.id).collect(),
+        collections: restored
+            .collections
+            .into_iter()
+            .map(|(name, collection)| RestoredCollectionInfo {
+                name,
+                documents: collection.snapshot.documents.len(),
+                aliases: collection.snapshot.aliases.len(),
+                settings: collection.settings,
+            })
+            .collect(),
+    }))
+}
+
+async fn verify_index(
+    format: Format,
+    State(state): State<AppState>,
+    Body(payload): Body<VerifyRequest>,
+) -> Result<Encoded<VerifyResponse>, AppError> {
+    let targets = match payload.collection {
+        Some(name) => {
+            let index = state.collections.get(Some(&name)).await?;
+            vec![(name, index)]
+        }
+        None => state.collections.all(),
+    };
+
+    let mut collections = Vec::with_capacity(targets.len());
+    for (name, index) in targets {
+        let report = index.verify(payload.repair).await?;
+        if !report.issues.is_empty() {
+            warn!(
+                "Collection {} has {} integrity issues{}",
+                name,
+                report.issues.len(),
+                if payload.repair { " (repairing)" } else { "" }
+            );
+        }
+        collections.push(CollectionVerifyReport { name, report });
+    }
+
+    Ok(format.encode(VerifyResponse {
+        healthy: collections
+            .iter()
+            .all(|c| c.report.issues.iter().all(|issue| issue.repaired)),
+        collections,
+    }))
+}
+
+async fn list_aliases(
+    format: Format,
+    State(state): State<AppState>,
+    Query(params): Query<CollectionParams>,
+) -> Result<Encoded<AliasesResponse>, AppError> {
+    let index = state.collections.get(params.collection.as_deref()).await?;
+    let mut aliases: Vec<Alias> = index
+        .aliases()
+        .await
+        .into_iter()
+        .map(|(alias, id)| Alias { alias, id })
+        .collect();
+    aliases.sort_by(|a, b| a.alias.cmp(&b.alias));
+
+    Ok(format.encode(AliasesResponse { aliases }))
+}
+
+/// Register an alternative id for a document, e.g. its path before a
+/// rename, so lookups, updates, and deletes by either id hit the same entry.
+async fn add_alias(
+    format: Format,
+    State(state): State<AppState>,
+    Body(payload): Body<AddAliasRequest>,
+) -> Result<Encoded<Alias>, AppError> {
+    let index = state.collections.get(payload.collection.as_deref()).await?;
+    let id = index.add_alias(&payload.alias, &payload.id).await?;
+
+    Ok(format.encode(Alias {
+        alias: payload.alias,
+        id,
+    }))
+}
+
+async fn remove_alias(
+    State(state): State<AppState>,
+    Path(alias): Path<String>,
+    Query(params): Query<CollectionParams>,
+) -> Result<StatusCode, AppError> {
+    let index = state.collections.get(params.collection.as_deref()).await?;
+    if !index.remove_alias(&alias).await? {
+        return Err(AppError::NotFound(format!("Alias not found: {}", alias)));
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn record_feedback(
+    format: Format,
+    State(state): State<AppState>,
+    Body(payload): Body<FeedbackEvent>,
+) -> Result<Encoded<FeedbackResponse>, AppError> {
+    if let Some(analytics) = &state.analytics {
+        analytics.record(AnalyticsEvent::Feedback(payload.clone()))?;
+    }
+    state.feedback_log.record(payload).await?;
+
+    Ok(format.encode(FeedbackResponse { success: true }))
+}
+
+#[derive(Deserialize)]
+struct AnalyticsExportParams {
+    /// Only records at or after this time
+    since: Option<chrono::DateTime<chrono::Utc>>,
+    /// Only records of this type, `search` or `feedback`
+    #[serde(rename = "type")]
+    kind: Option<String>,
+}
+
+/// Export the analytics log as NDJSON, oldest record first.
+async fn export_analytics(
+    State(state): State<AppState>,
+    Query(params): Query<AnalyticsExportParams>,
+) -> Result<Response, AppError> {
+    let analytics = state.analytics.as_ref().ok_or_else(|| {
+        AppError::NotFound(
+            "The analytics log is disabled; set SYSTEMATICS_ANALYTICS_MAX_MB".to_string(),
+        )
+    })?;
+    let matches_kind = |record: &AnalyticsRecord| match params.kind.as_deref() {
+        None => true,
+        Some("search") => matches!(record.event, AnalyticsEvent::Search(_)),
+        Some("feedback") => matches!(record.event, AnalyticsEvent::Feedback(_)),
+        Some(_) => false,
+    };
+    if let Some(kind) = params
+        .kind
+        .as_deref()
+        .filter(|&kind| kind != "search" && kind != "feedback")
+    {
+        return Err(AppError::BadRequest(format!(
+            "Unknown record type {:?}; expected search or feedback",
+            kind
+        )));
+    }
+
+    let mut body = String::new();
+    for record in analytics
+        .export(params.since)?
+        .iter()
+        .filter(|record| matches_kind(record))
+    {
+        body.push_str(&serde_json::to_string(record).map_err(anyhow::Error::from)?);
+        body.push('\n');
+    }
+    Ok(([(header::CONTENT_TYPE, "application/x-ndjson")], body).into_response())
+}
+
+/// Export feedback as NDJSON triplets for sentence-transformers training.
+async fn export_triplets(State(state): State<AppState>) -> Result<Response, AppError> {
+    let index = state.collections.get(None).await?;
+    let mut body = String::new();
+
+    for (query, positive_id, negative_id) in state.feedback_log.triplet_ids().await {
+        // Skip judgements about documents that are no longer indexed
+        let (Some(positive), Some(negative)) = (
+            index.get(&positive_id).await?,
+            index.get(&negative_id).await?,
+        ) else {
+            continue;
+        };
+
+        let triplet = Triplet {
+            anchor: query,
+            positive: positive.text,
+            negative: negative.text,
+        };
+        body.push_str(&serde_json::to_string(&triplet).map_err(anyhow::Error::from)?);
+        body.push('\n');
+    }
+
+    Ok(([(header::CONTENT_TYPE, "application/x-ndjson")], body).into_response())
+}
+
+/// Load a variant's model and tokenizer, the base model's tokenizer if it
+/// names none. With trusted keys configured, the files it names must be
+/// signed like downloaded ones, since the server runs whatever model it is
+/// pointed at.
+async fn load_variant(
+    config: &ModelConfig,
+    info: &VariantInfo,
+) -> Result<EmbeddingService, AppError> {
+    let tokenizer_path = match &info.tokenizer_path {
+        Some(path) => path.clone(),
+        None => EmbeddingService::download_tokenizer(config).await?,
+    };
+    let trust = TrustRoot::new(&config.trusted_keys)?;
+    let config = config.clone();
+    let info = info.clone();
+    // Reading and checking the model takes a while for a big one
+    tokio::task::spawn_blocking(move || {
+        for path in std::iter::once(&info.model_path).chain(&info.tokenizer_path) {
+            trust
+                .verify_cached(path)
+                .map_err(|e| AppError::BadRequest(e.to_string()))?;
+        }
+        Ok(EmbeddingService::from_files(
+            &info.model_path,
+            &tokenizer_path,
+            &config,
+        )?)
+    })
+    .await
+    .map_err(|e| AppError::Internal(e.to_string()))?
+}
+
+/// Load a fine-tuned model variant and kick off a background recall@k
+/// comparison against the base model on the feedback test set.
+async fn register_variant(
+    format: Format,
+    State(state): State<AppState>,
+    Body(payload): Body<RegisterVariantRequest>,
+) -> Result<Encoded<VariantInfo>, AppError> {
+    // Collections are bound to models by name, so the two can't overlap
+    if state.models.get(Some(&payload.name)).is_some() {
+        return Err(AppError::Conflict(format!(
+            "{} is already the name of a served model",
+            payload.name
+        )));
+    }
+    let info = VariantInfo {
+        name: payload.name,
+        model_path: payload.model_path,
+        tokenizer_path: payload.tokenizer_path,
+        evaluation: EvaluationStatus::Pending,
+    };
+    let service = Arc::new(load_variant(&state.config.model, &info).await?);
+    state
+        .model_variants
+        .register(info.clone(), service.clone())
+        .await?;
+
+    // Feedback is recorded against the default collection
+    let index = state.collections.get(None).await?;
+    let k = payload.k.unwrap_or(10);
+    let name = info.name.clone();
+    tokio::spawn(async move {
+        let test_set = state.feedback_log.relevant_ids().await;
+        let config_hash = state.config_changelog.active_hash().await;
+        let result = models::evaluate(
+            &index,
+            state.models.base(),
+            &service,
+            state.config.chunking,
+            &test_set,
+            k,
+            config_hash,
+        )
+        .await;
+
+        let evaluation = match result {
+            Ok(evaluation) => {
+                info!(
+                    "Variant {} recall@{}: {:.3} (base {:.3})",
+                    name, k, evaluation.variant_recall, evaluation.base_recall
+                );
+                EvaluationStatus::Complete(evaluation)
+            }
+            Err(e) => {
+                warn!("Evaluation of variant {} failed: {}", name, e);
+                EvaluationStatus::Failed {
+                    error: e.to_string(),
+                }
+            }
+        };
+        if let Err(e) = state.model_variants.set_evaluation(&name, evaluation).await {
+            warn!("Failed to save the evaluation of variant {}: {}", name, e);
+        }
+    });
+
+    Ok(format.encode(info))
+}
+
+async fn list_variants(format: Format, State(state): State<AppState>) -> Encoded<VariantsResponse> {
+    format.encode(VariantsResponse {
+        variants: state.model_variants.list().await,
+    })
+}
+
+async fn config_history(
+    format: Format,
+    State(state): State<AppState>,
+) -> Encoded<ConfigHistoryResponse> {
+    format.encode(ConfigHistoryResponse {
+        active_hash: state.config_changelog.active_hash().await,
+        changes: state.config_changelog.changes().await,
+    })
+}
+
+async fn list_peers(format: Format, State(state): State<AppState>) -> Encoded<PeersResponse> {
+    format.encode(PeersResponse {
+        peers: state.federation.list().await,
+    })
+}
+
+async fn add_peer(
+    format: Format,
+    State(state): State<AppState>,
+    Body(payload): Body<Peer>,
+) -> Result<Encoded<Peer>, AppError> {
+    if !payload.url.starts_with("http://") && !payload.url.starts_with("https://") {
+        return Err(AppError::BadRequest(format!(
+            "Peer url must start with http:// or https://: {}",
+            payload.url
+        )));
+    }
+
+    state.federation.add(payload.clone()).await?;
+
+    Ok(format.encode(payload))
+}
+
+async fn remove_peer(
+    format: Format,
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> Result<Encoded<PeersResponse>, AppError> {
+    if !state.federation.remove(&name).await? {
+        return Err(AppError::NotFound(format!("Peer not found: {}", name)));
+    }
+
+    Ok(format.encode(PeersResponse {
+        peers: state.federation.list().await,
+    }))
+}
+
+async fn list_templates(
+    format: Format,
+    State(state): State<AppState>,
+) -> Encoded<TemplatesResponse> {
+    format.encode(TemplatesResponse {
+        templates: state.templates.list().await,
+    })
+}
+
+async fn put_template(
+    format: Format,
+    State(state): State<AppState>,
+    Body(payload): Body<QueryTemplate>,
+) -> Result<Encoded<QueryTemplate>, AppError> {
+    if payload.name.is_empty() || payload.name.contains('/') {
+        return Err(AppError::BadRequest(format!(
+            "Template names must be non-empty and contain no '/': {:?}",
+            payload.name
+        )));
+    }
+    if !payload.request.is_object() {
+        return Err(AppError::BadRequest(
+            "Template request must be a search request object".to_string(),
+        ));
+    }
+
+    state.templates.put(payload.clone()).await?;
+
+    Ok(format.encode(payload))
+}
+
+async fn remove_template(
+    format: Format,
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> Result<Encoded<TemplatesResponse>, AppError> {
+    if !state.templates.remove(&name).await? {
+        return Err(AppError::NotFound(format!("Template not found: {}", name)));
+    }
+
+    Ok(format.encode(TemplatesResponse {
+        templates: state.templates.list().await,
+    }))
+}
+
+async fn cache_metrics(format: Format, State(state): State<AppState>) -> Encoded<CacheMetrics> {
+    format.encode(state.models.base().cache_metrics())
+}
+
+async fn debug_requests(
+    format: Format,
+    State(state): State<AppState>,
+) -> Result<Encoded<DebugRequestsResponse>, AppError> {
+    let capture = debug_capture(&state)?;
+    Ok(format.encode(DebugRequestsResponse {
+        captures: capture.list(),
+    }))
+}
+
+async fn clear_debug_requests(
+    format: Format,
+    State(state): State<AppState>,
+) -> Result<Encoded<DebugRequestsResponse>, AppError> {
+    let capture = debug_capture(&state)?;
+    capture.clear();
+    Ok(format.encode(DebugRequestsResponse {
+        captures: Vec::new(),
+    }))
+}
+
+fn debug_capture(state: &AppState) -> Result<&DebugCapture, AppError> {
+    state.debug_capture.as_deref().ok_or_else(|| {
+        AppError::NotFound(
+            "Debug capture is disabled; start the server with SYSTEMATICS_DEBUG_CAPTURE=true"
+                .to_string(),
+        )
+    })
+}
+
+async fn list_flags(format: Format, State(state): State<AppState>) -> Encoded<FlagsResponse> {
+    format.encode(FlagsResponse {
+        flags: state
+            .flags
+            .rules()
+            .into_iter()
+            .map(|(flag, rule)| FlagInfo {
+                flag,
+                description: flag.description(),
+                rule,
+            })
+            .collect(),
+    })
+}
+
+/// Turn a flag on or off, by default or for one collection or client.
+async fn update_flag(
+    format: Format,
+    State(state): State<AppState>,
+    Body(payload): Body<FlagUpdate>,
+) -> Result<Encoded<FlagInfo>, AppError> {
+    let flag = payload.flag;
+    let rule = state
+        .flags
+        .update(payload)
+        .map_err(|e| AppError::BadRequest(e.to_string()))?;
+    info!("Feature flag {} is now {:?}", flag.name(), rule);
+
+    Ok(format.encode(FlagInfo {
+        flag,
+        description: flag.description(),
+        rule,
+    }))
+}
+
+async fn tokenizer_metrics(
+    format: Format,
+    State(state): State<AppState>,
+) -> Encoded<TokenizerMetrics> {
+    format.encode(state.models.base().tokenizer_stats().snapshot())
+}
+
+/// Run the command line `cli` asks for: by default, load the models and
+/// collections and serve the HTTP API until the process is stopped.
+pub async fn run(cli: Cli) -> anyhow::Result<()> {
+    info!("Starting Systematics Embedding Server");
+
+    let config = Arc::new(Config::load(&cli)?);
+
+    if let Some(Command::Restore { id, to, from }) = &cli.command {
+        let from = from
+            .clone()
+            .unwrap_or_else(|| backup::backup_dir(&config.data_dir));
+        let restored = backup::restore_to(&from, id, to, &config).await?;
+        for info in &restored.chain {
+            println!(
+                "Applied backup {} ({} documents changed, {} deleted)",
+                info.id, info.changed, info.deleted
+            );
+        }
+        for (name, collection) in &restored.collections {
+            println!(
+                "Restored and verified collection {}: {} documents, {} aliases",
+                name,
+                collection.snapshot.documents.len(),
+                collection.snapshot.aliases.len()
+            );
+        }
+        println!("Start the server with --data-dir {:?} to use it", to);
+        return Ok(());
+    }
+
+    // `--check-migrations` reports what an upgrade would do without doing it
+    if cli.check_migrations {
+        let pending = migrations::pending(&config.data_dir)?;
+        if pending.is_empty() {
+            println!(
+                "{:?} is up to date (format {})",
+                config.data_dir,
+                migrations::FORMAT_VERSION
+            );
+        }
+        if !pending.is_empty() {
+            println!(
+                "Pending migrations (the data directory will first be copied to {:?}):",
+                config.data_dir.join(migrations::BACKUP_DIR)
+            );
+        }
+        for migration in pending {
+            println!(
+                "format {} -> {}: {}",
+                migration.from,
+                migration.from + 1,
+                migration.description
+            );
+        }
+        return Ok(());
+    }
+
+    // Held until the server exits, so a second server started against the
+    // same data directory stops here instead of corrupting it
+    let _lock = DataDirLock::acquire(
+        &config.data_dir,
+        &format!("{}:{}", config.host, config.port),
+    )?;
+    // Everything the configuration points at is checked before the model
+    // loads, so every problem is reported together
+    let serving = cli.command.is_none();
+    preflight::check_environment(&config, serving).into_result()?;
+    Spool::clear(&config.data_dir);
+    migrations::run(&config.data_dir)?;
+
+    #[cfg(feature = "chaos")]
+    if config.chaos.is_enabled() {
+        chaos::install(config.chaos.clone());
+    }
+
+    // Initialize embedding service
+    info!("Loading embedding models...");
+    let models = Arc::new(ModelRegistry::load(&config).await?);
+    info!("Embedding models loaded successfully");
+
+    // Variants registered by earlier runs are loaded again, before any
+    // collection bound to one is checked or served
+    let (model_variants, saved) = VariantRegistry::open(config.data_dir.join("variants.json"))?;
+    for info in saved {
+        if models.get(Some(&info.name)).is_some() {
+            warn!(
+                "Skipped variant {}: a served model now has its name",
+                info.name
+            );
+            continue;
+        }
+        match load_variant(&config.model, &info).await {
+            Ok(service) => model_variants.register(info, Arc::new(service)).await?,
+            Err(e) => warn!("Skipped variant {}: {}", info.name, e.message()),
+        }
+    }
+
+    let reranker = if config.reranker.is_enabled() {
+        let reranker = Reranker::new(&config.reranker, &config.model).await?;
+        info!("Reranker loaded successfully");
+        Some(Arc::new(reranker))
+    } else {
+        None
+    };
+
+    // Track retrieval-affecting config so quality changes can be traced
+    let config_changelog = ConfigChangelog::open(config.data_dir.join("config_history.jsonl"))?;
+    let config_hash = config_changelog
+        .record(RetrievalConfig::from_config(&config))
+        .await?;
+    info!("Active retrieval config: {}", config_hash);
+
+    let federation = FederationRegistry::open(
+        config.data_dir.join("peers.json"),
+        Duration::from_millis(config.federation_timeout_ms),
+    )?;
+    let templates = TemplateRegistry::open(config.data_dir.join("templates.json"))?;
+    let flags = FeatureFlags::open(config.data_dir.join("flags.json"), &config.flags)?;
+
+    // Open the default collection and any others created earlier
+    let collections = Collections::open(
+        &config.data_dir,
+        config.model.precision,
+        config.quantization,
+        config.hnsw,
+        config.storage,
+    )?;
+    let (errors, warnings) =
+        preflight::check_collections(&collections, &models, &model_variants).await;
+    preflight::warn_all(&warnings);
+    errors.into_result()?;
+
+    if let Some(Command::Pack { collection, out }) = &cli.command {
+        let name = collection.as_deref().unwrap_or(index::DEFAULT_COLLECTION);
+        let index = collections.get(Some(name)).await?;
+        let settings = collections.settings(Some(name)).await?;
+        let service = models::resolve(&models, &model_variants, settings.model.as_deref())
+            .ok_or_else(|| {
+                anyhow::anyhow!("Collection {} is bound to a model that isn't loaded", name)
+            })?;
+        let manifest = bundle::pack(&index, name, settings, service.fingerprint(), out)?;
+        println!(
+            "Packed {} documents of collection {} into {:?}",
+            manifest.documents, name, out
+        );
+        return Ok(());
+    }
+
+    // Canaries left behind by a self-test cut short are cleared out
+    let selftest_index = VectorIndex::open(
+        &config.data_dir.join(selftest::SELFTEST_DIR),
+        config.model.precision,
+        config.quantization,
+        config.hnsw,
+        config.storage,
+    )?;
+    selftest_index.clear().await?;
+
+    // Feedback recorded by earlier runs is read back from the analytics log
+    let (analytics, feedback) = if config.analytics.max_mb > 0 {
+        let (log, records) =
+            AnalyticsLog::open(&config.data_dir, config.analytics.max_mb * 1024 * 1024)?;
+        let feedback = records
+            .into_iter()
+            .filter_map(|record| match record.event {
+                AnalyticsEvent::Feedback(event) => Some(event),
+                AnalyticsEvent::Search(_) => None,
+            })
+            .collect();
+        (Some(Arc::new(log)), feedback)
+    } else {
+        (None, Vec::new())
+    };
+
+    let debug_capture = config
+        .debug_capture
+        .enabled
+        .then(|| Arc::new(DebugCapture::new(config.debug_capture.clone())));
+
+    let requests = Arc::new(RequestStats::default());
+    let state = AppState {
+        config: config.clone(),
+        models,
+        collections: Arc::new(collections),
+        feedback_log: Arc::new(FeedbackLog::with_events(feedback)),
+        analytics,
+        model_variants: Arc::new(model_variants),
+        config_changelog: Arc::new(config_changelog),
+        federation: Arc::new(federation),
+        templates: Arc::new(templates),
+        bulk_queue: Arc::new(BulkQueue::new(
+            config.max_bulk_jobs,
+            config.max_bulk_jobs_per_client,
+        )),
+        rebuild_lock: Arc::new(tokio::sync::Mutex::new(())),
+        jobs: Arc::new(Jobs::new(config.max_jobs, config.max_queued_jobs)),
+        selftest_index: Arc::new(selftest_index),
+        reranker,
+        debug_capture: debug_capture.clone(),
+        flags: Arc::new(flags),
+        requests: requests.clone(),
+        rate_limiter: config
+            .rate_limit
+            .is_enabled()
+            .then(|| Arc::new(RateLimiter::new(config.rate_limit))),
+        limits: Limits::new(&config.concurrency),
+    };
+
+    // Bundles copied into the data directory are served read-only
+    for path in bundle::discover(&config.data_dir)? {
+        if let Err(e) = mount_bundle(&state, &path) {
+            warn!("Skipped bundle {:?}: {}", path, e.message());
+        }
+    }
+
+    if config.warm_on_start {
+        let state = state.clone();
+        tokio::spawn(async move {
+            let collections = state.collections.all();
+            warm(&state, collections, state.config.hnsw.pin_entry_layers).await;
+        });
+    }
+
+    if let Some(ms) = config.hnsw.target_latency_ms {
+        tokio::spawn(tune_ef_search(
+            state.collections.clone(),
+            Duration::from_secs_f64(ms / 1000.0),
+            config.hnsw.tune_queries,
+        ));
+    }
+
+    if let Some(vault) = &config.vault.path {
+        info!("Watching vault {:?}", vault);
+        VaultWatcher::spawn(
+            config.clone(),
+            state.models.clone(),
+            state.collections.clone(),
+        )
+        .await?;
+    }
+
+    // Configure CORS for Obsidian
+    let allow_origin = if config.allowed_origins.is_empty() {
+        AllowOrigin::any()
+    } else {
+        AllowOrigin::list(
+            config
+                .allowed_origins
+                .iter()
+                .map(|origin| origin.parse().expect("validated by Config::load")),
+        )
+    };
+    let cors = CorsLayer::new()
+        .allow_origin(allow_origin)
+        .allow_methods([Method::GET, Method::POST, Method::PATCH, Method::DELETE])
+        .allow_headers([
+            header::CONTENT_TYPE,
+            header::ACCEPT,
+            header::IF_NONE_MATCH,
+            header::HeaderName::from_static("x-client-id"),
+        ])
+        .expose_headers([header::ETAG]);
+
+    // Build router
+    let api = api_routes(&state.limits);
+    let mut app = if config.route_prefix.is_empty() {
+        api.clone()
+    } else {
+        Router::new().nest(&config.route_prefix, api.clone())
+    };
+    if config.legacy_routes && !config.route_prefix.is_empty() {
+        app = app.merge(api);
+    }
+    // The UI is a page for browsers, which can't pass the hardened gate
+    if !config.hardened {
+        app = app.route("/ui", get(ui::page).with_state(config.clone()));
+    }
+    if let Some(capture) = debug_capture {
+        app = app.layer(middleware::from_fn_with_state(capture, debug::capture));
+    }
+    if let Some(limiter) = &state.rate_limiter {
+        app = app.layer(middleware::from_fn_with_state(
+            limiter.clone(),
+            ratelimit::limit,
+        ));
+    }
+    if config.hardened {
+        app = app.layer(middleware::from_fn_with_state(
+            config.clone(),
+            security::harden,
+        ));
+    }
+    if let Some(port) = config.grpc_port {
+        let addr: SocketAddr = format!("{}:{}", config.host, port).parse()?;
+        if config.tls.is_enabled() {
+            warn!("The gRPC API doesn't use TLS; keep its port private");
+        }
+        grpc::spawn(state.clone(), addr)?;
+    }
+    let app = app
+        .layer(middleware::from_fn_with_state(requests, stats::count))
+        .layer(cors)
+        .with_state(state);
+
+    // Start server
+    let tls_config = if config.tls.is_enabled() {
+        Some(tls::server_config(&config.tls)?)
+    } else {
+        None
+    };
+    let addr = format!("{}:{}", config.host, config.port);
+    let scheme = if tls_config.is_some() {
+        "https"
+    } else {
+        "http"
+    };
+    let base = format!("{}://{}{}", scheme, addr, config.route_prefix);
+    info!("Server listening on {}", addr);
+    println!("🚀 Systematics Embedding Server ready at {}", base);
+    println!("   - Health check: GET  {}/health", base);
+    println!("   - Embed text:   POST {}/embed", base);
+    println!("   - Index doc:    POST {}/index", base);
+    println!("   - Search:       POST {}/search", base);
+    println!("   - Live search:  GET  {}/ws (WebSocket)", base);
+    println!("   - Feedback:     POST {}/feedback", base);
+    if !config.hardened {
+        println!("   - Web UI:       GET  {}://{}/ui", scheme, addr);
+    }
+    if config.legacy_routes && !config.route_prefix.is_empty() {
+        println!("   (legacy unprefixed routes are also enabled)");
+    }
+    if config.hardened {
+        println!(
+            "   (hardened: local requests from {} only)",
+            config.allowed_origins.join(", ")
+        );
+    }
+    if let Some(port) = config.grpc_port {
+        println!(
+            "   - gRPC:         {}:{} (systematics.v1.Embeddings)",
+            config.host, port
+        );
+    }
+    if config.debug_capture.enabled {
+        println!(
+            "   - Debug:        GET  {}/debug/requests (capturing request bodies)",
+            base
+        );
+    }
+    if config.rate_limit.is_enabled() {
+        println!(
+            "   (rate limited to {}/s per client, bursts of {})",
+            config.rate_limit.per_second, config.rate_limit.burst
+        );
+    }
+    if config.tls.client_ca.is_some() {
+        println!("   (clients must present a certificate signed by the configured CA)");
+    }
+    if let Some(port) = config.tls.redirect_port {
+        println!(
+            "   (plain HTTP on {}:{} redirects to HTTPS)",
+            config.host, port
+        );
+    }
+
+    let listener = tokio::net::TcpListener::bind(&addr).await?;
+    if let Some(port) = config.tls.redirect_port {
+        let redirect_listener =
+            tokio::net::TcpListener::bind(format!("{}:{}", config.host, port)).await?;
+        let (host, port) = (config.host.clone(), config.port);
+        tokio::spawn(async move {
+            if let Err(e) = tls::redirect(redirect_listener, host, port).await {
+                warn!("HTTPS redirect listener stopped: {}", e);
+            }
+        });
+    }
+    let app = app.into_make_service_with_connect_info::<SocketAddr>();
+    match tls_config {
+        Some(tls_config) => tls::serve(listener, app, tls_config).await?,
+        None => axum::serve(listener, app).await?,
+    }
+
+    Ok(())
+}
+
+/// How often each collection's `ef_search` is tuned again, following the
+/// collection as it grows.
+const EF_TUNE_INTERVAL: Duration = Duration::from_secs(600);
+
+/// Keep every collection's `ef_search` tuned to a latency `budget`, from
+/// startup on.
+async fn tune_ef_search(collections: Arc<Collections>, budget: Duration, queries: usize) {
+    let mut interval = tokio::time::interval(EF_TUNE_INTERVAL);
+    loop {
+        interval.tick().await;
+        for (name, index) in collections.all() {
+            let tuned =
+                tokio::task::spawn_blocking(move || index.tune_ef_search(budget, queries)).await;
+            if let Ok(Some(tuning)) = tuned {
+                info!(
+                    "Tuned ef_search of collection {} to {} (recall@10 {:.3}, {:.2}ms per search)",
+                    name, tuning.ef_search, tuning.recall, tuning.latency_ms
+                );
+            }
+        }
+    }
+}
+
+/// All API routes, relative to the configured route prefix, with the
+/// endpoints that do heavy work limited in how many requests run at once.
+fn api_routes(limits: &Limits) -> Router<AppState> {
+    Router::new()
+        .route("/health", get(health))
+        .route("/health/selftest", get(health_selftest))
+        .route("/capabilities", get(capabilities))
+        .route("/stats", get(stats))
+        .route("/embed", limits.embed.apply(post(embed)))
+        .route("/embed/batch", limits.embed.apply(post(embed_batch)))
+        .route("/index", limits.index.apply(post(index_document)))
+        .route(
+            "/index/bulk",
+            post(index_bulk).layer(DefaultBodyLimit::max(BULK_BODY_LIMIT)),
+        )
+        .route("/index/bulk/queue", get(bulk_queue))
+        .route(
+            "/index/*id",
+            get(get_index_entry).delete(delete_index_entry),
+        )
+        .route("/search", limits.search.apply(post(search)))
+        .route("/ws", get(live::live_search))
+        .route(
+            "/search/template/:name",
+            limits.search.apply(post(search_template)),
+        )
+        .route("/search/templates", get(list_templates).post(put_template))
+        .route("/search/templates/:name", delete(remove_template))
+        .route("/documents", get(list_documents))
+        .route("/documents/get", post(get_documents))
+        .route("/documents/count", post(count_documents))
+        .route("/documents/*id", get(get_document))
+        .route("/similar/*id", limits.search.apply(get(similar_documents)))
+        .route(
+            "/collections",
+            get(list_collections).post(create_collection),
+        )
+        .route("/collections/mount", post(mount_collection))
+        .route(
+            "/collections/:name",
+            patch(update_collection).delete(delete_collection),
+        )
+        .route(
+            "/projection",
+            limits.analysis.apply(get(project_collection)),
+        )
+        .route("/digest", limits.analysis.apply(get(digest)))
+        .route("/dedupe", limits.analysis.apply(post(dedupe)))
+        .route("/cluster", limits.analysis.apply(post(cluster_documents)))
+        .route("/aliases", get(list_aliases).post(add_alias))
+        .route("/aliases/*alias", delete(remove_alias))
+        .route("/admin/verify", post(verify_index))
+        .route("/admin/rebuild", post(rebuild_index))
+        .route("/admin/reindex", post(reindex))
+        .route("/admin/jobs/:id", get(get_job))
+        .route("/admin/reconcile", post(reconcile_collection))
+        .route("/manifest", put(put_manifest))
+        .route("/jobs", get(list_jobs))
+        .route("/jobs/:id", get(get_job).delete(cancel_job))
+        .route("/admin/backups", get(list_backups).post(create_backup))
+        .route("/admin/backups/:id/verify", post(verify_backup))
+        .route("/admin/snapshot", post(export_snapshot))
+        .route("/admin/restore", post(import_snapshot))
+        .route("/admin/warm", post(warm_collections))
+        .route("/admin/flags", get(list_flags).patch(update_flag))
+        .route("/feedback", post(record_feedback))
+        .route("/feedback/export", get(export_triplets))
+        .route("/analytics/export", get(export_analytics))
+        .route(
+            "/models/variants",
+            get(list_variants).post(register_variant),
+        )
+        .route("/config/history", get(config_history))
+        .route("/metrics/tokenizer", get(tokenizer_metrics))
+        .route("/metrics/cache", get(cache_metrics))
+        .route(
+            "/debug/requests",
+            get(debug_requests).delete(clear_debug_requests),
+        )
+        .route("/federation/peers", get(list_peers).post(add_peer))
+        .route("/federation/peers/:name", delete(remove_peer))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(collection: &str, text: &str, score: f32) -> SearchResult {
+        let mut result: SearchResult = serde_json::from_value(serde_json::json!({
+            "id": format!("{}.md", collection),
+            "score": 0.0,
+            "text": text,
+            "collection": collection,
+        }))
+        .unwrap();
+        result.score = score;
+        result
+    }
+
+    #[test]
+    fn test_dedupe_by_content_keeps_the_best_copy() {
+        let results = dedupe_by_content(vec![
+            result("notes", "The  same\ntext", 0.7),
+            result("papers", "The same text", 0.9),
+            result("clippings", "Other text", f32::NAN),
+            result("drafts", "The same text", 0.2),
+        ]);
+
+        // A NaN score from a broken model sorts last, without panicking
+        let kept: Vec<_> = results
+            .iter()
+            .map(|result| (result.collection.as_deref().unwrap(), &result.duplicates))
+            .collect();
+        assert_eq!(
+            kept,
+            [
+                ("papers", &vec!["notes".to_string(), "drafts".to_string()]),
+                ("clippings", &vec![]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_merge_remote_drops_non_finite_peer_scores() {
+        let mut results = vec![result("notes", "Local text", 0.5)];
+        let remote = vec![
+            result("remote", "Broken", f32::NAN),
+            result("remote", "Overflowed", f32::INFINITY),
+            result("remote", "Remote text", 0.8),
+        ];
+        merge_remote(&mut results, remote, 3);
+
+        let texts: Vec<_> = results.iter().map(|result| result.text.as_str()).collect();
+        assert_eq!(texts, ["Remote text", "Local text"]);
+    }
+}
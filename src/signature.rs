@@ -0,0 +1,155 @@
+use anyhow::{Context, Result};
+use minisign_verify::{PublicKey, Signature};
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+/// Extension of a minisign signature, added to the name of the file it
+/// signs.
+const SIGNATURE_EXTENSION: &str = "minisig";
+
+/// Minisign public keys that downloaded models, tokenizers, and bundles
+/// must be signed by. With no keys, nothing is checked.
+pub struct TrustRoot {
+    keys: Vec<PublicKey>,
+}
+
+impl TrustRoot {
+    /// Keys in the base64 form minisign prints, e.g. `RWQf6LRC...`.
+    pub fn new(keys: &[String]) -> Result<Self> {
+        let keys = keys
+            .iter()
+            .map(|key| {
+                PublicKey::from_base64(key.trim())
+                    .map_err(|e| anyhow::anyhow!("Invalid minisign public key {:?}: {}", key, e))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { keys })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.keys.is_empty()
+    }
+
+    /// Check `path` against a minisign `signature` by one of the trusted
+    /// keys, reading the file a block at a time.
+    pub fn verify(&self, path: &Path, signature: &str) -> Result<()> {
+        let signature = Signature::decode(signature)
+            .map_err(|e| anyhow::anyhow!("Invalid signature for {:?}: {}", path, e))?;
+        let mut last_error = None;
+        for key in &self.keys {
+            let mut verifier = match key.verify_stream(&signature) {
+                Ok(verifier) => verifier,
+                Err(e) => {
+                    last_error = Some(e);
+                    continue;
+                }
+            };
+            let mut file = File::open(path)?;
+            let mut buffer = vec![0; 64 * 1024];
+            loop {
+                let read = file.read(&mut buffer)?;
+                if read == 0 {
+                    break;
+                }
+                verifier.update(&buffer[..read]);
+            }
+            match verifier.finalize() {
+                Ok(()) => return Ok(()),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        anyhow::bail!(
+            "{:?} isn't signed by a trusted key: {}",
+            path,
+            last_error.map_or_else(|| "no trusted keys".to_string(), |e| e.to_string())
+        )
+    }
+
+    /// Check `path`, downloaded from `url`, against the signature published
+    /// at `<url>.minisig`, and keep the signature next to `dest`, where the
+    /// file is about to be moved, for [`verify_cached`](Self::verify_cached).
+    pub async fn verify_download(&self, url: &str, path: &Path, dest: &Path) -> Result<()> {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+        let signature = fetch(url).await?;
+        self.verify(path, &signature)?;
+        fs::write(signature_path(dest), signature)?;
+        info!("Verified the signature of {}", url);
+        Ok(())
+    }
+
+    /// Check a file against the signature kept next to it.
+    pub fn verify_cached(&self, path: &Path) -> Result<()> {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+        let signature = fs::read_to_string(signature_path(path)).with_context(|| {
+            format!(
+                "{:?} has no signature next to it; delete it to download it again",
+                path
+            )
+        })?;
+        self.verify(path, &signature)
+    }
+}
+
+/// The signature published for `url`, at `<url>.minisig`.
+pub async fn fetch(client: &reqwest::Client, url: &str) -> Result<String> {
+    let signature_url = format!("{}.{}", url, SIGNATURE_EXTENSION);
+    let signature = client
+        .get(&signature_url)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .with_context(|| format!("No signature could be fetched from {}", signature_url))?
+        .text()
+        .await?;
+    Ok(signature)
+}
+
+/// Where the signature of `path` is kept: `<path>.minisig`.
+pub fn signature_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(SIGNATURE_EXTENSION);
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Test vector from minisign-verify: the four bytes `test`
+    const PUBLIC_KEY: &str = "RWQf6LRCGA9i53mlYecO4IzT51TGPpvWucNSCh1CBM0QTaLn73Y7GFO3";
+    const SIGNATURE: &str = "untrusted comment: signature from minisign secret key
+RUQf6LRCGA9i559r3g7V1qNyJDApGip8MfqcadIgT9CuhV3EMhHoN1mGTkUidF/z7SrlQgXdy8ofjb7bNJJylDOocrCo8KLzZwo=
+trusted comment: timestamp:1556193335\tfile:test
+y/rUw2y8/hOUYjZU71eHp/Wo1KZ40fGy2VJEDl34XMJM+TX48Ss/17u3IvIfbVR1FkZZSNCisQbuQY+bHwhEBg==";
+
+    #[test]
+    fn test_only_files_signed_by_a_trusted_key_verify() {
+        let dir = std::env::temp_dir().join(format!("systematics-sig-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("model.onnx");
+        fs::write(&path, b"test").unwrap();
+
+        let trusted = TrustRoot::new(&[PUBLIC_KEY.to_string()]).unwrap();
+        trusted.verify(&path, SIGNATURE).unwrap();
+
+        // No signature kept yet, then one that matches
+        assert!(trusted.verify_cached(&path).is_err());
+        fs::write(signature_path(&path), SIGNATURE).unwrap();
+        trusted.verify_cached(&path).unwrap();
+
+        fs::write(&path, b"Test").unwrap();
+        assert!(trusted.verify(&path, SIGNATURE).is_err());
+        assert!(TrustRoot::new(&["not a key".to_string()]).is_err());
+        // Without keys nothing is checked
+        TrustRoot::new(&[]).unwrap().verify_cached(&path).unwrap();
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,388 @@
+//! Dot products and cosine similarity over `f32` slices, the inner loop of
+//! every search. AVX2 with FMA is used on x86_64 CPUs that have it and NEON
+//! on aarch64; elsewhere a portable loop with independent lanes that the
+//! compiler can vectorize on its own.
+
+use std::sync::OnceLock;
+
+/// Vectors whose squared length is this close to 1 count as unit length
+/// and are left as they are by [`normalize`]. Model output is well within
+/// it, so embeddings stored earlier keep their exact values.
+const UNIT_TOLERANCE: f32 = 1e-3;
+
+/// An implementation of the kernels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kernel {
+    Scalar,
+    #[cfg(target_arch = "x86_64")]
+    Avx2,
+    #[cfg(target_arch = "aarch64")]
+    Neon,
+}
+
+impl Kernel {
+    fn is_supported(self) -> bool {
+        match self {
+            Kernel::Scalar => true,
+            #[cfg(target_arch = "x86_64")]
+            Kernel::Avx2 => is_x86_feature_detected!("avx2") && is_x86_feature_detected!("fma"),
+            #[cfg(target_arch = "aarch64")]
+            Kernel::Neon => std::arch::is_aarch64_feature_detected!("neon"),
+        }
+    }
+}
+
+/// The fastest kernel this CPU supports, detected once.
+pub fn kernel() -> Kernel {
+    static KERNEL: OnceLock<Kernel> = OnceLock::new();
+    *KERNEL.get_or_init(|| {
+        available()
+            .pop()
+            .expect("the scalar kernel is always available")
+    })
+}
+
+/// Every kernel this CPU supports, slowest first.
+pub fn available() -> Vec<Kernel> {
+    let all = [
+        Kernel::Scalar,
+        #[cfg(target_arch = "x86_64")]
+        Kernel::Avx2,
+        #[cfg(target_arch = "aarch64")]
+        Kernel::Neon,
+    ];
+    all.into_iter()
+        .filter(|kernel| kernel.is_supported())
+        .collect()
+}
+
+/// Dot product of two vectors of the same length.
+pub fn dot(a: &[f32], b: &[f32]) -> f32 {
+    assert_eq!(a.len(), b.len(), "Vectors must have same length");
+    // SAFETY: `kernel` only returns kernels the CPU supports
+    unsafe { dot_unchecked(kernel(), a, b) }
+}
+
+/// Cosine similarity of two vectors of the same length, 0 if either is
+/// all zeros. Computed in one pass over both.
+pub fn cosine(a: &[f32], b: &[f32]) -> f32 {
+    let (dot, norm_a, norm_b) = dot_and_norms(a, b);
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+    dot / (norm_a.sqrt() * norm_b.sqrt())
+}
+
+/// The dot product of `a` and `b` and the squared length of each.
+pub fn dot_and_norms(a: &[f32], b: &[f32]) -> (f32, f32, f32) {
+    assert_eq!(a.len(), b.len(), "Vectors must have same length");
+    // SAFETY: `kernel` only returns kernels the CPU supports
+    unsafe { dot_and_norms_unchecked(kernel(), a, b) }
+}
+
+/// [`dot`] with a particular kernel, for comparing them.
+pub fn dot_with(kernel: Kernel, a: &[f32], b: &[f32]) -> f32 {
+    assert_eq!(a.len(), b.len(), "Vectors must have same length");
+    assert!(kernel.is_supported(), "{:?} isn't supported here", kernel);
+    // SAFETY: checked just above
+    unsafe { dot_unchecked(kernel, a, b) }
+}
+
+/// [`cosine`] with a particular kernel, for comparing them.
+pub fn cosine_with(kernel: Kernel, a: &[f32], b: &[f32]) -> f32 {
+    assert_eq!(a.len(), b.len(), "Vectors must have same length");
+    assert!(kernel.is_supported(), "{:?} isn't supported here", kernel);
+    // SAFETY: checked just above
+    let (dot, norm_a, norm_b) = unsafe { dot_and_norms_unchecked(kernel, a, b) };
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+    dot / (norm_a.sqrt() * norm_b.sqrt())
+}
+
+/// Scale `values` to unit length, so comparing them with another unit
+/// vector is a plain [`dot`] product. Vectors already within
+/// [`UNIT_TOLERANCE`] of it, and zero vectors, are left unchanged.
+pub fn normalize(values: &mut [f32]) {
+    let norm = dot(values, values);
+    if norm == 0.0 || (norm - 1.0).abs() <= UNIT_TOLERANCE {
+        return;
+    }
+    let scale = 1.0 / norm.sqrt();
+    for value in values {
+        *value *= scale;
+    }
+}
+
+/// # Safety
+///
+/// The CPU must support `kernel`, and `a` and `b` must have equal lengths.
+unsafe fn dot_unchecked(kernel: Kernel, a: &[f32], b: &[f32]) -> f32 {
+    match kernel {
+        Kernel::Scalar => scalar::dot(a, b),
+        #[cfg(target_arch = "x86_64")]
+        Kernel::Avx2 => x86::dot(a, b),
+        #[cfg(target_arch = "aarch64")]
+        Kernel::Neon => neon::dot(a, b),
+    }
+}
+
+/// # Safety
+///
+/// The CPU must support `kernel`, and `a` and `b` must have equal lengths.
+unsafe fn dot_and_norms_unchecked(kernel: Kernel, a: &[f32], b: &[f32]) -> (f32, f32, f32) {
+    match kernel {
+        Kernel::Scalar => scalar::dot_and_norms(a, b),
+        #[cfg(target_arch = "x86_64")]
+        Kernel::Avx2 => x86::dot_and_norms(a, b),
+        #[cfg(target_arch = "aarch64")]
+        Kernel::Neon => neon::dot_and_norms(a, b),
+    }
+}
+
+mod scalar {
+    const LANES: usize = 8;
+
+    pub fn dot(a: &[f32], b: &[f32]) -> f32 {
+        let (a_blocks, b_blocks) = (a.chunks_exact(LANES), b.chunks_exact(LANES));
+        let mut sum: f32 = a_blocks
+            .remainder()
+            .iter()
+            .zip(b_blocks.remainder())
+            .map(|(x, y)| x * y)
+            .sum();
+
+        let mut lanes = [0.0f32; LANES];
+        for (x, y) in a_blocks.zip(b_blocks) {
+            for ((lane, x), y) in lanes.iter_mut().zip(x).zip(y) {
+                *lane += x * y;
+            }
+        }
+        sum += lanes.iter().sum::<f32>();
+        sum
+    }
+
+    pub fn dot_and_norms(a: &[f32], b: &[f32]) -> (f32, f32, f32) {
+        let (a_blocks, b_blocks) = (a.chunks_exact(LANES), b.chunks_exact(LANES));
+        let (mut dot, mut norm_a, mut norm_b) = (0.0f32, 0.0f32, 0.0f32);
+        for (x, y) in a_blocks.remainder().iter().zip(b_blocks.remainder()) {
+            dot += x * y;
+            norm_a += x * x;
+            norm_b += y * y;
+        }
+
+        let mut lanes = [[0.0f32; LANES]; 3];
+        for (x, y) in a_blocks.zip(b_blocks) {
+            for (i, (x, y)) in x.iter().zip(y).enumerate() {
+                lanes[0][i] += x * y;
+                lanes[1][i] += x * x;
+                lanes[2][i] += y * y;
+            }
+        }
+        dot += lanes[0].iter().sum::<f32>();
+        norm_a += lanes[1].iter().sum::<f32>();
+        norm_b += lanes[2].iter().sum::<f32>();
+        (dot, norm_a, norm_b)
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+mod x86 {
+    use std::arch::x86_64::*;
+
+    /// # Safety
+    ///
+    /// The CPU must support AVX2 and FMA, and `a` and `b` must have equal
+    /// lengths.
+    #[target_feature(enable = "avx2,fma")]
+    pub unsafe fn dot(a: &[f32], b: &[f32]) -> f32 {
+        let n = a.len();
+        let (a, b) = (a.as_ptr(), b.as_ptr());
+        // Two accumulators hide the latency of the fused multiply-adds
+        let mut acc0 = _mm256_setzero_ps();
+        let mut acc1 = _mm256_setzero_ps();
+        let mut i = 0;
+        while i + 16 <= n {
+            acc0 = _mm256_fmadd_ps(_mm256_loadu_ps(a.add(i)), _mm256_loadu_ps(b.add(i)), acc0);
+            acc1 = _mm256_fmadd_ps(
+                _mm256_loadu_ps(a.add(i + 8)),
+                _mm256_loadu_ps(b.add(i + 8)),
+                acc1,
+            );
+            i += 16;
+        }
+        if i + 8 <= n {
+            acc0 = _mm256_fmadd_ps(_mm256_loadu_ps(a.add(i)), _mm256_loadu_ps(b.add(i)), acc0);
+            i += 8;
+        }
+
+        let mut sum = horizontal_sum(_mm256_add_ps(acc0, acc1));
+        while i < n {
+            sum += *a.add(i) * *b.add(i);
+            i += 1;
+        }
+        sum
+    }
+
+    /// # Safety
+    ///
+    /// The CPU must support AVX2 and FMA, and `a` and `b` must have equal
+    /// lengths.
+    #[target_feature(enable = "avx2,fma")]
+    pub unsafe fn dot_and_norms(a: &[f32], b: &[f32]) -> (f32, f32, f32) {
+        let n = a.len();
+        let (a, b) = (a.as_ptr(), b.as_ptr());
+        let mut dot = _mm256_setzero_ps();
+        let mut norm_a = _mm256_setzero_ps();
+        let mut norm_b = _mm256_setzero_ps();
+        let mut i = 0;
+        while i + 8 <= n {
+            let x = _mm256_loadu_ps(a.add(i));
+            let y = _mm256_loadu_ps(b.add(i));
+            dot = _mm256_fmadd_ps(x, y, dot);
+            norm_a = _mm256_fmadd_ps(x, x, norm_a);
+            norm_b = _mm256_fmadd_ps(y, y, norm_b);
+            i += 8;
+        }
+
+        let mut sums = (
+            horizontal_sum(dot),
+            horizontal_sum(norm_a),
+            horizontal_sum(norm_b),
+        );
+        while i < n {
+            let (x, y) = (*a.add(i), *b.add(i));
+            sums.0 += x * y;
+            sums.1 += x * x;
+            sums.2 += y * y;
+            i += 1;
+        }
+        sums
+    }
+
+    #[target_feature(enable = "avx")]
+    unsafe fn horizontal_sum(v: __m256) -> f32 {
+        let sum = _mm_add_ps(_mm256_castps256_ps128(v), _mm256_extractf128_ps(v, 1));
+        let sum = _mm_add_ps(sum, _mm_movehl_ps(sum, sum));
+        let sum = _mm_add_ss(sum, _mm_shuffle_ps(sum, sum, 0b01));
+        _mm_cvtss_f32(sum)
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+mod neon {
+    use std::arch::aarch64::*;
+
+    /// # Safety
+    ///
+    /// The CPU must support NEON, and `a` and `b` must have equal lengths.
+    #[target_feature(enable = "neon")]
+    pub unsafe fn dot(a: &[f32], b: &[f32]) -> f32 {
+        let n = a.len();
+        let (a, b) = (a.as_ptr(), b.as_ptr());
+        let mut acc0 = vdupq_n_f32(0.0);
+        let mut acc1 = vdupq_n_f32(0.0);
+        let mut i = 0;
+        while i + 8 <= n {
+            acc0 = vfmaq_f32(acc0, vld1q_f32(a.add(i)), vld1q_f32(b.add(i)));
+            acc1 = vfmaq_f32(acc1, vld1q_f32(a.add(i + 4)), vld1q_f32(b.add(i + 4)));
+            i += 8;
+        }
+        if i + 4 <= n {
+            acc0 = vfmaq_f32(acc0, vld1q_f32(a.add(i)), vld1q_f32(b.add(i)));
+            i += 4;
+        }
+
+        let mut sum = vaddvq_f32(vaddq_f32(acc0, acc1));
+        while i < n {
+            sum += *a.add(i) * *b.add(i);
+            i += 1;
+        }
+        sum
+    }
+
+    /// # Safety
+    ///
+    /// The CPU must support NEON, and `a` and `b` must have equal lengths.
+    #[target_feature(enable = "neon")]
+    pub unsafe fn dot_and_norms(a: &[f32], b: &[f32]) -> (f32, f32, f32) {
+        let n = a.len();
+        let (a, b) = (a.as_ptr(), b.as_ptr());
+        let mut dot = vdupq_n_f32(0.0);
+        let mut norm_a = vdupq_n_f32(0.0);
+        let mut norm_b = vdupq_n_f32(0.0);
+        let mut i = 0;
+        while i + 4 <= n {
+            let x = vld1q_f32(a.add(i));
+            let y = vld1q_f32(b.add(i));
+            dot = vfmaq_f32(dot, x, y);
+            norm_a = vfmaq_f32(norm_a, x, x);
+            norm_b = vfmaq_f32(norm_b, y, y);
+            i += 4;
+        }
+
+        let mut sums = (vaddvq_f32(dot), vaddvq_f32(norm_a), vaddvq_f32(norm_b));
+        while i < n {
+            let (x, y) = (*a.add(i), *b.add(i));
+            sums.0 += x * y;
+            sums.1 += x * x;
+            sums.2 += y * y;
+            i += 1;
+        }
+        sums
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kernels_agree_with_each_other() {
+        let mut state = 0x2545_f491_u32;
+        let mut random = |len: usize| -> Vec<f32> {
+            (0..len)
+                .map(|_| {
+                    state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                    (state >> 8) as f32 / (1u32 << 24) as f32 - 0.5
+                })
+                .collect()
+        };
+
+        // Lengths around every block size, including empty and tails
+        for len in [0, 1, 3, 4, 7, 8, 9, 15, 16, 17, 31, 384, 1023] {
+            let (a, b) = (random(len), random(len));
+            let expected_dot: f32 = a.iter().zip(&b).map(|(x, y)| x * y).sum();
+            for kernel in available() {
+                let dot = dot_with(kernel, &a, &b);
+                assert!(
+                    (dot - expected_dot).abs() < 1e-3,
+                    "{:?} dot of length {}: {} vs {}",
+                    kernel,
+                    len,
+                    dot,
+                    expected_dot
+                );
+                let cosine = cosine_with(kernel, &a, &b);
+                let expected = cosine_with(Kernel::Scalar, &a, &b);
+                assert!((cosine - expected).abs() < 1e-5, "{:?} cosine", kernel);
+            }
+        }
+    }
+
+    #[test]
+    fn test_normalize() {
+        let mut values = vec![3.0, 4.0];
+        normalize(&mut values);
+        assert!((dot(&values, &values) - 1.0).abs() < 1e-6);
+        assert!((cosine(&values, &[3.0, 4.0]) - 1.0).abs() < 1e-6);
+
+        // Unit and zero vectors keep their exact values
+        let mut unit = vec![0.6, 0.8];
+        normalize(&mut unit);
+        assert_eq!(unit, vec![0.6, 0.8]);
+        let mut zero = vec![0.0; 3];
+        normalize(&mut zero);
+        assert_eq!(zero, vec![0.0; 3]);
+    }
+}
@@ -0,0 +1,408 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+use crate::codec::NdjsonLines;
+use crate::config::Precision;
+use crate::error::AppError;
+use crate::index::{Chunk, CollectionSettings, Collections, IndexedDocument};
+use crate::vector::StoredVector;
+
+/// Version of the snapshot archive layout. Archives written by a newer
+/// build are refused rather than misread.
+pub const SNAPSHOT_FORMAT: u32 = 1;
+
+/// Documents read from an index at a time while exporting, bounding how
+/// many are held in memory.
+const EXPORT_PAGE: usize = 256;
+
+/// One line of a snapshot archive: a header, then every document, then an
+/// end marker so a truncated archive is caught.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Record {
+    Header(Header),
+    Document {
+        collection: String,
+        #[serde(flatten)]
+        document: ArchivedDocument,
+    },
+    End {
+        documents: usize,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Header {
+    pub format: u32,
+    pub created_at: DateTime<Utc>,
+    pub collections: Vec<ArchivedCollection>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ArchivedCollection {
+    pub name: String,
+    pub settings: CollectionSettings,
+    /// Fingerprint of the model the collection's vectors were made by
+    pub model_fingerprint: String,
+    pub aliases: HashMap<String, String>,
+}
+
+/// A document with its vectors at full precision, whatever the exporting
+/// index stored them as, so the importing one can store them its own way.
+#[derive(Serialize, Deserialize)]
+pub struct ArchivedDocument {
+    pub id: String,
+    pub text: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<Value>,
+    pub version: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub updated_at: Option<DateTime<Utc>>,
+    pub embedding: Vec<f32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub chunks: Vec<ArchivedChunk>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ArchivedChunk {
+    pub start: usize,
+    pub end: usize,
+    pub embedding: Vec<f32>,
+}
+
+impl From<IndexedDocument> for ArchivedDocument {
+    fn from(doc: IndexedDocument) -> Self {
+        Self {
+            embedding: doc.embedding.to_f32().into_owned(),
+            chunks: doc
+                .chunks
+                .into_iter()
+                .map(|chunk| ArchivedChunk {
+                    start: chunk.start,
+                    end: chunk.end,
+                    embedding: chunk.embedding.to_f32().into_owned(),
+                })
+                .collect(),
+            id: doc.id,
+            text: doc.text,
+            metadata: doc.metadata,
+            version: doc.version,
+            updated_at: doc.updated_at,
+        }
+    }
+}
+
+impl From<ArchivedDocument> for IndexedDocument {
+    fn from(doc: ArchivedDocument) -> Self {
+        Self {
+            id: doc.id,
+            embedding: StoredVector::new(doc.embedding, Precision::F32),
+            text: doc.text,
+            metadata: doc.metadata,
+            version: doc.version,
+            updated_at: doc.updated_at,
+            chunks: doc
+                .chunks
+                .into_iter()
+                .map(|chunk| Chunk {
+                    start: chunk.start,
+                    end: chunk.end,
+                    embedding: StoredVector::new(chunk.embedding, Precision::F32),
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct RestoredCollection {
+    pub name: String,
+    pub documents: usize,
+    pub aliases: usize,
+}
+
+#[derive(Serialize)]
+pub struct RestoreReport {
+    pub format: u32,
+    pub created_at: DateTime<Utc>,
+    pub collections: Vec<RestoredCollection>,
+}
+
+/// Stream every collection as NDJSON archive lines, a page of documents at
+/// a time. `fingerprints` holds each collection's model fingerprint. The
+/// server keeps running meanwhile, so documents changed during the export
+/// may or may not be in it. A failure ends the stream without the end
+/// record.
+pub fn export(
+    collections: Arc<Collections>,
+    fingerprints: BTreeMap<String, String>,
+) -> mpsc::Receiver<Vec<u8>> {
+    let (lines, receiver) = mpsc::channel(EXPORT_PAGE);
+    tokio::spawn(async move {
+        if let Err(e) = write_archive(&collections, fingerprints, &lines).await {
+            warn!("Snapshot export stopped: {}", e);
+        }
+    });
+    receiver
+}
+
+async fn write_archive(
+    collections: &Collections,
+    fingerprints: BTreeMap<String, String>,
+    lines: &mpsc::Sender<Vec<u8>>,
+) -> anyhow::Result<()> {
+    let all = collections.writable();
+    let mut header = Header {
+        format: SNAPSHOT_FORMAT,
+        created_at: Utc::now(),
+        collections: Vec::with_capacity(all.len()),
+    };
+    for (name, index) in &all {
+        header.collections.push(ArchivedCollection {
+            settings: collections.settings(Some(name)).await?,
+            model_fingerprint: fingerprints.get(name).cloned().unwrap_or_default(),
+            aliases: index.aliases().await,
+            name: name.clone(),
+        });
+    }
+    send(lines, &Record::Header(header)).await?;
+
+    let mut documents = 0;
+    for (name, index) in all {
+        let mut ids = index.read_contents(|docs, _, _| docs.keys().cloned().collect::<Vec<_>>());
+        ids.sort();
+        for page in ids.chunks(EXPORT_PAGE) {
+            // Documents deleted since the ids were read are skipped
+            for doc in index.get_many(page).await?.into_iter().flatten() {
+                let record = Record::Document {
+                    collection: name.clone(),
+                    document: doc.into(),
+                };
+                send(lines, &record).await?;
+                documents += 1;
+            }
+        }
+    }
+    send(lines, &Record::End { documents }).await?;
+    info!("Exported a snapshot of {} documents", documents);
+    Ok(())
+}
+
+async fn send(lines: &mpsc::Sender<Vec<u8>>, record: &Record) -> anyhow::Result<()> {
+    let mut line = serde_json::to_vec(record)?;
+    line.push(b'\n');
+    lines
+        .send(line)
+        .await
+        .map_err(|_| anyhow::anyhow!("the client disconnected"))
+}
+
+/// Read an archive's header, refusing archives from a newer format.
+pub async fn read_header(lines: &mut NdjsonLines) -> Result<Header, AppError> {
+    match next_record(lines).await? {
+        Some(Record::Header(header)) if header.format > SNAPSHOT_FORMAT => {
+            Err(AppError::BadRequest(format!(
+                "Snapshot format {} is newer than this build supports ({})",
+                header.format, SNAPSHOT_FORMAT
+            )))
+        }
+        Some(Record::Header(header)) => Ok(header),
+        _ => Err(AppError::BadRequest(
+            "Not a snapshot archive: expected a header first".to_string(),
+        )),
+    }
+}
+
+/// Load the rest of an archive whose header has been read and checked.
+/// Every collection it names is created if missing and given its settings;
+/// with `replace`, documents already in them are deleted first. Documents
+/// are indexed as they stream in, so an archive that turns out truncated
+/// or malformed leaves those before the fault restored.
+pub async fn restore(
+    lines: &mut NdjsonLines,
+    header: Header,
+    collections: &Collections,
+    replace: bool,
+) -> Result<RestoreReport, AppError> {
+    let mut indexes = HashMap::new();
+    let mut restored = Vec::with_capacity(header.collections.len());
+    for archived in &header.collections {
+        let index = match collections.get(Some(&archived.name)).await {
+            Ok(index) => index,
+            Err(_) => collections.create(&archived.name).await?,
+        };
+        if replace {
+            index.clear().await?;
+        }
+        collections
+            .update_settings(&archived.name, archived.settings.clone())
+            .await?;
+        indexes.insert(archived.name.clone(), (index, restored.len()));
+        restored.push(RestoredCollection {
+            name: archived.name.clone(),
+            documents: 0,
+            aliases: 0,
+        });
+    }
+
+    let mut documents = 0;
+    loop {
+        match next_record(lines).await? {
+            Some(Record::Document {
+                collection,
+                document,
+            }) => {
+                let Some((index, position)) = indexes.get(&collection) else {
+                    return Err(AppError::BadRequest(format!(
+                        "Document {} belongs to collection {}, which the header doesn't list",
+                        document.id, collection
+                    )));
+                };
+                index.restore(document.into()).await?;
+                restored[*position].documents += 1;
+                documents += 1;
+            }
+            Some(Record::End {
+                documents: expected,
+            }) if expected == documents => break,
+            Some(Record::End {
+                documents: expected,
+            }) => {
+                return Err(AppError::BadRequest(format!(
+                    "Snapshot lists {} documents but held {}",
+                    expected, documents
+                )))
+            }
+            Some(Record::Header(_)) => {
+                return Err(AppError::BadRequest(
+                    "Snapshot has a second header".to_string(),
+                ))
+            }
+            None => {
+                return Err(AppError::BadRequest(format!(
+                    "Snapshot ended after {} documents without its end record; \
+                     it was truncated",
+                    documents
+                )))
+            }
+        }
+    }
+
+    // Aliases point at documents, so they go in once those are all back
+    for archived in header.collections {
+        let (index, position) = &indexes[&archived.name];
+        for (alias, id) in archived.aliases {
+            index.add_alias(&alias, &id).await?;
+            restored[*position].aliases += 1;
+        }
+    }
+    info!("Restored a snapshot of {} documents", documents);
+
+    Ok(RestoreReport {
+        format: header.format,
+        created_at: header.created_at,
+        collections: restored,
+    })
+}
+
+async fn next_record(lines: &mut NdjsonLines) -> Result<Option<Record>, AppError> {
+    let Some(line) = lines.next_line().await else {
+        return Ok(None);
+    };
+    serde_json::from_slice(&line?)
+        .map(Some)
+        .map_err(|e| AppError::BadRequest(format!("Malformed snapshot record: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{HnswConfig, QuantizationConfig, StorageConfig};
+
+    fn open(dir: &std::path::Path) -> Arc<Collections> {
+        Arc::new(
+            Collections::open(
+                dir,
+                Precision::F32,
+                QuantizationConfig::default(),
+                HnswConfig::default(),
+                StorageConfig::default(),
+            )
+            .unwrap(),
+        )
+    }
+
+    fn lines(archive: &[u8]) -> NdjsonLines {
+        NdjsonLines::new(axum::body::Body::from(archive.to_vec()))
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_round_trip() {
+        let dir = std::env::temp_dir().join(format!("systematics-snapshot-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let source = open(&dir.join("source"));
+        let work = source.create("work").await.unwrap();
+        work.add("a.md", vec![1.0, 0.0], "alpha".to_string(), None)
+            .await
+            .unwrap();
+        work.add("a.md", vec![0.8, 0.6], "alpha, edited".to_string(), None)
+            .await
+            .unwrap();
+        work.add_alias("old-a.md", "a.md").await.unwrap();
+        let default = source.get(None).await.unwrap();
+        default
+            .add("b.md", vec![0.0, 1.0], "beta".to_string(), None)
+            .await
+            .unwrap();
+
+        let fingerprints = [("default", "m"), ("work", "m")]
+            .map(|(name, fingerprint)| (name.to_string(), fingerprint.to_string()))
+            .into();
+        let mut receiver = export(source.clone(), fingerprints);
+        let mut archive = Vec::new();
+        while let Some(line) = receiver.recv().await {
+            archive.extend(line);
+        }
+
+        let target = open(&dir.join("target"));
+        let mut input = lines(&archive);
+        let header = read_header(&mut input).await.unwrap();
+        assert_eq!(header.collections.len(), 2);
+        let report = restore(&mut input, header, &target, false).await.unwrap();
+        assert_eq!(report.collections[1].documents, 1);
+        assert_eq!(report.collections[1].aliases, 1);
+
+        let work = target.get(Some("work")).await.unwrap();
+        let doc = work.get("old-a.md").await.unwrap().unwrap();
+        assert_eq!(doc.text, "alpha, edited");
+        assert_eq!(doc.version, 2);
+        assert_eq!(doc.embedding.to_f32().as_ref(), &[0.8, 0.6]);
+        assert_eq!(target.get(None).await.unwrap().count().await, 1);
+
+        // An archive cut short is reported, not taken as complete
+        let cut = archive.len()
+            - archive
+                .iter()
+                .rev()
+                .skip(1)
+                .position(|&b| b == b'\n')
+                .unwrap();
+        let mut input = lines(&archive[..cut]);
+        let header = read_header(&mut input).await.unwrap();
+        assert!(restore(&mut input, header, &target, true).await.is_err());
+
+        let newer =
+            String::from_utf8(archive)
+                .unwrap()
+                .replacen("\"format\":1", "\"format\":99", 1);
+        assert!(read_header(&mut lines(newer.as_bytes())).await.is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
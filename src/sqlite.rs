@@ -0,0 +1,371 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+use tracing::info;
+
+use crate::config::FsyncPolicy;
+use crate::index::IndexedDocument;
+use crate::storage::{LogRecord, ReplayRecord, Snapshot, VectorStore};
+use crate::vector::ProductQuantizer;
+
+/// Database file within a collection's directory.
+pub const DATABASE_FILE: &str = "index.db";
+
+/// Documents are rows with their metadata as JSON and their vectors as
+/// MessagePack blobs. `documents_fts` indexes the text with FTS5 and is
+/// kept in step by triggers, so the file can be searched by keyword with
+/// nothing but `sqlite3`.
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS documents (
+    id TEXT PRIMARY KEY,
+    text TEXT NOT NULL,
+    metadata TEXT,
+    embedding BLOB NOT NULL,
+    chunks BLOB,
+    version INTEGER NOT NULL DEFAULT 0,
+    updated_at TEXT
+);
+CREATE VIRTUAL TABLE IF NOT EXISTS documents_fts
+    USING fts5(text, content='documents', content_rowid='rowid');
+CREATE TRIGGER IF NOT EXISTS documents_ai AFTER INSERT ON documents BEGIN
+    INSERT INTO documents_fts(rowid, text) VALUES (new.rowid, new.text);
+END;
+CREATE TRIGGER IF NOT EXISTS documents_ad AFTER DELETE ON documents BEGIN
+    INSERT INTO documents_fts(documents_fts, rowid, text) VALUES ('delete', old.rowid, old.text);
+END;
+CREATE TRIGGER IF NOT EXISTS documents_au AFTER UPDATE ON documents BEGIN
+    INSERT INTO documents_fts(documents_fts, rowid, text) VALUES ('delete', old.rowid, old.text);
+    INSERT INTO documents_fts(rowid, text) VALUES (new.rowid, new.text);
+END;
+CREATE TABLE IF NOT EXISTS aliases (
+    alias TEXT PRIMARY KEY,
+    id TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS quantizer (
+    id INTEGER PRIMARY KEY CHECK (id = 0),
+    centroids BLOB NOT NULL
+);
+";
+
+/// A single-file store: every mutation is written straight to a SQLite
+/// database, so there is no log to replay and no snapshot to compact.
+pub struct SqliteStore {
+    connection: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    /// Open or create the database at `path`, returning it with everything
+    /// stored in it. SQLite syncs its own journal: on every commit under
+    /// [`FsyncPolicy::Always`], at checkpoints under `Interval`, and never
+    /// under `Never`.
+    pub fn open(path: &Path, fsync: FsyncPolicy) -> Result<(Self, Snapshot)> {
+        let connection = Connection::open(path)
+            .with_context(|| format!("Failed to open database {:?}", path))?;
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        let synchronous = match fsync {
+            FsyncPolicy::Always => "FULL",
+            FsyncPolicy::Interval => "NORMAL",
+            FsyncPolicy::Never => "OFF",
+        };
+        connection.pragma_update(None, "synchronous", synchronous)?;
+        connection.execute_batch(SCHEMA)?;
+
+        let store = Self {
+            connection: Mutex::new(connection),
+        };
+        let snapshot = store.load()?;
+        Ok((store, snapshot))
+    }
+
+    /// Read every document, alias, and the quantizer.
+    pub fn load(&self) -> Result<Snapshot> {
+        let connection = self.connection.lock().unwrap();
+
+        let mut statement = connection.prepare(
+            "SELECT id, text, metadata, embedding, chunks, version, updated_at FROM documents",
+        )?;
+        let documents = statement
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, Option<String>>(2)?,
+                    row.get::<_, Vec<u8>>(3)?,
+                    row.get::<_, Option<Vec<u8>>>(4)?,
+                    row.get::<_, i64>(5)?,
+                    row.get::<_, Option<String>>(6)?,
+                ))
+            })?
+            .map(|row| {
+                let (id, text, metadata, embedding, chunks, version, updated_at) = row?;
+                Ok(IndexedDocument {
+                    embedding: rmp_serde::from_slice(&embedding)
+                        .with_context(|| format!("Corrupt embedding for {}", id))?,
+                    chunks: match chunks {
+                        Some(chunks) => rmp_serde::from_slice(&chunks)
+                            .with_context(|| format!("Corrupt chunks for {}", id))?,
+                        None => Vec::new(),
+                    },
+                    metadata: metadata.as_deref().map(serde_json::from_str).transpose()?,
+                    version: version as u64,
+                    updated_at: updated_at
+                        .as_deref()
+                        .map(DateTime::parse_from_rfc3339)
+                        .transpose()?
+                        .map(|time| time.with_timezone(&Utc)),
+                    id,
+                    text,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let aliases = connection
+            .prepare("SELECT alias, id FROM aliases")?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<HashMap<String, String>>>()?;
+
+        let quantizer = connection
+            .query_row("SELECT centroids FROM quantizer WHERE id = 0", [], |row| {
+                row.get::<_, Vec<u8>>(0)
+            })
+            .optional()?
+            .map(|centroids| rmp_serde::from_slice(&centroids))
+            .transpose()
+            .context("Corrupt quantizer")?;
+
+        Ok(Snapshot {
+            documents,
+            aliases,
+            quantizer,
+        })
+    }
+
+    /// Replace the database's contents with a snapshot and the records
+    /// logged after it, in one transaction.
+    pub fn import(&self, snapshot: &Snapshot, records: &[ReplayRecord]) -> Result<()> {
+        let mut connection = self.connection.lock().unwrap();
+        let tx = connection.transaction()?;
+        replace_all(
+            &tx,
+            &snapshot.documents.iter().collect::<Vec<_>>(),
+            &snapshot.aliases,
+            snapshot.quantizer.as_ref(),
+        )?;
+        for record in records {
+            apply(&tx, &record.as_log_record())?;
+        }
+        tx.commit()?;
+        info!(
+            "Imported {} documents and {} log records",
+            snapshot.documents.len(),
+            records.len()
+        );
+        Ok(())
+    }
+}
+
+impl VectorStore for SqliteStore {
+    fn append(&self, record: &LogRecord) -> Result<bool> {
+        let mut connection = self.connection.lock().unwrap();
+        let tx = connection.transaction()?;
+        apply(&tx, record)?;
+        tx.commit()?;
+        Ok(false)
+    }
+
+    /// Rewrite every row, e.g. once vectors have been quantized.
+    fn snapshot(
+        &self,
+        documents: &[&IndexedDocument],
+        aliases: &HashMap<String, String>,
+        quantizer: Option<&ProductQuantizer>,
+    ) -> Result<()> {
+        let mut connection = self.connection.lock().unwrap();
+        let tx = connection.transaction()?;
+        replace_all(&tx, documents, aliases, quantizer)?;
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+fn apply(tx: &Transaction, record: &LogRecord) -> Result<()> {
+    match record {
+        LogRecord::Put(doc) => put(tx, doc)?,
+        LogRecord::Delete(id) => {
+            tx.execute("DELETE FROM documents WHERE id = ?1", [id])?;
+            tx.execute("DELETE FROM aliases WHERE id = ?1", [id])?;
+        }
+        LogRecord::Clear => {
+            tx.execute("DELETE FROM documents", [])?;
+            tx.execute("DELETE FROM aliases", [])?;
+        }
+        LogRecord::Alias { alias, id } => {
+            tx.execute(
+                "INSERT OR REPLACE INTO aliases (alias, id) VALUES (?1, ?2)",
+                [alias, id],
+            )?;
+        }
+        LogRecord::Unalias(alias) => {
+            tx.execute("DELETE FROM aliases WHERE alias = ?1", [alias])?;
+        }
+        LogRecord::Quantizer(quantizer) => put_quantizer(tx, quantizer)?,
+    }
+    Ok(())
+}
+
+fn put(tx: &Transaction, doc: &IndexedDocument) -> Result<()> {
+    let chunks = match doc.chunks.is_empty() {
+        true => None,
+        false => Some(rmp_serde::to_vec_named(&doc.chunks)?),
+    };
+    // An upsert rather than a replace, so the update trigger keeps the
+    // text index in step
+    tx.execute(
+        "INSERT INTO documents (id, text, metadata, embedding, chunks, version, updated_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+         ON CONFLICT (id) DO UPDATE SET
+             text = excluded.text,
+             metadata = excluded.metadata,
+             embedding = excluded.embedding,
+             chunks = excluded.chunks,
+             version = excluded.version,
+             updated_at = excluded.updated_at",
+        params![
+            doc.id,
+            doc.text,
+            doc.metadata.as_ref().map(|m| m.to_string()),
+            rmp_serde::to_vec_named(&doc.embedding)?,
+            chunks,
+            doc.version as i64,
+            doc.updated_at.map(|time| time.to_rfc3339()),
+        ],
+    )?;
+    Ok(())
+}
+
+fn put_quantizer(tx: &Transaction, quantizer: &ProductQuantizer) -> Result<()> {
+    tx.execute(
+        "INSERT OR REPLACE INTO quantizer (id, centroids) VALUES (0, ?1)",
+        [rmp_serde::to_vec_named(quantizer)?],
+    )?;
+    Ok(())
+}
+
+fn replace_all(
+    tx: &Transaction,
+    documents: &[&IndexedDocument],
+    aliases: &HashMap<String, String>,
+    quantizer: Option<&ProductQuantizer>,
+) -> Result<()> {
+    tx.execute("DELETE FROM documents", [])?;
+    tx.execute("DELETE FROM aliases", [])?;
+    tx.execute("DELETE FROM quantizer", [])?;
+    for doc in documents {
+        put(tx, doc)?;
+    }
+    for (alias, id) in aliases {
+        apply(tx, &LogRecord::Alias { alias, id })?;
+    }
+    if let Some(quantizer) = quantizer {
+        put_quantizer(tx, quantizer)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{HnswConfig, Precision, QuantizationConfig, StorageBackend, StorageConfig};
+    use crate::index::VectorIndex;
+
+    fn open_index(dir: &Path, backend: StorageBackend) -> VectorIndex {
+        let storage = StorageConfig {
+            backend,
+            ..Default::default()
+        };
+        VectorIndex::open(
+            dir,
+            Precision::F32,
+            QuantizationConfig::default(),
+            HnswConfig::default(),
+            storage,
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_backend_persists_and_switches() {
+        let dir = std::env::temp_dir().join(format!("systematics-sqlite-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        {
+            let index = open_index(&dir, StorageBackend::Memory);
+            index
+                .add("a", vec![1.0, 0.0], "alpha text".to_string(), None)
+                .await
+                .unwrap();
+            index.add_alias("old-a", "a").await.unwrap();
+        }
+
+        // The log-backed index moves into the database
+        {
+            let index = open_index(&dir, StorageBackend::Sqlite);
+            assert!(dir.join(DATABASE_FILE).exists());
+            assert!(!dir.join("index.log").exists());
+            assert_eq!(
+                index.get("old-a").await.unwrap().unwrap().text,
+                "alpha text"
+            );
+
+            let metadata = serde_json::json!({ "tags": ["x"] });
+            index
+                .add("b", vec![0.0, 1.0], "beta text".to_string(), Some(metadata))
+                .await
+                .unwrap();
+            index
+                .add("a", vec![1.0, 0.0], "gamma text".to_string(), None)
+                .await
+                .unwrap();
+            index.delete("missing").await.unwrap();
+        }
+
+        {
+            let (store, snapshot) =
+                SqliteStore::open(&dir.join(DATABASE_FILE), FsyncPolicy::Always).unwrap();
+            assert_eq!(snapshot.documents.len(), 2);
+            assert_eq!(snapshot.aliases["old-a"], "a");
+            let connection = store.connection.lock().unwrap();
+            let matches: Vec<String> = connection
+                .prepare(
+                    "SELECT d.id FROM documents_fts f JOIN documents d ON d.rowid = f.rowid
+                     WHERE documents_fts MATCH ?1 ORDER BY d.id",
+                )
+                .unwrap()
+                .query_map(["text"], |row| row.get(0))
+                .unwrap()
+                .collect::<rusqlite::Result<_>>()
+                .unwrap();
+            assert_eq!(matches, ["a", "b"]);
+            let alpha: i64 = connection
+                .query_row(
+                    "SELECT count(*) FROM documents_fts WHERE documents_fts MATCH 'alpha'",
+                    [],
+                    |row| row.get(0),
+                )
+                .unwrap();
+            assert_eq!(alpha, 0);
+        }
+
+        // And back again
+        let index = open_index(&dir, StorageBackend::Memory);
+        assert!(!dir.join(DATABASE_FILE).exists());
+        let b = index.get("b").await.unwrap().unwrap();
+        assert_eq!(b.metadata.unwrap()["tags"][0], "x");
+        assert_eq!(index.get("a").await.unwrap().unwrap().version, 2);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
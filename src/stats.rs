@@ -0,0 +1,143 @@
+//! Request counters since the server started, kept per endpoint for
+//! `/stats`.
+
+use axum::{
+    extract::{MatchedPath, Request, State},
+    middleware::Next,
+    response::Response,
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Requests to one endpoint.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct EndpointCounts {
+    pub requests: u64,
+    /// Responses with a 4xx status
+    pub client_errors: u64,
+    /// Responses with a 5xx status
+    pub server_errors: u64,
+    /// Mean time to the response's headers; streamed bodies take longer
+    pub mean_ms: f64,
+    #[serde(skip)]
+    total: Duration,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RequestCounts {
+    pub requests: u64,
+    pub client_errors: u64,
+    pub server_errors: u64,
+    /// By method and route, e.g. `POST /search`; requests matching no
+    /// route are counted under `unmatched`
+    pub endpoints: BTreeMap<String, EndpointCounts>,
+}
+
+/// When the server started, and the requests it has served since.
+pub struct RequestStats {
+    started: Instant,
+    started_at: DateTime<Utc>,
+    endpoints: Mutex<BTreeMap<String, EndpointCounts>>,
+}
+
+impl Default for RequestStats {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            started_at: Utc::now(),
+            endpoints: Mutex::new(BTreeMap::new()),
+        }
+    }
+}
+
+impl RequestStats {
+    pub fn started_at(&self) -> DateTime<Utc> {
+        self.started_at
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    fn record(&self, endpoint: String, status: u16, took: Duration) {
+        let mut endpoints = self.endpoints.lock().unwrap();
+        let counts = endpoints.entry(endpoint).or_default();
+        counts.requests += 1;
+        match status {
+            400..=499 => counts.client_errors += 1,
+            500..=599 => counts.server_errors += 1,
+            _ => {}
+        }
+        counts.total += took;
+        counts.mean_ms = counts.total.as_secs_f64() * 1000.0 / counts.requests as f64;
+    }
+
+    pub fn counts(&self) -> RequestCounts {
+        let endpoints = self.endpoints.lock().unwrap().clone();
+        let sum = |count: fn(&EndpointCounts) -> u64| endpoints.values().map(count).sum();
+        RequestCounts {
+            requests: sum(|counts| counts.requests),
+            client_errors: sum(|counts| counts.client_errors),
+            server_errors: sum(|counts| counts.server_errors),
+            endpoints,
+        }
+    }
+}
+
+/// Memory the process holds in RAM, where the OS reports it.
+pub fn resident_bytes() -> Option<u64> {
+    #[cfg(target_os = "linux")]
+    {
+        let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+        let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+        // Safety: sysconf only reads a system setting
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+        u64::try_from(page_size).ok().map(|size| pages * size)
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        None
+    }
+}
+
+/// Count every request by the route it matched, with its status and how
+/// long it took.
+pub async fn count(
+    State(stats): State<Arc<RequestStats>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let started = Instant::now();
+    let endpoint = match request.extensions().get::<MatchedPath>() {
+        Some(path) => format!("{} {}", request.method(), path.as_str()),
+        None => "unmatched".to_string(),
+    };
+    let response = next.run(request).await;
+    stats.record(endpoint, response.status().as_u16(), started.elapsed());
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counts_sum_endpoints() {
+        let stats = RequestStats::default();
+        stats.record("POST /search".into(), 200, Duration::from_millis(10));
+        stats.record("POST /search".into(), 429, Duration::from_millis(30));
+        stats.record("POST /index".into(), 503, Duration::from_millis(5));
+
+        let counts = stats.counts();
+        assert_eq!(
+            (counts.requests, counts.client_errors, counts.server_errors),
+            (3, 1, 1)
+        );
+        let search = &counts.endpoints["POST /search"];
+        assert_eq!(search.requests, 2);
+        assert!((search.mean_ms - 20.0).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,466 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::Duration;
+use tracing::{info, warn};
+
+use crate::config::{FsyncPolicy, StorageBackend, StorageConfig};
+use crate::index::IndexedDocument;
+use crate::sqlite::{self, SqliteStore};
+use crate::vector::ProductQuantizer;
+
+const SNAPSHOT_FILE: &str = "snapshot.bin";
+const LOG_FILE: &str = "index.log";
+/// Marks a snapshot and log already imported into SQLite, until they are
+/// deleted, so a crash partway through deleting them can't import what's
+/// left over the database.
+const MIGRATED_FILE: &str = "migrated";
+
+/// A single index mutation, as written to the log.
+#[derive(Serialize)]
+pub enum LogRecord<'a> {
+    Put(&'a IndexedDocument),
+    Delete(&'a str),
+    Clear,
+    Alias {
+        alias: &'a str,
+        id: &'a str,
+    },
+    Unalias(&'a str),
+    /// Centroids learned for product quantization; vectors logged after
+    /// this are coded against them
+    Quantizer(&'a ProductQuantizer),
+}
+
+/// Owned form of [`LogRecord`] for replay.
+#[derive(Deserialize)]
+pub enum ReplayRecord {
+    Put(IndexedDocument),
+    Delete(String),
+    Clear,
+    Alias { alias: String, id: String },
+    Unalias(String),
+    Quantizer(ProductQuantizer),
+}
+
+impl ReplayRecord {
+    /// The record as it was originally logged.
+    pub fn as_log_record(&self) -> LogRecord<'_> {
+        match self {
+            ReplayRecord::Put(doc) => LogRecord::Put(doc),
+            ReplayRecord::Delete(id) => LogRecord::Delete(id),
+            ReplayRecord::Clear => LogRecord::Clear,
+            ReplayRecord::Alias { alias, id } => LogRecord::Alias { alias, id },
+            ReplayRecord::Unalias(alias) => LogRecord::Unalias(alias),
+            ReplayRecord::Quantizer(quantizer) => LogRecord::Quantizer(quantizer),
+        }
+    }
+}
+
+/// Full index state as written to the snapshot file.
+#[derive(Default, Deserialize)]
+pub struct Snapshot {
+    pub documents: Vec<IndexedDocument>,
+    pub aliases: HashMap<String, String>,
+    #[serde(default)]
+    pub quantizer: Option<ProductQuantizer>,
+}
+
+#[derive(Serialize)]
+struct SnapshotRef<'a> {
+    documents: Vec<&'a IndexedDocument>,
+    aliases: &'a HashMap<String, String>,
+    quantizer: Option<&'a ProductQuantizer>,
+}
+
+/// Durable backing for a [`VectorIndex`](crate::index::VectorIndex).
+/// Indexes are always searched in memory; a store persists every mutation
+/// before it is applied, and hands the stored state back on startup.
+pub trait VectorStore: Send + Sync {
+    /// Persist a mutation. Returns true once enough have accumulated that
+    /// a snapshot is due.
+    fn append(&self, record: &LogRecord) -> Result<bool>;
+
+    /// Persist the full index state, superseding everything stored before.
+    fn snapshot(
+        &self,
+        documents: &[&IndexedDocument],
+        aliases: &HashMap<String, String>,
+        quantizer: Option<&ProductQuantizer>,
+    ) -> Result<()>;
+}
+
+/// Open the configured store in `dir`, returning it with the latest
+/// snapshot and the records logged since, which the caller replays in
+/// order. State left in `dir` by the other backend is carried over, so
+/// switching backends keeps the index.
+pub fn open(
+    dir: &Path,
+    config: StorageConfig,
+) -> Result<(Box<dyn VectorStore>, Snapshot, Vec<ReplayRecord>)> {
+    fs::create_dir_all(dir)?;
+    let database = dir.join(sqlite::DATABASE_FILE);
+    let log_files = [SNAPSHOT_FILE, LOG_FILE].map(|file| dir.join(file));
+
+    match config.backend {
+        StorageBackend::Memory => {
+            let (store, mut snapshot, mut records) = LogStore::open(dir, config)?;
+            if database.exists() {
+                info!("Moving {:?} into a snapshot", database);
+                let (_, imported) = SqliteStore::open(&database, config.fsync)?;
+                store.snapshot(
+                    &imported.documents.iter().collect::<Vec<_>>(),
+                    &imported.aliases,
+                    imported.quantizer.as_ref(),
+                )?;
+                fs::remove_file(&database)?;
+                (snapshot, records) = (imported, Vec::new());
+            }
+            Ok((Box::new(store), snapshot, records))
+        }
+        StorageBackend::Sqlite => {
+            let (store, mut snapshot) = SqliteStore::open(&database, config.fsync)?;
+            let migrated = dir.join(MIGRATED_FILE);
+            if !migrated.exists() && log_files.iter().any(|file| file.exists()) {
+                info!("Moving the index snapshot and log into {:?}", database);
+                let (_, logged, records) = LogStore::open(
+                    dir,
+                    StorageConfig {
+                        snapshot_every: usize::MAX,
+                        ..config
+                    },
+                )?;
+                store.import(&logged, &records)?;
+                File::create(&migrated)?.sync_all()?;
+            }
+            if migrated.exists() {
+                for file in &log_files {
+                    if file.exists() {
+                        fs::remove_file(file)?;
+                    }
+                }
+                fs::remove_file(&migrated)?;
+                snapshot = store.load()?;
+            }
+            Ok((Box::new(store), snapshot, Vec::new()))
+        }
+    }
+}
+
+struct LogWriter {
+    file: BufWriter<File>,
+    records_since_snapshot: usize,
+    /// Records written since the log was last synced
+    unsynced: bool,
+}
+
+impl LogWriter {
+    fn sync(&mut self) -> std::io::Result<()> {
+        self.file.get_ref().sync_data()?;
+        self.unsynced = false;
+        Ok(())
+    }
+}
+
+/// The default store: a snapshot of every document plus an append-only log
+/// of mutations made since, written before each mutation is applied and
+/// replayed on startup. Records are MessagePack, each prefixed with its
+/// length.
+pub struct LogStore {
+    dir: PathBuf,
+    log: Arc<Mutex<LogWriter>>,
+    snapshot_every: usize,
+    fsync: FsyncPolicy,
+}
+
+impl LogStore {
+    fn open(dir: &Path, config: StorageConfig) -> Result<(Self, Snapshot, Vec<ReplayRecord>)> {
+        let snapshot_path = dir.join(SNAPSHOT_FILE);
+        let snapshot = if snapshot_path.exists() {
+            let reader = BufReader::new(File::open(&snapshot_path)?);
+            rmp_serde::from_read(reader)
+                .with_context(|| format!("Failed to read snapshot {:?}", snapshot_path))?
+        } else {
+            Snapshot::default()
+        };
+
+        let log_path = dir.join(LOG_FILE);
+        let records = read_log(&log_path)?;
+        if !records.is_empty() {
+            info!("Replaying {} index log records", records.len());
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&log_path)?;
+
+        let storage = Self {
+            dir: dir.to_path_buf(),
+            log: Arc::new(Mutex::new(LogWriter {
+                file: BufWriter::new(file),
+                records_since_snapshot: records.len(),
+                unsynced: false,
+            })),
+            snapshot_every: config.snapshot_every,
+            fsync: config.fsync,
+        };
+        if config.fsync == FsyncPolicy::Interval {
+            let interval = Duration::from_millis(config.fsync_interval_ms);
+            let log = Arc::downgrade(&storage.log);
+            thread::spawn(move || sync_periodically(log, interval));
+        }
+
+        Ok((storage, snapshot, records))
+    }
+}
+
+/// Sync the log every `interval` while records are being written to it,
+/// until the store is dropped.
+fn sync_periodically(log: Weak<Mutex<LogWriter>>, interval: Duration) {
+    loop {
+        thread::sleep(interval);
+        let Some(log) = log.upgrade() else {
+            return;
+        };
+        let mut log = log.lock().unwrap();
+        if log.unsynced {
+            if let Err(e) = log.sync() {
+                warn!("Failed to sync the index log: {}", e);
+            }
+        }
+    }
+}
+
+impl VectorStore for LogStore {
+    /// Append a mutation to the log.
+    fn append(&self, record: &LogRecord) -> Result<bool> {
+        let bytes = rmp_serde::to_vec_named(record)?;
+
+        let mut log = self.log.lock().unwrap();
+        log.file.write_all(&(bytes.len() as u32).to_le_bytes())?;
+        log.file.write_all(&bytes)?;
+        log.file.flush()?;
+        log.records_since_snapshot += 1;
+        log.unsynced = true;
+        if self.fsync == FsyncPolicy::Always {
+            log.sync()?;
+        }
+
+        Ok(log.records_since_snapshot >= self.snapshot_every)
+    }
+
+    /// Write every document and alias to a fresh snapshot and truncate the
+    /// log. The snapshot is written to a temporary file and renamed into
+    /// place, so a crash mid-write leaves the previous snapshot and log
+    /// intact.
+    fn snapshot(
+        &self,
+        documents: &[&IndexedDocument],
+        aliases: &HashMap<String, String>,
+        quantizer: Option<&ProductQuantizer>,
+    ) -> Result<()> {
+        let snapshot = SnapshotRef {
+            documents: documents.to_vec(),
+            aliases,
+            quantizer,
+        };
+
+        let mut log = self.log.lock().unwrap();
+
+        let tmp_path = self.dir.join(format!("{}.tmp", SNAPSHOT_FILE));
+        let mut file = BufWriter::new(File::create(&tmp_path)?);
+        rmp_serde::encode::write_named(&mut file, &snapshot)?;
+        let file = file.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, self.dir.join(SNAPSHOT_FILE))?;
+
+        // Everything in the log is now covered by the snapshot
+        let file = File::create(self.dir.join(LOG_FILE))?;
+        log.file = BufWriter::new(file);
+        log.records_since_snapshot = 0;
+        log.unsynced = false;
+
+        info!(
+            "Wrote index snapshot with {} documents",
+            snapshot.documents.len()
+        );
+        Ok(())
+    }
+}
+
+/// Whether `err` came from the disk holding a store filling up.
+pub fn is_full(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        cause
+            .downcast_ref::<std::io::Error>()
+            .is_some_and(|e| e.kind() == ErrorKind::StorageFull)
+            || cause
+                .downcast_ref::<rusqlite::Error>()
+                .is_some_and(|e| e.sqlite_error_code() == Some(rusqlite::ErrorCode::DiskFull))
+    })
+}
+
+/// Read every complete record in the log. A torn record at the end (from a
+/// crash mid-write) is dropped and truncated away.
+fn read_log(path: &Path) -> Result<Vec<ReplayRecord>> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+
+    let file = File::open(path)?;
+    let file_len = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+    let mut records = Vec::new();
+    let mut valid_len = 0u64;
+
+    loop {
+        let mut len = [0u8; 4];
+        match reader.read_exact(&mut len) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        }
+
+        // A corrupt prefix can't claim more than the rest of the file, so
+        // it never allocates more than the file holds
+        let len = u32::from_le_bytes(len) as u64;
+        if valid_len + 4 + len > file_len {
+            break;
+        }
+        let mut bytes = vec![0u8; len as usize];
+        if reader.read_exact(&mut bytes).is_err() {
+            break;
+        }
+        let Ok(record) = rmp_serde::from_slice::<ReplayRecord>(&bytes) else {
+            break;
+        };
+
+        records.push(record);
+        valid_len += 4 + bytes.len() as u64;
+    }
+
+    if valid_len < file_len {
+        warn!(
+            "Discarding incomplete record at the end of {:?} after {} records",
+            path,
+            records.len()
+        );
+        OpenOptions::new()
+            .write(true)
+            .open(path)?
+            .set_len(valid_len)?;
+    }
+
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_records_replay_under_every_fsync_policy() {
+        for fsync in [
+            FsyncPolicy::Always,
+            FsyncPolicy::Interval,
+            FsyncPolicy::Never,
+        ] {
+            let dir = std::env::temp_dir().join(format!(
+                "systematics-storage-{}-{:?}",
+                std::process::id(),
+                fsync
+            ));
+            let _ = fs::remove_dir_all(&dir);
+            let config = StorageConfig {
+                fsync,
+                fsync_interval_ms: 1,
+                ..Default::default()
+            };
+
+            {
+                let (store, _, records) = open(&dir, config).unwrap();
+                assert!(records.is_empty());
+                store
+                    .append(&LogRecord::Alias {
+                        alias: "b",
+                        id: "a",
+                    })
+                    .unwrap();
+                store.append(&LogRecord::Delete("c")).unwrap();
+                std::thread::sleep(Duration::from_millis(5));
+            }
+
+            let (_, _, records) = open(&dir, config).unwrap();
+            assert!(matches!(
+                records.as_slice(),
+                [ReplayRecord::Alias { alias, id }, ReplayRecord::Delete(deleted)]
+                    if alias == "b" && id == "a" && deleted == "c"
+            ));
+            fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_interrupted_migration_is_not_imported_again() {
+        let dir = std::env::temp_dir().join(format!("systematics-migrate-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let memory = StorageConfig::default();
+        let sqlite = StorageConfig {
+            backend: StorageBackend::Sqlite,
+            ..memory
+        };
+
+        {
+            let (store, _, _) = open(&dir, memory).unwrap();
+            store
+                .append(&LogRecord::Alias {
+                    alias: "b",
+                    id: "a",
+                })
+                .unwrap();
+        }
+        let (_, snapshot, _) = open(&dir, sqlite).unwrap();
+        assert_eq!(snapshot.aliases["b"], "a");
+        assert!(!dir.join(LOG_FILE).exists());
+
+        // A crash after the import, with an empty log not yet deleted
+        File::create(dir.join(MIGRATED_FILE)).unwrap();
+        File::create(dir.join(LOG_FILE)).unwrap();
+        let (_, snapshot, _) = open(&dir, sqlite).unwrap();
+        assert_eq!(snapshot.aliases["b"], "a");
+        assert!(!dir.join(LOG_FILE).exists());
+        assert!(!dir.join(MIGRATED_FILE).exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_oversized_length_prefix_is_dropped_without_allocating() {
+        let dir = std::env::temp_dir().join(format!("systematics-torn-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        {
+            let (store, _, _) = open(&dir, StorageConfig::default()).unwrap();
+            store.append(&LogRecord::Delete("a")).unwrap();
+        }
+        let path = dir.join(LOG_FILE);
+        let valid_len = fs::metadata(&path).unwrap().len();
+
+        // A corrupt prefix claiming 4 GiB, followed by a few bytes
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&u32::MAX.to_le_bytes()).unwrap();
+        file.write_all(&[0; 8]).unwrap();
+        drop(file);
+
+        let records = read_log(&path).unwrap();
+        assert!(matches!(records.as_slice(), [ReplayRecord::Delete(id)] if id == "a"));
+        assert_eq!(fs::metadata(&path).unwrap().len(), valid_len);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,178 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::RwLock;
+
+/// A named search kept on the server, so a retrieval setup (filters,
+/// boosts, hybrid weights, prompt prefix) is defined once rather than in
+/// every client.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct QueryTemplate {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Prepended to the query before it is embedded, for models trained
+    /// with an instruction such as "Represent this sentence for searching
+    /// relevant passages: ". Keyword scoring sees the query without it.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub prefix: String,
+    /// A search request body in which `{{name}}` placeholders are
+    /// replaced by the caller's variables
+    pub request: Value,
+    /// Values for variables the caller leaves out
+    #[serde(default, skip_serializing_if = "Map::is_empty")]
+    pub defaults: Map<String, Value>,
+}
+
+impl QueryTemplate {
+    /// The search request with every placeholder filled in. A string that
+    /// is only a placeholder takes the variable's value as-is, so a list of
+    /// tags can fill an `$in` condition; placeholders inside longer strings
+    /// are replaced by the value's text.
+    pub fn render(&self, vars: &Map<String, Value>) -> Result<Value> {
+        let lookup = |name: &str| -> Result<Value> {
+            vars.get(name)
+                .or_else(|| self.defaults.get(name))
+                .cloned()
+                .ok_or_else(|| anyhow::anyhow!("Missing template variable: {}", name))
+        };
+        substitute(&self.request, &lookup)
+    }
+}
+
+fn substitute(value: &Value, lookup: &dyn Fn(&str) -> Result<Value>) -> Result<Value> {
+    Ok(match value {
+        Value::String(s) => {
+            if let Some(name) = placeholder(s) {
+                return lookup(name);
+            }
+
+            let mut rendered = String::with_capacity(s.len());
+            let mut rest = s.as_str();
+            while let Some(start) = rest.find("{{") {
+                let Some(end) = rest[start..].find("}}") else {
+                    break;
+                };
+                rendered.push_str(&rest[..start]);
+                match lookup(rest[start + 2..start + end].trim())? {
+                    Value::String(text) => rendered.push_str(&text),
+                    other => rendered.push_str(&other.to_string()),
+                }
+                rest = &rest[start + end + 2..];
+            }
+            rendered.push_str(rest);
+            Value::String(rendered)
+        }
+        Value::Array(values) => Value::Array(
+            values
+                .iter()
+                .map(|value| substitute(value, lookup))
+                .collect::<Result<_>>()?,
+        ),
+        Value::Object(fields) => Value::Object(
+            fields
+                .iter()
+                .map(|(key, value)| Ok((key.clone(), substitute(value, lookup)?)))
+                .collect::<Result<_>>()?,
+        ),
+        other => other.clone(),
+    })
+}
+
+/// The variable name if `s` is a single `{{name}}` placeholder.
+fn placeholder(s: &str) -> Option<&str> {
+    let name = s.strip_prefix("{{")?.strip_suffix("}}")?;
+    (!name.contains("{{") && !name.contains("}}")).then(|| name.trim())
+}
+
+/// Saved templates, persisted as JSON so they survive restarts.
+pub struct TemplateRegistry {
+    path: PathBuf,
+    templates: RwLock<BTreeMap<String, QueryTemplate>>,
+}
+
+impl TemplateRegistry {
+    pub fn open(path: PathBuf) -> Result<Self> {
+        let templates = if path.exists() {
+            serde_json::from_str(&fs::read_to_string(&path)?)?
+        } else {
+            BTreeMap::new()
+        };
+
+        Ok(Self {
+            path,
+            templates: RwLock::new(templates),
+        })
+    }
+
+    /// Save a template, replacing any with the same name.
+    pub async fn put(&self, template: QueryTemplate) -> Result<()> {
+        let mut templates = self.templates.write().unwrap();
+        templates.insert(template.name.clone(), template);
+        self.save(&templates)
+    }
+
+    pub async fn remove(&self, name: &str) -> Result<bool> {
+        let mut templates = self.templates.write().unwrap();
+        let removed = templates.remove(name).is_some();
+        if removed {
+            self.save(&templates)?;
+        }
+        Ok(removed)
+    }
+
+    pub async fn get(&self, name: &str) -> Option<QueryTemplate> {
+        let templates = self.templates.read().unwrap();
+        templates.get(name).cloned()
+    }
+
+    pub async fn list(&self) -> Vec<QueryTemplate> {
+        let templates = self.templates.read().unwrap();
+        templates.values().cloned().collect()
+    }
+
+    fn save(&self, templates: &BTreeMap<String, QueryTemplate>) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&self.path, serde_json::to_string_pretty(templates)?)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_render_template() {
+        let template: QueryTemplate = serde_json::from_value(json!({
+            "name": "project-notes",
+            "request": {
+                "query": "{{topic}} in {{project}}",
+                "limit": "{{limit}}",
+                "filter": { "tags": { "$in": "{{tags}}" }, "project": "{{project}}" }
+            },
+            "defaults": { "limit": 5 }
+        }))
+        .unwrap();
+
+        let vars = json!({ "topic": "triads", "project": "systematics", "tags": ["a", "b"] });
+        let rendered = template.render(vars.as_object().unwrap()).unwrap();
+        assert_eq!(
+            rendered,
+            json!({
+                "query": "triads in systematics",
+                "limit": 5,
+                "filter": { "tags": { "$in": ["a", "b"] }, "project": "systematics" }
+            })
+        );
+
+        let missing = json!({ "topic": "triads" });
+        assert!(template.render(missing.as_object().unwrap()).is_err());
+    }
+}
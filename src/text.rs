@@ -0,0 +1,80 @@
+use sha2::{Digest, Sha256};
+
+/// Marker appended to truncated text.
+pub const ELLIPSIS: char = '…';
+
+/// Shorten `text` to at most `max_chars` characters plus an ellipsis,
+/// returning `None` when it already fits.
+///
+/// The cut prefers the end of the last complete sentence, then the last
+/// word boundary, so snippets don't end mid-word. A sentence boundary is
+/// only used if it keeps at least half the allowed length, otherwise a
+/// short first sentence would swallow the rest of the snippet.
+pub fn truncate_at_sentence(text: &str, max_chars: usize) -> Option<String> {
+    let cut = match text.char_indices().nth(max_chars) {
+        Some((byte, _)) => byte,
+        None => return None,
+    };
+    let prefix = &text[..cut];
+
+    let sentence_end = prefix
+        .char_indices()
+        .zip(prefix.chars().skip(1).chain(text[cut..].chars().take(1)))
+        .filter(|((_, c), next)| matches!(c, '.' | '!' | '?') && next.is_whitespace())
+        .map(|((i, c), _)| i + c.len_utf8())
+        .last()
+        .filter(|&end| prefix[..end].chars().count() * 2 >= max_chars);
+
+    let end = sentence_end
+        .or_else(|| prefix.rfind(char::is_whitespace))
+        .unwrap_or(cut);
+
+    let mut truncated = prefix[..end].trim_end().to_string();
+    truncated.push(ELLIPSIS);
+    Some(truncated)
+}
+
+/// Hash identifying a text by its content, ignoring differences in
+/// whitespace such as line endings or a trailing newline.
+pub fn content_hash(text: &str) -> String {
+    let mut hasher = Sha256::new();
+    for word in text.split_whitespace() {
+        hasher.update(word.as_bytes());
+        hasher.update([0]);
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_truncate_at_sentence() {
+        assert_eq!(truncate_at_sentence("Short note.", 100), None);
+
+        let text = "First sentence here. Second sentence is longer. Third one.";
+        assert_eq!(
+            truncate_at_sentence(text, 50).as_deref(),
+            Some("First sentence here. Second sentence is longer.…")
+        );
+
+        // Falls back to a word boundary when the only sentence end is too early
+        assert_eq!(
+            truncate_at_sentence("Hi. This is a long run-on thought without stops", 30).as_deref(),
+            Some("Hi. This is a long run-on…")
+        );
+
+        // Multi-byte characters are counted, not bytes
+        assert_eq!(truncate_at_sentence("ééééé", 3).as_deref(), Some("ééé…"));
+    }
+
+    #[test]
+    fn test_content_hash_ignores_whitespace() {
+        assert_eq!(
+            content_hash("Three terms\r\nin relation\n"),
+            content_hash("Three terms\nin relation")
+        );
+        assert_ne!(content_hash("Three terms"), content_hash("Three term s"));
+    }
+}
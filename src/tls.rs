@@ -0,0 +1,173 @@
+use anyhow::{Context, Result};
+use axum::{
+    extract::{connect_info::IntoMakeServiceWithConnectInfo, Request},
+    http::header,
+    response::Redirect,
+    Router,
+};
+use hyper::server::conn::http1;
+use hyper_util::{rt::TokioIo, service::TowerToHyperService};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio_rustls::rustls::{
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    server::WebPkiClientVerifier,
+    RootCertStore, ServerConfig,
+};
+use tokio_rustls::TlsAcceptor;
+use tower::Service;
+use tracing::{debug, warn};
+
+use crate::config::TlsConfig;
+
+/// Build the rustls configuration for the listener. With a client CA,
+/// the handshake fails for any client that doesn't present a certificate
+/// signed by it, before a request is ever read.
+pub fn server_config(config: &TlsConfig) -> Result<Arc<ServerConfig>> {
+    let (Some(cert), Some(key)) = (&config.cert, &config.key) else {
+        anyhow::bail!("TLS needs both a certificate and a key");
+    };
+    let certs = load_certs(cert)?;
+    let key = PrivateKeyDer::from_pem_file(key)
+        .with_context(|| format!("Failed to read TLS key from {:?}", key))?;
+
+    let builder = ServerConfig::builder_with_provider(Arc::new(
+        tokio_rustls::rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()?;
+    let builder = match &config.client_ca {
+        Some(ca) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(ca)? {
+                roots
+                    .add(cert)
+                    .with_context(|| format!("Invalid client CA certificate in {:?}", ca))?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(
+                Arc::new(roots),
+                Arc::new(tokio_rustls::rustls::crypto::ring::default_provider()),
+            )
+            .build()?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let server_config = builder
+        .with_single_cert(certs, key)
+        .context("TLS certificate and key don't match")?;
+    Ok(Arc::new(server_config))
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("Failed to read certificates from {:?}", path))?;
+    if certs.is_empty() {
+        anyhow::bail!("No certificates found in {:?}", path);
+    }
+    Ok(certs)
+}
+
+/// Serve `app` over TLS, one task per connection. Failed handshakes, such
+/// as a client without an acceptable certificate, only drop that
+/// connection.
+pub async fn serve(
+    listener: TcpListener,
+    app: IntoMakeServiceWithConnectInfo<Router, SocketAddr>,
+    config: Arc<ServerConfig>,
+) -> Result<()> {
+    let acceptor = TlsAcceptor::from(config);
+    let mut app = app;
+
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("Failed to accept connection: {}", e);
+                continue;
+            }
+        };
+        let service = app.call(peer).await.unwrap_or_else(|never| match never {});
+        let acceptor = acceptor.clone();
+
+        tokio::spawn(async move {
+            let stream = match acceptor.accept(stream).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("TLS handshake with {} failed: {}", peer, e);
+                    return;
+                }
+            };
+
+            let service = TowerToHyperService::new(service);
+            if let Err(e) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .with_upgrades()
+                .await
+            {
+                debug!("Connection from {} closed: {}", peer, e);
+            }
+        });
+    }
+}
+
+/// Answer plain HTTP on `listener` with a permanent redirect of every
+/// request to the same path over HTTPS on `https_port`. Requests without a
+/// `Host` header are sent to `fallback_host`.
+pub async fn redirect(listener: TcpListener, fallback_host: String, https_port: u16) -> Result<()> {
+    let app = Router::new().fallback(move |request: Request| async move {
+        let host = request
+            .headers()
+            .get(header::HOST)
+            .and_then(|host| host.to_str().ok());
+        let path = request
+            .uri()
+            .path_and_query()
+            .map_or("/", |path| path.as_str());
+        Redirect::permanent(&https_location(
+            host.unwrap_or(&fallback_host),
+            https_port,
+            path,
+        ))
+    });
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+/// The HTTPS URL of `path` on `host`, whatever port `host` named.
+fn https_location(host: &str, port: u16, path: &str) -> String {
+    let name = match host.strip_prefix('[') {
+        // An IPv6 address, with or without a port after the brackets
+        Some(rest) => rest.split(']').next().map(|ip| format!("[{}]", ip)),
+        None if host.matches(':').count() > 1 => Some(format!("[{}]", host)),
+        None => host.split(':').next().map(str::to_string),
+    }
+    .unwrap_or_default();
+    if port == 443 {
+        format!("https://{}{}", name, path)
+    } else {
+        format!("https://{}:{}{}", name, port, path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_https_location_swaps_the_port() {
+        assert_eq!(
+            https_location("notes.lan:8080", 8443, "/search?q=triad"),
+            "https://notes.lan:8443/search?q=triad"
+        );
+        assert_eq!(https_location("notes.lan", 443, "/"), "https://notes.lan/");
+        assert_eq!(
+            https_location("[::1]:8080", 8443, "/health"),
+            "https://[::1]:8443/health"
+        );
+        assert_eq!(https_location("::", 8443, "/"), "https://[::]:8443/");
+    }
+}
@@ -0,0 +1,13 @@
+use axum::{extract::State, response::Html};
+use std::sync::Arc;
+
+use crate::config::Config;
+
+const PAGE: &str = include_str!("ui.html");
+
+/// The web UI: a single page that searches, browses documents, shows
+/// stats, and runs admin jobs through the regular API, so it needs the
+/// route prefix the API is mounted under.
+pub async fn page(State(config): State<Arc<Config>>) -> Html<String> {
+    Html(PAGE.replace("__API_BASE__", &config.route_prefix))
+}
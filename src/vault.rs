@@ -0,0 +1,524 @@
+use anyhow::{Context, Result};
+use notify::event::{ModifyKind, RenameMode};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+use walkdir::WalkDir;
+
+use crate::bulk::{BulkDocument, BulkIndexer};
+use crate::config::Config;
+use crate::index::{Collections, VectorIndex};
+use crate::markdown;
+use crate::models::ModelRegistry;
+
+/// What a debounced batch of filesystem events asks for, by note id.
+#[derive(Debug, PartialEq)]
+enum Change {
+    /// The file exists and should be (re)indexed
+    Upsert,
+    Remove,
+    /// Renamed from the given id, which becomes an alias of the new one
+    Rename(String),
+    /// Keyed by a folder's id with a trailing `/`: every indexed note
+    /// under the folder that is no longer on disk goes
+    RemoveFolder,
+}
+
+/// Keeps a collection in sync with the markdown files of a vault: every
+/// note is indexed on startup, then created, modified, deleted, and renamed
+/// notes are picked up as they change on disk.
+pub struct VaultWatcher {
+    root: PathBuf,
+    models: Arc<ModelRegistry>,
+    index: Arc<VectorIndex>,
+    collections: Arc<Collections>,
+    config: Arc<Config>,
+}
+
+impl VaultWatcher {
+    /// Start watching the vault configured in `config`, indexing it in the
+    /// background so the server can answer meanwhile. The watcher runs
+    /// until the server stops.
+    pub async fn spawn(
+        config: Arc<Config>,
+        models: Arc<ModelRegistry>,
+        collections: Arc<Collections>,
+    ) -> Result<()> {
+        let Some(root) = &config.vault.path else {
+            return Ok(());
+        };
+        let root = root
+            .canonicalize()
+            .with_context(|| format!("Vault directory {:?} not found", root))?;
+        let index = match collections.get(Some(&config.vault.collection)).await {
+            Ok(index) => index,
+            Err(_) => collections.create(&config.vault.collection).await?,
+        };
+        let settings = collections.settings(Some(&config.vault.collection)).await?;
+        models.get(settings.model.as_deref()).with_context(|| {
+            format!(
+                "Vault collection {} is bound to a model that isn't served",
+                config.vault.collection
+            )
+        })?;
+
+        // Subscribe before the initial sync so no change slips between them
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mut watcher =
+            notify::recommended_watcher(move |event: notify::Result<Event>| match event {
+                Ok(event) => {
+                    let _ = tx.send(event);
+                }
+                Err(e) => warn!("Vault watcher error: {}", e),
+            })?;
+        watcher.watch(&root, RecursiveMode::Recursive)?;
+
+        let vault = Self {
+            root,
+            models,
+            index,
+            collections,
+            config,
+        };
+        tokio::spawn(async move {
+            // Dropping the watcher would stop the events
+            let _watcher: RecommendedWatcher = watcher;
+            if let Err(e) = vault.sync().await {
+                warn!("Vault sync failed: {}", e);
+            }
+            vault.watch(rx).await;
+        });
+        Ok(())
+    }
+
+    /// Index every note, skipping those already indexed as they are, and
+    /// remove indexed notes that no longer exist.
+    async fn sync(&self) -> Result<()> {
+        let mut on_disk = HashSet::new();
+        let mut changed = Vec::new();
+        for entry in WalkDir::new(&self.root)
+            .into_iter()
+            .filter_entry(|entry| !is_hidden(entry.file_name().to_str().unwrap_or_default()))
+        {
+            let entry = entry?;
+            let Some(id) = note_id(&self.root, entry.path()) else {
+                continue;
+            };
+            on_disk.insert(id.clone());
+            let mut doc = match self.read(&id) {
+                Ok(doc) => doc,
+                Err(e) => {
+                    warn!("Skipping {}: {}", id, e);
+                    continue;
+                }
+            };
+            // Compare against the note as it would be indexed
+            if self.config.markdown.enabled {
+                let prepared =
+                    markdown::prepare(&doc.text, doc.metadata.take(), &self.config.markdown);
+                doc.text = prepared.text;
+                doc.metadata = prepared.metadata;
+            }
+            let current = self.index.get(&id).await?;
+            if current.is_none_or(|current| {
+                current.id != id || current.text != doc.text || current.metadata != doc.metadata
+            }) {
+                changed.push(id);
+            }
+        }
+
+        let stale: Vec<String> = self
+            .index
+            .list()
+            .await?
+            .into_iter()
+            .map(|doc| doc.id)
+            .filter(|id| is_note(id) && !on_disk.contains(id))
+            .collect();
+        info!(
+            "Vault sync: {} notes, {} to index, {} to remove",
+            on_disk.len(),
+            changed.len(),
+            stale.len()
+        );
+
+        let changes = changed
+            .into_iter()
+            .map(|id| (id, Change::Upsert))
+            .chain(stale.into_iter().map(|id| (id, Change::Remove)))
+            .collect();
+        self.apply(changes).await;
+        Ok(())
+    }
+
+    /// Apply filesystem events, batched: after the first event, wait until
+    /// none has arrived for the debounce interval, so a burst of saves or a
+    /// folder move is handled in one go.
+    async fn watch(&self, mut events: mpsc::UnboundedReceiver<Event>) {
+        let debounce = Duration::from_millis(self.config.vault.debounce_ms);
+        while let Some(event) = events.recv().await {
+            let mut changes = BTreeMap::new();
+            collect(&self.root, event, &mut changes);
+            while let Ok(Some(event)) = tokio::time::timeout(debounce, events.recv()).await {
+                collect(&self.root, event, &mut changes);
+            }
+            self.apply(changes).await;
+        }
+    }
+
+    async fn apply(&self, changes: BTreeMap<String, Change>) {
+        // The model and template may have changed since the vault was last
+        // synced, e.g. by a reindex, but can't while it is being synced
+        let _writing = self.index.writing().await;
+        let settings = match self
+            .collections
+            .settings(Some(&self.config.vault.collection))
+            .await
+        {
+            Ok(settings) => settings,
+            Err(e) => {
+                warn!("Failed to read the vault collection's settings: {}", e);
+                return;
+            }
+        };
+        let Some(service) = self.models.get(settings.model.as_deref()).cloned() else {
+            warn!(
+                "Vault collection {} is bound to a model that isn't served",
+                self.config.vault.collection
+            );
+            return;
+        };
+        let mut indexer = BulkIndexer::new(&service, &self.index, &self.config)
+            .with_template(settings.embed_template);
+        let mut batch = Vec::new();
+        let mut renames = Vec::new();
+        let mut removed = 0;
+
+        for (id, change) in changes {
+            match change {
+                Change::RemoveFolder => {
+                    // Notes moved out of the vault, or whose folder was
+                    // deleted or renamed without saying where to
+                    for note in self.index.ids().await {
+                        if note.starts_with(&id) && !self.root.join(&note).is_file() {
+                            match self.index.delete(&note).await {
+                                Ok(deleted) => removed += deleted as usize,
+                                Err(e) => warn!("Failed to remove {} from the index: {}", note, e),
+                            }
+                        }
+                    }
+                }
+                Change::Remove => {
+                    // If the id is an alias left by an earlier rename, the
+                    // note it points at is still there
+                    let indexed = self.index.get(&id).await.ok().flatten();
+                    if indexed.is_none_or(|doc| doc.id != id) {
+                        continue;
+                    }
+                    match self.index.delete(&id).await {
+                        Ok(deleted) => removed += deleted as usize,
+                        Err(e) => warn!("Failed to remove {} from the index: {}", id, e),
+                    }
+                }
+                Change::Upsert | Change::Rename(_) => {
+                    // A new note at a renamed note's old path replaces the
+                    // alias, rather than overwriting the renamed note
+                    if let Err(e) = self.index.remove_alias(&id).await {
+                        warn!("Failed to remove alias {}: {}", id, e);
+                    }
+                    if let Change::Rename(from) = change {
+                        renames.push((from, id.clone()));
+                    }
+                    batch.push(self.read(&id).map_err(|e| format!("{}: {}", id, e)));
+                    if batch.len() >= self.config.max_batch_size {
+                        indexer.flush(&mut batch).await;
+                    }
+                }
+            }
+        }
+        indexer.flush(&mut batch).await;
+
+        let response = indexer.finish();
+        for result in response.results.iter().filter(|result| !result.success) {
+            warn!(
+                "Failed to index {}: {}",
+                result.id.as_deref().unwrap_or("note"),
+                result.error.as_deref().unwrap_or_default()
+            );
+        }
+        // Links and clients holding the old path keep resolving
+        for (from, to) in renames {
+            if let Err(e) = self.index.add_alias(&from, &to).await {
+                warn!("Failed to alias {} to {}: {}", from, to, e);
+            }
+        }
+        if response.indexed > 0 || removed > 0 {
+            info!(
+                "Vault: indexed {} notes, removed {}",
+                response.indexed, removed
+            );
+        }
+    }
+
+    fn read(&self, id: &str) -> Result<BulkDocument> {
+        let contents = fs::read_to_string(self.root.join(id))?;
+        Ok(parse_note(id, &contents))
+    }
+}
+
+/// Turn a filesystem event under the vault at `root` into changes to
+/// the notes it touched, on top of those already in `changes`.
+fn collect(root: &Path, event: Event, changes: &mut BTreeMap<String, Change>) {
+    if let EventKind::Modify(ModifyKind::Name(RenameMode::Both)) = event.kind {
+        if let [from, to] = &event.paths[..] {
+            if to.is_dir() {
+                // Every note in a renamed folder is renamed with it, unless
+                // it was hidden on either side
+                if relative_id(root, to).is_none() {
+                    if let Some(folder) = folder_id(root, from) {
+                        changes.insert(folder, Change::RemoveFolder);
+                    }
+                }
+                for (moved, path) in notes_under(root, to) {
+                    let old = path
+                        .strip_prefix(to)
+                        .ok()
+                        .and_then(|rest| note_id(root, &from.join(rest)));
+                    match old {
+                        Some(old) => {
+                            changes.insert(moved, Change::Rename(old.clone()));
+                            changes.insert(old, Change::Remove);
+                        }
+                        None => {
+                            changes.insert(moved, Change::Upsert);
+                        }
+                    }
+                }
+                return;
+            }
+            match (note_id(root, from), note_id(root, to)) {
+                (Some(from), Some(to)) => {
+                    changes.insert(to, Change::Rename(from.clone()));
+                    changes.insert(from, Change::Remove);
+                }
+                (Some(from), None) => {
+                    changes.insert(from, Change::Remove);
+                }
+                (None, Some(to)) => {
+                    changes.insert(to, Change::Upsert);
+                }
+                (None, None) => {
+                    if let Some(folder) = folder_id(root, from) {
+                        changes.insert(folder, Change::RemoveFolder);
+                    }
+                }
+            }
+            return;
+        }
+    }
+    if matches!(event.kind, EventKind::Access(_)) {
+        return;
+    }
+
+    // A folder created or moved in, or out, by a rename that wasn't
+    // reported as one event
+    let moved = matches!(
+        event.kind,
+        EventKind::Create(_) | EventKind::Remove(_) | EventKind::Modify(ModifyKind::Name(_))
+    );
+
+    // Whatever else happened, the file's current state decides
+    for path in &event.paths {
+        if path.is_dir() {
+            if moved {
+                for (id, _) in notes_under(root, path) {
+                    if !matches!(changes.get(&id), Some(Change::Rename(_))) {
+                        changes.insert(id, Change::Upsert);
+                    }
+                }
+            }
+            continue;
+        }
+        let Some(id) = note_id(root, path) else {
+            if let Some(folder) = folder_id(root, path).filter(|_| moved && !path.exists()) {
+                changes.insert(folder, Change::RemoveFolder);
+            }
+            continue;
+        };
+        let change = if path.is_file() {
+            Change::Upsert
+        } else {
+            Change::Remove
+        };
+        // An upsert doesn't undo a rename recorded earlier in the batch,
+        // which indexes the note anyway
+        if !(change == Change::Upsert && matches!(changes.get(&id), Some(Change::Rename(_)))) {
+            changes.insert(id, change);
+        }
+    }
+}
+
+/// The id of the note at `path`: its path relative to the vault root
+/// with `/` separators, as the Obsidian plugin uses. `None` for anything
+/// that isn't a visible markdown file.
+fn note_id(root: &Path, path: &Path) -> Option<String> {
+    let id = relative_id(root, path)?;
+    is_note(&id).then_some(id)
+}
+
+/// The prefix the ids of notes in the folder at `path` share, if it could
+/// be a visible folder: one without an extension, since a file that no
+/// longer exists can't be told from a folder otherwise.
+fn folder_id(root: &Path, path: &Path) -> Option<String> {
+    let id = relative_id(root, path).filter(|id| !id.is_empty())?;
+    path.extension().is_none().then(|| format!("{}/", id))
+}
+
+fn relative_id(root: &Path, path: &Path) -> Option<String> {
+    let relative = path.strip_prefix(root).ok()?;
+    let parts: Vec<&str> = relative
+        .components()
+        .map(|part| part.as_os_str().to_str())
+        .collect::<Option<_>>()?;
+    (!parts.iter().any(|part| is_hidden(part))).then(|| parts.join("/"))
+}
+
+/// Every visible note under the folder `dir`, with its path.
+fn notes_under(root: &Path, dir: &Path) -> Vec<(String, PathBuf)> {
+    WalkDir::new(dir)
+        .into_iter()
+        .filter_entry(|entry| !is_hidden(entry.file_name().to_str().unwrap_or_default()))
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| Some((note_id(root, entry.path())?, entry.into_path())))
+        .collect()
+}
+
+fn is_note(id: &str) -> bool {
+    id.to_ascii_lowercase().ends_with(".md")
+}
+
+/// Obsidian keeps its settings in `.obsidian` and deleted notes in
+/// `.trash`, neither of which should be indexed.
+fn is_hidden(name: &str) -> bool {
+    name.starts_with('.') && name != "." && name != ".."
+}
+
+/// Split a note into its body and YAML frontmatter, which becomes the
+/// document's metadata. Frontmatter that isn't a valid YAML mapping is
+/// left in the text.
+pub fn parse_note(id: &str, contents: &str) -> BulkDocument {
+    let (metadata, text) = markdown::split_frontmatter(contents);
+    BulkDocument {
+        id: Some(id.to_string()),
+        text: text.trim().to_string(),
+        metadata,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_note_frontmatter() {
+        let doc = parse_note(
+            "Projects/Triads.md",
+            "---\ntags: [systematics, triad]\nstatus: draft\n---\n# Triads\n\nThree terms.\n",
+        );
+        assert_eq!(doc.text, "# Triads\n\nThree terms.");
+        assert_eq!(
+            doc.metadata,
+            Some(json!({ "tags": ["systematics", "triad"], "status": "draft" }))
+        );
+
+        // A horizontal rule isn't frontmatter
+        let doc = parse_note("a.md", "Intro\n---\nMore");
+        assert_eq!(
+            (doc.text.as_str(), doc.metadata),
+            ("Intro\n---\nMore", None)
+        );
+
+        // Neither is YAML that isn't a mapping
+        let doc = parse_note("b.md", "---\n- one\n---\nBody");
+        assert_eq!(doc.metadata, None);
+    }
+
+    #[test]
+    fn test_folder_renames_rename_their_notes() {
+        let root = std::env::temp_dir().join(format!("vault-folders-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        for note in [
+            "New/a.md",
+            "New/sub/b.md",
+            "New/.drafts/c.md",
+            ".trash/Old/d.md",
+        ] {
+            fs::create_dir_all(root.join(note).parent().unwrap()).unwrap();
+            fs::write(root.join(note), "text").unwrap();
+        }
+        let changes = |kind, paths: &[&str]| {
+            let mut event = Event::new(kind);
+            for path in paths {
+                event = event.add_path(root.join(path));
+            }
+            let mut changes = BTreeMap::new();
+            collect(&root, event, &mut changes);
+            changes.into_iter().collect::<Vec<_>>()
+        };
+        let renamed = |from: &str| Change::Rename(from.to_string());
+
+        assert_eq!(
+            changes(
+                EventKind::Modify(ModifyKind::Name(RenameMode::Both)),
+                &["Old", "New"]
+            ),
+            [
+                ("New/a.md".to_string(), renamed("Old/a.md")),
+                ("New/sub/b.md".to_string(), renamed("Old/sub/b.md")),
+                ("Old/a.md".to_string(), Change::Remove),
+                ("Old/sub/b.md".to_string(), Change::Remove),
+            ]
+        );
+        // Into the trash, the folder's notes go
+        assert_eq!(
+            changes(
+                EventKind::Modify(ModifyKind::Name(RenameMode::Both)),
+                &["Old", ".trash/Old"]
+            ),
+            [("Old/".to_string(), Change::RemoveFolder)]
+        );
+
+        // Halves of a rename reported apart
+        assert_eq!(
+            changes(
+                EventKind::Modify(ModifyKind::Name(RenameMode::From)),
+                &["Old"]
+            ),
+            [("Old/".to_string(), Change::RemoveFolder)]
+        );
+        assert_eq!(
+            changes(
+                EventKind::Modify(ModifyKind::Name(RenameMode::To)),
+                &["New"]
+            ),
+            [
+                ("New/a.md".to_string(), Change::Upsert),
+                ("New/sub/b.md".to_string(), Change::Upsert),
+            ]
+        );
+
+        // Writes inside a folder don't reindex all of it
+        assert!(changes(
+            EventKind::Modify(ModifyKind::Data(notify::event::DataChange::Any)),
+            &["New"]
+        )
+        .is_empty());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,543 @@
+use anyhow::Result;
+use half::f16;
+use half::slice::HalfFloatSliceExt;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::sync::{Arc, OnceLock};
+
+use crate::config::Precision;
+use crate::simd;
+use crate::warm;
+
+/// Half-precision values widened at once before the kernels run over them.
+const WIDEN_BLOCK: usize = 256;
+
+/// An embedding as held in the index, stored at the configured precision
+/// or quantization.
+#[derive(Clone, Serialize, Deserialize)]
+pub enum StoredVector {
+    F32(Vec<f32>),
+    F16(Vec<f16>),
+    /// Each value is `code * scale`
+    Int8 {
+        scale: f32,
+        codes: Vec<i8>,
+    },
+    /// Centroid indexes into the collection's [`ProductQuantizer`]
+    Pq(PqCodes),
+}
+
+/// A product-quantized vector. The quantizer isn't persisted with every
+/// vector; the index attaches its own when loading.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PqCodes {
+    pub codes: Vec<u8>,
+    #[serde(skip)]
+    pub quantizer: Option<Arc<ProductQuantizer>>,
+}
+
+impl PqCodes {
+    fn quantizer(&self) -> &ProductQuantizer {
+        self.quantizer
+            .as_deref()
+            .expect("the index attaches its quantizer on load")
+    }
+}
+
+impl StoredVector {
+    pub fn new(values: Vec<f32>, precision: Precision) -> Self {
+        match precision {
+            Precision::F32 => StoredVector::F32(values),
+            Precision::F16 => StoredVector::F16(values.into_iter().map(f16::from_f32).collect()),
+        }
+    }
+
+    /// Scalar-quantize to one byte per value, scaled so the largest
+    /// magnitude maps to 127.
+    pub fn int8(values: &[f32]) -> Self {
+        let max = values.iter().fold(0.0f32, |max, x| max.max(x.abs()));
+        let scale = if max > 0.0 { max / 127.0 } else { 1.0 };
+        StoredVector::Int8 {
+            scale,
+            codes: values
+                .iter()
+                .map(|x| (x / scale).round().clamp(-127.0, 127.0) as i8)
+                .collect(),
+        }
+    }
+
+    /// Widen to full precision, borrowing when already stored at f32.
+    /// Quantized vectors are reconstructed, so only approximate the
+    /// original embedding.
+    pub fn to_f32(&self) -> Cow<'_, [f32]> {
+        match self {
+            StoredVector::F32(values) => Cow::Borrowed(values),
+            StoredVector::F16(values) => values.iter().map(|x| x.to_f32()).collect(),
+            StoredVector::Int8 { scale, codes } => {
+                codes.iter().map(|&x| x as f32 * scale).collect()
+            }
+            StoredVector::Pq(pq) => Cow::Owned(pq.quantizer().decode(&pq.codes)),
+        }
+    }
+
+    /// Cosine similarity against a full-precision query. Compact values are
+    /// widened as they are read, so accumulation is always f32 and the
+    /// query itself is never quantized.
+    pub fn cosine_similarity(&self, query: &[f32]) -> f32 {
+        match self {
+            StoredVector::F32(values) => simd::cosine(query, values),
+            StoredVector::F16(values) => {
+                let (mut dot, mut norm_a, mut norm_b) = (0.0, 0.0, 0.0);
+                widen_blocks(query, values, |query, values| {
+                    let (d, a, b) = simd::dot_and_norms(query, values);
+                    dot += d;
+                    norm_a += a;
+                    norm_b += b;
+                });
+                if norm_a == 0.0 || norm_b == 0.0 {
+                    return 0.0;
+                }
+                dot / (norm_a.sqrt() * norm_b.sqrt())
+            }
+            // The scale cancels out of cosine similarity
+            StoredVector::Int8 { codes, .. } => {
+                widened_cosine(query, codes.iter().map(|&x| x as f32), codes.len())
+            }
+            StoredVector::Pq(pq) => pq.quantizer().cosine_similarity(&pq.codes, query),
+        }
+    }
+
+    /// Similarity to a unit-length query, for a vector the index stored at
+    /// unit length: a plain dot product at full or half precision.
+    /// Quantized vectors aren't unit length once reconstructed, so they are
+    /// still compared by cosine.
+    pub fn unit_similarity(&self, query: &[f32]) -> f32 {
+        match self {
+            StoredVector::F32(values) => simd::dot(query, values),
+            StoredVector::F16(values) => {
+                let mut dot = 0.0;
+                widen_blocks(query, values, |query, values| {
+                    dot += simd::dot(query, values);
+                });
+                dot
+            }
+            _ => self.cosine_similarity(query),
+        }
+    }
+
+    /// As [`unit_similarity`](Self::unit_similarity), scoring a
+    /// product-quantized vector from the query's distance table rather
+    /// than decoding it.
+    pub fn similarity(&self, query: &Query) -> f32 {
+        match self {
+            StoredVector::Pq(pq) => match pq.quantizer.as_ref().and_then(|q| query.table(q)) {
+                Some(table) => table.cosine_similarity(&pq.codes),
+                None => self.cosine_similarity(query.values),
+            },
+            _ => self.unit_similarity(query.values),
+        }
+    }
+
+    /// Bytes held for the vector's values.
+    pub fn size_bytes(&self) -> usize {
+        match self {
+            StoredVector::F32(values) => values.len() * 4,
+            StoredVector::F16(values) => values.len() * 2,
+            StoredVector::Int8 { codes, .. } => codes.len() + 4,
+            StoredVector::Pq(pq) => pq.codes.len(),
+        }
+    }
+
+    /// The memory holding the vector's values, for warming and pinning.
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            StoredVector::F32(values) => warm::bytes_of(values),
+            StoredVector::F16(values) => warm::bytes_of(values),
+            StoredVector::Int8 { codes, .. } => warm::bytes_of(codes),
+            StoredVector::Pq(pq) => &pq.codes,
+        }
+    }
+}
+
+/// A query scored against many stored vectors. For product-quantized
+/// vectors its dot product with every centroid is worked out once, on
+/// first use, so each vector then costs a lookup per subvector instead of
+/// being decoded: asymmetric distance computation.
+pub struct Query<'a> {
+    values: &'a [f32],
+    table: OnceLock<DistanceTable>,
+}
+
+impl<'a> Query<'a> {
+    pub fn new(values: &'a [f32]) -> Self {
+        Self {
+            values,
+            table: OnceLock::new(),
+        }
+    }
+
+    pub fn values(&self) -> &'a [f32] {
+        self.values
+    }
+
+    /// The distance table for `quantizer`, unless the query was already
+    /// tabled against another or has other dimensions.
+    fn table(&self, quantizer: &Arc<ProductQuantizer>) -> Option<&DistanceTable> {
+        if self.values.len() != quantizer.dimensions {
+            return None;
+        }
+        let table = self.table.get_or_init(|| DistanceTable {
+            quantizer: quantizer.clone(),
+            dots: quantizer.dots(self.values),
+            norm: simd::dot(self.values, self.values).sqrt(),
+        });
+        Arc::ptr_eq(&table.quantizer, quantizer).then_some(table)
+    }
+}
+
+/// A query's dot products with every centroid of a [`ProductQuantizer`].
+struct DistanceTable {
+    quantizer: Arc<ProductQuantizer>,
+    /// Laid out like the centroids, subspace by subspace
+    dots: Vec<f32>,
+    norm: f32,
+}
+
+impl DistanceTable {
+    /// Cosine similarity of the query to the centroids `codes` stand for,
+    /// the same as [`ProductQuantizer::cosine_similarity`] gives.
+    fn cosine_similarity(&self, codes: &[u8]) -> f32 {
+        let norms = self.quantizer.norms();
+        let (mut dot, mut norm) = (0.0f32, 0.0f32);
+        for (m, &code) in codes.iter().enumerate() {
+            let k = m * PQ_CENTROIDS + code as usize;
+            dot += self.dots[k];
+            norm += norms[k];
+        }
+        if self.norm == 0.0 || norm == 0.0 {
+            return 0.0;
+        }
+        dot / (self.norm * norm.sqrt())
+    }
+}
+
+/// Call `f` on successive blocks of `query` and of `values` widened to f32.
+fn widen_blocks(query: &[f32], values: &[f16], mut f: impl FnMut(&[f32], &[f32])) {
+    assert_eq!(query.len(), values.len(), "Vectors must have same length");
+
+    let mut widened = [0.0f32; WIDEN_BLOCK];
+    for (query, values) in query.chunks(WIDEN_BLOCK).zip(values.chunks(WIDEN_BLOCK)) {
+        let widened = &mut widened[..values.len()];
+        values.convert_to_f32_slice(widened);
+        f(query, widened);
+    }
+}
+
+fn widened_cosine(query: &[f32], values: impl Iterator<Item = f32>, len: usize) -> f32 {
+    assert_eq!(query.len(), len, "Vectors must have same length");
+
+    let mut dot_product = 0.0f32;
+    let mut norm_a = 0.0f32;
+    let mut norm_b = 0.0f32;
+    for (a, b) in query.iter().zip(values) {
+        dot_product += a * b;
+        norm_a += a * a;
+        norm_b += b * b;
+    }
+
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+
+    dot_product / (norm_a.sqrt() * norm_b.sqrt())
+}
+
+/// Centroids learned per subspace, so each fits in a byte.
+pub const PQ_CENTROIDS: usize = 256;
+
+/// Lloyd iterations when learning centroids. The codebook barely improves
+/// beyond this on embedding data.
+const PQ_TRAIN_ITERATIONS: usize = 12;
+
+/// Product quantizer: a vector is split into `subvectors` equal groups of
+/// dimensions, and each group is stored as the index of the nearest of
+/// [`PQ_CENTROIDS`] centroids learned for that group by k-means.
+#[derive(Serialize, Deserialize)]
+pub struct ProductQuantizer {
+    dimensions: usize,
+    subvectors: usize,
+    /// `subvectors * PQ_CENTROIDS` centroids of `dimensions / subvectors`
+    /// values each, laid out subspace by subspace
+    centroids: Vec<f32>,
+    /// Squared length of each centroid, worked out on first use
+    #[serde(skip)]
+    norms: OnceLock<Vec<f32>>,
+}
+
+impl ProductQuantizer {
+    /// Learn centroids from `vectors`, which must all have the same length
+    /// and number at least [`PQ_CENTROIDS`]. `subvectors` is rounded down
+    /// to a divisor of the dimensions.
+    pub fn train(vectors: &[Vec<f32>], subvectors: usize) -> Result<Self> {
+        let Some(dimensions) = vectors.first().map(Vec::len) else {
+            anyhow::bail!("No vectors to train the quantizer on");
+        };
+        if vectors.len() < PQ_CENTROIDS {
+            anyhow::bail!(
+                "Product quantization needs at least {} vectors to train on, got {}",
+                PQ_CENTROIDS,
+                vectors.len()
+            );
+        }
+        if vectors.iter().any(|v| v.len() != dimensions) {
+            anyhow::bail!("Training vectors differ in length");
+        }
+
+        let subvectors = (1..=subvectors.min(dimensions))
+            .rev()
+            .find(|m| dimensions % m == 0)
+            .unwrap_or(1);
+        let sub = dimensions / subvectors;
+
+        let mut centroids = Vec::with_capacity(subvectors * PQ_CENTROIDS * sub);
+        let mut assignments = vec![0usize; vectors.len()];
+        for m in 0..subvectors {
+            let part = |i: usize| &vectors[i][m * sub..(m + 1) * sub];
+
+            // Start from evenly spaced training vectors, so training is
+            // deterministic
+            let stride = vectors.len() / PQ_CENTROIDS;
+            let mut means: Vec<f32> = (0..PQ_CENTROIDS)
+                .flat_map(|k| part(k * stride).iter().copied())
+                .collect();
+
+            for _ in 0..PQ_TRAIN_ITERATIONS {
+                for (i, assignment) in assignments.iter_mut().enumerate() {
+                    *assignment = nearest(&means, part(i));
+                }
+
+                let mut sums = vec![0.0f32; PQ_CENTROIDS * sub];
+                let mut counts = vec![0usize; PQ_CENTROIDS];
+                for (i, &k) in assignments.iter().enumerate() {
+                    counts[k] += 1;
+                    for (sum, x) in sums[k * sub..(k + 1) * sub].iter_mut().zip(part(i)) {
+                        *sum += x;
+                    }
+                }
+                // A centroid nothing was assigned to keeps its position
+                for (k, &count) in counts.iter().enumerate() {
+                    if count > 0 {
+                        for (mean, sum) in means[k * sub..(k + 1) * sub]
+                            .iter_mut()
+                            .zip(&sums[k * sub..(k + 1) * sub])
+                        {
+                            *mean = sum / count as f32;
+                        }
+                    }
+                }
+            }
+            centroids.extend(means);
+        }
+
+        Ok(Self {
+            dimensions,
+            subvectors,
+            centroids,
+            norms: OnceLock::new(),
+        })
+    }
+
+    pub fn subvectors(&self) -> usize {
+        self.subvectors
+    }
+
+    fn subspace(&self, m: usize) -> &[f32] {
+        let size = PQ_CENTROIDS * self.dimensions / self.subvectors;
+        &self.centroids[m * size..(m + 1) * size]
+    }
+
+    fn centroid(&self, m: usize, code: u8) -> &[f32] {
+        let sub = self.dimensions / self.subvectors;
+        &self.subspace(m)[code as usize * sub..(code as usize + 1) * sub]
+    }
+
+    /// Quantize `values`, falling back to full precision if they don't
+    /// have the dimensions the quantizer was trained on.
+    pub fn encode(self: &Arc<Self>, values: Vec<f32>) -> StoredVector {
+        if values.len() != self.dimensions {
+            return StoredVector::F32(values);
+        }
+
+        let sub = self.dimensions / self.subvectors;
+        StoredVector::Pq(PqCodes {
+            codes: values
+                .chunks_exact(sub)
+                .enumerate()
+                .map(|(m, part)| nearest(self.subspace(m), part) as u8)
+                .collect(),
+            quantizer: Some(self.clone()),
+        })
+    }
+
+    /// Dot product of each subvector of `query` with every centroid of its
+    /// subspace.
+    fn dots(&self, query: &[f32]) -> Vec<f32> {
+        let sub = self.dimensions / self.subvectors;
+        query
+            .chunks_exact(sub)
+            .enumerate()
+            .flat_map(|(m, part)| {
+                self.subspace(m)
+                    .chunks_exact(sub)
+                    .map(move |centroid| simd::dot(part, centroid))
+            })
+            .collect()
+    }
+
+    fn norms(&self) -> &[f32] {
+        self.norms.get_or_init(|| {
+            let sub = self.dimensions / self.subvectors;
+            self.centroids
+                .chunks_exact(sub)
+                .map(|centroid| simd::dot(centroid, centroid))
+                .collect()
+        })
+    }
+
+    fn decode(&self, codes: &[u8]) -> Vec<f32> {
+        codes
+            .iter()
+            .enumerate()
+            .flat_map(|(m, &code)| self.centroid(m, code).iter().copied())
+            .collect()
+    }
+
+    /// Asymmetric cosine similarity: the full-precision query against the
+    /// centroids the codes stand for.
+    fn cosine_similarity(&self, codes: &[u8], query: &[f32]) -> f32 {
+        assert_eq!(
+            query.len(),
+            self.dimensions,
+            "Vectors must have same length"
+        );
+        simd::cosine(query, &self.decode(codes))
+    }
+}
+
+/// Index of the centroid in `centroids` (laid out back to back) closest to
+/// `part` by Euclidean distance.
+fn nearest(centroids: &[f32], part: &[f32]) -> usize {
+    centroids
+        .chunks_exact(part.len())
+        .map(|centroid| {
+            centroid
+                .iter()
+                .zip(part)
+                .map(|(c, x)| (c - x) * (c - x))
+                .sum::<f32>()
+        })
+        .enumerate()
+        .min_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map_or(0, |(k, _)| k)
+}
+
+/// Round values to what `precision` can represent, so embeddings returned
+/// to clients are exactly what the index would store.
+pub fn round_to_precision(values: &mut [f32], precision: Precision) {
+    if precision == Precision::F16 {
+        for value in values {
+            *value = f16::from_f32(*value).to_f32();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Deterministic unit vectors spread over the sphere.
+    fn unit_vectors(count: usize, dims: usize) -> Vec<Vec<f32>> {
+        let mut state = 0x2545_f491_u32;
+        (0..count)
+            .map(|_| {
+                let v: Vec<f32> = (0..dims)
+                    .map(|_| {
+                        state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                        (state >> 8) as f32 / (1u32 << 24) as f32 - 0.5
+                    })
+                    .collect();
+                let norm = v.iter().map(|x| x * x).sum::<f32>().sqrt();
+                v.into_iter().map(|x| x / norm).collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_f16_similarity_error_is_bounded() {
+        let vectors = unit_vectors(50, 384);
+        let query = &vectors[0];
+
+        for doc in &vectors[1..] {
+            let exact = StoredVector::new(doc.clone(), Precision::F32).cosine_similarity(query);
+            let half = StoredVector::new(doc.clone(), Precision::F16).cosine_similarity(query);
+            assert!(
+                (exact - half).abs() < 1e-3,
+                "f16 similarity {} too far from f32 {}",
+                half,
+                exact
+            );
+        }
+    }
+
+    #[test]
+    fn test_quantized_similarity_preserves_ranking() {
+        let vectors = unit_vectors(300, 64);
+        let query = &vectors[0];
+        // Rounded down to a divisor of 64
+        let quantizer = Arc::new(ProductQuantizer::train(&vectors, 24).unwrap());
+        assert_eq!(quantizer.subvectors(), 16);
+
+        let top = |encode: &dyn Fn(&Vec<f32>) -> StoredVector| -> Vec<usize> {
+            let mut scored: Vec<(usize, f32)> = vectors[1..]
+                .iter()
+                .enumerate()
+                .map(|(i, v)| (i, encode(v).cosine_similarity(query)))
+                .collect();
+            scored.sort_by(|a, b| b.1.total_cmp(&a.1));
+            scored.into_iter().take(10).map(|(i, _)| i).collect()
+        };
+        let exact = top(&|v| StoredVector::F32(v.clone()));
+        let overlap = |ids: Vec<usize>| ids.iter().filter(|i| exact.contains(i)).count();
+
+        let int8 = StoredVector::int8(&vectors[1]);
+        assert_eq!(int8.size_bytes(), 68);
+        assert!((int8.cosine_similarity(&vectors[1]) - 1.0).abs() < 1e-3);
+        assert!(overlap(top(&|v| StoredVector::int8(v))) >= 9);
+
+        let pq = quantizer.encode(vectors[1].clone());
+        assert_eq!(pq.size_bytes(), 16);
+        assert!(overlap(top(&|v| quantizer.encode(v.clone()))) >= 5);
+
+        // Scoring from the query's distance table matches decoding
+        let table = Query::new(query);
+        for v in &vectors[1..20] {
+            let pq = quantizer.encode(v.clone());
+            assert!((pq.similarity(&table) - pq.cosine_similarity(query)).abs() < 1e-5);
+        }
+        let other = Arc::new(ProductQuantizer::train(&vectors, 8).unwrap());
+        let pq = other.encode(vectors[1].clone());
+        assert!((pq.similarity(&table) - pq.cosine_similarity(query)).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_rounded_values_round_trip() {
+        let mut values = unit_vectors(1, 384).remove(0);
+        round_to_precision(&mut values, Precision::F16);
+
+        let StoredVector::F16(stored) = StoredVector::new(values.clone(), Precision::F16) else {
+            panic!("Expected a half-precision vector");
+        };
+        let widened: Vec<f32> = stored.iter().map(|x| x.to_f32()).collect();
+        assert_eq!(widened, values);
+    }
+}
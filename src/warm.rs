@@ -0,0 +1,212 @@
+//! Warming and pinning of index memory, so the first searches after a
+//! reboot or restore don't wait on pages the OS swapped out or never
+//! faulted in.
+
+use std::alloc::{self, Layout};
+use std::hint::black_box;
+use std::io;
+use std::ops::Deref;
+use std::ptr::NonNull;
+
+/// Granularity pages are touched at. Smaller than most systems' pages, so
+/// no page is skipped.
+const PAGE_SIZE: usize = 4096;
+
+/// Read one byte of every page of `bytes`, faulting them in. Returns how
+/// many bytes that covered.
+pub fn touch(bytes: &[u8]) -> usize {
+    let mut sum = 0u8;
+    for page in bytes.chunks(PAGE_SIZE) {
+        sum = sum.wrapping_add(page[0]);
+    }
+    black_box(sum);
+    bytes.len()
+}
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+/// Plain numbers: no padding, and every byte of their memory is part of
+/// their value. Only implemented here, for the types vectors and graph
+/// links are stored as.
+///
+/// # Safety
+///
+/// Implementors must have no padding and no invalid bit patterns.
+pub unsafe trait Plain: sealed::Sealed + Copy + Send + Sync + 'static {}
+
+macro_rules! plain {
+    ($($ty:ty),*) => {
+        $(
+            impl sealed::Sealed for $ty {}
+            // Safety: a primitive number, with no padding
+            unsafe impl Plain for $ty {}
+        )*
+    };
+}
+
+plain!(u8, i8, u16, u32, f32, half::f16);
+
+/// The memory behind `values`, as bytes, to touch.
+pub fn bytes_of<T: Plain>(values: &[T]) -> &[u8] {
+    // Safety: `Plain` types have no padding, so every byte of the slice is
+    // initialized
+    unsafe { std::slice::from_raw_parts(values.as_ptr().cast(), std::mem::size_of_val(values)) }
+}
+
+/// A copy of `values` in memory of its own, locked into RAM until it is
+/// dropped. The buffer is page-aligned, rounded up to whole pages, and
+/// never resized, so the pages locked are exactly the ones it owns and
+/// unlocking them on drop can't unlock anyone else's.
+pub struct Pinned<T: Plain> {
+    ptr: NonNull<T>,
+    len: usize,
+    /// `None` for an empty buffer, which has nothing to lock
+    layout: Option<Layout>,
+}
+
+// Safety: the buffer is owned, and `Plain` types are `Send` and `Sync`
+unsafe impl<T: Plain> Send for Pinned<T> {}
+unsafe impl<T: Plain> Sync for Pinned<T> {}
+
+impl<T: Plain> Pinned<T> {
+    /// Copy `values` into a new buffer and lock it into RAM. Fails if the
+    /// OS refuses, typically past the memory lock limit (`ulimit -l`).
+    pub fn lock(values: &[T]) -> io::Result<Self> {
+        if values.is_empty() {
+            return Ok(Self {
+                ptr: NonNull::dangling(),
+                len: 0,
+                layout: None,
+            });
+        }
+
+        let page = page_size()?;
+        let size = std::mem::size_of_val(values).next_multiple_of(page);
+        let layout = Layout::from_size_align(size, page)
+            .map_err(|e| io::Error::new(io::ErrorKind::OutOfMemory, e))?;
+        // Safety: the layout's size is at least one page
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        let Some(ptr) = NonNull::new(ptr.cast::<T>()) else {
+            alloc::handle_alloc_error(layout);
+        };
+        // Safety: the new allocation is page-aligned, so aligned for `T`,
+        // large enough for every value, and can't overlap `values`
+        unsafe { std::ptr::copy_nonoverlapping(values.as_ptr(), ptr.as_ptr(), values.len()) };
+
+        let pinned = Self {
+            ptr,
+            len: values.len(),
+            layout: Some(layout),
+        };
+        // Safety: the range is exactly the allocation, which lives until
+        // `drop` unlocks it
+        let result = unsafe { lock(ptr.as_ptr().cast(), size) };
+        match result {
+            Ok(()) => Ok(pinned),
+            Err(e) => {
+                // Unlocking pages that were never locked is harmless
+                drop(pinned);
+                Err(e)
+            }
+        }
+    }
+
+    /// Bytes locked, whole pages.
+    pub fn bytes(&self) -> usize {
+        self.layout.map_or(0, |layout| layout.size())
+    }
+}
+
+impl<T: Plain> Deref for Pinned<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        // Safety: `ptr` holds `len` initialized values, or is dangling with
+        // `len` 0
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl<T: Plain> Drop for Pinned<T> {
+    fn drop(&mut self) {
+        if let Some(layout) = self.layout {
+            // Safety: the allocation is still ours, unlocked before it is
+            // freed, and was allocated with this layout
+            unsafe {
+                unlock(self.ptr.as_ptr().cast(), layout.size());
+                alloc::dealloc(self.ptr.as_ptr().cast(), layout);
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn page_size() -> io::Result<usize> {
+    // Safety: sysconf only reads a system setting
+    let size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    usize::try_from(size)
+        .ok()
+        .filter(|size| size.is_power_of_two())
+        .ok_or_else(io::Error::last_os_error)
+}
+
+#[cfg(not(unix))]
+fn page_size() -> io::Result<usize> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "memory locking is only supported on Unix",
+    ))
+}
+
+/// # Safety
+///
+/// `ptr..ptr + len` must be memory this process owns.
+#[cfg(unix)]
+unsafe fn lock(ptr: *const u8, len: usize) -> io::Result<()> {
+    if libc::mlock(ptr.cast(), len) != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+unsafe fn lock(_ptr: *const u8, _len: usize) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "memory locking is only supported on Unix",
+    ))
+}
+
+/// # Safety
+///
+/// As for [`lock`].
+#[cfg(unix)]
+unsafe fn unlock(ptr: *const u8, len: usize) {
+    libc::munlock(ptr.cast(), len);
+}
+
+#[cfg(not(unix))]
+unsafe fn unlock(_ptr: *const u8, _len: usize) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pinned_copy_matches_and_fills_whole_pages() {
+        let values: Vec<u32> = (0..3000).collect();
+        // The sandbox's lock limit may refuse, which callers report
+        let Ok(pinned) = Pinned::lock(&values) else {
+            return;
+        };
+        assert_eq!(&*pinned, values.as_slice());
+        assert_eq!(pinned.ptr.as_ptr() as usize % page_size().unwrap(), 0);
+        assert!(pinned.bytes() >= 12_000 && pinned.bytes() % page_size().unwrap() == 0);
+
+        let empty = Pinned::<f32>::lock(&[]).unwrap();
+        assert!(empty.is_empty());
+        assert_eq!(empty.bytes(), 0);
+    }
+}